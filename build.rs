@@ -1,9 +1,113 @@
 fn main() {
     linker_be_nice();
+    embed_build_info();
+    embed_memory_budget();
+    if std::env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
 }
 
+/// Expose the git hash and enabled cargo features to the firmware (see `src/version.rs`)
+fn embed_build_info() {
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CHARGER_GIT_HASH={git_hash}");
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_lowercase))
+        .collect();
+    features.sort();
+    println!(
+        "cargo:rustc-env=CHARGER_BUILD_FEATURES={}",
+        features.join(",")
+    );
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// Expose the RAM budgets of `memory_budget.toml` for the enabled features to the firmware (see
+/// `src/budget.rs`), a section named after a feature adds to the base budgets when it's enabled
+fn embed_memory_budget() {
+    const MANIFEST: &str = "memory_budget.toml";
+    let manifest = std::fs::read_to_string(MANIFEST).expect("memory_budget.toml is missing");
+    let cargo_toml = std::fs::read_to_string("Cargo.toml").expect("Cargo.toml is missing");
+    let features = toml_keys(&cargo_toml, "features");
+
+    let mut budgets: Vec<(String, u64)> = Vec::new();
+    let mut section = "";
+    for (number, line) in manifest.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let location = format!("{MANIFEST}:{}", number + 1);
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            if name != "base" && !features.iter().any(|feature| feature == name) {
+                panic!("{location}: [{name}] is neither [base] nor a feature");
+            }
+            section = name;
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .unwrap_or_else(|| panic!("{location}: expected `area = bytes`"));
+        let bytes: u64 = value
+            .parse()
+            .unwrap_or_else(|_| panic!("{location}: {value} is not a number of bytes"));
+        let budget = budgets.iter_mut().find(|(area, _)| area == key);
+        match (section, budget) {
+            ("", _) => panic!("{location}: {key} is outside a section"),
+            ("base", Some(_)) => panic!("{location}: {key} is set twice"),
+            ("base", None) => budgets.push((key.to_string(), bytes)),
+            (_, None) => panic!("{location}: {key} has no base budget"),
+            (feature, Some((_, budget))) => {
+                let variable =
+                    format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+                if std::env::var_os(variable).is_some() {
+                    *budget += bytes;
+                }
+            }
+        }
+    }
+    for (area, bytes) in budgets {
+        println!(
+            "cargo:rustc-env=CHARGER_BUDGET_{}={bytes}",
+            area.to_uppercase()
+        );
+    }
+    println!("cargo:rerun-if-changed={MANIFEST}");
+}
+
+/// Keys of a section of a TOML file, enough for the features of Cargo.toml
+fn toml_keys(content: &str, section: &str) -> Vec<String> {
+    let header = format!("[{section}]");
+    content
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != header)
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, _)| key.trim().to_string())
+        .collect()
+}
+
 fn linker_be_nice() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
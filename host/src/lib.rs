@@ -0,0 +1,13 @@
+//! Modules of the firmware without hardware, statics or async, included from its sources. They
+//! use the log macros through the crate root like in the firmware
+
+pub use log::{info, warn};
+
+#[path = "../../src/frame.rs"]
+pub mod frame;
+
+#[path = "../../src/state_machine.rs"]
+pub mod state_machine;
+
+#[path = "../../src/timestamp.rs"]
+pub mod timestamp;
@@ -0,0 +1,176 @@
+use esp32c6_embassy_charged_host::frame::{self, Frame, Invalid, CALL, CALL_RESULT};
+
+fn invalid(message: &str) -> Invalid<'_> {
+    frame::parse(message).expect_err(message)
+}
+
+#[test]
+fn call() {
+    assert_eq!(
+        frame::parse(r#"[2,"19223201","ChangeAvailability",{"connectorId":1,"type":"Operative"}]"#),
+        Ok(Frame::Call {
+            unique_id: "19223201",
+            action: "ChangeAvailability",
+            payload: r#"{"connectorId":1,"type":"Operative"}"#,
+        })
+    );
+}
+
+#[test]
+fn call_result() {
+    assert_eq!(
+        frame::parse(r#"[3,"42",{"idTagInfo":{"status":"Accepted"},"transactionId":7}]"#),
+        Ok(Frame::CallResult {
+            unique_id: "42",
+            payload: r#"{"idTagInfo":{"status":"Accepted"},"transactionId":7}"#,
+        })
+    );
+}
+
+#[test]
+fn call_error() {
+    assert_eq!(
+        frame::parse(r#"[4,"42","NotSupported","Unknown action, try again",{"hint":[1,2]}]"#),
+        Ok(Frame::CallError {
+            unique_id: "42",
+            code: "NotSupported",
+            description: "Unknown action, try again",
+            details: r#"{"hint":[1,2]}"#,
+        })
+    );
+}
+
+#[test]
+fn whitespace_between_elements() {
+    assert_eq!(
+        frame::parse(" [ 3 ,\n\t\"42\" ,\r\n { \"status\" : \"Accepted\" } ] \n"),
+        Ok(Frame::CallResult {
+            unique_id: "42",
+            payload: "{ \"status\" : \"Accepted\" }",
+        })
+    );
+}
+
+#[test]
+fn nested_payloads() {
+    let payloads = [
+        r#"{"a":{"b":{"c":[1,2,{"d":[]}]}}}"#,
+        r#"{"chargingProfile":{"chargingSchedule":{"chargingSchedulePeriod":[{"startPeriod":0,"limit":16.0},{"startPeriod":3600,"limit":8.0}]}}}"#,
+        r#"{"localAuthorizationList":[{"idTag":"A","idTagInfo":{"status":"Accepted"}},{"idTag":"B"}],"listVersion":2}"#,
+        r#"{"empty":{},"list":[],"nested":[[[]]],"values":[true,false,null,-1.5e3]}"#,
+    ];
+    for payload in payloads {
+        let message = format!(r#"[2,"1","SetChargingProfile",{payload}]"#);
+        assert_eq!(
+            frame::parse(&message),
+            Ok(Frame::Call {
+                unique_id: "1",
+                action: "SetChargingProfile",
+                payload,
+            }),
+            "{payload}"
+        );
+    }
+}
+
+#[test]
+fn commas_and_brackets_in_strings() {
+    // A DataTransfer carries its data as a string, often JSON of its own
+    let payload = r#"{"vendorId":"acme","data":"{\"a\":[1,2],\"b\":\"x,y]}\"}"}"#;
+    let message = format!(r#"[2,"id,with,commas","DataTransfer",{payload}]"#);
+    assert_eq!(
+        frame::parse(&message),
+        Ok(Frame::Call {
+            unique_id: "id,with,commas",
+            action: "DataTransfer",
+            payload,
+        })
+    );
+
+    let payload = r#"{"idTag":"]}[{,","note":"\\"}"#;
+    let message = format!(r#"[3,"7",{payload}]"#);
+    assert_eq!(
+        frame::parse(&message),
+        Ok(Frame::CallResult {
+            unique_id: "7",
+            payload,
+        })
+    );
+}
+
+#[test]
+fn escapes_are_kept() {
+    assert_eq!(
+        frame::parse(r#"[4,"9","GenericError","Said \"no\"",{}]"#),
+        Ok(Frame::CallError {
+            unique_id: "9",
+            code: "GenericError",
+            description: r#"Said \"no\""#,
+            details: "{}",
+        })
+    );
+}
+
+#[test]
+fn malformed_json() {
+    for message in [
+        "",
+        "not json",
+        r#"{"a":1}"#,
+        "[2,\"1\",\"Reset\",{}",
+        "2,\"1\",\"Reset\",{}]",
+        r#"[2,"1","Reset",{"type":"Hard"]"#,
+        r#"[2,"1","Reset",{"type":"Hard"}}]"#,
+        r#"[2,"1","Reset",{"type":"Hard}]"#,
+        r#"[2,"1" "Reset",{}]"#,
+        r#"[2,"1","Reset"{}]"#,
+        r#"[2 3,"1","Reset",{}]"#,
+        r#"[2,,"1","Reset",{}]"#,
+        r#"[2,"1","Reset",{},]"#,
+        r#"[2,"1","Reset",{}][3]"#,
+    ] {
+        let invalid = invalid(message);
+        assert_eq!(invalid.message_type_id, None, "{message}");
+        assert_eq!(invalid.unique_id, None, "{message}");
+    }
+}
+
+#[test]
+fn nesting_is_limited() {
+    let deep = format!("[3,\"1\",{}{}]", "[".repeat(40), "]".repeat(40));
+    assert_eq!(invalid(&deep).reason, "Payload is nested too deep");
+}
+
+#[test]
+fn invalid_call_keeps_its_unique_id() {
+    // These are answered with a FormationViolation
+    for message in [
+        r#"[2,"5","Reset"]"#,
+        r#"[2,"5","Reset",{},{}]"#,
+        r#"[2,"5","Reset",{},{},{},{}]"#,
+        r#"[2,"5","Reset","payload"]"#,
+        r#"[2,"5","Reset",[1,2]]"#,
+        r#"[2,"5",7,{}]"#,
+        r#"[2,"5","",{}]"#,
+    ] {
+        let invalid = invalid(message);
+        assert_eq!(invalid.message_type_id, Some(CALL), "{message}");
+        assert_eq!(invalid.unique_id, Some("5"), "{message}");
+    }
+    for message in [r#"[2,5,"Reset",{}]"#, r#"[2,"","Reset",{}]"#, "[2]"] {
+        let invalid = invalid(message);
+        assert_eq!(invalid.message_type_id, Some(CALL), "{message}");
+        assert_eq!(invalid.unique_id, None, "{message}");
+    }
+}
+
+#[test]
+fn invalid_frames() {
+    assert_eq!(invalid("[]").message_type_id, None);
+    assert_eq!(invalid(r#"["2","1","Reset",{}]"#).message_type_id, None);
+    assert_eq!(invalid(r#"[5,"1",{}]"#).reason, "Unknown message type id");
+    assert_eq!(invalid(r#"[3,"1"]"#).message_type_id, Some(CALL_RESULT));
+    assert_eq!(invalid(r#"[3,"1","Accepted"]"#).unique_id, Some("1"));
+    assert!(frame::parse(r#"[4,"1","GenericError",{}]"#).is_err());
+    assert!(frame::parse(r#"[4,"1","GenericError","",[]]"#).is_err());
+}
@@ -0,0 +1,519 @@
+use esp32c6_embassy_charged_host::state_machine::{
+    self, ChargerState, Conditions, Effect, InputEvent, OutputEvent, SessionAction,
+};
+
+use ChargerState::*;
+use InputEvent::*;
+
+const STOP: &[OutputEvent] = &[OutputEvent::RemovePower, OutputEvent::Unlock];
+const START: &[OutputEvent] = &[OutputEvent::ApplyPower, OutputEvent::Lock];
+
+/// Inputs that fault the charger from any state
+const SAFETY: [InputEvent; 4] = [
+    InvariantViolated,
+    GroundFault,
+    PowerSwitchFailure,
+    Overheated,
+];
+
+/// From state and input to state, output events and effect
+type Transition = (
+    ChargerState,
+    InputEvent,
+    ChargerState,
+    &'static [OutputEvent],
+    Option<Effect>,
+);
+
+/// The transitions with no condition set. A pair that isn't listed keeps its state without
+/// events, except a faulted charger that settles and recovers, and the safety inputs
+#[rustfmt::skip]
+const TRANSITIONS: &[Transition] = &[
+    (Available, InsertCable, Preparing, &[], Option::None),
+    (Available, Reserve, Reserved, &[], Option::None),
+    (Available, MaintenanceStarted, Unavailable, &[], Option::None),
+    (Preparing, SwipeDetected, Authorizing, &[], Option::None),
+    (Preparing, RemoveCable, Available, &[], Option::None),
+    (Preparing, DiodeMissing, Faulted, STOP, Some(Effect::RecordFault)),
+    (Authorizing, Accepted, Charging, START, Option::None),
+    (Authorizing, Rejected, Preparing, &[OutputEvent::ShowRejected], Option::None),
+    (Authorizing, DiodeMissing, Faulted, STOP, Some(Effect::RecordFault)),
+    (Charging, SwipeDetected, Preparing, STOP, Option::None),
+    (Charging, StopRequested, Preparing, STOP, Option::None),
+    (Charging, Deauthorized, Preparing, STOP, Option::None),
+    (Charging, RemoveCable, Faulted, STOP, Some(Effect::RecordFault)),
+    (Charging, DiodeMissing, Faulted, STOP, Some(Effect::RecordFault)),
+    (Reserved, InsertCable, Preparing, &[], Option::None),
+    (Reserved, ReservationEnded, Available, &[], Option::None),
+    (Reserved, MaintenanceStarted, Unavailable, &[], Option::None),
+    (Unavailable, LockoutCleared, Available, &[], Option::None),
+    (Unavailable, MaintenanceEnded, Available, &[], Option::None),
+];
+
+fn expected(
+    state: ChargerState,
+    input: InputEvent,
+) -> (ChargerState, &'static [OutputEvent], Option<Effect>) {
+    if SAFETY.contains(&input) {
+        return (Faulted, STOP, Some(Effect::RecordFault));
+    }
+    if let Some((_, _, to, events, effect)) = TRANSITIONS
+        .iter()
+        .find(|(from, on, ..)| *from == state && *on == input)
+    {
+        return (*to, events, *effect);
+    }
+    if state == Faulted {
+        return (Available, &[], Some(Effect::Settle));
+    }
+    (state, &[], Option::None)
+}
+
+/// Every combination of the conditions, from the bits of a number
+fn all_conditions() -> impl Iterator<Item = Conditions> {
+    (0u16..1 << 10).map(|bits| {
+        let bit = |n: u16| bits & (1 << n) != 0;
+        Conditions {
+            master_card: bit(0),
+            safety_latched: bit(1),
+            safety_cleared: bit(2),
+            overheated: bit(3),
+            diode_missing: bit(4),
+            lockout: bit(5),
+            maintenance: bit(6),
+            reserved: bit(7),
+            waiting_turn: bit(8),
+            offline_stops_sessions: bit(9),
+        }
+    })
+}
+
+fn state(state: ChargerState, input: InputEvent, conditions: Conditions) -> ChargerState {
+    state_machine::next(state, input, &conditions).state
+}
+
+#[test]
+fn all_lists_every_variant() {
+    // Fails to build when a variant is added, so it's added to ALL as well
+    let state_index = |state: ChargerState| match state {
+        Off => 0,
+        Faulted => 1,
+        Available => 2,
+        Preparing => 3,
+        Charging => 4,
+        Authorizing => 5,
+        Unavailable => 6,
+        Reserved => 7,
+    };
+    let input_index = |input: InputEvent| match input {
+        InsertCable => 0,
+        RemoveCable => 1,
+        SwipeDetected => 2,
+        Accepted => 3,
+        Rejected => 4,
+        LockoutCleared => 5,
+        Reserve => 6,
+        ReservationEnded => 7,
+        MaintenanceStarted => 8,
+        MaintenanceEnded => 9,
+        DiodeMissing => 10,
+        Deauthorized => 11,
+        NetworkLost => 12,
+        NetworkRestored => 13,
+        InvariantViolated => 14,
+        StopRequested => 15,
+        GroundFault => 16,
+        PowerSwitchFailure => 17,
+        Overheated => 18,
+        Cooled => 19,
+        Queued => 20,
+        None => 21,
+    };
+    for (i, state) in ChargerState::ALL.into_iter().enumerate() {
+        assert_eq!(state_index(state), i, "{state:?}");
+    }
+    for (i, input) in InputEvent::ALL.into_iter().enumerate() {
+        assert_eq!(input_index(input), i, "{input:?}");
+    }
+}
+
+#[test]
+fn every_state_and_input() {
+    for from in ChargerState::ALL {
+        for input in InputEvent::ALL {
+            let step = state_machine::next(from, input, &Conditions::default());
+            let (to, events, effect) = expected(from, input);
+            assert_eq!(step.state, to, "{from:?} on {input:?}");
+            assert_eq!(&step.events[..], events, "{from:?} on {input:?}");
+            assert_eq!(step.effect, effect, "{from:?} on {input:?}");
+        }
+    }
+}
+
+#[test]
+fn power_follows_the_session_under_any_conditions() {
+    for conditions in all_conditions() {
+        for from in ChargerState::ALL {
+            for input in InputEvent::ALL {
+                let step = state_machine::next(from, input, &conditions);
+                let events = &step.events[..];
+                let context = || format!("{from:?} on {input:?} with {conditions:?}");
+
+                // Only an accepted authorization starts charging
+                let starts = from == Authorizing && input == Accepted;
+                assert_eq!(
+                    step.state == Charging && from != Charging,
+                    starts,
+                    "{}",
+                    context()
+                );
+                assert_eq!(
+                    events.contains(&OutputEvent::ApplyPower),
+                    starts,
+                    "{}",
+                    context()
+                );
+                if starts {
+                    assert_eq!(events, START, "{}", context());
+                }
+                // Leaving Charging or faulting always removes the power first
+                if (from == Charging && step.state != Charging)
+                    || (from != Faulted && step.state == Faulted)
+                {
+                    assert_eq!(events, STOP, "{}", context());
+                }
+                // Nothing but a session in progress keeps the power on
+                if from != Charging && !starts {
+                    assert!(
+                        events.is_empty()
+                            || events == STOP
+                            || events == [OutputEvent::ShowRejected],
+                        "{}",
+                        context()
+                    );
+                }
+                // Off is only the state before the first input, nothing goes back to it
+                if from != Off {
+                    assert_ne!(step.state, Off, "{}", context());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn safety_inputs_fault_every_state() {
+    for conditions in all_conditions() {
+        for from in ChargerState::ALL {
+            for input in SAFETY {
+                let step = state_machine::next(from, input, &conditions);
+                assert_eq!(step.state, Faulted, "{from:?} on {input:?}");
+                assert_eq!(&step.events[..], STOP, "{from:?} on {input:?}");
+                assert_eq!(step.effect, Some(Effect::RecordFault));
+            }
+        }
+    }
+}
+
+#[test]
+fn latched_safety_fault_only_clears_with_the_master_card() {
+    let latched = Conditions {
+        safety_latched: true,
+        ..Default::default()
+    };
+    for input in InputEvent::ALL {
+        if SAFETY.contains(&input) {
+            continue;
+        }
+        let step = state_machine::next(Faulted, input, &latched);
+        assert_eq!(step.state, Faulted, "{input:?}");
+        assert_eq!(step.effect, Option::None, "{input:?}");
+    }
+
+    // Another card, or the master card while the cause persists, keeps it faulted
+    let master = Conditions {
+        master_card: true,
+        ..latched
+    };
+    assert_eq!(state(Faulted, SwipeDetected, master), Faulted);
+    let cleared = Conditions {
+        safety_cleared: true,
+        ..master
+    };
+    assert_eq!(state(Faulted, SwipeDetected, cleared), Available);
+    let locked_out = Conditions {
+        lockout: true,
+        ..cleared
+    };
+    assert_eq!(state(Faulted, SwipeDetected, locked_out), Unavailable);
+    let maintenance = Conditions {
+        maintenance: true,
+        ..cleared
+    };
+    assert_eq!(state(Faulted, SwipeDetected, maintenance), Unavailable);
+}
+
+#[test]
+fn ground_fault_recovery_path() {
+    let mut conditions = Conditions::default();
+    let mut current = Available;
+    for input in [InsertCable, SwipeDetected, Accepted] {
+        current = state(current, input, conditions);
+    }
+    assert_eq!(current, Charging);
+
+    // The rcd module latches the ground fault before the input arrives
+    conditions.safety_latched = true;
+    current = state(current, GroundFault, conditions);
+    assert_eq!(current, Faulted);
+    for input in [RemoveCable, InsertCable, SwipeDetected, Cooled, None] {
+        current = state(current, input, conditions);
+        assert_eq!(current, Faulted, "{input:?}");
+    }
+
+    conditions.master_card = true;
+    conditions.safety_cleared = true;
+    current = state(current, SwipeDetected, conditions);
+    assert_eq!(current, Available);
+}
+
+#[test]
+fn overheated_charger_stays_faulted_until_cooled() {
+    let hot = Conditions {
+        overheated: true,
+        ..Default::default()
+    };
+    for input in InputEvent::ALL {
+        assert_eq!(state(Faulted, input, hot), Faulted, "{input:?}");
+    }
+    let step = state_machine::next(Faulted, Cooled, &Conditions::default());
+    assert_eq!(step.state, Available);
+    assert_eq!(step.effect, Some(Effect::Settle));
+}
+
+#[test]
+fn missing_diode_stays_faulted_until_the_cable_is_removed() {
+    let diode = Conditions {
+        diode_missing: true,
+        ..Default::default()
+    };
+    for input in InputEvent::ALL {
+        if input == RemoveCable || SAFETY.contains(&input) {
+            continue;
+        }
+        assert_eq!(state(Faulted, input, diode), Faulted, "{input:?}");
+    }
+    let step = state_machine::next(Faulted, RemoveCable, &diode);
+    assert_eq!(step.state, Available);
+    assert_eq!(step.effect, Some(Effect::ClearDiodeFault));
+    let locked_out = Conditions {
+        lockout: true,
+        ..diode
+    };
+    assert_eq!(state(Faulted, RemoveCable, locked_out), Unavailable);
+}
+
+#[test]
+fn recurring_fault_locks_out_until_cleared() {
+    let locked_out = Conditions {
+        lockout: true,
+        ..Default::default()
+    };
+    let step = state_machine::next(Faulted, None, &locked_out);
+    assert_eq!(step.state, Unavailable);
+    assert_eq!(step.effect, Some(Effect::ClearInputs));
+
+    // Only the master card or the central system lifts it
+    for input in [
+        InsertCable,
+        SwipeDetected,
+        MaintenanceEnded,
+        NetworkRestored,
+    ] {
+        assert_eq!(
+            state(Unavailable, input, locked_out),
+            Unavailable,
+            "{input:?}"
+        );
+    }
+    let master = Conditions {
+        master_card: true,
+        ..locked_out
+    };
+    let step = state_machine::next(Unavailable, SwipeDetected, &master);
+    assert_eq!(step.state, Available);
+    assert_eq!(step.effect, Some(Effect::ClearLockout));
+    let maintenance = Conditions {
+        maintenance: true,
+        ..master
+    };
+    assert_eq!(state(Unavailable, SwipeDetected, maintenance), Unavailable);
+    assert_eq!(state(Unavailable, LockoutCleared, locked_out), Available);
+}
+
+#[test]
+fn passing_fault_settles_and_recovers() {
+    let step = state_machine::next(Faulted, InsertCable, &Conditions::default());
+    assert_eq!(step.effect, Some(Effect::Settle));
+    assert!(step.events.is_empty());
+
+    let maintenance = Conditions {
+        maintenance: true,
+        ..Default::default()
+    };
+    assert_eq!(state_machine::recovered(&Conditions::default()), Available);
+    assert_eq!(state_machine::recovered(&maintenance), Unavailable);
+    assert_eq!(state(Faulted, InsertCable, maintenance), Unavailable);
+}
+
+#[test]
+fn fault_still_active_after_settling_stays_faulted() {
+    let step = state_machine::next(Faulted, InsertCable, &Conditions::default());
+    assert_eq!(step.effect, Some(Effect::Settle));
+
+    // Sampled again after the settle time, the inputs of the meantime are dropped
+    let overheated = Conditions {
+        overheated: true,
+        ..Default::default()
+    };
+    let latched = Conditions {
+        safety_latched: true,
+        ..Default::default()
+    };
+    let diode_missing = Conditions {
+        diode_missing: true,
+        maintenance: true,
+        ..Default::default()
+    };
+    for conditions in [overheated, latched, diode_missing] {
+        assert_eq!(
+            state_machine::recovered(&conditions),
+            Faulted,
+            "{conditions:?}"
+        );
+    }
+    // Cooled down later, the charger settles and recovers then
+    let step = state_machine::next(Faulted, Cooled, &Conditions::default());
+    assert_eq!(step.effect, Some(Effect::Settle));
+    assert_eq!(step.state, Available);
+}
+
+#[test]
+fn offline_policy_stops_a_session() {
+    let stops = Conditions {
+        offline_stops_sessions: true,
+        ..Default::default()
+    };
+    let step = state_machine::next(Charging, NetworkLost, &stops);
+    assert_eq!(step.state, Preparing);
+    assert_eq!(&step.events[..], STOP);
+    assert_eq!(step.effect, Some(Effect::StopOffline));
+    for from in ChargerState::ALL {
+        if from != Charging && from != Faulted {
+            assert_eq!(state(from, NetworkLost, stops), from, "{from:?}");
+        }
+    }
+}
+
+#[test]
+fn unplugged_connector_is_held_for_the_next_driver() {
+    let reserved = Conditions {
+        reserved: true,
+        ..Default::default()
+    };
+    let waiting = Conditions {
+        waiting_turn: true,
+        ..Default::default()
+    };
+    let maintenance = Conditions {
+        maintenance: true,
+        ..reserved
+    };
+    assert_eq!(state(Preparing, RemoveCable, reserved), Reserved);
+    assert_eq!(state(Preparing, RemoveCable, waiting), Reserved);
+    assert_eq!(state(Preparing, RemoveCable, maintenance), Unavailable);
+}
+
+#[test]
+fn maintenance_window() {
+    let maintenance = Conditions {
+        maintenance: true,
+        ..Default::default()
+    };
+    // A test session can start, a session in progress continues
+    assert_eq!(state(Unavailable, InsertCable, maintenance), Preparing);
+    assert_eq!(state(Charging, MaintenanceStarted, maintenance), Charging);
+    let locked_out = Conditions {
+        lockout: true,
+        ..maintenance
+    };
+    assert_eq!(state(Unavailable, InsertCable, locked_out), Unavailable);
+
+    assert_eq!(
+        state(Unavailable, MaintenanceEnded, locked_out),
+        Unavailable
+    );
+    let reserved = Conditions {
+        reserved: true,
+        ..Default::default()
+    };
+    assert_eq!(state(Unavailable, MaintenanceEnded, reserved), Reserved);
+}
+
+#[test]
+fn every_ended_session_is_stopped() {
+    let conditions = Conditions::default();
+    for from in ChargerState::ALL {
+        for input in InputEvent::ALL {
+            let step = state_machine::next(from, input, &conditions);
+            let action = state_machine::session_action(step.state, &step.events, true);
+            // Leaving Charging in any way stops the session, whether it ends in Preparing or Faulted
+            if from == Charging && step.state != Charging {
+                assert_eq!(action, Some(SessionAction::Stop), "{from:?} on {input:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn session_after_a_fault_mid_charge_starts() {
+    let conditions = Conditions::default();
+    let mut session_open = false;
+    let mut starts = 0;
+    let mut run = |state: ChargerState, input: InputEvent| {
+        let step = state_machine::next(state, input, &conditions);
+        match state_machine::session_action(step.state, &step.events, session_open) {
+            Some(SessionAction::Start) => {
+                session_open = true;
+                starts += 1;
+            }
+            Some(SessionAction::Stop) => session_open = false,
+            Some(SessionAction::AlreadyStarted) => panic!("StartTransaction suppressed"),
+            Option::None => {}
+        }
+        step.state
+    };
+
+    for fault in [
+        RemoveCable,
+        GroundFault,
+        Overheated,
+        DiodeMissing,
+        PowerSwitchFailure,
+    ] {
+        let mut state = Available;
+        for input in [InsertCable, SwipeDetected, Accepted, fault] {
+            state = run(state, input);
+        }
+        assert_eq!(state, Faulted, "{fault:?}");
+        // Settled and recovered, the next driver starts a session of their own
+        state = state_machine::recovered(&conditions);
+        for input in [InsertCable, SwipeDetected, Accepted] {
+            state = run(state, input);
+        }
+        assert_eq!(state, Charging, "{fault:?}");
+        state = run(state, SwipeDetected);
+        assert_eq!(state, Preparing, "{fault:?}");
+    }
+    assert_eq!(starts, 10);
+}
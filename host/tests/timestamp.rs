@@ -0,0 +1,110 @@
+use esp32c6_embassy_charged_host::timestamp::{format_iso8601, ntp_to_unix_ms, unix_ms_to_ntp};
+
+/// Unix time of the end of NTP era 0, 2036-02-07T06:28:16Z
+const ERA_END_UNIX: u64 = (1 << 32) - 2_208_988_800;
+/// Unix time the signed 32-bit seconds run out, 2038-01-19T03:14:08Z
+const Y2038_UNIX: u64 = 1 << 31;
+/// One millisecond as an NTP fraction, rounded up
+const MILLI_FRACTION: u64 = (1 << 32) / 1000 + 1;
+
+fn ntp(seconds: u32, fraction: u32) -> u64 {
+    u64::from(seconds) << 32 | u64::from(fraction)
+}
+
+#[test]
+fn unset_timestamp_is_none() {
+    assert_eq!(ntp_to_unix_ms(0), Option::None);
+}
+
+#[test]
+fn unix_epoch() {
+    assert_eq!(ntp_to_unix_ms(ntp(2_208_988_800, 0)), Some(0));
+    assert_eq!(unix_ms_to_ntp(0), ntp(2_208_988_800, 0));
+}
+
+#[test]
+fn era_rollover() {
+    // The last second of era 0 and the first of era 1 follow each other
+    assert_eq!(
+        ntp_to_unix_ms(ntp(0xFFFF_FFFF, 0)),
+        Some((ERA_END_UNIX - 1) * 1000)
+    );
+    assert_eq!(
+        ntp_to_unix_ms(ntp(0, 0x8000_0000)),
+        Some(ERA_END_UNIX * 1000 + 500)
+    );
+    assert_eq!(ntp_to_unix_ms(ntp(1, 0)), Some((ERA_END_UNIX + 1) * 1000));
+    assert_eq!(format_iso8601(ERA_END_UNIX), "2036-02-07T06:28:16Z");
+
+    // The seconds wrap to 0 at the rollover
+    assert_eq!(
+        unix_ms_to_ntp((ERA_END_UNIX - 1) * 1000),
+        ntp(0xFFFF_FFFF, 0)
+    );
+    assert_eq!(unix_ms_to_ntp((ERA_END_UNIX + 1) * 1000), ntp(1, 0));
+}
+
+#[test]
+fn signed_seconds_overflow() {
+    assert_eq!(format_iso8601(Y2038_UNIX), "2038-01-19T03:14:08Z");
+    assert_eq!(format_iso8601(Y2038_UNIX - 1), "2038-01-19T03:14:07Z");
+    let timestamp = unix_ms_to_ntp(Y2038_UNIX * 1000);
+    assert_eq!(timestamp >> 32, Y2038_UNIX + 2_208_988_800 - (1 << 32));
+    assert_eq!(ntp_to_unix_ms(timestamp), Some(Y2038_UNIX * 1000));
+}
+
+#[test]
+fn round_trip_from_unix() {
+    for boundary in [ERA_END_UNIX, Y2038_UNIX] {
+        for offset_ms in [-1001, -1000, -999, -1, 1, 999, 1000, 1001] {
+            let unix_ms = (boundary * 1000).saturating_add_signed(offset_ms);
+            assert_eq!(
+                ntp_to_unix_ms(unix_ms_to_ntp(unix_ms)),
+                Some(unix_ms),
+                "{unix_ms}"
+            );
+        }
+    }
+}
+
+#[test]
+fn round_trip_from_ntp() {
+    let y2038 = (Y2038_UNIX + 2_208_988_800 - (1 << 32)) as u32;
+    for (seconds, fraction) in [
+        (0xFFFF_FFFE, 0),
+        (0xFFFF_FFFF, 0x0000_0001),
+        (0xFFFF_FFFF, 0xFFFF_FFFF),
+        (0, 1),
+        (0, 0x8000_0000),
+        (1, 0x1234_5678),
+        (y2038 - 1, 0xFFFF_FFFF),
+        (y2038, 0),
+        (y2038, 0x4000_0000),
+    ] {
+        let timestamp = ntp(seconds, fraction);
+        let unix_ms = ntp_to_unix_ms(timestamp).unwrap();
+        let round_trip = unix_ms_to_ntp(unix_ms);
+        // Milliseconds are coarser than the fraction, the second stays the same
+        assert_eq!(round_trip >> 32, u64::from(seconds), "{timestamp:x}");
+        assert!(
+            round_trip.abs_diff(timestamp) < MILLI_FRACTION,
+            "{timestamp:x} became {round_trip:x}"
+        );
+    }
+}
+
+#[test]
+fn iso8601_past_2106() {
+    // The Unix seconds no longer fit 32 bits
+    assert_eq!(format_iso8601(1 << 32), "2106-02-07T06:28:16Z");
+    assert_eq!(format_iso8601((1 << 32) + 86_400), "2106-02-08T06:28:16Z");
+    assert_eq!(format_iso8601(4_354_819_200), "2108-01-01T00:00:00Z");
+    assert_eq!(format_iso8601(4_107_542_400), "2100-03-01T00:00:00Z");
+}
+
+#[test]
+fn iso8601() {
+    assert_eq!(format_iso8601(0), "1970-01-01T00:00:00Z");
+    assert_eq!(format_iso8601(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(format_iso8601(1_704_110_400), "2024-01-01T12:00:00Z");
+}
@@ -0,0 +1,48 @@
+#!/usr/bin/env python3
+# Make a patch for a differential firmware update, published next to the new image as
+# `<image>.patch`. The charger applies it against its running image when that is the old image.
+#
+# Usage: scripts/make_delta.py <old image> <new image> <patch>
+# Requires: pip install bsdiff4 heatshrink2
+import hashlib
+import struct
+import sys
+
+try:
+    import bsdiff4.core
+    import heatshrink2
+except ImportError:
+    print("Error: bsdiff4 and heatshrink2 are required (pip install bsdiff4 heatshrink2).", file=sys.stderr)
+    sys.exit(1)
+
+# Heatshrink window and lookahead in bits, the charger allocates a window of 2^WINDOW_BITS bytes
+WINDOW_BITS = 11
+LOOKAHEAD_BITS = 4
+
+if len(sys.argv) != 4:
+    print(f"Usage: {sys.argv[0]} <old image> <new image> <patch>", file=sys.stderr)
+    sys.exit(1)
+
+with open(sys.argv[1], "rb") as f:
+    old = f.read()
+with open(sys.argv[2], "rb") as f:
+    new = f.read()
+
+# Each record is its control followed by its add and copy bytes, so it can be applied as it streams in
+control, diff, extra = bsdiff4.core.diff(old, new)
+records = bytearray()
+diff_pos = extra_pos = 0
+for add, copy, seek in control:
+    records += struct.pack("<IIi", add, copy, seek)
+    records += diff[diff_pos:diff_pos + add]
+    records += extra[extra_pos:extra_pos + copy]
+    diff_pos += add
+    extra_pos += copy
+
+header = b"CDLT" + bytes([WINDOW_BITS, LOOKAHEAD_BITS, 0, 0])
+header += struct.pack("<I", len(old)) + hashlib.sha256(old).digest() + struct.pack("<I", len(new))
+patch = header + heatshrink2.compress(bytes(records), window_sz2=WINDOW_BITS, lookahead_sz2=LOOKAHEAD_BITS)
+
+with open(sys.argv[3], "wb") as f:
+    f.write(patch)
+print(f"Patch of {len(patch)} bytes for {len(new)} bytes of image ({len(patch) * 100 // len(new)}%)")
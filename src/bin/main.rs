@@ -2,44 +2,83 @@
 #![no_main]
 
 extern crate alloc;
+use core::cell::RefCell;
 use embassy_executor::Spawner;
-use embassy_net::tcp::TcpSocket;
+use embassy_futures::select::{select3, Either3};
 use embassy_time::{Duration, Instant, Timer};
-use embedded_hal_bus::spi::ExclusiveDevice;
+use embedded_hal_bus::{i2c::RefCellDevice, spi::RefCellDevice as SpiRefCellDevice};
 use esp32c6_embassy_charged::{
+    ble_provisioning, budget,
+    button::{self, BootHold},
+    buzzer::{self, Buzzer},
     charger::{self, Charger, ChargerState, InputEvent, OutputEvent},
+    command,
     config::Config,
+    connectivity, crash,
+    data_transfer::{self, DataTransferResponse},
+    demand_response::{self, DemandResponse},
+    demo, diagnostics,
+    display::{
+        self, AboutScreen, Banner, DisplayEvent, DisplayManager, DisplayPower, ErrorScreen,
+        NetworkScreen, QrCodeScreen, SessionsScreen, StatusScreen, TransactionScreen, UpdateScreen,
+    },
+    endpoints, energy, error,
+    expander::{Expander, ExpanderKind},
+    extensions, fault, guest, http_server, idempotency, info,
+    invariant::{self, Invariant},
+    io_state,
+    leds::{self, Polarity},
+    load_management::{self, LoadManagement},
+    log_forward, logging, maintenance, mdns,
+    memory::{self, Feature},
+    meter,
+    metering::{self, Atm90e32, Calibration, Hlw8032, MeterIc, MeterKind},
     mk_static, mqtt,
     network::{self, NetworkStack},
-    ntp, ocpp, utils,
+    ntp, ocpp, ocpp_config, onboarding, ota, panel,
+    pilot::{self, PilotPwm},
+    pins::{MappedInput, MappedOutput, SharedAdc, SharedExpander, SharedOutput},
+    profile::DisplayPages,
+    quiet, rcd, relay, reservation,
+    rfid::{self, CardReader, Mfrc522Reader, ReaderKind, SimulatedReader},
+    rtc, sensors,
+    sequence::{self, Transition},
+    sessions, settings,
+    smart_charging::{self, CurrentLimits},
+    solar::{self, Solar},
+    storage, telemetry, usb_api, version, waiting_list, warn, watchdog, webhook,
 };
 use esp_hal::{
+    analog::adc::{Adc, AdcConfig, Attenuation},
     clock::CpuClock,
     delay::Delay,
     gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull},
     i2c::master::{Config as I2cConfig, I2c},
+    interrupt::{software::SoftwareInterruptControl, Priority},
+    ledc::{LSGlobalClkSource, Ledc},
+    peripherals::ADC1,
     rmt::Rmt,
     spi::{self, master::Spi},
     time::Rate,
     timer::{systimer::SystemTimer, timg::TimerGroup},
-    Blocking,
+    tsens::{self, TemperatureSensor},
+    uart::{self, Parity, UartRx},
+    usb_serial_jtag::UsbSerialJtag,
+    Async, Blocking,
 };
+use esp_hal_embassy::InterruptExecutor;
 
 use esp_hal_smartled::{smart_led_buffer, SmartLedsAdapter};
-use smart_leds::{
-    brightness,
-    colors::{BLUE, GREEN, ORANGE, RED, WHITE},
-    SmartLedsWrite as _, RGB8,
-};
 
-use log::{info, warn};
-use mfrc522::{comm::blocking::spi::SpiInterface, Mfrc522};
-use rust_mqtt::client::client::MqttClient;
-use rust_mqtt::utils::rng_generator::CountingRng;
+#[cfg(feature = "factory-test")]
+use esp32c6_embassy_charged::factory_test::{self, LoadBank};
+#[cfg(not(feature = "factory-test"))]
+use {esp32c6_embassy_charged::modbus::ModbusMeter, esp_hal::uart::Uart};
 
 #[panic_handler]
-fn panic(_: &core::panic::PanicInfo) -> ! {
-    loop {}
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    crash::record(info);
+    esp_hal::system::software_reset();
 }
 
 // This creates a default app-descriptor required by the esp-idf bootloader.
@@ -50,19 +89,30 @@ esp_bootloader_esp_idf::esp_app_desc!();
 async fn main(spawner: Spawner) {
     // generator version: 0.5.0
 
-    esp_println::logger::init_logger_from_env();
+    logging::init_logger();
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
 
-    esp_alloc::heap_allocator!(size: 64 * 1024);
+    esp_alloc::heap_allocator!(size: HEAP_SIZE);
+
+    storage::init();
+    onboarding::load_provisioning();
+    settings::load();
+    reservation::load();
+    guest::load();
+    energy::load();
+    relay::check_reset();
+    watchdog::load();
+    crash::load();
 
     let timer0 = SystemTimer::new(peripherals.SYSTIMER);
     esp_hal_embassy::init(timer0.alarm0);
 
     info!("MAIN: Charger initialized!");
 
-    let rng = esp_hal::rng::Rng::new(peripherals.RNG);
+    let mut rng = esp_hal::rng::Rng::new(peripherals.RNG);
+    idempotency::seed(rng.random());
     let timer1 = TimerGroup::new(peripherals.TIMG0);
 
     // I2C Setup
@@ -71,75 +121,236 @@ async fn main(spawner: Spawner) {
         .into_async()
         .with_sda(peripherals.GPIO22)
         .with_scl(peripherals.GPIO23);
+    // The display and the RTC share the bus
+    let i2c_bus = mk_static!(RefCell<I2c<'static, Async>>, RefCell::new(i2c));
+
+    // Seed the clock from the RTC, if there is one, so timestamps are valid before WiFi is up
+    let mut rtc = match rtc::Rtc::detect(RefCellDevice::new(i2c_bus)) {
+        Ok(mut rtc) => {
+            match rtc.read() {
+                Ok(unix_timestamp) => ntp::set_from_rtc(unix_timestamp),
+                Err(e) => warn!("MAIN: {e}, waiting for NTP"),
+            }
+            Some(rtc)
+        }
+        Err(e) => {
+            info!("MAIN: {e}, the time is set by NTP");
+            None
+        }
+    };
+    let mut rtc_ntp_syncs = 0;
+
+    // SPI Cardreader setup, the bus is shared with a TFT display
+    let spi_bus = mk_static!(
+        RefCell<Spi<'static, Blocking>>,
+        RefCell::new(
+            Spi::new(
+                peripherals.SPI2,
+                spi::master::Config::default()
+                    .with_frequency(Rate::from_mhz(5))
+                    .with_mode(spi::Mode::_0),
+            )
+            .unwrap()
+            .with_sck(peripherals.GPIO19)
+            .with_mosi(peripherals.GPIO18)
+            .with_miso(peripherals.GPIO20)
+        )
+    );
 
-    // Initialize SSD1306 display
-    info!("MAIN: Initializing SSD1306 display...");
-    let mut display_manager: Option<esp32c6_embassy_charged::display::DisplayManager<_>> =
-        match esp32c6_embassy_charged::display::DisplayManager::new(i2c) {
-            Ok(mut display) => {
-                info!("Display initialized successfully");
-
-                // Draw the startup logo
-                match display.draw_logo() {
-                    Ok(()) => {
-                        info!("MAIN: Logo displayed successfully");
-                    }
-                    Err(e) => {
-                        warn!("MAIN: Failed to draw logo: {e}");
-                    }
+    let sd_cs = Output::new(peripherals.GPIO17, Level::High, OutputConfig::default());
+
+    // Initialize the display of the board, the driver is chosen with a feature
+    info!("MAIN: Initializing display...");
+    #[cfg(not(any(feature = "display-sh1106", feature = "display-st7735")))]
+    let panel = panel::ssd1306(RefCellDevice::new(i2c_bus));
+    #[cfg(feature = "display-sh1106")]
+    let panel = panel::Sh1106::new(RefCellDevice::new(i2c_bus));
+    #[cfg(feature = "display-st7735")]
+    let panel = SpiRefCellDevice::new(
+        spi_bus,
+        Output::new(peripherals.GPIO6, Level::High, OutputConfig::default()),
+        Delay::new(),
+    )
+    .map_err(|_| "Failed to set up the display chip select")
+    .and_then(|spi| {
+        panel::St7735::new(
+            spi,
+            Output::new(peripherals.GPIO7, Level::Low, OutputConfig::default()),
+            &mut Delay::new(),
+        )
+    });
+    let mut display_manager: Option<Display> = match panel.map(DisplayManager::new) {
+        Ok(mut display) => {
+            info!("Display initialized successfully");
+
+            // Draw the startup logo
+            match display.draw_logo() {
+                Ok(()) => {
+                    info!("MAIN: Logo displayed successfully");
+                }
+                Err(e) => {
+                    warn!("MAIN: Failed to draw logo: {e}");
                 }
-                Some(display)
-            }
-            Err(e) => {
-                warn!("MAIN: Failed to initialize display: {e}");
-                warn!("MAIN: Continuing without display functionality");
-                None
             }
-        };
+            Some(display)
+        }
+        Err(e) => {
+            warn!("MAIN: Failed to initialize display: {e}");
+            warn!("MAIN: Continuing without display functionality");
+            None
+        }
+    };
+
+    let status_leds = mk_static!(leds::StatusLeds, {
+        let frequency = Rate::from_mhz(80);
+        let rmt = Rmt::new(peripherals.RMT, frequency).expect("Failed to initialize RMT0");
+        SmartLedsAdapter::new(
+            rmt.channel0,
+            peripherals.GPIO0,
+            smart_led_buffer!(leds::MAX_LEDS),
+        )
+    });
 
-    let charger_led = mk_static!(
-        SmartLedsAdapter<esp_hal::rmt::ConstChannelAccess<esp_hal::rmt::Tx, 0>, 25>,
-        {
-            let frequency = Rate::from_mhz(80);
-            let rmt = Rmt::new(peripherals.RMT, frequency).expect("Failed to initialize RMT0");
-            SmartLedsAdapter::new(rmt.channel0, peripherals.GPIO0, smart_led_buffer!(1))
+    // Load configuration from TOML file with environment variable overrides
+    let config = Config::from_config();
+    info!(
+        "MAIN: Charger configuration loaded: {}",
+        config.charger_name
+    );
+    endpoints::load(config.mqtt_broker);
+
+    // A GPIO expander on the I2C bus for the pins mapped to it
+    let expander: Option<SharedExpander> = match ExpanderKind::parse(config.expander) {
+        Some(kind) => {
+            match Expander::new(RefCellDevice::new(i2c_bus), kind, config.expander_address) {
+                Ok(expander) => {
+                    let expander: SharedExpander = mk_static!(
+                        RefCell<Expander<RefCellDevice<'static, I2c<'static, Async>>>>,
+                        RefCell::new(expander)
+                    );
+                    Some(expander)
+                }
+                Err(e) => {
+                    warn!("MAIN: {} expander: {e}", kind.as_str());
+                    None
+                }
+            }
+        }
+        None if !config.expander.is_empty() => {
+            warn!("MAIN: Unknown expander {}", config.expander);
+            None
         }
+        None => None,
+    };
+
+    let cable_lock_pin = MappedOutput::new(
+        "Cable lock",
+        Output::new(peripherals.GPIO21, Level::Low, Default::default()),
+        config.lock_pin,
+        expander,
     );
 
-    let cable_lock_pin = Output::new(peripherals.GPIO21, Level::Low, Default::default());
+    let mut cable_switch = MappedInput::new(
+        "Cable switch",
+        Input::new(
+            peripherals.GPIO1,
+            InputConfig::default().with_pull(Pull::Up),
+        ),
+        config.cable_pin,
+        expander,
+    );
 
-    let cable_switch = Input::new(
-        peripherals.GPIO1,
-        InputConfig::default().with_pull(Pull::Up),
+    let charger_relay = MappedOutput::new(
+        "Relay",
+        Output::new(peripherals.GPIO2, Level::Low, Default::default()),
+        config.relay_pin,
+        expander,
     );
 
-    // SPI Cardreader setup
-    let spi_bus  = // mk_static!(Spi<Blocking>,
-        Spi::new(
-            peripherals.SPI2,
-            spi::master::Config::default()
-                .with_frequency(Rate::from_mhz(5))
-                .with_mode(spi::Mode::_0),
+    // The auxiliary contact of the contactor, closed to ground while the contactor is
+    let relay_feedback = (!config.relay_feedback_pin.is_empty()).then(|| {
+        MappedInput::new(
+            "Relay feedback",
+            Input::new(
+                peripherals.GPIO16,
+                InputConfig::default().with_pull(Pull::Up),
+            ),
+            config.relay_feedback_pin,
+            expander,
         )
-        .unwrap()
-        .with_sck(peripherals.GPIO19)
-        .with_mosi(peripherals.GPIO18)
-        .with_miso(peripherals.GPIO20);
-    // );
-
-    let sd_cs = //mk_static!(Output,
-        Output::new(peripherals.GPIO17, Level::High, OutputConfig::default());
-    // );
+    });
+
+    // The dry contact of the utility's ripple control receiver, closed to ground to shed the load.
+    // GPIO11 drives the load bank in a factory test build
+    #[cfg(not(feature = "factory-test"))]
+    let demand_response_input = (!config.demand_response_pin.is_empty()).then(|| {
+        MappedInput::new(
+            "Demand response",
+            Input::new(
+                peripherals.GPIO11,
+                InputConfig::default().with_pull(Pull::Up),
+            ),
+            config.demand_response_pin,
+            expander,
+        )
+    });
+    #[cfg(feature = "factory-test")]
+    let demand_response_input: Option<MappedInput> = None;
+
+    // The front panel button, held at boot for provisioning or a factory reset
+    let mut button = MappedInput::new(
+        "Button",
+        Input::new(
+            peripherals.GPIO9,
+            InputConfig::default().with_pull(Pull::Up),
+        ),
+        config.button_pin,
+        expander,
+    );
+    let boot_hold = if config.button_enabled {
+        button::boot_hold(
+            &mut button,
+            config.button_provisioning_secs,
+            config.button_reset_secs,
+        )
+        .await
+    } else {
+        BootHold::None
+    };
+    if boot_hold == BootHold::FactoryReset {
+        button::factory_reset();
+        info!("MAIN: Restarting with the factory settings");
+        Timer::after(Duration::from_secs(1)).await;
+        esp_hal::system::software_reset();
+    }
 
-    let charger_relay = Output::new(peripherals.GPIO2, Level::Low, Default::default());
+    // The single color indicator starts off, high when it's active low
+    let indicator = (!config.led_indicator.is_empty()).then(|| {
+        let off = Level::from(config.led_polarity == Polarity::ActiveLow);
+        leds::Indicator::new(
+            MappedOutput::new(
+                "Indicator",
+                Output::new(peripherals.GPIO15, off, Default::default()),
+                config.led_indicator,
+                expander,
+            ),
+            config.led_polarity,
+        )
+    });
 
     let charger = mk_static!(Charger, Charger::new());
 
-    match cable_switch.is_low() {
+    let cable_connected = cable_switch.is_low();
+    io_state::record_cable(cable_connected);
+    match cable_connected {
         true => {
             info!("MAIN: Cable is connected, setting initial state to Preparing");
             charger.set_state(ChargerState::Preparing).await;
         }
+        false if reservation::active().is_some() => {
+            info!("MAIN: Cable is not connected and a reservation is active, setting initial state to Reserved");
+            charger.set_state(ChargerState::Reserved).await;
+        }
         false => {
             info!("Cable is not connected, setting initial state to Available");
             charger.set_state(ChargerState::Available).await;
@@ -150,103 +361,465 @@ async fn main(spawner: Spawner) {
     let initial_publisher = charger::STATE_PUBSUB.publisher().unwrap();
     initial_publisher.publish_immediate((ChargerState::Available, heapless::Vec::new()));
 
-    // Load configuration from TOML file with environment variable overrides
-    let config = Config::from_config();
+    version::log_banner(&config);
+    if let Err((setting, e)) = config.validate_identifiers() {
+        error!("MAIN: Invalid {setting}: {e}");
+    }
     info!(
-        "MAIN: Charger configuration loaded: {}",
-        config.charger_name
+        "MAIN: Behavior profile: {}",
+        config.behavior_profile.as_str()
     );
+    ocpp_config::init(&config);
+    memory::init(config.memory_shed_order);
 
-    // Store values we need before config is moved
-    let ntp_server = config.ntp_server;
+    if let Some(ref mut display) = display_manager {
+        if let Err(e) = display.show(&AboutScreen { config: &config }) {
+            warn!("MAIN: Failed to draw about page: {e}");
+        }
+    }
 
-    info!("MAIN: Initializing network stack...");
-    let network =
-        network::NetworkStack::init(&spawner, timer1, rng, peripherals.WIFI, config).await;
-    let network = mk_static!(NetworkStack, network);
+    if let Err(e) =
+        data_transfer::register_handler(config.charger_vendor, Some("Inventory"), |_, _| {
+            DataTransferResponse::accepted(Some(version::inventory()))
+        })
+    {
+        warn!("MAIN: Failed to register inventory handler: {e}");
+    }
+    if let Err(e) =
+        data_transfer::register_handler(config.charger_vendor, Some("IoSnapshot"), |_, _| {
+            DataTransferResponse::accepted(Some(io_state::snapshot_json()))
+        })
+    {
+        warn!("MAIN: Failed to register I/O snapshot handler: {e}");
+    }
+    extensions::init(&config);
+    solar::init(&config);
+    waiting_list::init(&config);
+    demo::init(&config);
+    logging::init(&config);
+
+    fault::init(&config);
 
-    info!("MAIN: Waiting for network connection...");
-    network.wait_for_ip().await;
-    info!("MAIN: Network connected successfully");
+    let limits = mk_static!(CurrentLimits, CurrentLimits::new(&config));
+    spawner
+        .spawn(smart_charging::limit_watchdog_task(limits))
+        .ok();
+    spawner
+        .spawn(meter::meter_task(
+            charger,
+            limits,
+            config.supply_voltage,
+            config.supply_phases,
+        ))
+        .ok();
+    spawner.spawn(energy::energy_task()).ok();
+
+    // The meter IC's chip select or TX is on GPIO8, the RS-485 transceiver of a DIN rail meter on
+    // GPIO8 (RO), GPIO3 (DI) and GPIO10 (DE). The energy is estimated without one
+    let meter_ic: Result<Option<&'static mut dyn MeterIc>, &'static str> =
+        match MeterKind::parse(config.meter_type) {
+            Some(MeterKind::Hlw8032) => UartRx::new(
+                peripherals.UART1,
+                uart::Config::default()
+                    .with_baudrate(4800)
+                    .with_parity(Parity::Even),
+            )
+            .map_err(|_| "Failed to set up the HLW8032 UART")
+            .map(|rx| {
+                let meter: &'static mut dyn MeterIc = mk_static!(
+                    Hlw8032<UartRx<'static, Blocking>>,
+                    Hlw8032::new(rx.with_rx(peripherals.GPIO8))
+                );
+                Some(meter)
+            }),
+            Some(MeterKind::Atm90e32) => SpiRefCellDevice::new(
+                spi_bus,
+                Output::new(peripherals.GPIO8, Level::High, OutputConfig::default()),
+                Delay::new(),
+            )
+            .map_err(|_| "Failed to set up the ATM90E32 chip select")
+            .and_then(|spi| Atm90e32::new(spi, config.meter_constant))
+            .map(|atm| {
+                let meter: &'static mut dyn MeterIc = mk_static!(
+                    Atm90e32<
+                        SpiRefCellDevice<'static, Spi<'static, Blocking>, Output<'static>, Delay>,
+                    >,
+                    atm
+                );
+                Some(meter)
+            }),
+            #[cfg(not(feature = "factory-test"))]
+            Some(kind @ (MeterKind::Sdm120 | MeterKind::Sdm630 | MeterKind::Sdm72)) => Uart::new(
+                peripherals.UART1,
+                uart::Config::default().with_baudrate(u32::from(config.meter_baud)),
+            )
+            .map_err(|_| "Failed to set up the Modbus UART")
+            .and_then(|uart| {
+                ModbusMeter::new(
+                    uart.with_rx(peripherals.GPIO8).with_tx(peripherals.GPIO3),
+                    Output::new(peripherals.GPIO10, Level::Low, OutputConfig::default()),
+                    kind,
+                    config.meter_address,
+                )
+                .ok_or("Not a Modbus meter")
+            })
+            .map(|modbus| {
+                let meter: &'static mut dyn MeterIc = mk_static!(
+                    ModbusMeter<Uart<'static, Blocking>, Output<'static>>,
+                    modbus
+                );
+                Some(meter)
+            }),
+            // The load bank of the factory test is wired to the pins of the transceiver
+            #[cfg(feature = "factory-test")]
+            Some(MeterKind::Sdm120 | MeterKind::Sdm630 | MeterKind::Sdm72) => {
+                Err("The Modbus meter isn't available in a factory test build")
+            }
+            None => Ok(None),
+        };
+    match meter_ic {
+        Ok(Some(meter_ic)) => {
+            spawner
+                .spawn(metering::metering_task(meter_ic, Calibration::new(&config)))
+                .ok();
+        }
+        Ok(None) => {}
+        Err(e) => warn!("MAIN: {e}, estimating the energy"),
+    }
+    if config.soft_start_enabled {
+        spawner
+            .spawn(smart_charging::soft_start_task(
+                charger,
+                limits,
+                smart_charging::SoftStart::new(&config),
+            ))
+            .ok();
+    }
 
     // Start hardware-related tasks (can run independently of network)
-    spawner.spawn(charger_led_task(charger_led, charger)).ok();
+    spawner
+        .spawn(leds::led_task(
+            status_leds,
+            indicator,
+            charger,
+            config.accessibility_intensity,
+        ))
+        .ok();
+
+    // The LED PWM controller drives the buzzer and the pilot
+    let ledc = mk_static!(Ledc<'static>, Ledc::new(peripherals.LEDC));
+    ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+
+    let buzzer_volume = config
+        .buzzer_volume
+        .unwrap_or(config.accessibility_intensity);
+    if !config.buzzer_enabled {
+        info!("MAIN: Buzzer disabled");
+    } else {
+        match Buzzer::new(ledc, peripherals.GPIO5, buzzer_volume) {
+            Ok(buzzer) => {
+                spawner
+                    .spawn(buzzer::buzzer_task(
+                        buzzer,
+                        charger,
+                        config.buzzer_alarm_secs,
+                    ))
+                    .ok();
+            }
+            Err(e) => warn!("MAIN: Failed to initialize the buzzer: {e}"),
+        }
+    }
+
+    // GPIO7 is the display's data/command line with the `display-st7735` feature
+    #[cfg(not(feature = "display-st7735"))]
+    if config.pilot_pwm {
+        match PilotPwm::new(ledc, peripherals.GPIO7) {
+            Ok(pwm) => {
+                spawner
+                    .spawn(pilot::pilot_pwm_task(pwm, charger, limits))
+                    .ok();
+            }
+            Err(e) => warn!("MAIN: Failed to initialize the pilot PWM: {e}"),
+        }
+    }
+    #[cfg(feature = "display-st7735")]
+    if config.pilot_pwm {
+        warn!("MAIN: The pilot PWM shares GPIO7 with the display, not driving the pilot");
+    }
 
     spawner.spawn(cable_lock_task(cable_lock_pin)).ok();
 
     spawner.spawn(charger_cable_task(cable_switch)).ok();
 
-    spawner.spawn(card_swipe_task(spi_bus, sd_cs, charger)).ok();
+    if config.button_enabled {
+        spawner.spawn(button::button_task(button, charger)).ok();
+    }
 
-    spawner.spawn(charger_relay_task(charger_relay)).ok();
+    // The MFRC522's chip select is GPIO17, the reader is probed and brought up by its task
+    let card_reader: Option<&'static mut dyn CardReader> =
+        match ReaderKind::parse(config.rfid_reader) {
+            Some(ReaderKind::Mfrc522) => {
+                let reader: &'static mut dyn CardReader = mk_static!(
+                    Mfrc522Reader,
+                    Mfrc522Reader::new(
+                        spi_bus,
+                        SharedOutput(mk_static!(RefCell<Output<'static>>, RefCell::new(sd_cs))),
+                        config.rfid_gain_db,
+                    )
+                );
+                Some(reader)
+            }
+            Some(ReaderKind::Simulated) => {
+                let reader: &'static mut dyn CardReader =
+                    mk_static!(SimulatedReader, SimulatedReader::new());
+                Some(reader)
+            }
+            None => {
+                if !config.rfid_reader.is_empty() {
+                    warn!("MAIN: Unknown card reader {}", config.rfid_reader);
+                }
+                None
+            }
+        };
+    if let Some(card_reader) = card_reader {
+        spawner
+            .spawn(rfid::card_swipe_task(
+                card_reader,
+                charger,
+                Duration::from_millis(u64::from(config.rfid_quiet_ms)),
+                Duration::from_secs(u64::from(config.rfid_probe_secs)),
+            ))
+            .ok();
+    } else {
+        info!("MAIN: No card reader");
+    }
 
+    spawner.spawn(relay::relay_task(charger_relay)).ok();
     spawner
-        .spawn(charger::statemachine_handler_task(charger))
+        .spawn(relay::relay_deadline_task(config.relay_open_deadline_ms))
         .ok();
 
-    // Perform initial NTP time synchronization
-    info!("MAIN: Synchronizing time with NTP server...");
-    let mut sync_attempts = 0;
-    let max_sync_attempts = 3;
-
-    while !ntp::is_time_synced() && sync_attempts < max_sync_attempts {
-        sync_attempts += 1;
-        info!("MAIN: NTP sync attempt {sync_attempts} of {max_sync_attempts}");
-
-        match ntp::sync_time_with_ntp(network, ntp_server).await {
-            Ok(()) => {
-                info!("MAIN: NTP: Initial time synchronization successful");
-                info!("MAIN: NTP: Current time: {}", ntp::get_iso8601_time());
-                info!("MAIN: NTP: Timing info: {}", ntp::get_timing_info());
-                break;
-            }
-            Err(e) => {
-                warn!("MAIN: NTP: Sync attempt {sync_attempts} failed: {e}");
-                if sync_attempts < max_sync_attempts {
-                    Timer::after(Duration::from_secs(5)).await;
-                }
-            }
-        }
+    if let Some(relay_feedback) = relay_feedback {
+        spawner
+            .spawn(relay::relay_feedback_task(relay_feedback))
+            .ok();
+    }
+
+    if let Some(demand_response_input) = demand_response_input {
+        spawner
+            .spawn(demand_response::demand_response_task(
+                demand_response_input,
+                charger,
+                limits,
+                DemandResponse::new(&config),
+            ))
+            .ok();
     }
 
-    if !ntp::is_time_synced() {
-        warn!(
-            "MAIN: NTP: Failed to synchronize time after {max_sync_attempts} attempts, continuing anyway",
+    // Wired to a GPIO directly, a ground fault can't wait for the expander to be polled. The task
+    // runs on an interrupt executor above the main one, a blocking flash write in a task there
+    // can't hold up a trip
+    if config.rcd_enabled {
+        let rcd_input = Input::new(
+            peripherals.GPIO14,
+            InputConfig::default().with_pull(Pull::Up),
         );
+        let software_interrupts = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        let rcd_executor = mk_static!(
+            InterruptExecutor<2>,
+            InterruptExecutor::new(software_interrupts.software_interrupt2)
+        );
+        rcd_executor
+            .start(Priority::Priority3)
+            .spawn(rcd::rcd_task(rcd_input))
+            .ok();
     }
 
-    // Now start network-dependent tasks
-    info!("MAIN: Creating MQTT client...");
-    let rx_buffer = mk_static!([u8; 2048], [0; 2048]);
-    let tx_buffer = mk_static!([u8; 2048], [0; 2048]);
-    let write_buffer = mk_static!([u8; 2048], [0; 2048]);
-    let recv_buffer = mk_static!([u8; 2048], [0; 2048]);
+    spawner
+        .spawn(storage::storage_maintenance_task(charger))
+        .ok();
 
-    match network
-        .create_mqtt_client(rx_buffer, tx_buffer, write_buffer, recv_buffer)
-        .await
+    // ADC1 is shared by the pilot check and the NTC
+    let mut adc_config = AdcConfig::new();
+    let pilot_pin = config
+        .pilot_diode_check
+        .then(|| adc_config.enable_pin(peripherals.GPIO4, Attenuation::_11dB));
+    #[cfg(not(feature = "display-st7735"))]
+    let ntc_pin = config
+        .ntc_enabled
+        .then(|| adc_config.enable_pin(peripherals.GPIO6, Attenuation::_11dB));
+    #[cfg(feature = "display-st7735")]
+    let ntc_pin = {
+        if config.ntc_enabled {
+            warn!("MAIN: The NTC shares GPIO6 with the display's chip select, not reading it");
+        }
+        None
+    };
+    let adc: SharedAdc = mk_static!(
+        RefCell<Adc<'static, ADC1<'static>, Blocking>>,
+        RefCell::new(Adc::new(peripherals.ADC1, adc_config))
+    );
+
+    if let Some(pilot_pin) = pilot_pin {
+        spawner
+            .spawn(pilot::pilot_diode_task(charger, adc, pilot_pin))
+            .ok();
+    }
+
+    let chip_sensor = TemperatureSensor::new(peripherals.TSENS, tsens::Config::default())
+        .inspect_err(|e| warn!("MAIN: Failed to initialize the temperature sensor: {e:?}"))
+        .ok();
+    spawner
+        .spawn(sensors::sensors_task(
+            limits,
+            chip_sensor,
+            ntc_pin.map(|pin| (adc, pin)),
+            sensors::Thermal::new(&config),
+        ))
+        .ok();
+
+    spawner
+        .spawn(charger::statemachine_handler_task(charger))
+        .ok();
+    spawner.spawn(invariant::invariant_task(charger)).ok();
+    if config.watchdog_enabled {
+        spawner
+            .spawn(watchdog::watchdog_task(
+                TimerGroup::new(peripherals.TIMG1).wdt,
+                config.mqtt_stall_secs,
+                config.mqtt_stall_restarts,
+            ))
+            .ok();
+    } else {
+        warn!("MAIN: Watchdog disabled, a stalled task isn't recovered from");
+    }
+
+    #[cfg(feature = "factory-test")]
     {
-        Ok(client) => {
-            info!("MAIN: MQTT client created successfully");
-            let client = mk_static!(
-                MqttClient<'static, TcpSocket<'static>, 5, CountingRng>,
-                client
-            );
-            spawner.spawn(mqtt::mqtt_client_task(network, client)).ok();
+        let mut load_bank = LoadBank::new(
+            Output::new(peripherals.GPIO3, Level::Low, OutputConfig::default()),
+            [
+                Output::new(peripherals.GPIO10, Level::Low, OutputConfig::default()),
+                Output::new(peripherals.GPIO11, Level::Low, OutputConfig::default()),
+            ],
+            config.factory_stage_watts,
+        );
+        factory_test::run(charger, &mut load_bank, &config).await;
+        info!("MAIN: Factory test finished, not connecting to a backend");
+        loop {
+            Timer::after(Duration::from_secs(60)).await;
+        }
+    }
+
+    // Store values we need before config is moved
+    let ntp_server = config.ntp_server;
+
+    info!("MAIN: Initializing network stack...");
+    let network =
+        network::NetworkStack::init(&spawner, timer1, rng, peripherals.WIFI, config).await;
+    let network = mk_static!(NetworkStack, network);
+
+    if let Some(display) = display_manager.take() {
+        spawner
+            .spawn(display_task(display, charger, limits, network))
+            .ok();
+    }
+
+    if network.app_config.ble_provisioning || boot_hold == BootHold::Provisioning {
+        spawner
+            .spawn(ble_provisioning::ble_provisioning_task(
+                network.radio,
+                peripherals.BT,
+                &network.app_config,
+            ))
+            .ok();
+    }
+
+    // Before waiting for the network, settings on trial may be what keeps it from coming up
+    spawner.spawn(settings::settings_trial_task(charger)).ok();
+
+    // Also before it, the desktop tool sets up a charger that isn't online yet
+    if network.app_config.usb_api_enabled {
+        let (usb_rx, _) = UsbSerialJtag::new(peripherals.USB_DEVICE)
+            .into_async()
+            .split();
+        spawner
+            .spawn(usb_api::usb_api_task(network, charger, usb_rx))
+            .ok();
+    }
 
-            // Only start NTP sync task after MQTT client is successfully created
-            spawner.spawn(ntp::ntp_sync_task(network)).ok();
+    // A demo runs without the network, the simulated central system answers the OCPP calls
+    if demo::is_enabled() {
+        spawner.spawn(demo::demo_backend_task()).ok();
+    } else {
+        info!("MAIN: Waiting for network connection...");
+        network.wait_for_ip().await;
+        info!("MAIN: Network connected successfully");
+
+        if network.app_config.needs_onboarding() {
+            info!("MAIN: No backend configured, starting onboarding");
+            if onboarding::claim(network, &network.app_config).await {
+                info!("MAIN: Restarting with the provisioned configuration");
+                Timer::after(Duration::from_secs(1)).await;
+                esp_hal::system::software_reset();
+            }
+            warn!("MAIN: Onboarding not possible, continuing without backend");
         }
-        Err(e) => {
-            warn!("MAIN: Failed to create MQTT client: {e:?}");
-            // Could spawn a retry task here if needed
+
+        // Perform initial NTP time synchronization
+        info!("MAIN: Synchronizing time with NTP server...");
+        let mut sync_attempts = 0;
+        let max_sync_attempts = 3;
+
+        // Time from the RTC is only a head start, NTP is still synced at boot
+        while ntp::time_source() != ntp::TimeSource::Ntp && sync_attempts < max_sync_attempts {
+            sync_attempts += 1;
+            info!("MAIN: NTP sync attempt {sync_attempts} of {max_sync_attempts}");
+
+            match ntp::sync_time_with_ntp(network, ntp_server).await {
+                Ok(()) => {
+                    info!("MAIN: NTP: Initial time synchronization successful");
+                    info!("MAIN: NTP: Current time: {}", ntp::get_iso8601_time());
+                    info!("MAIN: NTP: Timing info: {}", ntp::get_timing_info());
+                    break;
+                }
+                Err(e) => {
+                    warn!("MAIN: NTP: Sync attempt {sync_attempts} failed: {e}");
+                    if sync_attempts < max_sync_attempts {
+                        Timer::after(Duration::from_secs(5)).await;
+                    }
+                }
+            }
         }
+
+        if ntp::time_source() != ntp::TimeSource::Ntp {
+            warn!(
+                "MAIN: NTP: Failed to synchronize time after {max_sync_attempts} attempts, continuing anyway",
+            );
+        }
+
+        // Now start network-dependent tasks
+        info!("MAIN: Creating MQTT client...");
+        spawner.spawn(mqtt::mqtt_client_task(network)).ok();
+
+        if mqtt::CONNECTION_SIGNAL.wait().await {
+            info!("MAIN: MQTT client created successfully");
+            ota::mark_valid();
+        } else {
+            warn!("MAIN: Failed to create MQTT client, the client task keeps retrying");
+        }
+
+        spawner.spawn(ntp::ntp_sync_task(network)).ok();
     }
 
-    // Start OCPP-related tasks
-    spawner.spawn(ocpp::response_handler_task(charger)).ok();
+    // Start OCPP-related tasks, they wait for the broker and the BootNotification themselves
+    spawner
+        .spawn(ocpp::response_handler_task(charger, limits))
+        .ok();
+    spawner.spawn(ocpp::deferred_call_task(charger)).ok();
 
     spawner.spawn(ocpp::heartbeat_task()).ok();
+    spawner.spawn(ocpp::meter_values_task(charger)).ok();
 
     spawner.spawn(ocpp::boot_notification_task()).ok();
 
@@ -254,25 +827,95 @@ async fn main(spawner: Spawner) {
 
     spawner.spawn(ocpp::authorize_task(charger)).ok();
 
-    spawner.spawn(ocpp::transaction_handler_task(charger)).ok();
+    spawner
+        .spawn(ocpp::transaction_handler_task(charger, limits))
+        .ok();
+
+    spawner.spawn(idempotency::start_retry_task()).ok();
+
+    spawner
+        .spawn(diagnostics::diagnostics_upload_task(network))
+        .ok();
+
+    spawner
+        .spawn(ota::firmware_update_task(network, charger))
+        .ok();
+
+    spawner.spawn(reservation::reservation_expiry_task()).ok();
+
+    spawner.spawn(maintenance::maintenance_expiry_task()).ok();
+    if !demo::is_enabled() {
+        spawner
+            .spawn(connectivity::connectivity_watcher_task(network))
+            .ok();
+    }
+
+    spawner.spawn(command::command_handler_task(charger)).ok();
+
+    if network.app_config.telemetry_enabled {
+        spawner
+            .spawn(telemetry::telemetry_task(
+                charger,
+                network.app_config.telemetry_interval_secs,
+                network.app_config.telemetry_anonymized,
+            ))
+            .ok();
+        if network.app_config.analytics_enabled() {
+            spawner.spawn(mqtt::analytics_client_task(network)).ok();
+        }
+    }
+
+    if network.app_config.load_management_enabled {
+        if network.app_config.site_id.is_empty() {
+            warn!("MAIN: Load management needs a site id, not sharing the site limit");
+        } else {
+            spawner
+                .spawn(load_management::load_management_task(
+                    charger,
+                    limits,
+                    LoadManagement::new(&network.app_config),
+                ))
+                .ok();
+        }
+    }
+
+    if network.app_config.solar_enabled {
+        spawner
+            .spawn(solar::solar_task(
+                charger,
+                limits,
+                Solar::new(&network.app_config),
+            ))
+            .ok();
+    }
+
+    if network.app_config.waiting_list_enabled {
+        spawner.spawn(waiting_list::waiting_list_task(charger)).ok();
+    }
+
+    spawner
+        .spawn(http_server::http_server_task(network, charger))
+        .ok();
+    if network.app_config.mdns_enabled {
+        spawner.spawn(mdns::mdns_task(network)).ok();
+    }
+    if network.app_config.webhook_enabled() {
+        spawner.spawn(webhook::webhook_task(network)).ok();
+    }
+    if network.app_config.log_forward_enabled() {
+        spawner.spawn(log_forward::log_forward_task(network)).ok();
+    }
 
     let mut old_state = charger.get_state().await;
-    let mut last_display_update = Instant::now();
 
     info!("MAIN: Starting main loop...");
     loop {
-        if let Some(ref mut display) = display_manager {
-            if last_display_update.elapsed() >= Duration::from_millis(900) {
-                let temp_config = Config::from_config();
-                match display.update_display(&temp_config, network, old_state) {
-                    Ok(()) => {
-                        // Display updated successfully
-                    }
-                    Err(e) => {
-                        warn!("MAIN: Failed to update display: {e}");
-                    }
-                }
-                last_display_update = Instant::now();
+        // Keep the time in the RTC after each NTP sync
+        if let Some(ref mut rtc) = rtc {
+            let ntp_syncs = ntp::ntp_syncs();
+            if ntp_syncs != rtc_ntp_syncs {
+                rtc_ntp_syncs = ntp_syncs;
+                rtc.store_time(ntp::get_current_unix_time());
             }
         }
 
@@ -285,106 +928,211 @@ async fn main(spawner: Spawner) {
     }
 }
 
-/// Maps charger states to corresponding LED colors
-fn get_led_color_for_state(state: ChargerState) -> Option<RGB8> {
-    match state {
-        ChargerState::Off => None,                 // LED off
-        ChargerState::Available => Some(GREEN),    // Green = Ready to charge
-        ChargerState::Preparing => Some(WHITE),    // White = Preparing to charge
-        ChargerState::Charging => Some(BLUE),      // Blue = Charging in progress
-        ChargerState::Authorizing => Some(ORANGE), // Orange = Authorizing user
-        ChargerState::Faulted => Some(RED),        // Red = Error/fault condition
-    }
-}
-
-/// Task to control the WS2812B RGB LED based on the charging state
+/// The OLED display on the I2C bus it shares with the RTC
+#[cfg(not(any(feature = "display-sh1106", feature = "display-st7735")))]
+type Panel = panel::Ssd1306Panel<RefCellDevice<'static, I2c<'static, Async>>>;
+#[cfg(feature = "display-sh1106")]
+type Panel = panel::Sh1106<RefCellDevice<'static, I2c<'static, Async>>>;
+/// The TFT display on the SPI bus it shares with the card reader
+#[cfg(feature = "display-st7735")]
+type Panel = panel::St7735<
+    SpiRefCellDevice<'static, Spi<'static, Blocking>, Output<'static>, Delay>,
+    Output<'static>,
+>;
+type Display = DisplayManager<Panel>;
+
+/// Heap for the broker connection buffers and the other allocations
+const HEAP_SIZE: usize = 64 * 1024;
+
+// The rest of the RAM budgets are checked in the budget module
+const _: () = assert!(
+    size_of::<Panel>() <= budget::DISPLAY,
+    "The display exceeds the display budget in memory_budget.toml"
+);
+const _: () = assert!(
+    HEAP_SIZE <= budget::HEAP,
+    "The heap exceeds the heap budget in memory_budget.toml"
+);
+const _: () = assert!(
+    budget::STATIC_USED + size_of::<Panel>() + HEAP_SIZE <= budget::TOTAL,
+    "The RAM in total exceeds the total budget in memory_budget.toml"
+);
+
+/// Task to draw the display pages, redrawn on a state change, a display event and every second
+/// for the clock, the running transaction and the page rotation
 #[embassy_executor::task]
-async fn charger_led_task(
-    charger_led: &'static mut SmartLedsAdapter<
-        esp_hal::rmt::ConstChannelAccess<esp_hal::rmt::Tx, 0>,
-        25,
-    >,
+async fn display_task(
+    mut display: Display,
     charger: &'static Charger,
+    limits: &'static CurrentLimits,
+    network: &'static NetworkStack,
 ) {
-    info!("TASK: Started WS2812B RGB LED Charger Status Indicator");
-
-    // Set initial LED color based on current charger state
-    let initial_state = charger.get_state().await;
-    info!(
-        "LED: Setting initial color for state: {}",
-        initial_state.as_str()
-    );
-
-    match get_led_color_for_state(initial_state) {
-        Some(color) => {
-            let brightness_level = 20; // Adjust brightness (0-255)
-            info!(
-                "LED: Initial color RGB({}, {}, {}) for state: {}",
-                color.r,
-                color.g,
-                color.b,
-                initial_state.as_str()
-            );
-
-            if let Err(e) = charger_led.write(brightness([color].into_iter(), brightness_level)) {
-                warn!("LED: Failed to set initial color: {e:?}");
-            }
-        }
-        None => {
-            // Turn off LED for Off state
-            info!(
-                "LED: Turning off LED for initial state: {}",
-                initial_state.as_str()
-            );
-            let off_color = RGB8::new(0, 0, 0);
-            if let Err(e) = charger_led.write([off_color].into_iter()) {
-                warn!("LED: Failed to turn off LED initially: {e:?}");
-            }
-        }
-    }
+    info!("TASK: Started Display Handler");
 
+    let config = &network.app_config;
     let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
+    let mut state = charger.get_state().await;
+    let mut last_page_switch = Instant::now();
+    let mut page_index = 0;
+    let mut shown_page = DisplayPages::STATUS;
+    let mut charging_since = Instant::now();
+    let mut transaction = (0, heapless::String::<32>::new());
+    let mut banner: Option<(Banner, Instant)> = None;
+    let mut last_activity = Instant::now();
+    let mut power = DisplayPower::On;
 
     loop {
-        // Wait for state changes via PubSub
-        if let embassy_sync::pubsub::WaitResult::Message((current_state, _)) =
-            subscriber.next_message().await
+        match select3(
+            subscriber.next_message_pure(),
+            display::DISPLAY_CHANNEL.receive(),
+            Timer::after(Duration::from_secs(1)),
+        )
+        .await
         {
-            info!("LED: Charger state changed to: {}", current_state.as_str());
-
-            match get_led_color_for_state(current_state) {
-                Some(color) => {
-                    let brightness_level = 20; // Adjust brightness (0-255)
-                    info!(
-                        "LED: Setting color RGB({}, {}, {}) for state: {}",
-                        color.r,
-                        color.g,
-                        color.b,
-                        current_state.as_str()
-                    );
-
-                    if let Err(e) =
-                        charger_led.write(brightness([color].into_iter(), brightness_level))
-                    {
-                        warn!("LED: Failed to set color: {e:?}");
-                    }
+            Either3::First((new_state, output_events)) => {
+                last_activity = Instant::now();
+                if new_state == ChargerState::Charging && state != ChargerState::Charging {
+                    charging_since = Instant::now();
                 }
-                None => {
-                    // Turn off LED for Off state
-                    info!("LED: Turning off LED for state: {}", current_state.as_str());
-                    let off_color = RGB8::new(0, 0, 0);
-                    if let Err(e) = charger_led.write([off_color].into_iter()) {
-                        warn!("LED: Failed to turn off LED: {e:?}");
+                if let Some(new_banner) = Banner::for_events(&output_events) {
+                    banner = Some((new_banner, Instant::now()));
+                }
+                state = new_state;
+            }
+            Either3::Second(event) => {
+                last_activity = Instant::now();
+                match event {
+                    DisplayEvent::NextPage => {
+                        page_index += 1;
+                        last_page_switch = Instant::now();
+                        banner = None;
+                    }
+                    DisplayEvent::ConfirmStop => {
+                        banner = Some((Banner::ConfirmStop, Instant::now()));
+                    }
+                    DisplayEvent::Queued(position) => {
+                        banner = Some((Banner::for_position(position), Instant::now()));
                     }
+                    _ => {}
                 }
             }
+            Either3::Third(()) => {}
+        }
+
+        // Dimmed and then off while idle in Available, against burn-in of the OLED
+        let idle_mins = last_activity.elapsed().as_secs() / 60;
+        let new_power = match state {
+            ChargerState::Available
+                if config.display_off_mins > 0 && idle_mins >= config.display_off_mins.into() =>
+            {
+                DisplayPower::Off
+            }
+            ChargerState::Available
+                if config.display_dim_mins > 0 && idle_mins >= config.display_dim_mins.into() =>
+            {
+                DisplayPower::Dimmed
+            }
+            // Dimmed during the quiet hours, unless somebody is at the charger
+            _ if quiet::is_quiet(config) => DisplayPower::Dimmed,
+            _ => DisplayPower::On,
+        };
+        if new_power != power {
+            info!("DISP: Display {new_power:?} after {idle_mins}min idle");
+            match display.set_power(new_power) {
+                Ok(()) => power = new_power,
+                Err(e) => warn!("DISP: {e}"),
+            }
+        }
+        if power == DisplayPower::Off {
+            continue;
+        }
+
+        // Only the status page is left after running out of memory
+        let pages = if memory::is_enabled(Feature::DisplayPages) {
+            config.behavior.display_pages
+        } else {
+            DisplayPages::STATUS
+        };
+        if last_page_switch.elapsed() >= Duration::from_secs(DISPLAY_PAGE_SECS) {
+            page_index += 1;
+            last_page_switch = Instant::now();
+        }
+        let qr_url = QrCodeScreen::url(config);
+        let applies = |page: &DisplayPages| match *page {
+            DisplayPages::TRANSACTION => state == ChargerState::Charging,
+            DisplayPages::ERROR => state == ChargerState::Faulted || fault::lockout().is_some(),
+            DisplayPages::QR_CODE => state == ChargerState::Available && qr_url.is_some(),
+            _ => true,
+        };
+        let page_count = pages.enabled().filter(applies).count().max(1);
+        let page = pages
+            .enabled()
+            .filter(applies)
+            .nth(page_index % page_count)
+            .unwrap_or(DisplayPages::STATUS);
+        // Reading the transaction logs, so it's read when its page comes up
+        if page == DisplayPages::TRANSACTION && shown_page != page {
+            transaction = (
+                charger.get_transaction_id().await,
+                charger.get_id_tag().await,
+            );
+        }
+        shown_page = page;
+
+        banner =
+            banner.filter(|(banner, shown)| shown.elapsed() < Duration::from_secs(banner.secs()));
+
+        // A firmware update takes over the display until the restart
+        let result = if let Some(progress) = ota::progress() {
+            display.show(&UpdateScreen {
+                progress: &progress,
+            })
+        } else if let Some((banner, _)) = banner {
+            display.show(&banner)
+        } else {
+            match page {
+                DisplayPages::ABOUT => display.show(&AboutScreen { config }),
+                DisplayPages::SESSIONS => display.show(&SessionsScreen {
+                    config,
+                    stats: &sessions::stats(),
+                }),
+                DisplayPages::NETWORK => display.show(&NetworkScreen { network }),
+                DisplayPages::TRANSACTION => display.show(&TransactionScreen {
+                    config,
+                    transaction_id: transaction.0,
+                    id_tag: &transaction.1,
+                    duration_secs: charging_since.elapsed().as_secs(),
+                    limit_amps: limits.effective_limit().await,
+                    energy_wh: meter::energy_wh().unwrap_or(0),
+                    watts: meter::measurement().map(|measurement| measurement.watts as u32),
+                }),
+                DisplayPages::ERROR => display.show(&ErrorScreen {
+                    state,
+                    fault: fault::last_fault(),
+                    locked_out: fault::lockout().is_some(),
+                }),
+                DisplayPages::QR_CODE => display.show(&QrCodeScreen {
+                    url: qr_url.as_deref().unwrap_or_default(),
+                    serial: config.charger_serial,
+                }),
+                _ => display.show(&StatusScreen {
+                    config,
+                    network,
+                    state,
+                }),
+            }
+        };
+        if let Err(e) = result {
+            warn!("DISP: Failed to update display: {e}");
         }
     }
 }
 
+const DISPLAY_PAGE_SECS: u64 = 5; // Time each display page is shown when pages rotate
+
 /// Task to detect charger cable connection and disconnection
 #[embassy_executor::task]
-async fn charger_cable_task(mut button: Input<'static>) {
+async fn charger_cable_task(mut button: MappedInput) {
     info!("TASK: Started Charger cable Detector");
 
     loop {
@@ -392,6 +1140,8 @@ async fn charger_cable_task(mut button: Input<'static>) {
 
         Timer::after(Duration::from_millis(300)).await; // Debounce delay
         let new_state = button.is_low();
+        io_state::record_cable(new_state);
+        sequence::record(Transition::Cable(new_state));
 
         // Send the appropriate event based on the new state
         let cable_event = if new_state {
@@ -405,39 +1155,9 @@ async fn charger_cable_task(mut button: Input<'static>) {
     }
 }
 
-/// Task to control the charger relay based on the charging state
-#[embassy_executor::task]
-async fn charger_relay_task(mut relay: Output<'static>) {
-    info!("TASK: Started Charger relay control");
-
-    let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
-
-    relay.set_low();
-    info!("RLAY: Initial state set to low (off)");
-
-    loop {
-        // Wait for state changes via PubSub
-        if let embassy_sync::pubsub::WaitResult::Message((current_state, output_events)) =
-            subscriber.next_message().await
-        {
-            // Simple logic: turn on relay when charging, off otherwise
-            match current_state {
-                ChargerState::Charging if output_events.contains(&OutputEvent::ApplyPower) => {
-                    info!("RLAY: Setting relay high (on)");
-                    relay.set_high();
-                }
-                _ => {
-                    info!("RLAY: Setting relay low (off)");
-                    relay.set_low();
-                }
-            }
-        }
-    }
-}
-
 /// Task to control the cable lock based on the charging state
 #[embassy_executor::task]
-async fn cable_lock_task(mut cable_lock_pin: Output<'static>) {
+async fn cable_lock_task(mut cable_lock_pin: MappedOutput) {
     info!("TASK: Started Cable Lock Control");
     let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
 
@@ -447,8 +1167,14 @@ async fn cable_lock_task(mut cable_lock_pin: Output<'static>) {
         {
             match current_state {
                 _ if output_events.contains(&OutputEvent::Lock) => {
+                    if !invariant::check(Invariant::LockOnlyWithCable, io_state::cable_connected())
+                    {
+                        continue;
+                    }
                     info!("LOCK: Locking cable for charging state");
                     cable_lock_pin.set_high();
+                    io_state::record_lock(true);
+                    sequence::record(Transition::Lock(true));
                 }
                 _ if output_events.contains(&OutputEvent::Unlock) => {
                     info!(
@@ -456,6 +1182,8 @@ async fn cable_lock_task(mut cable_lock_pin: Output<'static>) {
                         current_state.as_str()
                     );
                     cable_lock_pin.set_low();
+                    io_state::record_lock(false);
+                    sequence::record(Transition::Lock(false));
                 }
                 _ => {
                     info!("LOCK: No action for state: {}", current_state.as_str());
@@ -464,38 +1192,3 @@ async fn cable_lock_task(mut cable_lock_pin: Output<'static>) {
         }
     }
 }
-
-/// Task to handle card swipe events using the MFRC522 RFID reader
-#[embassy_executor::task]
-async fn card_swipe_task(
-    spi_bus: Spi<'static, Blocking>,
-    sd_cs: Output<'static>,
-    charger: &'static Charger,
-) {
-    info!("TASK: Started Card Swipe Detector");
-
-    let delay = Delay::new();
-    let spi_dev = ExclusiveDevice::new(spi_bus, sd_cs, delay).unwrap();
-    let spi_interface = SpiInterface::new(spi_dev);
-    let mut rfid_reader = Mfrc522::new(spi_interface).init().unwrap();
-
-    loop {
-        if let Ok(atqa) = rfid_reader.reqa() {
-            info!("RFID: Card swipe detected");
-            Timer::after(Duration::from_millis(50)).await;
-            if let Ok(uid) = rfid_reader.select(&atqa) {
-                let hex = utils::bytes_to_hex_string::<24>(uid.as_bytes());
-                info!("RFID: UID {hex}");
-
-                charger.set_id_tag(&hex).await;
-
-                charger::STATE_IN_CHANNEL
-                    .send(InputEvent::SwipeDetected)
-                    .await;
-                Timer::after(Duration::from_millis(500)).await;
-            }
-        }
-
-        Timer::after(Duration::from_secs(1)).await;
-    }
-}
@@ -0,0 +1,194 @@
+use bleps::{
+    ad_structure::{
+        create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+    },
+    async_attribute_server::AttributeServer,
+    asynch::Ble,
+    attribute_server::NotificationData,
+    gatt,
+};
+use core::cell::RefCell;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::peripherals::BT;
+use esp_wifi::{ble::controller::BleConnector, EspWifiController};
+
+use crate::{
+    config::Config,
+    info,
+    settings::{self, PendingSettings},
+    warn,
+};
+
+/// Setting written through one of the characteristics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    WifiSsid,
+    WifiPassword,
+    MqttBroker,
+    ChargerSerial,
+}
+
+impl Field {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::WifiSsid => "WiFi SSID",
+            Self::WifiPassword => "WiFi password",
+            Self::MqttBroker => "MQTT broker",
+            Self::ChargerSerial => "charger serial",
+        }
+    }
+}
+
+/// Store a written value in the pending settings, a long value can arrive in parts
+fn write_field(pending: &RefCell<PendingSettings>, field: Field, offset: usize, data: &[u8]) {
+    let mut pending = pending.borrow_mut();
+    let value = match field {
+        Field::WifiSsid => &mut pending.wifi_ssid,
+        Field::WifiPassword => &mut pending.wifi_password,
+        Field::MqttBroker => &mut pending.mqtt_broker,
+        Field::ChargerSerial => &mut pending.charger_serial,
+    };
+    let result = settings::validate_value(data).and_then(|data| {
+        if offset != value.len() {
+            value.clear();
+        }
+        value.push_str(data).map_err(|_| "is too long")
+    });
+    match result {
+        Ok(()) if field == Field::WifiPassword => info!("BLE : {} set", field.as_str()),
+        Ok(()) => info!("BLE : {} set to {value}", field.as_str()),
+        Err(e) => {
+            warn!("BLE : Rejected {}: {e}", field.as_str());
+            value.clear();
+        }
+    }
+}
+
+/// Copy a pending value to a read response
+fn read_field(value: &str, offset: usize, data: &mut [u8]) -> usize {
+    let bytes = value.as_bytes().get(offset..).unwrap_or_default();
+    let len = bytes.len().min(data.len());
+    data[..len].copy_from_slice(&bytes[..len]);
+    len
+}
+
+/// Task offering a GATT service to configure the WiFi, broker and serial from a phone, for a
+/// number of minutes after boot. Writing the save characteristic stores the settings and
+/// restarts the charger with them
+#[embassy_executor::task]
+pub async fn ble_provisioning_task(
+    radio: &'static EspWifiController<'static>,
+    bt: BT<'static>,
+    config: &'static Config,
+) {
+    info!(
+        "TASK: Started BLE Provisioning for {} minutes",
+        config.ble_window_mins
+    );
+
+    let connector = BleConnector::new(radio, bt);
+    let mut ble = Ble::new(connector, || Instant::now().as_millis());
+    if ble.init().await.is_err() {
+        warn!("BLE : Failed to initialize the controller");
+        return;
+    }
+
+    let mut name = heapless::String::<29>::new();
+    for c in "Charger "
+        .chars()
+        .chain(config.charger_serial.chars())
+        .take(name.capacity())
+    {
+        let _ = name.push(c);
+    }
+    let advertising = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::CompleteLocalName(&name),
+    ]);
+    let advertising_started = match advertising {
+        Ok(advertising) => {
+            ble.cmd_set_le_advertising_parameters().await.is_ok()
+                && ble.cmd_set_le_advertising_data(advertising).await.is_ok()
+                && ble.cmd_set_le_advertise_enable(true).await.is_ok()
+        }
+        Err(_) => false,
+    };
+    if !advertising_started {
+        warn!("BLE : Failed to start advertising");
+        return;
+    }
+    info!("BLE : Advertising as \"{name}\"");
+
+    let pending = RefCell::new(PendingSettings::default());
+
+    let mut ssid_read =
+        |offset: usize, data: &mut [u8]| read_field(&pending.borrow().wifi_ssid, offset, data);
+    let mut ssid_write =
+        |offset: usize, data: &[u8]| write_field(&pending, Field::WifiSsid, offset, data);
+    let mut password_write =
+        |offset: usize, data: &[u8]| write_field(&pending, Field::WifiPassword, offset, data);
+    let mut broker_read =
+        |offset: usize, data: &mut [u8]| read_field(&pending.borrow().mqtt_broker, offset, data);
+    let mut broker_write =
+        |offset: usize, data: &[u8]| write_field(&pending, Field::MqttBroker, offset, data);
+    let mut serial_read =
+        |offset: usize, data: &mut [u8]| read_field(&pending.borrow().charger_serial, offset, data);
+    let mut serial_write =
+        |offset: usize, data: &[u8]| write_field(&pending, Field::ChargerSerial, offset, data);
+    let mut save_write = |_offset: usize, _data: &[u8]| match pending.borrow().save() {
+        Ok(()) => {
+            info!("BLE : Settings saved, restarting");
+            esp_hal::system::software_reset();
+        }
+        Err(e) => warn!("BLE : Failed to save settings: {e}"),
+    };
+
+    gatt!([service {
+        uuid: "6e400001-c0de-4c68-8a5e-636861726765",
+        characteristics: [
+            characteristic {
+                uuid: "6e400002-c0de-4c68-8a5e-636861726765",
+                read: ssid_read,
+                write: ssid_write,
+            },
+            characteristic {
+                uuid: "6e400003-c0de-4c68-8a5e-636861726765",
+                write: password_write,
+            },
+            characteristic {
+                uuid: "6e400004-c0de-4c68-8a5e-636861726765",
+                read: broker_read,
+                write: broker_write,
+            },
+            characteristic {
+                uuid: "6e400005-c0de-4c68-8a5e-636861726765",
+                read: serial_read,
+                write: serial_write,
+            },
+            characteristic {
+                uuid: "6e400006-c0de-4c68-8a5e-636861726765",
+                write: save_write,
+            },
+        ],
+    },]);
+
+    let mut rng = bleps::no_rng::NoRng;
+    let mut server = AttributeServer::new(&mut ble, &mut gatt_attributes, &mut rng);
+    // The service only answers reads and writes, it never notifies
+    let mut notifier = || core::future::pending::<NotificationData>();
+
+    let window = Duration::from_secs(u64::from(config.ble_window_mins) * 60);
+    match embassy_time::with_timeout(window, server.run(&mut notifier)).await {
+        Ok(Err(e)) => warn!("BLE : Provisioning service stopped: {e:?}"),
+        _ => info!("BLE : Provisioning window ended"),
+    }
+    drop(server);
+
+    if ble.cmd_set_le_advertise_enable(false).await.is_err() {
+        warn!("BLE : Failed to stop advertising");
+    }
+    // Keep the task alive, the controller is released when the charger restarts
+    loop {
+        Timer::after(Duration::from_secs(3600)).await;
+    }
+}
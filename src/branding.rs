@@ -0,0 +1,178 @@
+use crate::{
+    info,
+    storage::{self, Slot},
+    utils, warn,
+};
+
+pub const LOGO_WIDTH: u32 = 128;
+pub const LOGO_HEIGHT: u32 = 64;
+/// One bit per pixel, rows from top to bottom, the most significant bit is the leftmost pixel
+pub const LOGO_SIZE: usize = (LOGO_WIDTH * LOGO_HEIGHT / 8) as usize;
+
+pub type Logo = [u8; LOGO_SIZE];
+
+/// The logo uploaded for this deployment, None to show the built-in logo
+pub fn load() -> Option<Logo> {
+    let mut logo = [0u8; LOGO_SIZE];
+    match storage::read(Slot::Logo, &mut logo) {
+        Ok(Some(LOGO_SIZE)) => Some(logo),
+        Ok(Some(len)) => {
+            warn!("LOGO: Stored logo has {len} bytes instead of {LOGO_SIZE}");
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("LOGO: Failed to read logo: {e}");
+            None
+        }
+    }
+}
+
+/// Store a logo, it's shown from the next boot
+pub fn store(logo: &Logo) -> Result<(), &'static str> {
+    storage::write(Slot::Logo, logo)?;
+    info!("LOGO: Stored a new logo, shown from the next boot");
+    Ok(())
+}
+
+/// Go back to the built-in logo
+pub fn reset() -> Result<(), &'static str> {
+    storage::erase(Slot::Logo)?;
+    info!("LOGO: Removed the stored logo, the built-in logo is shown from the next boot");
+    Ok(())
+}
+
+/// Decode a raw bitmap sent as base64, e.g. in a command on the cmd topic
+pub fn from_base64(encoded: &str) -> Result<Logo, &'static str> {
+    let bytes = utils::base64_decode::<LOGO_SIZE>(encoded).ok_or("Invalid base64 logo")?;
+    bytes.into_array().map_err(|_| "Logo is not 128x64")
+}
+
+/// Format of an uploaded logo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogoFormat {
+    /// The bitmap as stored, 1024 bytes
+    Raw,
+    /// C source of an XBM image, as exported by most image editors
+    Xbm,
+}
+
+/// Decoder of an uploaded logo, fed in chunks as it's received
+pub enum LogoDecoder {
+    Raw(Logo, usize),
+    Xbm(XbmDecoder),
+}
+
+impl LogoDecoder {
+    pub fn new(format: LogoFormat) -> Self {
+        match format {
+            LogoFormat::Raw => Self::Raw([0; LOGO_SIZE], 0),
+            LogoFormat::Xbm => Self::Xbm(XbmDecoder::new()),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        match self {
+            Self::Raw(logo, len) => {
+                let end = *len + data.len();
+                logo.get_mut(*len..end)
+                    .ok_or("Raw logo is larger than 128x64")?
+                    .copy_from_slice(data);
+                *len = end;
+                Ok(())
+            }
+            Self::Xbm(decoder) => decoder.feed(data),
+        }
+    }
+
+    pub fn finish(self) -> Result<Logo, &'static str> {
+        match self {
+            Self::Raw(logo, LOGO_SIZE) => Ok(logo),
+            Self::Raw(..) => Err("Raw logo is not 128x64"),
+            Self::Xbm(decoder) => decoder.finish(),
+        }
+    }
+}
+
+/// Decoder for the C source of an XBM image, fed in chunks as it's received
+/// XBM stores the leftmost pixel in the least significant bit, the bytes are mirrored
+pub struct XbmDecoder {
+    logo: Logo,
+    len: usize,
+    /// Value of the hex literal being read, with its number of digits
+    hex: Option<(u8, u8)>,
+    /// The previous character was a `0` that may start a hex literal
+    after_zero: bool,
+    /// The previous character was part of an identifier or number
+    in_word: bool,
+}
+
+impl Default for XbmDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl XbmDecoder {
+    pub fn new() -> Self {
+        Self {
+            logo: [0; LOGO_SIZE],
+            len: 0,
+            hex: None,
+            after_zero: false,
+            in_word: false,
+        }
+    }
+
+    fn push(&mut self, value: u8) -> Result<(), &'static str> {
+        let byte = self
+            .logo
+            .get_mut(self.len)
+            .ok_or("XBM is larger than 128x64")?;
+        *byte = value.reverse_bits();
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Collect the hex literals of the pixel data, the defines and declaration are skipped
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        for &c in data {
+            if let Some((value, digits)) = self.hex {
+                if let Some(digit) = char::from(c).to_digit(16) {
+                    if digits == 2 {
+                        return Err("Invalid XBM pixel value");
+                    }
+                    self.hex = Some((value << 4 | digit as u8, digits + 1));
+                    continue;
+                }
+                if digits == 0 {
+                    return Err("Invalid XBM pixel value");
+                }
+                self.push(value)?;
+                self.hex = None;
+            }
+            if self.after_zero && (c == b'x' || c == b'X') {
+                self.hex = Some((0, 0));
+                self.after_zero = false;
+                self.in_word = true;
+                continue;
+            }
+            self.after_zero = c == b'0' && !self.in_word;
+            self.in_word = c.is_ascii_alphanumeric() || c == b'_';
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Logo, &'static str> {
+        if let Some((value, digits)) = self.hex.take() {
+            if digits == 0 {
+                return Err("Invalid XBM pixel value");
+            }
+            self.push(value)?;
+        }
+        if self.len != LOGO_SIZE {
+            return Err("XBM is not 128x64");
+        }
+        Ok(self.logo)
+    }
+}
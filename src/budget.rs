@@ -0,0 +1,61 @@
+//! RAM budgets from `memory_budget.toml`, summed by the build script for the enabled features.
+//! The statics are checked against them when the firmware is built, so a larger queue or buffer
+//! fails the build with the area it took from instead of a link error or an allocation failure
+//! on the charger. The display and the heap are checked in the binary, which picks the display
+
+use crate::{guest, logging, mqtt, ocpp, ocpp_config, onboarding, settings};
+
+/// A budget set by the build script
+const fn bytes(value: &str) -> usize {
+    match usize::from_str_radix(value, 10) {
+        Ok(bytes) => bytes,
+        Err(_) => panic!("A budget in memory_budget.toml is not a number of bytes"),
+    }
+}
+
+/// Message queues between the tasks
+pub const CHANNELS: usize = bytes(env!("CHARGER_BUDGET_CHANNELS"));
+/// Log ring buffer and the lines waiting for the forwarder
+pub const LOGGING: usize = bytes(env!("CHARGER_BUDGET_LOGGING"));
+/// Buffers and caches of the records kept in flash
+pub const STORAGE: usize = bytes(env!("CHARGER_BUDGET_STORAGE"));
+/// Display driver and its framebuffer
+pub const DISPLAY: usize = bytes(env!("CHARGER_BUDGET_DISPLAY"));
+/// Buffers of the broker connection, taken from the heap while connected
+pub const CONNECTION: usize = bytes(env!("CHARGER_BUDGET_CONNECTION"));
+/// Heap for the connection buffers and the other allocations
+pub const HEAP: usize = bytes(env!("CHARGER_BUDGET_HEAP"));
+/// All of the above, the rest of the RAM is left to WiFi, BLE and the task stacks
+pub const TOTAL: usize = bytes(env!("CHARGER_BUDGET_TOTAL"));
+
+pub const CHANNELS_USED: usize = mqtt::RAM + ocpp::RAM;
+pub const LOGGING_USED: usize = logging::RAM;
+pub const STORAGE_USED: usize = settings::RAM + onboarding::RAM + guest::RAM + ocpp_config::RAM;
+/// Up buffer of defmt-rtt, its default size
+#[cfg(feature = "defmt")]
+const RTT_USED: usize = 1024;
+#[cfg(not(feature = "defmt"))]
+const RTT_USED: usize = 0;
+/// Statics counted in the total besides the display
+pub const STATIC_USED: usize = CHANNELS_USED + LOGGING_USED + STORAGE_USED + RTT_USED;
+
+const _: () = assert!(
+    CHANNELS_USED <= CHANNELS,
+    "The message queues exceed the channels budget in memory_budget.toml"
+);
+const _: () = assert!(
+    LOGGING_USED <= LOGGING,
+    "The log buffers exceed the logging budget in memory_budget.toml"
+);
+const _: () = assert!(
+    STORAGE_USED <= STORAGE,
+    "The storage buffers exceed the storage budget in memory_budget.toml"
+);
+const _: () = assert!(
+    mqtt::CONNECTION_RAM <= CONNECTION,
+    "The broker connection buffers exceed the connection budget in memory_budget.toml"
+);
+const _: () = assert!(
+    CONNECTION <= HEAP,
+    "The connection budget in memory_budget.toml doesn't fit the heap budget"
+);
@@ -0,0 +1,153 @@
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::{
+    charger::{self, Charger, ChargerState, InputEvent},
+    display::{self, DisplayEvent},
+    info,
+    pins::MappedInput,
+    quiet, solar,
+    storage::{self, Slot},
+    warn,
+};
+
+/// Time the level has to stay the same to count, against contact bounce
+const DEBOUNCE_MS: u64 = 30;
+/// Time after a release in which another press adds to the clicks
+const MULTI_CLICK_MS: u64 = 400;
+/// A press held longer than this isn't a click
+const LONG_PRESS_MS: u64 = 1500;
+/// Time a stop asked for with a double press waits for the confirming press
+pub const STOP_CONFIRM_SECS: u64 = 5;
+
+/// What the button was held for at boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootHold {
+    None,
+    /// Offer the BLE provisioning service, whatever the configuration
+    Provisioning,
+    /// Erase the settings and records kept in flash
+    FactoryReset,
+}
+
+/// Wait until the button is pressed or released, the level has to hold for the debounce time
+async fn wait_level(button: &mut MappedInput, pressed: bool) {
+    loop {
+        while button.is_low() != pressed {
+            button.wait_for_any_edge().await;
+        }
+        Timer::after(Duration::from_millis(DEBOUNCE_MS)).await;
+        if button.is_low() == pressed {
+            return;
+        }
+    }
+}
+
+/// Wait for the next clicks, presses following each other within the multi-click time count as
+/// one gesture. A long press counts as none
+async fn next_clicks(button: &mut MappedInput) -> u8 {
+    wait_level(button, true).await;
+    let mut clicks = 0u8;
+    loop {
+        let pressed_at = Instant::now();
+        wait_level(button, false).await;
+        if pressed_at.elapsed() >= Duration::from_millis(LONG_PRESS_MS) {
+            return 0;
+        }
+        clicks = clicks.saturating_add(1);
+        let next_press = wait_level(button, true);
+        if with_timeout(Duration::from_millis(MULTI_CLICK_MS), next_press)
+            .await
+            .is_err()
+        {
+            return clicks;
+        }
+    }
+}
+
+/// How long the button is held at boot: the provisioning time and released before the reset time
+/// enters provisioning, the reset time asks for a factory reset. Boot waits while it's held
+pub async fn boot_hold(
+    button: &mut MappedInput,
+    provisioning_secs: u16,
+    reset_secs: u16,
+) -> BootHold {
+    if !button.is_low() {
+        return BootHold::None;
+    }
+    info!(
+        "BTN : Held at boot, {provisioning_secs}s enters provisioning, {reset_secs}s resets to factory settings"
+    );
+    let held_at = Instant::now();
+    let mut provisioning = false;
+    while button.is_low() {
+        let held_secs = held_at.elapsed().as_secs();
+        if held_secs >= u64::from(reset_secs) {
+            return BootHold::FactoryReset;
+        }
+        if held_secs >= u64::from(provisioning_secs) && !provisioning {
+            info!("BTN : Release for provisioning, keep holding for a factory reset");
+            provisioning = true;
+        }
+        Timer::after(Duration::from_millis(50)).await;
+    }
+    if provisioning {
+        BootHold::Provisioning
+    } else {
+        info!("BTN : Released early, booting normally");
+        BootHold::None
+    }
+}
+
+/// Erase every record kept in flash, the configuration of the build applies after the restart.
+/// The broker ports that worked from this site are kept, the build's port may not
+pub fn factory_reset() {
+    warn!("BTN : Factory reset, erasing the settings and records in flash");
+    for slot in Slot::ALL
+        .into_iter()
+        .filter(|slot| *slot != Slot::Endpoints)
+    {
+        if let Err(e) = storage::erase(slot) {
+            warn!("BTN : Failed to erase {}: {e}", slot.as_str());
+        }
+    }
+}
+
+/// Task for the front panel button: a press shows the next display page, a double press while
+/// charging asks to stop the session and a press within the confirm time stops it. A triple
+/// press switches the eco mode of the solar charging
+#[embassy_executor::task]
+pub async fn button_task(mut button: MappedInput, charger: &'static Charger) {
+    info!("TASK: Started Button Handler");
+
+    let mut stop_asked: Option<Instant> = None;
+
+    loop {
+        let clicks = next_clicks(&mut button).await;
+        quiet::interaction();
+        let charging = charger.get_state().await == ChargerState::Charging;
+        let confirming = stop_asked
+            .take()
+            .is_some_and(|asked| asked.elapsed() < Duration::from_secs(STOP_CONFIRM_SECS));
+
+        match clicks {
+            0 => info!("BTN : Long press ignored"),
+            1 if confirming && charging => {
+                info!("BTN : Stop confirmed, stopping the session");
+                charger::STATE_IN_CHANNEL
+                    .send(InputEvent::StopRequested)
+                    .await;
+            }
+            1 => display::notify(DisplayEvent::NextPage),
+            2 if charging => {
+                info!("BTN : Stop asked, press again within {STOP_CONFIRM_SECS}s to confirm");
+                stop_asked = Some(Instant::now());
+                display::notify(DisplayEvent::ConfirmStop);
+            }
+            3 => {
+                let eco = solar::toggle_eco();
+                info!("BTN : Eco mode {}", if eco { "on" } else { "off" });
+            }
+            clicks => info!("BTN : {clicks} presses, nothing to do"),
+        }
+    }
+}
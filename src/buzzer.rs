@@ -0,0 +1,163 @@
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    ledc::{
+        channel::{self, Channel, ChannelIFace},
+        timer::{self, TimerIFace},
+        Ledc, LowSpeed,
+    },
+    peripherals::GPIO5,
+    time::Rate,
+};
+
+use crate::{
+    charger::{self, Charger, ChargerState},
+    config::Config,
+    feedback::{self, Intensity, Prompt, Tone},
+    info, mk_static, quiet, warn,
+};
+
+/// Time between the repeats of the fault alarm
+const ALARM_INTERVAL_SECS: u64 = 5;
+
+/// A piezo buzzer driven with a square wave by the LED PWM controller, with a timer per tone
+pub struct Buzzer {
+    channel: Channel<'static, LowSpeed>,
+    high: &'static timer::Timer<'static, LowSpeed>,
+    low: &'static timer::Timer<'static, LowSpeed>,
+    tone: Tone,
+    volume: Intensity,
+}
+
+impl Buzzer {
+    /// Set up the buzzer on GPIO5, silent until a prompt is played. It takes timers 0 and 1 and
+    /// channel 0 of the LED PWM controller
+    pub fn new(
+        ledc: &'static Ledc<'static>,
+        pin: GPIO5<'static>,
+        volume: Intensity,
+    ) -> Result<Self, &'static str> {
+        let high = mk_static!(
+            timer::Timer<'static, LowSpeed>,
+            ledc.timer::<LowSpeed>(timer::Number::Timer0)
+        );
+        let low = mk_static!(
+            timer::Timer<'static, LowSpeed>,
+            ledc.timer::<LowSpeed>(timer::Number::Timer1)
+        );
+        for (tone_timer, tone) in [(&mut *high, Tone::High), (&mut *low, Tone::Low)] {
+            tone_timer
+                .configure(timer::config::Config {
+                    duty: timer::config::Duty::Duty8Bit,
+                    clock_source: timer::LSClockSource::APBClk,
+                    frequency: Rate::from_hz(tone.hz()),
+                })
+                .map_err(|_| "Failed to configure the buzzer timer")?;
+        }
+
+        let mut buzzer = Self {
+            channel: ledc.channel(channel::Number::Channel0, pin),
+            high,
+            low,
+            tone: Tone::High,
+            volume,
+        };
+        buzzer.configure(Tone::High, 0)?;
+        Ok(buzzer)
+    }
+
+    /// Connect the channel to the timer of a tone
+    fn configure(&mut self, tone: Tone, duty_pct: u8) -> Result<(), &'static str> {
+        let timer = match tone {
+            Tone::High => self.high,
+            Tone::Low => self.low,
+        };
+        self.channel
+            .configure(channel::config::Config {
+                timer,
+                duty_pct,
+                pin_config: channel::config::PinConfig::PushPull,
+            })
+            .map_err(|_| "Failed to configure the buzzer channel")?;
+        self.tone = tone;
+        Ok(())
+    }
+
+    /// Sound a tone at the volume of the buzzer, or silence it
+    fn sound(&mut self, tone: Option<Tone>) -> Result<(), &'static str> {
+        match tone {
+            Some(tone) if tone != self.tone => self.configure(tone, self.volume.buzzer_duty_pct()),
+            Some(_) => self
+                .channel
+                .set_duty(self.volume.buzzer_duty_pct())
+                .map_err(|_| "Failed to set the duty cycle"),
+            None => self
+                .channel
+                .set_duty(0)
+                .map_err(|_| "Failed to set the duty cycle"),
+        }
+    }
+
+    async fn play(&mut self, prompt: Prompt) {
+        info!("BUZZ: Prompt {}", prompt.as_str());
+        feedback::play(prompt, |tone| {
+            if let Err(e) = self.sound(tone) {
+                warn!("BUZZ: {e}");
+            }
+        })
+        .await;
+    }
+}
+
+/// Task to sound the prompts on the buzzer, in step with the LED flashes of the LED task. The
+/// fault alarm repeats while the charger stays Faulted, for `alarm_secs` at most. The buzzer is
+/// silent during the quiet hours of the behavior profile, unless somebody is at the charger
+#[embassy_executor::task]
+pub async fn buzzer_task(mut buzzer: Buzzer, charger: &'static Charger, alarm_secs: u16) {
+    info!(
+        "TASK: Started Buzzer Prompts at {} volume",
+        buzzer.volume.as_str()
+    );
+
+    let config = Config::from_config();
+    let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
+    let mut last_state = charger.get_state().await;
+    let mut alarm_until = None;
+
+    loop {
+        let next = subscriber.next_message_pure();
+        let (state, events) = match alarm_until {
+            Some(until) if Instant::now() < until => {
+                let repeat = Timer::after(Duration::from_secs(ALARM_INTERVAL_SECS));
+                match select(next, repeat).await {
+                    Either::First(message) => message,
+                    Either::Second(()) => {
+                        if !quiet::is_quiet(&config) {
+                            buzzer.play(Prompt::Fault).await;
+                        }
+                        continue;
+                    }
+                }
+            }
+            _ => next.await,
+        };
+
+        let prompt = Prompt::for_transition(last_state, state, &events);
+        last_state = state;
+        if state != ChargerState::Faulted {
+            alarm_until = None;
+        } else if prompt == Some(Prompt::Fault) && buzzer.volume != Intensity::Off {
+            alarm_until = Some(Instant::now() + Duration::from_secs(u64::from(alarm_secs)));
+        }
+        if let Some(prompt) = prompt.filter(|_| buzzer.volume != Intensity::Off) {
+            if quiet::is_quiet(&config) {
+                info!(
+                    "BUZZ: Prompt {} silenced in the quiet hours",
+                    prompt.as_str()
+                );
+                continue;
+            }
+            buzzer.play(prompt).await;
+        }
+    }
+}
@@ -4,94 +4,47 @@ use embassy_sync::{
     pubsub::PubSubChannel,
 };
 use embassy_time::{Duration, Timer};
-use log::{info, warn};
+
+use crate::{
+    connectivity, diagnostics,
+    display::{self, DisplayEvent},
+    fault::{self, Fault},
+    info, maintenance, pilot, quiet, rcd, relay, reservation, sensors,
+    state_machine::{self, Conditions, Effect, Step},
+    vendor, waiting_list, warn,
+    watchdog::{self, Monitored},
+};
+
+pub use crate::state_machine::{
+    session_action, ChargerState, InputEvent, OutputEvent, OutputEvents, SessionAction, StopReason,
+};
 
 pub static DEFAULT_CONNECTOR_ID: u32 = 0;
 
+/// Subscriber slots of STATE_PUBSUB kept free for the tasks of a fork, see the vendor module
+pub const VENDOR_SUBSCRIBERS: usize = 1;
+/// Subscriber slots of STATE_PUBSUB, checked against the task registry at build time
+pub const STATE_SUBSCRIBERS: usize = 10 + VENDOR_SUBSCRIBERS;
+/// Publisher slots of STATE_PUBSUB
+pub const STATE_PUBLISHERS: usize = 4;
+
 /// PubSub channel for charger state changes
 pub static STATE_PUBSUB: PubSubChannel<
     CriticalSectionRawMutex,
-    (ChargerState, heapless::Vec<OutputEvent, 2>),
+    (ChargerState, OutputEvents),
     10,
-    6,
-    4,
+    STATE_SUBSCRIBERS,
+    STATE_PUBLISHERS,
 > = PubSubChannel::new();
 
 /// Message queue for charger input events
 pub static STATE_IN_CHANNEL: Channel<CriticalSectionRawMutex, InputEvent, 10> = Channel::new();
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum InputEvent {
-    InsertCable,
-    RemoveCable,
-    SwipeDetected,
-    Accepted,
-    Rejected,
-    None,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum OutputEvent {
-    Lock,
-    Unlock,
-    ApplyPower,
-    RemovePower,
-    ShowRejected,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ChargerState {
-    Off,
-    Faulted,
-    Available,
-    Preparing,
-    Charging,
-    Authorizing,
-}
-
-impl Default for ChargerState {
-    fn default() -> Self {
-        Self::Off
-    }
-}
-
-impl ChargerState {
-    pub fn is_operational(&self) -> bool {
-        matches!(self, Self::Available | Self::Preparing | Self::Charging)
-    }
-
-    pub fn is_charging(&self) -> bool {
-        matches!(self, Self::Charging)
-    }
-
-    pub fn is_prepared(&self) -> bool {
-        matches!(self, Self::Preparing | Self::Charging)
-    }
-
-    pub fn is_available(&self) -> bool {
-        matches!(self, Self::Available)
-    }
-
-    pub fn has_error(&self) -> bool {
-        matches!(self, Self::Faulted)
-    }
-
-    pub fn as_str(&self) -> &'static str {
-        match self {
-            Self::Off => "Off",
-            Self::Faulted => "Faulted",
-            Self::Available => "Available",
-            Self::Preparing => "Preparing",
-            Self::Charging => "Charging",
-            Self::Authorizing => "Authorizing",
-        }
-    }
-}
-
 pub struct Charger {
     state: Mutex<CriticalSectionRawMutex, RefCell<ChargerState>>,
     transaction_id: Mutex<CriticalSectionRawMutex, RefCell<i32>>,
     id_tag: Mutex<CriticalSectionRawMutex, RefCell<heapless::String<32>>>,
+    stop_reason: Mutex<CriticalSectionRawMutex, RefCell<Option<StopReason>>>,
 }
 
 impl Default for Charger {
@@ -106,6 +59,7 @@ impl Charger {
             state: Mutex::new(RefCell::new(ChargerState::default())),
             transaction_id: Mutex::new(RefCell::new(0)),
             id_tag: Mutex::new(RefCell::new(heapless::String::new())),
+            stop_reason: Mutex::new(RefCell::new(None)),
         }
     }
 
@@ -148,61 +102,124 @@ impl Charger {
         info!("CHGR: Set ID tag to: {new_tag}");
     }
 
-    pub async fn transition(
-        &self,
-        charger_input: InputEvent,
-    ) -> (ChargerState, heapless::Vec<OutputEvent, 2>) {
+    /// Take a swiped card, returns the input event for the state machine. During a session
+    /// another card joins the waiting list instead of stopping it
+    pub async fn card_swiped(&self, id_tag: &str) -> InputEvent {
+        if waiting_list::is_enabled() && self.get_state().await == ChargerState::Charging {
+            let session_tag = self.get_id_tag().await;
+            if !session_tag.eq_ignore_ascii_case(id_tag) {
+                return match waiting_list::join(id_tag) {
+                    Ok(_) => InputEvent::Queued,
+                    Err(e) => {
+                        warn!("CHGR: {id_tag} not queued: {e}");
+                        InputEvent::None
+                    }
+                };
+            }
+        }
+        self.set_id_tag(id_tag).await;
+        InputEvent::SwipeDetected
+    }
+
+    pub async fn set_stop_reason(&self, reason: StopReason) {
+        let stop_reason_guard = self.stop_reason.lock().await;
+        *stop_reason_guard.borrow_mut() = Some(reason);
+        info!("CHGR: Set stop reason to: {reason:?}");
+    }
+
+    /// The reason of the stopped transaction, cleared for the next one
+    pub async fn take_stop_reason(&self) -> Option<StopReason> {
+        let stop_reason_guard = self.stop_reason.lock().await;
+        let reason = stop_reason_guard.borrow_mut().take();
+        reason
+    }
+
+    /// Sample the conditions the transition table looks at
+    async fn conditions(&self, state: ChargerState, input: InputEvent) -> Conditions {
+        // Only the master card can clear a lockout or a ground fault on site
+        let master_card = matches!(state, ChargerState::Unavailable | ChargerState::Faulted)
+            && input == InputEvent::SwipeDetected
+            && fault::is_master_id_tag(&self.get_id_tag().await);
+        Conditions {
+            master_card,
+            safety_latched: safety_latched(),
+            safety_cleared: false,
+            overheated: sensors::overheated(),
+            diode_missing: pilot::diode_missing(),
+            lockout: fault::lockout().is_some(),
+            maintenance: maintenance::is_active(),
+            reserved: reservation::active().is_some(),
+            waiting_turn: waiting_list::turn().is_some(),
+            offline_stops_sessions: connectivity::stops_sessions(),
+        }
+    }
+
+    pub async fn transition(&self, charger_input: InputEvent) -> (ChargerState, OutputEvents) {
         let current_state = self.get_state().await;
 
         info!("CHGR: Transitioning from {current_state:?} with input {charger_input:?}");
 
-        let (new_state, events) = match (current_state, charger_input) {
-            (ChargerState::Available, InputEvent::InsertCable) => {
-                (ChargerState::Preparing, heapless::Vec::new())
-            }
-            (ChargerState::Preparing, InputEvent::SwipeDetected) => {
-                (ChargerState::Authorizing, heapless::Vec::new())
-            }
-            (ChargerState::Authorizing, InputEvent::Accepted) => (
-                ChargerState::Charging,
-                heapless::Vec::from_slice(&[OutputEvent::ApplyPower, OutputEvent::Lock]).unwrap(),
-            ),
-            (ChargerState::Authorizing, InputEvent::Rejected) => (
-                ChargerState::Preparing,
-                heapless::Vec::from_slice(&[OutputEvent::ShowRejected]).unwrap(),
-            ),
-            (ChargerState::Charging, InputEvent::SwipeDetected) => {
-                let output_events =
-                    heapless::Vec::from_slice(&[OutputEvent::RemovePower, OutputEvent::Unlock])
-                        .unwrap_or_default();
-                (ChargerState::Preparing, output_events)
-            }
-            (ChargerState::Preparing, InputEvent::RemoveCable) => {
-                (ChargerState::Available, heapless::Vec::new())
-            }
-            (ChargerState::Charging, InputEvent::RemoveCable) => {
-                let output_events =
-                    heapless::Vec::from_slice(&[OutputEvent::RemovePower, OutputEvent::Unlock])
-                        .unwrap_or_default();
-                (ChargerState::Faulted, output_events)
+        let mut conditions = self.conditions(current_state, charger_input).await;
+        // Each refuses to clear while its cause persists
+        if current_state == ChargerState::Faulted
+            && conditions.master_card
+            && conditions.safety_latched
+        {
+            rcd::clear();
+            relay::clear_switch_failure();
+            conditions.safety_cleared = !safety_latched();
+        }
+
+        let Step {
+            mut state,
+            events,
+            effect,
+        } = state_machine::next(current_state, charger_input, &conditions);
+        match effect {
+            Some(Effect::RecordFault) => {
+                if let Some(fault) = fault_of(charger_input) {
+                    fault::record(fault);
+                }
             }
-            (ChargerState::Faulted, _) => {
-                warn!("CHGR: Charger is in faulted state, resetting to available after 5 seconds");
-                Timer::after(Duration::from_secs(5)).await;
+            Some(Effect::StopOffline) => self.set_stop_reason(StopReason::Offline).await,
+            Some(Effect::ClearDiodeFault) => pilot::clear_diode_fault(),
+            Some(Effect::ClearLockout) => fault::clear_lockout(),
+            Some(Effect::ClearInputs) => STATE_IN_CHANNEL.clear(),
+            Some(Effect::Settle) => {
+                Timer::after(Duration::from_secs(state_machine::SETTLE_SECS)).await;
                 STATE_IN_CHANNEL.clear();
-                (ChargerState::Available, heapless::Vec::new())
+                let conditions = self.conditions(current_state, InputEvent::None).await;
+                state = state_machine::recovered(&conditions);
+                if state == ChargerState::Faulted {
+                    warn!("CHGR: Fault still active after settling, staying faulted");
+                }
             }
-            _ => {
-                warn!("CHGR: Invalid or unknown transition from {current_state:?} with input {charger_input:?}");
-                (current_state, heapless::Vec::new())
-            }
-        };
-        info!("CHGR: Transition result: {new_state:?}, {events:?}");
-        self.set_state(new_state).await;
-        (new_state, events)
+            None => {}
+        }
+        info!("CHGR: Transition result: {state:?}, {events:?}");
+        self.set_state(state).await;
+        (state, events)
     }
 }
 
+/// The fault recorded for an input of the transition table
+fn fault_of(input: InputEvent) -> Option<Fault> {
+    match input {
+        InputEvent::RemoveCable => Some(Fault::EvDisconnected),
+        InputEvent::DiodeMissing => Some(Fault::PilotDiodeMissing),
+        InputEvent::InvariantViolated => Some(Fault::InvariantViolated),
+        InputEvent::GroundFault => Some(Fault::GroundFault),
+        InputEvent::PowerSwitchFailure => Some(Fault::PowerSwitchFailure),
+        InputEvent::Overheated => Some(Fault::HighTemperature),
+        _ => None,
+    }
+}
+
+/// Whether a ground fault or a switch failure keeps the charger faulted until it's cleared
+fn safety_latched() -> bool {
+    rcd::latched() || relay::switch_failed()
+}
+
 #[embassy_executor::task]
 pub async fn statemachine_handler_task(charger: &'static Charger) {
     info!("TASK: Started Charger State Machine Handler");
@@ -211,11 +228,24 @@ pub async fn statemachine_handler_task(charger: &'static Charger) {
 
     loop {
         // Wait for state change events
+        watchdog::idle(Monitored::StateMachine);
         let event = STATE_IN_CHANNEL.receive().await;
+        watchdog::check_in(Monitored::StateMachine);
         info!("CHSM: State Machine: Received input event: {event:?}");
+        display::notify(DisplayEvent::Input);
+        // Somebody at the charger lifts the quiet hours, the prompts of this input are heard
+        if matches!(
+            event,
+            InputEvent::InsertCable
+                | InputEvent::RemoveCable
+                | InputEvent::SwipeDetected
+                | InputEvent::Queued
+        ) {
+            quiet::interaction();
+        }
 
         let old_state = charger.get_state().await;
-        let (new_state, output_events) = charger.transition(event).await;
+        let (new_state, mut output_events) = charger.transition(event).await;
         info!(
             "CHSM: State Machine: Transitioned to state: {}, events: {output_events:?}",
             new_state.as_str()
@@ -223,6 +253,13 @@ pub async fn statemachine_handler_task(charger: &'static Charger) {
 
         // Publish state change if state actually changed
         if old_state != new_state {
+            diagnostics::record_transition(old_state, new_state, event);
+            vendor::apply(old_state, event, new_state, &mut output_events);
+            if output_events.contains(&OutputEvent::RemovePower)
+                || new_state == ChargerState::Faulted
+            {
+                relay::expect_open();
+            }
             publisher.publish_immediate((new_state, output_events));
             info!(
                 "CHSM: State Machine: Published state change to {}",
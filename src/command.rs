@@ -0,0 +1,170 @@
+use embassy_time::{Duration, Timer};
+use log::Level;
+
+use crate::{
+    branding,
+    charger::{self, Charger, ChargerState, InputEvent},
+    config::Config,
+    endpoints, guest, info, logging, maintenance, meter, mqtt, ocpp, rfid,
+    settings::{self, RemoteChange},
+    tasks, warn,
+};
+
+/// Unix time field of a command
+fn time_field(payload: &str, key: &str) -> Option<u32> {
+    ocpp::json_integer_field(payload, key).and_then(|time| u32::try_from(time).ok())
+}
+
+/// Handle one command from the cmd topic or the USB API, a JSON object with a `command` field
+pub async fn handle_command(charger: &Charger, payload: &str) -> Result<(), &'static str> {
+    match ocpp::json_string_field(payload, "command") {
+        // {"command":"maintenance","hours":4}
+        Some("maintenance") => {
+            let hours = ocpp::json_integer_field(payload, "hours")
+                .and_then(|hours| u32::try_from(hours).ok())
+                .filter(|hours| *hours > 0)
+                .ok_or("maintenance requires a positive number of hours")?;
+            maintenance::start(hours);
+            Ok(())
+        }
+        // {"command":"resume"}
+        Some("resume") => {
+            if !maintenance::end() {
+                info!("CMD : Not in a maintenance window");
+            }
+            Ok(())
+        }
+        // {"command":"logo","data":"<base64 of the 1024 byte bitmap>"}, without data the
+        // built-in logo is restored
+        Some("logo") => match ocpp::json_string_field(payload, "data") {
+            Some(data) => branding::store(&branding::from_base64(data)?),
+            None => branding::reset(),
+        },
+        // {"command":"tasks"}, logs the tasks and the channels they use
+        Some("tasks") => {
+            tasks::log_tasks();
+            Ok(())
+        }
+        // {"command":"logs","level":"warn"}, sends the log ring buffer to the central system as
+        // `Logs` DataTransfers, all buffered levels without a level
+        Some("logs") => {
+            let level = match ocpp::json_string_field(payload, "level") {
+                Some(level) => level.parse().map_err(|_| "logs requires a valid level")?,
+                None => Level::Trace,
+            };
+            logging::send(Config::from_config().charger_vendor, level).await
+        }
+        // {"command":"endpoints"}, logs the broker ports tried from this site
+        Some("endpoints") => {
+            for line in endpoints::report().lines() {
+                info!("CMD : {line}");
+            }
+            Ok(())
+        }
+        // {"command":"endpoints_clear"}, forgets them, the configured port is used again
+        Some("endpoints_clear") => {
+            endpoints::clear();
+            Ok(())
+        }
+        // {"command":"guest_add","code":"482913","from":1735689600,"until":1735776000}, valid
+        // right away without from
+        Some("guest_add") => {
+            let code =
+                ocpp::json_string_field(payload, "code").ok_or("guest_add requires a code")?;
+            let until = time_field(payload, "until").ok_or("guest_add requires an until time")?;
+            guest::add(code, time_field(payload, "from").unwrap_or(0), until)
+        }
+        // {"command":"guest_remove","code":"482913"}
+        Some("guest_remove") => guest::remove(
+            ocpp::json_string_field(payload, "code").ok_or("guest_remove requires a code")?,
+        ),
+        // {"command":"guest_clear"}
+        Some("guest_clear") => {
+            guest::clear();
+            Ok(())
+        }
+        // {"command":"guest","code":"482913"}, a code entered by a guest, e.g. on the web page of
+        // the operator, starts a session like a card swipe once the cable is inserted
+        Some("guest") => {
+            let code = ocpp::json_string_field(payload, "code").ok_or("guest requires a code")?;
+            if charger.get_state().await != ChargerState::Preparing {
+                return Err("Insert the cable before entering a guest code");
+            }
+            let id_tag = guest::enter(code)?;
+            info!("CMD : Guest code accepted, starting a session for {id_tag}");
+            charger.set_id_tag(&id_tag).await;
+            charger::STATE_IN_CHANNEL
+                .send(InputEvent::SwipeDetected)
+                .await;
+            Ok(())
+        }
+        // {"command":"swipe","uid":"04A1B2C3"}, presents a card to the simulated card reader
+        Some("swipe") => rfid::simulate_swipe(
+            ocpp::json_string_field(payload, "uid").ok_or("swipe requires a uid")?,
+        ),
+        // {"command":"voltage","volts":228}, the supply voltage from an external meter for the
+        // soft start, at most a few seconds apart
+        Some("voltage") => {
+            let volts = ocpp::json_integer_field(payload, "volts")
+                .and_then(|volts| u16::try_from(volts).ok())
+                .ok_or("voltage requires volts")?;
+            meter::report_voltage(volts);
+            Ok(())
+        }
+        // {"command":"settings","ssid":"...","password":"...","broker":"...","timeout":300},
+        // applied with a restart and rolled back when the broker isn't reached within the timeout
+        Some("settings") => {
+            let change = RemoteChange {
+                wifi_ssid: ocpp::json_string_field(payload, "ssid"),
+                wifi_password: ocpp::json_string_field(payload, "password"),
+                mqtt_broker: ocpp::json_string_field(payload, "broker"),
+            };
+            if change.wifi_ssid.is_none()
+                && change.wifi_password.is_none()
+                && change.mqtt_broker.is_none()
+            {
+                return Err("settings requires an ssid, password or broker");
+            }
+            if charger.get_state().await.is_charging() {
+                return Err("Settings can't be changed while charging");
+            }
+            let secs = ocpp::json_integer_field(payload, "timeout")
+                .map(|secs| u32::try_from(secs).ok().filter(|secs| *secs > 0))
+                .unwrap_or(Some(settings::TRIAL_SECS))
+                .ok_or("settings requires a positive timeout")?;
+            settings::start_trial(&change, secs)?;
+            info!("CMD : Settings stored on trial for {secs}s, restarting");
+            Timer::after(Duration::from_secs(1)).await;
+            esp_hal::system::software_reset();
+        }
+        Some(_) => Err("Unknown command"),
+        None => Err("Command without a command field"),
+    }
+}
+
+/// Task to handle the commands received on the cmd topic
+#[embassy_executor::task]
+pub async fn command_handler_task(charger: &'static Charger) {
+    info!("TASK: Started Command Handler");
+
+    loop {
+        let message = mqtt::MQTT_CMD_CHANNEL.receive().await;
+        let Ok(payload) = core::str::from_utf8(&message) else {
+            warn!("CMD : Ignoring command that isn't valid UTF-8");
+            continue;
+        };
+
+        // Guest codes and passwords are kept out of the log, voltage readings are too frequent to log
+        let command = ocpp::json_string_field(payload, "command");
+        let logged = match command {
+            Some(command) if command.starts_with("guest") || command == "settings" => command,
+            _ => payload,
+        };
+        if command != Some("voltage") {
+            info!("CMD : Received command: {logged}");
+        }
+        if let Err(e) = handle_command(charger, payload).await {
+            warn!("CMD : {e}: {logged}");
+        }
+    }
+}
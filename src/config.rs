@@ -1,11 +1,83 @@
 extern crate alloc;
 use alloc::format;
+use core::{cell::Cell, fmt::Write};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{
+    charger,
+    connectivity::OfflinePolicy,
+    feedback::Intensity,
+    leds::{Polarity, MAX_LEDS},
+    locale::Locale,
+    profile::{self, BehaviorProfile, BehaviorSettings, DisplayPages},
+    timezone::TimeZone,
+    utils,
+};
+
+/// Settings assigned by the onboarding claim flow, they take precedence over the
+/// compiled configuration
+#[derive(Clone, Copy, Debug)]
+pub struct ProvisionedSettings {
+    pub mqtt_broker: &'static str,
+    pub mqtt_port: u16,
+    pub mqtt_client_id: &'static str,
+    pub mqtt_username: &'static str,
+    pub mqtt_password: &'static str,
+    pub site_id: &'static str,
+    pub profile: Option<BehaviorProfile>,
+}
+
+static PROVISIONED: Mutex<CriticalSectionRawMutex, Cell<Option<ProvisionedSettings>>> =
+    Mutex::new(Cell::new(None));
+
+/// Install the settings from the onboarding claim flow
+pub fn set_provisioned(settings: ProvisionedSettings) {
+    PROVISIONED.lock(|provisioned| provisioned.set(Some(settings)));
+}
+
+pub fn provisioned() -> Option<ProvisionedSettings> {
+    PROVISIONED.lock(|provisioned| provisioned.get())
+}
+
+/// Settings configured on site, e.g. over BLE, None keeps the compiled value
+/// They take precedence over both the compiled and the provisioned configuration
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalSettings {
+    pub wifi_ssid: Option<&'static str>,
+    pub wifi_password: Option<&'static str>,
+    pub mqtt_broker: Option<&'static str>,
+    pub charger_serial: Option<&'static str>,
+}
+
+static LOCAL_SETTINGS: Mutex<CriticalSectionRawMutex, Cell<Option<LocalSettings>>> =
+    Mutex::new(Cell::new(None));
+
+/// Install the settings stored on site
+pub fn set_local_settings(settings: LocalSettings) {
+    LOCAL_SETTINGS.lock(|local| local.set(Some(settings)));
+}
+
+pub fn local_settings() -> Option<LocalSettings> {
+    LOCAL_SETTINGS.lock(|local| local.get())
+}
+
+/// Credentials of a WiFi network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WifiNetwork {
+    pub ssid: &'static str,
+    pub password: &'static str,
+}
 
 /// Configuration structure for the ESP32-C6 charger
 #[derive(Clone, Debug)]
 pub struct Config {
     pub wifi_ssid: &'static str,
     pub wifi_password: &'static str,
+    pub wifi_fallbacks: [WifiNetwork; 2], // Networks to fall back to in priority order, an empty SSID is unused
+    pub wifi_roaming: bool, // Move to a stronger access point when the signal stays weak
+    pub wifi_roam_threshold_dbm: i8, // Signal below which the charger looks for a stronger access point
+    pub wifi_roam_after_secs: u16,   // Time the signal must stay weak before scanning
+    pub wifi_bssids: &'static str,   // Comma separated access points to roam to, empty allows any
     pub charger_name: &'static str,
     pub charger_model: &'static str,
     pub charger_vendor: &'static str,
@@ -13,12 +85,192 @@ pub struct Config {
     pub mqtt_broker: &'static str,
     pub mqtt_port: u16,
     pub mqtt_client_id: &'static str,
+    pub mqtt_username: &'static str,
+    pub mqtt_password: &'static str,
+    pub site_id: &'static str,
+    pub onboarding_broker: &'static str,
+    pub onboarding_port: u16,
+    pub onboarding_key: &'static str, // Shared secret to verify provisioning blobs
     pub ntp_server: &'static str,
     pub ntp_sync_interval_minutes: u16, // NTP sync interval in minutes
     pub timezone_offset_hours: i8, // Timezone offset from UTC in hours (e.g., +1 for CET, -5 for EST)
+    pub time_zone: TimeZone,       // Named or POSIX TZ timezone, the fixed offset when not set
+    pub locale: Locale,            // Number formats on the display and in receipts
+    pub tariff_cents_per_kwh: u16, // Price per kWh to show the cost of sessions, 0 hides costs
+    pub display_qr_url: &'static str, // URL of the QR code page, `{serial}` is replaced
+    pub display_dim_mins: u16,     // Minutes idle in Available before the display dims, 0 never
+    pub display_off_mins: u16, // Minutes idle in Available before the display turns off, 0 never
     pub ocpp_heartbeat_interval: u16, // Heartbeat interval in seconds
+    pub ocpp_clock_drift_threshold_secs: u16, // Drift from the central system time that is corrected
+    pub stop_transaction_on_invalid_id: bool, // Stop a deauthorized session, or limit it to the minimum current
+    pub max_current_amps: u16,                // Maximum current the installation supports
+    pub failsafe_current_amps: u16, // Current to fall back to when a limit source goes stale
+    pub load_balancing_timeout_secs: u16, // Staleness timeout for load balancing limits
+    pub solar_timeout_secs: u16,    // Staleness timeout for solar/PV limits
+    pub supply_voltage: u16,        // Nominal voltage per phase, to estimate the energy
+    pub supply_phases: u8,          // Phases the charger is connected to, 1 to 3
+    pub soft_start_enabled: bool,   // Start sessions at the minimum current and step up
+    pub soft_start_step_amps: u16,  // Current added every step of the soft start
+    pub soft_start_step_secs: u16,  // Time between the steps of the soft start
+    pub soft_start_min_voltage: u16, // Supply voltage below which the soft start backs off
+    pub load_management_enabled: bool, // Share the site limit with the other chargers of the site
+    pub site_limit_amps: u16,       // Current all chargers of the site may draw together
+    pub peer_timeout_secs: u16,     // Time a silent charger is counted before it is forgotten
+    pub solar_enabled: bool,        // Charge on the solar surplus from the grid topic in eco mode
+    pub solar_min_current_amps: u16, // Lowest current offered in eco mode
+    pub solar_hysteresis_watts: u16, // Import or export tolerated before the current changes
+    pub waiting_list_enabled: bool, // Cards swiped during a session join the waiting list
+    pub waiting_priority_secs: u16, // Time the next driver has to plug in and start
+    pub watchdog_enabled: bool,     // Reset the charger when a critical task stalls
+    pub relay_open_deadline_ms: u16, // Time the relay has to open after power is removed
+    pub mqtt_stall_secs: u16,       // Time the send queue may stay full without a message going out
+    pub mqtt_stall_restarts: u8, // Client teardowns for a full send queue before the charger resets
+    pub demand_response_mode: &'static str, // "suspend" or "clamp" while the contact is asserted
+    pub demand_response_clamp_amps: u16, // Current the clamp mode limits to
+    pub log_buffer_level: &'static str, // Least severe level kept in the log ring buffer
+    pub log_forward_target: &'static str, // "syslog" or "mqtt" to forward warnings and errors
+    pub log_forward_syslog: &'static str, // Syslog server as host:port, UDP
+    pub log_forward_per_minute: u16, // Forwarded lines per minute, the rest is counted and dropped
+    pub demo_mode: bool,         // Simulate the central system, nothing is sent to the broker
+    pub behavior_profile: BehaviorProfile,
+    pub behavior: BehaviorSettings, // Profile preset with the individually configured overrides
+    pub quiet_override_mins: u16,   // Minutes an interaction lifts the quiet hours
+    pub default_id_tag: &'static str, // Id tag used for transactions started by free vend
+    pub local_id_tags: &'static str, // Comma separated id tags accepted by the local list
+    pub fault_lockout_count: u16,   // Recurrences of a fault that latch the charger unavailable
+    pub fault_lockout_window_mins: u16, // Window in which fault recurrences are counted
+    pub master_id_tag: &'static str, // Card that clears a fault lockout on site
+    pub offline_policy: OfflinePolicy, // What a session does when the network is lost
+    pub offline_grace_secs: u16,    // Time without network before the charger goes offline
+    pub backend_timeout_secs: u16,  // Time without a CallResult before the backend is degraded
+    pub accessibility_intensity: Intensity, // Buzzer volume and LED flashes of the prompts
+    pub buzzer_enabled: bool,
+    pub buzzer_volume: Option<Intensity>, // Overrides the accessibility intensity for the buzzer
+    pub buzzer_alarm_secs: u16,           // Time the fault alarm keeps repeating, 0 sounds it once
+    pub factory_stage_watts: u16, // Power of one load bank resistor stage in the factory test
+    pub factory_session_secs: u16, // Duration of the simulated factory test session
+    pub factory_energy_tolerance: u16, // Percentage the metered energy may differ from the load bank
+    pub http_port: u16,                // Port of the HTTP server for the session export
+    pub http_username: &'static str,
+    pub http_password: &'static str, // Empty disables the HTTP server
+    pub mdns_enabled: bool,          // Announce the charger on the LAN over mDNS
+    pub usb_api_enabled: bool,       // Serve the API for the desktop tool on the USB port
+    pub ota_delta_enabled: bool,     // Try a patch against the running image before the full image
+    pub ota_key: &'static str, // Key the image checksums are signed with, empty rejects updates
+    pub topics: TopicTemplates,
+    pub ocpp_delivery: Delivery, // QoS and retain of outbound OCPP messages
+    pub status_delivery: Delivery, // QoS and retain of the online/offline status
+    pub subscribe_qos: u8,       // Highest QoS of the inbound topics
+    pub telemetry_enabled: bool,
+    pub telemetry_interval_secs: u16, // Interval of the device metrics on the telemetry topic
+    pub telemetry_anonymized: bool,   // Coarse metrics under a pseudonym instead of the serial
+    pub analytics_broker: &'static str, // Broker for telemetry, empty publishes it on the OCPP broker
+    pub analytics_port: u16,
+    pub analytics_username: &'static str,
+    pub analytics_password: &'static str,
+    pub analytics_qos: u8,       // QoS of the telemetry on the analytics broker
+    pub pilot_diode_check: bool, // Check the vehicle's pilot diode, needs the pilot front-end
+    pub pilot_pwm: bool,         // Drive the pilot PWM on GPIO7 with the offered current
+    pub expander: &'static str, // GPIO expander on the I2C bus, "mcp23017" or "pcf8574", empty without
+    pub expander_address: u8,   // I2C address of the expander
+    pub relay_pin: &'static str, // Where the relay is wired, "gpio" or "expander:<pin>"
+    pub relay_feedback_pin: &'static str, // Contactor auxiliary contact, "gpio" or "expander:<pin>", empty without
+    pub demand_response_pin: &'static str, // Demand response contact, "gpio" or "expander:<pin>", empty without
+    pub lock_pin: &'static str,            // Where the cable lock is wired
+    pub cable_pin: &'static str,           // Where the cable switch is wired
+    pub led_count: u8,                     // LEDs of the WS2812 status strip
+    pub led_brightness: u8,                // Brightness of the status LEDs (0-255)
+    pub led_colors: &'static str, // Color and pattern overrides per status, "status=color[:pattern]"
+    pub led_indicator: &'static str, // Single color indicator LED, "gpio" or "expander:<pin>", empty without
+    pub led_polarity: Polarity,      // Level that switches the indicator on
+    pub button_enabled: bool,        // Front panel button for the pages, stop and provisioning
+    pub button_pin: &'static str,    // Where the button is wired, "gpio" or "expander:<pin>"
+    pub button_provisioning_secs: u16, // Hold at boot that enters BLE provisioning
+    pub button_reset_secs: u16,      // Hold at boot that resets to factory settings
+    pub rcd_enabled: bool,           // Monitor the test output of a residual current device
+    pub rfid_gain_db: u16,           // Receiver gain of the card reader, 18 to 48dB
+    pub rfid_quiet_ms: u16,          // Time after a relay switch before the card reader is polled
+    pub rfid_reader: &'static str,   // Card reader, "mfrc522", "simulated" or empty for none
+    pub rfid_probe_secs: u16,        // Interval of the check that the card reader still answers
+    pub temperature_warn_c: u16,     // Above this the current is derated
+    pub temperature_critical_c: u16, // Above this charging stops with a HighTemperature fault
+    pub ntc_enabled: bool,           // External NTC on GPIO6, next to the contactor or terminals
+    pub ntc_beta: u16,               // Beta coefficient of the NTC
+    pub ntc_ohms: u16,               // Resistance of the NTC at 25°C
+    pub ntc_series_ohms: u16,        // Resistor between 3V3 and the NTC
+    pub meter_type: &'static str,    // Meter IC measuring the energy, empty to estimate it
+    pub meter_voltage_cal: u16,      // Voltage calibration in thousandths
+    pub meter_current_cal: u16,      // Current calibration in thousandths
+    pub meter_power_cal: u16,        // Power and energy calibration in thousandths
+    pub meter_constant: u16,         // Pulses per kWh of the ATM90E32
+    pub meter_address: u8,           // Modbus address of the DIN rail meter
+    pub meter_baud: u16,             // Baud rate of the RS-485 bus of the DIN rail meter
+    pub ble_provisioning: bool,      // Offer the BLE provisioning service after boot
+    pub ble_window_mins: u16,        // Minutes after boot the provisioning service is available
+    pub memory_shed_order: &'static str, // Optional features disabled in turn when the heap runs out
+    pub webhook_url: &'static str, // Session and fault events are posted here, empty disables them
+    pub webhook_retries: u8,       // Retries of an event with a doubling delay, at most 10
+}
+
+/// MQTT topic templates, `{serial}`, `{model}`, `{vendor}`, `{site}` and `{connector}` are
+/// replaced by the values of this charger
+#[derive(Debug, Clone, Copy)]
+pub struct TopicTemplates {
+    pub charger: &'static str,
+    pub system: &'static str,
+    pub ocpp: &'static str,
+    pub cmd: &'static str,
+    pub ota: &'static str,
+    pub status: &'static str,
+    pub telemetry: &'static str,
+    pub load: &'static str,
+    pub grid: &'static str,
+    pub log: &'static str,
+}
+
+impl TopicTemplates {
+    pub const DEFAULT: Self = Self {
+        charger: "/charger/{serial}",
+        system: "/system/{serial}",
+        ocpp: "/ocpp/{serial}",
+        cmd: "/cmd/{serial}",
+        ota: "/ota/{serial}",
+        status: "/status/{serial}",
+        telemetry: "/telemetry/{serial}",
+        load: "/load/{site}",
+        grid: "/grid/{site}",
+        log: "/log/{serial}",
+    };
+}
+
+/// How a class of messages is published
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    pub qos: u8, // 0 or 1, QoS 2 isn't supported by the client
+    pub retain: bool,
 }
 
+impl Delivery {
+    fn parse(qos: Option<&str>, retain: Option<&str>, default: Self) -> Self {
+        Self {
+            qos: qos
+                .and_then(|qos| qos.parse().ok())
+                .filter(|qos| *qos <= 1)
+                .unwrap_or(default.qos),
+            retain: retain.map_or(default.retain, |retain| retain == "true"),
+        }
+    }
+}
+
+const DEFAULT_OCPP_DELIVERY: Delivery = Delivery {
+    qos: 1,
+    retain: true,
+};
+const DEFAULT_STATUS_DELIVERY: Delivery = Delivery {
+    qos: 1,
+    retain: true,
+};
+
 fn extract_toml_string<'a>(content: &'a str, section: &str, key: &str) -> Option<&'a str> {
     let section_marker = format!("[{section}]");
     let section_start = content.find(&section_marker)?;
@@ -29,10 +281,10 @@ fn extract_toml_string<'a>(content: &'a str, section: &str, key: &str) -> Option
     let section_content = &after_section[..section_end];
 
     for line in section_content.lines() {
-        let line = line.trim();
-        if line.starts_with(key) && line.contains('=') {
-            if let Some(eq_pos) = line.find('=') {
-                let value = line[eq_pos + 1..].trim();
+        // Match the whole key, so a key doesn't match a longer key it's a prefix of
+        if let Some((line_key, value)) = line.trim().split_once('=') {
+            if line_key.trim() == key {
+                let value = value.trim();
                 // Remove quotes if present
                 if value.starts_with('"') && value.ends_with('"') && value.len() >= 2 {
                     return Some(&value[1..value.len() - 1]);
@@ -48,6 +300,56 @@ fn extract_toml_integer(content: &str, section: &str, key: &str) -> Option<u16>
     extract_toml_string(content, section, key)?.parse().ok()
 }
 
+/// The provisioned profile takes precedence over the configured one, defaults to legacy
+fn behavior_profile(configured: Option<&str>) -> BehaviorProfile {
+    provisioned()
+        .and_then(|provisioned| provisioned.profile)
+        .or_else(|| configured.and_then(BehaviorProfile::parse))
+        .unwrap_or(BehaviorProfile::Legacy)
+}
+
+/// The named or POSIX TZ timezone, the fixed offset when none or an invalid one is configured
+fn time_zone(configured: &str, offset_hours: i8) -> TimeZone {
+    if configured.trim().is_empty() {
+        return TimeZone::fixed(offset_hours);
+    }
+    TimeZone::parse(configured).unwrap_or(TimeZone::fixed(offset_hours))
+}
+
+/// Start from the profile preset and apply the individually configured keys
+fn behavior_settings(
+    profile: BehaviorProfile,
+    free_vend: Option<&str>,
+    receipts: Option<&str>,
+    quiet_hours: Option<&str>,
+    display_pages: Option<&str>,
+    authorization: Option<&str>,
+) -> BehaviorSettings {
+    let mut settings = profile.settings();
+    if let Some(free_vend) = free_vend {
+        settings.free_vend = free_vend == "true";
+    }
+    if let Some(receipts) = receipts {
+        settings.receipts = receipts == "true";
+    }
+    match quiet_hours {
+        Some("off") => settings.quiet_hours = None,
+        Some(quiet_hours) => {
+            if let Some(quiet_hours) = profile::parse_quiet_hours(quiet_hours) {
+                settings.quiet_hours = Some(quiet_hours);
+            }
+        }
+        None => {}
+    }
+    if let Some(pages) = display_pages.and_then(DisplayPages::parse) {
+        settings.display_pages = pages;
+    }
+    if let Some(chain) = authorization.and_then(profile::parse_authorization_chain) {
+        settings.authorization = chain;
+    }
+    settings
+}
+
 impl Config {
     pub fn from_config() -> Self {
         // Include the TOML configuration at compile time
@@ -56,6 +358,20 @@ impl Config {
         let toml_wifi_ssid =
             extract_toml_string(CONFIG_TOML, "wifi", "ssid").unwrap_or("Wokwi-GUEST");
         let toml_wifi_password = extract_toml_string(CONFIG_TOML, "wifi", "password").unwrap_or("");
+        let toml_wifi_roaming =
+            extract_toml_string(CONFIG_TOML, "wifi", "roaming").unwrap_or("false");
+        let toml_wifi_roam_threshold = extract_toml_string(CONFIG_TOML, "wifi", "roam_threshold")
+            .and_then(|threshold| threshold.parse().ok())
+            .unwrap_or(-75);
+        let toml_wifi_roam_after =
+            extract_toml_integer(CONFIG_TOML, "wifi", "roam_after").unwrap_or(60);
+        let toml_wifi_bssids = extract_toml_string(CONFIG_TOML, "wifi", "bssids").unwrap_or("");
+        let toml_wifi_ssid_2 = extract_toml_string(CONFIG_TOML, "wifi", "ssid_2").unwrap_or("");
+        let toml_wifi_password_2 =
+            extract_toml_string(CONFIG_TOML, "wifi", "password_2").unwrap_or("");
+        let toml_wifi_ssid_3 = extract_toml_string(CONFIG_TOML, "wifi", "ssid_3").unwrap_or("");
+        let toml_wifi_password_3 =
+            extract_toml_string(CONFIG_TOML, "wifi", "password_3").unwrap_or("");
         let toml_charger_name =
             extract_toml_string(CONFIG_TOML, "charger", "name").unwrap_or("esp32c6 charger 001");
         let toml_charger_model =
@@ -69,20 +385,369 @@ impl Config {
         let toml_mqtt_port = extract_toml_integer(CONFIG_TOML, "mqtt", "port").unwrap_or(1883);
         let toml_mqtt_client_id =
             extract_toml_string(CONFIG_TOML, "mqtt", "client_id").unwrap_or("esp32c6-charger-001");
+        let toml_mqtt_username = extract_toml_string(CONFIG_TOML, "mqtt", "username").unwrap_or("");
+        let toml_mqtt_password = extract_toml_string(CONFIG_TOML, "mqtt", "password").unwrap_or("");
+        let toml_site_id = extract_toml_string(CONFIG_TOML, "charger", "site_id").unwrap_or("");
+        let toml_onboarding_broker =
+            extract_toml_string(CONFIG_TOML, "onboarding", "broker").unwrap_or("broker.hivemq.com");
+        let toml_onboarding_port =
+            extract_toml_integer(CONFIG_TOML, "onboarding", "port").unwrap_or(1883);
+        let toml_onboarding_key =
+            extract_toml_string(CONFIG_TOML, "onboarding", "key").unwrap_or("");
         let toml_ntp_server =
             extract_toml_string(CONFIG_TOML, "ntp", "server").unwrap_or("pool.ntp.org");
         let toml_ntp_sync_interval_minutes =
             extract_toml_integer(CONFIG_TOML, "ntp", "sync_interval_minutes").unwrap_or(240);
+        let toml_decimal =
+            extract_toml_string(CONFIG_TOML, "display", "decimal").unwrap_or("point");
+        let toml_currency =
+            extract_toml_string(CONFIG_TOML, "display", "currency").unwrap_or("EUR");
+        let toml_currency_position =
+            extract_toml_string(CONFIG_TOML, "display", "currency_position").unwrap_or("before");
+        let toml_tariff = extract_toml_integer(CONFIG_TOML, "display", "tariff").unwrap_or(0);
         let toml_timezone_offset =
             extract_toml_integer(CONFIG_TOML, "display", "timezone_offset_hours")
                 .map(|offset| offset as i8)
                 .unwrap_or(0);
+        let toml_timezone = extract_toml_string(CONFIG_TOML, "display", "timezone").unwrap_or("");
+        let toml_qr_url = extract_toml_string(CONFIG_TOML, "display", "qr_url").unwrap_or("");
+        let toml_display_dim =
+            extract_toml_integer(CONFIG_TOML, "display", "dim_after").unwrap_or(5);
+        let toml_display_off =
+            extract_toml_integer(CONFIG_TOML, "display", "off_after").unwrap_or(30);
         let toml_heartbeat_interval =
             extract_toml_integer(CONFIG_TOML, "ocpp", "heartbeat_interval").unwrap_or(900);
+        let toml_clock_drift_threshold =
+            extract_toml_integer(CONFIG_TOML, "ocpp", "clock_drift_threshold").unwrap_or(30);
+        let toml_stop_transaction_on_invalid_id =
+            extract_toml_string(CONFIG_TOML, "ocpp", "stop_transaction_on_invalid_id")
+                .unwrap_or("true");
+        let toml_max_current =
+            extract_toml_integer(CONFIG_TOML, "smart_charging", "max_current").unwrap_or(16);
+        let toml_failsafe_current =
+            extract_toml_integer(CONFIG_TOML, "smart_charging", "failsafe_current").unwrap_or(6);
+        let toml_load_balancing_timeout =
+            extract_toml_integer(CONFIG_TOML, "smart_charging", "load_balancing_timeout")
+                .unwrap_or(120);
+        let toml_solar_timeout =
+            extract_toml_integer(CONFIG_TOML, "smart_charging", "solar_timeout").unwrap_or(300);
+        let toml_supply_voltage =
+            extract_toml_integer(CONFIG_TOML, "smart_charging", "supply_voltage").unwrap_or(230);
+        let toml_supply_phases =
+            extract_toml_integer(CONFIG_TOML, "smart_charging", "phases").unwrap_or(1);
+        let toml_soft_start_enabled =
+            extract_toml_string(CONFIG_TOML, "soft_start", "enabled").unwrap_or("false");
+        let toml_soft_start_step =
+            extract_toml_integer(CONFIG_TOML, "soft_start", "step").unwrap_or(2);
+        let toml_soft_start_interval =
+            extract_toml_integer(CONFIG_TOML, "soft_start", "interval").unwrap_or(30);
+        let toml_soft_start_min_voltage =
+            extract_toml_integer(CONFIG_TOML, "soft_start", "min_voltage").unwrap_or(207);
+        let toml_load_management_enabled =
+            extract_toml_string(CONFIG_TOML, "load_management", "enabled").unwrap_or("false");
+        let toml_site_limit =
+            extract_toml_integer(CONFIG_TOML, "load_management", "site_limit").unwrap_or(32);
+        let toml_peer_timeout =
+            extract_toml_integer(CONFIG_TOML, "load_management", "peer_timeout").unwrap_or(60);
+        let toml_solar_enabled =
+            extract_toml_string(CONFIG_TOML, "solar", "enabled").unwrap_or("false");
+        let toml_solar_min_current =
+            extract_toml_integer(CONFIG_TOML, "solar", "min_current").unwrap_or(6);
+        let toml_solar_hysteresis =
+            extract_toml_integer(CONFIG_TOML, "solar", "hysteresis").unwrap_or(300);
+        let toml_waiting_list_enabled =
+            extract_toml_string(CONFIG_TOML, "waiting_list", "enabled").unwrap_or("false");
+        let toml_waiting_priority =
+            extract_toml_integer(CONFIG_TOML, "waiting_list", "priority_window").unwrap_or(300);
+        let toml_watchdog_enabled =
+            extract_toml_string(CONFIG_TOML, "watchdog", "enabled").unwrap_or("true");
+        let toml_relay_open_deadline =
+            extract_toml_integer(CONFIG_TOML, "watchdog", "relay_open_deadline").unwrap_or(500);
+        let toml_mqtt_stall =
+            extract_toml_integer(CONFIG_TOML, "watchdog", "mqtt_stall").unwrap_or(120);
+        let toml_mqtt_stall_restarts =
+            extract_toml_integer(CONFIG_TOML, "watchdog", "mqtt_stall_restarts").unwrap_or(3);
+        let toml_demand_response_mode =
+            extract_toml_string(CONFIG_TOML, "demand_response", "mode").unwrap_or("suspend");
+        let toml_demand_response_clamp =
+            extract_toml_integer(CONFIG_TOML, "demand_response", "clamp_current").unwrap_or(6);
+        let toml_log_buffer_level =
+            extract_toml_string(CONFIG_TOML, "logging", "buffer_level").unwrap_or("info");
+        let toml_log_forward_target =
+            extract_toml_string(CONFIG_TOML, "log_forward", "target").unwrap_or("");
+        let toml_log_forward_syslog =
+            extract_toml_string(CONFIG_TOML, "log_forward", "syslog").unwrap_or("");
+        let toml_log_forward_per_minute =
+            extract_toml_integer(CONFIG_TOML, "log_forward", "per_minute").unwrap_or(20);
+        let toml_demo_enabled =
+            extract_toml_string(CONFIG_TOML, "demo", "enabled").unwrap_or("false");
+        let behavior_profile = behavior_profile(
+            option_env!("CHARGER_BEHAVIOR_PROFILE").or(extract_toml_string(
+                CONFIG_TOML,
+                "behavior",
+                "profile",
+            )),
+        );
+        let behavior = behavior_settings(
+            behavior_profile,
+            option_env!("CHARGER_BEHAVIOR_FREE_VEND").or(extract_toml_string(
+                CONFIG_TOML,
+                "behavior",
+                "free_vend",
+            )),
+            option_env!("CHARGER_BEHAVIOR_RECEIPTS").or(extract_toml_string(
+                CONFIG_TOML,
+                "behavior",
+                "receipts",
+            )),
+            option_env!("CHARGER_BEHAVIOR_QUIET_HOURS").or(extract_toml_string(
+                CONFIG_TOML,
+                "behavior",
+                "quiet_hours",
+            )),
+            option_env!("CHARGER_BEHAVIOR_DISPLAY_PAGES").or(extract_toml_string(
+                CONFIG_TOML,
+                "behavior",
+                "display_pages",
+            )),
+            option_env!("CHARGER_BEHAVIOR_AUTHORIZATION").or(extract_toml_string(
+                CONFIG_TOML,
+                "behavior",
+                "authorization",
+            )),
+        );
+        let toml_quiet_override =
+            extract_toml_integer(CONFIG_TOML, "behavior", "quiet_override").unwrap_or(10);
+        let toml_default_id_tag =
+            extract_toml_string(CONFIG_TOML, "behavior", "default_id_tag").unwrap_or("FREEVEND");
+        let toml_local_id_tags =
+            extract_toml_string(CONFIG_TOML, "behavior", "local_id_tags").unwrap_or("");
+        let toml_fault_lockout_count =
+            extract_toml_integer(CONFIG_TOML, "fault", "lockout_count").unwrap_or(3);
+        let toml_fault_lockout_window =
+            extract_toml_integer(CONFIG_TOML, "fault", "lockout_window").unwrap_or(60);
+        let toml_master_id_tag =
+            extract_toml_string(CONFIG_TOML, "fault", "master_id_tag").unwrap_or("");
+        let toml_offline_policy =
+            extract_toml_string(CONFIG_TOML, "offline", "policy").unwrap_or("continue");
+        let toml_offline_grace =
+            extract_toml_integer(CONFIG_TOML, "offline", "grace").unwrap_or(60);
+        let toml_backend_timeout =
+            extract_toml_integer(CONFIG_TOML, "offline", "backend_timeout").unwrap_or(600);
+        let toml_accessibility_intensity =
+            extract_toml_string(CONFIG_TOML, "accessibility", "intensity").unwrap_or("medium");
+        let toml_buzzer_enabled =
+            extract_toml_string(CONFIG_TOML, "buzzer", "enabled").unwrap_or("true");
+        let toml_buzzer_volume = extract_toml_string(CONFIG_TOML, "buzzer", "volume").unwrap_or("");
+        let toml_buzzer_alarm = extract_toml_integer(CONFIG_TOML, "buzzer", "alarm").unwrap_or(60);
+        let toml_factory_stage_power =
+            extract_toml_integer(CONFIG_TOML, "factory", "stage_power").unwrap_or(1000);
+        let toml_factory_session_duration =
+            extract_toml_integer(CONFIG_TOML, "factory", "session_duration").unwrap_or(10);
+        let toml_factory_energy_tolerance =
+            extract_toml_integer(CONFIG_TOML, "factory", "energy_tolerance").unwrap_or(10);
+        let toml_http_port = extract_toml_integer(CONFIG_TOML, "http", "port").unwrap_or(80);
+        let toml_http_username =
+            extract_toml_string(CONFIG_TOML, "http", "username").unwrap_or("admin");
+        let toml_http_password = extract_toml_string(CONFIG_TOML, "http", "password").unwrap_or("");
+        let toml_usb_api_enabled = extract_toml_string(CONFIG_TOML, "usb", "api").unwrap_or("true");
+        let toml_ota_delta_enabled =
+            extract_toml_string(CONFIG_TOML, "ota", "delta").unwrap_or("true");
+        let toml_ota_key = extract_toml_string(CONFIG_TOML, "ota", "key").unwrap_or("");
+        let toml_mdns_enabled =
+            extract_toml_string(CONFIG_TOML, "mdns", "enabled").unwrap_or("true");
+        let topic = |key, env: Option<&'static str>, default| {
+            env.or(extract_toml_string(CONFIG_TOML, "topics", key))
+                .unwrap_or(default)
+        };
+        let topics = TopicTemplates {
+            charger: topic(
+                "charger",
+                option_env!("CHARGER_TOPICS_CHARGER"),
+                TopicTemplates::DEFAULT.charger,
+            ),
+            system: topic(
+                "system",
+                option_env!("CHARGER_TOPICS_SYSTEM"),
+                TopicTemplates::DEFAULT.system,
+            ),
+            ocpp: topic(
+                "ocpp",
+                option_env!("CHARGER_TOPICS_OCPP"),
+                TopicTemplates::DEFAULT.ocpp,
+            ),
+            cmd: topic(
+                "cmd",
+                option_env!("CHARGER_TOPICS_CMD"),
+                TopicTemplates::DEFAULT.cmd,
+            ),
+            ota: topic(
+                "ota",
+                option_env!("CHARGER_TOPICS_OTA"),
+                TopicTemplates::DEFAULT.ota,
+            ),
+            status: topic(
+                "status",
+                option_env!("CHARGER_TOPICS_STATUS"),
+                TopicTemplates::DEFAULT.status,
+            ),
+            telemetry: topic(
+                "telemetry",
+                option_env!("CHARGER_TOPICS_TELEMETRY"),
+                TopicTemplates::DEFAULT.telemetry,
+            ),
+            load: topic(
+                "load",
+                option_env!("CHARGER_TOPICS_LOAD"),
+                TopicTemplates::DEFAULT.load,
+            ),
+            grid: topic(
+                "grid",
+                option_env!("CHARGER_TOPICS_GRID"),
+                TopicTemplates::DEFAULT.grid,
+            ),
+            log: topic(
+                "log",
+                option_env!("CHARGER_TOPICS_LOG"),
+                TopicTemplates::DEFAULT.log,
+            ),
+        };
+        let ocpp_delivery = Delivery::parse(
+            option_env!("CHARGER_TOPICS_OCPP_QOS").or(extract_toml_string(
+                CONFIG_TOML,
+                "topics",
+                "ocpp_qos",
+            )),
+            option_env!("CHARGER_TOPICS_OCPP_RETAIN").or(extract_toml_string(
+                CONFIG_TOML,
+                "topics",
+                "ocpp_retain",
+            )),
+            DEFAULT_OCPP_DELIVERY,
+        );
+        let status_delivery = Delivery::parse(
+            option_env!("CHARGER_TOPICS_STATUS_QOS").or(extract_toml_string(
+                CONFIG_TOML,
+                "topics",
+                "status_qos",
+            )),
+            option_env!("CHARGER_TOPICS_STATUS_RETAIN").or(extract_toml_string(
+                CONFIG_TOML,
+                "topics",
+                "status_retain",
+            )),
+            DEFAULT_STATUS_DELIVERY,
+        );
+        let toml_subscribe_qos =
+            extract_toml_integer(CONFIG_TOML, "topics", "subscribe_qos").unwrap_or(1);
+        let toml_telemetry_enabled =
+            extract_toml_string(CONFIG_TOML, "telemetry", "enabled").unwrap_or("false");
+        let toml_telemetry_interval =
+            extract_toml_integer(CONFIG_TOML, "telemetry", "interval").unwrap_or(60);
+        let toml_telemetry_anonymized =
+            extract_toml_string(CONFIG_TOML, "telemetry", "anonymized").unwrap_or("false");
+        let toml_analytics_broker =
+            extract_toml_string(CONFIG_TOML, "analytics", "broker").unwrap_or("");
+        let toml_analytics_port =
+            extract_toml_integer(CONFIG_TOML, "analytics", "port").unwrap_or(1883);
+        let toml_analytics_username =
+            extract_toml_string(CONFIG_TOML, "analytics", "username").unwrap_or("");
+        let toml_analytics_password =
+            extract_toml_string(CONFIG_TOML, "analytics", "password").unwrap_or("");
+        let toml_analytics_qos = extract_toml_integer(CONFIG_TOML, "analytics", "qos").unwrap_or(0);
+        let toml_pilot_diode_check =
+            extract_toml_string(CONFIG_TOML, "pilot", "diode_check").unwrap_or("false");
+        let toml_pilot_pwm = extract_toml_string(CONFIG_TOML, "pilot", "pwm").unwrap_or("false");
+        let toml_expander = extract_toml_string(CONFIG_TOML, "expander", "type").unwrap_or("");
+        let toml_expander_address =
+            extract_toml_integer(CONFIG_TOML, "expander", "address").unwrap_or(0x20);
+        let toml_relay_pin = extract_toml_string(CONFIG_TOML, "pins", "relay").unwrap_or("gpio");
+        let toml_relay_feedback_pin =
+            extract_toml_string(CONFIG_TOML, "pins", "relay_feedback").unwrap_or("");
+        let toml_demand_response_pin =
+            extract_toml_string(CONFIG_TOML, "pins", "demand_response").unwrap_or("");
+        let toml_lock_pin = extract_toml_string(CONFIG_TOML, "pins", "lock").unwrap_or("gpio");
+        let toml_cable_pin = extract_toml_string(CONFIG_TOML, "pins", "cable").unwrap_or("gpio");
+        let toml_led_count = extract_toml_integer(CONFIG_TOML, "leds", "count").unwrap_or(1);
+        let toml_led_brightness =
+            extract_toml_integer(CONFIG_TOML, "leds", "brightness").unwrap_or(20);
+        let toml_led_colors = extract_toml_string(CONFIG_TOML, "leds", "colors").unwrap_or("");
+        let toml_led_indicator =
+            extract_toml_string(CONFIG_TOML, "leds", "indicator").unwrap_or("");
+        let toml_led_polarity =
+            extract_toml_string(CONFIG_TOML, "leds", "polarity").unwrap_or("active_high");
+        let toml_button_enabled =
+            extract_toml_string(CONFIG_TOML, "button", "enabled").unwrap_or("true");
+        let toml_button_pin = extract_toml_string(CONFIG_TOML, "pins", "button").unwrap_or("gpio");
+        let toml_button_provisioning =
+            extract_toml_integer(CONFIG_TOML, "button", "provisioning").unwrap_or(3);
+        let toml_button_reset = extract_toml_integer(CONFIG_TOML, "button", "reset").unwrap_or(10);
+        let toml_rfid_gain = extract_toml_integer(CONFIG_TOML, "rfid", "gain").unwrap_or(33);
+        let toml_rfid_quiet = extract_toml_integer(CONFIG_TOML, "rfid", "quiet").unwrap_or(300);
+        let toml_rfid_reader =
+            extract_toml_string(CONFIG_TOML, "rfid", "reader").unwrap_or("mfrc522");
+        let toml_rfid_probe = extract_toml_integer(CONFIG_TOML, "rfid", "probe").unwrap_or(5);
+        let toml_temperature_warn =
+            extract_toml_integer(CONFIG_TOML, "temperature", "warn").unwrap_or(70);
+        let toml_temperature_critical =
+            extract_toml_integer(CONFIG_TOML, "temperature", "critical").unwrap_or(85);
+        let toml_ntc_enabled =
+            extract_toml_string(CONFIG_TOML, "temperature", "ntc").unwrap_or("false");
+        let toml_ntc_beta =
+            extract_toml_integer(CONFIG_TOML, "temperature", "ntc_beta").unwrap_or(3950);
+        let toml_ntc_ohms =
+            extract_toml_integer(CONFIG_TOML, "temperature", "ntc_ohms").unwrap_or(10000);
+        let toml_ntc_series_ohms =
+            extract_toml_integer(CONFIG_TOML, "temperature", "series_ohms").unwrap_or(10000);
+        let toml_meter_type = extract_toml_string(CONFIG_TOML, "meter", "type").unwrap_or("");
+        let toml_meter_voltage_cal =
+            extract_toml_integer(CONFIG_TOML, "meter", "voltage_cal").unwrap_or(1000);
+        let toml_meter_current_cal =
+            extract_toml_integer(CONFIG_TOML, "meter", "current_cal").unwrap_or(1000);
+        let toml_meter_power_cal =
+            extract_toml_integer(CONFIG_TOML, "meter", "power_cal").unwrap_or(1000);
+        let toml_meter_constant =
+            extract_toml_integer(CONFIG_TOML, "meter", "constant").unwrap_or(3200);
+        let toml_meter_address = extract_toml_integer(CONFIG_TOML, "meter", "address").unwrap_or(1);
+        let toml_meter_baud = extract_toml_integer(CONFIG_TOML, "meter", "baud").unwrap_or(9600);
+        let toml_rcd_enabled =
+            extract_toml_string(CONFIG_TOML, "rcd", "enabled").unwrap_or("false");
+        let toml_ble_provisioning =
+            extract_toml_string(CONFIG_TOML, "ble", "provisioning").unwrap_or("true");
+        let toml_ble_window = extract_toml_integer(CONFIG_TOML, "ble", "window").unwrap_or(10);
+        let toml_memory_shed_order = extract_toml_string(CONFIG_TOML, "memory", "shed_order")
+            .unwrap_or("analytics,display_pages,diagnostics");
+        let toml_webhook_url = extract_toml_string(CONFIG_TOML, "webhook", "url").unwrap_or("");
+        let toml_webhook_retries =
+            extract_toml_integer(CONFIG_TOML, "webhook", "retries").unwrap_or(5);
+        let timezone_offset_hours = option_env!("CHARGER_TIMEZONE_OFFSET_HOURS")
+            .and_then(|offset| offset.parse().ok())
+            .unwrap_or(toml_timezone_offset);
 
-        Self {
+        let config = Self {
             wifi_ssid: option_env!("CHARGER_WIFI_SSID").unwrap_or(toml_wifi_ssid),
             wifi_password: option_env!("CHARGER_WIFI_PASSWORD").unwrap_or(toml_wifi_password),
+            wifi_fallbacks: [
+                WifiNetwork {
+                    ssid: option_env!("CHARGER_WIFI_SSID_2").unwrap_or(toml_wifi_ssid_2),
+                    password: option_env!("CHARGER_WIFI_PASSWORD_2")
+                        .unwrap_or(toml_wifi_password_2),
+                },
+                WifiNetwork {
+                    ssid: option_env!("CHARGER_WIFI_SSID_3").unwrap_or(toml_wifi_ssid_3),
+                    password: option_env!("CHARGER_WIFI_PASSWORD_3")
+                        .unwrap_or(toml_wifi_password_3),
+                },
+            ],
+            wifi_roaming: option_env!("CHARGER_WIFI_ROAMING").unwrap_or(toml_wifi_roaming)
+                == "true",
+            wifi_roam_threshold_dbm: option_env!("CHARGER_WIFI_ROAM_THRESHOLD")
+                .and_then(|threshold| threshold.parse().ok())
+                .unwrap_or(toml_wifi_roam_threshold),
+            wifi_roam_after_secs: option_env!("CHARGER_WIFI_ROAM_AFTER")
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(toml_wifi_roam_after),
+            wifi_bssids: option_env!("CHARGER_WIFI_BSSIDS").unwrap_or(toml_wifi_bssids),
             charger_name: option_env!("CHARGER_NAME").unwrap_or(toml_charger_name),
             charger_model: option_env!("CHARGER_MODEL").unwrap_or(toml_charger_model),
             charger_vendor: option_env!("CHARGER_VENDOR").unwrap_or(toml_charger_vendor),
@@ -92,23 +757,357 @@ impl Config {
                 .and_then(|p| p.parse().ok())
                 .unwrap_or(toml_mqtt_port),
             mqtt_client_id: option_env!("CHARGER_MQTT_CLIENT_ID").unwrap_or(toml_mqtt_client_id),
+            mqtt_username: option_env!("CHARGER_MQTT_USERNAME").unwrap_or(toml_mqtt_username),
+            mqtt_password: option_env!("CHARGER_MQTT_PASSWORD").unwrap_or(toml_mqtt_password),
+            site_id: option_env!("CHARGER_SITE_ID").unwrap_or(toml_site_id),
+            onboarding_broker: option_env!("CHARGER_ONBOARDING_BROKER")
+                .unwrap_or(toml_onboarding_broker),
+            onboarding_port: option_env!("CHARGER_ONBOARDING_PORT")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(toml_onboarding_port),
+            onboarding_key: option_env!("CHARGER_ONBOARDING_KEY").unwrap_or(toml_onboarding_key),
             ntp_server: option_env!("CHARGER_NTP_SERVER").unwrap_or(toml_ntp_server),
             ntp_sync_interval_minutes: option_env!("CHARGER_NTP_SYNC_INTERVAL_MINUTES")
                 .and_then(|interval| interval.parse().ok())
                 .unwrap_or(toml_ntp_sync_interval_minutes),
-            timezone_offset_hours: option_env!("CHARGER_TIMEZONE_OFFSET_HOURS")
-                .and_then(|offset| offset.parse().ok())
-                .unwrap_or(toml_timezone_offset),
+            timezone_offset_hours,
+            time_zone: time_zone(
+                option_env!("CHARGER_TIMEZONE").unwrap_or(toml_timezone),
+                timezone_offset_hours,
+            ),
+            locale: Locale::parse(
+                option_env!("CHARGER_DISPLAY_DECIMAL").unwrap_or(toml_decimal),
+                option_env!("CHARGER_DISPLAY_CURRENCY").unwrap_or(toml_currency),
+                option_env!("CHARGER_DISPLAY_CURRENCY_POSITION").unwrap_or(toml_currency_position),
+            ),
+            tariff_cents_per_kwh: option_env!("CHARGER_DISPLAY_TARIFF")
+                .and_then(|tariff| tariff.parse().ok())
+                .unwrap_or(toml_tariff),
+            display_qr_url: option_env!("CHARGER_DISPLAY_QR_URL").unwrap_or(toml_qr_url),
+            display_dim_mins: option_env!("CHARGER_DISPLAY_DIM_AFTER")
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(toml_display_dim),
+            display_off_mins: option_env!("CHARGER_DISPLAY_OFF_AFTER")
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(toml_display_off),
             ocpp_heartbeat_interval: option_env!("CHARGER_OCPP_HEARTBEAT_INTERVAL")
                 .and_then(|interval| interval.parse().ok())
                 .unwrap_or(toml_heartbeat_interval),
-        }
+            ocpp_clock_drift_threshold_secs: option_env!("CHARGER_OCPP_CLOCK_DRIFT_THRESHOLD")
+                .and_then(|threshold| threshold.parse().ok())
+                .unwrap_or(toml_clock_drift_threshold),
+            stop_transaction_on_invalid_id: option_env!(
+                "CHARGER_OCPP_STOP_TRANSACTION_ON_INVALID_ID"
+            )
+            .unwrap_or(toml_stop_transaction_on_invalid_id)
+                == "true",
+            max_current_amps: option_env!("CHARGER_SMART_CHARGING_MAX_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(toml_max_current),
+            failsafe_current_amps: option_env!("CHARGER_SMART_CHARGING_FAILSAFE_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(toml_failsafe_current),
+            load_balancing_timeout_secs: option_env!(
+                "CHARGER_SMART_CHARGING_LOAD_BALANCING_TIMEOUT"
+            )
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(toml_load_balancing_timeout),
+            solar_timeout_secs: option_env!("CHARGER_SMART_CHARGING_SOLAR_TIMEOUT")
+                .and_then(|timeout| timeout.parse().ok())
+                .unwrap_or(toml_solar_timeout),
+            supply_voltage: option_env!("CHARGER_SMART_CHARGING_SUPPLY_VOLTAGE")
+                .and_then(|voltage| voltage.parse().ok())
+                .unwrap_or(toml_supply_voltage),
+            supply_phases: option_env!("CHARGER_SMART_CHARGING_PHASES")
+                .and_then(|phases| phases.parse().ok())
+                .unwrap_or(toml_supply_phases)
+                .clamp(1, 3) as u8,
+            soft_start_enabled: option_env!("CHARGER_SOFT_START_ENABLED")
+                .unwrap_or(toml_soft_start_enabled)
+                == "true",
+            soft_start_step_amps: option_env!("CHARGER_SOFT_START_STEP")
+                .and_then(|step| step.parse().ok())
+                .unwrap_or(toml_soft_start_step),
+            soft_start_step_secs: option_env!("CHARGER_SOFT_START_INTERVAL")
+                .and_then(|interval| interval.parse().ok())
+                .unwrap_or(toml_soft_start_interval),
+            soft_start_min_voltage: option_env!("CHARGER_SOFT_START_MIN_VOLTAGE")
+                .and_then(|voltage| voltage.parse().ok())
+                .unwrap_or(toml_soft_start_min_voltage),
+            load_management_enabled: option_env!("CHARGER_LOAD_MANAGEMENT_ENABLED")
+                .unwrap_or(toml_load_management_enabled)
+                == "true",
+            site_limit_amps: option_env!("CHARGER_LOAD_MANAGEMENT_SITE_LIMIT")
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(toml_site_limit),
+            peer_timeout_secs: option_env!("CHARGER_LOAD_MANAGEMENT_PEER_TIMEOUT")
+                .and_then(|timeout| timeout.parse().ok())
+                .unwrap_or(toml_peer_timeout),
+            solar_enabled: option_env!("CHARGER_SOLAR_ENABLED").unwrap_or(toml_solar_enabled)
+                == "true",
+            solar_min_current_amps: option_env!("CHARGER_SOLAR_MIN_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(toml_solar_min_current),
+            solar_hysteresis_watts: option_env!("CHARGER_SOLAR_HYSTERESIS")
+                .and_then(|hysteresis| hysteresis.parse().ok())
+                .unwrap_or(toml_solar_hysteresis),
+            waiting_list_enabled: option_env!("CHARGER_WAITING_LIST_ENABLED")
+                .unwrap_or(toml_waiting_list_enabled)
+                == "true",
+            waiting_priority_secs: option_env!("CHARGER_WAITING_LIST_PRIORITY_WINDOW")
+                .and_then(|window| window.parse().ok())
+                .unwrap_or(toml_waiting_priority),
+            watchdog_enabled: option_env!("CHARGER_WATCHDOG_ENABLED")
+                .unwrap_or(toml_watchdog_enabled)
+                == "true",
+            relay_open_deadline_ms: option_env!("CHARGER_WATCHDOG_RELAY_OPEN_DEADLINE")
+                .and_then(|deadline| deadline.parse().ok())
+                .unwrap_or(toml_relay_open_deadline)
+                .clamp(100, 5000),
+            mqtt_stall_secs: option_env!("CHARGER_WATCHDOG_MQTT_STALL")
+                .and_then(|stall| stall.parse().ok())
+                .unwrap_or(toml_mqtt_stall)
+                .clamp(30, 3600),
+            mqtt_stall_restarts: option_env!("CHARGER_WATCHDOG_MQTT_STALL_RESTARTS")
+                .and_then(|restarts| restarts.parse().ok())
+                .unwrap_or(toml_mqtt_stall_restarts)
+                .clamp(1, 10) as u8,
+            demand_response_mode: option_env!("CHARGER_DEMAND_RESPONSE_MODE")
+                .unwrap_or(toml_demand_response_mode),
+            demand_response_clamp_amps: option_env!("CHARGER_DEMAND_RESPONSE_CLAMP_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(toml_demand_response_clamp),
+            log_buffer_level: option_env!("CHARGER_LOGGING_BUFFER_LEVEL")
+                .unwrap_or(toml_log_buffer_level),
+            log_forward_target: option_env!("CHARGER_LOG_FORWARD_TARGET")
+                .unwrap_or(toml_log_forward_target),
+            log_forward_syslog: option_env!("CHARGER_LOG_FORWARD_SYSLOG")
+                .unwrap_or(toml_log_forward_syslog),
+            log_forward_per_minute: option_env!("CHARGER_LOG_FORWARD_PER_MINUTE")
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(toml_log_forward_per_minute)
+                .clamp(1, 600),
+            demo_mode: option_env!("CHARGER_DEMO_ENABLED").unwrap_or(toml_demo_enabled) == "true",
+            behavior_profile,
+            behavior,
+            quiet_override_mins: option_env!("CHARGER_BEHAVIOR_QUIET_OVERRIDE")
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(toml_quiet_override),
+            default_id_tag: option_env!("CHARGER_BEHAVIOR_DEFAULT_ID_TAG")
+                .unwrap_or(toml_default_id_tag),
+            local_id_tags: option_env!("CHARGER_BEHAVIOR_LOCAL_ID_TAGS")
+                .unwrap_or(toml_local_id_tags),
+            fault_lockout_count: option_env!("CHARGER_FAULT_LOCKOUT_COUNT")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(toml_fault_lockout_count),
+            fault_lockout_window_mins: option_env!("CHARGER_FAULT_LOCKOUT_WINDOW")
+                .and_then(|window| window.parse().ok())
+                .unwrap_or(toml_fault_lockout_window),
+            master_id_tag: option_env!("CHARGER_FAULT_MASTER_ID_TAG").unwrap_or(toml_master_id_tag),
+            offline_policy: OfflinePolicy::parse(
+                option_env!("CHARGER_OFFLINE_POLICY").unwrap_or(toml_offline_policy),
+            )
+            .unwrap_or(OfflinePolicy::Continue),
+            offline_grace_secs: option_env!("CHARGER_OFFLINE_GRACE")
+                .and_then(|grace| grace.parse().ok())
+                .unwrap_or(toml_offline_grace),
+            backend_timeout_secs: option_env!("CHARGER_OFFLINE_BACKEND_TIMEOUT")
+                .and_then(|timeout| timeout.parse().ok())
+                .unwrap_or(toml_backend_timeout),
+            accessibility_intensity: Intensity::parse(
+                option_env!("CHARGER_ACCESSIBILITY_INTENSITY")
+                    .unwrap_or(toml_accessibility_intensity),
+            )
+            .unwrap_or(Intensity::Medium),
+            buzzer_enabled: option_env!("CHARGER_BUZZER_ENABLED").unwrap_or(toml_buzzer_enabled)
+                == "true",
+            buzzer_volume: Intensity::parse(
+                option_env!("CHARGER_BUZZER_VOLUME").unwrap_or(toml_buzzer_volume),
+            ),
+            buzzer_alarm_secs: option_env!("CHARGER_BUZZER_ALARM")
+                .and_then(|alarm| alarm.parse().ok())
+                .unwrap_or(toml_buzzer_alarm),
+            factory_stage_watts: option_env!("CHARGER_FACTORY_STAGE_POWER")
+                .and_then(|power| power.parse().ok())
+                .unwrap_or(toml_factory_stage_power),
+            factory_session_secs: option_env!("CHARGER_FACTORY_SESSION_DURATION")
+                .and_then(|duration| duration.parse().ok())
+                .unwrap_or(toml_factory_session_duration),
+            factory_energy_tolerance: option_env!("CHARGER_FACTORY_ENERGY_TOLERANCE")
+                .and_then(|tolerance| tolerance.parse().ok())
+                .unwrap_or(toml_factory_energy_tolerance),
+            http_port: option_env!("CHARGER_HTTP_PORT")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(toml_http_port),
+            http_username: option_env!("CHARGER_HTTP_USERNAME").unwrap_or(toml_http_username),
+            http_password: option_env!("CHARGER_HTTP_PASSWORD").unwrap_or(toml_http_password),
+            mdns_enabled: option_env!("CHARGER_MDNS_ENABLED").unwrap_or(toml_mdns_enabled)
+                != "false",
+            usb_api_enabled: option_env!("CHARGER_USB_API").unwrap_or(toml_usb_api_enabled)
+                != "false",
+            ota_delta_enabled: option_env!("CHARGER_OTA_DELTA").unwrap_or(toml_ota_delta_enabled)
+                != "false",
+            ota_key: option_env!("CHARGER_OTA_KEY").unwrap_or(toml_ota_key),
+            topics,
+            ocpp_delivery,
+            status_delivery,
+            subscribe_qos: option_env!("CHARGER_TOPICS_SUBSCRIBE_QOS")
+                .and_then(|qos| qos.parse().ok())
+                .unwrap_or(toml_subscribe_qos)
+                .min(1) as u8,
+            telemetry_enabled: option_env!("CHARGER_TELEMETRY_ENABLED")
+                .unwrap_or(toml_telemetry_enabled)
+                == "true",
+            telemetry_interval_secs: option_env!("CHARGER_TELEMETRY_INTERVAL")
+                .and_then(|interval| interval.parse().ok())
+                .unwrap_or(toml_telemetry_interval),
+            telemetry_anonymized: option_env!("CHARGER_TELEMETRY_ANONYMIZED")
+                .unwrap_or(toml_telemetry_anonymized)
+                == "true",
+            analytics_broker: option_env!("CHARGER_ANALYTICS_BROKER")
+                .unwrap_or(toml_analytics_broker),
+            analytics_port: option_env!("CHARGER_ANALYTICS_PORT")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(toml_analytics_port),
+            analytics_username: option_env!("CHARGER_ANALYTICS_USERNAME")
+                .unwrap_or(toml_analytics_username),
+            analytics_password: option_env!("CHARGER_ANALYTICS_PASSWORD")
+                .unwrap_or(toml_analytics_password),
+            analytics_qos: option_env!("CHARGER_ANALYTICS_QOS")
+                .and_then(|qos| qos.parse().ok())
+                .unwrap_or(toml_analytics_qos)
+                .min(1) as u8,
+            pilot_diode_check: option_env!("CHARGER_PILOT_DIODE_CHECK")
+                .unwrap_or(toml_pilot_diode_check)
+                == "true",
+            pilot_pwm: option_env!("CHARGER_PILOT_PWM").unwrap_or(toml_pilot_pwm) == "true",
+            expander: option_env!("CHARGER_EXPANDER").unwrap_or(toml_expander),
+            expander_address: option_env!("CHARGER_EXPANDER_ADDRESS")
+                .and_then(|address| address.parse().ok())
+                .unwrap_or(toml_expander_address)
+                .min(0x7F) as u8,
+            relay_pin: option_env!("CHARGER_RELAY_PIN").unwrap_or(toml_relay_pin),
+            relay_feedback_pin: option_env!("CHARGER_RELAY_FEEDBACK_PIN")
+                .unwrap_or(toml_relay_feedback_pin),
+            demand_response_pin: option_env!("CHARGER_DEMAND_RESPONSE_PIN")
+                .unwrap_or(toml_demand_response_pin),
+            lock_pin: option_env!("CHARGER_LOCK_PIN").unwrap_or(toml_lock_pin),
+            cable_pin: option_env!("CHARGER_CABLE_PIN").unwrap_or(toml_cable_pin),
+            led_count: option_env!("CHARGER_LED_COUNT")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(toml_led_count)
+                .clamp(1, MAX_LEDS as u16) as u8,
+            led_brightness: option_env!("CHARGER_LED_BRIGHTNESS")
+                .and_then(|brightness| brightness.parse().ok())
+                .unwrap_or(toml_led_brightness)
+                .min(255) as u8,
+            led_colors: option_env!("CHARGER_LED_COLORS").unwrap_or(toml_led_colors),
+            led_indicator: option_env!("CHARGER_LED_INDICATOR").unwrap_or(toml_led_indicator),
+            led_polarity: Polarity::parse(
+                option_env!("CHARGER_LED_POLARITY").unwrap_or(toml_led_polarity),
+            )
+            .unwrap_or(Polarity::ActiveHigh),
+            button_enabled: option_env!("CHARGER_BUTTON_ENABLED").unwrap_or(toml_button_enabled)
+                == "true",
+            button_pin: option_env!("CHARGER_BUTTON_PIN").unwrap_or(toml_button_pin),
+            button_provisioning_secs: option_env!("CHARGER_BUTTON_PROVISIONING")
+                .and_then(|hold| hold.parse().ok())
+                .unwrap_or(toml_button_provisioning),
+            button_reset_secs: option_env!("CHARGER_BUTTON_RESET")
+                .and_then(|hold| hold.parse().ok())
+                .unwrap_or(toml_button_reset),
+            rcd_enabled: option_env!("CHARGER_RCD_ENABLED").unwrap_or(toml_rcd_enabled) == "true",
+            rfid_gain_db: option_env!("CHARGER_RFID_GAIN")
+                .and_then(|gain| gain.parse().ok())
+                .unwrap_or(toml_rfid_gain),
+            rfid_quiet_ms: option_env!("CHARGER_RFID_QUIET")
+                .and_then(|quiet| quiet.parse().ok())
+                .unwrap_or(toml_rfid_quiet),
+            rfid_reader: option_env!("CHARGER_RFID_READER").unwrap_or(toml_rfid_reader),
+            rfid_probe_secs: option_env!("CHARGER_RFID_PROBE")
+                .and_then(|probe| probe.parse().ok())
+                .unwrap_or(toml_rfid_probe)
+                .clamp(1, 60),
+            temperature_warn_c: option_env!("CHARGER_TEMPERATURE_WARN")
+                .and_then(|celsius| celsius.parse().ok())
+                .unwrap_or(toml_temperature_warn),
+            temperature_critical_c: option_env!("CHARGER_TEMPERATURE_CRITICAL")
+                .and_then(|celsius| celsius.parse().ok())
+                .unwrap_or(toml_temperature_critical),
+            ntc_enabled: option_env!("CHARGER_NTC_ENABLED").unwrap_or(toml_ntc_enabled) == "true",
+            ntc_beta: option_env!("CHARGER_NTC_BETA")
+                .and_then(|beta| beta.parse().ok())
+                .unwrap_or(toml_ntc_beta),
+            ntc_ohms: option_env!("CHARGER_NTC_OHMS")
+                .and_then(|ohms| ohms.parse().ok())
+                .unwrap_or(toml_ntc_ohms),
+            ntc_series_ohms: option_env!("CHARGER_NTC_SERIES_OHMS")
+                .and_then(|ohms| ohms.parse().ok())
+                .unwrap_or(toml_ntc_series_ohms),
+            meter_type: option_env!("CHARGER_METER_TYPE").unwrap_or(toml_meter_type),
+            meter_voltage_cal: option_env!("CHARGER_METER_VOLTAGE_CAL")
+                .and_then(|cal| cal.parse().ok())
+                .unwrap_or(toml_meter_voltage_cal),
+            meter_current_cal: option_env!("CHARGER_METER_CURRENT_CAL")
+                .and_then(|cal| cal.parse().ok())
+                .unwrap_or(toml_meter_current_cal),
+            meter_power_cal: option_env!("CHARGER_METER_POWER_CAL")
+                .and_then(|cal| cal.parse().ok())
+                .unwrap_or(toml_meter_power_cal),
+            meter_constant: option_env!("CHARGER_METER_CONSTANT")
+                .and_then(|constant| constant.parse().ok())
+                .unwrap_or(toml_meter_constant),
+            meter_address: option_env!("CHARGER_METER_ADDRESS")
+                .and_then(|address| address.parse().ok())
+                .unwrap_or(toml_meter_address)
+                .clamp(1, 247) as u8,
+            meter_baud: option_env!("CHARGER_METER_BAUD")
+                .and_then(|baud| baud.parse().ok())
+                .unwrap_or(toml_meter_baud),
+            ble_provisioning: option_env!("CHARGER_BLE_PROVISIONING")
+                .unwrap_or(toml_ble_provisioning)
+                == "true",
+            ble_window_mins: option_env!("CHARGER_BLE_WINDOW")
+                .and_then(|window| window.parse().ok())
+                .unwrap_or(toml_ble_window),
+            memory_shed_order: option_env!("CHARGER_MEMORY_SHED_ORDER")
+                .unwrap_or(toml_memory_shed_order),
+            webhook_url: option_env!("CHARGER_WEBHOOK_URL").unwrap_or(toml_webhook_url),
+            webhook_retries: option_env!("CHARGER_WEBHOOK_RETRIES")
+                .and_then(|retries| retries.parse().ok())
+                .unwrap_or(toml_webhook_retries)
+                .min(10) as u8,
+        };
+
+        config.with_provisioned().with_local_settings()
     }
 
     pub fn from_env() -> Self {
+        let behavior_profile = behavior_profile(option_env!("CHARGER_BEHAVIOR_PROFILE"));
+        let timezone_offset_hours = option_env!("CHARGER_TIMEZONE_OFFSET_HOURS")
+            .and_then(|offset| offset.parse().ok())
+            .unwrap_or(0);
         Self {
             wifi_ssid: option_env!("CHARGER_WIFI_SSID").unwrap_or("Wokwi-GUEST"),
             wifi_password: option_env!("CHARGER_WIFI_PASSWORD").unwrap_or(""),
+            wifi_fallbacks: [
+                WifiNetwork {
+                    ssid: option_env!("CHARGER_WIFI_SSID_2").unwrap_or(""),
+                    password: option_env!("CHARGER_WIFI_PASSWORD_2").unwrap_or(""),
+                },
+                WifiNetwork {
+                    ssid: option_env!("CHARGER_WIFI_SSID_3").unwrap_or(""),
+                    password: option_env!("CHARGER_WIFI_PASSWORD_3").unwrap_or(""),
+                },
+            ],
+            wifi_roaming: option_env!("CHARGER_WIFI_ROAMING") == Some("true"),
+            wifi_roam_threshold_dbm: option_env!("CHARGER_WIFI_ROAM_THRESHOLD")
+                .and_then(|threshold| threshold.parse().ok())
+                .unwrap_or(-75),
+            wifi_roam_after_secs: option_env!("CHARGER_WIFI_ROAM_AFTER")
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(60),
+            wifi_bssids: option_env!("CHARGER_WIFI_BSSIDS").unwrap_or(""),
             charger_name: option_env!("CHARGER_NAME").unwrap_or("esp32c6-charger-001"),
             charger_model: option_env!("CHARGER_MODEL").unwrap_or("ESP32-C6"),
             charger_vendor: option_env!("CHARGER_VENDOR").unwrap_or("GA Make"),
@@ -118,29 +1117,514 @@ impl Config {
                 .and_then(|p| p.parse().ok())
                 .unwrap_or(1883),
             mqtt_client_id: option_env!("CHARGER_MQTT_CLIENT_ID").unwrap_or("esp32c6-charger-001"),
+            mqtt_username: option_env!("CHARGER_MQTT_USERNAME").unwrap_or(""),
+            mqtt_password: option_env!("CHARGER_MQTT_PASSWORD").unwrap_or(""),
+            site_id: option_env!("CHARGER_SITE_ID").unwrap_or(""),
+            onboarding_broker: option_env!("CHARGER_ONBOARDING_BROKER")
+                .unwrap_or("broker.hivemq.com"),
+            onboarding_port: option_env!("CHARGER_ONBOARDING_PORT")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(1883),
+            onboarding_key: option_env!("CHARGER_ONBOARDING_KEY").unwrap_or(""),
             ntp_server: option_env!("CHARGER_NTP_SERVER").unwrap_or("pool.ntp.org"),
             ntp_sync_interval_minutes: option_env!("CHARGER_NTP_SYNC_INTERVAL_MINUTES")
                 .and_then(|interval| interval.parse().ok())
                 .unwrap_or(240),
-            timezone_offset_hours: option_env!("CHARGER_TIMEZONE_OFFSET_HOURS")
-                .and_then(|offset| offset.parse().ok())
+            timezone_offset_hours,
+            time_zone: time_zone(
+                option_env!("CHARGER_TIMEZONE").unwrap_or(""),
+                timezone_offset_hours,
+            ),
+            locale: Locale::parse(
+                option_env!("CHARGER_DISPLAY_DECIMAL").unwrap_or("point"),
+                option_env!("CHARGER_DISPLAY_CURRENCY").unwrap_or("EUR"),
+                option_env!("CHARGER_DISPLAY_CURRENCY_POSITION").unwrap_or("before"),
+            ),
+            tariff_cents_per_kwh: option_env!("CHARGER_DISPLAY_TARIFF")
+                .and_then(|tariff| tariff.parse().ok())
                 .unwrap_or(0),
+            display_qr_url: option_env!("CHARGER_DISPLAY_QR_URL").unwrap_or(""),
+            display_dim_mins: option_env!("CHARGER_DISPLAY_DIM_AFTER")
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(5),
+            display_off_mins: option_env!("CHARGER_DISPLAY_OFF_AFTER")
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(30),
             ocpp_heartbeat_interval: option_env!("CHARGER_OCPP_HEARTBEAT_INTERVAL")
                 .and_then(|interval| interval.parse().ok())
                 .unwrap_or(900),
+            ocpp_clock_drift_threshold_secs: option_env!("CHARGER_OCPP_CLOCK_DRIFT_THRESHOLD")
+                .and_then(|threshold| threshold.parse().ok())
+                .unwrap_or(30),
+            stop_transaction_on_invalid_id: option_env!(
+                "CHARGER_OCPP_STOP_TRANSACTION_ON_INVALID_ID"
+            ) != Some("false"),
+            max_current_amps: option_env!("CHARGER_SMART_CHARGING_MAX_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(16),
+            failsafe_current_amps: option_env!("CHARGER_SMART_CHARGING_FAILSAFE_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(6),
+            load_balancing_timeout_secs: option_env!(
+                "CHARGER_SMART_CHARGING_LOAD_BALANCING_TIMEOUT"
+            )
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(120),
+            solar_timeout_secs: option_env!("CHARGER_SMART_CHARGING_SOLAR_TIMEOUT")
+                .and_then(|timeout| timeout.parse().ok())
+                .unwrap_or(300),
+            supply_voltage: option_env!("CHARGER_SMART_CHARGING_SUPPLY_VOLTAGE")
+                .and_then(|voltage| voltage.parse().ok())
+                .unwrap_or(230),
+            supply_phases: option_env!("CHARGER_SMART_CHARGING_PHASES")
+                .and_then(|phases| phases.parse::<u8>().ok())
+                .unwrap_or(1)
+                .clamp(1, 3),
+            soft_start_enabled: option_env!("CHARGER_SOFT_START_ENABLED") == Some("true"),
+            soft_start_step_amps: option_env!("CHARGER_SOFT_START_STEP")
+                .and_then(|step| step.parse().ok())
+                .unwrap_or(2),
+            soft_start_step_secs: option_env!("CHARGER_SOFT_START_INTERVAL")
+                .and_then(|interval| interval.parse().ok())
+                .unwrap_or(30),
+            soft_start_min_voltage: option_env!("CHARGER_SOFT_START_MIN_VOLTAGE")
+                .and_then(|voltage| voltage.parse().ok())
+                .unwrap_or(207),
+            load_management_enabled: option_env!("CHARGER_LOAD_MANAGEMENT_ENABLED") == Some("true"),
+            site_limit_amps: option_env!("CHARGER_LOAD_MANAGEMENT_SITE_LIMIT")
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(32),
+            peer_timeout_secs: option_env!("CHARGER_LOAD_MANAGEMENT_PEER_TIMEOUT")
+                .and_then(|timeout| timeout.parse().ok())
+                .unwrap_or(60),
+            solar_enabled: option_env!("CHARGER_SOLAR_ENABLED") == Some("true"),
+            solar_min_current_amps: option_env!("CHARGER_SOLAR_MIN_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(6),
+            solar_hysteresis_watts: option_env!("CHARGER_SOLAR_HYSTERESIS")
+                .and_then(|hysteresis| hysteresis.parse().ok())
+                .unwrap_or(300),
+            waiting_list_enabled: option_env!("CHARGER_WAITING_LIST_ENABLED") == Some("true"),
+            waiting_priority_secs: option_env!("CHARGER_WAITING_LIST_PRIORITY_WINDOW")
+                .and_then(|window| window.parse().ok())
+                .unwrap_or(300),
+            watchdog_enabled: option_env!("CHARGER_WATCHDOG_ENABLED") != Some("false"),
+            relay_open_deadline_ms: option_env!("CHARGER_WATCHDOG_RELAY_OPEN_DEADLINE")
+                .and_then(|deadline| deadline.parse::<u16>().ok())
+                .unwrap_or(500)
+                .clamp(100, 5000),
+            mqtt_stall_secs: option_env!("CHARGER_WATCHDOG_MQTT_STALL")
+                .and_then(|stall| stall.parse::<u16>().ok())
+                .unwrap_or(120)
+                .clamp(30, 3600),
+            mqtt_stall_restarts: option_env!("CHARGER_WATCHDOG_MQTT_STALL_RESTARTS")
+                .and_then(|restarts| restarts.parse::<u8>().ok())
+                .unwrap_or(3)
+                .clamp(1, 10),
+            demand_response_mode: option_env!("CHARGER_DEMAND_RESPONSE_MODE").unwrap_or("suspend"),
+            demand_response_clamp_amps: option_env!("CHARGER_DEMAND_RESPONSE_CLAMP_CURRENT")
+                .and_then(|current| current.parse().ok())
+                .unwrap_or(6),
+            log_buffer_level: option_env!("CHARGER_LOGGING_BUFFER_LEVEL").unwrap_or("info"),
+            log_forward_target: option_env!("CHARGER_LOG_FORWARD_TARGET").unwrap_or(""),
+            log_forward_syslog: option_env!("CHARGER_LOG_FORWARD_SYSLOG").unwrap_or(""),
+            log_forward_per_minute: option_env!("CHARGER_LOG_FORWARD_PER_MINUTE")
+                .and_then(|limit| limit.parse::<u16>().ok())
+                .unwrap_or(20)
+                .clamp(1, 600),
+            demo_mode: option_env!("CHARGER_DEMO_ENABLED") == Some("true"),
+            behavior_profile,
+            behavior: behavior_settings(
+                behavior_profile,
+                option_env!("CHARGER_BEHAVIOR_FREE_VEND"),
+                option_env!("CHARGER_BEHAVIOR_RECEIPTS"),
+                option_env!("CHARGER_BEHAVIOR_QUIET_HOURS"),
+                option_env!("CHARGER_BEHAVIOR_DISPLAY_PAGES"),
+                option_env!("CHARGER_BEHAVIOR_AUTHORIZATION"),
+            ),
+            quiet_override_mins: option_env!("CHARGER_BEHAVIOR_QUIET_OVERRIDE")
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(10),
+            default_id_tag: option_env!("CHARGER_BEHAVIOR_DEFAULT_ID_TAG").unwrap_or("FREEVEND"),
+            local_id_tags: option_env!("CHARGER_BEHAVIOR_LOCAL_ID_TAGS").unwrap_or(""),
+            fault_lockout_count: option_env!("CHARGER_FAULT_LOCKOUT_COUNT")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(3),
+            fault_lockout_window_mins: option_env!("CHARGER_FAULT_LOCKOUT_WINDOW")
+                .and_then(|window| window.parse().ok())
+                .unwrap_or(60),
+            master_id_tag: option_env!("CHARGER_FAULT_MASTER_ID_TAG").unwrap_or(""),
+            offline_policy: option_env!("CHARGER_OFFLINE_POLICY")
+                .and_then(OfflinePolicy::parse)
+                .unwrap_or(OfflinePolicy::Continue),
+            offline_grace_secs: option_env!("CHARGER_OFFLINE_GRACE")
+                .and_then(|grace| grace.parse().ok())
+                .unwrap_or(60),
+            backend_timeout_secs: option_env!("CHARGER_OFFLINE_BACKEND_TIMEOUT")
+                .and_then(|timeout| timeout.parse().ok())
+                .unwrap_or(600),
+            accessibility_intensity: option_env!("CHARGER_ACCESSIBILITY_INTENSITY")
+                .and_then(Intensity::parse)
+                .unwrap_or(Intensity::Medium),
+            buzzer_enabled: option_env!("CHARGER_BUZZER_ENABLED") != Some("false"),
+            buzzer_volume: option_env!("CHARGER_BUZZER_VOLUME").and_then(Intensity::parse),
+            buzzer_alarm_secs: option_env!("CHARGER_BUZZER_ALARM")
+                .and_then(|alarm| alarm.parse().ok())
+                .unwrap_or(60),
+            factory_stage_watts: option_env!("CHARGER_FACTORY_STAGE_POWER")
+                .and_then(|power| power.parse().ok())
+                .unwrap_or(1000),
+            factory_session_secs: option_env!("CHARGER_FACTORY_SESSION_DURATION")
+                .and_then(|duration| duration.parse().ok())
+                .unwrap_or(10),
+            factory_energy_tolerance: option_env!("CHARGER_FACTORY_ENERGY_TOLERANCE")
+                .and_then(|tolerance| tolerance.parse().ok())
+                .unwrap_or(10),
+            http_port: option_env!("CHARGER_HTTP_PORT")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(80),
+            http_username: option_env!("CHARGER_HTTP_USERNAME").unwrap_or("admin"),
+            http_password: option_env!("CHARGER_HTTP_PASSWORD").unwrap_or(""),
+            mdns_enabled: option_env!("CHARGER_MDNS_ENABLED") != Some("false"),
+            usb_api_enabled: option_env!("CHARGER_USB_API") != Some("false"),
+            ota_delta_enabled: option_env!("CHARGER_OTA_DELTA") != Some("false"),
+            ota_key: option_env!("CHARGER_OTA_KEY").unwrap_or(""),
+            topics: TopicTemplates {
+                charger: option_env!("CHARGER_TOPICS_CHARGER")
+                    .unwrap_or(TopicTemplates::DEFAULT.charger),
+                system: option_env!("CHARGER_TOPICS_SYSTEM")
+                    .unwrap_or(TopicTemplates::DEFAULT.system),
+                ocpp: option_env!("CHARGER_TOPICS_OCPP").unwrap_or(TopicTemplates::DEFAULT.ocpp),
+                cmd: option_env!("CHARGER_TOPICS_CMD").unwrap_or(TopicTemplates::DEFAULT.cmd),
+                ota: option_env!("CHARGER_TOPICS_OTA").unwrap_or(TopicTemplates::DEFAULT.ota),
+                status: option_env!("CHARGER_TOPICS_STATUS")
+                    .unwrap_or(TopicTemplates::DEFAULT.status),
+                telemetry: option_env!("CHARGER_TOPICS_TELEMETRY")
+                    .unwrap_or(TopicTemplates::DEFAULT.telemetry),
+                load: option_env!("CHARGER_TOPICS_LOAD").unwrap_or(TopicTemplates::DEFAULT.load),
+                grid: option_env!("CHARGER_TOPICS_GRID").unwrap_or(TopicTemplates::DEFAULT.grid),
+                log: option_env!("CHARGER_TOPICS_LOG").unwrap_or(TopicTemplates::DEFAULT.log),
+            },
+            ocpp_delivery: Delivery::parse(
+                option_env!("CHARGER_TOPICS_OCPP_QOS"),
+                option_env!("CHARGER_TOPICS_OCPP_RETAIN"),
+                DEFAULT_OCPP_DELIVERY,
+            ),
+            status_delivery: Delivery::parse(
+                option_env!("CHARGER_TOPICS_STATUS_QOS"),
+                option_env!("CHARGER_TOPICS_STATUS_RETAIN"),
+                DEFAULT_STATUS_DELIVERY,
+            ),
+            subscribe_qos: option_env!("CHARGER_TOPICS_SUBSCRIBE_QOS")
+                .and_then(|qos| qos.parse::<u8>().ok())
+                .unwrap_or(1)
+                .min(1),
+            telemetry_enabled: option_env!("CHARGER_TELEMETRY_ENABLED") == Some("true"),
+            telemetry_interval_secs: option_env!("CHARGER_TELEMETRY_INTERVAL")
+                .and_then(|interval| interval.parse().ok())
+                .unwrap_or(60),
+            telemetry_anonymized: option_env!("CHARGER_TELEMETRY_ANONYMIZED") == Some("true"),
+            analytics_broker: option_env!("CHARGER_ANALYTICS_BROKER").unwrap_or(""),
+            analytics_port: option_env!("CHARGER_ANALYTICS_PORT")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(1883),
+            analytics_username: option_env!("CHARGER_ANALYTICS_USERNAME").unwrap_or(""),
+            analytics_password: option_env!("CHARGER_ANALYTICS_PASSWORD").unwrap_or(""),
+            analytics_qos: option_env!("CHARGER_ANALYTICS_QOS")
+                .and_then(|qos| qos.parse::<u8>().ok())
+                .unwrap_or(0)
+                .min(1),
+            pilot_diode_check: option_env!("CHARGER_PILOT_DIODE_CHECK") == Some("true"),
+            pilot_pwm: option_env!("CHARGER_PILOT_PWM") == Some("true"),
+            expander: option_env!("CHARGER_EXPANDER").unwrap_or(""),
+            expander_address: option_env!("CHARGER_EXPANDER_ADDRESS")
+                .and_then(|address| address.parse::<u8>().ok())
+                .unwrap_or(0x20)
+                .min(0x7F),
+            relay_pin: option_env!("CHARGER_RELAY_PIN").unwrap_or("gpio"),
+            relay_feedback_pin: option_env!("CHARGER_RELAY_FEEDBACK_PIN").unwrap_or(""),
+            demand_response_pin: option_env!("CHARGER_DEMAND_RESPONSE_PIN").unwrap_or(""),
+            lock_pin: option_env!("CHARGER_LOCK_PIN").unwrap_or("gpio"),
+            cable_pin: option_env!("CHARGER_CABLE_PIN").unwrap_or("gpio"),
+            led_count: option_env!("CHARGER_LED_COUNT")
+                .and_then(|count| count.parse::<u8>().ok())
+                .unwrap_or(1)
+                .clamp(1, MAX_LEDS as u8),
+            led_brightness: option_env!("CHARGER_LED_BRIGHTNESS")
+                .and_then(|brightness| brightness.parse().ok())
+                .unwrap_or(20),
+            led_colors: option_env!("CHARGER_LED_COLORS").unwrap_or(""),
+            led_indicator: option_env!("CHARGER_LED_INDICATOR").unwrap_or(""),
+            led_polarity: option_env!("CHARGER_LED_POLARITY")
+                .and_then(Polarity::parse)
+                .unwrap_or(Polarity::ActiveHigh),
+            button_enabled: option_env!("CHARGER_BUTTON_ENABLED") != Some("false"),
+            button_pin: option_env!("CHARGER_BUTTON_PIN").unwrap_or("gpio"),
+            button_provisioning_secs: option_env!("CHARGER_BUTTON_PROVISIONING")
+                .and_then(|hold| hold.parse().ok())
+                .unwrap_or(3),
+            button_reset_secs: option_env!("CHARGER_BUTTON_RESET")
+                .and_then(|hold| hold.parse().ok())
+                .unwrap_or(10),
+            rcd_enabled: option_env!("CHARGER_RCD_ENABLED") == Some("true"),
+            rfid_gain_db: option_env!("CHARGER_RFID_GAIN")
+                .and_then(|gain| gain.parse().ok())
+                .unwrap_or(33),
+            rfid_quiet_ms: option_env!("CHARGER_RFID_QUIET")
+                .and_then(|quiet| quiet.parse().ok())
+                .unwrap_or(300),
+            rfid_reader: option_env!("CHARGER_RFID_READER").unwrap_or("mfrc522"),
+            rfid_probe_secs: option_env!("CHARGER_RFID_PROBE")
+                .and_then(|probe| probe.parse::<u16>().ok())
+                .unwrap_or(5)
+                .clamp(1, 60),
+            temperature_warn_c: option_env!("CHARGER_TEMPERATURE_WARN")
+                .and_then(|celsius| celsius.parse().ok())
+                .unwrap_or(70),
+            temperature_critical_c: option_env!("CHARGER_TEMPERATURE_CRITICAL")
+                .and_then(|celsius| celsius.parse().ok())
+                .unwrap_or(85),
+            ntc_enabled: option_env!("CHARGER_NTC_ENABLED") == Some("true"),
+            ntc_beta: option_env!("CHARGER_NTC_BETA")
+                .and_then(|beta| beta.parse().ok())
+                .unwrap_or(3950),
+            ntc_ohms: option_env!("CHARGER_NTC_OHMS")
+                .and_then(|ohms| ohms.parse().ok())
+                .unwrap_or(10000),
+            ntc_series_ohms: option_env!("CHARGER_NTC_SERIES_OHMS")
+                .and_then(|ohms| ohms.parse().ok())
+                .unwrap_or(10000),
+            meter_type: option_env!("CHARGER_METER_TYPE").unwrap_or(""),
+            meter_voltage_cal: option_env!("CHARGER_METER_VOLTAGE_CAL")
+                .and_then(|cal| cal.parse().ok())
+                .unwrap_or(1000),
+            meter_current_cal: option_env!("CHARGER_METER_CURRENT_CAL")
+                .and_then(|cal| cal.parse().ok())
+                .unwrap_or(1000),
+            meter_power_cal: option_env!("CHARGER_METER_POWER_CAL")
+                .and_then(|cal| cal.parse().ok())
+                .unwrap_or(1000),
+            meter_constant: option_env!("CHARGER_METER_CONSTANT")
+                .and_then(|constant| constant.parse().ok())
+                .unwrap_or(3200),
+            meter_address: option_env!("CHARGER_METER_ADDRESS")
+                .and_then(|address| address.parse::<u16>().ok())
+                .unwrap_or(1)
+                .clamp(1, 247) as u8,
+            meter_baud: option_env!("CHARGER_METER_BAUD")
+                .and_then(|baud| baud.parse().ok())
+                .unwrap_or(9600),
+            ble_provisioning: option_env!("CHARGER_BLE_PROVISIONING") != Some("false"),
+            ble_window_mins: option_env!("CHARGER_BLE_WINDOW")
+                .and_then(|window| window.parse().ok())
+                .unwrap_or(10),
+            memory_shed_order: option_env!("CHARGER_MEMORY_SHED_ORDER")
+                .unwrap_or("analytics,display_pages,diagnostics"),
+            webhook_url: option_env!("CHARGER_WEBHOOK_URL").unwrap_or(""),
+            webhook_retries: option_env!("CHARGER_WEBHOOK_RETRIES")
+                .and_then(|retries| retries.parse::<u8>().ok())
+                .unwrap_or(5)
+                .min(10),
+        }
+    }
+
+    /// Apply the settings from the onboarding claim flow, if any
+    fn with_provisioned(mut self) -> Self {
+        if let Some(provisioned) = provisioned() {
+            self.mqtt_broker = provisioned.mqtt_broker;
+            self.mqtt_port = provisioned.mqtt_port;
+            self.mqtt_client_id = provisioned.mqtt_client_id;
+            self.mqtt_username = provisioned.mqtt_username;
+            self.mqtt_password = provisioned.mqtt_password;
+            self.site_id = provisioned.site_id;
+        }
+        self
+    }
+
+    /// Apply the settings stored on site, if any
+    fn with_local_settings(mut self) -> Self {
+        if let Some(local) = local_settings() {
+            self.wifi_ssid = local.wifi_ssid.unwrap_or(self.wifi_ssid);
+            self.wifi_password = local.wifi_password.unwrap_or(self.wifi_password);
+            self.mqtt_broker = local.mqtt_broker.unwrap_or(self.mqtt_broker);
+            self.charger_serial = local.charger_serial.unwrap_or(self.charger_serial);
+        }
+        self
+    }
+
+    /// The configured WiFi networks in priority order, the network set on site comes first
+    pub fn wifi_networks(&self) -> impl Iterator<Item = WifiNetwork> + '_ {
+        let primary = WifiNetwork {
+            ssid: self.wifi_ssid,
+            password: self.wifi_password,
+        };
+        core::iter::once(primary).chain(
+            self.wifi_fallbacks
+                .iter()
+                .copied()
+                .filter(|network| !network.ssid.is_empty() && network.ssid != self.wifi_ssid),
+        )
+    }
+
+    /// No backend is configured, the charger has to be claimed through onboarding
+    pub fn needs_onboarding(&self) -> bool {
+        self.mqtt_broker.is_empty()
+    }
+
+    /// Session and fault events are posted to a webhook
+    pub fn webhook_enabled(&self) -> bool {
+        !self.webhook_url.is_empty()
+    }
+
+    /// Warnings and errors are forwarded to a syslog server or the log topic
+    pub fn log_forward_enabled(&self) -> bool {
+        !self.log_forward_target.is_empty()
+    }
+
+    /// Telemetry goes to a separate analytics broker instead of the OCPP broker
+    pub fn analytics_enabled(&self) -> bool {
+        !self.analytics_broker.is_empty()
+    }
+
+    /// Check the identifiers and topic templates that end up in MQTT topics, client ids and JSON
+    /// Returns the offending setting with the reason it was rejected
+    pub fn validate_identifiers(&self) -> Result<(), (&'static str, &'static str)> {
+        utils::validate_identifier(self.charger_serial).map_err(|e| ("charger serial", e))?;
+        if !self.mqtt_client_id.is_empty() {
+            utils::validate_identifier(self.mqtt_client_id).map_err(|e| ("MQTT client id", e))?;
+        }
+        if !self.site_id.is_empty() {
+            utils::validate_identifier(self.site_id).map_err(|e| ("site id", e))?;
+        }
+        let templates = [
+            ("charger topic", self.topics.charger),
+            ("system topic", self.topics.system),
+            ("ocpp topic", self.topics.ocpp),
+            ("cmd topic", self.topics.cmd),
+            ("ota topic", self.topics.ota),
+            ("status topic", self.topics.status),
+            ("telemetry topic", self.topics.telemetry),
+            ("load topic", self.topics.load),
+            ("grid topic", self.topics.grid),
+            ("log topic", self.topics.log),
+        ];
+        for (setting, template) in templates {
+            if template.is_empty() {
+                return Err((setting, "is empty"));
+            }
+            if template.contains(['+', '#']) {
+                return Err((setting, "contains an MQTT wildcard (+ or #)"));
+            }
         }
+        Ok(())
+    }
+
+    /// Whether an id tag is in the local authorization list
+    pub fn is_local_id_tag(&self, id_tag: &str) -> bool {
+        self.local_id_tags
+            .split(',')
+            .any(|tag| !tag.trim().is_empty() && tag.trim().eq_ignore_ascii_case(id_tag))
     }
 
     pub fn charger_topic(&self) -> heapless::String<64> {
-        let mut topic = heapless::String::new();
-        topic.push_str("/charger/").ok();
-        topic.push_str(self.charger_serial).ok();
-        topic
+        self.expand_topic(self.topics.charger)
     }
     pub fn system_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.system)
+    }
+    pub fn ocpp_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.ocpp)
+    }
+    pub fn cmd_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.cmd)
+    }
+    pub fn ota_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.ota)
+    }
+    pub fn status_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.status)
+    }
+    /// Topic shared by the chargers of the site for the load management
+    pub fn load_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.load)
+    }
+    /// Topic of the power at the grid connection of the site, for the solar charging
+    pub fn grid_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.grid)
+    }
+    /// Topic the forwarded warnings and errors are published on
+    pub fn log_topic(&self) -> heapless::String<64> {
+        self.expand_topic(self.topics.log)
+    }
+    /// Telemetry topic, with the pseudonym in place of the serial when anonymized
+    pub fn telemetry_topic(&self) -> heapless::String<64> {
+        if self.telemetry_anonymized {
+            self.expand_topic_as(self.topics.telemetry, &self.pseudonym())
+        } else {
+            self.expand_topic(self.topics.telemetry)
+        }
+    }
+
+    /// Client id on the analytics broker, differs from the OCPP one so a broker shared by both
+    /// doesn't take over the OCPP session
+    pub fn analytics_client_id(&self) -> heapless::String<64> {
+        let mut client_id = heapless::String::new();
+        if self.telemetry_anonymized {
+            write!(client_id, "{}-analytics", self.pseudonym()).ok();
+        } else {
+            write!(client_id, "{}-analytics", self.mqtt_client_id).ok();
+        }
+        client_id
+    }
+
+    /// Stable name of the charger in anonymized telemetry, so the fleet can be monitored per
+    /// charger without the serial. A pseudonym, it can be linked to a known serial
+    pub fn pseudonym(&self) -> heapless::String<16> {
+        let mut pseudonym = heapless::String::new();
+        write!(
+            pseudonym,
+            "cp-{:08x}",
+            utils::crc32(self.charger_serial.as_bytes())
+        )
+        .ok();
+        pseudonym
+    }
+
+    fn expand_topic(&self, template: &str) -> heapless::String<64> {
+        self.expand_topic_as(template, self.charger_serial)
+    }
+
+    /// Replace the placeholders of a topic template, with `serial` for `{serial}`. The values are
+    /// sanitized so they can't add levels or wildcards to the topic
+    fn expand_topic_as(&self, template: &str, serial: &str) -> heapless::String<64> {
         let mut topic = heapless::String::new();
-        topic.push_str("/system/").ok();
-        topic.push_str(self.charger_serial).ok();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            topic.push_str(&rest[..start]).ok();
+            let Some(end) = rest[start..].find('}') else {
+                rest = &rest[start..];
+                break;
+            };
+            let value = match &rest[start + 1..start + end] {
+                "serial" => Some(serial),
+                "model" => Some(self.charger_model),
+                "vendor" => Some(self.charger_vendor),
+                "site" => Some(self.site_id),
+                _ => None,
+            };
+            match value {
+                Some(value) => topic
+                    .push_str(&utils::sanitize_identifier::<{ utils::MAX_IDENTIFIER_LEN }>(value))
+                    .ok(),
+                None if &rest[start..=start + end] == "{connector}" => {
+                    write!(topic, "{}", charger::DEFAULT_CONNECTOR_ID).ok()
+                }
+                // Unknown placeholders are kept as they are
+                None => topic.push_str(&rest[start..=start + end]).ok(),
+            };
+            rest = &rest[start + end + 1..];
+        }
+        topic.push_str(rest).ok();
         topic
     }
 }
@@ -0,0 +1,154 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    charger::{self, InputEvent},
+    display::{self, DisplayEvent},
+    info,
+    network::NetworkStack,
+    ocpp_config::{self, ConfigKey},
+    warn,
+};
+
+const CHECK_INTERVAL_SECS: u64 = 1;
+
+/// What a session in progress does when the charger goes offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfflinePolicy {
+    /// Keep charging, the transaction messages are queued until the network is back
+    Continue,
+    /// Stop the session
+    Stop,
+}
+
+impl OfflinePolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "continue" => Some(Self::Continue),
+            "stop" => Some(Self::Stop),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Continue => "continue",
+            Self::Stop => "stop",
+        }
+    }
+}
+
+/// The network has been gone for the grace period
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+/// Sessions are stopped when the charger goes offline
+static STOP_SESSIONS: AtomicBool = AtomicBool::new(false);
+/// The central system hasn't answered a call for the backend timeout while the network is up
+static BACKEND_DEGRADED: AtomicBool = AtomicBool::new(false);
+/// Last CallResult from the central system
+static LAST_CALL_RESULT: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Whether the charger is offline, the network has been gone for the grace period
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Record a CallResult, the central system is processing the messages of the charger
+pub fn record_call_result() {
+    LAST_CALL_RESULT.lock(|last| last.set(Some(Instant::now())));
+}
+
+/// Whether the broker is reachable but the central system stopped answering
+pub fn is_backend_degraded() -> bool {
+    BACKEND_DEGRADED.load(Ordering::Relaxed)
+}
+
+/// Whether the central system can't authorize, the charger is offline or the backend degraded
+pub fn is_backend_unavailable() -> bool {
+    is_offline() || is_backend_degraded()
+}
+
+/// Time without a CallResult before the backend is degraded, at least two heartbeats so a
+/// charger that has nothing else to send isn't taken for a silent backend
+fn backend_timeout(timeout_secs: u16) -> Duration {
+    let heartbeat = ocpp_config::integer(ConfigKey::HeartbeatInterval).max(1) as u64;
+    Duration::from_secs(u64::from(timeout_secs).max(2 * heartbeat))
+}
+
+/// Whether a session in progress is stopped when the charger goes offline
+pub fn stops_sessions() -> bool {
+    STOP_SESSIONS.load(Ordering::Relaxed)
+}
+
+/// Task to tell the state machine when the network is lost for the grace period and when it
+/// comes back, and to degrade the backend when the central system stops answering
+#[embassy_executor::task]
+pub async fn connectivity_watcher_task(network: &'static NetworkStack) {
+    let config = &network.app_config;
+    let grace = Duration::from_secs(config.offline_grace_secs.into());
+    STOP_SESSIONS.store(
+        config.offline_policy == OfflinePolicy::Stop,
+        Ordering::Relaxed,
+    );
+    info!(
+        "TASK: Started Connectivity Watcher, sessions {} after {}s offline",
+        config.offline_policy.as_str(),
+        config.offline_grace_secs
+    );
+
+    let mut lost_since: Option<Instant> = None;
+    // The central system gets the timeout from when the network is up
+    let mut connected_since: Option<Instant> = None;
+    loop {
+        Timer::after(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+
+        if network.is_connected() {
+            lost_since = None;
+            if OFFLINE.swap(false, Ordering::Relaxed) {
+                info!("NETW: Network restored, back online");
+                let _ = charger::STATE_IN_CHANNEL.try_send(InputEvent::NetworkRestored);
+                display::notify(DisplayEvent::NetworkChanged);
+            }
+
+            let connected = *connected_since.get_or_insert_with(Instant::now);
+            let last_answer = LAST_CALL_RESULT
+                .lock(|last| last.get())
+                .map_or(connected, |at| at.max(connected));
+            let silent = last_answer.elapsed() >= backend_timeout(config.backend_timeout_secs);
+            if silent != is_backend_degraded() {
+                if silent {
+                    warn!(
+                        "OCPP: No CallResult from the central system for {}s, backend degraded",
+                        last_answer.elapsed().as_secs()
+                    );
+                } else {
+                    info!("OCPP: Central system is answering again, backend restored");
+                }
+                BACKEND_DEGRADED.store(silent, Ordering::Relaxed);
+                display::notify(DisplayEvent::NetworkChanged);
+            }
+            continue;
+        }
+
+        // Offline takes over from a degraded backend, the timeout restarts with the network
+        connected_since = None;
+        if BACKEND_DEGRADED.swap(false, Ordering::Relaxed) {
+            display::notify(DisplayEvent::NetworkChanged);
+        }
+
+        let since = *lost_since.get_or_insert_with(Instant::now);
+        if !is_offline() && since.elapsed() >= grace {
+            warn!(
+                "NETW: Network lost for {}s, going offline",
+                since.elapsed().as_secs()
+            );
+            OFFLINE.store(true, Ordering::Relaxed);
+            let _ = charger::STATE_IN_CHANNEL.try_send(InputEvent::NetworkLost);
+            display::notify(DisplayEvent::NetworkChanged);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use core::{
+    cell::RefCell,
+    fmt::Write,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+use crate::{
+    data_transfer, error, info, ocpp,
+    storage::{self, Slot},
+    warn,
+};
+
+/// Possible return addresses kept from the stack
+const STACK_ADDRESSES: usize = 8;
+/// Words of the stack above the panic handler's frame searched for return addresses
+const STACK_SCAN_WORDS: usize = 512;
+/// Instructions mapped from flash, a word in this range on the stack is likely a return address
+const CODE_START: u32 = 0x4200_0000;
+const CODE_END: u32 = 0x4280_0000;
+/// End of the internal SRAM, the stack scan stops there
+const SRAM_END: usize = 0x4088_0000;
+/// Size of the serialized crash record
+const RECORD_SIZE: usize = 4 + 4 + 4 * STACK_ADDRESSES + 1 + 64 + 1 + 128;
+
+/// Set by the first panic, a panic while recording the first one only resets
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// What was known of a panic, written to flash by the panic handler and reported at the next boot
+#[derive(Debug, Clone)]
+pub struct CrashRecord {
+    pub uptime_secs: u32,
+    /// Return address of the panic handler, in the panic machinery of core
+    pub return_address: u32,
+    /// Words on the stack that point into the code, the innermost first. Not every one is a
+    /// frame, resolve them with `addr2line` against the ELF of the build
+    pub stack: heapless::Vec<u32, STACK_ADDRESSES>,
+    /// File and line of the panic
+    pub location: heapless::String<64>,
+    pub message: heapless::String<128>,
+}
+
+impl CrashRecord {
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.uptime_secs.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.return_address.to_le_bytes());
+        for (i, address) in self.stack.iter().enumerate() {
+            bytes[8 + i * 4..12 + i * 4].copy_from_slice(&address.to_le_bytes());
+        }
+        let mut offset = 8 + 4 * STACK_ADDRESSES;
+        for (text, capacity) in [(self.location.as_str(), 64), (self.message.as_str(), 128)] {
+            bytes[offset] = text.len() as u8;
+            bytes[offset + 1..offset + 1 + text.len()].copy_from_slice(text.as_bytes());
+            offset += 1 + capacity;
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+        let mut stack = heapless::Vec::new();
+        for word in bytes[8..8 + 4 * STACK_ADDRESSES].chunks(4) {
+            let address = u32::from_le_bytes(word.try_into().ok()?);
+            if address != 0 {
+                let _ = stack.push(address);
+            }
+        }
+        let text = |offset: usize, capacity: usize| {
+            let len = usize::from(bytes[offset]).min(capacity);
+            core::str::from_utf8(&bytes[offset + 1..offset + 1 + len]).ok()
+        };
+        let location_offset = 8 + 4 * STACK_ADDRESSES;
+        let message_offset = location_offset + 1 + 64;
+        Some(Self {
+            uptime_secs: u32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            return_address: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            stack,
+            location: heapless::String::try_from(text(location_offset, 64)?).ok()?,
+            message: heapless::String::try_from(text(message_offset, 128)?).ok()?,
+        })
+    }
+
+    /// The crash as JSON, for the CrashReport DataTransfer
+    fn to_json(&self) -> heapless::String<512> {
+        let mut json = heapless::String::new();
+        let _ = json.push_str("{\"message\":\"");
+        let _ = ocpp::push_json_escaped(&mut json, &self.message);
+        let _ = json.push_str("\",\"location\":\"");
+        let _ = ocpp::push_json_escaped(&mut json, &self.location);
+        let _ = write!(
+            json,
+            "\",\"uptime\":{},\"returnAddress\":\"0x{:08x}\",\"stack\":[",
+            self.uptime_secs, self.return_address
+        );
+        for (i, address) in self.stack.iter().enumerate() {
+            let separator = if i > 0 { "," } else { "" };
+            let _ = write!(json, "{separator}\"0x{address:08x}\"");
+        }
+        let _ = json.push_str("]}");
+        json
+    }
+}
+
+/// A crash of the previous run, until it's reported to the central system
+static PENDING: Mutex<CriticalSectionRawMutex, RefCell<Option<CrashRecord>>> =
+    Mutex::new(RefCell::new(None));
+/// The last crash since boot, kept for the diagnostics after it was reported
+static LAST: Mutex<CriticalSectionRawMutex, RefCell<Option<CrashRecord>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Copy as much of the formatted text as fits
+struct Truncating<'a, const N: usize>(&'a mut heapless::String<N>);
+
+impl<const N: usize> Write for Truncating<'_, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The stack pointer and return address of the caller's frame
+#[inline(always)]
+fn registers() -> (usize, u32) {
+    #[cfg(target_arch = "riscv32")]
+    {
+        let (sp, ra): (usize, u32);
+        // SAFETY: copies two registers, no memory is touched
+        unsafe {
+            core::arch::asm!("mv {0}, sp", "mv {1}, ra", out(reg) sp, out(reg) ra);
+        }
+        (sp, ra)
+    }
+    #[cfg(not(target_arch = "riscv32"))]
+    {
+        (0, 0)
+    }
+}
+
+/// Words above the stack pointer that point into the code
+fn scan_stack(sp: usize) -> heapless::Vec<u32, STACK_ADDRESSES> {
+    let mut addresses = heapless::Vec::new();
+    if sp == 0 {
+        return addresses;
+    }
+    let end = (sp + STACK_SCAN_WORDS * 4).min(SRAM_END);
+    for address in (sp..end).step_by(4) {
+        // SAFETY: an aligned word of the stack, between the stack pointer and the end of SRAM
+        let word = unsafe { core::ptr::read_volatile(address as *const u32) };
+        if (CODE_START..CODE_END).contains(&word) && addresses.push(word).is_err() {
+            break;
+        }
+    }
+    addresses
+}
+
+/// Write what is known of a panic to flash, called by the panic handler before it resets. A
+/// panic while recording returns right away
+#[inline(always)]
+pub fn record(info: &PanicInfo) {
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let (sp, return_address) = registers();
+
+    let mut record = CrashRecord {
+        uptime_secs: Instant::now().as_secs() as u32,
+        return_address,
+        stack: scan_stack(sp),
+        location: heapless::String::new(),
+        message: heapless::String::new(),
+    };
+    if let Some(location) = info.location() {
+        let _ = write!(
+            Truncating(&mut record.location),
+            "{}:{}",
+            location.file(),
+            location.line()
+        );
+    }
+    let _ = write!(Truncating(&mut record.message), "{}", info.message());
+
+    error!("PANIC: {} at {}", record.message, record.location);
+    let _ = storage::write(Slot::Crash, &record.to_bytes());
+}
+
+/// Read the crash record at boot, a crash of the previous run is reported once the central
+/// system accepted the BootNotification
+pub fn load() {
+    let mut buffer = [0u8; RECORD_SIZE];
+    let record = match storage::read(Slot::Crash, &mut buffer) {
+        Ok(Some(len)) => CrashRecord::from_bytes(&buffer[..len]),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("CRSH: Failed to read the crash record: {e}");
+            None
+        }
+    };
+    let Some(record) = record else {
+        return;
+    };
+    error!(
+        "CRSH: Restarted after a panic at {}s uptime: {} at {}",
+        record.uptime_secs, record.message, record.location
+    );
+    LAST.lock(|last| last.replace(Some(record.clone())));
+    PENDING.lock(|pending| pending.replace(Some(record)));
+}
+
+/// Whether the previous run crashed and it wasn't reported yet
+pub fn is_pending() -> bool {
+    PENDING.lock(|pending| pending.borrow().is_some())
+}
+
+/// The last crash, for the diagnostics
+pub fn last() -> Option<CrashRecord> {
+    LAST.lock(|last| last.borrow().clone())
+}
+
+/// Report a crash of the previous run as a `CrashReport` DataTransfer, the record is erased once
+/// the report is queued
+pub fn report(vendor_id: &str) {
+    let Some(record) = PENDING.lock(|pending| pending.borrow().clone()) else {
+        return;
+    };
+    let json = record.to_json();
+    if !data_transfer::send_data_transfer(vendor_id, Some("CrashReport"), Some(&json)) {
+        warn!("CRSH: Failed to queue the crash report, reporting it after the next boot");
+        return;
+    }
+    info!("CRSH: Crash report sent");
+    PENDING.lock(|pending| pending.replace(None));
+    if let Err(e) = storage::erase(Slot::Crash) {
+        warn!("CRSH: Failed to erase the crash record: {e}");
+    }
+}
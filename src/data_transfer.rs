@@ -0,0 +1,204 @@
+use core::{cell::RefCell, fmt::Write};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use ocpp_rs::v16::call::{Action, DataTransfer};
+
+use crate::{
+    extensions, info,
+    mqtt::Priority,
+    ocpp::{self, CallErrorCode, CallResponse},
+    warn,
+};
+
+/// Maximum number of vendor extension handlers that can be registered
+const MAX_HANDLERS: usize = 8;
+
+/// Status of a DataTransfer as defined by OCPP 1.6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataTransferStatus {
+    Accepted,
+    Rejected,
+    UnknownMessageId,
+    UnknownVendorId,
+}
+
+impl DataTransferStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Accepted => "Accepted",
+            Self::Rejected => "Rejected",
+            Self::UnknownMessageId => "UnknownMessageId",
+            Self::UnknownVendorId => "UnknownVendorId",
+        }
+    }
+}
+
+/// Response of a vendor extension handler, the data is escaped and sent back as a JSON string
+#[derive(Debug, Clone)]
+pub struct DataTransferResponse {
+    pub status: DataTransferStatus,
+    pub data: Option<heapless::String<256>>,
+}
+
+impl DataTransferResponse {
+    pub fn accepted(data: Option<heapless::String<256>>) -> Self {
+        Self {
+            status: DataTransferStatus::Accepted,
+            data,
+        }
+    }
+
+    pub fn rejected() -> Self {
+        Self {
+            status: DataTransferStatus::Rejected,
+            data: None,
+        }
+    }
+}
+
+/// Handler for an incoming DataTransfer, receives the messageId and data of the request
+pub type DataTransferHandler = fn(Option<&str>, Option<&str>) -> DataTransferResponse;
+
+#[derive(Clone, Copy)]
+struct Registration {
+    vendor_id: &'static str,
+    message_id: Option<&'static str>,
+    handler: DataTransferHandler,
+}
+
+/// Registered vendor extension handlers
+static HANDLERS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<heapless::Vec<Registration, MAX_HANDLERS>>,
+> = Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// Register a handler for DataTransfer calls with the given vendorId
+/// With a message id of None the handler receives all messages for the vendor
+pub fn register_handler(
+    vendor_id: &'static str,
+    message_id: Option<&'static str>,
+    handler: DataTransferHandler,
+) -> Result<(), &'static str> {
+    HANDLERS.lock(|handlers| {
+        let mut handlers = handlers.borrow_mut();
+        if handlers
+            .iter()
+            .any(|r| r.vendor_id == vendor_id && r.message_id == message_id)
+        {
+            return Err("Handler already registered");
+        }
+        handlers
+            .push(Registration {
+                vendor_id,
+                message_id,
+                handler,
+            })
+            .map_err(|_| "Too many DataTransfer handlers registered")
+    })?;
+
+    info!(
+        "DATA: Registered DataTransfer handler for {vendor_id}/{}",
+        message_id.unwrap_or("*")
+    );
+    Ok(())
+}
+
+/// Find the handler for a vendorId/messageId, an exact message id match takes precedence
+fn find_handler(
+    vendor_id: &str,
+    message_id: Option<&str>,
+) -> Result<DataTransferHandler, DataTransferStatus> {
+    HANDLERS.lock(|handlers| {
+        let handlers = handlers.borrow();
+        let mut vendor_known = false;
+        let mut wildcard = None;
+
+        for registration in handlers.iter().filter(|r| r.vendor_id == vendor_id) {
+            vendor_known = true;
+            match registration.message_id {
+                Some(id) if Some(id) == message_id => return Ok(registration.handler),
+                None => wildcard = Some(registration.handler),
+                _ => {}
+            }
+        }
+
+        match (wildcard, vendor_known) {
+            (Some(handler), _) => Ok(handler),
+            (None, true) => Err(DataTransferStatus::UnknownMessageId),
+            (None, false) => Err(DataTransferStatus::UnknownVendorId),
+        }
+    })
+}
+
+/// Handle a DataTransfer Call from the central system
+pub fn handle_call(payload: &str) -> CallResponse {
+    let Some(vendor_id) = ocpp::json_string_field(payload, "vendorId") else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "DataTransfer requires a vendorId",
+        ));
+    };
+    let message_id = ocpp::json_string_field(payload, "messageId");
+    let data = ocpp::json_string_field(payload, "data");
+
+    info!(
+        "DATA: DataTransfer from central system for {vendor_id}/{}",
+        message_id.unwrap_or("*")
+    );
+
+    let response = match find_handler(vendor_id, message_id) {
+        Ok(handler) => handler(message_id, data),
+        Err(status) => {
+            warn!(
+                "DATA: No handler for {vendor_id}/{}: {}",
+                message_id.unwrap_or("*"),
+                status.as_str()
+            );
+            DataTransferResponse { status, data: None }
+        }
+    };
+
+    response_payload(&response).ok_or((
+        CallErrorCode::InternalError,
+        "DataTransfer response too large",
+    ))
+}
+
+fn response_payload(
+    response: &DataTransferResponse,
+) -> Option<heapless::String<{ ocpp::MAX_CALL_PAYLOAD }>> {
+    let mut payload = heapless::String::new();
+    write!(payload, "{{\"status\":\"{}\"", response.status.as_str()).ok()?;
+    if let Some(data) = &response.data {
+        payload.push_str(",\"data\":\"").ok()?;
+        ocpp::push_json_escaped(&mut payload, data)?;
+        payload.push('"').ok()?;
+    }
+    payload.push('}').ok()?;
+    Some(payload)
+}
+
+/// Handle the CallResult of a DataTransfer sent by the charger
+pub fn handle_call_result(payload: &str) {
+    match ocpp::json_string_field(payload, "status") {
+        Some("Accepted") => info!("DATA: DataTransfer accepted"),
+        Some(status) => warn!("DATA: DataTransfer not accepted: {status}"),
+        None => warn!("DATA: DataTransfer response without status"),
+    }
+}
+
+/// A DataTransfer with the fields of the vendor extensions added to its data
+pub fn data_transfer(vendor_id: &str, message_id: Option<&str>, data: Option<&str>) -> Action {
+    Action::DataTransfer(DataTransfer {
+        vendor_id: vendor_id.into(),
+        message_id: message_id.map(Into::into),
+        data: data.map(|data| extensions::extend_data(vendor_id, message_id, data)),
+    })
+}
+
+/// Send a vendor specific DataTransfer to the central system
+/// Returns false if the message could not be queued
+pub fn send_data_transfer(vendor_id: &str, message_id: Option<&str>, data: Option<&str>) -> bool {
+    ocpp::send_ocpp("DataTransfer", Priority::Normal, |_| {
+        data_transfer(vendor_id, message_id, data)
+    })
+}
@@ -0,0 +1,369 @@
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+use embedded_storage::ReadStorage;
+
+use crate::storage;
+
+/// First bytes of a patch
+const MAGIC: &[u8; 4] = b"CDLT";
+/// Length of the patch header, the compressed records follow it
+const HEADER_LEN: usize = 48;
+/// Length of the control of a record: add and copy length and seek
+const CONTROL_LEN: usize = 12;
+/// Source bytes read from flash at a time
+const SOURCE_CHUNK: usize = 4096;
+/// Patched bytes collected before they're handed to the image writer
+const OUTPUT_CHUNK: usize = 256;
+
+/// The running image a patch is applied against
+#[derive(Debug, Clone, Copy)]
+pub struct Base {
+    pub offset: u32, // Offset of the app partition in flash
+    pub size: u32,   // Size of the image in the partition
+    pub sha256: [u8; 32],
+}
+
+/// Heatshrink (LZSS) decoder of the records, with the window and lookahead of the patch header
+struct Decoder {
+    window: Vec<u8>,
+    head: usize,
+    index_bits: u8,
+    count_bits: u8,
+    state: DecodeState,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DecodeState {
+    Tag,
+    Literal { value: u16, bits: u8 },
+    Index { value: u16, bits: u8 },
+    Count { index: u16, value: u16, bits: u8 },
+}
+
+impl Decoder {
+    fn new(window_bits: u8, lookahead_bits: u8) -> Result<Self, &'static str> {
+        if !(4..=12).contains(&window_bits) || !(3..window_bits).contains(&lookahead_bits) {
+            return Err("Unsupported patch compression");
+        }
+        Ok(Self {
+            // Heatshrink starts with a zeroed window, a back reference may point before the start
+            window: vec![0u8; 1 << window_bits],
+            head: 0,
+            index_bits: window_bits,
+            count_bits: lookahead_bits,
+            state: DecodeState::Tag,
+        })
+    }
+
+    /// Decode a byte of the compressed stream, the bits are read most significant first
+    fn decode(
+        &mut self,
+        byte: u8,
+        out: &mut impl FnMut(u8) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        for shift in (0..8).rev() {
+            let bit = u16::from((byte >> shift) & 1);
+            self.state = match self.state {
+                DecodeState::Tag if bit == 1 => DecodeState::Literal { value: 0, bits: 0 },
+                DecodeState::Tag => DecodeState::Index { value: 0, bits: 0 },
+                DecodeState::Literal { value, bits } if bits + 1 < 8 => DecodeState::Literal {
+                    value: value << 1 | bit,
+                    bits: bits + 1,
+                },
+                DecodeState::Literal { value, .. } => {
+                    self.push((value << 1 | bit) as u8, out)?;
+                    DecodeState::Tag
+                }
+                DecodeState::Index { value, bits } if bits + 1 < self.index_bits => {
+                    DecodeState::Index {
+                        value: value << 1 | bit,
+                        bits: bits + 1,
+                    }
+                }
+                DecodeState::Index { value, .. } => DecodeState::Count {
+                    index: (value << 1 | bit) + 1,
+                    value: 0,
+                    bits: 0,
+                },
+                DecodeState::Count { index, value, bits } if bits + 1 < self.count_bits => {
+                    DecodeState::Count {
+                        index,
+                        value: value << 1 | bit,
+                        bits: bits + 1,
+                    }
+                }
+                DecodeState::Count { index, value, .. } => {
+                    let mask = self.window.len() - 1;
+                    for _ in 0..=(value << 1 | bit) {
+                        let byte = self.window[self.head.wrapping_sub(usize::from(index)) & mask];
+                        self.push(byte, out)?;
+                    }
+                    DecodeState::Tag
+                }
+            };
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        byte: u8,
+        out: &mut impl FnMut(u8) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        let mask = self.window.len() - 1;
+        self.window[self.head & mask] = byte;
+        self.head = self.head.wrapping_add(1);
+        out(byte)
+    }
+}
+
+/// Part of a record being applied
+#[derive(Debug, Clone, Copy)]
+enum Record {
+    Control,
+    /// Bytes added to the source, followed by the copied bytes
+    Add {
+        remaining: u32,
+        copy: u32,
+        seek: i32,
+    },
+    /// Bytes taken from the patch as they are
+    Copy {
+        remaining: u32,
+        seek: i32,
+    },
+}
+
+/// Applies the decoded records to the base image
+struct Apply {
+    base: Base,
+    target_size: u32,
+    produced: u32,
+    record: Record,
+    control: heapless::Vec<u8, CONTROL_LEN>,
+    source_pos: i64,
+    source: Vec<u8>,
+    source_chunk: Option<u32>,
+    output: heapless::Vec<u8, OUTPUT_CHUNK>,
+}
+
+impl Apply {
+    fn source_byte(&mut self) -> Result<u8, &'static str> {
+        let pos = u32::try_from(self.source_pos)
+            .ok()
+            .filter(|pos| *pos < self.base.size)
+            .ok_or("Patch reads outside the base image")?;
+        self.source_pos += 1;
+        let chunk = pos / SOURCE_CHUNK as u32;
+        if self.source_chunk != Some(chunk) {
+            // The partition is a whole number of sectors, a chunk never reads past it
+            let offset = self.base.offset + chunk * SOURCE_CHUNK as u32;
+            let source = &mut self.source;
+            storage::with_flash(|flash| {
+                flash
+                    .read(offset, source)
+                    .map_err(|_| "Failed to read the running image")
+            })?;
+            self.source_chunk = Some(chunk);
+        }
+        Ok(self.source[pos as usize % SOURCE_CHUNK])
+    }
+
+    fn emit(
+        &mut self,
+        byte: u8,
+        sink: &mut impl FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        self.produced += 1;
+        if self.output.push(byte).is_err() {
+            sink(&self.output)?;
+            self.output.clear();
+            let _ = self.output.push(byte);
+        }
+        Ok(())
+    }
+
+    /// The next record after the add and copy bytes of one are done
+    fn next(&mut self, copy: u32, seek: i32) -> Record {
+        if copy > 0 {
+            return Record::Copy {
+                remaining: copy,
+                seek,
+            };
+        }
+        self.source_pos += i64::from(seek);
+        Record::Control
+    }
+
+    /// Apply a decoded byte of the records, bytes after the target is complete are ignored
+    fn apply(
+        &mut self,
+        byte: u8,
+        sink: &mut impl FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        if self.produced == self.target_size && matches!(self.record, Record::Control) {
+            return Ok(());
+        }
+        self.record = match self.record {
+            Record::Control => {
+                let _ = self.control.push(byte);
+                if !self.control.is_full() {
+                    return Ok(());
+                }
+                let field = |i: usize| {
+                    let mut bytes = [0u8; 4];
+                    bytes.copy_from_slice(&self.control[i * 4..i * 4 + 4]);
+                    bytes
+                };
+                let add = u32::from_le_bytes(field(0));
+                let copy = u32::from_le_bytes(field(1));
+                let seek = i32::from_le_bytes(field(2));
+                self.control.clear();
+                let end = u64::from(self.produced) + u64::from(add) + u64::from(copy);
+                if end > u64::from(self.target_size) {
+                    return Err("Patch writes past the end of the image");
+                }
+                if add > 0 {
+                    Record::Add {
+                        remaining: add,
+                        copy,
+                        seek,
+                    }
+                } else {
+                    self.next(copy, seek)
+                }
+            }
+            Record::Add {
+                remaining,
+                copy,
+                seek,
+            } => {
+                let source = self.source_byte()?;
+                self.emit(byte.wrapping_add(source), sink)?;
+                if remaining > 1 {
+                    Record::Add {
+                        remaining: remaining - 1,
+                        copy,
+                        seek,
+                    }
+                } else {
+                    self.next(copy, seek)
+                }
+            }
+            Record::Copy { remaining, seek } => {
+                self.emit(byte, sink)?;
+                if remaining > 1 {
+                    Record::Copy {
+                        remaining: remaining - 1,
+                        seek,
+                    }
+                } else {
+                    self.next(0, seek)
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Applies a patch streamed in chunks against the running image, the patched image is handed to
+/// the sink in the order it's produced.
+///
+/// A patch is a 48 byte header followed by heatshrink compressed bsdiff records, as made by
+/// `scripts/make_delta.py`. The header, little endian:
+/// - magic `CDLT` (4 bytes), heatshrink window and lookahead bits (1 byte each), 2 zero bytes
+/// - size (4 bytes) and SHA-256 (32 bytes) of the image the patch was made against
+/// - size of the patched image (4 bytes)
+///
+/// A record is an add length, a copy length and a seek (4 bytes each), then the add bytes that
+/// are added to the base image from the current position and the copy bytes that are taken as
+/// they are. The seek moves the position in the base image after the record.
+pub struct Patcher {
+    header: heapless::Vec<u8, HEADER_LEN>,
+    decoder: Option<Decoder>,
+    apply: Apply,
+}
+
+impl Patcher {
+    pub fn new(base: Base) -> Self {
+        Self {
+            header: heapless::Vec::new(),
+            decoder: None,
+            apply: Apply {
+                base,
+                target_size: 0,
+                produced: 0,
+                record: Record::Control,
+                control: heapless::Vec::new(),
+                source_pos: 0,
+                source: vec![0u8; SOURCE_CHUNK],
+                source_chunk: None,
+                output: heapless::Vec::new(),
+            },
+        }
+    }
+
+    /// Check the header against the base image, fails when the patch was made against another
+    fn start(&mut self) -> Result<(), &'static str> {
+        let header = &self.header;
+        if header[..4] != MAGIC[..] {
+            return Err("Not a firmware patch");
+        }
+        let word = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&header[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let base = &self.apply.base;
+        if word(8) != base.size || header[12..44] != base.sha256[..] {
+            return Err("Patch is for another base image");
+        }
+        self.apply.target_size = word(44);
+        self.decoder = Some(Decoder::new(header[4], header[5])?);
+        Ok(())
+    }
+
+    /// Apply the next chunk of the patch
+    pub fn write(
+        &mut self,
+        mut data: &[u8],
+        sink: &mut impl FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        if self.decoder.is_none() {
+            let len = (HEADER_LEN - self.header.len()).min(data.len());
+            let _ = self.header.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if !self.header.is_full() {
+                return Ok(());
+            }
+            self.start()?;
+        }
+
+        let Self { decoder, apply, .. } = self;
+        let Some(decoder) = decoder.as_mut() else {
+            return Ok(());
+        };
+        for &byte in data {
+            decoder.decode(byte, &mut |byte| apply.apply(byte, sink))?;
+        }
+        Ok(())
+    }
+
+    /// Hand the last patched bytes to the sink, fails when the patch ended before the image
+    pub fn finish(
+        mut self,
+        sink: &mut impl FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        if self.decoder.is_none() {
+            return Err("Patch ended in the header");
+        }
+        if self.apply.produced != self.apply.target_size {
+            return Err("Patch ended before the end of the image");
+        }
+        if !self.apply.output.is_empty() {
+            sink(&self.apply.output)?;
+            self.apply.output.clear();
+        }
+        Ok(())
+    }
+}
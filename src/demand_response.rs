@@ -0,0 +1,127 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    charger::{Charger, ChargerState},
+    config::Config,
+    info,
+    mqtt::Priority,
+    ocpp,
+    pins::MappedInput,
+    relay,
+    smart_charging::{CurrentLimits, LimitSource, MIN_CURRENT_AMPS},
+    warn,
+};
+
+/// Time the contact has to keep its level before a change counts, ripple control receivers
+/// switch slowly and their contacts bounce
+const DEBOUNCE_MS: u64 = 500;
+
+/// Set while the utility asks to shed the load
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the demand response contact is asserted
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// What the charger does while the contact is asserted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DemandResponseMode {
+    /// Charging is suspended, the session continues once the contact is released
+    Suspend,
+    /// The current is clamped to the configured current
+    Clamp,
+}
+
+impl DemandResponseMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "suspend" => Some(Self::Suspend),
+            "clamp" => Some(Self::Clamp),
+            _ => None,
+        }
+    }
+}
+
+/// Settings of the demand response input
+#[derive(Debug, Clone, Copy)]
+pub struct DemandResponse {
+    pub mode: DemandResponseMode,
+    pub clamp_current: u16,
+}
+
+impl DemandResponse {
+    pub fn new(config: &Config) -> Self {
+        let mode = DemandResponseMode::parse(config.demand_response_mode).unwrap_or_else(|| {
+            warn!(
+                "DR  : Unknown mode {}, suspending charging",
+                config.demand_response_mode
+            );
+            DemandResponseMode::Suspend
+        });
+        Self {
+            mode,
+            clamp_current: config
+                .demand_response_clamp_amps
+                .clamp(MIN_CURRENT_AMPS, config.max_current_amps),
+        }
+    }
+
+    /// The demand response limit while the contact is asserted
+    fn limit(&self) -> u16 {
+        match self.mode {
+            DemandResponseMode::Suspend => 0,
+            DemandResponseMode::Clamp => self.clamp_current,
+        }
+    }
+}
+
+/// Task to follow the dry contact of a ripple control receiver or a demand response relay,
+/// closed to ground while the utility asks to shed the load. Asserted, charging is suspended or
+/// clamped, released the charger resumes. A suspended session is reported as SuspendedEVSE
+#[embassy_executor::task]
+pub async fn demand_response_task(
+    mut input: MappedInput,
+    charger: &'static Charger,
+    limits: &'static CurrentLimits,
+    settings: DemandResponse,
+) {
+    info!(
+        "TASK: Started Demand Response, {:?} while asserted",
+        settings.mode
+    );
+
+    loop {
+        let asserted = input.is_low();
+        if asserted != is_active() {
+            Timer::after(Duration::from_millis(DEBOUNCE_MS)).await;
+            if input.is_low() != asserted {
+                continue;
+            }
+
+            ACTIVE.store(asserted, Ordering::Relaxed);
+            if asserted {
+                warn!("DR  : Demand response asserted, {:?}", settings.mode);
+                limits
+                    .set_limit(LimitSource::DemandResponse, settings.limit())
+                    .await;
+            } else {
+                info!("DR  : Demand response released, resuming");
+                limits.clear_limit(LimitSource::DemandResponse).await;
+            }
+
+            if settings.mode == DemandResponseMode::Suspend {
+                relay::set_suspended(asserted).await;
+                let state = charger.get_state().await;
+                if state == ChargerState::Charging {
+                    ocpp::send_ocpp("status notification", Priority::Low, |timestamp| {
+                        ocpp::status_notification(state, timestamp)
+                    });
+                }
+            }
+        }
+
+        input.wait_for_any_edge().await;
+    }
+}
@@ -0,0 +1,86 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Timer};
+
+use crate::{config::Config, info, mqtt, warn};
+
+/// Time the simulated central system takes to authorize an id tag
+const AUTHORIZE_DELAY_MS: u64 = 1000;
+/// Time it takes to answer the transaction messages
+const ANSWER_DELAY_MS: u64 = 200;
+
+/// The OCPP layer answers its own calls, nothing is sent to the broker
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Transaction id of the next simulated transaction
+static NEXT_TRANSACTION_ID: AtomicI32 = AtomicI32::new(1);
+
+/// Calls for the simulated central system, by unique id and action
+static CALLS: Channel<CriticalSectionRawMutex, (heapless::String<32>, &'static str), 4> =
+    Channel::new();
+
+/// Enable demo mode, before the OCPP tasks are spawned
+pub fn init(config: &Config) {
+    if config.demo_mode {
+        warn!("DEMO: Demo mode, the central system is simulated");
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether the simulated central system answers an action, the others are dropped
+pub fn answers(action: &str) -> bool {
+    matches!(action, "Authorize" | "StartTransaction" | "StopTransaction")
+}
+
+/// Hand a call to the simulated central system
+pub fn answer(unique_id: &heapless::String<32>, action: &'static str) {
+    if CALLS.try_send((unique_id.clone(), action)).is_err() {
+        warn!("DEMO: Too many calls waiting, {action} not answered");
+    }
+}
+
+/// Task to answer the calls of the charger in demo mode, as the central system would: an
+/// Authorize is accepted after a second and a StartTransaction gets the next local transaction
+/// id. The CallResults go through the response handler like those from the broker
+#[embassy_executor::task]
+pub async fn demo_backend_task() {
+    info!("TASK: Started Demo Backend");
+
+    loop {
+        let (unique_id, action) = CALLS.receive().await;
+
+        let mut payload = heapless::String::<64>::new();
+        let delay = match action {
+            "Authorize" => {
+                let _ = payload.push_str("{\"idTagInfo\":{\"status\":\"Accepted\"}}");
+                AUTHORIZE_DELAY_MS
+            }
+            "StartTransaction" => {
+                let transaction_id = NEXT_TRANSACTION_ID.fetch_add(1, Ordering::Relaxed);
+                let _ = write!(
+                    payload,
+                    "{{\"transactionId\":{transaction_id},\"idTagInfo\":{{\"status\":\"Accepted\"}}}}"
+                );
+                ANSWER_DELAY_MS
+            }
+            _ => {
+                let _ = payload.push_str("{\"idTagInfo\":{\"status\":\"Accepted\"}}");
+                ANSWER_DELAY_MS
+            }
+        };
+        Timer::after(Duration::from_millis(delay)).await;
+
+        let mut frame = heapless::String::<128>::new();
+        let _ = write!(frame, "[3,\"{unique_id}\",{payload}]");
+        let mut message = heapless::Vec::new();
+        let _ = message.extend_from_slice(frame.as_bytes());
+        info!("DEMO: Answering {action}");
+        mqtt::MQTT_RECEIVE_CHANNEL.send(message).await;
+    }
+}
@@ -0,0 +1,422 @@
+extern crate alloc;
+use alloc::string::String;
+use core::{cell::RefCell, fmt::Write};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant, Timer};
+use log::Level;
+use ocpp_rs::v16::{
+    call::{Action, DiagnosticsStatusNotification},
+    enums::DiagnosticsStatus,
+};
+
+use crate::{
+    charger::{ChargerState, InputEvent},
+    config::Config,
+    crash, endpoints, ftp,
+    http::{self, Scheme, Url},
+    info, invariant, io_state, logging,
+    memory::{self, Feature},
+    metering::MeterKind,
+    modbus,
+    mqtt::{self, Priority},
+    network::NetworkStack,
+    ntp,
+    ocpp::{self, CallErrorCode, CallResponse},
+    relay, rfid, sequence, storage, version, warn, watchdog,
+    wire::WireFormat,
+};
+
+const TRANSITIONS: usize = 16;
+/// Room for the report, reserved up front so a full heap fails the upload instead of the charger
+const REPORT_CAPACITY: usize = 7168;
+
+#[derive(Debug, Clone, Copy)]
+struct Transition {
+    uptime_secs: u64,
+    from: ChargerState,
+    to: ChargerState,
+    input: InputEvent,
+}
+
+/// Recent state machine transitions
+static TRANSITION_BUFFER: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<heapless::Deque<Transition, TRANSITIONS>>,
+> = Mutex::new(RefCell::new(heapless::Deque::new()));
+
+/// A GetDiagnostics request waiting to be uploaded
+pub struct DiagnosticsRequest {
+    location: heapless::String<256>,
+    file_name: heapless::String<64>,
+    retries: u8,
+    retry_interval_secs: u16,
+}
+
+static DIAGNOSTICS_REQUEST_CHANNEL: Channel<CriticalSectionRawMutex, DiagnosticsRequest, 1> =
+    Channel::new();
+
+/// Record a state machine transition for the diagnostics report
+pub fn record_transition(from: ChargerState, to: ChargerState, input: InputEvent) {
+    let transition = Transition {
+        uptime_secs: Instant::now().as_secs(),
+        from,
+        to,
+        input,
+    };
+    TRANSITION_BUFFER.lock(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        if buffer.is_full() {
+            buffer.pop_front();
+        }
+        let _ = buffer.push_back(transition);
+    });
+}
+
+/// Build the diagnostics report with charger info, network stats, transitions and log lines
+pub fn build_report(config: &Config, network: &NetworkStack) -> Result<String, &'static str> {
+    let mut report = String::new();
+    if report.try_reserve(REPORT_CAPACITY).is_err() {
+        memory::allocation_failed("DIAG", REPORT_CAPACITY);
+        return Err("Out of memory for the diagnostics report");
+    }
+
+    let _ = writeln!(report, "Charger: {}", config.charger_serial);
+    let _ = writeln!(
+        report,
+        "Model: {} {}",
+        config.charger_vendor, config.charger_model
+    );
+    let _ = writeln!(report, "Firmware: {}", version::firmware_version());
+    let _ = writeln!(report, "Uptime: {}s", Instant::now().as_secs());
+    let _ = writeln!(report, "Time: {}", ntp::get_iso8601_time());
+
+    let _ = writeln!(report, "\n[network]");
+    match network.get_ip_address() {
+        Some(ip) => {
+            let _ = writeln!(report, "IP: {ip}");
+        }
+        None => {
+            let _ = writeln!(report, "IP: Not Connected");
+        }
+    }
+    let stats = mqtt::stats();
+    let _ = writeln!(
+        report,
+        "MQTT sent: {}, send failures: {}, dropped: {}, received: {}",
+        stats.sent, stats.send_failures, stats.dropped, stats.received
+    );
+    let _ = writeln!(
+        report,
+        "MQTT broker disconnects: {}, client id conflicts: {}, send stalls: {}",
+        stats.broker_disconnects, stats.client_id_conflicts, stats.send_stalls
+    );
+
+    let _ = write!(report, "{}", endpoints::report());
+
+    let parse = ocpp::parse_stats();
+    let _ = writeln!(
+        report,
+        "OCPP messages handled: {}, average: {}us, max: {}us, over budget: {}, deferred: {}",
+        parse.handled, parse.average_us, parse.max_us, parse.over_budget, parse.deferred
+    );
+
+    let _ = writeln!(report, "\n[memory]");
+    let _ = writeln!(
+        report,
+        "Heap free: {}, allocation failures: {}",
+        esp_alloc::HEAP.free(),
+        memory::failures()
+    );
+    for feature in memory::disabled() {
+        let _ = writeln!(report, "Disabled: {}", feature.as_str());
+    }
+
+    let (violations, last_violation) = invariant::violations();
+    let _ = write!(report, "Invariant violations: {violations}");
+    let _ = match last_violation {
+        Some((invariant, uptime_secs)) => {
+            writeln!(report, ", last: {} at {uptime_secs}s", invariant.as_str())
+        }
+        None => writeln!(report),
+    };
+
+    let _ = writeln!(report, "\n[storage]");
+    for slot in storage::Slot::ALL {
+        let _ = writeln!(
+            report,
+            "{}: {} erases",
+            slot.as_str(),
+            storage::erase_cycles(slot)
+        );
+    }
+    let maintenance = storage::maintenance_stats();
+    let _ = write!(
+        report,
+        "Maintenance passes: {}, preempted: {}, sectors reclaimed: {}",
+        maintenance.passes, maintenance.preempted, maintenance.reclaimed
+    );
+    let _ = match maintenance.last_pass_secs {
+        Some(uptime_secs) => writeln!(report, ", last at {uptime_secs}s"),
+        None => writeln!(report),
+    };
+
+    let _ = writeln!(report, "\n[power]");
+    let power = relay::power_stats();
+    let _ = writeln!(report, "Reset reason: {}", power.reset_reason);
+    let _ = writeln!(
+        report,
+        "Brownout resets: {}, while energizing the relay: {}",
+        power.brownout_resets, power.relay_brownouts
+    );
+    let _ = writeln!(
+        report,
+        "Relay actuations: {}, verify failures: {}, contactor switch failures: {}",
+        power.actuations, power.verify_failures, power.switch_failures
+    );
+    let _ = writeln!(
+        report,
+        "Relay open deadlines missed: {}",
+        power.deadline_misses
+    );
+
+    let _ = writeln!(report, "\n[watchdog]");
+    let watchdog = watchdog::stats();
+    let _ = match watchdog.last_culprit {
+        Some(task) => writeln!(report, "Resets: {}, last by {task}", watchdog.resets),
+        None => writeln!(report, "Resets: {}", watchdog.resets),
+    };
+
+    if let Some(crash) = crash::last() {
+        let _ = writeln!(report, "\n[crash]");
+        let _ = writeln!(
+            report,
+            "Panicked at {}s uptime: {} at {}",
+            crash.uptime_secs, crash.message, crash.location
+        );
+        let _ = write!(
+            report,
+            "Return address: 0x{:08x}, stack:",
+            crash.return_address
+        );
+        for address in &crash.stack {
+            let _ = write!(report, " 0x{address:08x}");
+        }
+        let _ = writeln!(report);
+    }
+
+    let _ = writeln!(report, "\n[rfid]");
+    let rfid = rfid::read_quality();
+    let _ = match config.rfid_reader {
+        "" => writeln!(report, "No card reader"),
+        reader if rfid.lost => writeln!(report, "Reader {reader} lost, lost {} times", rfid.losses),
+        reader => writeln!(
+            report,
+            "Reader {reader} present, lost {} times",
+            rfid.losses
+        ),
+    };
+    let _ = writeln!(
+        report,
+        "Polls: {}, deferred for the relay: {}",
+        rfid.polls, rfid.deferred
+    );
+    let _ = match rfid.percent() {
+        Some(percent) => writeln!(
+            report,
+            "Cards detected: {}, read: {}, misread: {} ({percent}% read)",
+            rfid.detections, rfid.reads, rfid.misreads
+        ),
+        None => writeln!(report, "No cards detected"),
+    };
+
+    if let Some(kind) = MeterKind::parse(config.meter_type).filter(MeterKind::is_modbus) {
+        let _ = writeln!(report, "\n[meter]");
+        let modbus = modbus::stats();
+        let _ = writeln!(
+            report,
+            "{} at address {}, requests: {}, timeouts: {}, crc errors: {}, exceptions: {}",
+            kind.as_str(),
+            config.meter_address,
+            modbus.requests,
+            modbus.timeouts,
+            modbus.crc_errors,
+            modbus.exceptions
+        );
+    }
+
+    let _ = writeln!(report, "\n[io]");
+    let _ = writeln!(report, "{}", io_state::snapshot_json());
+
+    let _ = writeln!(report, "\n[transitions]");
+    TRANSITION_BUFFER.lock(|buffer| {
+        for t in buffer.borrow().iter() {
+            let _ = writeln!(
+                report,
+                "{}s {} -> {} ({})",
+                t.uptime_secs,
+                t.from.wire_name(),
+                t.to.wire_name(),
+                t.input.wire_name()
+            );
+        }
+    });
+
+    let _ = writeln!(
+        report,
+        "\n[sequence]\nLast {} sessions, session uptime transition",
+        sequence::SESSIONS
+    );
+    let _ = sequence::write_report(&mut report);
+
+    let _ = writeln!(report, "\n[log]");
+    for entry in logging::entries(Level::Warn)? {
+        let _ = entry.write_line(&mut report);
+    }
+
+    Ok(report)
+}
+
+/// Handle a GetDiagnostics Call, the upload itself happens in the diagnostics task
+pub fn handle_get_diagnostics(payload: &str, config: &Config) -> CallResponse {
+    // An empty response tells the central system no diagnostics are available
+    if !memory::is_enabled(Feature::Diagnostics) {
+        warn!("DIAG: Diagnostics disabled after running out of memory");
+        let mut payload = heapless::String::new();
+        let _ = payload.push_str("{}");
+        return Ok(payload);
+    }
+
+    let Some(location) = ocpp::json_string_field(payload, "location") else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "GetDiagnostics requires a location",
+        ));
+    };
+
+    let Some(location) = ocpp::json_unescape::<256>(location) else {
+        return Err((
+            CallErrorCode::PropertyConstraintViolation,
+            "Location too long",
+        ));
+    };
+
+    let mut request = DiagnosticsRequest {
+        location,
+        file_name: heapless::String::new(),
+        retries: ocpp::json_integer_field(payload, "retries")
+            .map(|retries| retries.clamp(0, 5) as u8)
+            .unwrap_or(0),
+        retry_interval_secs: ocpp::json_integer_field(payload, "retryInterval")
+            .map(|interval| interval.clamp(0, 3600) as u16)
+            .unwrap_or(30),
+    };
+    let _ = write!(
+        request.file_name,
+        "diagnostics-{}-{}.txt",
+        config.charger_serial,
+        ntp::get_current_unix_time()
+    );
+
+    let mut payload = heapless::String::new();
+    let _ = write!(payload, "{{\"fileName\":\"{}\"}}", request.file_name);
+
+    match DIAGNOSTICS_REQUEST_CHANNEL.try_send(request) {
+        Ok(()) => {
+            info!("DIAG: Diagnostics upload scheduled");
+        }
+        Err(_) => {
+            // An empty response tells the central system no diagnostics are available
+            warn!("DIAG: Diagnostics upload already in progress");
+            payload.clear();
+            let _ = payload.push_str("{}");
+        }
+    }
+    Ok(payload)
+}
+
+fn diagnostics_status_notification(status: DiagnosticsStatus) -> Action {
+    Action::DiagnosticsStatusNotification(DiagnosticsStatusNotification { status })
+}
+
+fn send_status(status: DiagnosticsStatus, description: &str) {
+    ocpp::send_ocpp(description, Priority::Normal, |_| {
+        diagnostics_status_notification(status)
+    });
+}
+
+async fn upload(
+    network: &NetworkStack,
+    request: &DiagnosticsRequest,
+    body: &[u8],
+) -> Result<(), &'static str> {
+    let url = Url::parse(&request.location)?;
+    match url.scheme {
+        Scheme::Http => match http::put(network, &url, "text/plain", body).await? {
+            200..=299 => Ok(()),
+            _ => Err("Upload rejected by server"),
+        },
+        Scheme::Ftp => ftp::store(network, &url, body).await,
+        Scheme::Https => Err("HTTPS uploads are not supported"),
+    }
+}
+
+/// Task to upload diagnostics requested with GetDiagnostics
+#[embassy_executor::task]
+pub async fn diagnostics_upload_task(network: &'static NetworkStack) {
+    info!("TASK: Started Diagnostics Upload Handler");
+
+    loop {
+        let request = DIAGNOSTICS_REQUEST_CHANNEL.receive().await;
+        info!(
+            "DIAG: Uploading {} to {}",
+            request.file_name, request.location
+        );
+
+        send_status(
+            DiagnosticsStatus::Uploading,
+            "DiagnosticsStatusNotification Uploading",
+        );
+        let report = match build_report(&network.app_config, network) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("DIAG: {e}");
+                send_status(
+                    DiagnosticsStatus::UploadFailed,
+                    "DiagnosticsStatusNotification UploadFailed",
+                );
+                continue;
+            }
+        };
+
+        let mut attempt = 0;
+        let status = loop {
+            match upload(network, &request, report.as_bytes()).await {
+                Ok(()) => {
+                    info!("DIAG: Diagnostics uploaded ({} bytes)", report.len());
+                    break DiagnosticsStatus::Uploaded;
+                }
+                Err(e) if attempt < request.retries => {
+                    attempt += 1;
+                    warn!(
+                        "DIAG: Upload failed: {e}, retry {attempt} of {} in {}s",
+                        request.retries, request.retry_interval_secs
+                    );
+                    Timer::after(Duration::from_secs(request.retry_interval_secs.into())).await;
+                }
+                Err(e) => {
+                    warn!("DIAG: Upload failed: {e}");
+                    break DiagnosticsStatus::UploadFailed;
+                }
+            }
+        };
+
+        let description = match status {
+            DiagnosticsStatus::Uploaded => "DiagnosticsStatusNotification Uploaded",
+            _ => "DiagnosticsStatusNotification UploadFailed",
+        };
+        send_status(status, description);
+    }
+}
@@ -1,75 +1,235 @@
 use core::fmt::Write;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embedded_graphics::{
+    image::{Image, ImageRaw},
     mono_font::{
         ascii::{FONT_10X20, FONT_6X10},
         MonoTextStyleBuilder,
     },
     pixelcolor::BinaryColor,
     prelude::*,
-    primitives::{Circle, Line, PrimitiveStyleBuilder},
+    primitives::{Circle, Line, PrimitiveStyleBuilder, Rectangle},
     text::{Baseline, Text},
 };
-use log::info;
-use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
-
-use crate::{charger::ChargerState, config::Config, network::NetworkStack};
-
-/// Display manager for SSD1306 OLED display
-pub struct DisplayManager<I2C> {
-    display: Ssd1306<
-        I2CInterface<I2C>,
-        DisplaySize128x64,
-        ssd1306::mode::BufferedGraphicsMode<DisplaySize128x64>,
-    >,
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
+
+use crate::{
+    branding, button,
+    charger::{ChargerState, OutputEvent},
+    config::Config,
+    connectivity, demo,
+    fault::Fault,
+    info, locale, mqtt,
+    network::NetworkStack,
+    ota::UpdateProgress,
+    panel::Panel,
+    sessions::SessionStats,
+    solar, version, wifi_monitor,
+};
+
+/// Changes shown on the display besides the charger state, so it's redrawn right away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayEvent {
+    /// A firmware update started, progressed or ended
+    UpdateProgress,
+    /// A session was recorded, for the session totals
+    SessionRecorded,
+    /// The network was lost or restored
+    NetworkChanged,
+    /// An input reached the state machine, e.g. a cable or a card, wakes the display
+    Input,
+    /// The button asked for the next page
+    NextPage,
+    /// The button asked to stop the session, shown until it's confirmed
+    ConfirmStop,
+    /// A card joined the waiting list, at its position from 1
+    Queued(u8),
 }
 
-impl<I2C> DisplayManager<I2C>
-where
-    I2C: embedded_hal::i2c::I2c,
-{
-    /// Initialize the SSD1306 display
-    pub fn new(i2c: I2C) -> Result<Self, &'static str> {
-        info!("DISP:Initializing SSD1306 display...");
+/// Power of the display, dimmed and off against burn-in while nobody is around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayPower {
+    On,
+    Dimmed,
+    Off,
+}
 
-        let display_addr = 0x3C;
+/// Message queue for display events, events are dropped while it's full as the display is
+/// redrawn every second anyway
+pub static DISPLAY_CHANNEL: Channel<CriticalSectionRawMutex, DisplayEvent, 4> = Channel::new();
 
-        // Try to initialize with the detected address
-        info!("DISP: Trying I2C address 0x{display_addr:02X}...");
-        let interface = I2CDisplayInterface::new_custom_address(i2c, display_addr);
+/// Ask for a redraw of the display
+pub fn notify(event: DisplayEvent) {
+    let _ = DISPLAY_CHANNEL.try_send(event);
+}
 
-        let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-            .into_buffered_graphics_mode();
-        info!("Created display object, attempting init() with address 0x{display_addr:02X}...");
+/// Display manager, draws the screens on the panel of the board
+pub struct DisplayManager<P> {
+    display: P,
+}
 
-        match display.init() {
-            Ok(()) => {
-                info!("DISP: Display init() completed successfully with address 0x{display_addr:02X}!");
-            }
-            Err(_) => {
-                info!("DISP: Failed to initialize display at address 0x{display_addr:02X}");
-                return Err("Failed to initialize display - device responded but init failed");
-            }
+impl<P: Panel> DisplayManager<P> {
+    /// Take over an initialized panel
+    pub fn new(display: P) -> Self {
+        info!("DISP: {} display initialized successfully", P::NAME);
+        DisplayManager { display }
+    }
+
+    /// Draw a screen and show it
+    pub fn show(&mut self, screen: &impl Screen) -> Result<(), &'static str> {
+        self.display.clear_buffer();
+        screen.draw(&mut self.display)?;
+        self.display.flush()
+    }
+
+    /// Dim the display or turn it off, the contents are kept
+    pub fn set_power(&mut self, power: DisplayPower) -> Result<(), &'static str> {
+        self.display.set_power(power)
+    }
+
+    /// Show a QR code of a text, e.g. a deep link for a phone app
+    pub fn draw_qr(&mut self, data: &str) -> Result<(), &'static str> {
+        self.show(&QrCodeScreen {
+            url: data,
+            serial: "",
+        })
+    }
+
+    /// Draw the logo uploaded for this deployment, or the built-in GA Make logo
+    pub fn draw_logo(&mut self) -> Result<(), &'static str> {
+        // Clear the display buffer first
+        self.display.clear_buffer();
+
+        if let Some(logo) = branding::load() {
+            let raw = ImageRaw::<BinaryColor>::new(&logo, branding::LOGO_WIDTH);
+            Image::new(&raw, Point::zero())
+                .draw(&mut self.display)
+                .map_err(|_| "Failed to draw stored logo")?;
+            return self.display.flush();
         }
 
-        // Clear the display and flush
-        display.flush().map_err(|_| "Failed to flush display")?;
-        info!("DISP: Display cleared and flushed successfully");
+        let stroke_style = PrimitiveStyleBuilder::new()
+            .stroke_color(BinaryColor::On)
+            .stroke_width(1)
+            .build();
+
+        let thick_stroke_style = PrimitiveStyleBuilder::new()
+            .stroke_color(BinaryColor::On)
+            .stroke_width(2)
+            .build();
+
+        let center_x = 64; // Center of 128px width
+        let center_y = 32; // Center of 64px height
+
+        let circle = Circle::new(Point::new(center_x - 25, center_y - 25), 50);
+        circle
+            .into_styled(thick_stroke_style)
+            .draw(&mut self.display)
+            .map_err(|_| "Failed to draw main circle")?;
+
+        let left_line = Line::new(
+            Point::new(center_x - 15, center_y), // Start point
+            Point::new(center_x - 2, center_y),  // End point
+        );
+        left_line
+            .into_styled(stroke_style)
+            .draw(&mut self.display)
+            .map_err(|_| "Failed to draw left line")?;
+
+        let vertical_down = Line::new(
+            Point::new(center_x - 2, center_y),      // Start point
+            Point::new(center_x - 2, center_y + 22), // End point (down)
+        );
+        vertical_down
+            .into_styled(stroke_style)
+            .draw(&mut self.display)
+            .map_err(|_| "Failed to draw vertical down line")?;
 
-        info!("DISP: SSD1306 display initialized successfully");
+        let vertical_up = Line::new(
+            Point::new(center_x + 2, center_y + 22), // Start point
+            Point::new(center_x + 2, center_y - 22), // End point (up)
+        );
+        vertical_up
+            .into_styled(stroke_style)
+            .draw(&mut self.display)
+            .map_err(|_| "Failed to draw vertical up line")?;
 
-        Ok(DisplayManager { display })
+        let right_line = Line::new(
+            Point::new(center_x + 2, center_y),  // Start point
+            Point::new(center_x + 20, center_y), // End point
+        );
+        right_line
+            .into_styled(stroke_style)
+            .draw(&mut self.display)
+            .map_err(|_| "Failed to draw right line")?;
+
+        let text_style = MonoTextStyleBuilder::new()
+            .font(&FONT_6X10)
+            .text_color(BinaryColor::On)
+            .build();
+
+        Text::with_baseline(
+            "Make",
+            Point::new(center_x + 20, 55),
+            text_style,
+            Baseline::Top,
+        )
+        .draw(&mut self.display)
+        .map_err(|_| "Failed to draw logo text")?;
+
+        self.display.flush()
     }
 
-    /// Update the display with current charger information
-    pub fn update_display(
-        &mut self,
-        config: &Config,
-        network: &NetworkStack,
-        charger_state: ChargerState,
-    ) -> Result<(), &'static str> {
-        // Clear the display buffer
+    /// Clear the display
+    pub fn clear(&mut self) -> Result<(), &'static str> {
         self.display.clear_buffer();
+        self.display.flush()
+    }
+}
+
+/// A page of the display, drawn into the buffer of the 128x64 panel
+pub trait Screen {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>;
+}
+
+/// Draw up to 5 lines of small text, cut off at the width of the panel
+fn draw_lines<D>(target: &mut D, lines: &[&str]) -> Result<(), &'static str>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let text_style = MonoTextStyleBuilder::new()
+        .font(&FONT_6X10)
+        .text_color(BinaryColor::On)
+        .build();
+
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.get(..21).unwrap_or(line);
+        Text::with_baseline(
+            line,
+            Point::new(0, i as i32 * 12),
+            text_style,
+            Baseline::Top,
+        )
+        .draw(target)
+        .map_err(|_| "Failed to draw text")?;
+    }
+    Ok(())
+}
+
+/// Serial, state, IP address, signal strength and local time
+pub struct StatusScreen<'a> {
+    pub config: &'a Config,
+    pub network: &'a NetworkStack,
+    pub state: ChargerState,
+}
 
+impl Screen for StatusScreen<'_> {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
         let text_style = MonoTextStyleBuilder::new()
             .font(&FONT_6X10)
             .text_color(BinaryColor::On)
@@ -77,14 +237,14 @@ where
 
         // Line 1: Serial number
         let mut serial_line = heapless::String::<21>::new();
-        if config.charger_serial.len() > 20 {
-            let _ = write!(serial_line, "{}...", &config.charger_serial[..17]);
+        if self.config.charger_serial.len() > 20 {
+            let _ = write!(serial_line, "{}...", &self.config.charger_serial[..17]);
         } else {
-            let _ = write!(serial_line, "{}", config.charger_serial);
+            let _ = write!(serial_line, "{}", self.config.charger_serial);
         }
 
         Text::with_baseline(&serial_line, Point::new(0, 0), text_style, Baseline::Top)
-            .draw(&mut self.display)
+            .draw(target)
             .map_err(|_| "Failed to draw serial")?;
 
         // horizontal line
@@ -99,11 +259,11 @@ where
         );
         left_line
             .into_styled(stroke_style)
-            .draw(&mut self.display)
+            .draw(target)
             .map_err(|_| "Failed to draw left line")?;
 
         // Line 2: Current state in a full-width rectangle with inverted text and larger font
-        let state_text = charger_state.as_str();
+        let state_text = self.state.as_str();
         // Using larger FONT_10X20 which is approximately 2x the size of FONT_6X10
         let char_width = 10; // Width per character for FONT_10X20
 
@@ -125,7 +285,7 @@ where
         .into_styled(rect_style);
 
         state_rect
-            .draw(&mut self.display)
+            .draw(target)
             .map_err(|_| "Failed to draw state background")?;
 
         // Inverted text style for the state with larger font
@@ -145,7 +305,7 @@ where
             inverted_text_style,
             Baseline::Top,
         )
-        .draw(&mut self.display)
+        .draw(target)
         .map_err(|_| "Failed to draw state")?;
 
         // horizontal line0
@@ -160,130 +320,541 @@ where
         );
         left_line
             .into_styled(stroke_style)
-            .draw(&mut self.display)
+            .draw(target)
             .map_err(|_| "Failed to draw left line")?;
 
         // Line 4: IP Address
         let mut ip_line = heapless::String::<21>::new();
-        if let Some(ip) = network.get_ip_address() {
+        if demo::is_enabled() {
+            let _ = write!(ip_line, "Demo mode");
+        } else if connectivity::is_offline() {
+            let _ = write!(ip_line, "Offline mode");
+        } else if connectivity::is_backend_degraded() {
+            let _ = write!(ip_line, "Backend silent");
+        } else if let Some(ip) = self.network.get_ip_address() {
             let _ = write!(ip_line, "{ip}");
         } else {
             let _ = write!(ip_line, "Not Connected");
         }
 
         Text::with_baseline(&ip_line, Point::new(0, 46), text_style, Baseline::Top) // Moved down 4 pixels
-            .draw(&mut self.display)
+            .draw(target)
             .map_err(|_| "Failed to draw IP address")?;
 
+        // Signal strength, right aligned on the IP address line
+        if let Some(rssi) = wifi_monitor::rssi() {
+            let mut rssi_text = heapless::String::<8>::new();
+            let _ = write!(rssi_text, "{rssi}dB");
+            let x = 128 - rssi_text.len() as i32 * 6;
+            Text::with_baseline(&rssi_text, Point::new(x, 46), text_style, Baseline::Top)
+                .draw(target)
+                .map_err(|_| "Failed to draw signal strength")?;
+        }
+
         // Line 5: Current local time (if NTP is synced)
         let mut time_line = heapless::String::<21>::new();
         if crate::ntp::is_time_synced() {
-            let local_time = crate::ntp::get_local_time_formatted(config.timezone_offset_hours);
-            let local_date = crate::ntp::get_local_date_formatted(config.timezone_offset_hours);
+            let local_time = crate::ntp::get_local_time_formatted(&self.config.time_zone);
+            let local_date = crate::ntp::get_local_date_formatted(&self.config.time_zone);
             let _ = write!(time_line, "{local_date} {local_time}");
         } else {
             let _ = write!(time_line, "Time Not Synced");
         }
 
         Text::with_baseline(&time_line, Point::new(0, 56), text_style, Baseline::Top) // Moved down 4 pixels
-            .draw(&mut self.display)
+            .draw(target)
             .map_err(|_| "Failed to draw time")?;
 
-        // Flush the buffer to the display
-        self.display
-            .flush()
-            .map_err(|_| "Failed to flush display")?;
-
         Ok(())
     }
+}
+/// Charger identity and firmware version
+pub struct AboutScreen<'a> {
+    pub config: &'a Config,
+}
 
-    /// Draw the GA Make logo on the display
-    pub fn draw_logo(&mut self) -> Result<(), &'static str> {
-        // Clear the display buffer first
-        self.display.clear_buffer();
+impl Screen for AboutScreen<'_> {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut version_line = heapless::String::<21>::new();
+        let _ = write!(version_line, "v{}", version::FIRMWARE_VERSION);
+        let mut build_line = heapless::String::<21>::new();
+        let _ = write!(build_line, "git {}", version::GIT_HASH);
+        let mut ocpp_line = heapless::String::<21>::new();
+        let _ = write!(ocpp_line, "OCPP 1.6 ");
+        for profile in version::OCPP_PROFILES {
+            if ocpp_line.push_str(profile).is_err() || ocpp_line.push(' ').is_err() {
+                break;
+            }
+        }
 
-        let stroke_style = PrimitiveStyleBuilder::new()
-            .stroke_color(BinaryColor::On)
-            .stroke_width(1)
-            .build();
+        draw_lines(
+            target,
+            &[
+                self.config.charger_vendor,
+                self.config.charger_model,
+                &version_line,
+                &build_line,
+                &ocpp_line,
+            ],
+        )
+    }
+}
 
-        let thick_stroke_style = PrimitiveStyleBuilder::new()
-            .stroke_color(BinaryColor::On)
-            .stroke_width(2)
-            .build();
+/// Totals of the recorded sessions and the last session, in the number formats of the
+/// configured locale
+pub struct SessionsScreen<'a> {
+    pub config: &'a Config,
+    pub stats: &'a SessionStats,
+}
 
-        let center_x = 64; // Center of 128px width
-        let center_y = 32; // Center of 64px height
+impl Screen for SessionsScreen<'_> {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let locale = self.config.locale;
+        let stats = self.stats;
+
+        let mut lines: [heapless::String<32>; 5] = Default::default();
+        let _ = write!(lines[0], "{} sessions", stats.count);
+        let _ = write!(lines[1], "Total {}", locale.energy(stats.energy_wh));
+        if self.config.tariff_cents_per_kwh > 0 {
+            let cost = locale::cost_cents(stats.energy_wh, self.config.tariff_cents_per_kwh);
+            let _ = write!(lines[2], "Cost  {}", locale.money(cost));
+        }
+        if let Some((duration_secs, energy_wh)) = stats.last {
+            let _ = write!(
+                lines[3],
+                "Last  {}h{:02}m",
+                duration_secs / 3600,
+                duration_secs / 60 % 60
+            );
+            let _ = write!(lines[4], "      {}", locale.energy(energy_wh.into()));
+        }
 
-        let circle = Circle::new(Point::new(center_x - 25, center_y - 25), 50);
-        circle
-            .into_styled(thick_stroke_style)
-            .draw(&mut self.display)
-            .map_err(|_| "Failed to draw main circle")?;
+        let lines = lines.each_ref().map(|line| line.as_str());
+        draw_lines(target, &lines)
+    }
+}
 
-        let left_line = Line::new(
-            Point::new(center_x - 15, center_y), // Start point
-            Point::new(center_x - 2, center_y),  // End point
-        );
-        left_line
-            .into_styled(stroke_style)
-            .draw(&mut self.display)
-            .map_err(|_| "Failed to draw left line")?;
+/// Network the charger is connected to, its address and signal strength and the backend
+/// connection
+pub struct NetworkScreen<'a> {
+    pub network: &'a NetworkStack,
+}
 
-        let vertical_down = Line::new(
-            Point::new(center_x - 2, center_y),      // Start point
-            Point::new(center_x - 2, center_y + 22), // End point (down)
+impl Screen for NetworkScreen<'_> {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut lines: [heapless::String<32>; 5] = Default::default();
+        let _ = write!(
+            lines[0],
+            "WiFi {}",
+            wifi_monitor::ssid().unwrap_or("not connected")
         );
-        vertical_down
-            .into_styled(stroke_style)
-            .draw(&mut self.display)
-            .map_err(|_| "Failed to draw vertical down line")?;
-
-        let vertical_up = Line::new(
-            Point::new(center_x + 2, center_y + 22), // Start point
-            Point::new(center_x + 2, center_y - 22), // End point (up)
+        if let Some(ip) = self.network.get_ip_address() {
+            let _ = write!(lines[1], "IP   {ip}");
+        }
+        if let Some(rssi) = wifi_monitor::rssi() {
+            let _ = write!(lines[2], "RSSI {rssi}dBm");
+        }
+        let backend = if demo::is_enabled() {
+            "demo"
+        } else if connectivity::is_offline() {
+            "offline"
+        } else if connectivity::is_backend_degraded() {
+            "degraded"
+        } else {
+            "online"
+        };
+        let _ = write!(lines[3], "OCPP {backend}");
+        let stats = mqtt::stats();
+        let _ = write!(
+            lines[4],
+            "Sent {} Drops {}",
+            stats.sent, stats.broker_disconnects
         );
-        vertical_up
-            .into_styled(stroke_style)
-            .draw(&mut self.display)
-            .map_err(|_| "Failed to draw vertical up line")?;
 
-        let right_line = Line::new(
-            Point::new(center_x + 2, center_y),  // Start point
-            Point::new(center_x + 20, center_y), // End point
+        let lines = lines.each_ref().map(|line| line.as_str());
+        draw_lines(target, &lines)
+    }
+}
+
+/// The running transaction, its duration, the current limit and the energy and cost. Without a
+/// meter IC the energy is estimated, the central system knows the energy that was billed
+pub struct TransactionScreen<'a> {
+    pub config: &'a Config,
+    pub transaction_id: i32,
+    pub id_tag: &'a str,
+    pub duration_secs: u64,
+    pub limit_amps: u16,
+    pub energy_wh: u64,
+    /// Power measured by the meter IC, None while the energy is estimated
+    pub watts: Option<u32>,
+}
+
+impl Screen for TransactionScreen<'_> {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let locale = &self.config.locale;
+        let mut lines: [heapless::String<32>; 5] = Default::default();
+        let _ = write!(lines[0], "Tx {} {}", self.transaction_id, self.id_tag);
+        let _ = write!(
+            lines[1],
+            "Time  {}:{:02}:{:02}",
+            self.duration_secs / 3600,
+            self.duration_secs / 60 % 60,
+            self.duration_secs % 60
         );
-        right_line
-            .into_styled(stroke_style)
-            .draw(&mut self.display)
-            .map_err(|_| "Failed to draw right line")?;
+        match self.watts {
+            Some(watts) => {
+                let _ = write!(lines[2], "Limit {}A {}W", self.limit_amps, watts);
+            }
+            None => {
+                let _ = write!(lines[2], "Limit {}A", self.limit_amps);
+            }
+        }
+        // The limit follows the solar surplus
+        if solar::is_eco() {
+            let _ = write!(lines[2], " Eco");
+        }
+        // Estimated from the offered current without a meter IC, marked as such
+        let estimate = if self.watts.is_some() { "" } else { "~" };
+        let _ = write!(
+            lines[3],
+            "Energy {estimate}{}",
+            locale.energy(self.energy_wh)
+        );
+        if self.config.tariff_cents_per_kwh > 0 {
+            let cost = locale::cost_cents(self.energy_wh, self.config.tariff_cents_per_kwh);
+            let _ = write!(lines[4], "Cost  {estimate}{}", locale.money(cost));
+        }
+
+        let lines = lines.each_ref().map(|line| line.as_str());
+        draw_lines(target, &lines)
+    }
+}
+
+/// The fault of a faulted or locked out charger and what clears it
+pub struct ErrorScreen {
+    pub state: ChargerState,
+    pub fault: Option<Fault>,
+    pub locked_out: bool,
+}
+
+impl Screen for ErrorScreen {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let fill_style = PrimitiveStyleBuilder::new()
+            .fill_color(BinaryColor::On)
+            .build();
+        Rectangle::new(Point::new(0, 0), Size::new(128, 22))
+            .into_styled(fill_style)
+            .draw(target)
+            .map_err(|_| "Failed to draw error background")?;
+
+        let inverted_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(BinaryColor::Off)
+            .build();
+        let title = self.state.as_str();
+        let x = (128 - title.len() as i32 * 10) / 2;
+        Text::with_baseline(title, Point::new(x, 1), inverted_style, Baseline::Top)
+            .draw(target)
+            .map_err(|_| "Failed to draw error title")?;
 
         let text_style = MonoTextStyleBuilder::new()
             .font(&FONT_6X10)
             .text_color(BinaryColor::On)
             .build();
+        let hint = if self.locked_out {
+            "Swipe master card"
+        } else {
+            "Remove the cable"
+        };
+        let lines = [
+            self.fault.map_or("Unknown fault", |fault| fault.as_str()),
+            if self.locked_out { "Locked out" } else { "" },
+            hint,
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            Text::with_baseline(
+                line,
+                Point::new(0, 28 + i as i32 * 12),
+                text_style,
+                Baseline::Top,
+            )
+            .draw(target)
+            .map_err(|_| "Failed to draw error")?;
+        }
+        Ok(())
+    }
+}
 
+/// Largest QR code that fits the height of the panel with its quiet zone, 41x41 modules
+const QR_MAX_VERSION: Version = Version::new(6);
+
+/// The configured URL as a QR code, e.g. to start a session or pay from a phone, with the
+/// serial next to it when given
+pub struct QrCodeScreen<'a> {
+    pub url: &'a str,
+    pub serial: &'a str,
+}
+
+impl QrCodeScreen<'_> {
+    /// The configured URL with `{serial}` replaced, or the serial when no URL is configured so
+    /// a phone app can pair with the charger. None when the URL doesn't fit
+    pub fn url(config: &Config) -> Option<heapless::String<128>> {
+        let mut url = heapless::String::new();
+        if config.display_qr_url.is_empty() {
+            url.push_str(config.charger_serial).ok()?;
+            return Some(url);
+        }
+        let mut parts = config.display_qr_url.split("{serial}");
+        url.push_str(parts.next()?).ok()?;
+        for part in parts {
+            url.push_str(config.charger_serial).ok()?;
+            url.push_str(part).ok()?;
+        }
+        Some(url)
+    }
+}
+
+impl Screen for QrCodeScreen<'_> {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut temp_buffer = [0u8; QR_MAX_VERSION.buffer_len()];
+        let mut out_buffer = [0u8; QR_MAX_VERSION.buffer_len()];
+        let qr = QrCode::encode_text(
+            self.url,
+            &mut temp_buffer,
+            &mut out_buffer,
+            QrCodeEcc::Low,
+            Version::MIN,
+            QR_MAX_VERSION,
+            None,
+            true,
+        )
+        .map_err(|_| "QR code URL too long")?;
+
+        // Dark modules on a lit square, with a quiet zone of at least one module
+        let size = qr.size();
+        let scale = (64 / (size + 2)).max(1);
+        let offset = (64 - size * scale) / 2;
+        let lit_style = PrimitiveStyleBuilder::new()
+            .fill_color(BinaryColor::On)
+            .build();
+        let dark_style = PrimitiveStyleBuilder::new()
+            .fill_color(BinaryColor::Off)
+            .build();
+        Rectangle::new(Point::zero(), Size::new(64, 64))
+            .into_styled(lit_style)
+            .draw(target)
+            .map_err(|_| "Failed to draw QR code")?;
+        for y in 0..size {
+            for x in 0..size {
+                if qr.get_module(x, y) {
+                    Rectangle::new(
+                        Point::new(offset + x * scale, offset + y * scale),
+                        Size::new(scale as u32, scale as u32),
+                    )
+                    .into_styled(dark_style)
+                    .draw(target)
+                    .map_err(|_| "Failed to draw QR code")?;
+                }
+            }
+        }
+
+        let text_style = MonoTextStyleBuilder::new()
+            .font(&FONT_6X10)
+            .text_color(BinaryColor::On)
+            .build();
+        let serial = self.serial.get(..10).unwrap_or(self.serial);
+        for (i, line) in ["Scan to", "charge", "", serial].iter().enumerate() {
+            Text::with_baseline(
+                line,
+                Point::new(68, 8 + i as i32 * 12),
+                text_style,
+                Baseline::Top,
+            )
+            .draw(target)
+            .map_err(|_| "Failed to draw QR code caption")?;
+        }
+        Ok(())
+    }
+}
+
+/// Feedback on a card swipe, shown over the pages for a few seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Banner {
+    Authorized,
+    Rejected,
+    ConfirmStop,
+    /// The card is first on the waiting list
+    YoureNext,
+    /// The card is on the waiting list behind others
+    InLine,
+}
+
+impl Banner {
+    /// Time a banner is shown
+    pub const SECS: u64 = 3;
+
+    /// The banner for the output events of a state change, if any
+    pub fn for_events(events: &[OutputEvent]) -> Option<Self> {
+        if events.contains(&OutputEvent::ShowRejected) {
+            Some(Self::Rejected)
+        } else if events.contains(&OutputEvent::ApplyPower) {
+            Some(Self::Authorized)
+        } else {
+            None
+        }
+    }
+
+    /// The banner for a card that joined the waiting list at a position
+    pub fn for_position(position: u8) -> Self {
+        if position <= 1 {
+            Self::YoureNext
+        } else {
+            Self::InLine
+        }
+    }
+
+    /// Time the banner is shown, a stop is asked for as long as it waits for the confirmation
+    pub fn secs(&self) -> u64 {
+        match self {
+            Self::ConfirmStop => button::STOP_CONFIRM_SECS,
+            Self::Authorized | Self::Rejected | Self::YoureNext | Self::InLine => Self::SECS,
+        }
+    }
+
+    fn lines(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Authorized => ("Authorized", "Charging starts"),
+            Self::Rejected => ("Rejected", "Card not accepted"),
+            Self::ConfirmStop => ("Stop?", "Press again to stop"),
+            Self::YoureNext => ("You're next", "Plug in when it's free"),
+            Self::InLine => ("In line", "Others are waiting"),
+        }
+    }
+}
+
+impl Screen for Banner {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let (title, detail) = self.lines();
+        let fill_style = PrimitiveStyleBuilder::new()
+            .fill_color(BinaryColor::On)
+            .build();
+        Rectangle::new(Point::new(0, 8), Size::new(128, 30))
+            .into_styled(fill_style)
+            .draw(target)
+            .map_err(|_| "Failed to draw banner")?;
+
+        let title_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(BinaryColor::Off)
+            .build();
         Text::with_baseline(
-            "Make",
-            Point::new(center_x + 20, 55),
-            text_style,
+            title,
+            Point::new((128 - title.len() as i32 * 10) / 2, 13),
+            title_style,
             Baseline::Top,
         )
-        .draw(&mut self.display)
-        .map_err(|_| "Failed to draw logo text")?;
-
-        self.display
-            .flush()
-            .map_err(|_| "Failed to flush display")?;
+        .draw(target)
+        .map_err(|_| "Failed to draw banner")?;
 
+        let detail_style = MonoTextStyleBuilder::new()
+            .font(&FONT_6X10)
+            .text_color(BinaryColor::On)
+            .build();
+        Text::with_baseline(
+            detail,
+            Point::new((128 - detail.len() as i32 * 6) / 2, 46),
+            detail_style,
+            Baseline::Top,
+        )
+        .draw(target)
+        .map_err(|_| "Failed to draw banner")?;
         Ok(())
     }
+}
+
+/// Firmware update with the image, stage and a progress bar
+pub struct UpdateScreen<'a> {
+    pub progress: &'a UpdateProgress,
+}
+
+impl Screen for UpdateScreen<'_> {
+    fn draw<D>(&self, target: &mut D) -> Result<(), &'static str>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let text_style = MonoTextStyleBuilder::new()
+            .font(&FONT_6X10)
+            .text_color(BinaryColor::On)
+            .build();
+        let title_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(BinaryColor::On)
+            .build();
+
+        Text::with_baseline("Updating...", Point::new(9, 0), title_style, Baseline::Top)
+            .draw(target)
+            .map_err(|_| "Failed to draw update title")?;
+
+        let image = self
+            .progress
+            .image
+            .get(..21)
+            .unwrap_or(&self.progress.image);
+        Text::with_baseline(image, Point::new(0, 24), text_style, Baseline::Top)
+            .draw(target)
+            .map_err(|_| "Failed to draw update image")?;
+
+        let mut stage_line = heapless::String::<21>::new();
+        let _ = write!(
+            stage_line,
+            "{} {}%",
+            self.progress.stage.as_str(),
+            self.progress.percent
+        );
+        Text::with_baseline(&stage_line, Point::new(0, 36), text_style, Baseline::Top)
+            .draw(target)
+            .map_err(|_| "Failed to draw update stage")?;
+
+        // Progress bar: an outline with a filled part for the percentage
+        let outline_style = PrimitiveStyleBuilder::new()
+            .stroke_color(BinaryColor::On)
+            .stroke_width(1)
+            .build();
+        let fill_style = PrimitiveStyleBuilder::new()
+            .fill_color(BinaryColor::On)
+            .build();
+
+        Rectangle::new(Point::new(0, 52), Size::new(128, 10))
+            .into_styled(outline_style)
+            .draw(target)
+            .map_err(|_| "Failed to draw progress bar")?;
+        let filled = 124 * u32::from(self.progress.percent.min(100)) / 100;
+        Rectangle::new(Point::new(2, 54), Size::new(filled, 6))
+            .into_styled(fill_style)
+            .draw(target)
+            .map_err(|_| "Failed to draw progress bar")?;
 
-    /// Clear the display
-    pub fn clear(&mut self) -> Result<(), &'static str> {
-        self.display.clear_buffer();
-        self.display
-            .flush()
-            .map_err(|_| "Failed to flush display")?;
         Ok(())
     }
 }
@@ -0,0 +1,246 @@
+use core::{
+    cell::Cell,
+    fmt::Write,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{
+    info, ntp,
+    storage::{self, Slot},
+    utils, warn,
+};
+
+/// Ports remembered for the broker
+const PORTS: usize = 4;
+/// Size of the serialized record, the broker hash and the ports
+const RECORD_SIZE: usize = 4 + PORTS * 10;
+
+/// Connection history of one port of the broker
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortHealth {
+    pub port: u16,
+    pub successes: u16,
+    pub failures: u16,
+    /// Unix time of the last connection, 0 if it never connected or the clock wasn't set
+    pub last_success: u32,
+}
+
+/// Ports of the broker that were tried from this site, the last one that connected first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct HealthRecord {
+    /// Crc of the broker's host name, a different broker starts a new record
+    broker_hash: u32,
+    ports: [PortHealth; PORTS],
+}
+
+impl HealthRecord {
+    const fn new(broker_hash: u32) -> Self {
+        Self {
+            broker_hash,
+            ports: [PortHealth {
+                port: 0,
+                successes: 0,
+                failures: 0,
+                last_success: 0,
+            }; PORTS],
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.broker_hash.to_le_bytes());
+        for (health, chunk) in self.ports.iter().zip(bytes[4..].chunks_exact_mut(10)) {
+            chunk[0..2].copy_from_slice(&health.port.to_le_bytes());
+            chunk[2..4].copy_from_slice(&health.successes.to_le_bytes());
+            chunk[4..6].copy_from_slice(&health.failures.to_le_bytes());
+            chunk[6..10].copy_from_slice(&health.last_success.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+        let mut record = Self::new(u32::from_le_bytes(bytes[0..4].try_into().ok()?));
+        for (health, chunk) in record.ports.iter_mut().zip(bytes[4..].chunks_exact(10)) {
+            *health = PortHealth {
+                port: u16::from_le_bytes([chunk[0], chunk[1]]),
+                successes: u16::from_le_bytes([chunk[2], chunk[3]]),
+                failures: u16::from_le_bytes([chunk[4], chunk[5]]),
+                last_success: u32::from_le_bytes([chunk[6], chunk[7], chunk[8], chunk[9]]),
+            };
+        }
+        Some(record)
+    }
+
+    /// The entry of a port, added in place of the least proven one when it's new
+    fn entry(&mut self, port: u16) -> (&mut PortHealth, bool) {
+        match self.ports.iter().position(|health| health.port == port) {
+            Some(index) => (&mut self.ports[index], false),
+            None => {
+                // Ports that never connected go first, then the one that connected longest ago
+                let index = self
+                    .ports
+                    .iter()
+                    .position(|health| health.port == 0)
+                    .or_else(|| self.ports.iter().rposition(|health| health.successes == 0))
+                    .unwrap_or(PORTS - 1);
+                self.ports[index] = PortHealth {
+                    port,
+                    ..Default::default()
+                };
+                (&mut self.ports[index], true)
+            }
+        }
+    }
+}
+
+static RECORD: Mutex<CriticalSectionRawMutex, Cell<HealthRecord>> =
+    Mutex::new(Cell::new(HealthRecord::new(0)));
+/// Failed connections since the last one that succeeded, picks the next candidate port
+static ATTEMPT: AtomicU32 = AtomicU32::new(0);
+
+fn broker_hash(broker: &str) -> u32 {
+    utils::crc32(broker.as_bytes())
+}
+
+fn save(record: &HealthRecord) {
+    if let Err(e) = storage::write(Slot::Endpoints, &record.to_bytes()) {
+        warn!("ENDP: Failed to persist the broker port history: {e}");
+    }
+}
+
+/// Restore the port history of the broker, kept through a factory reset and firmware updates
+pub fn load(broker: &str) {
+    let mut buffer = [0u8; RECORD_SIZE];
+    let stored = match storage::read(Slot::Endpoints, &mut buffer) {
+        Ok(Some(len)) => HealthRecord::from_bytes(&buffer[..len]),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("ENDP: Failed to read the broker port history: {e}");
+            None
+        }
+    };
+    let hash = broker_hash(broker);
+    let record = match stored {
+        Some(record) if record.broker_hash == hash => record,
+        Some(_) => {
+            info!("ENDP: The broker changed, its ports are learned again");
+            HealthRecord::new(hash)
+        }
+        None => HealthRecord::new(hash),
+    };
+    RECORD.lock(|current| current.set(record));
+    if let Some(good) = last_known_good() {
+        info!(
+            "ENDP: Last known good port of {broker} is {}, connected {} times",
+            good.port, good.successes
+        );
+    }
+}
+
+/// Ports tried from this site, the last one that connected first
+pub fn history() -> heapless::Vec<PortHealth, PORTS> {
+    RECORD.lock(|record| {
+        record
+            .get()
+            .ports
+            .into_iter()
+            .filter(|health| health.port != 0)
+            .collect()
+    })
+}
+
+/// The port that connected last, None before the broker was reached from this site
+pub fn last_known_good() -> Option<PortHealth> {
+    history().into_iter().find(|health| health.successes > 0)
+}
+
+/// Port for the next connection. The proven ports come first, so a reset configuration or a
+/// firmware update with a different default doesn't lose a port that works from this site, the
+/// configured port is tried when they fail
+pub fn next_port(configured: u16) -> u16 {
+    let mut candidates: heapless::Vec<u16, { PORTS + 1 }> = history()
+        .into_iter()
+        .filter(|health| health.successes > 0)
+        .map(|health| health.port)
+        .collect();
+    if !candidates.contains(&configured) {
+        let _ = candidates.push(configured);
+    }
+    let attempt = ATTEMPT.load(Ordering::Relaxed) as usize;
+    candidates[attempt % candidates.len()]
+}
+
+/// Record a connection to the broker, the port moves to the front of the history
+pub fn record_success(port: u16) {
+    ATTEMPT.store(0, Ordering::Relaxed);
+    let unix_time = u32::try_from(ntp::get_current_unix_time()).unwrap_or(0);
+    let (record, changed) = RECORD.lock(|current| {
+        let mut record = current.get();
+        let was_good = record.ports[0].port == port && record.ports[0].successes > 0;
+        let (health, added) = record.entry(port);
+        health.successes = health.successes.saturating_add(1);
+        health.last_success = unix_time;
+        let index = record
+            .ports
+            .iter()
+            .position(|health| health.port == port)
+            .unwrap_or(0);
+        record.ports[..=index].rotate_right(1);
+        current.set(record);
+        (record, added || !was_good)
+    });
+    // Only a new last known good port is written, the counts are saved along with it
+    if changed {
+        info!("ENDP: Port {port} is the last known good port of the broker");
+        save(&record);
+    }
+}
+
+/// Record a failed connection, the next attempt uses the next candidate port
+pub fn record_failure(port: u16) {
+    ATTEMPT.fetch_add(1, Ordering::Relaxed);
+    RECORD.lock(|current| {
+        let mut record = current.get();
+        let (health, _) = record.entry(port);
+        health.failures = health.failures.saturating_add(1);
+        current.set(record);
+    });
+}
+
+/// Forget the ports of the broker, the configured port is used until one connects
+pub fn clear() {
+    ATTEMPT.store(0, Ordering::Relaxed);
+    RECORD.lock(|current| current.set(HealthRecord::new(current.get().broker_hash)));
+    if let Err(e) = storage::erase(Slot::Endpoints) {
+        warn!("ENDP: Failed to erase the broker port history: {e}");
+    }
+    info!("ENDP: Cleared the broker port history");
+}
+
+/// One line per port, the last known good one first
+pub fn report() -> heapless::String<384> {
+    let mut report = heapless::String::new();
+    for health in history() {
+        let _ = write!(
+            report,
+            "Port {}: {} connected, {} failed",
+            health.port, health.successes, health.failures
+        );
+        let _ = match health.last_success {
+            0 => writeln!(report),
+            unix_time => writeln!(
+                report,
+                ", last at {}",
+                ntp::format_iso8601(u64::from(unix_time))
+            ),
+        };
+    }
+    if report.is_empty() {
+        let _ = writeln!(report, "No broker connections recorded");
+    }
+    report
+}
@@ -0,0 +1,249 @@
+use core::{cell::Cell, fmt::Write};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    config::Config,
+    data_transfer, info, meter, ntp,
+    ready::{self, Subsystem},
+    storage::{self, Slot},
+    timezone::TimeZone,
+    warn,
+};
+
+/// Size of the serialized energy record
+const RECORD_SIZE: usize = 61;
+/// Interval the day is checked at, the report follows midnight within this time
+const CHECK_SECS: u64 = 60;
+/// Time between saves of the register while it's advancing, a restart loses less than this
+const SAVE_INTERVAL_SECS: u64 = 1800;
+const SECS_PER_DAY: i64 = 86400;
+
+/// Energy of one local day, the register delta against the energy of the sessions stopped that day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DailyReport {
+    /// Local days since the Unix epoch
+    pub day: u32,
+    pub register_start_wh: u64,
+    pub register_end_wh: u64,
+    pub sessions_wh: u64,
+    pub sessions: u16,
+}
+
+impl DailyReport {
+    /// The report as JSON, a difference means energy delivered without a recorded session
+    fn to_json(&self) -> heapless::String<256> {
+        let date = ntp::format_iso8601(u64::from(self.day) * SECS_PER_DAY as u64);
+        let delta_wh = self.register_end_wh.saturating_sub(self.register_start_wh);
+        let mut json = heapless::String::new();
+        let _ = write!(
+            json,
+            "{{\"date\":\"{}\",\"registerStartWh\":{},\"registerEndWh\":{},\"registerDeltaWh\":{delta_wh},\
+             \"sessionsWh\":{},\"sessions\":{},\"differenceWh\":{}}}",
+            date.get(..10).unwrap_or(&date),
+            self.register_start_wh,
+            self.register_end_wh,
+            self.sessions_wh,
+            self.sessions,
+            delta_wh as i64 - self.sessions_wh as i64
+        );
+        json
+    }
+}
+
+/// The register and the tally of the current day, kept in flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EnergyRecord {
+    register_wh: u64,
+    /// Local days since the Unix epoch, 0 until the clock was set
+    day: u32,
+    day_start_wh: u64,
+    day_sessions_wh: u64,
+    day_sessions: u16,
+    /// The last day, until its report was sent
+    report: Option<DailyReport>,
+}
+
+impl EnergyRecord {
+    const fn new() -> Self {
+        Self {
+            register_wh: 0,
+            day: 0,
+            day_start_wh: 0,
+            day_sessions_wh: 0,
+            day_sessions: 0,
+            report: None,
+        }
+    }
+
+    /// Serialized as the integers (little endian), the report after a flag byte
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.register_wh.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.day.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.day_start_wh.to_le_bytes());
+        bytes[20..28].copy_from_slice(&self.day_sessions_wh.to_le_bytes());
+        bytes[28..30].copy_from_slice(&self.day_sessions.to_le_bytes());
+        if let Some(report) = self.report {
+            bytes[30] = 1;
+            bytes[31..35].copy_from_slice(&report.day.to_le_bytes());
+            bytes[35..43].copy_from_slice(&report.register_start_wh.to_le_bytes());
+            bytes[43..51].copy_from_slice(&report.register_end_wh.to_le_bytes());
+            bytes[51..59].copy_from_slice(&report.sessions_wh.to_le_bytes());
+            bytes[59..61].copy_from_slice(&report.sessions.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+        let long = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let integer = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let short = |at: usize| u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap());
+        Some(Self {
+            register_wh: long(0),
+            day: integer(8),
+            day_start_wh: long(12),
+            day_sessions_wh: long(20),
+            day_sessions: short(28),
+            report: (bytes[30] == 1).then(|| DailyReport {
+                day: integer(31),
+                register_start_wh: long(35),
+                register_end_wh: long(43),
+                sessions_wh: long(51),
+                sessions: short(59),
+            }),
+        })
+    }
+}
+
+static RECORD: Mutex<CriticalSectionRawMutex, Cell<EnergyRecord>> =
+    Mutex::new(Cell::new(EnergyRecord::new()));
+/// Register reading of the last save and when it was made
+static LAST_SAVE: Mutex<CriticalSectionRawMutex, Cell<Option<(u64, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+fn update(f: impl FnOnce(&mut EnergyRecord)) -> EnergyRecord {
+    RECORD.lock(|record| {
+        let mut updated = record.get();
+        f(&mut updated);
+        record.set(updated);
+        updated
+    })
+}
+
+fn save(record: &EnergyRecord) {
+    if let Err(e) = storage::write(Slot::Energy, &record.to_bytes()) {
+        warn!("ENGY: Failed to persist the energy register: {e}");
+    }
+    LAST_SAVE.lock(|last| last.set(Some((record.register_wh, Instant::now()))));
+}
+
+/// Restore the energy register and the tally of the day stored before a restart
+pub fn load() {
+    let mut buffer = [0u8; RECORD_SIZE];
+    let record = match storage::read(Slot::Energy, &mut buffer) {
+        Ok(Some(len)) => EnergyRecord::from_bytes(&buffer[..len]),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("ENGY: Failed to read the energy register: {e}");
+            None
+        }
+    };
+    let Some(record) = record else {
+        return;
+    };
+    info!(
+        "ENGY: Restored the energy register at {}Wh",
+        record.register_wh
+    );
+    meter::restore_register(record.register_wh);
+    RECORD.lock(|current| current.set(record));
+    LAST_SAVE.lock(|last| last.set(Some((record.register_wh, Instant::now()))));
+}
+
+/// Add a stopped session to the tally of the day
+pub fn session_recorded(energy_wh: u32) {
+    let register_wh = meter::register_wh();
+    let record = update(|record| {
+        record.register_wh = register_wh;
+        record.day_sessions_wh += u64::from(energy_wh);
+        record.day_sessions = record.day_sessions.saturating_add(1);
+    });
+    save(&record);
+}
+
+/// Local days since the Unix epoch, None before the clock was set
+fn local_day(time_zone: &TimeZone) -> Option<u32> {
+    let now = ntp::get_current_unix_time() as i64;
+    if now == 0 {
+        return None;
+    }
+    let local = now + i64::from(time_zone.offset_at(now));
+    u32::try_from(local.div_euclid(SECS_PER_DAY)).ok()
+}
+
+/// Task to close the day at local midnight and report the energy reconciliation, so the central
+/// system can tell when StopTransaction or MeterValues messages went missing. The register is
+/// saved periodically while it advances, so energy of a session lost in a restart still counts
+#[embassy_executor::task]
+pub async fn energy_task() {
+    info!("TASK: Started Energy Reconciliation");
+
+    let config = Config::from_config();
+    ready::wait(Subsystem::Time).await;
+
+    loop {
+        let register_wh = meter::register_wh();
+        let today = local_day(&config.time_zone);
+        let mut day_changed = false;
+        let record = update(|record| {
+            record.register_wh = register_wh;
+            let Some(today) = today.filter(|today| *today != record.day) else {
+                return;
+            };
+            if record.day != 0 {
+                record.report = Some(DailyReport {
+                    day: record.day,
+                    register_start_wh: record.day_start_wh,
+                    register_end_wh: register_wh,
+                    sessions_wh: record.day_sessions_wh,
+                    sessions: record.day_sessions,
+                });
+            }
+            record.day = today;
+            record.day_start_wh = register_wh;
+            record.day_sessions_wh = 0;
+            record.day_sessions = 0;
+            day_changed = true;
+        });
+
+        let save_due = LAST_SAVE.lock(|last| match last.get() {
+            Some((saved_wh, saved_at)) => {
+                saved_wh != register_wh
+                    && saved_at.elapsed() >= Duration::from_secs(SAVE_INTERVAL_SECS)
+            }
+            None => register_wh > 0,
+        });
+        if day_changed || save_due {
+            save(&record);
+        }
+
+        if let Some(report) = record.report.filter(|_| ready::is_ready(Subsystem::Boot)) {
+            let json = report.to_json();
+            if data_transfer::send_data_transfer(
+                config.charger_vendor,
+                Some("EnergyReconciliation"),
+                Some(&json),
+            ) {
+                info!("ENGY: Sent the energy reconciliation {json}");
+                let record = update(|record| record.report = None);
+                save(&record);
+            }
+        }
+
+        Timer::after(Duration::from_secs(CHECK_SECS)).await;
+    }
+}
@@ -0,0 +1,163 @@
+use embedded_hal::i2c::I2c;
+
+use crate::info;
+
+/// MCP23017 registers, in the default bank 0 layout with port B at the next address
+const MCP_IODIR: u8 = 0x00;
+const MCP_GPPU: u8 = 0x0C;
+const MCP_GPIO: u8 = 0x12;
+const MCP_OLAT: u8 = 0x14;
+
+/// GPIO expander chips on the I2C bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpanderKind {
+    /// 16 pins with a direction and pull-up per pin
+    Mcp23017,
+    /// 8 quasi-bidirectional pins, a pin written high is a weakly pulled up input
+    Pcf8574,
+}
+
+impl ExpanderKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "mcp23017" => Some(Self::Mcp23017),
+            "pcf8574" => Some(Self::Pcf8574),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mcp23017 => "MCP23017",
+            Self::Pcf8574 => "PCF8574",
+        }
+    }
+
+    pub fn pins(&self) -> u8 {
+        match self {
+            Self::Mcp23017 => 16,
+            Self::Pcf8574 => 8,
+        }
+    }
+}
+
+/// Pins of a GPIO expander, for the pin mapping to use without knowing the chip or the bus
+pub trait ExpanderIo {
+    fn configure_output(&mut self, pin: u8, high: bool) -> Result<(), &'static str>;
+    /// Configure a pin as an input with its pull-up
+    fn configure_input(&mut self, pin: u8) -> Result<(), &'static str>;
+    fn set_output(&mut self, pin: u8, high: bool) -> Result<(), &'static str>;
+    fn is_high(&mut self, pin: u8) -> Result<bool, &'static str>;
+}
+
+/// A GPIO expander, the output levels are kept so a pin is changed without reading the others
+pub struct Expander<I2C> {
+    i2c: I2C,
+    kind: ExpanderKind,
+    address: u8,
+    /// Output latch, bit per pin
+    latch: u16,
+    /// Pins configured as inputs, bit per pin
+    inputs: u16,
+    /// Pins with their pull-up on, bit per pin
+    pull_ups: u16,
+}
+
+impl<I2C: I2c> Expander<I2C> {
+    /// Detect the expander at an address, all pins start as inputs
+    pub fn new(i2c: I2C, kind: ExpanderKind, address: u8) -> Result<Self, &'static str> {
+        let mut expander = Self {
+            i2c,
+            kind,
+            address,
+            latch: 0xFFFF,
+            inputs: 0xFFFF,
+            pull_ups: 0,
+        };
+        expander.write_directions()?;
+        expander.write_latch()?;
+        info!("PINS: {} expander found at 0x{address:02X}", kind.as_str());
+        Ok(expander)
+    }
+
+    fn check_pin(&self, pin: u8) -> Result<u16, &'static str> {
+        if pin >= self.kind.pins() {
+            return Err("Pin beyond the pins of the expander");
+        }
+        Ok(1 << pin)
+    }
+
+    /// Write a 16 bit register pair of the MCP23017, port A first
+    fn write_pair(&mut self, register: u8, value: u16) -> Result<(), &'static str> {
+        let [a, b] = value.to_le_bytes();
+        self.i2c
+            .write(self.address, &[register, a, b])
+            .map_err(|_| "Expander not responding")
+    }
+
+    fn write_directions(&mut self) -> Result<(), &'static str> {
+        match self.kind {
+            ExpanderKind::Mcp23017 => {
+                self.write_pair(MCP_IODIR, self.inputs)?;
+                self.write_pair(MCP_GPPU, self.pull_ups)
+            }
+            // Inputs are pins written high
+            ExpanderKind::Pcf8574 => self.write_latch(),
+        }
+    }
+
+    fn write_latch(&mut self) -> Result<(), &'static str> {
+        match self.kind {
+            ExpanderKind::Mcp23017 => self.write_pair(MCP_OLAT, self.latch),
+            ExpanderKind::Pcf8574 => {
+                let byte = (self.latch | self.inputs) as u8;
+                self.i2c
+                    .write(self.address, &[byte])
+                    .map_err(|_| "Expander not responding")
+            }
+        }
+    }
+}
+
+impl<I2C: I2c> ExpanderIo for Expander<I2C> {
+    fn configure_output(&mut self, pin: u8, high: bool) -> Result<(), &'static str> {
+        let bit = self.check_pin(pin)?;
+        self.inputs &= !bit;
+        self.pull_ups &= !bit;
+        self.set_output(pin, high)?;
+        self.write_directions()
+    }
+
+    fn configure_input(&mut self, pin: u8) -> Result<(), &'static str> {
+        let bit = self.check_pin(pin)?;
+        self.inputs |= bit;
+        self.pull_ups |= bit;
+        self.write_directions()
+    }
+
+    fn set_output(&mut self, pin: u8, high: bool) -> Result<(), &'static str> {
+        let bit = self.check_pin(pin)?;
+        if high {
+            self.latch |= bit;
+        } else {
+            self.latch &= !bit;
+        }
+        self.write_latch()
+    }
+
+    fn is_high(&mut self, pin: u8) -> Result<bool, &'static str> {
+        let bit = self.check_pin(pin)?;
+        let mut levels = [0u8; 2];
+        match self.kind {
+            ExpanderKind::Mcp23017 => self
+                .i2c
+                .write_read(self.address, &[MCP_GPIO], &mut levels)
+                .map_err(|_| "Expander not responding")?,
+            ExpanderKind::Pcf8574 => self
+                .i2c
+                .read(self.address, &mut levels[..1])
+                .map_err(|_| "Expander not responding")?,
+        }
+        Ok(u16::from_le_bytes(levels) & bit != 0)
+    }
+}
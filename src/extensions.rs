@@ -0,0 +1,157 @@
+extern crate alloc;
+use alloc::string::String;
+use core::fmt::Write;
+
+use crate::{
+    config::Config,
+    data_transfer::{self, DataTransferHandler},
+    info, ocpp, warn,
+};
+
+/// Room for the fields an extension adds to the data of one DataTransfer
+const MAX_DATA_FIELDS: usize = 192;
+
+/// Optional BootNotification fields the charger leaves empty, for an extension to fill in. Values
+/// longer than OCPP allows are cut off
+#[derive(Debug, Clone, Default)]
+pub struct BootFields {
+    pub charge_point_serial_number: Option<heapless::String<25>>,
+    pub iccid: Option<heapless::String<20>>,
+    pub imsi: Option<heapless::String<20>>,
+    pub meter_serial_number: Option<heapless::String<25>>,
+    pub meter_type: Option<heapless::String<25>>,
+}
+
+/// Copy as much of a value as fits an OCPP field
+pub fn field<const N: usize>(value: &str) -> heapless::String<N> {
+    let mut field = heapless::String::new();
+    for c in value.chars() {
+        if field.push(c).is_err() {
+            break;
+        }
+    }
+    field
+}
+
+/// JSON fields added to the data object of an outgoing DataTransfer
+#[derive(Debug, Default)]
+pub struct DataFields {
+    json: heapless::String<MAX_DATA_FIELDS>,
+}
+
+impl DataFields {
+    /// Add a field, one that doesn't fit is left out whole
+    fn add(&mut self, write: impl FnOnce(&mut Self) -> Option<()>) -> Result<(), &'static str> {
+        let len = self.json.len();
+        let separated = self.json.is_empty() || self.json.push(',').is_ok();
+        if separated && write(self).is_some() {
+            return Ok(());
+        }
+        self.json.truncate(len);
+        Err("No room for another DataTransfer field")
+    }
+
+    /// Add a string field, escaped for JSON
+    pub fn string(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        self.add(|fields| {
+            write!(fields.json, "\"{key}\":\"").ok()?;
+            ocpp::push_json_escaped(&mut fields.json, value)?;
+            fields.json.push('"').ok()
+        })
+    }
+
+    /// Add a number field
+    pub fn number(&mut self, key: &str, value: i64) -> Result<(), &'static str> {
+        self.add(|fields| write!(fields.json, "\"{key}\":{value}").ok())
+    }
+}
+
+/// Fills BootNotification fields, an extension listed later overrides the ones before it
+pub type BootHook = fn(&Config, &mut BootFields);
+/// Adds fields to the data of a DataTransfer the charger sends, with its vendorId and messageId
+pub type DataFieldsHook = fn(&str, Option<&str>, &mut DataFields);
+
+/// A DataTransfer handler of an extension
+pub struct InboundHandler {
+    /// vendorId of the calls, None for the configured vendor
+    pub vendor_id: Option<&'static str>,
+    /// messageId of the calls, None for every message of the vendor
+    pub message_id: Option<&'static str>,
+    pub handler: DataTransferHandler,
+}
+
+/// An operator specific extension of the OCPP messages, kept by a fork in its own module
+pub struct VendorExtension {
+    pub name: &'static str,
+    pub boot_notification: Option<BootHook>,
+    pub data_fields: Option<DataFieldsHook>,
+    pub handlers: &'static [InboundHandler],
+}
+
+/// The extensions of this build. A fork lists its extensions here, the builders in ocpp.rs and
+/// data_transfer.rs pick them up, e.g.
+///
+/// ```ignore
+/// pub const EXTENSIONS: &[VendorExtension] = &[acme::EXTENSION];
+/// ```
+pub const EXTENSIONS: &[VendorExtension] = &[];
+
+/// Register the DataTransfer handlers of the extensions, before the OCPP tasks are spawned
+pub fn init(config: &Config) {
+    for extension in EXTENSIONS {
+        for inbound in extension.handlers {
+            let vendor_id = inbound.vendor_id.unwrap_or(config.charger_vendor);
+            if let Err(e) =
+                data_transfer::register_handler(vendor_id, inbound.message_id, inbound.handler)
+            {
+                warn!("EXT : {}: {e}", extension.name);
+            }
+        }
+        info!("EXT : Vendor extension {} enabled", extension.name);
+    }
+}
+
+/// The BootNotification fields of the extensions
+pub fn boot_fields(config: &Config) -> BootFields {
+    let mut fields = BootFields::default();
+    for hook in EXTENSIONS
+        .iter()
+        .filter_map(|extension| extension.boot_notification)
+    {
+        hook(config, &mut fields);
+    }
+    fields
+}
+
+/// The data of an outgoing DataTransfer with the fields of the extensions added. Only a JSON
+/// object gets fields, other data is sent as it is
+pub fn extend_data(vendor_id: &str, message_id: Option<&str>, data: &str) -> String {
+    let mut extended = String::from(data);
+    let Some(body) = data
+        .trim_end()
+        .strip_suffix('}')
+        .filter(|_| data.trim_start().starts_with('{'))
+    else {
+        return extended;
+    };
+
+    let mut fields = DataFields::default();
+    for hook in EXTENSIONS
+        .iter()
+        .filter_map(|extension| extension.data_fields)
+    {
+        hook(vendor_id, message_id, &mut fields);
+    }
+    if fields.json.is_empty() {
+        return extended;
+    }
+
+    extended.clear();
+    extended.push_str(body);
+    if !body.trim_end().ends_with('{') {
+        extended.push(',');
+    }
+    extended.push_str(&fields.json);
+    extended.push('}');
+    extended
+}
@@ -0,0 +1,217 @@
+use embassy_sync::pubsub::{DynSubscriber, WaitResult};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::Output;
+use esp_println::println;
+
+use crate::{
+    charger::{self, Charger, ChargerState, InputEvent, OutputEvent, OutputEvents},
+    config::Config,
+    info, meter, metering, version, warn,
+};
+
+const STEP_TIMEOUT_SECS: u64 = 5;
+const FACTORY_ID_TAG: &str = "FACTORYTEST";
+
+/// Test load bank: a contactor that connects the load and resistor stages that set its power
+pub struct LoadBank {
+    contactor: Output<'static>,
+    stages: [Output<'static>; 2],
+    stage_watts: u16,
+}
+
+impl LoadBank {
+    pub fn new(contactor: Output<'static>, stages: [Output<'static>; 2], stage_watts: u16) -> Self {
+        let mut load_bank = Self {
+            contactor,
+            stages,
+            stage_watts,
+        };
+        load_bank.disconnect();
+        load_bank
+    }
+
+    /// Engage a number of resistor stages and close the contactor, returns the nominal load in watts
+    pub fn connect(&mut self, stages: usize) -> u32 {
+        for (i, stage) in self.stages.iter_mut().enumerate() {
+            if i < stages {
+                stage.set_high();
+            } else {
+                stage.set_low();
+            }
+        }
+        self.contactor.set_high();
+        let watts = stages.min(self.stages.len()) as u32 * u32::from(self.stage_watts);
+        info!("LOAD: Load bank connected, {watts}W");
+        watts
+    }
+
+    /// Open the contactor before releasing the stages, so the stages never switch under load
+    pub fn disconnect(&mut self) {
+        self.contactor.set_low();
+        for stage in self.stages.iter_mut() {
+            stage.set_low();
+        }
+        info!("LOAD: Load bank disconnected");
+    }
+}
+
+/// Outcome of one step of the factory test
+struct Step {
+    name: &'static str,
+    passed: bool,
+}
+
+struct Report {
+    steps: heapless::Vec<Step, 8>,
+    energy_wh: u32,
+}
+
+impl Report {
+    fn record(&mut self, name: &'static str, passed: bool) -> bool {
+        if passed {
+            info!("FACT: {name} passed");
+        } else {
+            warn!("FACT: {name} failed");
+        }
+        let _ = self.steps.push(Step { name, passed });
+        passed
+    }
+
+    fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.passed)
+    }
+
+    fn print(&self, config: &Config) {
+        println!("==== FACTORY TEST REPORT ====");
+        println!("Serial:   {}", config.charger_serial);
+        println!(
+            "Model:    {} {}",
+            config.charger_vendor, config.charger_model
+        );
+        println!("Firmware: {}", version::firmware_version());
+        for step in self.steps.iter() {
+            println!(
+                "[{}] {}",
+                if step.passed { "PASS" } else { "FAIL" },
+                step.name
+            );
+        }
+        println!("Energy:   {} Wh", self.energy_wh);
+        println!("RESULT:   {}", if self.passed() { "PASS" } else { "FAIL" });
+        println!("=============================");
+    }
+}
+
+/// Wait for the state machine to publish a state with the given output events
+async fn expect_state(
+    subscriber: &mut DynSubscriber<'_, (ChargerState, OutputEvents)>,
+    expected: ChargerState,
+    events: &[OutputEvent],
+) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(STEP_TIMEOUT_SECS);
+    while Instant::now() < deadline {
+        match embassy_time::with_timeout_at(deadline, subscriber.next_message()).await {
+            Ok(WaitResult::Message((state, output_events))) if state == expected => {
+                return events.iter().all(|event| output_events.contains(event));
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    warn!("FACT: Timeout waiting for {}", expected.as_str());
+    false
+}
+
+/// Run a simulated charging session end-to-end and print a pass/fail report over serial
+/// The session is driven by injecting the events of a cable, a card and the central system
+pub async fn run(charger: &'static Charger, load_bank: &mut LoadBank, config: &Config) -> bool {
+    info!("FACT: Starting factory test");
+    let mut subscriber = charger::STATE_PUBSUB.dyn_subscriber().unwrap();
+    let mut report = Report {
+        steps: heapless::Vec::new(),
+        energy_wh: 0,
+    };
+
+    // Let the hardware tasks settle
+    Timer::after(Duration::from_secs(1)).await;
+    let ready = report.record(
+        "Charger available",
+        charger.get_state().await == ChargerState::Available,
+    );
+
+    if ready {
+        charger::STATE_IN_CHANNEL
+            .send(InputEvent::InsertCable)
+            .await;
+        report.record(
+            "Cable insert -> Preparing",
+            expect_state(&mut subscriber, ChargerState::Preparing, &[]).await,
+        );
+
+        charger.set_id_tag(FACTORY_ID_TAG).await;
+        charger::STATE_IN_CHANNEL
+            .send(InputEvent::SwipeDetected)
+            .await;
+        report.record(
+            "Card swipe -> Authorizing",
+            expect_state(&mut subscriber, ChargerState::Authorizing, &[]).await,
+        );
+
+        charger::STATE_IN_CHANNEL.send(InputEvent::Accepted).await;
+        let charging = report.record(
+            "Authorized -> Charging, lock and power",
+            expect_state(
+                &mut subscriber,
+                ChargerState::Charging,
+                &[OutputEvent::Lock, OutputEvent::ApplyPower],
+            )
+            .await,
+        );
+
+        if charging {
+            // The meter IC has to count the energy of the load bank, an estimate proves nothing
+            let before = meter::measured_mwh();
+            let watts = load_bank.connect(2);
+            let start = Instant::now();
+            Timer::after(Duration::from_secs(config.factory_session_secs.into())).await;
+            load_bank.disconnect();
+            // W x ms / 3600 is mWh
+            let expected_mwh = u64::from(watts) * start.elapsed().as_millis() / 3600;
+            let measured_mwh = meter::measured_mwh() - before;
+            let tolerance_mwh = expected_mwh * u64::from(config.factory_energy_tolerance) / 100;
+            info!("FACT: Metered {measured_mwh} mWh, expected {expected_mwh} mWh");
+            report.energy_wh = (measured_mwh / 1000) as u32;
+            report.record(
+                "Meter accumulated energy",
+                metering::present()
+                    && measured_mwh > 0
+                    && measured_mwh.abs_diff(expected_mwh) <= tolerance_mwh,
+            );
+        }
+
+        charger::STATE_IN_CHANNEL
+            .send(InputEvent::SwipeDetected)
+            .await;
+        report.record(
+            "Card swipe -> stop, unlock and remove power",
+            expect_state(
+                &mut subscriber,
+                ChargerState::Preparing,
+                &[OutputEvent::RemovePower, OutputEvent::Unlock],
+            )
+            .await,
+        );
+
+        charger::STATE_IN_CHANNEL
+            .send(InputEvent::RemoveCable)
+            .await;
+        report.record(
+            "Cable remove -> Available",
+            expect_state(&mut subscriber, ChargerState::Available, &[]).await,
+        );
+    }
+
+    load_bank.disconnect();
+    report.print(config);
+    report.passed()
+}
@@ -0,0 +1,176 @@
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use ocpp_rs::v16::enums::ChargePointErrorCode;
+
+use crate::{
+    config::Config,
+    info,
+    sequence::{self, Transition},
+    warn, webhook,
+};
+
+/// Most occurrences that can be tracked per fault, limits the lockout count
+const MAX_OCCURRENCES: usize = 10;
+
+/// Faults the charger recovers from automatically, until they recur too often
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The cable was removed while charging
+    EvDisconnected,
+    /// The negative half of the pilot isn't clipped by the vehicle's diode
+    PilotDiodeMissing,
+    /// A safety invariant didn't hold
+    InvariantViolated,
+    /// The residual current device tripped
+    GroundFault,
+    /// The contactor didn't follow the relay, welded or failed to close
+    PowerSwitchFailure,
+    /// A temperature sensor went above the critical temperature
+    HighTemperature,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 6] = [
+        Fault::EvDisconnected,
+        Fault::PilotDiodeMissing,
+        Fault::InvariantViolated,
+        Fault::GroundFault,
+        Fault::PowerSwitchFailure,
+        Fault::HighTemperature,
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            Self::EvDisconnected => 0,
+            Self::PilotDiodeMissing => 1,
+            Self::InvariantViolated => 2,
+            Self::GroundFault => 3,
+            Self::PowerSwitchFailure => 4,
+            Self::HighTemperature => 5,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::EvDisconnected => "EvDisconnected",
+            Self::PilotDiodeMissing => "PilotDiodeMissing",
+            Self::InvariantViolated => "InvariantViolated",
+            Self::GroundFault => "GroundFault",
+            Self::PowerSwitchFailure => "PowerSwitchFailure",
+            Self::HighTemperature => "HighTemperature",
+        }
+    }
+
+    /// Error code reported in a StatusNotification
+    pub fn error_code(&self) -> ChargePointErrorCode {
+        match self {
+            Self::EvDisconnected => ChargePointErrorCode::OtherError,
+            Self::PilotDiodeMissing => ChargePointErrorCode::EVCommunicationError,
+            Self::InvariantViolated => ChargePointErrorCode::InternalError,
+            Self::GroundFault => ChargePointErrorCode::GroundFailure,
+            Self::PowerSwitchFailure => ChargePointErrorCode::PowerSwitchFailure,
+            Self::HighTemperature => ChargePointErrorCode::HighTemperature,
+        }
+    }
+}
+
+struct FaultHistory {
+    occurrences: [heapless::Deque<u64, MAX_OCCURRENCES>; Fault::ALL.len()],
+    last: Option<Fault>,
+    locked_out: Option<Fault>,
+    lockout_count: usize,
+    lockout_window_secs: u64,
+    master_id_tag: &'static str,
+}
+
+static FAULTS: Mutex<CriticalSectionRawMutex, RefCell<FaultHistory>> =
+    Mutex::new(RefCell::new(FaultHistory {
+        occurrences: [const { heapless::Deque::new() }; Fault::ALL.len()],
+        last: None,
+        locked_out: None,
+        lockout_count: 3,
+        lockout_window_secs: 3600,
+        master_id_tag: "",
+    }));
+
+/// Apply the lockout settings from the configuration
+pub fn init(config: &Config) {
+    FAULTS.lock(|faults| {
+        let mut faults = faults.borrow_mut();
+        faults.lockout_count = usize::from(config.fault_lockout_count).clamp(1, MAX_OCCURRENCES);
+        faults.lockout_window_secs = u64::from(config.fault_lockout_window_mins) * 60;
+        faults.master_id_tag = config.master_id_tag;
+    });
+}
+
+/// Record a fault, returns true if it recurred often enough to latch the lockout
+pub fn record(fault: Fault) -> bool {
+    let now = Instant::now().as_secs();
+    sequence::record(Transition::Fault(fault));
+    let locked_out = FAULTS.lock(|faults| {
+        let mut faults = faults.borrow_mut();
+        let window = faults.lockout_window_secs;
+        let count = faults.lockout_count;
+        faults.last = Some(fault);
+
+        let occurrences = &mut faults.occurrences[fault.index()];
+        while occurrences
+            .front()
+            .is_some_and(|at| now.saturating_sub(*at) > window)
+        {
+            occurrences.pop_front();
+        }
+        if occurrences.is_full() {
+            occurrences.pop_front();
+        }
+        let _ = occurrences.push_back(now);
+        let recurrences = occurrences.len();
+
+        warn!(
+            "FLT : {} occurred ({recurrences} of {count} within {}min)",
+            fault.as_str(),
+            window / 60
+        );
+        if recurrences >= count {
+            warn!("FLT : {} keeps recurring, locking out", fault.as_str());
+            faults.locked_out = Some(fault);
+        }
+        faults.locked_out.is_some()
+    });
+    webhook::fault_raised(fault, locked_out);
+    locked_out
+}
+
+/// The fault that latched the lockout, if any
+pub fn lockout() -> Option<Fault> {
+    FAULTS.lock(|faults| faults.borrow().locked_out)
+}
+
+/// The most recent fault
+pub fn last_fault() -> Option<Fault> {
+    FAULTS.lock(|faults| faults.borrow().last)
+}
+
+/// Clear the lockout and the fault history, returns false if there was no lockout
+pub fn clear_lockout() -> bool {
+    FAULTS.lock(|faults| {
+        let mut faults = faults.borrow_mut();
+        let Some(fault) = faults.locked_out.take() else {
+            return false;
+        };
+        info!("FLT : Lockout for {} cleared", fault.as_str());
+        for occurrences in faults.occurrences.iter_mut() {
+            occurrences.clear();
+        }
+        true
+    })
+}
+
+/// Whether an id tag is the master card that can clear a lockout on site
+pub fn is_master_id_tag(id_tag: &str) -> bool {
+    FAULTS.lock(|faults| {
+        let master_id_tag = faults.borrow().master_id_tag;
+        !master_id_tag.is_empty() && master_id_tag.eq_ignore_ascii_case(id_tag)
+    })
+}
@@ -0,0 +1,176 @@
+use embassy_time::{Duration, Timer};
+use smart_leds::{
+    colors::{BLUE, GREEN, RED, WHITE, YELLOW},
+    RGB8,
+};
+
+use crate::charger::{ChargerState, OutputEvent};
+
+/// Pitch of a beep on the buzzer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tone {
+    /// The resonant frequency of a common piezo disc, the loudest
+    High,
+    /// A low buzz for a refusal
+    Low,
+}
+
+impl Tone {
+    pub fn hz(&self) -> u32 {
+        match self {
+            Self::High => 2700,
+            Self::Low => 1000,
+        }
+    }
+}
+
+/// Strength of the prompts, the volume of the buzzer and the brightness of the LED flashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intensity {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl Intensity {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "off" => Some(Self::Off),
+            "low" => Some(Self::Low),
+            "medium" => Some(Self::Medium),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+
+    /// Duty cycle of the buzzer, a square wave at 50% is the loudest
+    pub fn buzzer_duty_pct(&self) -> u8 {
+        match self {
+            Self::Off => 0,
+            Self::Low => 5,
+            Self::Medium => 15,
+            Self::High => 50,
+        }
+    }
+
+    /// Brightness of the LED flashes
+    pub fn led_brightness(&self) -> u8 {
+        match self {
+            Self::Off => 0,
+            Self::Low => 20,
+            Self::Medium => 60,
+            Self::High => 160,
+        }
+    }
+}
+
+/// Key prompts, each with its own rhythm on the buzzer and the LED so they can be told apart
+/// without seeing the display or telling colors apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    /// Two short beeps, white flashes
+    PresentCard,
+    /// One short beep, yellow flash
+    CardDetected,
+    /// Two beeps, green flashes
+    Accepted,
+    /// One long beep, blue flash
+    ChargingStarted,
+    /// Two long beeps, green flashes
+    ChargingComplete,
+    /// One long low beep, red flash
+    Rejected,
+    /// Alternating high and low beeps, red flashes, repeated by the buzzer while Faulted
+    Fault,
+}
+
+impl Prompt {
+    /// The prompt for a state change, if any
+    pub fn for_transition(
+        old: ChargerState,
+        new: ChargerState,
+        events: &[OutputEvent],
+    ) -> Option<Self> {
+        if events.contains(&OutputEvent::ShowRejected) {
+            return Some(Self::Rejected);
+        }
+        if new == ChargerState::Faulted && old != ChargerState::Faulted {
+            return Some(Self::Fault);
+        }
+        match (old, new) {
+            (ChargerState::Available | ChargerState::Reserved, ChargerState::Preparing) => {
+                Some(Self::PresentCard)
+            }
+            (_, ChargerState::Authorizing) => Some(Self::CardDetected),
+            (ChargerState::Authorizing, ChargerState::Charging) => Some(Self::Accepted),
+            (_, ChargerState::Charging) => Some(Self::ChargingStarted),
+            (ChargerState::Charging, ChargerState::Preparing) => Some(Self::ChargingComplete),
+            _ => None,
+        }
+    }
+
+    /// Rhythm of the prompt as the tone, on and off times in milliseconds
+    pub fn pattern(&self) -> &'static [(Tone, u64, u64)] {
+        match self {
+            Self::PresentCard => &[(Tone::High, 100, 100), (Tone::High, 100, 100)],
+            Self::CardDetected => &[(Tone::High, 80, 80)],
+            Self::Accepted => &[(Tone::High, 150, 100), (Tone::High, 150, 100)],
+            Self::ChargingStarted => &[(Tone::High, 600, 200)],
+            Self::ChargingComplete => &[(Tone::High, 300, 150), (Tone::High, 300, 150)],
+            Self::Rejected => &[(Tone::Low, 800, 200)],
+            Self::Fault => &[
+                (Tone::High, 250, 0),
+                (Tone::Low, 250, 0),
+                (Tone::High, 250, 0),
+                (Tone::Low, 250, 250),
+            ],
+        }
+    }
+
+    /// Color of the LED flashes
+    pub fn color(&self) -> RGB8 {
+        match self {
+            Self::PresentCard => WHITE,
+            Self::CardDetected => YELLOW,
+            Self::Accepted => GREEN,
+            Self::ChargingStarted => BLUE,
+            Self::ChargingComplete => GREEN,
+            Self::Rejected | Self::Fault => RED,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PresentCard => "PresentCard",
+            Self::CardDetected => "CardDetected",
+            Self::Accepted => "Accepted",
+            Self::ChargingStarted => "ChargingStarted",
+            Self::ChargingComplete => "ChargingComplete",
+            Self::Rejected => "Rejected",
+            Self::Fault => "Fault",
+        }
+    }
+}
+
+/// Play the rhythm of a prompt, `set` switches the buzzer or the LED to a tone or off
+pub async fn play(prompt: Prompt, mut set: impl FnMut(Option<Tone>)) {
+    for &(tone, on_ms, off_ms) in prompt.pattern() {
+        set(Some(tone));
+        Timer::after(Duration::from_millis(on_ms)).await;
+        if off_ms > 0 {
+            set(None);
+            Timer::after(Duration::from_millis(off_ms)).await;
+        }
+    }
+    set(None);
+}
@@ -0,0 +1,46 @@
+//! Log macros of the firmware, `use crate::{info, warn}` in place of the ones of `log`. Without
+//! the `defmt` feature they're those of `log`, with it the lines go out over RTT with defmt and
+//! are still kept in the ring buffer and forwarded, see the logging module
+
+#[cfg(feature = "defmt")]
+defmt::timestamp!("{=u64:ms}", embassy_time::Instant::now().as_millis());
+
+#[cfg(feature = "defmt")]
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::logging::emit(::log::Level::Error, format_args!($($arg)+))
+    };
+}
+
+#[cfg(feature = "defmt")]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::logging::emit(::log::Level::Warn, format_args!($($arg)+))
+    };
+}
+
+#[cfg(feature = "defmt")]
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::logging::emit(::log::Level::Info, format_args!($($arg)+))
+    };
+}
+
+#[cfg(feature = "defmt")]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::logging::emit(::log::Level::Debug, format_args!($($arg)+))
+    };
+}
+
+#[cfg(feature = "defmt")]
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::logging::emit(::log::Level::Trace, format_args!($($arg)+))
+    };
+}
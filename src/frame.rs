@@ -0,0 +1,253 @@
+//! OCPP-J frames from the central system. The frame is split into its elements by a tokenizer
+//! that follows strings, escapes and nesting, so a payload may contain any JSON. Pure like the
+//! state machine, the tests in `host/` build it for the host
+
+/// OCPP-J message type ids
+pub const CALL: u8 = 2;
+pub const CALL_RESULT: u8 = 3;
+pub const CALL_ERROR: u8 = 4;
+
+/// Elements kept of a frame, a CallError has the most
+const MAX_ELEMENTS: usize = 5;
+/// Objects and arrays a payload may be nested in
+const MAX_DEPTH: usize = 32;
+
+/// A frame borrowed from the message, strings are returned without their quotes and with their
+/// escapes as-is, payloads as the JSON object they are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame<'a> {
+    /// `[2,"<uniqueId>","<Action>",{payload}]`
+    Call {
+        unique_id: &'a str,
+        action: &'a str,
+        payload: &'a str,
+    },
+    /// `[3,"<uniqueId>",{payload}]`
+    CallResult {
+        unique_id: &'a str,
+        payload: &'a str,
+    },
+    /// `[4,"<uniqueId>","<errorCode>","<errorDescription>",{errorDetails}]`
+    CallError {
+        unique_id: &'a str,
+        code: &'a str,
+        description: &'a str,
+        details: &'a str,
+    },
+}
+
+/// Why a message is not a frame, with the message type id and unique id when they could be read
+/// so a malformed Call can still be answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Invalid<'a> {
+    pub message_type_id: Option<u8>,
+    pub unique_id: Option<&'a str>,
+    pub reason: &'static str,
+}
+
+/// Where the tokenizer is within an element of the frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Element {
+    /// Nothing but whitespace yet
+    Start,
+    /// In a number, `true`, `false` or `null`
+    Scalar,
+    /// After a complete value, only whitespace may follow until the comma
+    Done,
+}
+
+/// The elements of a frame and how many there are, only the first `MAX_ELEMENTS` are kept
+struct Elements<'a> {
+    values: heapless::Vec<&'a str, MAX_ELEMENTS>,
+    count: usize,
+}
+
+impl<'a> Elements<'a> {
+    fn push(&mut self, value: &'a str) {
+        let _ = self.values.push(value.trim());
+        self.count += 1;
+    }
+
+    fn get(&self, index: usize) -> Option<&'a str> {
+        self.values.get(index).copied()
+    }
+}
+
+/// Split a JSON array into its elements, each a single JSON value. Strings and nesting are
+/// followed so commas and brackets within them don't split the array, the scalars are not checked
+fn elements(message: &str) -> Result<Elements<'_>, &'static str> {
+    let inner = message
+        .trim()
+        .strip_prefix('[')
+        .and_then(|message| message.strip_suffix(']'))
+        .ok_or("Not a JSON array")?;
+    let mut elements = Elements {
+        values: heapless::Vec::new(),
+        count: 0,
+    };
+    if inner.trim().is_empty() {
+        return Ok(elements);
+    }
+
+    let mut closers: heapless::Vec<u8, MAX_DEPTH> = heapless::Vec::new();
+    let mut element = Element::Start;
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, byte) in inner.bytes().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => {
+                    in_string = false;
+                    if closers.is_empty() {
+                        element = Element::Done;
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' | b'{' | b'[' if closers.is_empty() && element != Element::Start => {
+                return Err("Elements must be separated by a comma");
+            }
+            b'"' => in_string = true,
+            b'{' => closers
+                .push(b'}')
+                .map_err(|_| "Payload is nested too deep")?,
+            b'[' => closers
+                .push(b']')
+                .map_err(|_| "Payload is nested too deep")?,
+            b'}' | b']' => {
+                if closers.pop() != Some(byte) {
+                    return Err("Unbalanced brackets");
+                }
+                if closers.is_empty() {
+                    element = Element::Done;
+                }
+            }
+            _ if !closers.is_empty() => {}
+            b',' => {
+                if element == Element::Start {
+                    return Err("Empty element");
+                }
+                elements.push(&inner[start..i]);
+                start = i + 1;
+                element = Element::Start;
+            }
+            b' ' | b'\t' | b'\r' | b'\n' => {
+                if element == Element::Scalar {
+                    element = Element::Done;
+                }
+            }
+            _ => match element {
+                Element::Start => element = Element::Scalar,
+                Element::Scalar => {}
+                Element::Done => return Err("Elements must be separated by a comma"),
+            },
+        }
+    }
+    if in_string {
+        return Err("Unterminated string");
+    }
+    if !closers.is_empty() {
+        return Err("Unbalanced brackets");
+    }
+    if element == Element::Start {
+        return Err("Empty element");
+    }
+    elements.push(&inner[start..]);
+    Ok(elements)
+}
+
+/// The contents of a string element, None for any other value
+fn string(element: &str) -> Option<&str> {
+    // The tokenizer only ends a string element at its closing quote
+    element
+        .strip_prefix('"')
+        .and_then(|element| element.strip_suffix('"'))
+}
+
+/// An object element as it is, None for any other value
+fn object(element: &str) -> Option<&str> {
+    element.starts_with('{').then_some(element)
+}
+
+/// Parse a message from the central system into a frame
+pub fn parse(message: &str) -> Result<Frame<'_>, Invalid<'_>> {
+    let elements = elements(message).map_err(|reason| Invalid {
+        message_type_id: None,
+        unique_id: None,
+        reason,
+    })?;
+    let message_type_id = elements.get(0).and_then(|id| id.parse::<u8>().ok());
+    let unique_id = elements.get(1).and_then(string).filter(|id| !id.is_empty());
+    let invalid = |reason| Invalid {
+        message_type_id,
+        unique_id,
+        reason,
+    };
+
+    let message_type_id = message_type_id.ok_or(invalid("Message type id must be a number"))?;
+    if !matches!(message_type_id, CALL | CALL_RESULT | CALL_ERROR) {
+        return Err(invalid("Unknown message type id"));
+    }
+    let unique_id = unique_id.ok_or(invalid("Unique id must be a non-empty string"))?;
+    match message_type_id {
+        CALL => {
+            if elements.count != 4 {
+                return Err(invalid(
+                    "Call must contain a unique id, an action and a payload",
+                ));
+            }
+            Ok(Frame::Call {
+                unique_id,
+                action: elements
+                    .get(2)
+                    .and_then(string)
+                    .filter(|action| !action.is_empty())
+                    .ok_or(invalid("Action must be a non-empty string"))?,
+                payload: elements
+                    .get(3)
+                    .and_then(object)
+                    .ok_or(invalid("Payload must be a JSON object"))?,
+            })
+        }
+        CALL_RESULT => {
+            if elements.count != 3 {
+                return Err(invalid("CallResult must contain a unique id and a payload"));
+            }
+            Ok(Frame::CallResult {
+                unique_id,
+                payload: elements
+                    .get(2)
+                    .and_then(object)
+                    .ok_or(invalid("Payload must be a JSON object"))?,
+            })
+        }
+        _ => {
+            if elements.count != 5 {
+                return Err(invalid(
+                    "CallError must contain a unique id, an error code, a description and details",
+                ));
+            }
+            Ok(Frame::CallError {
+                unique_id,
+                code: elements
+                    .get(2)
+                    .and_then(string)
+                    .ok_or(invalid("Error code must be a string"))?,
+                description: elements
+                    .get(3)
+                    .and_then(string)
+                    .ok_or(invalid("Error description must be a string"))?,
+                details: elements
+                    .get(4)
+                    .and_then(object)
+                    .ok_or(invalid("Error details must be a JSON object"))?,
+            })
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use core::{fmt::Write, str};
+use embassy_net::tcp::TcpSocket;
+
+use crate::{
+    http::{self, Scheme, Url},
+    info,
+    network::NetworkStack,
+    warn,
+};
+
+const SOCKET_BUFFER_SIZE: usize = 1024;
+
+/// Read a (possibly multi-line) FTP reply and return its 3 digit code
+async fn read_reply(socket: &mut TcpSocket<'_>) -> Result<u16, &'static str> {
+    let mut reply = [0u8; 256];
+    let mut len = 0;
+
+    loop {
+        if len == reply.len() {
+            // Keep the tail, the final line of a long multi-line reply is what matters
+            reply.copy_within(len / 2.., 0);
+            len -= len / 2;
+        }
+        match socket.read(&mut reply[len..]).await {
+            Ok(0) => return Err("Connection closed by FTP server"),
+            Ok(read) => len += read,
+            Err(_) => return Err("Failed to read FTP reply"),
+        }
+
+        let text = str::from_utf8(&reply[..len]).map_err(|_| "Invalid FTP reply")?;
+        // A reply is complete when its last line is "<code> <text>"
+        if let Some(last_line) = text
+            .strip_suffix("\r\n")
+            .and_then(|t| t.rsplit("\r\n").next())
+        {
+            let bytes = last_line.as_bytes();
+            if bytes.len() >= 4 && bytes[..3].iter().all(u8::is_ascii_digit) && bytes[3] == b' ' {
+                return last_line[..3].parse().map_err(|_| "Invalid FTP reply code");
+            }
+        }
+    }
+}
+
+async fn command(
+    socket: &mut TcpSocket<'_>,
+    cmd: &str,
+    arg: Option<&str>,
+) -> Result<u16, &'static str> {
+    let mut line = heapless::String::<128>::new();
+    match arg {
+        Some(arg) => write!(line, "{cmd} {arg}\r\n"),
+        None => write!(line, "{cmd}\r\n"),
+    }
+    .map_err(|_| "FTP command too long")?;
+    http::write_all(socket, line.as_bytes()).await?;
+    read_reply(socket).await
+}
+
+fn expect(code: u16, expected: u16, error: &'static str) -> Result<(), &'static str> {
+    if code / 100 == expected / 100 {
+        Ok(())
+    } else {
+        warn!("FTP : Unexpected reply {code} (expected {expected})");
+        Err(error)
+    }
+}
+
+/// Parse the data port from a PASV reply: `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`
+fn parse_pasv_port(reply: &str) -> Option<u16> {
+    let start = reply.find('(')? + 1;
+    let end = start + reply[start..].find(')')?;
+    let mut numbers = reply[start..end].split(',').map(|n| n.trim().parse::<u8>());
+    let port_hi = numbers.nth(4)?.ok()?;
+    let port_lo = numbers.next()?.ok()?;
+    Some(u16::from(port_hi) << 8 | u16::from(port_lo))
+}
+
+/// Enter passive mode and return the data port announced by the server
+async fn passive(socket: &mut TcpSocket<'_>) -> Result<u16, &'static str> {
+    http::write_all(socket, b"PASV\r\n").await?;
+
+    let mut reply = [0u8; 128];
+    let mut len = 0;
+    while !reply[..len].ends_with(b"\r\n") {
+        if len == reply.len() {
+            return Err("PASV reply too long");
+        }
+        match socket.read(&mut reply[len..]).await {
+            Ok(0) | Err(_) => return Err("Failed to read PASV reply"),
+            Ok(read) => len += read,
+        }
+    }
+
+    let reply = str::from_utf8(&reply[..len]).map_err(|_| "Invalid PASV reply")?;
+    if !reply.starts_with("227") {
+        warn!("FTP : Passive mode refused: {reply}");
+        return Err("Passive mode refused");
+    }
+    parse_pasv_port(reply).ok_or("Invalid PASV reply")
+}
+
+/// Upload a file with FTP in passive binary mode
+/// The data connection is made to the control host, the address in the PASV reply is ignored
+pub async fn store(network: &NetworkStack, url: &Url<'_>, body: &[u8]) -> Result<(), &'static str> {
+    if url.scheme != Scheme::Ftp {
+        return Err("Not an FTP URL");
+    }
+
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut control =
+        http::connect(network, url.host, url.port, &mut rx_buffer, &mut tx_buffer).await?;
+
+    let result = async {
+        expect(read_reply(&mut control).await?, 220, "FTP server not ready")?;
+
+        let code = command(&mut control, "USER", Some(url.user.unwrap_or("anonymous"))).await?;
+        if code == 331 {
+            let code = command(&mut control, "PASS", Some(url.password.unwrap_or(""))).await?;
+            expect(code, 230, "FTP login failed")?;
+        } else {
+            expect(code, 230, "FTP login failed")?;
+        }
+
+        expect(
+            command(&mut control, "TYPE", Some("I")).await?,
+            200,
+            "FTP binary mode refused",
+        )?;
+        let data_port = passive(&mut control).await?;
+
+        let mut data_rx_buffer = [0u8; 64];
+        let mut data_tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+        let mut data = http::connect(
+            network,
+            url.host,
+            data_port,
+            &mut data_rx_buffer,
+            &mut data_tx_buffer,
+        )
+        .await?;
+
+        let path = url.path.strip_prefix('/').unwrap_or(url.path);
+        expect(
+            command(&mut control, "STOR", Some(path)).await?,
+            150,
+            "FTP upload refused",
+        )?;
+
+        info!("FTP : Storing {} bytes as {path}", body.len());
+        let written = http::write_all(&mut data, body).await;
+        let _ = data.flush().await;
+        data.close();
+        written?;
+
+        expect(read_reply(&mut control).await?, 226, "FTP upload failed")?;
+        let _ = command(&mut control, "QUIT", None).await;
+        Ok::<(), &'static str>(())
+    }
+    .await;
+
+    control.close();
+
+    if let Err(e) = result {
+        warn!("FTP : Upload failed: {e}");
+    }
+    result
+}
@@ -0,0 +1,255 @@
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::Config,
+    info, ntp,
+    storage::{self, Slot},
+    utils, warn,
+};
+
+/// Most guest codes kept, the one that expires first makes room for a new one
+pub const MAX_CODES: usize = 32;
+/// Truncated SHA-256 of the serial and the code, the codes themselves aren't stored
+const HASH_SIZE: usize = 16;
+/// Size of a serialized guest code, all codes share one storage slot
+const ENTRY_SIZE: usize = HASH_SIZE + 8;
+/// Wrong codes in a row before codes are refused for a while, against guessing
+const MAX_FAILED_ATTEMPTS: u8 = 5;
+const LOCKOUT_SECS: u64 = 60;
+/// Time a session has to be authorized after its code was entered
+const PENDING_SECS: u64 = 30;
+
+/// A guest code, valid from and until a Unix time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GuestCode {
+    hash: [u8; HASH_SIZE],
+    valid_from: u32,
+    valid_until: u32,
+}
+
+impl GuestCode {
+    /// Serialized as the hash followed by the validity window (little endian)
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[..HASH_SIZE].copy_from_slice(&self.hash);
+        bytes[HASH_SIZE..HASH_SIZE + 4].copy_from_slice(&self.valid_from.to_le_bytes());
+        bytes[HASH_SIZE + 4..].copy_from_slice(&self.valid_until.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self {
+            hash: bytes.get(..HASH_SIZE)?.try_into().ok()?,
+            valid_from: u32::from_le_bytes(bytes.get(HASH_SIZE..HASH_SIZE + 4)?.try_into().ok()?),
+            valid_until: u32::from_le_bytes(bytes.get(HASH_SIZE + 4..ENTRY_SIZE)?.try_into().ok()?),
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        now > u64::from(self.valid_until)
+    }
+}
+
+struct GuestCodes {
+    codes: heapless::Vec<GuestCode, MAX_CODES>,
+    failed_attempts: u8,
+    refused_until: Option<Instant>,
+    /// Id tag of the code that was entered last, until its session is authorized
+    pending: Option<(heapless::String<20>, Instant)>,
+}
+
+static GUEST_CODES: Mutex<CriticalSectionRawMutex, RefCell<GuestCodes>> =
+    Mutex::new(RefCell::new(GuestCodes {
+        codes: heapless::Vec::new(),
+        failed_attempts: 0,
+        refused_until: None,
+        pending: None,
+    }));
+/// RAM of the codes, counted against the storage budget
+pub const RAM: usize = size_of_val(&GUEST_CODES);
+
+/// A guest code is 4 to 8 digits
+fn validate(code: &str) -> Result<(), &'static str> {
+    if !(4..=8).contains(&code.len()) || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Err("A guest code is 4 to 8 digits");
+    }
+    Ok(())
+}
+
+/// Hash of a code, salted with the serial so equal codes of two chargers differ
+fn hash(code: &str) -> [u8; HASH_SIZE] {
+    let digest = Sha256::new()
+        .chain_update(Config::from_config().charger_serial)
+        .chain_update(b":")
+        .chain_update(code)
+        .finalize();
+    let mut hash = [0u8; HASH_SIZE];
+    hash.copy_from_slice(&digest[..HASH_SIZE]);
+    hash
+}
+
+/// Id tag of the sessions of a guest code, from its hash so the code doesn't show up in the
+/// transactions, e.g. `GUEST-1a2b3c4d`
+fn id_tag(hash: &[u8; HASH_SIZE]) -> heapless::String<20> {
+    let mut id_tag = heapless::String::new();
+    let _ = id_tag.push_str("GUEST-");
+    let _ = id_tag.push_str(&utils::bytes_to_hex_string::<8>(&hash[..4]));
+    id_tag
+}
+
+fn store(codes: &[GuestCode]) {
+    let mut bytes = [0u8; MAX_CODES * ENTRY_SIZE];
+    for (i, code) in codes.iter().enumerate() {
+        bytes[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE].copy_from_slice(&code.to_bytes());
+    }
+    let result = if codes.is_empty() {
+        storage::erase(Slot::GuestCodes)
+    } else {
+        storage::write(Slot::GuestCodes, &bytes[..codes.len() * ENTRY_SIZE])
+    };
+    if let Err(e) = result {
+        warn!("GST : Failed to store the guest codes: {e}");
+    }
+}
+
+/// Load the guest codes from flash
+pub fn load() {
+    let mut bytes = [0u8; MAX_CODES * ENTRY_SIZE];
+    let codes = match storage::read(Slot::GuestCodes, &mut bytes) {
+        Ok(Some(len)) => bytes[..len]
+            .chunks_exact(ENTRY_SIZE)
+            .filter_map(GuestCode::from_bytes)
+            .collect(),
+        Ok(None) => heapless::Vec::new(),
+        Err(e) => {
+            warn!("GST : Failed to load the guest codes: {e}");
+            return;
+        }
+    };
+    if !codes.is_empty() {
+        info!("GST : Loaded {} guest codes", codes.len());
+    }
+    GUEST_CODES.lock(|guest| guest.borrow_mut().codes = codes);
+}
+
+/// Add a guest code valid from and until a Unix time, replaces the window of a code that was
+/// added before
+pub fn add(code: &str, valid_from: u32, valid_until: u32) -> Result<(), &'static str> {
+    validate(code)?;
+    if valid_until <= valid_from {
+        return Err("A guest code must be valid until after it's valid from");
+    }
+    let now = ntp::get_current_unix_time();
+    if now != 0 && now > u64::from(valid_until) {
+        return Err("The guest code already expired");
+    }
+
+    let hash = hash(code);
+    GUEST_CODES.lock(|guest| {
+        let mut guest = guest.borrow_mut();
+        let codes = &mut guest.codes;
+        codes.retain(|code| code.hash != hash && (now == 0 || !code.is_expired(now)));
+        if codes.is_full() {
+            if let Some(first) = (0..codes.len()).min_by_key(|i| codes[*i].valid_until) {
+                codes.swap_remove(first);
+            }
+        }
+        let _ = codes.push(GuestCode {
+            hash,
+            valid_from,
+            valid_until,
+        });
+        store(codes);
+        info!(
+            "GST : Added guest code {} valid from {valid_from} until {valid_until}",
+            id_tag(&hash)
+        );
+    });
+    Ok(())
+}
+
+/// Remove a guest code
+pub fn remove(code: &str) -> Result<(), &'static str> {
+    validate(code)?;
+    let hash = hash(code);
+    GUEST_CODES.lock(|guest| {
+        let mut guest = guest.borrow_mut();
+        let codes = &mut guest.codes;
+        let position = codes
+            .iter()
+            .position(|code| code.hash == hash)
+            .ok_or("Unknown guest code")?;
+        codes.swap_remove(position);
+        store(codes);
+        info!("GST : Removed guest code {}", id_tag(&hash));
+        Ok(())
+    })
+}
+
+/// Remove all guest codes
+pub fn clear() {
+    GUEST_CODES.lock(|guest| {
+        guest.borrow_mut().codes.clear();
+        store(&[]);
+    });
+    info!("GST : Removed all guest codes");
+}
+
+/// Check a code entered by a guest, returns the id tag to start the session with. It's
+/// accepted by the guest source of the authorization chain for a short while
+pub fn enter(code: &str) -> Result<heapless::String<20>, &'static str> {
+    validate(code)?;
+    let now = ntp::get_current_unix_time();
+    let hash = hash(code);
+    GUEST_CODES.lock(|guest| {
+        let mut guest = guest.borrow_mut();
+        if guest
+            .refused_until
+            .is_some_and(|until| Instant::now() < until)
+        {
+            return Err("Too many wrong guest codes, try again later");
+        }
+        // The validity window can't be checked without the time
+        if now == 0 {
+            return Err("Clock not synced, guest codes can't be checked");
+        }
+
+        let Some(code) = guest.codes.iter().find(|code| code.hash == hash).copied() else {
+            guest.failed_attempts += 1;
+            if guest.failed_attempts >= MAX_FAILED_ATTEMPTS {
+                warn!("GST : {MAX_FAILED_ATTEMPTS} wrong guest codes, refusing codes for {LOCKOUT_SECS}s");
+                guest.failed_attempts = 0;
+                guest.refused_until = Some(Instant::now() + Duration::from_secs(LOCKOUT_SECS));
+            }
+            return Err("Unknown guest code");
+        };
+        guest.failed_attempts = 0;
+        if now < u64::from(code.valid_from) {
+            return Err("Guest code not valid yet");
+        }
+        if code.is_expired(now) {
+            return Err("Guest code expired");
+        }
+
+        let id_tag = id_tag(&hash);
+        guest.pending = Some((id_tag.clone(), Instant::now()));
+        Ok(id_tag)
+    })
+}
+
+/// Whether an id tag is of a guest code that was just entered, it's accepted once
+pub fn accepts(id_tag: &str) -> bool {
+    GUEST_CODES.lock(|guest| {
+        let mut guest = guest.borrow_mut();
+        let accepted = guest.pending.as_ref().is_some_and(|(pending, entered)| {
+            pending == id_tag && entered.elapsed() < Duration::from_secs(PENDING_SECS)
+        });
+        if accepted {
+            guest.pending = None;
+        }
+        accepted
+    })
+}
@@ -0,0 +1,330 @@
+use core::{fmt::Write, str};
+use embassy_net::tcp::TcpSocket;
+use embassy_time::Duration;
+
+use crate::network::NetworkStack;
+use crate::{info, warn};
+
+const SOCKET_BUFFER_SIZE: usize = 1024;
+const SOCKET_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Https,
+    Ftp,
+}
+
+impl Scheme {
+    fn default_port(&self) -> u16 {
+        match self {
+            Self::Http => 80,
+            Self::Https => 443,
+            Self::Ftp => 21,
+        }
+    }
+}
+
+/// Parsed `scheme://[user[:password]@]host[:port][/path]` URL
+#[derive(Debug, Clone, Copy)]
+pub struct Url<'a> {
+    pub scheme: Scheme,
+    pub user: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    pub fn parse(url: &'a str) -> Result<Self, &'static str> {
+        let (scheme, rest) = url.split_once("://").ok_or("URL without scheme")?;
+        let scheme = match scheme {
+            "http" => Scheme::Http,
+            "https" => Scheme::Https,
+            "ftp" => Scheme::Ftp,
+            _ => return Err("Unsupported URL scheme"),
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+
+        let (credentials, host_port) = match authority.rsplit_once('@') {
+            Some((credentials, host_port)) => (Some(credentials), host_port),
+            None => (None, authority),
+        };
+        let (user, password) = match credentials {
+            Some(credentials) => match credentials.split_once(':') {
+                Some((user, password)) => (Some(user), Some(password)),
+                None => (Some(credentials), None),
+            },
+            None => (None, None),
+        };
+
+        let (host, port) = match host_port.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "Invalid URL port")?),
+            None => (host_port, scheme.default_port()),
+        };
+
+        if host.is_empty() {
+            return Err("URL without host");
+        }
+
+        Ok(Self {
+            scheme,
+            user,
+            password,
+            host,
+            port,
+            path,
+        })
+    }
+}
+
+/// Open a TCP connection to a host, resolving its name with DNS
+pub async fn connect<'a>(
+    network: &NetworkStack,
+    host: &str,
+    port: u16,
+    rx_buffer: &'a mut [u8],
+    tx_buffer: &'a mut [u8],
+) -> Result<TcpSocket<'a>, &'static str> {
+    let address = network
+        .resolve_dns(host)
+        .await
+        .ok_or("Failed to resolve host")?;
+
+    let mut socket = TcpSocket::new(*network.stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT_SECS)));
+
+    match embassy_time::with_timeout(
+        Duration::from_secs(SOCKET_TIMEOUT_SECS),
+        socket.connect((address, port)),
+    )
+    .await
+    {
+        Ok(Ok(())) => Ok(socket),
+        Ok(Err(_)) => Err("Connection refused"),
+        Err(_) => Err("Connection timeout"),
+    }
+}
+
+/// Write all bytes to a socket
+pub async fn write_all(socket: &mut TcpSocket<'_>, mut data: &[u8]) -> Result<(), &'static str> {
+    while !data.is_empty() {
+        match socket.write(data).await {
+            Ok(0) | Err(_) => return Err("Failed to write to socket"),
+            Ok(written) => data = &data[written..],
+        }
+    }
+    Ok(())
+}
+
+/// Read the status code from the response status line, e.g. `HTTP/1.1 201 Created`
+async fn read_status_code(socket: &mut TcpSocket<'_>) -> Result<u16, &'static str> {
+    let mut response = [0u8; 64];
+    let mut len = 0;
+    while len < response.len() {
+        match socket.read(&mut response[len..]).await {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(_) => return Err("Failed to read response"),
+        }
+        if response[..len].contains(&b'\n') {
+            break;
+        }
+    }
+
+    let status_line = str::from_utf8(&response[..len]).map_err(|_| "Invalid response")?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or("Invalid response status line")
+}
+
+/// Upload a body with an HTTP PUT request, returns the HTTP status code
+pub async fn put(
+    network: &NetworkStack,
+    url: &Url<'_>,
+    content_type: &str,
+    body: &[u8],
+) -> Result<u16, &'static str> {
+    send("PUT", network, url, content_type, body).await
+}
+
+/// Send a body with an HTTP POST request, returns the HTTP status code
+pub async fn post(
+    network: &NetworkStack,
+    url: &Url<'_>,
+    content_type: &str,
+    body: &[u8],
+) -> Result<u16, &'static str> {
+    send("POST", network, url, content_type, body).await
+}
+
+async fn send(
+    method: &str,
+    network: &NetworkStack,
+    url: &Url<'_>,
+    content_type: &str,
+    body: &[u8],
+) -> Result<u16, &'static str> {
+    if url.scheme != Scheme::Http {
+        return Err("Only plain HTTP is supported");
+    }
+
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut socket = connect(network, url.host, url.port, &mut rx_buffer, &mut tx_buffer).await?;
+
+    let mut header = heapless::String::<512>::new();
+    write!(
+        header,
+        "{method} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.host,
+        body.len()
+    )
+    .map_err(|_| "Request header too large")?;
+
+    info!(
+        "HTTP: {method} {} bytes to {}{}",
+        body.len(),
+        url.host,
+        url.path
+    );
+
+    let result = async {
+        write_all(&mut socket, header.as_bytes()).await?;
+        write_all(&mut socket, body).await?;
+        socket.flush().await.map_err(|_| "Failed to flush socket")?;
+        read_status_code(&mut socket).await
+    }
+    .await;
+
+    socket.close();
+
+    match result {
+        Ok(status) => {
+            info!("HTTP: {method} response status {status}");
+            Ok(status)
+        }
+        Err(e) => {
+            warn!("HTTP: {method} failed: {e}");
+            Err(e)
+        }
+    }
+}
+
+/// Read the response header, returns the status code, the content length if given and the
+/// number of body bytes that were read along with the header
+async fn read_header(
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut [u8],
+) -> Result<(u16, Option<usize>, usize, usize), &'static str> {
+    let mut len = 0;
+    let header_len = loop {
+        if len == buffer.len() {
+            return Err("Response header too large");
+        }
+        match socket.read(&mut buffer[len..]).await {
+            Ok(0) => return Err("Connection closed before end of header"),
+            Ok(read) => len += read,
+            Err(_) => return Err("Failed to read response"),
+        }
+        if let Some(pos) = buffer[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let header = str::from_utf8(&buffer[..header_len]).map_err(|_| "Invalid response header")?;
+    let mut lines = header.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or("Invalid response status line")?;
+
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok());
+
+    Ok((status, content_length, header_len, len - header_len))
+}
+
+/// Download a resource with an HTTP GET request, the body is passed to `sink` in chunks
+/// together with the content length, if the server sent one
+/// Returns the number of body bytes received, responses other than 200 are an error
+pub async fn get(
+    network: &NetworkStack,
+    url: &Url<'_>,
+    mut sink: impl FnMut(&[u8], Option<usize>) -> Result<(), &'static str>,
+) -> Result<usize, &'static str> {
+    if url.scheme != Scheme::Http {
+        return Err("Only plain HTTP is supported");
+    }
+
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut socket = connect(network, url.host, url.port, &mut rx_buffer, &mut tx_buffer).await?;
+
+    // HTTP/1.0 so the server doesn't use a chunked transfer encoding
+    let mut request = heapless::String::<512>::new();
+    write!(
+        request,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    )
+    .map_err(|_| "Request header too large")?;
+
+    info!("HTTP: GET {}{}", url.host, url.path);
+
+    let result = async {
+        write_all(&mut socket, request.as_bytes()).await?;
+        socket.flush().await.map_err(|_| "Failed to flush socket")?;
+
+        let mut buffer = [0u8; SOCKET_BUFFER_SIZE];
+        let (status, content_length, header_len, body_len) =
+            read_header(&mut socket, &mut buffer).await?;
+        if status != 200 {
+            warn!("HTTP: GET response status {status}");
+            return Err("Download rejected by server");
+        }
+
+        let mut received = body_len;
+        sink(&buffer[header_len..header_len + body_len], content_length)?;
+        while content_length.is_none_or(|length| received < length) {
+            match socket.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(read) => {
+                    received += read;
+                    sink(&buffer[..read], content_length)?;
+                }
+                Err(_) => return Err("Failed to read response body"),
+            }
+        }
+
+        match content_length {
+            Some(length) if received != length => Err("Incomplete response body"),
+            _ => Ok(received),
+        }
+    }
+    .await;
+
+    socket.close();
+
+    match result {
+        Ok(received) => {
+            info!("HTTP: GET received {received} bytes");
+            Ok(received)
+        }
+        Err(e) => {
+            warn!("HTTP: GET failed: {e}");
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,395 @@
+extern crate alloc;
+use alloc::string::String;
+use core::{fmt::Write, str};
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{Duration, Instant, Timer};
+use log::Level;
+
+use crate::{
+    branding::{self, LogoDecoder, LogoFormat},
+    charger::Charger,
+    config::Config,
+    http::write_all,
+    info, logging,
+    network::NetworkStack,
+    sessions::{self, SessionRecord},
+    telemetry, utils, warn, wifi_monitor, wire,
+};
+
+const SOCKET_BUFFER_SIZE: usize = 1024;
+const SOCKET_TIMEOUT_SECS: u64 = 10;
+const SESSIONS_PATH: &str = "/sessions.csv";
+const LOGO_PATH: &str = "/logo";
+const STATUS_PATH: &str = "/status";
+const DASHBOARD_PATH: &str = "/";
+const LOGS_PATH: &str = "/logs";
+/// Largest logo upload, the C source of an XBM image is about 6kB
+const MAX_LOGO_UPLOAD: usize = 16 * 1024;
+
+/// Dashboard showing the status, refreshed every 5 seconds
+const DASHBOARD_HTML: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width">
+<title>Charger</title>
+<style>body{font-family:sans-serif;margin:2em}td{padding:.3em 1em .3em 0}</style>
+</head><body><h1>Charger</h1><table id="status"></table>
+<p><a href="/sessions.csv">Session history</a> | <a href="/logs">Log</a></p>
+<script>
+const rows = [["serial","Serial"],["state","State"],["transaction_id","Transaction"],
+  ["ip","IP address"],["rssi","Signal (dBm)"],["uptime","Uptime (s)"],
+  ["heap_free","Free heap (bytes)"],["heap_high_water","Heap high water (bytes)"]];
+async function refresh() {
+  const status = await (await fetch("/status")).json();
+  document.getElementById("status").innerHTML = rows
+    .map(([key, label]) => `<tr><td>${label}</td><td>${status[key] ?? "-"}</td></tr>`).join("");
+}
+refresh();
+setInterval(refresh, 5000);
+</script></body></html>
+"#;
+
+/// Head of a request, with the part of the body that was read along with it
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    credentials: Option<&'a str>, // Basic credentials
+    content_type: Option<&'a str>,
+    content_length: Option<usize>,
+    body: &'a [u8],
+}
+
+/// Read the request head
+async fn read_request<'a>(
+    socket: &mut TcpSocket<'_>,
+    buffer: &'a mut [u8],
+) -> Result<Request<'a>, &'static str> {
+    let mut len = 0;
+    let header_len = loop {
+        if len == buffer.len() {
+            return Err("Request header too large");
+        }
+        match socket.read(&mut buffer[len..]).await {
+            Ok(0) => return Err("Connection closed before end of header"),
+            Ok(read) => len += read,
+            Err(_) => return Err("Failed to read request"),
+        }
+        if let Some(pos) = buffer[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let buffer: &'a [u8] = buffer;
+    let (header, body) = buffer[..len].split_at(header_len);
+    let header = str::from_utf8(header).map_err(|_| "Invalid request header")?;
+    let mut lines = header.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err("Invalid request line");
+    };
+
+    let field = |field: &str| {
+        header
+            .split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(field))
+            .map(|(_, value)| value.trim())
+    };
+    Ok(Request {
+        method,
+        path,
+        credentials: field("authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .map(str::trim),
+        content_type: field("content-type"),
+        content_length: field("content-length").and_then(|value| value.parse().ok()),
+        body,
+    })
+}
+
+/// Whether the Basic credentials match the configured user and password
+fn is_authorized(config: &Config, credentials: Option<&str>) -> bool {
+    let mut expected = heapless::String::<128>::new();
+    if write!(
+        expected,
+        "{}:{}",
+        config.http_username, config.http_password
+    )
+    .is_err()
+    {
+        return false;
+    }
+    credentials.is_some_and(|credentials| {
+        credentials == utils::base64_encode::<172>(expected.as_bytes()).as_str()
+    })
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    extra_header: &str,
+) -> Result<(), &'static str> {
+    let mut response = heapless::String::<256>::new();
+    write!(
+        response,
+        "HTTP/1.1 {status}\r\n{extra_header}Content-Length: 0\r\nConnection: close\r\n\r\n"
+    )
+    .map_err(|_| "Response header too large")?;
+    write_all(socket, response.as_bytes()).await
+}
+
+/// Respond with a complete body
+async fn respond_with_body(
+    socket: &mut TcpSocket<'_>,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), &'static str> {
+    let mut header = heapless::String::<128>::new();
+    write!(
+        header,
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .map_err(|_| "Response header too large")?;
+    write_all(socket, header.as_bytes()).await?;
+    write_all(socket, body).await
+}
+
+/// Status of the charger as JSON, for installers on the LAN
+async fn status_json(network: &NetworkStack, charger: &Charger) -> heapless::String<384> {
+    let mut json = heapless::String::new();
+    let _ = write!(
+        json,
+        "{{\"serial\":\"{}\",",
+        network.app_config.charger_serial
+    );
+    let _ = wire::write_json(&mut json, "state", charger.get_state().await);
+    let _ = write!(json, ",\"transaction_id\":");
+    let _ = match charger.get_transaction_id().await {
+        0 => write!(json, "null"),
+        id => write!(json, "{id}"),
+    };
+    let _ = match network.get_ip_address() {
+        Some(ip) => write!(json, ",\"ip\":\"{ip}\""),
+        None => write!(json, ",\"ip\":null"),
+    };
+    let _ = match wifi_monitor::rssi() {
+        Some(rssi) => write!(json, ",\"rssi\":{rssi}"),
+        None => write!(json, ",\"rssi\":null"),
+    };
+    telemetry::record_heap_usage();
+    let _ = write!(
+        json,
+        ",\"uptime\":{},\"heap_free\":{},\"heap_high_water\":{}}}",
+        Instant::now().as_secs(),
+        esp_alloc::HEAP.free(),
+        telemetry::heap_high_water()
+    );
+    json
+}
+
+/// Write one chunk of a chunked transfer encoding
+async fn write_chunk(socket: &mut TcpSocket<'_>, data: &[u8]) -> Result<(), &'static str> {
+    let mut size = heapless::String::<12>::new();
+    let _ = write!(size, "{:x}\r\n", data.len());
+    write_all(socket, size.as_bytes()).await?;
+    write_all(socket, data).await?;
+    write_all(socket, b"\r\n").await
+}
+
+/// Stream the session history as CSV, one chunk per session
+async fn send_sessions(
+    socket: &mut TcpSocket<'_>,
+    sessions: &[SessionRecord],
+) -> Result<(), &'static str> {
+    write_all(
+        socket,
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\n\
+          Content-Disposition: attachment; filename=\"sessions.csv\"\r\n\
+          Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+    write_chunk(socket, sessions::CSV_HEADER.as_bytes()).await?;
+    for session in sessions {
+        write_chunk(socket, session.csv_row().as_bytes()).await?;
+    }
+    write_all(socket, b"0\r\n\r\n").await
+}
+
+/// Send the log ring buffer as text, oldest first. `?level=warn` leaves out the less severe lines
+async fn send_logs(socket: &mut TcpSocket<'_>, query: &str) -> Result<(), &'static str> {
+    let level = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("level="))
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(Level::Trace);
+    let entries = match logging::entries(level) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("HTTP: {e}");
+            return respond(socket, "503 Service Unavailable", "").await;
+        }
+    };
+    // A line is at most the text of an entry with its uptime and level
+    let mut text = String::new();
+    if text.try_reserve(entries.len() * 128).is_err() {
+        warn!("HTTP: Out of memory for the log");
+        return respond(socket, "503 Service Unavailable", "").await;
+    }
+    for entry in &entries {
+        let _ = entry.write_line(&mut text);
+    }
+    respond_with_body(socket, "text/plain; charset=utf-8", text.as_bytes()).await
+}
+
+/// Receive an uploaded logo, the C source of an XBM image or the raw bitmap
+async fn receive_logo(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+) -> Result<branding::Logo, &'static str> {
+    let length = request
+        .content_length
+        .ok_or("Logo upload without Content-Length")?;
+    if length > MAX_LOGO_UPLOAD {
+        return Err("Logo upload too large");
+    }
+    let format = match request.content_type {
+        Some(content_type) if content_type.starts_with("image/x-xbitmap") => LogoFormat::Xbm,
+        Some(content_type) if content_type.starts_with("image/x-xbm") => LogoFormat::Xbm,
+        _ => LogoFormat::Raw,
+    };
+
+    let mut decoder = LogoDecoder::new(format);
+    let body = request.body.get(..length).unwrap_or(request.body);
+    decoder.feed(body)?;
+    let mut received = body.len();
+    let mut chunk = [0u8; 256];
+    while received < length {
+        let max = chunk.len().min(length - received);
+        match socket.read(&mut chunk[..max]).await {
+            Ok(0) => return Err("Connection closed during logo upload"),
+            Ok(read) => {
+                decoder.feed(&chunk[..read])?;
+                received += read;
+            }
+            Err(_) => return Err("Failed to read logo upload"),
+        }
+    }
+    decoder.finish()
+}
+
+/// Replace or remove the logo shown at boot
+async fn handle_logo(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+) -> Result<(), &'static str> {
+    let result = if request.method == "DELETE" {
+        branding::reset()
+    } else {
+        match receive_logo(socket, request).await {
+            Ok(logo) => branding::store(&logo),
+            Err(e) => {
+                warn!("HTTP: Rejected logo: {e}");
+                return respond(socket, "400 Bad Request", "").await;
+            }
+        }
+    };
+    match result {
+        Ok(()) => respond(socket, "204 No Content", "").await,
+        Err(e) => {
+            warn!("HTTP: Failed to update the logo: {e}");
+            respond(socket, "500 Internal Server Error", "").await
+        }
+    }
+}
+
+async fn handle_connection(
+    socket: &mut TcpSocket<'_>,
+    network: &NetworkStack,
+    charger: &Charger,
+) -> Result<(), &'static str> {
+    let config = &network.app_config;
+    let mut buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let request = read_request(socket, &mut buffer).await?;
+    let (method, path) = (request.method, request.path);
+    info!("HTTP: {method} {path}");
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+    let allowed = match path {
+        SESSIONS_PATH | STATUS_PATH | DASHBOARD_PATH | LOGS_PATH => "GET",
+        LOGO_PATH => "PUT, DELETE",
+        _ => return respond(socket, "404 Not Found", "").await,
+    };
+    if !allowed.split(", ").any(|allowed| allowed == method) {
+        let mut allow = heapless::String::<32>::new();
+        let _ = write!(allow, "Allow: {allowed}\r\n");
+        return respond(socket, "405 Method Not Allowed", &allow).await;
+    }
+    if !is_authorized(config, request.credentials) {
+        warn!("HTTP: Unauthorized request for {path}");
+        return respond(
+            socket,
+            "401 Unauthorized",
+            "WWW-Authenticate: Basic realm=\"charger\"\r\n",
+        )
+        .await;
+    }
+
+    match path {
+        LOGO_PATH => return handle_logo(socket, &request).await,
+        STATUS_PATH => {
+            let json = status_json(network, charger).await;
+            return respond_with_body(socket, "application/json", json.as_bytes()).await;
+        }
+        DASHBOARD_PATH => {
+            return respond_with_body(socket, "text/html", DASHBOARD_HTML.as_bytes()).await;
+        }
+        LOGS_PATH => return send_logs(socket, query).await,
+        _ => {}
+    }
+
+    match sessions::load() {
+        Ok(sessions) => {
+            info!("HTTP: Sending {} sessions", sessions.len());
+            send_sessions(socket, &sessions).await
+        }
+        Err(e) => {
+            warn!("HTTP: Failed to load sessions: {e}");
+            respond(socket, "500 Internal Server Error", "").await
+        }
+    }
+}
+
+/// Task serving the session history as CSV on `/sessions.csv`, the logo upload on `/logo`, the
+/// status as JSON on `/status`, the log on `/logs` and a dashboard on `/`, protected with Basic
+/// auth
+/// The server is disabled when no password is configured
+#[embassy_executor::task]
+pub async fn http_server_task(network: &'static NetworkStack, charger: &'static Charger) {
+    let config = &network.app_config;
+    if config.http_password.is_empty() {
+        info!("TASK: HTTP Server disabled, no password configured");
+        return;
+    }
+    info!("TASK: Started HTTP Server on port {}", config.http_port);
+
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(*network.stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT_SECS)));
+
+        if socket.accept(config.http_port).await.is_err() {
+            warn!("HTTP: Failed to accept connection");
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        if let Err(e) = handle_connection(&mut socket, network, charger).await {
+            warn!("HTTP: {e}");
+        }
+        let _ = socket.flush().await;
+        socket.close();
+    }
+}
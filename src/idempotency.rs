@@ -0,0 +1,381 @@
+use chrono::DateTime;
+use core::{
+    cell::RefCell,
+    fmt::Write,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use ocpp_rs::v16::data_types::DateTimeWrapper;
+
+use crate::{
+    charger::StopReason,
+    config::Config,
+    data_transfer, info,
+    mqtt::Priority,
+    ntp, ocpp,
+    ocpp_config::{self, ConfigKey},
+    ready::{self, Subsystem},
+    warn,
+};
+
+/// Interval the open StartTransaction is checked for a response at
+const CHECK_SECS: u64 = 5;
+/// Attempts of a StartTransaction whose unique ids are kept, for a late response to any of them
+const MAX_ATTEMPTS: usize = 5;
+
+/// Locally generated id of a physical session, a version 4 UUID
+pub type SessionUuid = heapless::String<36>;
+
+/// State of the xorshift generator of the session UUIDs, seeded from the hardware RNG at boot
+static UUID_STATE: AtomicU32 = AtomicU32::new(0x2545_F491);
+
+/// Seed the session UUIDs, so they differ between restarts and chargers
+pub fn seed(value: u32) {
+    if value != 0 {
+        UUID_STATE.store(value, Ordering::Relaxed);
+    }
+}
+
+fn next_random() -> u32 {
+    let mut x = UUID_STATE.load(Ordering::Relaxed) ^ Instant::now().as_ticks() as u32;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    let x = if x == 0 { 1 } else { x };
+    UUID_STATE.store(x, Ordering::Relaxed);
+    x
+}
+
+fn new_uuid() -> SessionUuid {
+    let mut bytes = [0u8; 16];
+    for chunk in bytes.chunks_mut(4) {
+        chunk.copy_from_slice(&next_random().to_le_bytes());
+    }
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+    let mut uuid = SessionUuid::new();
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            let _ = uuid.push('-');
+        }
+        let _ = write!(uuid, "{byte:02x}");
+    }
+    uuid
+}
+
+/// A StopTransaction held until the transaction id of its session is known
+#[derive(Debug, Clone)]
+struct PendingStop {
+    /// Transaction id used when the central system never answers
+    fallback_id: i32,
+    meter_stop_wh: u32,
+    reason: Option<StopReason>,
+    stopped: u64, // Unix time
+}
+
+/// A physical session and the StartTransaction attempts sent for it
+#[derive(Debug, Clone)]
+struct Session {
+    uuid: SessionUuid,
+    id_tag: heapless::String<32>,
+    meter_start_wh: u32,
+    reservation_id: Option<i32>,
+    /// Unix time the session started, the timestamp of every attempt
+    started: u64,
+    /// Unique ids of the attempts, a response to any of them belongs to this session
+    message_ids: heapless::Vec<heapless::String<32>, MAX_ATTEMPTS>,
+    last_sent: Instant,
+    transaction_id: Option<i32>,
+    /// The attempts ran out without a response
+    abandoned: bool,
+    stopped: bool,
+    pending_stop: Option<PendingStop>,
+}
+
+static SESSION: Mutex<CriticalSectionRawMutex, RefCell<Option<Session>>> =
+    Mutex::new(RefCell::new(None));
+
+/// How a StartTransaction response relates to the open session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmation {
+    /// The transaction id of the session
+    Adopted,
+    /// The transaction id of a session that already stopped, its held StopTransaction was sent
+    AdoptedAfterStop,
+    /// The same transaction id again
+    Repeated,
+    /// A second transaction the central system created for the session, it was stopped
+    Duplicate,
+    /// Not a response to a StartTransaction of the session
+    Unmatched,
+}
+
+fn timestamp(unix: u64) -> DateTimeWrapper {
+    DateTimeWrapper::new(DateTime::from_timestamp(unix as i64, 0).unwrap_or_default())
+}
+
+/// Send an attempt of the StartTransaction of the session and announce its session UUID as a
+/// `{vendor}/TransactionSession` DataTransfer, for the central system to recognize a retry
+fn send_attempt(vendor: &str, session: &Session) -> Option<heapless::String<32>> {
+    let attempt = session.message_ids.len() + 1;
+    let message_id = ocpp::send_call("StartTransaction message", Priority::Critical, |now| {
+        let timestamp = if attempt == 1 {
+            now
+        } else {
+            timestamp(session.started)
+        };
+        ocpp::start_transaction(
+            &session.id_tag,
+            session.meter_start_wh,
+            session.reservation_id,
+            timestamp,
+        )
+    })?;
+
+    let mut json = heapless::String::<128>::new();
+    let _ = write!(
+        json,
+        "{{\"sessionUuid\":\"{}\",\"messageId\":\"{message_id}\",\"attempt\":{attempt}}}",
+        session.uuid
+    );
+    data_transfer::send_data_transfer(vendor, Some("TransactionSession"), Some(&json));
+    info!(
+        "IDEM: Sent StartTransaction attempt {attempt} of session {}",
+        session.uuid
+    );
+    Some(message_id)
+}
+
+fn send_stop(transaction_id: i32, id_tag: &str, stop: &PendingStop) {
+    ocpp::send_ocpp("StopTransaction message", Priority::Critical, |_| {
+        ocpp::stop_transaction(
+            transaction_id,
+            id_tag,
+            stop.meter_stop_wh,
+            stop.reason,
+            timestamp(stop.stopped),
+        )
+    });
+}
+
+/// Send the held StopTransaction of a session, with its transaction id or the fallback
+fn flush_stop(session: &mut Session) {
+    if let Some(stop) = session.pending_stop.take() {
+        let transaction_id = session.transaction_id.unwrap_or(stop.fallback_id);
+        info!(
+            "IDEM: Sending the held StopTransaction of session {} for transaction {transaction_id}",
+            session.uuid
+        );
+        send_stop(transaction_id, &session.id_tag, &stop);
+    }
+}
+
+/// The UUID of the running session, a second StartTransaction for it is suppressed
+pub fn open_session() -> Option<SessionUuid> {
+    SESSION.lock(|session| {
+        session
+            .borrow()
+            .as_ref()
+            .filter(|session| !session.stopped)
+            .map(|session| session.uuid.clone())
+    })
+}
+
+/// Start a session with a new UUID and send its StartTransaction
+pub fn start(vendor: &str, id_tag: &str, meter_start_wh: u32, reservation_id: Option<i32>) {
+    // A previous session still waiting for its transaction id gives up on it
+    if let Some(mut previous) = SESSION.lock(|session| session.borrow_mut().take()) {
+        flush_stop(&mut previous);
+    }
+
+    let mut session = Session {
+        uuid: new_uuid(),
+        id_tag: heapless::String::new(),
+        meter_start_wh,
+        reservation_id,
+        started: ntp::get_current_unix_time(),
+        message_ids: heapless::Vec::new(),
+        last_sent: Instant::now(),
+        transaction_id: None,
+        abandoned: false,
+        stopped: false,
+        pending_stop: None,
+    };
+    let _ = session.id_tag.push_str(id_tag.get(..32).unwrap_or(id_tag));
+    if let Some(message_id) = send_attempt(vendor, &session) {
+        let _ = session.message_ids.push(message_id);
+    }
+    SESSION.lock(|current| *current.borrow_mut() = Some(session));
+}
+
+/// Stop the session, the StopTransaction is held while its StartTransaction awaits a response
+pub fn stop(fallback_id: i32, id_tag: &str, meter_stop_wh: u32, reason: Option<StopReason>) {
+    let stop = PendingStop {
+        fallback_id,
+        meter_stop_wh,
+        reason,
+        stopped: ntp::get_current_unix_time(),
+    };
+    let held = SESSION.lock(|session| {
+        let mut session = session.borrow_mut();
+        let Some(session) = session.as_mut().filter(|session| !session.stopped) else {
+            return Err(stop);
+        };
+        session.stopped = true;
+        match session.transaction_id {
+            Some(transaction_id) => Err(PendingStop {
+                fallback_id: transaction_id,
+                ..stop
+            }),
+            None if session.abandoned => Err(stop),
+            None => {
+                session.pending_stop = Some(stop);
+                Ok(session.uuid.clone())
+            }
+        }
+    });
+    match held {
+        Ok(uuid) => info!("IDEM: StopTransaction of session {uuid} held until its transaction id"),
+        Err(stop) => send_stop(stop.fallback_id, id_tag, &stop),
+    }
+}
+
+/// Match a StartTransaction response to the session by the unique id of the Call
+/// A second transaction id for the same session is stopped right away with a
+/// `{vendor}/DuplicateTransaction` DataTransfer naming the transaction that is kept
+pub fn confirm(unique_id: &str, transaction_id: i32) -> Confirmation {
+    let (confirmation, session) = SESSION.lock(|session| {
+        let mut session = session.borrow_mut();
+        let Some(session) = session.as_mut() else {
+            return (Confirmation::Unmatched, None);
+        };
+        // A central system that echoes the action instead of the unique id is understood as well
+        let ours =
+            unique_id == "StartTransaction" || session.message_ids.iter().any(|id| id == unique_id);
+        let confirmation = match session.transaction_id {
+            Some(known) if known == transaction_id => Confirmation::Repeated,
+            Some(_) if ours => Confirmation::Duplicate,
+            Some(_) => Confirmation::Unmatched,
+            None => {
+                session.transaction_id = Some(transaction_id);
+                session.abandoned = false;
+                if session.stopped {
+                    Confirmation::AdoptedAfterStop
+                } else {
+                    Confirmation::Adopted
+                }
+            }
+        };
+        (confirmation, Some(session.clone()))
+    });
+    let Some(mut session) = session else {
+        return confirmation;
+    };
+
+    match confirmation {
+        Confirmation::AdoptedAfterStop => {
+            flush_stop(&mut session);
+            SESSION.lock(|current| {
+                if let Some(current) = current.borrow_mut().as_mut() {
+                    if current.uuid == session.uuid {
+                        current.pending_stop = None;
+                    }
+                }
+            });
+        }
+        Confirmation::Duplicate => {
+            let original = session.transaction_id.unwrap_or_default();
+            warn!(
+                "IDEM: Transaction {transaction_id} duplicates {original} of session {}, stopping it",
+                session.uuid
+            );
+            // Stopped at its start, so it carries no energy
+            let stop = PendingStop {
+                fallback_id: transaction_id,
+                meter_stop_wh: session.meter_start_wh,
+                reason: Some(StopReason::Duplicate),
+                stopped: session.started,
+            };
+            send_stop(transaction_id, &session.id_tag, &stop);
+            let mut json = heapless::String::<128>::new();
+            let _ = write!(
+                json,
+                "{{\"sessionUuid\":\"{}\",\"transactionId\":{transaction_id},\"originalTransactionId\":{original}}}",
+                session.uuid
+            );
+            let vendor = Config::from_config().charger_vendor;
+            data_transfer::send_data_transfer(vendor, Some("DuplicateTransaction"), Some(&json));
+        }
+        _ => {}
+    }
+    confirmation
+}
+
+/// Task to retry a StartTransaction without a response, every TransactionMessageRetryInterval
+/// times the attempt while connected, up to TransactionMessageAttempts. Each attempt carries the
+/// session UUID, so a central system that did receive an earlier one can tell it's the same session
+#[embassy_executor::task]
+pub async fn start_retry_task() {
+    info!("TASK: Started StartTransaction Retry");
+
+    let config = Config::from_config();
+
+    loop {
+        Timer::after(Duration::from_secs(CHECK_SECS)).await;
+
+        let connected = ready::is_ready(Subsystem::Mqtt);
+        let attempts = ocpp_config::integer(ConfigKey::TransactionMessageAttempts).max(1) as usize;
+        let interval =
+            ocpp_config::integer(ConfigKey::TransactionMessageRetryInterval).max(1) as u64;
+
+        let due = SESSION.lock(|session| {
+            let mut session = session.borrow_mut();
+            let session = session
+                .as_mut()
+                .filter(|session| session.transaction_id.is_none() && !session.abandoned)?;
+            // A queued message goes out once connected, the wait starts from there
+            if !connected {
+                session.last_sent = Instant::now();
+                return None;
+            }
+            let sent = session.message_ids.len().max(1);
+            let wait = Duration::from_secs(interval * sent as u64);
+            (session.last_sent.elapsed() >= wait).then(|| session.clone())
+        });
+        let Some(mut session) = due else {
+            continue;
+        };
+
+        if session.message_ids.len() >= attempts.min(MAX_ATTEMPTS) {
+            warn!(
+                "IDEM: No response to the StartTransaction of session {} after {} attempts",
+                session.uuid,
+                session.message_ids.len()
+            );
+            flush_stop(&mut session);
+            SESSION.lock(|current| {
+                if let Some(current) = current.borrow_mut().as_mut() {
+                    if current.uuid == session.uuid {
+                        current.abandoned = true;
+                        current.pending_stop = None;
+                    }
+                }
+            });
+            continue;
+        }
+
+        let message_id = send_attempt(config.charger_vendor, &session);
+        SESSION.lock(|current| {
+            if let Some(current) = current.borrow_mut().as_mut() {
+                if current.uuid == session.uuid && current.transaction_id.is_none() {
+                    current.last_sent = Instant::now();
+                    if let Some(message_id) = message_id {
+                        let _ = current.message_ids.push(message_id);
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,125 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    charger::{self, Charger, ChargerState, InputEvent},
+    error, info, io_state,
+};
+
+const CHECK_INTERVAL_MS: u64 = 500;
+/// Checks in a row an invariant must fail before it's a violation, the outputs follow a state
+/// change a moment later
+const FAILED_CHECKS: u8 = 3;
+
+/// Safety conditions that must always hold. A violation faults the charger instead of being
+/// impossible by assumption
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    /// The relay is only on while charging
+    RelayOnlyWhileCharging,
+    /// The cable is only locked while a cable is connected
+    LockOnlyWithCable,
+}
+
+impl Invariant {
+    pub const ALL: [Invariant; 2] = [
+        Invariant::RelayOnlyWhileCharging,
+        Invariant::LockOnlyWithCable,
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            Self::RelayOnlyWhileCharging => 0,
+            Self::LockOnlyWithCable => 1,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RelayOnlyWhileCharging => "RelayOnlyWhileCharging",
+            Self::LockOnlyWithCable => "LockOnlyWithCable",
+        }
+    }
+
+    /// Whether the invariant holds for the state and the recorded outputs
+    fn holds(&self, state: ChargerState) -> bool {
+        match self {
+            Self::RelayOnlyWhileCharging => !io_state::relay_on() || state.is_charging(),
+            Self::LockOnlyWithCable => !io_state::locked() || io_state::cable_connected(),
+        }
+    }
+}
+
+/// Violations since boot
+static VIOLATIONS: AtomicU32 = AtomicU32::new(0);
+/// Most recent violation and the uptime in seconds it happened at
+static LAST_VIOLATION: Mutex<CriticalSectionRawMutex, Cell<Option<(Invariant, u64)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Violations since boot and the most recent one with its uptime in seconds
+pub fn violations() -> (u32, Option<(Invariant, u64)>) {
+    (
+        VIOLATIONS.load(Ordering::Relaxed),
+        LAST_VIOLATION.lock(|last| last.get()),
+    )
+}
+
+/// Record a violation as a security event
+fn record(invariant: Invariant) {
+    VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+    LAST_VIOLATION.lock(|last| last.set(Some((invariant, Instant::now().as_secs()))));
+    error!("INV : Security event, {} violated", invariant.as_str());
+}
+
+/// Fault the charger, false when the state machine queue is full
+fn raise() -> bool {
+    charger::STATE_IN_CHANNEL
+        .try_send(InputEvent::InvariantViolated)
+        .is_ok()
+}
+
+/// Check an invariant where it's relied on, like `debug_assert!` but in the field. A violation
+/// is recorded and faults the charger, returns whether it holds
+pub fn check(invariant: Invariant, holds: bool) -> bool {
+    if !holds {
+        record(invariant);
+        if !raise() {
+            error!("INV : State machine queue full, unable to fault the charger");
+        }
+    }
+    holds
+}
+
+/// Task to check the invariants against the state and the outputs
+#[embassy_executor::task]
+pub async fn invariant_task(charger: &'static Charger) {
+    info!("TASK: Started Invariant Checker");
+
+    let mut failed = [0u8; Invariant::ALL.len()];
+    // A violation waiting for room in the state machine queue
+    let mut pending = false;
+    loop {
+        Timer::after(Duration::from_millis(CHECK_INTERVAL_MS)).await;
+
+        let state = charger.get_state().await;
+        for invariant in Invariant::ALL {
+            let failed = &mut failed[invariant.index()];
+            if invariant.holds(state) {
+                *failed = 0;
+                continue;
+            }
+            *failed = failed.saturating_add(1);
+            if *failed == FAILED_CHECKS {
+                record(invariant);
+                pending = true;
+            }
+        }
+        if pending {
+            pending = !raise();
+        }
+    }
+}
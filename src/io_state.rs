@@ -0,0 +1,105 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+};
+use embassy_time::Instant;
+
+use crate::wifi_monitor;
+
+/// Value of a reading that wasn't taken yet
+const NO_READING: i32 = i32::MIN;
+
+static CABLE_CONNECTED: AtomicBool = AtomicBool::new(false);
+static RELAY_ON: AtomicBool = AtomicBool::new(false);
+static LOCKED: AtomicBool = AtomicBool::new(false);
+static PILOT_MV: AtomicI32 = AtomicI32::new(NO_READING);
+static RFID_READER: AtomicBool = AtomicBool::new(false);
+static RFID_CARD: AtomicBool = AtomicBool::new(false);
+static TEMPERATURE_DECI: AtomicI32 = AtomicI32::new(NO_READING);
+
+/// Level of the cable switch, true while a cable is connected
+pub fn record_cable(connected: bool) {
+    CABLE_CONNECTED.store(connected, Ordering::Relaxed);
+}
+
+/// Level the relay is driven to
+pub fn record_relay(on: bool) {
+    RELAY_ON.store(on, Ordering::Relaxed);
+}
+
+/// Level the cable lock is driven to
+pub fn record_lock(locked: bool) {
+    LOCKED.store(locked, Ordering::Relaxed);
+}
+
+/// Whether a cable is connected, the level of the cable switch
+pub fn cable_connected() -> bool {
+    CABLE_CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Whether the relay is driven on
+pub fn relay_on() -> bool {
+    RELAY_ON.load(Ordering::Relaxed)
+}
+
+/// Whether the cable lock is driven locked
+pub fn locked() -> bool {
+    LOCKED.load(Ordering::Relaxed)
+}
+
+/// Last level of the negative half of the pilot
+pub fn record_pilot(negative_half_mv: i32) {
+    PILOT_MV.store(negative_half_mv, Ordering::Relaxed);
+}
+
+/// Whether the card reader is up and a card was in the field at the last poll
+pub fn record_rfid(reader: bool, card: bool) {
+    RFID_READER.store(reader, Ordering::Relaxed);
+    RFID_CARD.store(card, Ordering::Relaxed);
+}
+
+/// Chip temperature in °C
+pub fn record_temperature(celsius: f32) {
+    TEMPERATURE_DECI.store((celsius * 10.0) as i32, Ordering::Relaxed);
+}
+
+fn write_reading(json: &mut heapless::String<256>, key: &str, reading: Option<i32>) {
+    let _ = match reading {
+        Some(value) => write!(json, ",\"{key}\":{value}"),
+        None => write!(json, ",\"{key}\":null"),
+    };
+}
+
+/// Snapshot of the I/O as JSON, for remote triage of a charger that won't charge. The board
+/// has no relay feedback contact, lock position switch or pilot PWM generator, those are null
+pub fn snapshot_json() -> heapless::String<256> {
+    let mut json = heapless::String::new();
+    let _ = write!(
+        json,
+        "{{\"cable\":{},\"relay\":{},\"relay_feedback\":null,\"lock\":{},\"lock_position\":null",
+        CABLE_CONNECTED.load(Ordering::Relaxed),
+        RELAY_ON.load(Ordering::Relaxed),
+        LOCKED.load(Ordering::Relaxed)
+    );
+    let pilot_mv = PILOT_MV.load(Ordering::Relaxed);
+    write_reading(
+        &mut json,
+        "pilot_mv",
+        Some(pilot_mv).filter(|mv| *mv != NO_READING),
+    );
+    let _ = write!(
+        json,
+        ",\"pilot_duty\":null,\"rfid_reader\":{},\"rfid_card\":{}",
+        RFID_READER.load(Ordering::Relaxed),
+        RFID_CARD.load(Ordering::Relaxed)
+    );
+    let temperature = TEMPERATURE_DECI.load(Ordering::Relaxed);
+    let _ = if temperature == NO_READING {
+        write!(json, ",\"temperature\":null")
+    } else {
+        write!(json, ",\"temperature\":{:.1}", temperature as f32 / 10.0)
+    };
+    write_reading(&mut json, "rssi", wifi_monitor::rssi());
+    let _ = write!(json, ",\"uptime\":{}}}", Instant::now().as_secs());
+    json
+}
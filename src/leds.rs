@@ -0,0 +1,411 @@
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rmt::{ConstChannelAccess, Tx};
+use esp_hal_smartled::SmartLedsAdapter;
+use smart_leds::{
+    brightness,
+    colors::{BLACK, BLUE, CYAN, GREEN, ORANGE, PURPLE, RED, WHITE, YELLOW},
+    SmartLedsWrite as _, RGB8,
+};
+
+use crate::{
+    charger::{self, Charger, ChargerState},
+    config::Config,
+    connectivity,
+    fault::{self, Fault},
+    feedback::{self, Intensity, Prompt},
+    info,
+    pins::MappedOutput,
+    quiet, warn,
+};
+
+/// Longest WS2812 strip, the RMT buffer is sized for it
+pub const MAX_LEDS: usize = 8;
+/// RMT pulses of a full strip, 24 per LED and the end marker
+pub const LED_BUFFER_SIZE: usize = MAX_LEDS * 24 + 1;
+
+/// The WS2812 strip on the RMT
+pub type StatusLeds = SmartLedsAdapter<ConstChannelAccess<Tx, 0>, LED_BUFFER_SIZE>;
+
+/// Brightness during the quiet hours of the behavior profile
+const QUIET_BRIGHTNESS: u8 = 2;
+/// Interval the patterns are animated at
+const TICK_MS: u64 = 50;
+const SLOW_BLINK_PERIOD_MS: u64 = 1000;
+const FAST_BLINK_PERIOD_MS: u64 = 250;
+/// Two short flashes, at the start of the period and after `HEARTBEAT_GAP_MS`
+const HEARTBEAT_PERIOD_MS: u64 = 1200;
+const HEARTBEAT_FLASH_MS: u64 = 100;
+const HEARTBEAT_GAP_MS: u64 = 250;
+const PULSE_PERIOD_MS: u64 = 2000;
+/// Lowest level of a pulse, so a pulsing LED never looks off
+const PULSE_FLOOR: u64 = 25;
+
+/// How the color of a status is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Solid,
+    /// On and off every half second
+    SlowBlink,
+    /// On and off four times a second
+    FastBlink,
+    /// Two short flashes and a pause
+    Heartbeat,
+    /// Fading in and out, a single color indicator shows it as a slow blink
+    Pulse,
+}
+
+impl Pattern {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "solid" => Some(Self::Solid),
+            "slow_blink" | "blink" => Some(Self::SlowBlink),
+            "fast_blink" => Some(Self::FastBlink),
+            "heartbeat" => Some(Self::Heartbeat),
+            "pulse" => Some(Self::Pulse),
+            _ => None,
+        }
+    }
+
+    /// Level of the pattern out of 255, `elapsed_ms` after the status was shown
+    fn level(&self, elapsed_ms: u64) -> u8 {
+        let on = |on: bool| if on { 255 } else { 0 };
+        match self {
+            Self::Solid => 255,
+            Self::SlowBlink => on(elapsed_ms % SLOW_BLINK_PERIOD_MS < SLOW_BLINK_PERIOD_MS / 2),
+            Self::FastBlink => on(elapsed_ms % FAST_BLINK_PERIOD_MS < FAST_BLINK_PERIOD_MS / 2),
+            Self::Heartbeat => {
+                let phase = elapsed_ms % HEARTBEAT_PERIOD_MS;
+                on(phase < HEARTBEAT_FLASH_MS
+                    || (HEARTBEAT_GAP_MS..HEARTBEAT_GAP_MS + HEARTBEAT_FLASH_MS).contains(&phase))
+            }
+            Self::Pulse => {
+                let half = PULSE_PERIOD_MS / 2;
+                let phase = elapsed_ms % PULSE_PERIOD_MS;
+                let ramp = if phase < half {
+                    phase
+                } else {
+                    PULSE_PERIOD_MS - phase
+                };
+                (PULSE_FLOOR + ramp * (255 - PULSE_FLOOR) / half) as u8
+            }
+        }
+    }
+}
+
+/// What the LEDs show, the charger state with the loss of the network over the idle states and
+/// the fault that caused a Faulted state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedStatus {
+    Off,
+    Available,
+    /// A cable is connected or the charger is reserved
+    Occupied,
+    Authorizing,
+    Charging,
+    /// Faulted without a known fault
+    Faulted,
+    /// The cable was removed while charging
+    EvDisconnected,
+    /// The vehicle's pilot diode is missing
+    PilotDiode,
+    /// A safety invariant didn't hold
+    Invariant,
+    /// Locked out after recurring faults
+    Unavailable,
+    /// Not charging without a network
+    Offline,
+}
+
+impl LedStatus {
+    pub const ALL: [LedStatus; 11] = [
+        LedStatus::Off,
+        LedStatus::Available,
+        LedStatus::Occupied,
+        LedStatus::Authorizing,
+        LedStatus::Charging,
+        LedStatus::Faulted,
+        LedStatus::EvDisconnected,
+        LedStatus::PilotDiode,
+        LedStatus::Invariant,
+        LedStatus::Unavailable,
+        LedStatus::Offline,
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            Self::Off => 0,
+            Self::Available => 1,
+            Self::Occupied => 2,
+            Self::Authorizing => 3,
+            Self::Charging => 4,
+            Self::Faulted => 5,
+            Self::EvDisconnected => 6,
+            Self::PilotDiode => 7,
+            Self::Invariant => 8,
+            Self::Unavailable => 9,
+            Self::Offline => 10,
+        }
+    }
+
+    /// Name of the status in the configured mapping
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Available => "available",
+            Self::Occupied => "occupied",
+            Self::Authorizing => "authorizing",
+            Self::Charging => "charging",
+            Self::Faulted => "faulted",
+            Self::EvDisconnected => "ev_disconnected",
+            Self::PilotDiode => "pilot_diode",
+            Self::Invariant => "invariant",
+            Self::Unavailable => "unavailable",
+            Self::Offline => "offline",
+        }
+    }
+
+    pub fn of(state: ChargerState, offline: bool, fault: Option<Fault>) -> Self {
+        match state {
+            ChargerState::Off => Self::Off,
+            ChargerState::Available | ChargerState::Preparing | ChargerState::Reserved
+                if offline =>
+            {
+                Self::Offline
+            }
+            ChargerState::Available => Self::Available,
+            ChargerState::Preparing | ChargerState::Reserved => Self::Occupied,
+            ChargerState::Authorizing => Self::Authorizing,
+            ChargerState::Charging => Self::Charging,
+            ChargerState::Faulted => match fault {
+                Some(Fault::EvDisconnected) => Self::EvDisconnected,
+                Some(Fault::PilotDiodeMissing) => Self::PilotDiode,
+                Some(Fault::InvariantViolated) => Self::Invariant,
+                Some(Fault::GroundFault | Fault::PowerSwitchFailure | Fault::HighTemperature)
+                | None => Self::Faulted,
+            },
+            ChargerState::Unavailable => Self::Unavailable,
+        }
+    }
+
+    fn default_look(&self) -> (RGB8, Pattern) {
+        match self {
+            Self::Off => (BLACK, Pattern::Solid),
+            Self::Available => (GREEN, Pattern::Solid),
+            Self::Occupied => (BLUE, Pattern::Solid),
+            Self::Authorizing => (YELLOW, Pattern::SlowBlink),
+            Self::Charging => (GREEN, Pattern::Pulse),
+            Self::Faulted => (RED, Pattern::Solid),
+            Self::EvDisconnected => (RED, Pattern::SlowBlink),
+            Self::PilotDiode => (RED, Pattern::FastBlink),
+            Self::Invariant => (RED, Pattern::Heartbeat),
+            Self::Unavailable => (ORANGE, Pattern::Solid),
+            Self::Offline => (PURPLE, Pattern::Solid),
+        }
+    }
+}
+
+/// A color by name or as `#rrggbb`
+fn parse_color(value: &str) -> Option<RGB8> {
+    let named = match value {
+        "off" | "black" => BLACK,
+        "white" => WHITE,
+        "red" => RED,
+        "green" => GREEN,
+        "blue" => BLUE,
+        "yellow" => YELLOW,
+        "orange" => ORANGE,
+        "purple" => PURPLE,
+        "cyan" => CYAN,
+        _ => {
+            let hex = value.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+            let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+            return Some(RGB8::new(channel(0)?, channel(2)?, channel(4)?));
+        }
+    };
+    Some(named)
+}
+
+/// Color and pattern of each status
+pub struct LedMapping {
+    looks: [(RGB8, Pattern); LedStatus::ALL.len()],
+}
+
+impl LedMapping {
+    /// The defaults with the configured overrides, `status=color[:pattern]` separated by commas,
+    /// e.g. "available=#00ff00,charging=blue:pulse". An invalid override is skipped
+    pub fn parse(value: &str) -> Self {
+        let mut mapping = Self {
+            looks: LedStatus::ALL.map(|status| status.default_look()),
+        };
+        for entry in value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let look = entry.split_once('=').and_then(|(name, look)| {
+                let status = LedStatus::ALL
+                    .into_iter()
+                    .find(|status| status.as_str() == name.trim())?;
+                let (color, pattern) = match look.trim().split_once(':') {
+                    Some((color, pattern)) => (color, Some(Pattern::parse(pattern)?)),
+                    None => (look.trim(), None),
+                };
+                let default = status.default_look();
+                Some((status, (parse_color(color)?, pattern.unwrap_or(default.1))))
+            });
+            match look {
+                Some((status, look)) => mapping.looks[status.index()] = look,
+                None => warn!("LED : Invalid color mapping {entry}"),
+            }
+        }
+        mapping
+    }
+
+    pub fn look(&self, status: LedStatus) -> (RGB8, Pattern) {
+        self.looks[status.index()]
+    }
+}
+
+/// Brightness lowered during the quiet hours of the behavior profile
+fn quiet_brightness(config: &Config, brightness: u8) -> u8 {
+    if quiet::is_quiet(config) {
+        QUIET_BRIGHTNESS.min(brightness)
+    } else {
+        brightness
+    }
+}
+
+/// Level of the line that switches a single color LED on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    /// Wired to the supply, like an LED on a PCF8574 that can only sink current
+    ActiveLow,
+}
+
+impl Polarity {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "active_high" => Some(Self::ActiveHigh),
+            "active_low" => Some(Self::ActiveLow),
+            _ => None,
+        }
+    }
+}
+
+/// A single color LED showing the patterns next to the strip, on while a pattern is above half
+pub struct Indicator {
+    output: MappedOutput,
+    polarity: Polarity,
+}
+
+impl Indicator {
+    /// An indicator that starts off
+    pub fn new(output: MappedOutput, polarity: Polarity) -> Self {
+        let mut indicator = Self { output, polarity };
+        indicator.set(false);
+        indicator
+    }
+
+    fn set(&mut self, on: bool) {
+        self.output
+            .set_level(on != (self.polarity == Polarity::ActiveLow));
+    }
+}
+
+/// The strip and the indicator the status is shown on
+struct StatusOutputs {
+    leds: &'static mut StatusLeds,
+    count: usize,
+    indicator: Option<Indicator>,
+}
+
+impl StatusOutputs {
+    /// Show a color on every LED of the strip and switch the indicator
+    fn show(&mut self, color: RGB8, level: u8, on: bool) {
+        let colors = core::iter::repeat(color).take(self.count);
+        if let Err(e) = self.leds.write(brightness(colors, level)) {
+            warn!("LED : Failed to set the color: {e:?}");
+        }
+        if let Some(indicator) = self.indicator.as_mut() {
+            indicator.set(on);
+        }
+    }
+}
+
+/// Task to show the charger status on the WS2812 strip and the indicator, with the prompts
+/// flashed in step with the buzzer
+#[embassy_executor::task]
+pub async fn led_task(
+    leds: &'static mut StatusLeds,
+    indicator: Option<Indicator>,
+    charger: &'static Charger,
+    intensity: Intensity,
+) {
+    info!("TASK: Started Status LEDs");
+
+    let config = Config::from_config();
+    let mapping = LedMapping::parse(config.led_colors);
+    let mut outputs = StatusOutputs {
+        leds,
+        count: usize::from(config.led_count),
+        indicator,
+    };
+
+    let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
+    let mut state = charger.get_state().await;
+    let mut status = None;
+    let mut since = Instant::now();
+    let mut shown = None;
+
+    loop {
+        let next_status = LedStatus::of(state, connectivity::is_offline(), fault::last_fault());
+        if status != Some(next_status) {
+            let (color, pattern) = mapping.look(next_status);
+            info!(
+                "LED : Showing {} as RGB({}, {}, {}) {pattern:?}",
+                next_status.as_str(),
+                color.r,
+                color.g,
+                color.b
+            );
+            status = Some(next_status);
+            since = Instant::now();
+        }
+
+        let (color, pattern) = mapping.look(next_status);
+        let pattern_level = pattern.level(since.elapsed().as_millis());
+        let brightness = quiet_brightness(&config, config.led_brightness);
+        let level = (u16::from(brightness) * u16::from(pattern_level) / 255) as u8;
+        let on = color != BLACK && pattern_level >= 128;
+        if shown != Some((color, level, on)) {
+            outputs.show(color, level, on);
+            shown = Some((color, level, on));
+        }
+
+        let tick = Timer::after(Duration::from_millis(TICK_MS));
+        let (current_state, output_events) =
+            match select(subscriber.next_message_pure(), tick).await {
+                Either::First(message) => message,
+                Either::Second(()) => continue,
+            };
+
+        // Flash the prompt in step with the buzzer before showing the state
+        let prompt = Prompt::for_transition(state, current_state, &output_events);
+        state = current_state;
+        if let Some(prompt) = prompt.filter(|_| intensity != Intensity::Off) {
+            let flash = quiet_brightness(&config, intensity.led_brightness());
+            feedback::play(prompt, |tone| {
+                let color = if tone.is_some() {
+                    prompt.color()
+                } else {
+                    BLACK
+                };
+                outputs.show(color, flash, tone.is_some());
+            })
+            .await;
+            shown = None;
+        }
+    }
+}
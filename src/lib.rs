@@ -1,10 +1,91 @@
 #![no_std]
 
+// Log macros of the firmware, see the fmt module
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+#[cfg(not(feature = "defmt"))]
+pub use log::{debug, error, info, trace, warn};
+
+pub mod ble_provisioning;
+pub mod branding;
+pub mod budget;
+pub mod button;
+pub mod buzzer;
 pub mod charger;
+pub mod command;
 pub mod config;
+pub mod connectivity;
+pub mod crash;
+pub mod data_transfer;
+pub mod delta;
+pub mod demand_response;
+pub mod demo;
+pub mod diagnostics;
 pub mod display;
+pub mod endpoints;
+pub mod energy;
+pub mod expander;
+pub mod extensions;
+pub mod factory_test;
+pub mod fault;
+pub mod feedback;
+mod fmt;
+pub mod frame;
+pub mod ftp;
+pub mod guest;
+pub mod http;
+pub mod http_server;
+pub mod idempotency;
+pub mod invariant;
+pub mod io_state;
+pub mod leds;
+pub mod load_management;
+pub mod locale;
+pub mod log_forward;
+pub mod logging;
+pub mod maintenance;
+pub mod mdns;
+pub mod memory;
+pub mod meter;
+pub mod metering;
+pub mod modbus;
 pub mod mqtt;
 pub mod network;
+pub mod network_cache;
 pub mod ntp;
 pub mod ocpp;
+pub mod ocpp_config;
+pub mod onboarding;
+pub mod ota;
+pub mod panel;
+pub mod pilot;
+pub mod pins;
+pub mod profile;
+pub mod quiet;
+pub mod rcd;
+pub mod ready;
+pub mod relay;
+pub mod reservation;
+pub mod rfid;
+pub mod rtc;
+pub mod sequence;
+pub mod sessions;
+pub mod settings;
+pub mod smart_charging;
+pub mod solar;
+pub mod state_machine;
+pub mod storage;
+pub mod tasks;
+pub mod telemetry;
+pub mod timestamp;
+pub mod timezone;
+pub mod usb_api;
 pub mod utils;
+pub mod vendor;
+pub mod version;
+pub mod waiting_list;
+pub mod watchdog;
+pub mod webhook;
+pub mod wifi_monitor;
+pub mod wifi_networks;
+pub mod wire;
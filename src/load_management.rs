@@ -0,0 +1,208 @@
+use core::fmt::Write;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    charger::Charger,
+    config::Config,
+    info, mqtt, ocpp,
+    smart_charging::{CurrentLimits, LimitSource, MIN_CURRENT_AMPS},
+    warn,
+};
+
+/// Chargers of the site besides this one that are tracked, more are left out of the split
+const MAX_PEERS: usize = 16;
+/// Time between the announcements of this charger, a change of state is announced right away
+const ANNOUNCE_INTERVAL_SECS: u64 = 10;
+/// Missed announcements after which a charger is taken to be silent
+const MISSED_ANNOUNCEMENTS: u64 = 3;
+
+/// Settings of the load management
+#[derive(Debug, Clone, Copy)]
+pub struct LoadManagement {
+    pub serial: &'static str,
+    pub site_limit: u16,
+    pub peer_timeout_secs: u16,
+}
+
+impl LoadManagement {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            serial: config.charger_serial,
+            site_limit: config.site_limit_amps,
+            // A peer is forgotten no sooner than it turns silent
+            peer_timeout_secs: config
+                .peer_timeout_secs
+                .max((ANNOUNCE_INTERVAL_SECS * MISSED_ANNOUNCEMENTS) as u16),
+        }
+    }
+}
+
+/// Another charger of the site, as last announced
+#[derive(Debug, Clone)]
+struct Peer {
+    serial: heapless::String<32>,
+    charging: bool,
+    seen: Instant,
+}
+
+impl Peer {
+    /// Missed its announcements, it may still be drawing current
+    fn is_silent(&self) -> bool {
+        self.seen.elapsed() > Duration::from_secs(ANNOUNCE_INTERVAL_SECS * MISSED_ANNOUNCEMENTS)
+    }
+}
+
+/// An announcement on the load topic, e.g. `{"serial":"CP001","charging":true}`
+fn announcement(serial: &str, charging: bool) -> heapless::Vec<u8, 128> {
+    let mut json = heapless::String::<128>::new();
+    write!(json, "{{\"serial\":\"{serial}\",\"charging\":{charging}}}").ok();
+    json.into_bytes()
+}
+
+/// The chargers of the site that announced themselves
+struct Site {
+    peers: heapless::Vec<Peer, MAX_PEERS>,
+    /// Last message on the load topic, this charger's own announcements included
+    last_heard: Option<Instant>,
+}
+
+impl Site {
+    const fn new() -> Self {
+        Self {
+            peers: heapless::Vec::new(),
+            last_heard: None,
+        }
+    }
+
+    /// Record an announcement, this charger's own only shows the topic is alive
+    fn record(&mut self, own_serial: &str, payload: &[u8]) -> Result<(), &'static str> {
+        let payload = core::str::from_utf8(payload).map_err(|_| "Announcement isn't UTF-8")?;
+        let serial = ocpp::json_string_field(payload, "serial")
+            .and_then(ocpp::json_unescape::<32>)
+            .ok_or("Announcement without a serial")?;
+        let charging =
+            ocpp::json_bool_field(payload, "charging").ok_or("Announcement without a state")?;
+        self.last_heard = Some(Instant::now());
+        if serial == own_serial {
+            return Ok(());
+        }
+
+        match self.peers.iter_mut().find(|peer| peer.serial == serial) {
+            Some(peer) => {
+                if peer.is_silent() {
+                    info!("LOAD: {serial} is announcing again");
+                }
+                peer.charging = charging;
+                peer.seen = Instant::now();
+            }
+            None => {
+                self.peers
+                    .push(Peer {
+                        serial: serial.clone(),
+                        charging,
+                        seen: Instant::now(),
+                    })
+                    .map_err(|_| "Too many chargers on the site, leaving one out")?;
+                info!("LOAD: {serial} joined the site");
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the chargers that were silent for longer than the timeout
+    fn expire(&mut self, timeout: Duration) {
+        self.peers.retain(|peer| {
+            let expired = peer.seen.elapsed() > timeout;
+            if expired {
+                warn!("LOAD: {} went silent, no longer counted", peer.serial);
+            }
+            !expired
+        });
+    }
+
+    /// The share of the site limit of this charger. Split evenly over the chargers that are
+    /// charging, this one counted as if it charges so a session starts at its share. The minimum
+    /// current while a charger that was charging is silent, it may still be drawing
+    fn share(&self, site_limit: u16) -> u16 {
+        if self
+            .peers
+            .iter()
+            .any(|peer| peer.charging && peer.is_silent())
+        {
+            return MIN_CURRENT_AMPS;
+        }
+        let charging = self.peers.iter().filter(|peer| peer.charging).count() as u16 + 1;
+        (site_limit / charging).max(MIN_CURRENT_AMPS)
+    }
+}
+
+/// Task to share the site limit with the other chargers of the site over the load topic. Each
+/// charger announces whether it is charging, the limit is split over the ones that are and the
+/// share is this charger's load balancing limit. When the topic goes quiet the limit is no longer
+/// refreshed and the limit watchdog falls back to the failsafe current
+#[embassy_executor::task]
+pub async fn load_management_task(
+    charger: &'static Charger,
+    limits: &'static CurrentLimits,
+    settings: LoadManagement,
+) {
+    info!(
+        "TASK: Started Load Management, sharing {}A on the site",
+        settings.site_limit
+    );
+
+    let peer_timeout = Duration::from_secs(settings.peer_timeout_secs.into());
+    let mut site = Site::new();
+    let mut charging = charger.get_state().await.is_charging();
+    let mut last_announced: Option<Instant> = None;
+    let mut last_share = None;
+    let mut last_refreshed: Option<Instant> = None;
+
+    loop {
+        while let Ok(payload) = mqtt::MQTT_LOAD_CHANNEL.try_receive() {
+            if let Err(e) = site.record(settings.serial, &payload) {
+                warn!("LOAD: {e}");
+            }
+        }
+        site.expire(peer_timeout);
+
+        let now_charging = charger.get_state().await.is_charging();
+        let due = last_announced
+            .is_none_or(|at| at.elapsed() >= Duration::from_secs(ANNOUNCE_INTERVAL_SECS));
+        if due || now_charging != charging {
+            charging = now_charging;
+            // The latest announcement replaces one the client hasn't sent yet
+            mqtt::MQTT_ANNOUNCE_CHANNEL.clear();
+            let _ = mqtt::MQTT_ANNOUNCE_CHANNEL.try_send(announcement(settings.serial, charging));
+            last_announced = Some(Instant::now());
+        }
+
+        // Without the topic the share is unknown, the limit goes stale instead
+        let heard = site.last_heard.is_some_and(|at| {
+            at.elapsed() <= Duration::from_secs(ANNOUNCE_INTERVAL_SECS * MISSED_ANNOUNCEMENTS)
+        });
+        if heard {
+            let share = site.share(settings.site_limit);
+            if last_share != Some(share) {
+                info!(
+                    "LOAD: {} of {} chargers charging, share {share}A",
+                    site.peers.iter().filter(|peer| peer.charging).count() + usize::from(charging),
+                    site.peers.len() + 1
+                );
+                last_share = Some(share);
+                last_refreshed = None;
+            }
+            // Refreshed with the announcements, well within the staleness timeout of the limit
+            let refresh = last_refreshed
+                .is_none_or(|at| at.elapsed() >= Duration::from_secs(ANNOUNCE_INTERVAL_SECS));
+            if refresh {
+                limits.set_limit(LimitSource::LoadBalancing, share).await;
+                last_refreshed = Some(Instant::now());
+            }
+        } else if last_share.take().is_some() {
+            warn!("LOAD: Nothing heard on the load topic, the share is no longer refreshed");
+        }
+
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
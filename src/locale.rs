@@ -0,0 +1,89 @@
+use core::fmt::Write;
+
+/// Number formats of the values shown to drivers, on the display and in receipts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale {
+    /// Decimal comma with a point to group thousands, e.g. `1.234,5`
+    pub decimal_comma: bool,
+    /// Currency symbol or code, the display font only has ASCII characters
+    pub currency: &'static str,
+    /// The currency follows the amount, e.g. `4,38 EUR`
+    pub currency_after: bool,
+}
+
+impl Locale {
+    /// Parse the configured decimal separator (`point` or `comma`), currency and currency
+    /// position (`before` or `after`), an unknown value keeps the default
+    pub fn parse(decimal: &str, currency: &'static str, position: &str) -> Self {
+        Self {
+            decimal_comma: decimal == "comma",
+            currency,
+            currency_after: position == "after",
+        }
+    }
+
+    fn separators(&self) -> (char, char) {
+        if self.decimal_comma {
+            (',', '.')
+        } else {
+            ('.', ',')
+        }
+    }
+
+    /// Write a fixed point value, a `value` of 12345 with 2 decimals is written as `123.45`
+    pub fn write_decimal<const N: usize>(
+        &self,
+        out: &mut heapless::String<N>,
+        value: u64,
+        decimals: u32,
+    ) {
+        let (decimal, thousands) = self.separators();
+        let scale = 10u64.pow(decimals);
+        let whole = value / scale;
+
+        let mut digits = heapless::String::<20>::new();
+        let _ = write!(digits, "{whole}");
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                let _ = out.push(thousands);
+            }
+            let _ = out.push(digit);
+        }
+        if decimals > 0 {
+            let _ = out.push(decimal);
+            let _ = write!(out, "{:0width$}", value % scale, width = decimals as usize);
+        }
+    }
+
+    /// Energy in kWh with one decimal, e.g. `12.5 kWh`
+    pub fn energy(&self, wh: u64) -> heapless::String<24> {
+        let mut out = heapless::String::new();
+        self.write_decimal(&mut out, (wh + 50) / 100, 1);
+        let _ = out.push_str(" kWh");
+        out
+    }
+
+    /// An amount of money with two decimals and the currency, e.g. `EUR 4.38` or `4,38 EUR`
+    pub fn money(&self, cents: u64) -> heapless::String<32> {
+        let mut out = heapless::String::new();
+        // A currency code is set apart from the amount, a symbol before it is not
+        let space = if self.currency.chars().all(|c| c.is_ascii_alphabetic()) {
+            " "
+        } else {
+            ""
+        };
+        if self.currency_after {
+            self.write_decimal(&mut out, cents, 2);
+            let _ = write!(out, " {}", self.currency);
+        } else {
+            let _ = write!(out, "{}{space}", self.currency);
+            self.write_decimal(&mut out, cents, 2);
+        }
+        out
+    }
+}
+
+/// Cost of an amount of energy at a price per kWh in cents, rounded to the cent
+pub fn cost_cents(wh: u64, cents_per_kwh: u16) -> u64 {
+    (wh * u64::from(cents_per_kwh) + 500) / 1000
+}
@@ -0,0 +1,263 @@
+use core::fmt::Write;
+use embassy_net::{udp::UdpSocket, IpAddress};
+use embassy_time::{with_deadline, Duration, Instant};
+use log::Level;
+
+use crate::{
+    info,
+    logging::{self, LogEntry},
+    mqtt,
+    network::NetworkStack,
+    ntp, ocpp, warn,
+};
+
+/// Time the first line of a batch waits for more lines
+const BATCH_SECS: u64 = 5;
+/// Lines per batch, so a batch fits a message on the log topic
+const BATCH_ENTRIES: usize = 6;
+const SYSLOG_PORT: u16 = 514;
+/// Syslog facility local0, the severity is added to it
+const SYSLOG_FACILITY: u8 = 16;
+/// Time before a syslog server that wasn't found is resolved again
+const RESOLVE_RETRY_SECS: u64 = 60;
+/// Room kept after the entries of a batch for the end of the JSON
+const BATCH_TAIL: usize = 32;
+
+/// Where the warnings and errors go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// One RFC 5424 datagram per line to a syslog server
+    Syslog,
+    /// A JSON batch on the log topic of the broker
+    Mqtt,
+}
+
+/// Token bucket of the forwarded lines, refilled evenly over a minute
+struct RateLimit {
+    per_minute: u64,
+    tokens: u64,
+    refilled_ms: u64,
+    /// Lines dropped by the limit since the last batch went out
+    dropped: u32,
+}
+
+impl RateLimit {
+    fn new(per_minute: u16) -> Self {
+        Self {
+            per_minute: u64::from(per_minute),
+            tokens: u64::from(per_minute),
+            refilled_ms: Instant::now().as_millis(),
+            dropped: 0,
+        }
+    }
+
+    /// Take a token for a line, counts the line as dropped when there is none
+    fn allow(&mut self) -> bool {
+        let now_ms = Instant::now().as_millis();
+        let refill = now_ms.saturating_sub(self.refilled_ms) * self.per_minute / 60_000;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(self.per_minute);
+            self.refilled_ms += refill * 60_000 / self.per_minute;
+        }
+        if self.tokens == 0 {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/// The batch as JSON for the log topic, e.g.
+/// `{"serial":"...","time":"...","entries":[{"uptimeMs":5210,"level":"WARN",...}],"dropped":0}`
+/// Entries that don't fit are counted as dropped
+fn batch_json(serial: &str, entries: &[LogEntry], dropped: u32) -> heapless::Vec<u8, 1024> {
+    let mut json = heapless::String::<1024>::new();
+    let _ = json.push_str("{\"serial\":\"");
+    let _ = ocpp::push_json_escaped(&mut json, serial);
+    let _ = write!(
+        json,
+        "\",\"time\":\"{}\",\"entries\":[",
+        ntp::get_iso8601_time()
+    );
+    let mut dropped = dropped;
+    let mut first = true;
+    for entry in entries {
+        let len = json.len();
+        let written = (first || json.push(',').is_ok())
+            && entry.write_json(&mut json).is_some()
+            && json.len() <= json.capacity() - BATCH_TAIL;
+        if written {
+            first = false;
+        } else {
+            json.truncate(len);
+            dropped = dropped.saturating_add(1);
+        }
+    }
+    let _ = write!(json, "],\"dropped\":{dropped}}}");
+    let mut message = heapless::Vec::new();
+    let _ = message.extend_from_slice(json.as_bytes());
+    message
+}
+
+/// A line as an RFC 5424 message, e.g.
+/// `<132>1 2025-01-01T12:00:00Z CHARGER-001 charger - - - [5.210] OCPP: Failed to send heartbeat`
+/// The timestamp is the nil value until the time is synced
+fn syslog_line(serial: &str, severity: u8, uptime_ms: u64, text: &str) -> heapless::String<256> {
+    let mut line = heapless::String::new();
+    let time = if ntp::is_time_synced() {
+        ntp::get_iso8601_time()
+    } else {
+        heapless::String::try_from("-").unwrap_or_default()
+    };
+    let _ = write!(
+        line,
+        "<{}>1 {time} {serial} charger - - - [{}.{:03}] ",
+        SYSLOG_FACILITY * 8 + severity,
+        uptime_ms / 1000,
+        uptime_ms % 1000
+    );
+    for c in text.chars() {
+        if line.push(c).is_err() {
+            break;
+        }
+    }
+    line
+}
+
+/// Severity of a log level in syslog, error or warning
+fn syslog_severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        _ => 4,
+    }
+}
+
+/// Send the batch to the syslog server, one datagram per line and one for the dropped lines
+async fn send_syslog(
+    network: &NetworkStack,
+    server: (IpAddress, u16),
+    entries: &[LogEntry],
+    dropped: u32,
+) -> Result<(), &'static str> {
+    let serial = network.app_config.charger_serial;
+    let mut rx_meta = [embassy_net::udp::PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; 16];
+    let mut tx_meta = [embassy_net::udp::PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0u8; 512];
+    let mut socket = UdpSocket::new(
+        *network.stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(0).is_err() {
+        return Err("Failed to bind UDP socket");
+    }
+
+    for entry in entries {
+        let severity = syslog_severity(entry.level);
+        let line = syslog_line(serial, severity, entry.uptime_ms, &entry.text);
+        socket
+            .send_to(line.as_bytes(), server)
+            .await
+            .map_err(|_| "Failed to send to the syslog server")?;
+    }
+    if dropped > 0 {
+        let mut text = heapless::String::<64>::new();
+        let _ = write!(text, "LOGF: {dropped} log lines dropped");
+        let line = syslog_line(serial, 4, Instant::now().as_millis(), &text);
+        socket
+            .send_to(line.as_bytes(), server)
+            .await
+            .map_err(|_| "Failed to send to the syslog server")?;
+    }
+    Ok(())
+}
+
+/// Address and port of the syslog server, resolved when it's a host name
+async fn resolve_syslog(network: &NetworkStack) -> Option<(IpAddress, u16)> {
+    let server = network.app_config.log_forward_syslog;
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (server, SYSLOG_PORT),
+    };
+    let (address, _) = network.resolve_cached(host, None).await?;
+    Some((address, port))
+}
+
+/// Task to forward warnings and errors to a syslog server or the log topic, so they can be seen
+/// centrally. Lines are batched for a few seconds and limited per minute, the lines over the
+/// limit are counted and the count is sent with the next batch
+#[embassy_executor::task]
+pub async fn log_forward_task(network: &'static NetworkStack) {
+    info!("TASK: Started Log Forwarder");
+
+    let config = &network.app_config;
+    let target = match config.log_forward_target {
+        "syslog" if !config.log_forward_syslog.is_empty() => Target::Syslog,
+        "syslog" => {
+            warn!("LOGF: No syslog server configured, not forwarding");
+            return;
+        }
+        "mqtt" => Target::Mqtt,
+        other => {
+            warn!("LOGF: Unknown target {other}, not forwarding");
+            return;
+        }
+    };
+    logging::start_forwarding();
+
+    let mut limit = RateLimit::new(config.log_forward_per_minute);
+    let mut server = None;
+    let mut resolve_after = Instant::now();
+    loop {
+        let mut batch = heapless::Vec::<LogEntry, BATCH_ENTRIES>::new();
+        let first = logging::FORWARD_CHANNEL.receive().await;
+        if limit.allow() {
+            let _ = batch.push(first);
+        }
+        let deadline = Instant::now() + Duration::from_secs(BATCH_SECS);
+        while !batch.is_full() {
+            match with_deadline(deadline, logging::FORWARD_CHANNEL.receive()).await {
+                Ok(entry) if limit.allow() => {
+                    let _ = batch.push(entry);
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        // The dropped lines are reported with the next batch that goes out
+        if batch.is_empty() {
+            continue;
+        }
+        let dropped =
+            core::mem::take(&mut limit.dropped).saturating_add(logging::take_forward_overflows());
+
+        match target {
+            Target::Mqtt => {
+                let message = batch_json(config.charger_serial, &batch, dropped);
+                if mqtt::MQTT_LOG_CHANNEL.try_send(message).is_err() {
+                    limit.dropped = limit.dropped.saturating_add(batch.len() as u32 + dropped);
+                }
+            }
+            Target::Syslog => {
+                // A failed lookup logs an error itself, it isn't repeated for every batch
+                if server.is_none() && Instant::now() >= resolve_after {
+                    server = resolve_syslog(network).await;
+                    resolve_after = Instant::now() + Duration::from_secs(RESOLVE_RETRY_SECS);
+                }
+                let Some(address) = server else {
+                    limit.dropped = limit.dropped.saturating_add(batch.len() as u32 + dropped);
+                    continue;
+                };
+                if let Err(e) = send_syslog(network, address, &batch, dropped).await {
+                    info!("LOGF: {e}");
+                    limit.dropped = limit.dropped.saturating_add(batch.len() as u32 + dropped);
+                    server = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::{
+    cell::RefCell,
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{config::Config, data_transfer, memory, mqtt, ocpp, warn};
+
+/// Entries kept in the ring buffer, the oldest is dropped for a new one
+pub const LOG_ENTRIES: usize = 64;
+const LOG_TEXT_LENGTH: usize = 96;
+/// Entries per `Logs` DataTransfer, so one fits a Call
+const ENTRIES_PER_TRANSFER: usize = 6;
+/// Time a part waits for the send queue to empty, the queue only has room for a few messages
+const QUEUE_WAIT_SECS: u64 = 10;
+/// Warnings and errors waiting for the log forwarder
+const FORWARD_ENTRIES: usize = 16;
+/// Prefix of the forwarder's own lines, they aren't forwarded so a failure can't feed itself
+const FORWARD_PREFIX: &str = "LOGF";
+
+/// A log line with its severity and the uptime it was logged at
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub uptime_ms: u64,
+    pub level: Level,
+    pub text: heapless::String<LOG_TEXT_LENGTH>,
+}
+
+impl LogEntry {
+    /// The entry as a line of text, e.g. `5.210 WARN  OCPP: Failed to send heartbeat`
+    pub fn write_line(&self, out: &mut impl Write) -> core::fmt::Result {
+        writeln!(
+            out,
+            "{}.{:03} {:<5} {}",
+            self.uptime_ms / 1000,
+            self.uptime_ms % 1000,
+            self.level,
+            self.text
+        )
+    }
+
+    /// The entry as a JSON object, `{"uptimeMs":5210,"level":"WARN","message":"..."}`. Returns
+    /// None when it doesn't fit, `out` may hold part of it then
+    pub fn write_json<const N: usize>(&self, out: &mut heapless::String<N>) -> Option<()> {
+        write!(
+            out,
+            "{{\"uptimeMs\":{},\"level\":\"{}\",\"message\":\"",
+            self.uptime_ms, self.level
+        )
+        .ok()?;
+        ocpp::push_json_escaped(out, &self.text)?;
+        out.push_str("\"}").ok()
+    }
+}
+
+/// Recent log lines of the buffered levels
+static BUFFER: Mutex<CriticalSectionRawMutex, RefCell<heapless::Deque<LogEntry, LOG_ENTRIES>>> =
+    Mutex::new(RefCell::new(heapless::Deque::new()));
+/// Least severe level kept in the buffer, as its `Level` discriminant
+static BUFFER_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Warnings and errors for the log forwarder, filled while forwarding is enabled
+pub static FORWARD_CHANNEL: Channel<CriticalSectionRawMutex, LogEntry, FORWARD_ENTRIES> =
+    Channel::new();
+static FORWARDING: AtomicBool = AtomicBool::new(false);
+/// RAM of the buffer and the lines waiting for the forwarder, counted against the logging budget
+pub const RAM: usize = size_of_val(&BUFFER) + size_of_val(&FORWARD_CHANNEL);
+/// Lines not forwarded because the forwarder fell behind
+static FORWARD_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+/// Print a line on the serial port like the esp-println logger
+#[cfg(not(feature = "defmt"))]
+fn print(level: Level, args: core::fmt::Arguments) {
+    esp_println::println!("{level} - {args}");
+}
+
+/// Send a line over RTT with defmt, formatted here so the format strings of `log` work as well
+#[cfg(feature = "defmt")]
+fn print(level: Level, args: core::fmt::Arguments) {
+    let mut line = heapless::String::<256>::new();
+    let _ = write!(line, "{args}");
+    match level {
+        Level::Error => defmt::error!("{=str}", line.as_str()),
+        Level::Warn => defmt::warn!("{=str}", line.as_str()),
+        Level::Info => defmt::info!("{=str}", line.as_str()),
+        Level::Debug => defmt::debug!("{=str}", line.as_str()),
+        Level::Trace => defmt::trace!("{=str}", line.as_str()),
+    }
+}
+
+/// Keep a line in the ring buffer and pass it to the log forwarder, as far as its level is
+fn keep(level: Level, args: core::fmt::Arguments) {
+    let buffered = level as usize <= BUFFER_LEVEL.load(Ordering::Relaxed);
+    let forwarded = level <= Level::Warn && FORWARDING.load(Ordering::Relaxed);
+    if !buffered && !forwarded {
+        return;
+    }
+
+    let mut entry = LogEntry {
+        uptime_ms: Instant::now().as_millis(),
+        level,
+        text: heapless::String::new(),
+    };
+    // Truncated lines are kept, the start of a message is the most useful part
+    let _ = write!(entry.text, "{args}");
+
+    if forwarded
+        && !entry.text.starts_with(FORWARD_PREFIX)
+        && FORWARD_CHANNEL.try_send(entry.clone()).is_err()
+    {
+        FORWARD_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    }
+    if buffered {
+        BUFFER.lock(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            if buffer.is_full() {
+                buffer.pop_front();
+            }
+            let _ = buffer.push_back(entry);
+        });
+    }
+}
+
+/// Log a line of the firmware, the log macros come here with the `defmt` feature. Lines of the
+/// dependencies still go through `log` and the logger below
+#[cfg(feature = "defmt")]
+pub fn emit(level: Level, args: core::fmt::Arguments) {
+    if level <= log::max_level() {
+        print(level, args);
+        keep(level, args);
+    }
+}
+
+/// Logger that prints the lines and keeps the recent ones in the ring buffer
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        print(record.level(), *record.args());
+        keep(record.level(), *record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Install the logger, the level is taken from the ESP_LOG build variable
+pub fn init_logger() {
+    let level = option_env!("ESP_LOG")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Set the least severe level kept in the ring buffer from the configuration
+pub fn init(config: &Config) {
+    match config.log_buffer_level.parse::<Level>() {
+        Ok(level) => BUFFER_LEVEL.store(level as usize, Ordering::Relaxed),
+        Err(_) => warn!(
+            "LOG : Unknown buffer level {}, keeping info and above",
+            config.log_buffer_level
+        ),
+    }
+}
+
+/// Start passing warnings and errors to the log forwarder
+pub fn start_forwarding() {
+    FORWARDING.store(true, Ordering::Relaxed);
+}
+
+/// Lines the log forwarder missed since the last call, because its queue was full
+pub fn take_forward_overflows() -> u32 {
+    FORWARD_OVERFLOWS.swap(0, Ordering::Relaxed)
+}
+
+/// The buffered entries of `level` and above, oldest first. Copied to the heap, so the buffer
+/// isn't locked while they're sent
+pub fn entries(level: Level) -> Result<Vec<LogEntry>, &'static str> {
+    let mut entries = Vec::new();
+    if entries.try_reserve(LOG_ENTRIES).is_err() {
+        memory::allocation_failed("LOG ", LOG_ENTRIES * core::mem::size_of::<LogEntry>());
+        return Err("Out of memory for the log entries");
+    }
+    BUFFER.lock(|buffer| {
+        entries.extend(
+            buffer
+                .borrow()
+                .iter()
+                .filter(|entry| entry.level <= level)
+                .cloned(),
+        );
+    });
+    Ok(entries)
+}
+
+/// Wait until the send queue is empty, returns false when it didn't empty in time
+async fn wait_for_queue() -> bool {
+    with_timeout(Duration::from_secs(QUEUE_WAIT_SECS), async {
+        while !mqtt::MQTT_SEND_QUEUE.is_empty() {
+            Timer::after(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Send the buffered entries of `level` and above to the central system, as DataTransfers with
+/// messageId `Logs` of up to 6 entries each, e.g.
+/// `{"part":1,"parts":3,"entries":[{"uptimeMs":5210,"level":"WARN","message":"..."}]}`
+/// A part is queued once the previous ones went out, so the other messages aren't crowded out
+pub async fn send(vendor_id: &str, level: Level) -> Result<(), &'static str> {
+    let entries = entries(level)?;
+    let parts = entries.len().div_ceil(ENTRIES_PER_TRANSFER).max(1);
+    for part in 0..parts {
+        if !wait_for_queue().await {
+            return Err("Send queue busy, log entries not sent");
+        }
+        let mut json = heapless::String::<1280>::new();
+        let _ = write!(
+            json,
+            "{{\"part\":{},\"parts\":{parts},\"entries\":[",
+            part + 1
+        );
+        let start = part * ENTRIES_PER_TRANSFER;
+        let end = (start + ENTRIES_PER_TRANSFER).min(entries.len());
+        for (i, entry) in entries[start..end].iter().enumerate() {
+            if i > 0 {
+                let _ = json.push(',');
+            }
+            let _ = entry.write_json(&mut json);
+        }
+        let _ = json.push_str("]}");
+        if !data_transfer::send_data_transfer(vendor_id, Some("Logs"), Some(&json)) {
+            return Err("Failed to queue the log entries");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::charger::{self, InputEvent};
+use crate::info;
+
+const EXPIRY_CHECK_SECS: u64 = 10;
+/// Longest maintenance window that can be requested
+pub const MAX_WINDOW_HOURS: u32 = 72;
+
+/// End of the maintenance window, uptime based so it works without a synced clock
+static WINDOW_END: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Whether the charger is in a maintenance window
+pub fn is_active() -> bool {
+    WINDOW_END.lock(|end| end.get().is_some())
+}
+
+/// Seconds left in the maintenance window, if any
+pub fn remaining_secs() -> Option<u64> {
+    WINDOW_END.lock(|end| {
+        end.get()
+            .map(|end| end.saturating_duration_since(Instant::now()).as_secs())
+    })
+}
+
+/// Start or extend a maintenance window of a number of hours
+pub fn start(hours: u32) {
+    let hours = hours.clamp(1, MAX_WINDOW_HOURS);
+    info!("MNT : Maintenance window started for {hours}h");
+    WINDOW_END.lock(|end| {
+        end.set(Some(
+            Instant::now() + Duration::from_secs(u64::from(hours) * 3600),
+        ))
+    });
+    let _ = charger::STATE_IN_CHANNEL.try_send(InputEvent::MaintenanceStarted);
+}
+
+/// End the maintenance window, returns false if there was none
+pub fn end() -> bool {
+    if WINDOW_END.lock(|end| end.take()).is_none() {
+        return false;
+    }
+    info!("MNT : Maintenance window ended, returning to service");
+    let _ = charger::STATE_IN_CHANNEL.try_send(InputEvent::MaintenanceEnded);
+    true
+}
+
+/// Task to return to service when the maintenance window ends
+#[embassy_executor::task]
+pub async fn maintenance_expiry_task() {
+    info!("TASK: Started Maintenance Window Handler");
+
+    loop {
+        Timer::after(Duration::from_secs(EXPIRY_CHECK_SECS)).await;
+
+        if remaining_secs() == Some(0) {
+            end();
+        }
+    }
+}
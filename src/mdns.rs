@@ -0,0 +1,311 @@
+use core::fmt::Write;
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, Ipv4Address,
+};
+use embassy_time::{Duration, Timer};
+
+use crate::{info, network::NetworkStack, version, warn};
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const PACKET_SIZE: usize = 512;
+
+const SERVICE: [&str; 3] = ["_ocpp-charger", "_tcp", "local"];
+const SERVICES_ENUMERATION: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+/// Time to live of the host and service records, and of the service type records
+const HOST_TTL_SECS: u32 = 120;
+const SERVICE_TTL_SECS: u32 = 4500;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Records only this charger answers for replace cached ones (RFC 6762 section 10.2)
+const CLASS_IN_FLUSH: u16 = 0x8001;
+
+/// DNS label of the charger, the serial with characters other than letters, digits and `-`
+/// replaced, used as host name and service instance name
+pub fn host_label(serial: &str) -> heapless::String<63> {
+    let mut label = heapless::String::new();
+    for c in serial.chars() {
+        let c = if c.is_ascii_alphanumeric() { c } else { '-' };
+        if label.push(c.to_ascii_lowercase()).is_err() {
+            break;
+        }
+    }
+    label
+}
+
+/// Read a name from a packet as lowercase dotted labels, returns it with the offset after it
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(heapless::String<128>, usize)> {
+    let mut name = heapless::String::new();
+    let mut end = None;
+    // A bound on the compression pointers followed, a malformed packet may loop
+    for _ in 0..32 {
+        let len = usize::from(*packet.get(offset)?);
+        if len & 0xc0 == 0xc0 {
+            let pointer = (len & 0x3f) << 8 | usize::from(*packet.get(offset + 1)?);
+            end.get_or_insert(offset + 2);
+            offset = pointer;
+            continue;
+        }
+        if len == 0 {
+            return Some((name, end.unwrap_or(offset + 1)));
+        }
+        if !name.is_empty() {
+            name.push('.').ok()?;
+        }
+        for &byte in packet.get(offset + 1..offset + 1 + len)? {
+            name.push(char::from(byte.to_ascii_lowercase())).ok()?;
+        }
+        offset += 1 + len;
+    }
+    None
+}
+
+/// Whether a lowercase dotted name matches the labels of a record
+fn is_name(name: &str, labels: &[&str]) -> bool {
+    let mut rest = name;
+    for (i, label) in labels.iter().enumerate() {
+        if i > 0 {
+            let Some(after) = rest.strip_prefix('.') else {
+                return false;
+            };
+            rest = after;
+        }
+        let Some(after) = rest.strip_prefix(label) else {
+            return false;
+        };
+        rest = after;
+    }
+    rest.is_empty()
+}
+
+/// What a query asks about the charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Query {
+    /// The service, its instance or the host
+    Charger,
+    /// The service types on the network
+    ServiceTypes,
+}
+
+/// Parse a query, returns None when it doesn't ask about the charger
+fn parse_query(packet: &[u8], host: &str) -> Option<Query> {
+    let header = packet.get(..12)?;
+    // Responses, including our own announcements, are ignored
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let mut offset = 12;
+    let mut query = None;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, offset)?;
+        offset = next + 4; // Type and class
+        if is_name(&name, &SERVICES_ENUMERATION) {
+            query = query.or(Some(Query::ServiceTypes));
+        } else if is_name(&name, &SERVICE)
+            || is_name(&name, &[host, SERVICE[0], SERVICE[1], SERVICE[2]])
+            || is_name(&name, &[host, "local"])
+        {
+            query = Some(Query::Charger);
+        }
+    }
+    query
+}
+
+/// A response being written
+struct Response {
+    packet: heapless::Vec<u8, PACKET_SIZE>,
+    answers: u16,
+}
+
+impl Response {
+    fn new() -> Self {
+        let mut packet = heapless::Vec::new();
+        // Id 0, an authoritative response, the answer count is set when done
+        let _ = packet.extend_from_slice(&[0, 0, 0x84, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        Self { packet, answers: 0 }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        self.packet.extend_from_slice(bytes).map_err(|_| ())
+    }
+
+    fn name(&mut self, labels: &[&str]) -> Result<(), ()> {
+        for label in labels {
+            self.push(&[label.len() as u8])?;
+            self.push(label.as_bytes())?;
+        }
+        self.push(&[0])
+    }
+
+    /// Write a record, the data is written by the closure
+    fn record(
+        &mut self,
+        labels: &[&str],
+        record_type: u16,
+        class: u16,
+        ttl: u32,
+        data: impl FnOnce(&mut Self) -> Result<(), ()>,
+    ) -> Result<(), ()> {
+        self.name(labels)?;
+        self.push(&record_type.to_be_bytes())?;
+        self.push(&class.to_be_bytes())?;
+        self.push(&ttl.to_be_bytes())?;
+        let length_at = self.packet.len();
+        self.push(&[0, 0])?;
+        data(self)?;
+        let length = (self.packet.len() - length_at - 2) as u16;
+        self.packet[length_at..length_at + 2].copy_from_slice(&length.to_be_bytes());
+        self.answers += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> heapless::Vec<u8, PACKET_SIZE> {
+        self.packet[6..8].copy_from_slice(&self.answers.to_be_bytes());
+        self.packet
+    }
+}
+
+/// Write the records announcing the charger: the service instance on the port of the HTTP
+/// server, with the serial, firmware version and model as TXT records, and the host address
+fn write_records(
+    response: &mut Response,
+    network: &NetworkStack,
+    host: &str,
+    ip: Ipv4Address,
+    query: Query,
+) -> Result<(), ()> {
+    let config = &network.app_config;
+    let instance = [host, SERVICE[0], SERVICE[1], SERVICE[2]];
+
+    if query == Query::ServiceTypes {
+        response.record(
+            &SERVICES_ENUMERATION,
+            TYPE_PTR,
+            CLASS_IN,
+            SERVICE_TTL_SECS,
+            |r| r.name(&SERVICE),
+        )?;
+    }
+    response.record(&SERVICE, TYPE_PTR, CLASS_IN, SERVICE_TTL_SECS, |r| {
+        r.name(&instance)
+    })?;
+    response.record(&instance, TYPE_SRV, CLASS_IN_FLUSH, HOST_TTL_SECS, |r| {
+        r.push(&[0, 0, 0, 0])?; // Priority and weight
+        r.push(&config.http_port.to_be_bytes())?;
+        r.name(&[host, "local"])
+    })?;
+    response.record(&instance, TYPE_TXT, CLASS_IN_FLUSH, SERVICE_TTL_SECS, |r| {
+        for (key, value) in [
+            ("serial", config.charger_serial),
+            ("version", version::FIRMWARE_VERSION),
+            ("model", config.charger_model),
+            ("path", "/"),
+        ] {
+            let mut entry = heapless::String::<96>::new();
+            let _ = write!(entry, "{key}={value}");
+            r.push(&[entry.len() as u8])?;
+            r.push(entry.as_bytes())?;
+        }
+        Ok(())
+    })?;
+    response.record(
+        &[host, "local"],
+        TYPE_A,
+        CLASS_IN_FLUSH,
+        HOST_TTL_SECS,
+        |r| r.push(&ip.octets()),
+    )
+}
+
+/// The response announcing the charger, None if it doesn't fit a packet
+fn charger_response(
+    network: &NetworkStack,
+    host: &str,
+    ip: Ipv4Address,
+    query: Query,
+) -> Option<heapless::Vec<u8, PACKET_SIZE>> {
+    let mut response = Response::new();
+    write_records(&mut response, network, host, ip, query).ok()?;
+    Some(response.finish())
+}
+
+async fn send(socket: &mut UdpSocket<'_>, response: Option<heapless::Vec<u8, PACKET_SIZE>>) {
+    let Some(response) = response else {
+        warn!("MDNS: Response too large");
+        return;
+    };
+    if let Err(e) = socket
+        .send_to(&response, (IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT))
+        .await
+    {
+        warn!("MDNS: Failed to send response: {e:?}");
+    }
+}
+
+/// Task answering mDNS queries, the charger is announced as `<serial>._ocpp-charger._tcp.local`
+/// on the port of the HTTP server, with the host name `<serial>.local`
+#[embassy_executor::task]
+pub async fn mdns_task(network: &'static NetworkStack) {
+    let host = host_label(network.app_config.charger_serial);
+    info!("TASK: Started mDNS Responder as {host}.local");
+
+    if let Err(e) = network.stack.join_multicast_group(MDNS_GROUP) {
+        warn!("MDNS: Failed to join the multicast group: {e:?}");
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0u8; PACKET_SIZE];
+    let mut socket = UdpSocket::new(
+        *network.stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(MDNS_PORT).is_err() {
+        warn!("MDNS: Failed to bind port {MDNS_PORT}");
+        return;
+    }
+
+    let mut announced_ip = None;
+    let mut packet = [0u8; PACKET_SIZE];
+    loop {
+        let ip = network.get_ip_address();
+        if let Some(ip) = ip.filter(|ip| Some(*ip) != announced_ip) {
+            // Announced twice, a second apart (RFC 6762 section 8.3)
+            for _ in 0..2 {
+                send(
+                    &mut socket,
+                    charger_response(network, &host, ip, Query::Charger),
+                )
+                .await;
+                Timer::after(Duration::from_secs(1)).await;
+            }
+            info!("MDNS: Announced {host}.local at {ip}");
+            announced_ip = Some(ip);
+        }
+
+        // Wake up now and then to announce a new address
+        match embassy_time::with_timeout(Duration::from_secs(10), socket.recv_from(&mut packet))
+            .await
+        {
+            Ok(Ok((len, _))) => {
+                if let (Some(ip), Some(query)) = (ip, parse_query(&packet[..len], &host)) {
+                    send(&mut socket, charger_response(network, &host, ip, query)).await;
+                }
+            }
+            Ok(Err(e)) => warn!("MDNS: Failed to receive: {e:?}"),
+            Err(_) => {}
+        }
+    }
+}
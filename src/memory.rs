@@ -0,0 +1,114 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::warn;
+
+/// Optional features that are disabled, one per allocation failure, so charging continues
+/// when the heap runs out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// The connection to the analytics broker and its buffers
+    Analytics,
+    /// The display pages besides the status page, the sessions page reads the history
+    DisplayPages,
+    /// The GetDiagnostics report
+    Diagnostics,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [
+        Feature::Analytics,
+        Feature::DisplayPages,
+        Feature::Diagnostics,
+    ];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "analytics" => Some(Self::Analytics),
+            "display_pages" => Some(Self::DisplayPages),
+            "diagnostics" => Some(Self::Diagnostics),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Analytics => "analytics",
+            Self::DisplayPages => "display_pages",
+            Self::Diagnostics => "diagnostics",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+/// Features disabled after an allocation failure, one bit per feature
+static DISABLED: AtomicU8 = AtomicU8::new(0);
+/// Allocation failures since boot
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+/// Comma separated features in the order they are disabled
+static SHED_ORDER: Mutex<CriticalSectionRawMutex, Cell<&'static str>> =
+    Mutex::new(Cell::new("analytics,display_pages,diagnostics"));
+
+/// Set the order in which optional features are disabled, unknown names are skipped
+pub fn init(shed_order: &'static str) {
+    SHED_ORDER.lock(|order| order.set(shed_order));
+}
+
+/// Whether an optional feature is still enabled
+pub fn is_enabled(feature: Feature) -> bool {
+    DISABLED.load(Ordering::Relaxed) & feature.bit() == 0
+}
+
+/// Allocation failures since boot
+pub fn failures() -> u32 {
+    FAILURES.load(Ordering::Relaxed)
+}
+
+/// Features disabled after allocation failures
+pub fn disabled() -> impl Iterator<Item = Feature> {
+    Feature::ALL
+        .into_iter()
+        .filter(|feature| !is_enabled(*feature))
+}
+
+/// Record that a subsystem failed to allocate, and disable the next optional feature of the
+/// shed order to free its memory. Returns the feature that was disabled, if any was left
+pub fn allocation_failed(subsystem: &str, size: usize) -> Option<Feature> {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        "MEM : {subsystem} failed to allocate {size} bytes, {} bytes free",
+        esp_alloc::HEAP.free()
+    );
+
+    let order = SHED_ORDER.lock(|order| order.get());
+    let feature = order
+        .split(',')
+        .filter_map(Feature::parse)
+        .find(|feature| is_enabled(*feature))?;
+    DISABLED.fetch_or(feature.bit(), Ordering::Relaxed);
+    warn!(
+        "MEM : Disabled {} to keep charging, until restart",
+        feature.as_str()
+    );
+    Some(feature)
+}
+
+/// Allocate a zeroed buffer without aborting when the heap has no free block that large, a
+/// failure is recorded for the subsystem
+pub fn try_alloc(subsystem: &str, size: usize) -> Result<Vec<u8>, &'static str> {
+    let mut buffer = Vec::new();
+    if buffer.try_reserve_exact(size).is_err() {
+        allocation_failed(subsystem, size);
+        return Err("Out of memory");
+    }
+    buffer.resize(size, 0);
+    Ok(buffer)
+}
@@ -0,0 +1,160 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{charger::Charger, info, smart_charging::CurrentLimits};
+
+/// Energy of the running session. Without a meter IC it's estimated from the current offered to
+/// the vehicle at the nominal supply voltage, a vehicle drawing less than it's offered makes it
+/// an upper bound
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    last_sample: Instant,
+    energy_mwh: u64,
+}
+
+static SESSION: Mutex<CriticalSectionRawMutex, Cell<Option<Session>>> = Mutex::new(Cell::new(None));
+
+/// Energy register in mWh, the energy of every session added up. It's kept in flash by
+/// the energy module and used as the meter values of the transactions
+static REGISTER_MWH: Mutex<CriticalSectionRawMutex, Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Energy counted by the meter IC in mWh since boot, without the estimates of the register
+static MEASURED_MWH: Mutex<CriticalSectionRawMutex, Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Supply voltage readings older than this are not used
+const VOLTAGE_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// Latest supply voltage reading and when it was received
+static SUPPLY_VOLTAGE: Mutex<CriticalSectionRawMutex, Cell<Option<(u16, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Measurement of a meter IC, after calibration
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub volts: f32,
+    pub amps: f32,
+    pub watts: f32,
+}
+
+/// Measurements older than this are not used, the energy is estimated again
+const MEASUREMENT_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// Latest measurement of the meter IC and when it was taken
+static MEASUREMENT: Mutex<CriticalSectionRawMutex, Cell<Option<(Measurement, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Latest measurement of the meter IC, None without a meter IC or a recent measurement
+pub fn measurement() -> Option<Measurement> {
+    MEASUREMENT
+        .lock(|measurement| measurement.get())
+        .filter(|(_, taken)| taken.elapsed() <= MEASUREMENT_MAX_AGE)
+        .map(|(measurement, _)| measurement)
+}
+
+/// Record a measurement of the meter IC with the energy it counted since the last one, the
+/// energy is added to the register and the running session
+pub fn record_measurement(measurement: Measurement, added_mwh: u64) {
+    MEASUREMENT.lock(|latest| latest.set(Some((measurement, Instant::now()))));
+    report_voltage(measurement.volts as u16);
+    REGISTER_MWH.lock(|register| register.set(register.get() + added_mwh));
+    MEASURED_MWH.lock(|measured| measured.set(measured.get() + added_mwh));
+    SESSION.lock(|session| {
+        if let Some(last) = session.get() {
+            session.set(Some(Session {
+                last_sample: Instant::now(),
+                energy_mwh: last.energy_mwh + added_mwh,
+            }));
+        }
+    });
+}
+
+/// Record a supply voltage reading of an external meter or the meter IC
+pub fn report_voltage(volts: u16) {
+    SUPPLY_VOLTAGE.lock(|voltage| voltage.set(Some((volts, Instant::now()))));
+}
+
+/// Latest supply voltage, None without a recent reading
+pub fn supply_voltage() -> Option<u16> {
+    SUPPLY_VOLTAGE
+        .lock(|voltage| voltage.get())
+        .filter(|(_, received)| received.elapsed() <= VOLTAGE_MAX_AGE)
+        .map(|(volts, _)| volts)
+}
+
+/// Energy of the running session, None while not charging
+pub fn energy_wh() -> Option<u64> {
+    SESSION.lock(|session| session.get().map(|session| session.energy_mwh / 1000))
+}
+
+/// Reading of the energy register in Wh
+pub fn register_wh() -> u64 {
+    REGISTER_MWH.lock(|register| register.get()) / 1000
+}
+
+/// Energy counted by the meter IC since boot in mWh, 0 without a meter IC
+pub fn measured_mwh() -> u64 {
+    MEASURED_MWH.lock(|measured| measured.get())
+}
+
+/// Set the energy register to the reading kept in flash, at boot
+pub fn restore_register(wh: u64) {
+    REGISTER_MWH.lock(|register| register.set(wh * 1000));
+}
+
+/// Add the energy at an offered power since the last sample, starts a session at 0
+fn sample(watts: u32) {
+    SESSION.lock(|session| {
+        let next = match session.get() {
+            Some(last) => {
+                // W x ms / 3600 is mWh
+                let added_mwh = u64::from(watts) * last.last_sample.elapsed().as_millis() / 3600;
+                REGISTER_MWH.lock(|register| register.set(register.get() + added_mwh));
+                Session {
+                    last_sample: Instant::now(),
+                    energy_mwh: last.energy_mwh + added_mwh,
+                }
+            }
+            None => Session {
+                last_sample: Instant::now(),
+                energy_mwh: 0,
+            },
+        };
+        session.set(Some(next));
+    });
+}
+
+/// Task to track the running session and estimate its energy every second, while the meter IC
+/// measures it the estimate is skipped
+#[embassy_executor::task]
+pub async fn meter_task(
+    charger: &'static Charger,
+    limits: &'static CurrentLimits,
+    supply_voltage: u16,
+    phases: u8,
+) {
+    info!("TASK: Started Energy Estimate at {supply_voltage}V, {phases} phase(s)");
+
+    loop {
+        Timer::after(Duration::from_secs(1)).await;
+
+        if charger.get_state().await.is_charging() {
+            if measurement().is_some() {
+                // The meter IC adds the energy, only start the session
+                SESSION.lock(|session| {
+                    if session.get().is_none() {
+                        session.set(Some(Session {
+                            last_sample: Instant::now(),
+                            energy_mwh: 0,
+                        }));
+                    }
+                });
+                continue;
+            }
+            let amps = limits.effective_limit().await;
+            sample(u32::from(amps) * u32::from(supply_voltage) * u32::from(phases));
+        } else {
+            SESSION.lock(|session| session.set(None));
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::{Duration, Timer};
+use embedded_hal::spi::SpiDevice;
+use embedded_io::{Read, ReadReady};
+
+use crate::{
+    config::Config,
+    info,
+    meter::{self, Measurement},
+    warn,
+};
+
+/// Interval the meter IC is read at, the HLW8032 sends a frame every 50ms and the UART buffers
+/// about 5 of them
+const READ_INTERVAL_MS: u64 = 100;
+/// Calibration factors are in thousandths
+const CALIBRATION_UNITY: f32 = 1000.0;
+
+/// Size of an HLW8032 frame
+const HLW_FRAME_SIZE: usize = 24;
+/// Second byte of every HLW8032 frame
+const HLW_CHECK: u8 = 0x5A;
+/// State byte of a chip whose calibration parameters are unusable
+const HLW_STATE_ERROR: u8 = 0xAA;
+/// Voltage coefficient of the common modules, a 1.88MΩ divider into 1kΩ
+const HLW_VOLTAGE_COEFFICIENT: f32 = 1.88;
+/// Current coefficient of a 1mΩ shunt
+const HLW_CURRENT_COEFFICIENT: f32 = 1.0;
+
+/// ATM90E32 registers
+const ATM_METER_EN: u16 = 0x00;
+const ATM_PL_CONST_H: u16 = 0x31;
+const ATM_PL_CONST_L: u16 = 0x32;
+const ATM_MMODE0: u16 = 0x33;
+const ATM_SOFT_RESET: u16 = 0x70;
+const ATM_CFG_REG_ACC_EN: u16 = 0x7F;
+const ATM_AP_ENERGY_T: u16 = 0x80;
+const ATM_PMEAN_T: u16 = 0xB0;
+const ATM_URMS_A: u16 = 0xD9;
+const ATM_IRMS_A: u16 = 0xDD;
+/// 50Hz, three phase four wire, all phases added up
+const ATM_MMODE0_50HZ: u16 = 0x0087;
+/// Watts per unit of the total mean active power
+const ATM_POWER_LSB_W: f32 = 4.0;
+
+/// Set while a meter IC reports measurements
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Whether a meter IC measures the energy, otherwise it's estimated from the offered current
+pub fn present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// Energy metering chips and the DIN rail meters read over Modbus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeterKind {
+    /// Single phase, streams its measurements over a UART at 4800 baud
+    Hlw8032,
+    /// Three phase, read over SPI
+    Atm90e32,
+    /// Eastron single phase DIN rail meter, see the modbus module
+    Sdm120,
+    /// Eastron three phase DIN rail meter
+    Sdm630,
+    /// Eastron three phase DIN rail meter, the compact one
+    Sdm72,
+}
+
+impl MeterKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "hlw8032" => Some(Self::Hlw8032),
+            "atm90e32" => Some(Self::Atm90e32),
+            "sdm120" => Some(Self::Sdm120),
+            "sdm630" => Some(Self::Sdm630),
+            "sdm72" => Some(Self::Sdm72),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hlw8032 => "HLW8032",
+            Self::Atm90e32 => "ATM90E32",
+            Self::Sdm120 => "SDM120",
+            Self::Sdm630 => "SDM630",
+            Self::Sdm72 => "SDM72",
+        }
+    }
+
+    /// Whether it's a meter on the RS-485 bus
+    pub fn is_modbus(&self) -> bool {
+        matches!(self, Self::Sdm120 | Self::Sdm630 | Self::Sdm72)
+    }
+}
+
+/// A measurement of a meter IC, before calibration
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub volts: f32,
+    pub amps: f32,
+    pub watts: f32,
+    /// Energy counted since the driver started, in mWh
+    pub energy_mwh: u64,
+}
+
+/// A meter IC, for the metering task to read without knowing the chip or the bus
+pub trait MeterIc {
+    fn kind(&self) -> MeterKind;
+    /// The latest measurement, None while the chip has no new one
+    fn read(&mut self) -> Result<Option<Reading>, &'static str>;
+}
+
+/// Calibration of the readings in thousandths, 1000 leaves a reading as it is
+#[derive(Debug, Clone, Copy)]
+pub struct Calibration {
+    pub voltage: u16,
+    pub current: u16,
+    /// Applies to the power and the energy
+    pub power: u16,
+}
+
+impl Calibration {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            voltage: config.meter_voltage_cal,
+            current: config.meter_current_cal,
+            power: config.meter_power_cal,
+        }
+    }
+
+    fn factor(thousandths: u16) -> f32 {
+        f32::from(thousandths) / CALIBRATION_UNITY
+    }
+
+    fn measurement(&self, reading: &Reading) -> Measurement {
+        Measurement {
+            volts: reading.volts * Self::factor(self.voltage),
+            amps: reading.amps * Self::factor(self.current),
+            watts: reading.watts * Self::factor(self.power),
+        }
+    }
+
+    fn energy_mwh(&self, mwh: u64) -> u64 {
+        mwh * u64::from(self.power) / 1000
+    }
+}
+
+/// HLW8032 on a UART, 4800 baud 8E1. Only its TX line is needed
+pub struct Hlw8032<U> {
+    uart: U,
+    frame: heapless::Vec<u8, HLW_FRAME_SIZE>,
+    /// Pulse count of the last frame, None before the first one
+    last_pulses: Option<u16>,
+    energy_mwh: f64,
+}
+
+impl<U: Read + ReadReady> Hlw8032<U> {
+    pub fn new(uart: U) -> Self {
+        Self {
+            uart,
+            frame: heapless::Vec::new(),
+            last_pulses: None,
+            energy_mwh: 0.0,
+        }
+    }
+
+    /// Add a received byte, returns a complete frame with a valid checksum
+    fn push(&mut self, byte: u8) -> Option<[u8; HLW_FRAME_SIZE]> {
+        let _ = self.frame.push(byte);
+        // Resynchronize on the check byte
+        if self.frame.len() == 2 && self.frame[1] != HLW_CHECK {
+            self.frame.remove(0);
+        }
+        if !self.frame.is_full() {
+            return None;
+        }
+        let frame: [u8; HLW_FRAME_SIZE] = self.frame.as_slice().try_into().ok()?;
+        self.frame.clear();
+        let checksum = frame[2..23]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        (checksum == frame[23]).then_some(frame)
+    }
+
+    fn parse(&mut self, frame: &[u8; HLW_FRAME_SIZE]) -> Result<Reading, &'static str> {
+        let state = frame[0];
+        if state == HLW_STATE_ERROR {
+            return Err("HLW8032 calibration parameters unusable");
+        }
+        let register = |at: usize| {
+            u32::from(frame[at]) << 16 | u32::from(frame[at + 1]) << 8 | u32::from(frame[at + 2])
+        };
+        // A period register overflows when its signal is too small to measure
+        let overflowed = |bit: u8| state & 0xF0 == 0xF0 && state & bit != 0;
+        let updated = frame[20];
+        let quotient = |parameter: u32, register: u32, fresh: bool| {
+            if fresh && register > 0 {
+                parameter as f32 / register as f32
+            } else {
+                0.0
+            }
+        };
+
+        let (voltage_parameter, current_parameter, power_parameter) =
+            (register(2), register(8), register(14));
+        let volts = quotient(
+            voltage_parameter,
+            register(5),
+            updated & 0x40 != 0 && !overflowed(0x08),
+        ) * HLW_VOLTAGE_COEFFICIENT;
+        let amps = quotient(
+            current_parameter,
+            register(11),
+            updated & 0x20 != 0 && !overflowed(0x04),
+        ) * HLW_CURRENT_COEFFICIENT;
+        let watts = quotient(
+            power_parameter,
+            register(17),
+            updated & 0x10 != 0 && !overflowed(0x02),
+        ) * HLW_VOLTAGE_COEFFICIENT
+            * HLW_CURRENT_COEFFICIENT;
+
+        // One PF pulse is the power parameter times the coefficients over 3.6e9 kWh
+        let pulses = u16::from_be_bytes([frame[21], frame[22]]);
+        if let Some(last) = self.last_pulses {
+            let mwh_per_pulse = f64::from(power_parameter)
+                * f64::from(HLW_VOLTAGE_COEFFICIENT * HLW_CURRENT_COEFFICIENT)
+                / 3_600_000.0;
+            self.energy_mwh += f64::from(pulses.wrapping_sub(last)) * mwh_per_pulse;
+        }
+        self.last_pulses = Some(pulses);
+
+        Ok(Reading {
+            volts,
+            amps,
+            watts,
+            energy_mwh: self.energy_mwh as u64,
+        })
+    }
+}
+
+impl<U: Read + ReadReady> MeterIc for Hlw8032<U> {
+    fn kind(&self) -> MeterKind {
+        MeterKind::Hlw8032
+    }
+
+    fn read(&mut self) -> Result<Option<Reading>, &'static str> {
+        let mut latest = None;
+        let mut buffer = [0u8; 32];
+        while self.uart.read_ready().map_err(|_| "HLW8032 UART error")? {
+            let received = self
+                .uart
+                .read(&mut buffer)
+                .map_err(|_| "HLW8032 UART error")?;
+            for byte in &buffer[..received] {
+                if let Some(frame) = self.push(*byte) {
+                    latest = Some(frame);
+                }
+            }
+        }
+        match latest {
+            Some(frame) => self.parse(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// ATM90E32 on an SPI bus, phase A's voltage and current with the power of all phases
+pub struct Atm90e32<S> {
+    spi: S,
+    /// mWh per unit of the energy register, a hundredth of a pulse of the meter constant
+    mwh_per_unit: f64,
+    energy_mwh: f64,
+}
+
+impl<S: SpiDevice> Atm90e32<S> {
+    /// Reset the chip and configure it for 50Hz with the meter constant in pulses per kWh
+    pub fn new(spi: S, meter_constant: u16) -> Result<Self, &'static str> {
+        let meter_constant = u32::from(meter_constant.max(1));
+        let mut atm = Self {
+            spi,
+            mwh_per_unit: 10_000.0 / f64::from(meter_constant),
+            energy_mwh: 0.0,
+        };
+        atm.write_register(ATM_SOFT_RESET, 0x789A)?;
+        atm.write_register(ATM_CFG_REG_ACC_EN, 0x55AA)?;
+        atm.write_register(ATM_METER_EN, 0x0001)?;
+        let pl_constant = 450_000_000_000u64 / u64::from(meter_constant);
+        atm.write_register(ATM_PL_CONST_H, (pl_constant >> 16) as u16)?;
+        atm.write_register(ATM_PL_CONST_L, pl_constant as u16)?;
+        atm.write_register(ATM_MMODE0, ATM_MMODE0_50HZ)?;
+        atm.write_register(ATM_CFG_REG_ACC_EN, 0x0000)?;
+        if atm.read_register(ATM_MMODE0)? != ATM_MMODE0_50HZ {
+            return Err("ATM90E32 not responding");
+        }
+        info!("MTR : ATM90E32 configured for {meter_constant} pulses per kWh");
+        Ok(atm)
+    }
+
+    fn write_register(&mut self, address: u16, value: u16) -> Result<(), &'static str> {
+        let [address_high, address_low] = address.to_be_bytes();
+        let [value_high, value_low] = value.to_be_bytes();
+        self.spi
+            .write(&[address_high, address_low, value_high, value_low])
+            .map_err(|_| "ATM90E32 SPI error")
+    }
+
+    fn read_register(&mut self, address: u16) -> Result<u16, &'static str> {
+        let [address_high, address_low] = (address | 0x8000).to_be_bytes();
+        let mut buffer = [address_high, address_low, 0, 0];
+        self.spi
+            .transfer_in_place(&mut buffer)
+            .map_err(|_| "ATM90E32 SPI error")?;
+        Ok(u16::from_be_bytes([buffer[2], buffer[3]]))
+    }
+}
+
+impl<S: SpiDevice> MeterIc for Atm90e32<S> {
+    fn kind(&self) -> MeterKind {
+        MeterKind::Atm90e32
+    }
+
+    fn read(&mut self) -> Result<Option<Reading>, &'static str> {
+        let volts = f32::from(self.read_register(ATM_URMS_A)?) / 100.0;
+        let amps = f32::from(self.read_register(ATM_IRMS_A)?) / 1000.0;
+        let watts = f32::from(self.read_register(ATM_PMEAN_T)? as i16) * ATM_POWER_LSB_W;
+        // The energy register is cleared when it's read
+        let units = self.read_register(ATM_AP_ENERGY_T)?;
+        self.energy_mwh += f64::from(units) * self.mwh_per_unit;
+        Ok(Some(Reading {
+            volts,
+            amps,
+            watts: watts.max(0.0),
+            energy_mwh: self.energy_mwh as u64,
+        }))
+    }
+}
+
+/// Task to read the meter IC, its measurements replace the estimate of the energy for the meter
+/// values, the transactions and the display
+#[embassy_executor::task]
+pub async fn metering_task(meter: &'static mut dyn MeterIc, calibration: Calibration) {
+    info!("TASK: Started Meter IC {}", meter.kind().as_str());
+
+    let mut last_energy_mwh: Option<u64> = None;
+    let mut failing = false;
+
+    loop {
+        Timer::after(Duration::from_millis(READ_INTERVAL_MS)).await;
+
+        match meter.read() {
+            Ok(Some(reading)) => {
+                if failing {
+                    info!("MTR : {} reading again", meter.kind().as_str());
+                    failing = false;
+                }
+                let added_mwh = last_energy_mwh
+                    .map(|last| reading.energy_mwh.saturating_sub(last))
+                    .unwrap_or(0);
+                last_energy_mwh = Some(reading.energy_mwh);
+                PRESENT.store(true, Ordering::Relaxed);
+                meter::record_measurement(
+                    calibration.measurement(&reading),
+                    calibration.energy_mwh(added_mwh),
+                );
+            }
+            Ok(None) => {}
+            Err(e) if !failing => {
+                warn!("MTR : {}: {e}", meter.kind().as_str());
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_time::{Duration, Instant};
+use embedded_hal::digital::OutputPin;
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::info;
+use crate::metering::{MeterIc, MeterKind, Reading};
+
+/// Time between the polls of the meter, a poll reads every quantity of its register map
+const POLL_INTERVAL_MS: u64 = 1000;
+/// Time the meter has to answer a request, a reply takes about 40ms at 2400 baud
+const RESPONSE_TIMEOUT_MS: u64 = 300;
+/// Read input registers, the function the SDM meters report their measurements with
+const READ_INPUT_REGISTERS: u8 = 0x04;
+/// Set in the function code of an exception response
+const EXCEPTION_FLAG: u8 = 0x80;
+/// A float is two registers, the reply is the address, function, byte count, data and crc
+const FLOAT_RESPONSE_SIZE: usize = 3 + 4 + 2;
+/// Address, function, exception code and crc
+const EXCEPTION_RESPONSE_SIZE: usize = 5;
+
+static REQUESTS: AtomicU32 = AtomicU32::new(0);
+static TIMEOUTS: AtomicU32 = AtomicU32::new(0);
+static CRC_ERRORS: AtomicU32 = AtomicU32::new(0);
+static EXCEPTIONS: AtomicU32 = AtomicU32::new(0);
+
+/// Requests to the Modbus meter since boot and how they failed
+pub struct ModbusStats {
+    pub requests: u32,
+    pub timeouts: u32,
+    pub crc_errors: u32,
+    pub exceptions: u32,
+}
+
+pub fn stats() -> ModbusStats {
+    ModbusStats {
+        requests: REQUESTS.load(Ordering::Relaxed),
+        timeouts: TIMEOUTS.load(Ordering::Relaxed),
+        crc_errors: CRC_ERRORS.load(Ordering::Relaxed),
+        exceptions: EXCEPTIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// Modbus RTU crc, sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xA001 & mask);
+        }
+    }
+    crc
+}
+
+/// Quantities read from the meter, in the order they are polled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quantity {
+    Volts,
+    Amps,
+    Watts,
+    EnergyKwh,
+}
+
+impl Quantity {
+    const ALL: [Quantity; 4] = [
+        Quantity::Volts,
+        Quantity::Amps,
+        Quantity::Watts,
+        Quantity::EnergyKwh,
+    ];
+}
+
+/// Input registers of the quantities, each an IEEE 754 float in two registers, high word first
+#[derive(Debug, Clone, Copy)]
+struct RegisterMap {
+    /// Voltage of phase 1
+    volts: u16,
+    /// Current of phase 1
+    amps: u16,
+    /// Active power of all phases
+    watts: u16,
+    /// Imported active energy of all phases
+    energy_kwh: u16,
+}
+
+impl RegisterMap {
+    fn of(kind: MeterKind) -> Option<Self> {
+        match kind {
+            MeterKind::Sdm120 => Some(Self {
+                volts: 0x0000,
+                amps: 0x0006,
+                watts: 0x000C,
+                energy_kwh: 0x0048,
+            }),
+            // The three phase meters share the map of the SDM630
+            MeterKind::Sdm630 | MeterKind::Sdm72 => Some(Self {
+                volts: 0x0000,
+                amps: 0x0006,
+                watts: 0x0034,
+                energy_kwh: 0x0048,
+            }),
+            MeterKind::Hlw8032 | MeterKind::Atm90e32 => None,
+        }
+    }
+
+    fn register(&self, quantity: Quantity) -> u16 {
+        match quantity {
+            Quantity::Volts => self.volts,
+            Quantity::Amps => self.amps,
+            Quantity::Watts => self.watts,
+            Quantity::EnergyKwh => self.energy_kwh,
+        }
+    }
+}
+
+/// A DIN rail kWh meter on an RS-485 bus, read over a UART through a half duplex transceiver.
+/// The transceiver's driver enable is high while a request is sent
+pub struct ModbusMeter<U, D> {
+    uart: U,
+    driver_enable: D,
+    kind: MeterKind,
+    map: RegisterMap,
+    address: u8,
+    /// Quantity of the request in flight and when it was sent
+    pending: Option<(usize, Instant)>,
+    response: heapless::Vec<u8, FLOAT_RESPONSE_SIZE>,
+    values: [f32; Quantity::ALL.len()],
+    last_poll: Option<Instant>,
+    /// Register of the meter at the first poll, the energy is counted from it
+    first_energy_kwh: Option<f32>,
+}
+
+impl<U: Read + ReadReady + Write, D: OutputPin> ModbusMeter<U, D> {
+    /// A meter at a Modbus address, None when the kind isn't a Modbus meter
+    pub fn new(uart: U, driver_enable: D, kind: MeterKind, address: u8) -> Option<Self> {
+        let map = RegisterMap::of(kind)?;
+        info!("MTR : {} at Modbus address {address}", kind.as_str());
+        Some(Self {
+            uart,
+            driver_enable,
+            kind,
+            map,
+            address,
+            pending: None,
+            response: heapless::Vec::new(),
+            values: [0.0; Quantity::ALL.len()],
+            last_poll: None,
+            first_energy_kwh: None,
+        })
+    }
+
+    /// Send the request for the two registers of a quantity
+    fn request(&mut self, index: usize) -> Result<(), &'static str> {
+        let register = self.map.register(Quantity::ALL[index]);
+        let [register_high, register_low] = register.to_be_bytes();
+        let mut frame = [
+            self.address,
+            READ_INPUT_REGISTERS,
+            register_high,
+            register_low,
+            0,
+            2,
+            0,
+            0,
+        ];
+        let [crc_low, crc_high] = crc16(&frame[..6]).to_le_bytes();
+        frame[6] = crc_low;
+        frame[7] = crc_high;
+
+        // Bytes left from an earlier reply would be taken for the start of this one
+        let mut stale = [0u8; 16];
+        while self.uart.read_ready().map_err(|_| "Modbus UART error")? {
+            self.uart
+                .read(&mut stale)
+                .map_err(|_| "Modbus UART error")?;
+        }
+        self.response.clear();
+
+        let _ = self.driver_enable.set_high();
+        let sent = self.uart.write_all(&frame).and_then(|()| self.uart.flush());
+        let _ = self.driver_enable.set_low();
+        sent.map_err(|_| "Modbus UART error")?;
+
+        REQUESTS.fetch_add(1, Ordering::Relaxed);
+        self.pending = Some((index, Instant::now()));
+        Ok(())
+    }
+
+    /// Take the received bytes, the value of the quantity once the reply is complete
+    fn receive(&mut self) -> Result<Option<f32>, &'static str> {
+        let mut buffer = [0u8; FLOAT_RESPONSE_SIZE];
+        while !self.response.is_full() && self.uart.read_ready().map_err(|_| "Modbus UART error")? {
+            let room = FLOAT_RESPONSE_SIZE - self.response.len();
+            let received = self
+                .uart
+                .read(&mut buffer[..room])
+                .map_err(|_| "Modbus UART error")?;
+            let _ = self.response.extend_from_slice(&buffer[..received]);
+        }
+
+        let response = self.response.as_slice();
+        let expected = match response.get(1) {
+            Some(function) if function & EXCEPTION_FLAG != 0 => EXCEPTION_RESPONSE_SIZE,
+            Some(_) => FLOAT_RESPONSE_SIZE,
+            None => return Ok(None),
+        };
+        if response.len() < expected {
+            return Ok(None);
+        }
+        let (frame, crc) = response[..expected].split_at(expected - 2);
+        if crc16(frame).to_le_bytes() != [crc[0], crc[1]] {
+            CRC_ERRORS.fetch_add(1, Ordering::Relaxed);
+            return Err("Modbus reply with a bad crc");
+        }
+        if frame[0] != self.address {
+            return Err("Modbus reply from another address");
+        }
+        if frame[1] & EXCEPTION_FLAG != 0 {
+            EXCEPTIONS.fetch_add(1, Ordering::Relaxed);
+            return Err("Modbus meter answered with an exception");
+        }
+        if frame[1] != READ_INPUT_REGISTERS || frame[2] != 4 {
+            return Err("Unexpected Modbus reply");
+        }
+        Ok(Some(f32::from_be_bytes([
+            frame[3], frame[4], frame[5], frame[6],
+        ])))
+    }
+}
+
+impl<U: Read + ReadReady + Write, D: OutputPin> MeterIc for ModbusMeter<U, D> {
+    fn kind(&self) -> MeterKind {
+        self.kind
+    }
+
+    /// One request per call, the reading is complete when the last quantity of a poll arrived
+    fn read(&mut self) -> Result<Option<Reading>, &'static str> {
+        let Some((index, sent)) = self.pending else {
+            let due = self
+                .last_poll
+                .is_none_or(|last| last.elapsed() >= Duration::from_millis(POLL_INTERVAL_MS));
+            if due {
+                self.last_poll = Some(Instant::now());
+                self.request(0)?;
+            }
+            return Ok(None);
+        };
+
+        let value = match self.receive() {
+            Ok(Some(value)) => value,
+            Ok(None) if sent.elapsed() < Duration::from_millis(RESPONSE_TIMEOUT_MS) => {
+                return Ok(None)
+            }
+            Ok(None) => {
+                // The poll is abandoned, the next one starts over
+                self.pending = None;
+                TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                return Err("Modbus meter not responding");
+            }
+            Err(e) => {
+                self.pending = None;
+                return Err(e);
+            }
+        };
+        self.values[index] = value;
+
+        if index + 1 < Quantity::ALL.len() {
+            self.request(index + 1)?;
+            return Ok(None);
+        }
+        self.pending = None;
+
+        let [volts, amps, watts, energy_kwh] = self.values;
+        let first_energy_kwh = *self.first_energy_kwh.get_or_insert(energy_kwh);
+        let energy_mwh = (f64::from(energy_kwh) - f64::from(first_energy_kwh)) * 1_000_000.0;
+        Ok(Some(Reading {
+            volts,
+            amps,
+            watts: watts.max(0.0),
+            energy_mwh: energy_mwh.max(0.0) as u64,
+        }))
+    }
+}
@@ -1,27 +1,617 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use embassy_futures::select::{select, Either};
 use embassy_net::tcp::TcpSocket;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration, Timer};
-use log::{info, warn};
-use rust_mqtt::{client::client::MqttClient, utils::rng_generator::CountingRng};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use rust_mqtt::{
+    client::client::MqttClient, packet::v5::reason_codes::ReasonCode,
+    utils::rng_generator::CountingRng,
+};
 
-use crate::network::NetworkStack;
+use crate::{
+    config::Config,
+    error, info,
+    memory::{self, Feature},
+    mk_static,
+    network::NetworkStack,
+    ready::{self, Subsystem},
+    telemetry, warn,
+    watchdog::{self, Monitored},
+};
 
-/// Message queues for MQTT messages
-pub static MQTT_SEND_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 2048>, 5> =
-    Channel::new();
+/// Outbound OCPP messages, sent highest priority first
+pub static MQTT_SEND_QUEUE: OutboundQueue = OutboundQueue::new();
 
+/// Inbound OCPP messages, from both the system and the ocpp topic
 pub static MQTT_RECEIVE_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 2048>, 5> =
     Channel::new();
 
-/// Task to handle MQTT client operations
+/// Device metrics for the telemetry topic, kept apart from the OCPP messages
+pub static MQTT_TELEMETRY_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 256>, 1> =
+    Channel::new();
+
+/// Inbound messages from the cmd topic
+pub static MQTT_CMD_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 2048>, 2> =
+    Channel::new();
+
+/// Inbound messages from the ota topic
+pub static MQTT_OTA_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 2048>, 2> =
+    Channel::new();
+
+/// Announcements of the chargers of the site from the load topic, this charger's own included
+pub static MQTT_LOAD_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 128>, 8> =
+    Channel::new();
+
+/// Readings of the power at the grid connection from the grid topic, for the solar charging
+pub static MQTT_GRID_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 128>, 2> =
+    Channel::new();
+
+/// Announcement of this charger for the load topic, only the latest is kept
+pub static MQTT_ANNOUNCE_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 128>, 1> =
+    Channel::new();
+
+/// A batch of forwarded warnings and errors for the log topic
+pub static MQTT_LOG_CHANNEL: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 1024>, 1> =
+    Channel::new();
+
+/// RAM of the message queues to and from the broker, counted against the channels budget
+pub const RAM: usize = size_of_val(&MQTT_SEND_QUEUE)
+    + size_of_val(&MQTT_RECEIVE_CHANNEL)
+    + size_of_val(&MQTT_TELEMETRY_CHANNEL)
+    + size_of_val(&MQTT_CMD_CHANNEL)
+    + size_of_val(&MQTT_OTA_CHANNEL)
+    + size_of_val(&MQTT_LOAD_CHANNEL)
+    + size_of_val(&MQTT_GRID_CHANNEL)
+    + size_of_val(&MQTT_ANNOUNCE_CHANNEL)
+    + size_of_val(&MQTT_LOG_CHANNEL);
+
+/// Result of each connection attempt of the client task, true when connected
+pub static CONNECTION_SIGNAL: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Delay before retrying after a failed connection attempt
+const CONNECT_RETRY_SECS: u64 = 10;
+
+/// Size of each of the socket and client buffers of a broker connection
+const SESSION_BUFFER_SIZE: usize = 2048;
+const SESSION_BUFFER_COUNT: usize = 4;
+/// Heap taken by the buffers of a connection, counted against the connection budget
+pub const CONNECTION_RAM: usize = SESSION_BUFFER_SIZE * SESSION_BUFFER_COUNT;
+
+/// Socket rx/tx and client write/receive buffers of one broker connection, taken from the heap
+/// only while connected and freed on disconnect
+struct SessionBuffers {
+    block: Vec<u8>,
+}
+
+impl SessionBuffers {
+    /// Allocate the buffers as one block, so a connection never leaves holes between other
+    /// allocations. Fails instead of panicking when the heap has no free block that large
+    fn allocate() -> Result<Self, &'static str> {
+        let size = CONNECTION_RAM;
+        let block = memory::try_alloc("MQTT", size)
+            .map_err(|_| "No free heap block for the connection buffers")?;
+        let used = telemetry::record_heap_usage();
+        info!("MQTT: Allocated {size} bytes of connection buffers, heap used {used} bytes");
+        Ok(Self { block })
+    }
+
+    fn split(&mut self) -> (&mut [u8], &mut [u8], &mut [u8], &mut [u8]) {
+        let (rx, rest) = self.block.split_at_mut(SESSION_BUFFER_SIZE);
+        let (tx, rest) = rest.split_at_mut(SESSION_BUFFER_SIZE);
+        let (write, recv) = rest.split_at_mut(SESSION_BUFFER_SIZE);
+        (rx, tx, write, recv)
+    }
+}
+
+impl Drop for SessionBuffers {
+    fn drop(&mut self) {
+        info!("MQTT: Released the connection buffers");
+    }
+}
+
+const SEND_QUEUE_SIZE: usize = 5;
+
+pub type OutboundMessage = heapless::Vec<u8, 2048>;
+
+/// Priority class of an outbound OCPP message, a full queue drops lower classes first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Heartbeat and StatusNotification, the next one supersedes them
+    Low,
+    /// Responses to the central system and other notifications
+    Normal,
+    /// StartTransaction, StopTransaction and Authorize, never dropped for a lower class
+    Critical,
+}
+
+/// Bounded queue of outbound messages with priority classes, first in first out within a class
+pub struct OutboundQueue {
+    messages: Mutex<
+        CriticalSectionRawMutex,
+        RefCell<heapless::Vec<(Priority, OutboundMessage), SEND_QUEUE_SIZE>>,
+    >,
+}
+
+impl Default for OutboundQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutboundQueue {
+    pub const fn new() -> Self {
+        Self {
+            messages: Mutex::new(RefCell::new(heapless::Vec::new())),
+        }
+    }
+
+    /// Queue a message, when the queue is full the oldest message of the lowest class below
+    /// this one is dropped. Returns false if the queue is full with messages of the same or a
+    /// higher class
+    pub fn enqueue(&self, priority: Priority, message: OutboundMessage) -> bool {
+        self.insert(priority, message, false)
+    }
+
+    /// Put a message that failed to send back in front of its class
+    pub fn requeue(&self, priority: Priority, message: OutboundMessage) -> bool {
+        self.insert(priority, message, true)
+    }
+
+    /// Take the oldest message of the highest class
+    pub fn dequeue(&self) -> Option<(Priority, OutboundMessage)> {
+        self.messages.lock(|messages| {
+            let mut messages = messages.borrow_mut();
+            let highest = messages.iter().map(|(priority, _)| *priority).max()?;
+            let index = messages
+                .iter()
+                .position(|(priority, _)| *priority == highest)?;
+            Some(messages.remove(index))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock(|messages| messages.borrow().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.messages.lock(|messages| messages.borrow().is_full())
+    }
+
+    fn insert(&self, priority: Priority, message: OutboundMessage, front: bool) -> bool {
+        self.messages.lock(|messages| {
+            let mut messages = messages.borrow_mut();
+            if messages.is_full() {
+                let Some(lowest) = messages
+                    .iter()
+                    .map(|(queued, _)| *queued)
+                    .filter(|queued| *queued < priority)
+                    .min()
+                else {
+                    return false;
+                };
+                if let Some(index) = messages.iter().position(|(queued, _)| *queued == lowest) {
+                    messages.remove(index);
+                    MESSAGES_DROPPED.fetch_add(1, Ordering::Relaxed);
+                    warn!("MQTT: Send queue full, dropped a {lowest:?} message for a {priority:?} one");
+                }
+            }
+            let index = if front {
+                messages
+                    .iter()
+                    .position(|(queued, _)| *queued == priority)
+                    .unwrap_or(messages.len())
+            } else {
+                messages.len()
+            };
+            messages.insert(index, (priority, message)).is_ok()
+        })
+    }
+}
+
+/// Message counters for diagnostics
+static MESSAGES_SENT: AtomicU32 = AtomicU32::new(0);
+static SEND_FAILURES: AtomicU32 = AtomicU32::new(0);
+static MESSAGES_RECEIVED: AtomicU32 = AtomicU32::new(0);
+static MESSAGES_DROPPED: AtomicU32 = AtomicU32::new(0);
+static BROKER_DISCONNECTS: AtomicU32 = AtomicU32::new(0);
+static CLIENT_ID_CONFLICTS: AtomicU32 = AtomicU32::new(0);
+static SEND_STALLS: AtomicU32 = AtomicU32::new(0);
+/// Whether the OCPP client is connected to the broker and sending
+static SESSION_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Ends the session of the OCPP client, which then reconnects
+static CLIENT_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Uptime in ms until which publishes are held, wrapping
+static TX_HOLD_UNTIL_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Hold the publishes on the OCPP broker for a while, e.g. while the relay coil's inrush dips the
+/// supply. Messages stay queued, receiving goes on
+pub fn hold_tx(duration: Duration) {
+    let until = (Instant::now() + duration).as_millis() as u32;
+    TX_HOLD_UNTIL_MS.store(until, Ordering::Relaxed);
+}
+
+fn tx_held() -> bool {
+    let now = Instant::now().as_millis() as u32;
+    (TX_HOLD_UNTIL_MS.load(Ordering::Relaxed).wrapping_sub(now) as i32) > 0
+}
+
+/// Whether the OCPP client has a session with the broker
+pub fn is_session_active() -> bool {
+    SESSION_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Tear down the session of the OCPP client when its send queue stalled, the queued messages
+/// are kept and sent after it reconnects
+pub fn reset_client() {
+    SEND_STALLS.fetch_add(1, Ordering::Relaxed);
+    CLIENT_RESET.signal(());
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MqttStats {
+    pub sent: u32,
+    pub send_failures: u32,
+    pub received: u32,
+    pub dropped: u32,
+    pub broker_disconnects: u32,
+    pub client_id_conflicts: u32,
+    pub send_stalls: u32, // Sessions torn down for a send queue that stayed full
+}
+
+pub fn stats() -> MqttStats {
+    MqttStats {
+        sent: MESSAGES_SENT.load(Ordering::Relaxed),
+        send_failures: SEND_FAILURES.load(Ordering::Relaxed),
+        received: MESSAGES_RECEIVED.load(Ordering::Relaxed),
+        dropped: MESSAGES_DROPPED.load(Ordering::Relaxed),
+        broker_disconnects: BROKER_DISCONNECTS.load(Ordering::Relaxed),
+        client_id_conflicts: CLIENT_ID_CONFLICTS.load(Ordering::Relaxed),
+        send_stalls: SEND_STALLS.load(Ordering::Relaxed),
+    }
+}
+
+/// Why the broker closed the connection, from the reason code of its DISCONNECT packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerDisconnect {
+    /// Another client connected with the same client id
+    SessionTakenOver,
+    /// The broker limits the connections or messages of this client
+    Throttled(ReasonCode),
+    /// The broker refuses this client, reconnecting won't help
+    Refused(ReasonCode),
+    /// The broker went away or asked the client to reconnect
+    ServerGone(ReasonCode),
+    /// Not the broker: the send queue stayed full and the watchdog tore the session down
+    SendStalled,
+}
+
+impl BrokerDisconnect {
+    /// Classify a reason code, None for errors the client raised itself
+    pub fn from_reason_code(code: ReasonCode) -> Option<Self> {
+        match code {
+            ReasonCode::SessionTakeOver => Some(Self::SessionTakenOver),
+            ReasonCode::QuotaExceeded
+            | ReasonCode::ConnectionRateExceeded
+            | ReasonCode::MessageRateTooHigh
+            | ReasonCode::ServerBusy => Some(Self::Throttled(code)),
+            ReasonCode::Banned | ReasonCode::NotAuthorized | ReasonCode::BadUserNameOrPassword => {
+                Some(Self::Refused(code))
+            }
+            ReasonCode::ServerShuttingDown
+            | ReasonCode::ServerUnavailable
+            | ReasonCode::ServerMoved
+            | ReasonCode::UseAnotherServer
+            | ReasonCode::AdministrativeAction
+            | ReasonCode::KeepAliveTimeout
+            | ReasonCode::MaximumConnectTime => Some(Self::ServerGone(code)),
+            _ => None,
+        }
+    }
+
+    /// Delay before reconnecting, None to stop reconnecting until restart
+    pub fn reconnect_delay(&self) -> Option<Duration> {
+        match self {
+            // Reconnecting right away would take the session back and start a storm
+            Self::SessionTakenOver => Some(Duration::from_secs(300)),
+            Self::Throttled(_) => Some(Duration::from_secs(60)),
+            Self::Refused(_) => None,
+            Self::ServerGone(_) | Self::SendStalled => Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// Topics the charger subscribes to, each with its own handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundTopic {
+    System,
+    Ocpp,
+    Cmd,
+    Ota,
+    /// Shared by the chargers of the site, only subscribed with load management enabled
+    Load,
+    /// Power at the grid connection, only subscribed with solar charging enabled
+    Grid,
+}
+
+impl InboundTopic {
+    pub const ALL: [InboundTopic; 6] = [
+        InboundTopic::System,
+        InboundTopic::Ocpp,
+        InboundTopic::Cmd,
+        InboundTopic::Ota,
+        InboundTopic::Load,
+        InboundTopic::Grid,
+    ];
+
+    pub fn topic(&self, config: &Config) -> heapless::String<64> {
+        match self {
+            Self::System => config.system_topic(),
+            Self::Ocpp => config.ocpp_topic(),
+            Self::Cmd => config.cmd_topic(),
+            Self::Ota => config.ota_topic(),
+            Self::Load => config.load_topic(),
+            Self::Grid => config.grid_topic(),
+        }
+    }
+
+    /// Whether the charger subscribes to the topic with this configuration
+    pub fn is_subscribed(&self, config: &Config) -> bool {
+        match self {
+            Self::Load => config.load_management_enabled,
+            Self::Grid => config.solar_enabled,
+            _ => true,
+        }
+    }
+
+    /// Find the inbound topic matching the topic name of a received message
+    pub fn from_topic(config: &Config, topic: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .filter(|inbound| inbound.is_subscribed(config))
+            .find(|inbound| inbound.topic(config).as_str() == topic)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::Ocpp => "ocpp",
+            Self::Cmd => "cmd",
+            Self::Ota => "ota",
+            Self::Load => "load",
+            Self::Grid => "grid",
+        }
+    }
+}
+
+/// Hand a received message to the handler of its topic
+fn route_message(topic: InboundTopic, message: heapless::Vec<u8, 2048>) {
+    let delivered = match topic {
+        InboundTopic::System | InboundTopic::Ocpp => MQTT_RECEIVE_CHANNEL.try_send(message).is_ok(),
+        InboundTopic::Cmd => MQTT_CMD_CHANNEL.try_send(message).is_ok(),
+        InboundTopic::Ota => MQTT_OTA_CHANNEL.try_send(message).is_ok(),
+        // Announcements and readings are small, a larger message isn't one
+        InboundTopic::Load | InboundTopic::Grid => match heapless::Vec::from_slice(&message) {
+            Ok(small) if topic == InboundTopic::Load => MQTT_LOAD_CHANNEL.try_send(small).is_ok(),
+            Ok(small) => MQTT_GRID_CHANNEL.try_send(small).is_ok(),
+            Err(_) => {
+                warn!(
+                    "MQTT: Message on the {} topic too large, ignoring",
+                    topic.as_str()
+                );
+                return;
+            }
+        },
+    };
+    // Use try_send to avoid blocking the client task if a handler is falling behind
+    if !delivered {
+        warn!(
+            "MQTT: Receive channel for {} topic is full, dropping message",
+            topic.as_str()
+        );
+    }
+}
+
+/// Broker a client task keeps a connection to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    /// OCPP messages and commands, and telemetry when there is no analytics broker
+    Ocpp,
+    /// Telemetry only, connects with its own client id
+    Analytics(&'static str),
+}
+
+impl Endpoint {
+    fn client_id(&self, network: &NetworkStack) -> &'static str {
+        match self {
+            Self::Ocpp => network.app_config.mqtt_client_id,
+            Self::Analytics(client_id) => client_id,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ocpp => "OCPP",
+            Self::Analytics(_) => "analytics",
+        }
+    }
+
+    /// Only the OCPP connection is reported to the tasks waiting for the broker
+    fn signal_connection(&self, connected: bool) {
+        if *self == Self::Ocpp {
+            CONNECTION_SIGNAL.signal(connected);
+            if connected {
+                ready::set(Subsystem::Mqtt);
+            }
+        }
+    }
+}
+
+/// Task to connect to the broker and handle MQTT client operations, reconnects when the
+/// broker closes the connection
 #[embassy_executor::task]
-pub async fn mqtt_client_task(
-    network: &'static NetworkStack,
-    client: &'static mut MqttClient<'static, TcpSocket<'static>, 5, CountingRng>,
-) {
+pub async fn mqtt_client_task(network: &'static NetworkStack) {
     info!("TASK: Started MQTT Client (Send/Receive)");
+    keep_connected(network, Endpoint::Ocpp).await;
+}
+
+/// Task to publish the telemetry to the analytics broker, so the OCPP broker doesn't carry it
+#[embassy_executor::task]
+pub async fn analytics_client_task(network: &'static NetworkStack) {
+    info!("TASK: Started Analytics MQTT Client");
+    let client_id = mk_static!(
+        heapless::String<64>,
+        network.app_config.analytics_client_id()
+    );
+    keep_connected(network, Endpoint::Analytics(client_id)).await;
+}
+
+/// Run sessions with the broker of the endpoint, reconnecting after the delay the broker's
+/// disconnect asks for. Returns when the broker refuses the charger
+async fn keep_connected(network: &'static NetworkStack, endpoint: Endpoint) {
+    // The OCPP client carries the transactions, it's the one the watchdog looks after
+    let monitored = matches!(endpoint, Endpoint::Ocpp);
+    loop {
+        // The analytics connection is the first to go when the heap runs out
+        if matches!(endpoint, Endpoint::Analytics(_)) && !memory::is_enabled(Feature::Analytics) {
+            warn!("MQTT: Analytics disabled after running out of memory, disconnecting");
+            return;
+        }
+        if monitored {
+            watchdog::check_in(Monitored::MqttClient);
+        }
+        // The buffers are dropped at the end of the session, before waiting to reconnect
+        let delay = match SessionBuffers::allocate() {
+            Ok(mut buffers) => match run_session(network, endpoint, &mut buffers).await {
+                Some(delay) => delay,
+                None => {
+                    if monitored {
+                        watchdog::idle(Monitored::MqttClient);
+                    }
+                    return;
+                }
+            },
+            Err(e) => {
+                warn!("MQTT: {e}");
+                endpoint.signal_connection(false);
+                Duration::from_secs(CONNECT_RETRY_SECS)
+            }
+        };
+
+        info!(
+            "MQTT: Reconnecting to the {} broker in {}s",
+            endpoint.as_str(),
+            delay.as_secs()
+        );
+        if monitored {
+            watchdog::idle(Monitored::MqttClient);
+        }
+        Timer::after(delay).await;
+    }
+}
+
+/// Connect and run the client until the connection ends, returns the delay before reconnecting
+/// or None to stop reconnecting
+async fn run_session(
+    network: &'static NetworkStack,
+    endpoint: Endpoint,
+    buffers: &mut SessionBuffers,
+) -> Option<Duration> {
+    let (rx_buffer, tx_buffer, write_buffer, recv_buffer) = buffers.split();
+    let result = match endpoint {
+        Endpoint::Ocpp => {
+            network
+                .create_mqtt_client(rx_buffer, tx_buffer, write_buffer, recv_buffer)
+                .await
+        }
+        Endpoint::Analytics(client_id) => {
+            network
+                .create_analytics_client(client_id, rx_buffer, tx_buffer, write_buffer, recv_buffer)
+                .await
+        }
+    };
+    match result {
+        Ok(mut client) => {
+            endpoint.signal_connection(true);
+            let disconnect = match endpoint {
+                Endpoint::Ocpp => {
+                    CLIENT_RESET.reset();
+                    SESSION_ACTIVE.store(true, Ordering::Relaxed);
+                    // A client stuck in a send is dropped along with its session
+                    let disconnect =
+                        match select(run_client(network, &mut client), CLIENT_RESET.wait()).await {
+                            Either::First(disconnect) => disconnect,
+                            Either::Second(()) => BrokerDisconnect::SendStalled,
+                        };
+                    SESSION_ACTIVE.store(false, Ordering::Relaxed);
+                    disconnect
+                }
+                Endpoint::Analytics(_) => {
+                    run_analytics_client(network, endpoint, &mut client).await
+                }
+            };
+            let delay = disconnect.reconnect_delay();
+            if delay.is_none() {
+                error!(
+                    "MQTT: {} broker refused the charger ({disconnect:?}), not reconnecting",
+                    endpoint.as_str()
+                );
+            }
+            delay
+        }
+        Err(e) => {
+            warn!(
+                "MQTT: Failed to connect to the {} broker: {e:?}",
+                endpoint.as_str()
+            );
+            endpoint.signal_connection(false);
+            Some(Duration::from_secs(CONNECT_RETRY_SECS))
+        }
+    }
+}
+
+/// Classify an error of the client, Some when the broker closed the connection
+fn broker_closed(
+    network: &NetworkStack,
+    endpoint: Endpoint,
+    code: ReasonCode,
+) -> Option<BrokerDisconnect> {
+    let disconnect = BrokerDisconnect::from_reason_code(code)?;
+    BROKER_DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+    if disconnect == BrokerDisconnect::SessionTakenOver {
+        CLIENT_ID_CONFLICTS.fetch_add(1, Ordering::Relaxed);
+        error!(
+            "MQTT: Session taken over, another client uses client id {}",
+            endpoint.client_id(network)
+        );
+    } else {
+        warn!(
+            "MQTT: {} broker closed the connection: {disconnect:?}",
+            endpoint.as_str()
+        );
+    }
+    Some(disconnect)
+}
 
+/// Send and receive messages until the broker closes the connection
+async fn run_client(
+    network: &NetworkStack,
+    client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
+) -> BrokerDisconnect {
     loop {
+        watchdog::check_in(Monitored::MqttClient);
         // Use a timeout to prevent blocking indefinitely
         match embassy_time::with_timeout(
             Duration::from_millis(100),
@@ -29,38 +619,109 @@ pub async fn mqtt_client_task(
         )
         .await
         {
-            Ok(Ok(Some(message))) => {
-                // Use try_send to avoid blocking if the receive channel is full
-                if MQTT_RECEIVE_CHANNEL.try_send(message).is_err() {
-                    warn!("MQTT: Receive channel is full, dropping message");
-                }
+            Ok(Ok(Some((topic, message)))) => {
+                MESSAGES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+                route_message(topic, message);
             }
             Ok(Ok(None)) => {
                 // No message received, continue
             }
-            Ok(Err(e)) => {
-                warn!("MQTT: Failed to receive MQTT message: {e:?}");
-            }
+            Ok(Err(e)) => match broker_closed(network, Endpoint::Ocpp, e) {
+                Some(disconnect) => return disconnect,
+                None => warn!("MQTT: Failed to receive MQTT message: {e:?}"),
+            },
             Err(_) => {
                 // Timeout occurred, this is normal when no messages are available
             }
         }
 
-        if let Ok(message) = MQTT_SEND_CHANNEL.try_receive() {
+        if tx_held() {
+            Timer::after(Duration::from_millis(50)).await;
+            continue;
+        }
+
+        if let Some((priority, message)) = MQTT_SEND_QUEUE.dequeue() {
             match network.send_message_with_client(client, &message).await {
                 Ok(()) => {
-                    // Message sent successfully
+                    MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
                 }
                 Err(e) => {
+                    SEND_FAILURES.fetch_add(1, Ordering::Relaxed);
                     warn!("MQTT: client task, failed to send message: {e:?}");
                     // Put the message back in the queue to retry later
-                    if MQTT_SEND_CHANNEL.try_send(message).is_err() {
+                    if !MQTT_SEND_QUEUE.requeue(priority, message) {
                         warn!("MQTT: Failed to requeue message for retry, queue full");
                     }
                 }
             }
         }
 
+        // Announcements are repeated, one that fails to send is dropped
+        if let Ok(announcement) = MQTT_ANNOUNCE_CHANNEL.try_receive() {
+            if let Err(e) = network
+                .send_announcement_with_client(client, &announcement)
+                .await
+            {
+                warn!("MQTT: Failed to send load announcement: {e:?}");
+            }
+        }
+
+        // A failure isn't logged as a warning, it would be forwarded again
+        if let Ok(batch) = MQTT_LOG_CHANNEL.try_receive() {
+            if let Err(e) = network.send_log_with_client(client, &batch).await {
+                info!("MQTT: Failed to send forwarded log lines: {e:?}");
+            }
+        }
+
+        // Telemetry is best effort, a sample that fails to send is dropped
+        if !network.app_config.analytics_enabled() {
+            if let Ok(telemetry) = MQTT_TELEMETRY_CHANNEL.try_receive() {
+                if let Err(e) = network
+                    .send_telemetry_with_client(client, &telemetry, 0)
+                    .await
+                {
+                    warn!("MQTT: Failed to send telemetry: {e:?}");
+                }
+            }
+        }
+
+        Timer::after(Duration::from_millis(50)).await;
+    }
+}
+
+/// Publish the telemetry until the analytics broker closes the connection, nothing is
+/// subscribed so receiving only picks up the broker's disconnect
+async fn run_analytics_client(
+    network: &NetworkStack,
+    endpoint: Endpoint,
+    client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
+) -> BrokerDisconnect {
+    loop {
+        // End the session to free the buffers, keep_connected doesn't reconnect
+        if !memory::is_enabled(Feature::Analytics) {
+            return BrokerDisconnect::ServerGone(ReasonCode::Success);
+        }
+
+        if let Ok(Err(e)) = embassy_time::with_timeout(
+            Duration::from_millis(100),
+            network.receive_message_with_client(client),
+        )
+        .await
+        {
+            if let Some(disconnect) = broker_closed(network, endpoint, e) {
+                return disconnect;
+            }
+        }
+
+        if let Ok(telemetry) = MQTT_TELEMETRY_CHANNEL.try_receive() {
+            if let Err(e) = network
+                .send_telemetry_with_client(client, &telemetry, network.app_config.analytics_qos)
+                .await
+            {
+                warn!("MQTT: Failed to send telemetry to the analytics broker: {e:?}");
+            }
+        }
+
         Timer::after(Duration::from_millis(50)).await;
     }
 }
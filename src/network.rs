@@ -1,4 +1,15 @@
-use crate::{config::Config, mk_static};
+use crate::{
+    config::{Config, WifiNetwork},
+    endpoints, error, info, mk_static,
+    mqtt::InboundTopic,
+    network_cache,
+    ready::{self, Subsystem},
+    settings::Cached,
+    warn,
+    watchdog::{self, Monitored},
+    wifi_monitor::{self, MonitorOutcome, RoamTarget},
+    wifi_networks::WifiNetworks,
+};
 use core::{
     default::Default,
     matches,
@@ -11,22 +22,35 @@ use embassy_net::{tcp::TcpSocket, IpAddress, StackResources};
 use embassy_time::{Duration, Timer};
 use esp_hal::timer::timg::TimerGroup;
 use esp_wifi::{
-    wifi::{ClientConfiguration, Configuration, WifiController, WifiEvent, WifiState},
+    wifi::{ClientConfiguration, Configuration, WifiController, WifiState},
     EspWifiController,
 };
-use log::{error, info, warn};
 use rust_mqtt::{
     client::{client::MqttClient, client_config::ClientConfig},
-    packet::v5::{publish_packet::QualityOfService::QoS1, reason_codes::ReasonCode},
+    packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
     utils::rng_generator::CountingRng,
 };
 
 const BUFFER_SIZE: usize = 2048;
 const DEFAULT_TIMEOUT_MS: u64 = 200;
 
+/// Retained payloads of the status topic, the broker publishes the offline one as last will
+const STATUS_ONLINE: &[u8] = b"online";
+const STATUS_OFFLINE: &[u8] = b"offline";
+
+/// MQTT QoS for a configured level, the client supports up to QoS 1
+fn qos(level: u8) -> QualityOfService {
+    match level {
+        0 => QualityOfService::QoS0,
+        _ => QualityOfService::QoS1,
+    }
+}
+
 pub struct NetworkStack {
     pub stack: &'static embassy_net::Stack<'static>,
     pub app_config: Config,
+    pub radio: &'static EspWifiController<'static>, // Shared with the BLE controller
+    status_topic: heapless::String<64>, // Kept here, the last will refers to it for the connection lifetime
 }
 
 impl NetworkStack {
@@ -53,7 +77,9 @@ impl NetworkStack {
         let (stack, runner) = embassy_net::new(
             wifi_interface,
             config,
-            mk_static!(StackResources<3>, StackResources::<3>::new()),
+            // DHCP, DNS, MQTT, analytics MQTT, NTP, the HTTP server, mDNS and up to two sockets
+            // for HTTP/FTP transfers
+            mk_static!(StackResources<9>, StackResources::<9>::new()),
             seed,
         );
 
@@ -67,7 +93,12 @@ impl NetworkStack {
             .ok();
 
         info!("NETW: WiFi controller started");
-        NetworkStack { stack, app_config }
+        NetworkStack {
+            stack,
+            radio: esp_wifi_ctrl,
+            status_topic: app_config.status_topic(),
+            app_config,
+        }
     }
 
     pub async fn wait_for_ip(&self) {
@@ -75,6 +106,7 @@ impl NetworkStack {
         loop {
             if let Some(config) = self.stack.config_v4() {
                 info!("Got IP: {}", config.address);
+                ready::set(Subsystem::Network);
                 break;
             }
             Timer::after(Duration::from_millis(500)).await;
@@ -107,32 +139,86 @@ impl NetworkStack {
         }
     }
 
-    pub fn create_mqtt_config(&self) -> ClientConfig<'static, 5, CountingRng> {
+    /// Resolve a host, trying the address cached from the last connection first. Returns the
+    /// address and whether it came from the cache
+    pub async fn resolve_cached(
+        &self,
+        hostname: &str,
+        cached: Option<Cached>,
+    ) -> Option<(IpAddress, bool)> {
+        if let Some(address) = cached.and_then(|cached| network_cache::address(cached, hostname)) {
+            return Some((address, true));
+        }
+        let address = self.resolve_dns(hostname).await?;
+        if let Some(cached) = cached {
+            network_cache::remember_address(cached, hostname, address);
+        }
+        Some((address, false))
+    }
+
+    /// Client configuration for the configured broker, with a last will that marks the charger offline
+    pub fn create_mqtt_config(&'static self) -> ClientConfig<'static, 5, CountingRng> {
+        let mut config = Self::mqtt_config(
+            self.app_config.mqtt_client_id,
+            self.app_config.mqtt_username,
+            self.app_config.mqtt_password,
+        );
+        config.add_max_subscribe_qos(qos(self.app_config.subscribe_qos));
+        config.add_will(
+            &self.status_topic,
+            STATUS_OFFLINE,
+            self.app_config.status_delivery.retain,
+        );
+        config
+    }
+
+    pub fn mqtt_config(
+        client_id: &'static str,
+        username: &'static str,
+        password: &'static str,
+    ) -> ClientConfig<'static, 5, CountingRng> {
         let mut config = ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
             CountingRng(20000),
         );
 
-        config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-        config.add_client_id(self.app_config.mqtt_client_id);
+        config.add_max_subscribe_qos(QualityOfService::QoS1);
+        config.add_client_id(client_id);
+        if !username.is_empty() {
+            config.add_username(username);
+            config.add_password(password);
+        }
         config.max_packet_size = 2048;
         config
     }
 
-    pub async fn create_mqtt_client<'a>(
+    /// Connect an MQTT client to a broker without subscribing to any topics, the address of
+    /// the broker is cached when `cached` is set
+    #[allow(clippy::too_many_arguments)]
+    pub async fn connect_mqtt_client<'a>(
         &self,
+        broker: &str,
+        port: u16,
+        cached: Option<Cached>,
+        config: ClientConfig<'static, 5, CountingRng>,
         rx_buffer: &'a mut [u8],
         tx_buffer: &'a mut [u8],
         write_buffer: &'a mut [u8],
         recv_buffer: &'a mut [u8],
     ) -> Result<MqttClient<'a, TcpSocket<'a>, 5, CountingRng>, ReasonCode> {
-        let address = self
-            .resolve_dns(self.app_config.mqtt_broker)
+        let (address, from_cache) = self
+            .resolve_cached(broker, cached)
             .await
             .ok_or(ReasonCode::NetworkError)?;
+        // The broker may have moved, the next attempt resolves it again
+        let forget_cached = || {
+            if let (Some(cached), true) = (cached, from_cache) {
+                network_cache::forget(cached);
+            }
+        };
 
         let mut socket = TcpSocket::new(*self.stack, rx_buffer, tx_buffer);
-        let remote_endpoint = (address, self.app_config.mqtt_port);
+        let remote_endpoint = (address, port);
 
         // Use a timeout for the socket connection to prevent indefinite blocking
         if let Err(_e) =
@@ -140,10 +226,10 @@ impl NetworkStack {
                 .await
         {
             warn!("NETW: Timeout connecting to broker");
+            forget_cached();
             return Err(ReasonCode::NetworkError);
         }
 
-        let config = self.create_mqtt_config();
         let mut client = MqttClient::<_, 5, _>::new(
             socket,
             write_buffer,
@@ -157,22 +243,129 @@ impl NetworkStack {
             embassy_time::with_timeout(Duration::from_secs(10), client.connect_to_broker()).await
         {
             warn!("NETW: Timeout during broker connection handshake");
+            forget_cached();
             return Err(ReasonCode::NetworkError);
         }
+        if cached.is_some() {
+            network_cache::log_milestone("Broker connected", from_cache);
+        }
+
+        Ok(client)
+    }
+
+    /// Connect to the configured broker, subscribe to the inbound topics and publish the
+    /// retained online status
+    pub async fn create_mqtt_client<'a>(
+        &'static self,
+        rx_buffer: &'a mut [u8],
+        tx_buffer: &'a mut [u8],
+        write_buffer: &'a mut [u8],
+        recv_buffer: &'a mut [u8],
+    ) -> Result<MqttClient<'a, TcpSocket<'a>, 5, CountingRng>, ReasonCode> {
+        // A port that worked from this site before is preferred over the configured one
+        let port = endpoints::next_port(self.app_config.mqtt_port);
+        if port != self.app_config.mqtt_port {
+            info!("NETW: Connecting on port {port}, known to work from this site");
+        }
+        let mut client = match self
+            .connect_mqtt_client(
+                self.app_config.mqtt_broker,
+                port,
+                Some(Cached::Broker),
+                self.create_mqtt_config(),
+                rx_buffer,
+                tx_buffer,
+                write_buffer,
+                recv_buffer,
+            )
+            .await
+        {
+            Ok(client) => {
+                endpoints::record_success(port);
+                client
+            }
+            Err(e) => {
+                endpoints::record_failure(port);
+                return Err(e);
+            }
+        };
+
+        for inbound in InboundTopic::ALL
+            .into_iter()
+            .filter(|inbound| inbound.is_subscribed(&self.app_config))
+        {
+            let topic = inbound.topic(&self.app_config);
+            match embassy_time::with_timeout(
+                Duration::from_secs(10),
+                client.subscribe_to_topic(&topic),
+            )
+            .await
+            {
+                Ok(Ok(())) => info!("NETW: Subscribed to {topic}"),
+                Ok(Err(e)) => {
+                    warn!("NETW: Failed to subscribe to {topic}: {e:?}");
+                    return Err(e);
+                }
+                Err(_) => {
+                    warn!("NETW: Timeout subscribing to topic {topic}");
+                    return Err(ReasonCode::NetworkError);
+                }
+            }
+        }
 
-        if let Err(_e) = embassy_time::with_timeout(
+        match embassy_time::with_timeout(
             Duration::from_secs(10),
-            client.subscribe_to_topic(&self.app_config.system_topic()),
+            client.send_message(
+                &self.status_topic,
+                STATUS_ONLINE,
+                qos(self.app_config.status_delivery.qos),
+                self.app_config.status_delivery.retain,
+            ),
         )
         .await
         {
-            warn!("NETW: Timeout subscribing to topic");
-            return Err(ReasonCode::NetworkError);
+            Ok(Ok(())) => info!("NETW: Published online status to {}", self.status_topic),
+            Ok(Err(e)) => warn!("NETW: Failed to publish online status: {e:?}"),
+            Err(_) => warn!("NETW: Timeout publishing online status"),
         }
 
         Ok(client)
     }
 
+    /// Connect to the analytics broker, it only receives telemetry so there are no
+    /// subscriptions and no last will
+    pub async fn create_analytics_client<'a>(
+        &self,
+        client_id: &'static str,
+        rx_buffer: &'a mut [u8],
+        tx_buffer: &'a mut [u8],
+        write_buffer: &'a mut [u8],
+        recv_buffer: &'a mut [u8],
+    ) -> Result<MqttClient<'a, TcpSocket<'a>, 5, CountingRng>, ReasonCode> {
+        let config = Self::mqtt_config(
+            client_id,
+            self.app_config.analytics_username,
+            self.app_config.analytics_password,
+        );
+        let client = self
+            .connect_mqtt_client(
+                self.app_config.analytics_broker,
+                self.app_config.analytics_port,
+                None,
+                config,
+                rx_buffer,
+                tx_buffer,
+                write_buffer,
+                recv_buffer,
+            )
+            .await?;
+        info!(
+            "NETW: Connected to analytics broker {}",
+            self.app_config.analytics_broker
+        );
+        Ok(client)
+    }
+
     pub async fn send_message_with_client(
         &self,
         client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
@@ -185,7 +378,11 @@ impl NetworkStack {
             message.len(),
             str::from_utf8(message).unwrap_or("<invalid UTF-8>")
         );
-        match client.send_message(&topic, message, QoS1, true).await {
+        let delivery = self.app_config.ocpp_delivery;
+        match client
+            .send_message(&topic, message, qos(delivery.qos), delivery.retain)
+            .await
+        {
             Ok(()) => {
                 info!("MQTT: Message sent successfully");
                 Ok(())
@@ -197,10 +394,44 @@ impl NetworkStack {
         }
     }
 
+    /// Publish device metrics to the telemetry topic, not retained
+    pub async fn send_telemetry_with_client(
+        &self,
+        client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
+        message: &[u8],
+        qos_level: u8,
+    ) -> Result<(), ReasonCode> {
+        let topic = self.app_config.telemetry_topic();
+        client
+            .send_message(&topic, message, qos(qos_level), false)
+            .await
+    }
+
+    /// Publish an announcement of the load management on the topic of the site, not retained
+    /// so a charger that went away isn't announced to the ones that connect later
+    pub async fn send_announcement_with_client(
+        &self,
+        client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
+        message: &[u8],
+    ) -> Result<(), ReasonCode> {
+        let topic = self.app_config.load_topic();
+        client.send_message(&topic, message, qos(0), false).await
+    }
+
+    /// Publish a batch of forwarded log lines to the log topic, not retained
+    pub async fn send_log_with_client(
+        &self,
+        client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
+        message: &[u8],
+    ) -> Result<(), ReasonCode> {
+        let topic = self.app_config.log_topic();
+        client.send_message(&topic, message, qos(0), false).await
+    }
+
     pub async fn receive_message_with_client(
         &self,
         client: &mut MqttClient<'_, TcpSocket<'_>, 5, CountingRng>,
-    ) -> Result<Option<heapless::Vec<u8, BUFFER_SIZE>>, ReasonCode> {
+    ) -> Result<Option<(InboundTopic, heapless::Vec<u8, BUFFER_SIZE>)>, ReasonCode> {
         match embassy_time::with_timeout(
             Duration::from_millis(DEFAULT_TIMEOUT_MS),
             client.receive_message(),
@@ -208,6 +439,10 @@ impl NetworkStack {
         .await
         {
             Ok(Ok((topic, payload))) => {
+                let Some(inbound) = InboundTopic::from_topic(&self.app_config, topic) else {
+                    warn!("MQTT: Received message on unexpected topic {topic}, ignoring");
+                    return Ok(None);
+                };
                 let mut v = heapless::Vec::<u8, BUFFER_SIZE>::new();
                 if v.extend_from_slice(payload).is_ok() {
                     info!(
@@ -215,7 +450,7 @@ impl NetworkStack {
                         topic,
                         str::from_utf8(payload).unwrap_or("<invalid UTF-8>")
                     );
-                    Ok(Some(v))
+                    Ok(Some((inbound, v)))
                 } else {
                     warn!(
                         "MQTT: Received message too large for buffer (size: {})",
@@ -238,34 +473,83 @@ impl NetworkStack {
 
 #[embassy_executor::task]
 async fn connection_task(mut controller: WifiController<'static>, config: &'static Config) {
+    let mut networks = WifiNetworks::new(config);
     loop {
+        watchdog::check_in(Monitored::Network);
         if esp_wifi::wifi::wifi_state() == WifiState::StaConnected {
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            Timer::after(Duration::from_millis(5000)).await
+            let network = networks.current();
+            match wifi_monitor::monitor(&mut controller, config, network.ssid).await {
+                MonitorOutcome::Disconnected => Timer::after(Duration::from_millis(5000)).await,
+                MonitorOutcome::Roam(target) => {
+                    // Reconnect to the stronger access point instead of waiting for a disconnect
+                    let client_config = client_configuration(network, Some(target));
+                    if let Err(e) = controller.set_configuration(&client_config) {
+                        warn!("NETW: Failed to configure the access point to roam to: {e:?}");
+                        continue;
+                    }
+                    networks.roaming(target);
+                    if let Err(e) = controller.disconnect_async().await {
+                        warn!("NETW: Failed to disconnect to roam: {e:?}");
+                    }
+                }
+            }
         }
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: config.wifi_ssid.into(),
-                password: config.wifi_password.into(),
-                ..Default::default()
-            });
+            let client_config = client_configuration(networks.current(), None);
             controller.set_configuration(&client_config).unwrap();
             info!("NETW: Starting wifi");
             controller.start_async().await.unwrap();
             info!("NETW: Wifi started!");
         }
-        info!("NETW: About to connect...");
+        if let Some(target) = networks.take_cached() {
+            // Straight to the access point of the last connection, skipping the scan
+            let client_config = client_configuration(networks.current(), Some(target));
+            if let Err(e) = controller.set_configuration(&client_config) {
+                warn!("NETW: Failed to configure the cached access point: {e:?}");
+            }
+        } else if networks.needs_scan() {
+            let previous = networks.current();
+            networks.scan(&mut controller).await;
+            if networks.current() != previous {
+                let client_config = client_configuration(networks.current(), None);
+                if let Err(e) = controller.set_configuration(&client_config) {
+                    warn!("NETW: Failed to configure the selected network: {e:?}");
+                }
+            }
+        }
+        info!("NETW: About to connect to {}...", networks.current().ssid);
 
         match controller.connect_async().await {
-            Ok(_) => info!("NETW: Wifi connected!"),
+            Ok(_) => {
+                info!("NETW: Wifi connected!");
+                networks.connected();
+            }
             Err(e) => {
                 info!("NETW: Failed to connect to wifi: {e:?}");
+                networks.failed();
+                // Let the driver pick the access point again after a failed roam, or move on to
+                // the next network
+                let client_config = client_configuration(networks.current(), None);
+                if let Err(e) = controller.set_configuration(&client_config) {
+                    warn!("NETW: Failed to reset the WiFi configuration: {e:?}");
+                }
                 Timer::after(Duration::from_millis(5000)).await
             }
         }
     }
 }
 
+/// Station configuration for a network, pinned to an access point when roaming
+fn client_configuration(network: WifiNetwork, target: Option<RoamTarget>) -> Configuration {
+    Configuration::Client(ClientConfiguration {
+        ssid: network.ssid.into(),
+        password: network.password.into(),
+        bssid: target.map(|target| target.bssid),
+        channel: target.map(|target| target.channel),
+        ..Default::default()
+    })
+}
+
 #[embassy_executor::task]
 async fn net_task(
     mut runner: embassy_net::Runner<'static, esp_wifi::wifi::WifiDevice<'static>>,
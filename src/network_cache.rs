@@ -0,0 +1,76 @@
+use core::fmt::Write;
+use embassy_net::{IpAddress, Ipv4Address};
+use embassy_time::Instant;
+
+use crate::{
+    info,
+    settings::{self, Cached},
+    utils, warn,
+    wifi_monitor::{self, RoamTarget},
+};
+
+/// Access point the charger connected to on a network, stored as `aa:bb:cc:dd:ee:ff/6`
+pub fn access_point(ssid: &str) -> Option<RoamTarget> {
+    if settings::last_network().as_deref() != Some(ssid) {
+        return None;
+    }
+    let cached = settings::cached(Cached::AccessPoint)?;
+    let (bssid, channel) = cached.split_once('/')?;
+    Some(RoamTarget {
+        bssid: wifi_monitor::parse_bssid(bssid)?,
+        channel: channel.parse().ok()?,
+        rssi: 0,
+    })
+}
+
+/// Remember the access point of the network the charger connected to, after
+/// `settings::remember_network`
+pub fn remember_access_point(target: &RoamTarget) {
+    let mut value = heapless::String::<24>::new();
+    for (i, octet) in target.bssid.iter().enumerate() {
+        let separator = if i == 0 { "" } else { ":" };
+        let _ = write!(value, "{separator}{octet:02x}");
+    }
+    let _ = write!(value, "/{}", target.channel);
+    remember(Cached::AccessPoint, &value);
+}
+
+/// Address a host resolved to on the last connection, stored as `<ip>/<crc32 of the host>` so
+/// an address isn't used for another host after the configuration changes
+pub fn address(cached: Cached, host: &str) -> Option<IpAddress> {
+    let value = settings::cached(cached)?;
+    let (ip, crc) = value.split_once('/')?;
+    if u32::from_str_radix(crc, 16).ok()? != utils::crc32(host.as_bytes()) {
+        return None;
+    }
+    ip.parse::<Ipv4Address>().ok().map(IpAddress::Ipv4)
+}
+
+/// Remember the address a host resolved to
+pub fn remember_address(cached: Cached, host: &str, ip: IpAddress) {
+    let mut value = heapless::String::<32>::new();
+    let _ = write!(value, "{ip}/{:08x}", utils::crc32(host.as_bytes()));
+    remember(cached, &value);
+}
+
+/// Forget a cached parameter that didn't work, the next attempt starts from scratch
+pub fn forget(cached: Cached) {
+    warn!("NETW: Cached {} failed, forgetting it", cached.as_str());
+    remember(cached, "");
+}
+
+fn remember(cached: Cached, value: &str) {
+    if let Err(e) = settings::cache(cached, value) {
+        warn!("NETW: Failed to cache the {}: {e}", cached.as_str());
+    }
+}
+
+/// Log a step of reconnecting with the time since boot, to compare the cached path with a
+/// cold start
+pub fn log_milestone(step: &str, cached: bool) {
+    info!(
+        "NETW: {step} {}ms after boot{}",
+        Instant::now().as_millis(),
+        if cached { " (cached)" } else { "" }
+    );
+}
@@ -1,20 +1,85 @@
 use chrono::{Datelike, Timelike, Utc};
+use core::cell::Cell;
 use core::fmt::Write;
 use core::sync::atomic::{AtomicU32, Ordering};
-use embassy_net::udp::UdpSocket;
+use embassy_net::{udp::UdpSocket, IpAddress};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
 use embassy_time::{Duration, Instant, Timer};
-use log::{error, info, warn};
 
 use crate::config::Config;
 use crate::network::NetworkStack;
+use crate::network_cache;
+use crate::ready::{self, Subsystem};
+use crate::settings::Cached;
+use crate::timestamp::{ntp_to_unix_ms, unix_ms_to_ntp};
+use crate::timezone::TimeZone;
+
+pub use crate::timestamp::format_iso8601;
+use crate::{error, info, warn};
 
-const NTP_EPOCH_OFFSET: u32 = 2_208_988_800;
 const NTP_PACKET_SIZE: usize = 48;
 const NTP_PORT: u16 = 123;
 
-static NTP_BASE_TIME: AtomicU32 = AtomicU32::new(0);
-static SYSTEM_TIMER_BASE: AtomicU32 = AtomicU32::new(0);
-static TIME_SYNCED: AtomicU32 = AtomicU32::new(0);
+/// Requests to each server, the one with the shortest round trip is used
+const SAMPLES_PER_SERVER: usize = 4;
+/// Round trips longer than this are too uncertain to use
+const MAX_DELAY_MS: i64 = 2000;
+/// Offsets of an NTP synced clock beyond this are rejected as absurd
+const MAX_OFFSET_MS: i64 = 1000 * 1000;
+/// Offsets up to this are slewed into the clock, larger ones step it
+const MAX_SLEW_MS: i64 = 10 * 1000;
+/// Rate at which an offset is slewed in, 5 ms per second keeps the clock monotonic
+const SLEW_PER_MILLE: u64 = 5;
+
+/// The clock: a Unix time at an uptime, and the correction being slewed in since then
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    base_unix_ms: u64,
+    base_uptime_ms: u64,
+    slew_ms: i64,
+    source: TimeSource,
+}
+
+impl Clock {
+    const UNSET: Self = Self {
+        base_unix_ms: 0,
+        base_uptime_ms: 0,
+        slew_ms: 0,
+        source: TimeSource::None,
+    };
+
+    /// Unix time in milliseconds at an uptime, the uptime itself while the clock isn't set
+    fn unix_ms_at(&self, uptime_ms: u64) -> u64 {
+        let elapsed = uptime_ms.saturating_sub(self.base_uptime_ms);
+        let max_slew = (elapsed * SLEW_PER_MILLE / 1000) as i64;
+        let slewed = self.slew_ms.clamp(-max_slew, max_slew);
+        (self.base_unix_ms + elapsed).saturating_add_signed(slewed)
+    }
+}
+
+static CLOCK: Mutex<CriticalSectionRawMutex, Cell<Clock>> = Mutex::new(Cell::new(Clock::UNSET));
+/// Number of successful NTP syncs since boot
+static NTP_SYNCS: AtomicU32 = AtomicU32::new(0);
+
+/// Where the current base time came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    None = 0,
+    Ntp = 1,
+    CentralSystem = 2, // currentTime of a Heartbeat or BootNotification response
+    Rtc = 3,           // Battery backed RTC, read at boot
+}
+
+impl TimeSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Ntp => "NTP",
+            Self::CentralSystem => "Central System",
+            Self::Rtc => "RTC",
+        }
+    }
+}
 
 #[repr(C, packed)]
 struct NtpPacket {
@@ -99,29 +164,56 @@ impl NtpPacket {
         })
     }
 
-    fn get_unix_timestamp(&self) -> Option<u32> {
-        // Upper 32 bits are seconds, lower 32 bits are fractional seconds
-        let ntp_seconds = (self.trans_timestamp >> 32) as u32;
+    /// Leap indicator 3 means the server's clock isn't synchronized
+    fn leap_indicator(&self) -> u8 {
+        self.li_vn_mode >> 6
+    }
 
-        if ntp_seconds > NTP_EPOCH_OFFSET {
-            Some(ntp_seconds - NTP_EPOCH_OFFSET)
-        } else {
-            None
+    fn mode(&self) -> u8 {
+        self.li_vn_mode & 0x07
+    }
+}
+
+/// Offset of the local clock to a server and the round trip delay, in milliseconds
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    offset_ms: i64,
+    delay_ms: i64,
+}
+
+impl Sample {
+    /// Offset and delay from the four timestamps of an exchange: our transmit time `t1`, the
+    /// server's receive `t2` and transmit `t3` times, and our receive time `t4`
+    fn from_timestamps(t1: u64, t2: u64, t3: u64, t4: u64) -> Self {
+        let (t1, t2, t3, t4) = (t1 as i64, t2 as i64, t3 as i64, t4 as i64);
+        Self {
+            offset_ms: ((t2 - t1) + (t3 - t4)) / 2,
+            delay_ms: (t4 - t1) - (t3 - t2),
         }
     }
 }
 
+/// Servers of a comma separated list, e.g. `0.pool.ntp.org, time.google.com`
+pub fn servers(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+}
+
 /// Task to synchronize time with NTP servers
 #[embassy_executor::task]
 pub async fn ntp_sync_task(network: &'static NetworkStack) {
     info!("TASK: Started NTP Time Synchronization");
 
-    Timer::after(Duration::from_secs(60)).await;
-
     let config = Config::from_config();
 
+    // DNS servers come with the DHCP lease, they're usable as soon as there's an address
+    ready::wait(Subsystem::Network).await;
+
     loop {
-        if !is_time_synced() || minutes_since_last_sync() > config.ntp_sync_interval_minutes as u32
+        // Time from the central system is only a fallback, NTP is retried until it succeeds
+        if time_source() != TimeSource::Ntp
+            || minutes_since_last_sync() > config.ntp_sync_interval_minutes as u32
         {
             info!(
                 "NTP : Attempting time synchronization with {}",
@@ -138,7 +230,7 @@ pub async fn ntp_sync_task(network: &'static NetworkStack) {
                 }
             }
 
-            let wait_time = if is_time_synced() {
+            let wait_time = if time_source() == TimeSource::Ntp {
                 Duration::from_secs(60 * config.ntp_sync_interval_minutes as u64)
             } else {
                 Duration::from_secs(900)
@@ -151,22 +243,91 @@ pub async fn ntp_sync_task(network: &'static NetworkStack) {
     }
 }
 
+/// Synchronize the clock with the first server of a comma separated list that gives a usable
+/// answer, the others are fallbacks
 pub async fn sync_time_with_ntp(
     stack: &'static NetworkStack,
-    server: &str,
+    servers_list: &str,
 ) -> Result<(), &'static str> {
+    let mut result = Err("NTP : No server configured");
+    for server in servers(servers_list) {
+        result = sync_with_server(stack, server).await;
+        match result {
+            Ok(()) => break,
+            Err(e) => warn!("NTP : Server {server} failed: {e}"),
+        }
+    }
+    result
+}
+
+async fn sync_with_server(stack: &'static NetworkStack, server: &str) -> Result<(), &'static str> {
     info!("NTP : Starting NTP sync with server: {server}");
 
-    let server_addr = match embassy_time::with_timeout(Duration::from_secs(10), async {
-        stack.resolve_dns(server).await
-    })
-    .await
-    {
-        Ok(Some(addr)) => addr,
-        Ok(None) => return Err("NTP : Failed to resolve NTP server address"),
-        Err(_) => return Err("NTP : DNS resolution timeout"),
-    };
+    let (server_addr, from_cache) =
+        match embassy_time::with_timeout(Duration::from_secs(10), async {
+            stack.resolve_cached(server, Some(Cached::NtpServer)).await
+        })
+        .await
+        {
+            Ok(Some(resolved)) => resolved,
+            Ok(None) => return Err("NTP : Failed to resolve NTP server address"),
+            Err(_) => return Err("NTP : DNS resolution timeout"),
+        };
+    let result = best_sample(stack, server_addr).await;
+    match result {
+        Ok(sample) => {
+            adjust_clock(sample);
+            network_cache::log_milestone("Time synchronized", from_cache);
+            Ok(())
+        }
+        Err(e) => {
+            if from_cache {
+                // The server may have moved, the next attempt resolves it again
+                network_cache::forget(Cached::NtpServer);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Take a few samples from a server and keep the one with the shortest round trip, it is the
+/// least affected by network jitter
+async fn best_sample(
+    stack: &'static NetworkStack,
+    server_addr: IpAddress,
+) -> Result<Sample, &'static str> {
+    let mut best: Option<Sample> = None;
+    let mut last_error = "NTP : No samples";
+    for i in 0..SAMPLES_PER_SERVER {
+        if i > 0 {
+            Timer::after(Duration::from_millis(200)).await;
+        }
+        match request_time(stack, server_addr).await {
+            Ok(sample) => {
+                info!(
+                    "NTP : Sample {}: offset {}ms, delay {}ms",
+                    i + 1,
+                    sample.offset_ms,
+                    sample.delay_ms
+                );
+                if best.is_none_or(|best| sample.delay_ms < best.delay_ms) {
+                    best = Some(sample);
+                }
+            }
+            Err(e) => {
+                warn!("NTP : Sample {} rejected: {e}", i + 1);
+                last_error = e;
+            }
+        }
+    }
+    best.ok_or(last_error)
+}
 
+/// Request the time from an NTP server, returns the offset and delay of the exchange
+async fn request_time(
+    stack: &'static NetworkStack,
+    server_addr: IpAddress,
+) -> Result<Sample, &'static str> {
     let mut rx_meta = heapless::Vec::<embassy_net::udp::PacketMetadata, 2>::new();
     rx_meta
         .resize(2, embassy_net::udp::PacketMetadata::EMPTY)
@@ -196,7 +357,11 @@ pub async fn sync_time_with_ntp(
         return Err("NTP : Failed to bind UDP socket");
     }
 
-    let request = NtpPacket::new_request();
+    // The transmit time is echoed back as the origin time, which ties the response to the request
+    let sent_uptime_ms = Instant::now().as_millis();
+    let t1 = CLOCK.lock(|clock| clock.get().unix_ms_at(sent_uptime_ms));
+    let mut request = NtpPacket::new_request();
+    request.trans_timestamp = unix_ms_to_ntp(t1);
     let request_bytes = request.to_bytes();
 
     if socket
@@ -218,24 +383,13 @@ pub async fn sync_time_with_ntp(
     {
         Ok(Ok((len, _addr))) => {
             if len >= NTP_PACKET_SIZE {
-                // Parse response
-                if let Some(response) = NtpPacket::from_bytes(&response_buffer) {
-                    if let Some(unix_timestamp) = response.get_unix_timestamp() {
-                        let current_system_time = Instant::now().as_secs() as u32;
-
-                        NTP_BASE_TIME.store(unix_timestamp, Ordering::Relaxed);
-                        SYSTEM_TIMER_BASE.store(current_system_time, Ordering::Relaxed);
-                        TIME_SYNCED.store(1, Ordering::Relaxed);
-
-                        info!("NTP : sync successful. Unix timestamp: {unix_timestamp}, System time: {current_system_time}s");
-                        Ok(())
-                    } else {
-                        error!("NTP : Invalid timestamp received");
-                        Err("Invalid NTP timestamp")
+                let t4 = t1 + (Instant::now().as_millis() - sent_uptime_ms);
+                match NtpPacket::from_bytes(&response_buffer) {
+                    Some(response) => check_response(&response, &request, t1, t4),
+                    None => {
+                        error!("NTP : Failed to parse response");
+                        Err("Failed to parse NTP response")
                     }
-                } else {
-                    error!("NTP : Failed to parse response");
-                    Err("Failed to parse NTP response")
                 }
             } else {
                 error!("NTP : response too short: {len} bytes");
@@ -257,59 +411,68 @@ pub async fn sync_time_with_ntp(
     result
 }
 
-pub fn get_current_unix_time() -> u32 {
-    if !is_time_synced() {
-        return 0;
+/// Check a response and compute the offset and delay, a response is rejected when the server
+/// isn't synchronized or sends a Kiss-o'-Death (stratum 0), when it doesn't answer our request,
+/// and when the offset or delay are absurd
+fn check_response(
+    response: &NtpPacket,
+    request: &NtpPacket,
+    t1: u64,
+    t4: u64,
+) -> Result<Sample, &'static str> {
+    let stratum = response.stratum;
+    if stratum == 0 {
+        let code = response.ref_id.to_be_bytes();
+        warn!(
+            "NTP : Kiss-o'-Death {}",
+            core::str::from_utf8(&code).unwrap_or("????")
+        );
+        return Err("Kiss-o'-Death received");
+    }
+    if stratum > 15 || response.leap_indicator() == 3 {
+        return Err("Server not synchronized");
+    }
+    if response.mode() != 4 {
+        return Err("Not a server response");
+    }
+    let orig_timestamp = response.orig_timestamp;
+    if orig_timestamp != request.trans_timestamp {
+        return Err("Response doesn't match the request");
     }
 
-    let ntp_base = NTP_BASE_TIME.load(Ordering::Relaxed);
-    let system_base = SYSTEM_TIMER_BASE.load(Ordering::Relaxed);
-    let current_system_time = Instant::now().as_secs() as u32;
-
-    let elapsed_seconds = current_system_time.wrapping_sub(system_base);
-
-    ntp_base + elapsed_seconds
+    let (Some(t2), Some(t3)) = (
+        ntp_to_unix_ms(response.recv_timestamp),
+        ntp_to_unix_ms(response.trans_timestamp),
+    ) else {
+        return Err("Invalid NTP timestamp");
+    };
+    let sample = Sample::from_timestamps(t1, t2, t3, t4);
+    if !(0..=MAX_DELAY_MS).contains(&sample.delay_ms) {
+        return Err("Round trip delay out of range");
+    }
+    // Only a clock that was synced before can tell an offset is absurd
+    if time_source() == TimeSource::Ntp && sample.offset_ms.abs() > MAX_OFFSET_MS {
+        return Err("Offset out of range");
+    }
+    Ok(sample)
 }
 
-pub fn get_iso8601_time() -> heapless::String<32> {
-    let timestamp = get_current_unix_time();
-
-    if timestamp == 0 {
-        let mut result = heapless::String::new();
-        result.push_str("1970-01-01T00:00:00Z").unwrap();
-        return result;
+/// Unix time in seconds, 0 while the time isn't set. 64 bits, so it doesn't wrap in 2038 or
+/// 2106 and the uptime it is counted from doesn't wrap either
+pub fn get_current_unix_time() -> u64 {
+    if !is_time_synced() {
+        return 0;
     }
+    current_unix_ms() / 1000
+}
 
-    // Convert Unix timestamp to date and time components
-    let mut result = heapless::String::new();
-
-    // Calculate days since Unix epoch
-    let days_since_epoch = timestamp / 86400; // 86400 seconds in a day
-    let seconds_in_day = timestamp % 86400;
-
-    // Calculate hours, minutes, seconds
-    let hours = seconds_in_day / 3600;
-    let minutes = (seconds_in_day % 3600) / 60;
-    let seconds = seconds_in_day % 60;
-
-    // Calculate year, month, day from days since epoch
-    let (year, month, day) = days_to_date(days_since_epoch);
-
-    // Format as ISO8601: YYYY-MM-DDTHH:MM:SSZ
-    write_u32_padded(&mut result, year, 4);
-    result.push('-').unwrap();
-    write_u32_padded(&mut result, month, 2);
-    result.push('-').unwrap();
-    write_u32_padded(&mut result, day, 2);
-    result.push('T').unwrap();
-    write_u32_padded(&mut result, hours, 2);
-    result.push(':').unwrap();
-    write_u32_padded(&mut result, minutes, 2);
-    result.push(':').unwrap();
-    write_u32_padded(&mut result, seconds, 2);
-    result.push('Z').unwrap();
+fn current_unix_ms() -> u64 {
+    let now = Instant::now().as_millis();
+    CLOCK.lock(|clock| clock.get().unix_ms_at(now))
+}
 
-    result
+pub fn get_iso8601_time() -> heapless::String<32> {
+    format_iso8601(get_current_unix_time())
 }
 
 pub fn get_date_time() -> Option<chrono::DateTime<Utc>> {
@@ -317,13 +480,98 @@ pub fn get_date_time() -> Option<chrono::DateTime<Utc>> {
     if timestamp == 0 {
         None
     } else {
-        chrono::DateTime::<Utc>::from_timestamp(timestamp as i64, 0)
+        chrono::DateTime::<Utc>::from_timestamp(i64::try_from(timestamp).ok()?, 0)
     }
 }
 
-/// Check if NTP time synchronization has been completed successfully
+/// Check if the time has been set, by NTP or by the central system
 pub fn is_time_synced() -> bool {
-    TIME_SYNCED.load(Ordering::Relaxed) != 0
+    time_source() != TimeSource::None
+}
+
+pub fn time_source() -> TimeSource {
+    CLOCK.lock(|clock| clock.get().source)
+}
+
+/// Step the clock to a Unix time
+fn set_base_time(unix_timestamp: u64, source: TimeSource) {
+    let now = Instant::now().as_millis();
+    CLOCK.lock(|clock| {
+        clock.set(Clock {
+            base_unix_ms: unix_timestamp * 1000,
+            base_uptime_ms: now,
+            slew_ms: 0,
+            source,
+        })
+    });
+    ready::set(Subsystem::Time);
+}
+
+/// Correct the clock by the offset of an NTP sample. A small offset is slewed in so timestamps
+/// stay monotonic, the clock is only stepped when it isn't set yet or is too far off
+fn adjust_clock(sample: Sample) {
+    let now = Instant::now().as_millis();
+    let stepped = CLOCK.lock(|clock| {
+        let current = clock.get();
+        let unix_ms = current.unix_ms_at(now);
+        let step = current.source == TimeSource::None || sample.offset_ms.abs() > MAX_SLEW_MS;
+        clock.set(Clock {
+            base_unix_ms: if step {
+                unix_ms.saturating_add_signed(sample.offset_ms)
+            } else {
+                unix_ms
+            },
+            base_uptime_ms: now,
+            slew_ms: if step { 0 } else { sample.offset_ms },
+            source: TimeSource::Ntp,
+        });
+        step
+    });
+    NTP_SYNCS.fetch_add(1, Ordering::Relaxed);
+    ready::set(Subsystem::Time);
+    info!(
+        "NTP : {} clock by {}ms, round trip {}ms",
+        if stepped { "Stepped" } else { "Slewing" },
+        sample.offset_ms,
+        sample.delay_ms
+    );
+}
+
+/// Seed the clock with the time of the RTC at boot, until NTP or the central system take over
+pub fn set_from_rtc(unix_timestamp: u64) {
+    if is_time_synced() {
+        return;
+    }
+    set_base_time(unix_timestamp, TimeSource::Rtc);
+    info!("NTP : Time set from the RTC to {}", get_iso8601_time());
+}
+
+/// Number of successful NTP syncs since boot, a change means there's a new time to keep
+pub fn ntp_syncs() -> u32 {
+    NTP_SYNCS.load(Ordering::Relaxed)
+}
+
+/// Correct the clock with the time of the central system when it is not set or drifted
+/// more than `max_drift_secs`, returns true if the clock was corrected
+pub fn correct_time(unix_timestamp: u64, max_drift_secs: u64) -> bool {
+    if is_time_synced() {
+        let drift = get_current_unix_time().abs_diff(unix_timestamp);
+        if drift <= max_drift_secs {
+            return false;
+        }
+        warn!("NTP : Clock drifted {drift}s from the central system time, correcting");
+    } else {
+        info!("NTP : Time not synced, using the central system time");
+    }
+
+    // A correction of an NTP synced clock keeps NTP as the source, its next sync takes over
+    let source = match time_source() {
+        TimeSource::Ntp => TimeSource::Ntp,
+        _ => TimeSource::CentralSystem,
+    };
+    set_base_time(unix_timestamp, source);
+    info!("NTP : Time set to {}", get_iso8601_time());
+    true
 }
 /// Get the number of minutes since the last NTP sync
 pub fn minutes_since_last_sync() -> u32 {
@@ -331,11 +579,9 @@ pub fn minutes_since_last_sync() -> u32 {
         return u32::MAX; // No sync yet
     }
 
-    let system_base = SYSTEM_TIMER_BASE.load(Ordering::Relaxed);
-    let current_system_time = Instant::now().as_secs() as u32;
-
-    let elapsed_seconds = current_system_time.wrapping_sub(system_base);
-    elapsed_seconds / 60 // Convert to minutes
+    let base_uptime_ms = CLOCK.lock(|clock| clock.get().base_uptime_ms);
+    let elapsed_seconds = (Instant::now().as_millis() - base_uptime_ms) / 1000;
+    (elapsed_seconds / 60) as u32 // Convert to minutes
 }
 
 /// Get detailed timing information for debugging
@@ -343,15 +589,17 @@ pub fn get_timing_info() -> heapless::String<128> {
     let mut result = heapless::String::new();
 
     if is_time_synced() {
-        let ntp_base = NTP_BASE_TIME.load(Ordering::Relaxed);
-        let system_base = SYSTEM_TIMER_BASE.load(Ordering::Relaxed);
-        let current_system_time = Instant::now().as_secs() as u32;
-        let elapsed_seconds = current_system_time.wrapping_sub(system_base);
-        let current_unix_time = ntp_base + elapsed_seconds;
+        let clock = CLOCK.lock(|clock| clock.get());
+        let now = Instant::now().as_millis();
+        let elapsed_seconds = (now - clock.base_uptime_ms) / 1000;
+        let current_unix_time = clock.unix_ms_at(now) / 1000;
 
         write!(
             result,
-            "NTP : Synced: {elapsed_seconds}s ago, Unix: {current_unix_time}, Boot: {current_system_time}s",
+            "NTP : Synced: {elapsed_seconds}s ago ({}), Unix: {current_unix_time}, Boot: {}s, Slew: {}ms",
+            clock.source.as_str(),
+            now / 1000,
+            clock.slew_ms
         ).ok();
     } else {
         write!(result, "Time not synced yet").ok();
@@ -360,16 +608,21 @@ pub fn get_timing_info() -> heapless::String<128> {
     result
 }
 
-/// Get local time formatted as a string with timezone offset applied
-/// Returns UTC time if no valid timezone offset is provided
-pub fn get_local_time_formatted(timezone_offset_hours: i8) -> heapless::String<32> {
-    if let Some(utc_datetime) = get_date_time() {
-        // Apply timezone offset
-        let offset_seconds = timezone_offset_hours as i32 * 3600;
-        let local_offset = chrono::FixedOffset::east_opt(offset_seconds)
-            .unwrap_or_else(|| chrono::FixedOffset::east_opt(0).unwrap()); // Default to UTC if invalid
+/// Local time of a UTC time, with daylight saving time when in effect
+fn to_local(
+    utc_datetime: chrono::DateTime<Utc>,
+    time_zone: &TimeZone,
+) -> chrono::DateTime<chrono::FixedOffset> {
+    let offset_seconds = time_zone.offset_at(utc_datetime.timestamp());
+    let local_offset = chrono::FixedOffset::east_opt(offset_seconds)
+        .unwrap_or_else(|| chrono::FixedOffset::east_opt(0).unwrap()); // Default to UTC if invalid
+    utc_datetime.with_timezone(&local_offset)
+}
 
-        let local_datetime = utc_datetime.with_timezone(&local_offset);
+/// Get local time formatted as a string in the configured timezone
+pub fn get_local_time_formatted(time_zone: &TimeZone) -> heapless::String<32> {
+    if let Some(utc_datetime) = get_date_time() {
+        let local_datetime = to_local(utc_datetime, time_zone);
         let mut result = heapless::String::new();
 
         write!(
@@ -389,16 +642,16 @@ pub fn get_local_time_formatted(timezone_offset_hours: i8) -> heapless::String<3
     }
 }
 
-/// Get local date formatted as a string with timezone offset applied
-/// Returns UTC date if no valid timezone offset is provided
-pub fn get_local_date_formatted(timezone_offset_hours: i8) -> heapless::String<16> {
-    if let Some(utc_datetime) = get_date_time() {
-        // Apply timezone offset
-        let offset_seconds = timezone_offset_hours as i32 * 3600;
-        let local_offset = chrono::FixedOffset::east_opt(offset_seconds)
-            .unwrap_or_else(|| chrono::FixedOffset::east_opt(0).unwrap()); // Default to UTC if invalid
+/// Get the local hour of the day, None if the time is not synced
+pub fn get_local_hour(time_zone: &TimeZone) -> Option<u8> {
+    let utc_datetime = get_date_time()?;
+    Some(to_local(utc_datetime, time_zone).hour() as u8)
+}
 
-        let local_datetime = utc_datetime.with_timezone(&local_offset);
+/// Get local date formatted as a string in the configured timezone
+pub fn get_local_date_formatted(time_zone: &TimeZone) -> heapless::String<16> {
+    if let Some(utc_datetime) = get_date_time() {
+        let local_datetime = to_local(utc_datetime, time_zone);
         let mut result = heapless::String::new();
 
         write!(
@@ -416,80 +669,3 @@ pub fn get_local_date_formatted(timezone_offset_hours: i8) -> heapless::String<1
         result
     }
 }
-
-fn write_u32_padded(s: &mut heapless::String<32>, num: u32, width: usize) {
-    let mut temp = heapless::String::<12>::new();
-    write_u32_to_temp(&mut temp, num);
-
-    // Add leading zeros if needed
-    for _ in temp.len()..width {
-        s.push('0').unwrap();
-    }
-
-    s.push_str(&temp).unwrap();
-}
-
-fn write_u32_to_temp(s: &mut heapless::String<12>, mut num: u32) {
-    if num == 0 {
-        s.push('0').unwrap();
-        return;
-    }
-
-    let mut digits = [0u8; 10];
-    let mut count = 0;
-
-    while num > 0 && count < 10 {
-        digits[count] = (num % 10) as u8 + b'0';
-        num /= 10;
-        count += 1;
-    }
-
-    for i in (0..count).rev() {
-        if s.push(digits[i] as char).is_err() {
-            break;
-        }
-    }
-}
-
-fn days_to_date(mut days: u32) -> (u32, u32, u32) {
-    // Start from 1970
-    let mut year = 1970;
-
-    // Handle full years
-    loop {
-        let days_in_year = if is_leap_year(year) { 366 } else { 365 };
-        if days >= days_in_year {
-            days -= days_in_year;
-            year += 1;
-        } else {
-            break;
-        }
-    }
-
-    // Days in each month (non-leap year)
-    const DAYS_IN_MONTH: [u32; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
-
-    let mut month = 1;
-    for &days_in_month in &DAYS_IN_MONTH {
-        let actual_days = if month == 2 && is_leap_year(year) {
-            29 // February in leap year
-        } else {
-            days_in_month
-        };
-
-        if days >= actual_days {
-            days -= actual_days;
-            month += 1;
-        } else {
-            break;
-        }
-    }
-
-    let day = days + 1; // Day is 1-indexed
-
-    (year, month, day)
-}
-
-fn is_leap_year(year: u32) -> bool {
-    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
-}
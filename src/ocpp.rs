@@ -1,28 +1,112 @@
+extern crate alloc;
+use alloc::{string::ToString, vec, vec::Vec};
 use chrono::DateTime;
 use core::{
+    cell::RefCell,
     fmt::Write,
     str::from_utf8,
     sync::atomic::{AtomicU32, Ordering},
 };
-use embassy_sync::pubsub::WaitResult;
-use embassy_time::{Duration, Timer};
-use log::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+    pubsub::WaitResult,
+};
+use embassy_time::{Duration, Instant, Timer};
 use ocpp_rs::v16::{
     call::{
-        Action, Authorize, BootNotification, Call, Heartbeat, StartTransaction, StatusNotification,
+        Action, Authorize, BootNotification, Call, Heartbeat, MeterValues, StartTransaction,
+        StatusNotification,
+    },
+    data_types::{DateTimeWrapper, MeterValue, SampledValue},
+    enums::{
+        ChargePointErrorCode, ChargePointStatus, Measurand, ReadingContext, Reason, UnitOfMeasure,
     },
-    data_types::DateTimeWrapper,
-    enums::{ChargePointErrorCode, ChargePointStatus},
     parse::{self, Message},
 };
 
 use crate::{
-    charger::{self, Charger, ChargerState, InputEvent, OutputEvent},
+    charger::{self, Charger, ChargerState, InputEvent, SessionAction, StopReason},
     config::Config,
-    mqtt::{self},
-    ntp, ocpp,
+    connectivity, crash, data_transfer, demo, diagnostics, energy, extensions, fault,
+    frame::{self, Frame, Invalid},
+    guest,
+    idempotency::{self, Confirmation},
+    info, locale, maintenance, meter, metering,
+    mqtt::{self, Priority},
+    ntp,
+    ocpp_config::{self, ConfigKey},
+    ota,
+    profile::AuthSource,
+    rcd,
+    ready::{self, Subsystem},
+    relay, reservation, rfid,
+    sessions::{self, SessionRecord},
+    smart_charging::{self, CurrentLimits, LimitSource},
+    version, waiting_list, warn,
+    watchdog::{self, Monitored},
+    webhook,
 };
 
+pub use crate::data_transfer::send_data_transfer;
+
+/// Actions the central system can initiate in OCPP 1.6, used to tell apart
+/// recognized but unsupported actions from unknown ones
+const CENTRAL_SYSTEM_ACTIONS: [&str; 19] = [
+    "CancelReservation",
+    "ChangeAvailability",
+    "ChangeConfiguration",
+    "ClearCache",
+    "ClearChargingProfile",
+    "DataTransfer",
+    "GetCompositeSchedule",
+    "GetConfiguration",
+    "GetDiagnostics",
+    "GetLocalListVersion",
+    "RemoteStartTransaction",
+    "RemoteStopTransaction",
+    "ReserveNow",
+    "Reset",
+    "SendLocalList",
+    "SetChargingProfile",
+    "TriggerMessage",
+    "UnlockConnector",
+    "UpdateFirmware",
+];
+
+/// Error codes for a CallError as defined by OCPP-J 1.6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallErrorCode {
+    NotImplemented,
+    NotSupported,
+    InternalError,
+    ProtocolError,
+    SecurityError,
+    FormationViolation,
+    PropertyConstraintViolation,
+    OccurrenceConstraintViolation,
+    TypeConstraintViolation,
+    GenericError,
+}
+
+impl CallErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotImplemented => "NotImplemented",
+            Self::NotSupported => "NotSupported",
+            Self::InternalError => "InternalError",
+            Self::ProtocolError => "ProtocolError",
+            Self::SecurityError => "SecurityError",
+            Self::FormationViolation => "FormationViolation",
+            Self::PropertyConstraintViolation => "PropertyConstraintViolation",
+            Self::OccurrenceConstraintViolation => "OccurrenceConstraintViolation",
+            Self::TypeConstraintViolation => "TypeConstraintViolation",
+            Self::GenericError => "GenericError",
+        }
+    }
+}
+
 /// Thread-safe static counter for OCPP message IDs
 static OCPP_MESSAGE_ID_COUNTER: AtomicU32 = AtomicU32::new(1);
 pub fn next_ocpp_message_id() -> heapless::String<32> {
@@ -37,86 +121,464 @@ fn get_timestamp() -> DateTimeWrapper {
     DateTimeWrapper::new(timestamp)
 }
 
+/// Calls awaiting a response from the central system, the oldest is forgotten when full
+const MAX_PENDING_CALLS: usize = 8;
+
+/// Unique id and action of the Calls awaiting a response
+static PENDING_CALLS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<heapless::Deque<(heapless::String<32>, &'static str), MAX_PENDING_CALLS>>,
+> = Mutex::new(RefCell::new(heapless::Deque::new()));
+
+fn register_pending(unique_id: &heapless::String<32>, action: &'static str) {
+    PENDING_CALLS.lock(|pending| {
+        let mut pending = pending.borrow_mut();
+        if pending.is_full() {
+            pending.pop_front();
+        }
+        let _ = pending.push_back((unique_id.clone(), action));
+    });
+}
+
+/// Take the action of a pending Call by its unique id
+fn take_pending(unique_id: &str) -> Option<&'static str> {
+    PENDING_CALLS.lock(|pending| {
+        let mut pending = pending.borrow_mut();
+        let index = pending.iter().position(|(id, _)| id == unique_id)?;
+        // Rotate the entry to the front, the Deque has no remove
+        for _ in 0..index {
+            if let Some(entry) = pending.pop_front() {
+                let _ = pending.push_back(entry);
+            }
+        }
+        pending.pop_front().map(|(_, action)| action)
+    })
+}
+
+/// Name of an action the charger sends
+fn action_name(action: &Action) -> &'static str {
+    match action {
+        Action::Authorize(_) => "Authorize",
+        Action::BootNotification(_) => "BootNotification",
+        Action::DataTransfer(_) => "DataTransfer",
+        Action::DiagnosticsStatusNotification(_) => "DiagnosticsStatusNotification",
+        Action::FirmwareStatusNotification(_) => "FirmwareStatusNotification",
+        Action::Heartbeat(_) => "Heartbeat",
+        Action::StartTransaction(_) => "StartTransaction",
+        Action::StatusNotification(_) => "StatusNotification",
+        Action::StopTransaction(_) => "StopTransaction",
+        _ => "Call",
+    }
+}
+
+/// Send a Call to the central system: assigns the unique id, passes the current time to the
+/// builder, serializes the message, registers it as awaiting a response and queues it with its
+/// priority class. A full queue drops lower classes first, a failed publish is retried by the
+/// MQTT client
+/// Returns false if the message could not be queued
+pub fn send_ocpp(
+    description: &str,
+    priority: Priority,
+    build: impl FnOnce(DateTimeWrapper) -> Action,
+) -> bool {
+    send_call(description, priority, build).is_some()
+}
+
+/// Send a Call like `send_ocpp`, returns the unique id it was sent with
+pub fn send_call(
+    description: &str,
+    priority: Priority,
+    build: impl FnOnce(DateTimeWrapper) -> Action,
+) -> Option<heapless::String<32>> {
+    let unique_id = next_ocpp_message_id();
+    let action = build(get_timestamp());
+    let name = action_name(&action);
+    let call = Message::Call(Call::new(unique_id.as_str().into(), action));
+    let Ok(message) = parse::serialize_message(&call) else {
+        warn!("OCPP: Failed to serialize {description}");
+        return None;
+    };
+
+    // In demo mode nothing reaches the broker, the simulated central system answers
+    if demo::is_enabled() {
+        if demo::answers(name) {
+            register_pending(&unique_id, name);
+            demo::answer(&unique_id, name);
+        }
+        return Some(unique_id);
+    }
+
+    register_pending(&unique_id, name);
+    if queue_message(&message, description, priority) {
+        Some(unique_id)
+    } else {
+        take_pending(&unique_id);
+        None
+    }
+}
+
 // message templates
 
-pub fn boot_notification(id: &str, config: &Config) -> Message {
-    Message::Call(Call::new(
-        id.into(),
-        Action::BootNotification(BootNotification {
-            charge_point_model: config.charger_model.into(),
-            charge_point_vendor: config.charger_vendor.into(),
-            firmware_version: Some(env!("CARGO_PKG_VERSION").into()),
-            charge_box_serial_number: Some(config.charger_serial.into()),
-            charge_point_serial_number: None,
-            iccid: None,
-            imsi: None,
-            meter_serial_number: None,
-            meter_type: None,
-        }),
-    ))
+/// The BootNotification, the optional fields are filled in by the vendor extensions. After a
+/// crash the firmware version is marked with `.crash` until the crash report is sent
+pub fn boot_notification(config: &Config) -> Action {
+    let fields = extensions::boot_fields(config);
+    let mut firmware_version = version::firmware_version();
+    if crash::is_pending() {
+        let _ = firmware_version.push_str(".crash");
+    }
+    Action::BootNotification(BootNotification {
+        charge_point_model: config.charger_model.into(),
+        charge_point_vendor: config.charger_vendor.into(),
+        firmware_version: Some(firmware_version.as_str().into()),
+        charge_box_serial_number: Some(config.charger_serial.into()),
+        charge_point_serial_number: fields
+            .charge_point_serial_number
+            .map(|value| value.as_str().into()),
+        iccid: fields.iccid.map(|value| value.as_str().into()),
+        imsi: fields.imsi.map(|value| value.as_str().into()),
+        meter_serial_number: fields
+            .meter_serial_number
+            .map(|value| value.as_str().into()),
+        meter_type: fields.meter_type.map(|value| value.as_str().into()),
+    })
 }
 
-pub fn heartbeat(id: &str) -> Message {
-    Message::Call(Call::new(id.into(), Action::Heartbeat(Heartbeat {})))
+pub fn heartbeat() -> Action {
+    Action::Heartbeat(Heartbeat {})
 }
 
-pub fn start_transaction(id: &str, id_tag: &str) -> Message {
-    Message::Call(Call::new(
-        id.into(),
-        Action::StartTransaction(StartTransaction {
-            connector_id: charger::DEFAULT_CONNECTOR_ID,
-            id_tag: id_tag.into(),
-            meter_start: 0,
-            reservation_id: None,
-            timestamp: get_timestamp(),
-        }),
-    ))
-}
-
-pub fn stop_transaction(id: &str, transaction_id: i32, id_tag: &str) -> Message {
-    Message::Call(Call::new(
-        id.into(),
-        Action::StopTransaction(ocpp_rs::v16::call::StopTransaction {
-            transaction_id,
-            id_tag: Some(id_tag.into()),
-            meter_stop: 0,
-            timestamp: get_timestamp(),
-            reason: None,
-            transaction_data: None,
+pub fn start_transaction(
+    id_tag: &str,
+    meter_start_wh: u32,
+    reservation_id: Option<i32>,
+    timestamp: DateTimeWrapper,
+) -> Action {
+    Action::StartTransaction(StartTransaction {
+        connector_id: charger::DEFAULT_CONNECTOR_ID,
+        id_tag: id_tag.into(),
+        meter_start: meter_start_wh.try_into().unwrap_or_default(),
+        reservation_id,
+        timestamp,
+    })
+}
+
+pub fn stop_transaction(
+    transaction_id: i32,
+    id_tag: &str,
+    meter_stop_wh: u32,
+    reason: Option<StopReason>,
+    timestamp: DateTimeWrapper,
+) -> Action {
+    Action::StopTransaction(ocpp_rs::v16::call::StopTransaction {
+        transaction_id,
+        id_tag: Some(id_tag.into()),
+        meter_stop: meter_stop_wh.try_into().unwrap_or_default(),
+        timestamp,
+        reason: reason.map(|reason| match reason {
+            StopReason::DeAuthorized => Reason::DeAuthorized,
+            StopReason::Offline | StopReason::Duplicate => Reason::Other,
         }),
-    ))
+        transaction_data: None,
+    })
 }
 
-pub fn status_notification(id: &str, status: ChargerState) -> Message {
+fn sampled_value(value: impl ToString, measurand: Measurand, unit: UnitOfMeasure) -> SampledValue {
+    SampledValue {
+        value: value.to_string(),
+        context: Some(ReadingContext::SamplePeriodic),
+        format: None,
+        measurand: Some(measurand),
+        phase: None,
+        location: None,
+        unit: Some(unit),
+    }
+}
+
+/// Periodic meter values of a transaction, the register with the power, current and voltage of
+/// the meter IC
+pub fn meter_values(
+    transaction_id: i32,
+    register_wh: u32,
+    measurement: Option<meter::Measurement>,
+    timestamp: DateTimeWrapper,
+) -> Action {
+    let mut sampled_value = vec![sampled_value(
+        register_wh,
+        Measurand::EnergyActiveImportRegister,
+        UnitOfMeasure::Wh,
+    )];
+    if let Some(measurement) = measurement {
+        sampled_value.extend([
+            sampled_value(
+                measurement.watts as u32,
+                Measurand::PowerActiveImport,
+                UnitOfMeasure::W,
+            ),
+            sampled_value(
+                format_args!("{:.1}", measurement.amps),
+                Measurand::CurrentImport,
+                UnitOfMeasure::A,
+            ),
+            sampled_value(
+                measurement.volts as u32,
+                Measurand::Voltage,
+                UnitOfMeasure::V,
+            ),
+        ]);
+    }
+    Action::MeterValues(MeterValues {
+        connector_id: charger::DEFAULT_CONNECTOR_ID,
+        transaction_id: Some(transaction_id),
+        meter_value: Vec::from([MeterValue {
+            timestamp,
+            sampled_value,
+        }]),
+    })
+}
+
+pub fn status_notification(status: ChargerState, timestamp: DateTimeWrapper) -> Action {
+    let (error_code, info) = match status {
+        ChargerState::Faulted => (
+            fault::last_fault().map_or(ChargePointErrorCode::OtherError, |f| f.error_code()),
+            None,
+        ),
+        // A latched lockout is told apart from a regular unavailable state by its info
+        ChargerState::Unavailable => match fault::lockout() {
+            Some(fault) => (fault.error_code(), Some("FaultLockout".into())),
+            None if maintenance::is_active() => {
+                (ChargePointErrorCode::NoError, Some("Maintenance".into()))
+            }
+            None => (reader_error_code(), None),
+        },
+        _ => (reader_error_code(), None),
+    };
     let status = match status {
         ChargerState::Available => ChargePointStatus::Available,
         ChargerState::Preparing => ChargePointStatus::Preparing,
+        // The session goes on, the relay is open for a demand response
+        ChargerState::Charging if relay::is_suspended() => ChargePointStatus::SuspendedEVSE,
         ChargerState::Charging => ChargePointStatus::Charging,
         ChargerState::Faulted => ChargePointStatus::Faulted,
+        ChargerState::Reserved => ChargePointStatus::Reserved,
         ChargerState::Off => ChargePointStatus::Unavailable,
         _ => ChargePointStatus::Unavailable, // Default case
     };
-    Message::Call(Call::new(
-        id.into(),
-        Action::StatusNotification(StatusNotification {
-            connector_id: charger::DEFAULT_CONNECTOR_ID,
-            error_code: ChargePointErrorCode::NoError,
-            status,
-            timestamp: Some(get_timestamp()),
-            info: None,
-            vendor_id: None,
-            vendor_error_code: None,
-        }),
-    ))
+    Action::StatusNotification(StatusNotification {
+        connector_id: charger::DEFAULT_CONNECTOR_ID,
+        error_code,
+        status,
+        timestamp: Some(timestamp),
+        info,
+        vendor_id: None,
+        vendor_error_code: None,
+    })
 }
 
-pub fn authorize(id: &str, id_tag: &str) -> Message {
-    Message::Call(Call::new(
-        id.into(),
-        Action::Authorize(Authorize {
-            id_tag: id_tag.into(),
-        }),
-    ))
+/// ReaderFailure while the card reader doesn't answer, a fault of its own is reported instead
+fn reader_error_code() -> ChargePointErrorCode {
+    if rfid::reader_lost() {
+        ChargePointErrorCode::ReaderFailure
+    } else {
+        ChargePointErrorCode::NoError
+    }
+}
+
+pub fn authorize(id_tag: &str) -> Action {
+    Action::Authorize(Authorize {
+        id_tag: id_tag.into(),
+    })
+}
+
+/// Build a CallError frame: `[4,"<uniqueId>","<errorCode>","<errorDescription>",{}]`
+/// The unique id is echoed as received, the description is escaped
+/// Returns None if the frame doesn't fit the buffer
+pub fn call_error(
+    unique_id: &str,
+    code: CallErrorCode,
+    description: &str,
+) -> Option<heapless::String<256>> {
+    let mut frame = heapless::String::new();
+    write!(frame, "[4,\"{unique_id}\",\"{}\",\"", code.as_str()).ok()?;
+    push_json_escaped(&mut frame, description)?;
+    frame.push_str("\",{}]").ok()?;
+    Some(frame)
+}
+
+/// Append a string to a JSON string literal, escaping quotes, backslashes and control characters
+pub fn push_json_escaped<const N: usize>(out: &mut heapless::String<N>, value: &str) -> Option<()> {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\"").ok()?,
+            '\\' => out.push_str("\\\\").ok()?,
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).ok()?,
+            c => out.push(c).ok()?,
+        }
+    }
+    Some(())
+}
+
+/// Largest CallResult payload, a GetConfiguration of all keys is the largest response
+pub const MAX_CALL_PAYLOAD: usize = 1536;
+
+/// Response to a Call from the central system, either a CallResult payload or a CallError
+pub type CallResponse = Result<heapless::String<MAX_CALL_PAYLOAD>, (CallErrorCode, &'static str)>;
+
+/// Build a CallResult frame: `[3,"<uniqueId>",{payload}]`
+/// Returns None if the frame doesn't fit the buffer
+pub fn call_result(
+    unique_id: &str,
+    payload: &str,
+) -> Option<heapless::String<{ MAX_CALL_PAYLOAD + 64 }>> {
+    let mut frame = heapless::String::new();
+    write!(frame, "[3,\"{unique_id}\",{payload}]").ok()?;
+    Some(frame)
+}
+
+/// Queue a CallResult response for a Call from the central system
+pub fn send_call_result(unique_id: &str, payload: &str) {
+    match call_result(unique_id, payload) {
+        Some(frame) => {
+            queue_message(&frame, "CallResult", Priority::Normal);
+        }
+        None => warn!("OCPP: CallResult for {unique_id} too large, unable to respond"),
+    }
+}
+
+/// Queue a CallError response for a Call from the central system
+pub fn send_call_error(unique_id: &str, code: CallErrorCode, description: &str) {
+    match call_error(unique_id, code, description) {
+        Some(frame) => {
+            if queue_message(&frame, "CallError", Priority::Normal) {
+                info!("OCPP: CallError {} for {unique_id}", code.as_str());
+            }
+        }
+        None => warn!("OCPP: CallError for {unique_id} too large, unable to respond"),
+    }
+}
+
+/// Queue a serialized OCPP message on the MQTT send queue with its priority class
+/// Returns false if the message could not be queued
+pub fn queue_message(message: &str, description: &str, priority: Priority) -> bool {
+    let mut msg_vec = heapless::Vec::new();
+    if msg_vec.extend_from_slice(message.as_bytes()).is_err() {
+        warn!("OCPP: {description} message too large for queue");
+        return false;
+    }
+    if mqtt::MQTT_SEND_QUEUE.enqueue(priority, msg_vec) {
+        info!("OCPP: Successfully sent {description}");
+        true
+    } else {
+        warn!("OCPP: Failed to send {description}, MQTT queue full");
+        false
+    }
+}
+
+/// Extract the value of an integer field from a JSON payload, e.g. `"retries":3`
+pub fn json_integer_field(payload: &str, key: &str) -> Option<i64> {
+    let mut search_from = 0;
+    while let Some(pos) = payload[search_from..].find(key) {
+        let key_start = search_from + pos;
+        let key_end = key_start + key.len();
+        search_from = key_end;
+
+        if !payload[..key_start].ends_with('"') || !payload[key_end..].starts_with('"') {
+            continue;
+        }
+        let Some(value) = payload[key_end + 1..].trim_start().strip_prefix(':') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let end = value
+            .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+            .unwrap_or(value.len());
+        return value[..end].parse().ok();
+    }
+    None
+}
+
+/// Extract the value of a boolean field from a JSON payload, e.g. `"charging":true`
+pub fn json_bool_field(payload: &str, key: &str) -> Option<bool> {
+    let mut search_from = 0;
+    while let Some(pos) = payload[search_from..].find(key) {
+        let key_start = search_from + pos;
+        let key_end = key_start + key.len();
+        search_from = key_end;
+
+        if !payload[..key_start].ends_with('"') || !payload[key_end..].starts_with('"') {
+            continue;
+        }
+        let Some(value) = payload[key_end + 1..].trim_start().strip_prefix(':') else {
+            continue;
+        };
+        let value = value.trim_start();
+        if value.starts_with("true") {
+            return Some(true);
+        }
+        if value.starts_with("false") {
+            return Some(false);
+        }
+        return None;
+    }
+    None
+}
+
+/// Copy a JSON string value, as returned by `json_string_field`, resolving escape sequences
+/// Returns None if the unescaped value doesn't fit
+pub fn json_unescape<const N: usize>(value: &str) -> Option<heapless::String<N>> {
+    let mut out = heapless::String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let code: heapless::String<4> = chars.by_ref().take(4).collect();
+                    char::from_u32(u32::from_str_radix(&code, 16).ok()?)?
+                }
+                escaped => escaped,
+            }
+        } else {
+            c
+        };
+        out.push(c).ok()?;
+    }
+    Some(out)
+}
+
+/// Extract the value of a string field from a JSON payload, e.g. `"status":"Accepted"`
+/// Escaped characters are returned as-is
+pub fn json_string_field<'a>(payload: &'a str, key: &str) -> Option<&'a str> {
+    let mut search_from = 0;
+    while let Some(pos) = payload[search_from..].find(key) {
+        let key_start = search_from + pos;
+        let key_end = key_start + key.len();
+        search_from = key_end;
+
+        // The key must be a complete quoted name followed by a colon
+        if !payload[..key_start].ends_with('"') || !payload[key_end..].starts_with('"') {
+            continue;
+        }
+        let Some(value) = payload[key_end + 1..].trim_start().strip_prefix(':') else {
+            continue;
+        };
+        let value = value.trim_start().strip_prefix('"')?;
+
+        let mut escaped = false;
+        for (i, c) in value.char_indices() {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => return Some(&value[..i]),
+                _ => escaped = false,
+            }
+        }
+        return None;
+    }
+    None
 }
 
 // aysnc tasks
@@ -125,27 +587,32 @@ pub fn authorize(id: &str, id_tag: &str) -> Message {
 pub async fn authorize_task(charger: &'static Charger) {
     info!("TASK: Started Authorize Task (PubSub Mode)");
 
+    let config = Config::from_config();
     let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
 
     loop {
         // Wait for state changes via PubSub
-        if let WaitResult::Message((current_state, _)) = subscriber.next_message().await {
-            if current_state == ChargerState::Authorizing {
-                let id_tag = charger.get_id_tag().await;
-                info!("OCPP: Sending authorization request for tag: {id_tag}");
-                let authorize_request = authorize(&next_ocpp_message_id(), &id_tag);
-                let message = parse::serialize_message(&authorize_request).unwrap();
-
-                match mqtt::MQTT_SEND_CHANNEL
-                    .try_send(heapless::Vec::from_slice(message.as_bytes()).unwrap())
+        if let WaitResult::Message((current_state, output_events)) = subscriber.next_message().await
+        {
+            match current_state {
+                // A cable was just inserted, free vend starts charging without a card swipe
+                ChargerState::Preparing
+                    if output_events.is_empty() && config.behavior.free_vend =>
                 {
-                    Ok(()) => {
-                        info!("OCPP: Successfully sent authorization request");
-                    }
-                    Err(_) => {
-                        warn!("OCPP: Failed to send authorization request, MQTT queue full");
-                    }
+                    info!(
+                        "OCPP: Free vend, starting with id tag {}",
+                        config.default_id_tag
+                    );
+                    charger.set_id_tag(config.default_id_tag).await;
+                    charger::STATE_IN_CHANNEL
+                        .send(InputEvent::SwipeDetected)
+                        .await;
                 }
+                ChargerState::Authorizing => {
+                    let id_tag = charger.get_id_tag().await;
+                    authorize_id_tag(&config, &id_tag).await;
+                }
+                _ => {}
             }
         }
         // ignore any other messages
@@ -153,53 +620,111 @@ pub async fn authorize_task(charger: &'static Charger) {
     }
 }
 
+/// Whether the local list authorizes in place of the central system, it's offline or not
+/// answering and LocalAuthorizeOffline is set
+fn authorizes_offline() -> bool {
+    connectivity::is_backend_unavailable() && ocpp_config::boolean(ConfigKey::LocalAuthorizeOffline)
+}
+
+/// Authorize an id tag with the configured authorization chain, the first source
+/// that can decide wins
+async fn authorize_id_tag(config: &Config, id_tag: &str) {
+    if !reservation::accepts(id_tag) {
+        info!("OCPP: Id tag {id_tag} rejected, the charger is reserved for another id tag");
+        charger::STATE_IN_CHANNEL.send(InputEvent::Rejected).await;
+        return;
+    }
+
+    if !waiting_list::accepts(id_tag) {
+        info!(
+            "OCPP: Id tag {id_tag} rejected, it's the turn of the next driver on the waiting list"
+        );
+        charger::STATE_IN_CHANNEL.send(InputEvent::Rejected).await;
+        return;
+    }
+
+    if ota::is_updating() {
+        info!("OCPP: Id tag {id_tag} rejected, a firmware update is in progress");
+        charger::STATE_IN_CHANNEL.send(InputEvent::Rejected).await;
+        return;
+    }
+
+    // During maintenance only the master card can start a test session
+    if maintenance::is_active() {
+        if fault::is_master_id_tag(id_tag) {
+            info!("OCPP: Master card accepted for a maintenance test session");
+            charger::STATE_IN_CHANNEL.send(InputEvent::Accepted).await;
+        } else {
+            info!("OCPP: Id tag {id_tag} rejected, the charger is in maintenance");
+            charger::STATE_IN_CHANNEL.send(InputEvent::Rejected).await;
+        }
+        return;
+    }
+
+    for source in config.behavior.authorization.iter() {
+        match source {
+            AuthSource::FreeVend => {
+                info!("OCPP: Id tag {id_tag} accepted by free vend");
+                charger::STATE_IN_CHANNEL.send(InputEvent::Accepted).await;
+                return;
+            }
+            // LocalPreAuthorize is off, the central system decides unless it can't be reached
+            // and LocalAuthorizeOffline lets the local list decide in its place
+            AuthSource::LocalList
+                if !ocpp_config::boolean(ConfigKey::LocalPreAuthorize) && !authorizes_offline() => {
+            }
+            AuthSource::LocalList if config.is_local_id_tag(id_tag) => {
+                info!("OCPP: Id tag {id_tag} accepted by the local list");
+                charger::STATE_IN_CHANNEL.send(InputEvent::Accepted).await;
+                return;
+            }
+            AuthSource::LocalList => {}
+            AuthSource::GuestCode if guest::accepts(id_tag) => {
+                info!("OCPP: Id tag {id_tag} accepted by a guest code");
+                charger::STATE_IN_CHANNEL.send(InputEvent::Accepted).await;
+                return;
+            }
+            AuthSource::GuestCode => {}
+            AuthSource::CentralSystem => {
+                info!("OCPP: Sending authorization request for tag: {id_tag}");
+                send_ocpp("authorization request", Priority::Critical, |_| {
+                    authorize(id_tag)
+                });
+                return;
+            }
+        }
+    }
+
+    info!("OCPP: Id tag {id_tag} not accepted by any authorization source");
+    charger::STATE_IN_CHANNEL.send(InputEvent::Rejected).await;
+}
+
 #[embassy_executor::task]
 pub async fn status_notification_task(charger: &'static Charger) {
     info!("TASK: Started Status Notification Handler (PubSub Mode)");
 
     let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
 
-    Timer::after(Duration::from_secs(3)).await;
+    // Nothing but the BootNotification is sent before the central system accepted it
+    ready::wait(Subsystem::Boot).await;
 
     let initial_state = charger.get_state().await;
-
-    let status_notification =
-        ocpp::status_notification(&ocpp::next_ocpp_message_id(), initial_state);
-    let message = parse::serialize_message(&status_notification).unwrap();
-
-    match mqtt::MQTT_SEND_CHANNEL.try_send(heapless::Vec::from_slice(message.as_bytes()).unwrap()) {
-        Ok(()) => {
-            info!(
-                "OCPP: Sent initial status notification for state: {}",
-                initial_state.as_str()
-            );
-        }
-        Err(_) => {
-            warn!("OCPP: Failed to send initial notification, MQTT queue full");
-        }
-    }
+    send_ocpp("initial status notification", Priority::Low, |timestamp| {
+        status_notification(initial_state, timestamp)
+    });
 
     loop {
-        if let WaitResult::Message((current_state, _)) = subscriber.next_message().await {
-            let status_notification =
-                ocpp::status_notification(&ocpp::next_ocpp_message_id(), current_state);
-            let message = parse::serialize_message(&status_notification).unwrap();
-
-            if current_state != ChargerState::Authorizing {
-                match mqtt::MQTT_SEND_CHANNEL
-                    .try_send(heapless::Vec::from_slice(message.as_bytes()).unwrap())
-                {
-                    Ok(()) => {
-                        info!(
-                            "OCPP: Sent status notification for state: {}",
-                            current_state.as_str()
-                        );
-                    }
-                    Err(_) => {
-                        warn!("OCPP: Failed to send notification, MQTT queue full");
-                    }
-                }
-            }
+        // The state is sent again when the card reader is lost or back, for its error code
+        let current_state =
+            match select(subscriber.next_message(), rfid::READER_CHANGED.wait()).await {
+                Either::First(WaitResult::Message((current_state, _))) => current_state,
+                Either::First(WaitResult::Lagged(_)) => continue,
+                Either::Second(()) => charger.get_state().await,
+            };
+        if current_state != ChargerState::Authorizing {
+            send_ocpp("status notification", Priority::Low, |timestamp| {
+                status_notification(current_state, timestamp)
+            });
         }
         Timer::after(Duration::from_millis(100)).await; // Avoid busy loop
     }
@@ -209,123 +734,289 @@ pub async fn status_notification_task(charger: &'static Charger) {
 #[embassy_executor::task]
 pub async fn heartbeat_task() {
     info!("TASK: Started Network Heartbeat");
-    Timer::after(Duration::from_secs(5)).await;
+    ready::wait(Subsystem::Boot).await;
 
-    let ocpp_heartbeat_interval = Config::from_config().ocpp_heartbeat_interval;
     loop {
-        let heartbeat_req = &ocpp::heartbeat(&ocpp::next_ocpp_message_id());
-        let message = parse::serialize_message(heartbeat_req).unwrap();
-
-        let mut msg_vec = heapless::Vec::new();
-        if msg_vec.extend_from_slice(message.as_bytes()).is_ok() {
-            match mqtt::MQTT_SEND_CHANNEL.try_send(msg_vec) {
-                Ok(()) => {
-                    info!("OCPP: Successfully sent heartbeat message");
-                }
-                Err(_) => {
-                    warn!("OCPP: Failed to send heartbeat, MQTT queue full");
-                }
-            }
-        } else {
-            warn!("OCPP: Heartbeat message too large for queue");
+        send_ocpp("heartbeat message", Priority::Low, |_| heartbeat());
+        // Read on every beat, the central system can change it with ChangeConfiguration
+        let interval = ocpp_config::integer(ConfigKey::HeartbeatInterval).max(1);
+        Timer::after(Duration::from_secs(interval as u64)).await;
+    }
+}
+
+/// Task to send the meter values of the running transaction every MeterValueSampleInterval,
+/// only with a meter IC. Without one the register is an estimate and only sent at the start
+/// and stop of a transaction
+#[embassy_executor::task]
+pub async fn meter_values_task(charger: &'static Charger) {
+    info!("TASK: Started Meter Values");
+    ready::wait(Subsystem::Boot).await;
+
+    loop {
+        // Read on every sample, the central system can change it with ChangeConfiguration
+        let interval = ocpp_config::integer(ConfigKey::MeterValueSampleInterval);
+        if interval <= 0 {
+            Timer::after(Duration::from_secs(10)).await;
+            continue;
+        }
+        Timer::after(Duration::from_secs(interval as u64)).await;
+
+        if !metering::present() || !charger.get_state().await.is_charging() {
+            continue;
         }
-        Timer::after(Duration::from_secs(ocpp_heartbeat_interval.into())).await;
+        // No meter values before the central system assigned the transaction id
+        let transaction_id = charger.get_transaction_id().await;
+        if transaction_id == 0 {
+            continue;
+        }
+        let register_wh = register_reading();
+        let measurement = meter::measurement();
+        send_ocpp("meter values", Priority::Low, |timestamp| {
+            meter_values(transaction_id, register_wh, measurement, timestamp)
+        });
     }
 }
 
+/// Delay before a BootNotification without an accepting response is sent again
+const BOOT_RETRY_SECS: u64 = 60;
+
 #[embassy_executor::task]
 pub async fn boot_notification_task() {
     info!("TASK: Started Boot Notification");
 
-    let boot_notification_req =
-        &ocpp::boot_notification(&ocpp::next_ocpp_message_id(), &Config::from_config());
-    let message = parse::serialize_message(boot_notification_req).unwrap();
+    let config = Config::from_config();
+    if demo::is_enabled() {
+        info!("OCPP: Demo mode, BootNotification suppressed");
+        ready::set(Subsystem::Boot);
+        return;
+    }
+    ready::wait(Subsystem::Mqtt).await;
 
-    let mut msg_vec = heapless::Vec::new();
-    if msg_vec.extend_from_slice(message.as_bytes()).is_ok() {
-        match mqtt::MQTT_SEND_CHANNEL.try_send(msg_vec) {
-            Ok(()) => {
-                info!("OCPP: Successfully sent boot notification");
-            }
-            Err(_) => {
-                warn!("OCPP: Failed to send boot notification, MQTT queue full");
+    // Sent again until the central system accepts it
+    loop {
+        send_ocpp("boot notification", Priority::Normal, |_| {
+            boot_notification(&config)
+        });
+        let retry = Timer::after(Duration::from_secs(BOOT_RETRY_SECS));
+        match select(ready::wait(Subsystem::Boot), retry).await {
+            Either::First(()) => {
+                crash::report(config.charger_vendor);
+                return;
             }
+            Either::Second(()) => warn!("OCPP: BootNotification not accepted, sending it again"),
         }
-    } else {
-        warn!("OCPP: Boot Notification message too large for queue");
     }
 }
 
+/// Send a receipt for a stopped transaction as a `{vendor}/Receipt` DataTransfer
+/// The energy and cost are also included as text in the number formats of the configured locale,
+/// for the receipt to be shown as is
+fn send_receipt(config: &Config, id_tag: &str, session: &SessionRecord) {
+    let transaction_id = session.transaction_id;
+    let energy_wh = u64::from(session.energy_wh());
+    let mut receipt = heapless::String::<384>::new();
+    let _ = write!(receipt, "{{\"transactionId\":{transaction_id},\"idTag\":\"");
+    let _ = push_json_escaped(&mut receipt, id_tag);
+    let _ = write!(
+        receipt,
+        "\",\"durationSecs\":{},\"stopTime\":\"{}\",\"energyWh\":{energy_wh},\"energy\":\"{}\"",
+        session.duration_secs,
+        ntp::get_iso8601_time(),
+        config.locale.energy(energy_wh)
+    );
+    if config.tariff_cents_per_kwh > 0 {
+        let cost = locale::cost_cents(energy_wh, config.tariff_cents_per_kwh);
+        let _ = write!(receipt, ",\"cost\":\"");
+        let _ = push_json_escaped(&mut receipt, &config.locale.money(cost));
+        let _ = receipt.push('"');
+    }
+    let _ = receipt.push('}');
+
+    if send_data_transfer(config.charger_vendor, Some("Receipt"), Some(&receipt)) {
+        info!("OCPP: Sent receipt for transaction {transaction_id}");
+    }
+}
+
+/// Reading of the energy register for the meter values of a transaction, in Wh
+fn register_reading() -> u32 {
+    u32::try_from(meter::register_wh()).unwrap_or(u32::MAX)
+}
+
 #[embassy_executor::task]
-pub async fn transaction_handler_task(charger: &'static Charger) {
+pub async fn transaction_handler_task(charger: &'static Charger, limits: &'static CurrentLimits) {
     info!("TASK: Started OCPP Transaction Handler");
 
+    let config = Config::from_config();
     let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
+    let mut started_at = Instant::now();
+    let mut started_unix = 0;
+    let mut meter_start = 0;
 
     loop {
-        if let WaitResult::Message((current_state, output_events)) = subscriber.next_message().await
-        {
-            match current_state {
-                ChargerState::Charging if output_events.contains(&OutputEvent::ApplyPower) => {
-                    let id_tag = charger.get_id_tag().await;
-                    let message = parse::serialize_message(&start_transaction(
-                        &next_ocpp_message_id(),
-                        &id_tag,
-                    ))
-                    .unwrap();
-                    let mut msg_vec = heapless::Vec::new();
-                    if msg_vec.extend_from_slice(message.as_bytes()).is_ok() {
-                        match mqtt::MQTT_SEND_CHANNEL.try_send(msg_vec) {
-                            Ok(()) => {
-                                info!("OCPP: Successfully sent StartTransaction message");
-                            }
-                            Err(_) => {
-                                warn!("OCPP: Failed to send StartTransaction message, MQTT queue full");
-                            }
-                        }
+        watchdog::idle(Monitored::OcppTransactions);
+        let message = subscriber.next_message().await;
+        watchdog::check_in(Monitored::OcppTransactions);
+        if let WaitResult::Message((current_state, output_events)) = message {
+            let open_session = idempotency::open_session();
+            match charger::session_action(current_state, &output_events, open_session.is_some()) {
+                Some(SessionAction::AlreadyStarted) => {
+                    if let Some(uuid) = open_session {
+                        warn!("OCPP: Session {uuid} already started, StartTransaction suppressed");
                     }
                 }
-                ChargerState::Preparing if output_events.contains(&OutputEvent::RemovePower) => {
+                Some(SessionAction::Start) => {
+                    started_at = Instant::now();
+                    started_unix = sessions::session_time(ntp::get_current_unix_time());
+                    meter_start = register_reading();
+                    // Until the response, the transaction id of the previous session isn't reused
+                    charger.set_transaction_id(0).await;
                     let id_tag = charger.get_id_tag().await;
-                    let message = parse::serialize_message(&stop_transaction(
-                        &next_ocpp_message_id(),
-                        charger.get_transaction_id().await,
-                        &id_tag,
-                    ))
-                    .unwrap();
-                    let mut msg_vec = heapless::Vec::new();
-                    if msg_vec.extend_from_slice(message.as_bytes()).is_ok() {
-                        match mqtt::MQTT_SEND_CHANNEL.try_send(msg_vec) {
-                            Ok(()) => {
-                                info!("OCPP: Successfully sent StopTransaction message");
-                            }
-                            Err(_) => {
-                                warn!(
-                                    "OCPP: Failed to send StopTransaction message, MQTT queue full"
-                                );
-                            }
-                        }
-                    } else {
-                        warn!("OCPP: StopTransaction message too large for queue");
+                    let reservation_id = reservation::consume(&id_tag);
+                    waiting_list::consume(&id_tag);
+                    webhook::session_started(&id_tag);
+                    idempotency::start(config.charger_vendor, &id_tag, meter_start, reservation_id);
+                }
+                Some(SessionAction::Stop) => {
+                    let id_tag = charger.get_id_tag().await;
+                    let transaction_id = charger.get_transaction_id().await;
+                    let reason = charger.take_stop_reason().await;
+                    limits.clear_limit(LimitSource::Deauthorized).await;
+                    let meter_stop = register_reading();
+                    idempotency::stop(transaction_id, &id_tag, meter_stop, reason);
+
+                    let mut session_id_tag = heapless::String::new();
+                    let _ = session_id_tag.push_str(id_tag.get(..20).unwrap_or(&id_tag));
+                    let session = SessionRecord {
+                        transaction_id,
+                        id_tag: session_id_tag,
+                        started: started_unix,
+                        stopped: sessions::session_time(ntp::get_current_unix_time()),
+                        duration_secs: started_at.elapsed().as_secs() as u32,
+                        meter_start,
+                        meter_stop,
+                    };
+
+                    if config.behavior.receipts {
+                        send_receipt(&config, &id_tag, &session);
                     }
+                    webhook::session_ended(&session);
+                    sessions::record(&session);
+                    energy::session_recorded(session.energy_wh());
                 }
-                _ => {
-                    // ignoring other states
+                None => {
+                    // ignoring other transitions
                 }
             }
         }
     }
 }
 
-/// Task to handle incoming OCPP responses from MQTT
-/// Note: as the payload differs for different message types, we would need a dynamic way of parsing json
-/// none of the no_std json libraries support this (they all require heap allocation)
-/// so for now we just parse the messages as strings and use string matching
+/// Time a message may take to handle before it's counted as over budget
+const PARSE_BUDGET: Duration = Duration::from_millis(50);
+/// Calls larger than this are handled by the deferred call task, so the responses the state
+/// machine waits for aren't held up by a SendLocalList or a large DataTransfer
+const DEFER_SIZE: usize = 1024;
+
+/// Calls waiting for the deferred call task, handled in place while it's full
+static DEFERRED_CALLS: Channel<CriticalSectionRawMutex, heapless::Vec<u8, 2048>, 2> =
+    Channel::new();
+/// RAM of the deferred calls, counted against the channels budget
+pub const RAM: usize = size_of_val(&DEFERRED_CALLS);
+
+static MESSAGES_HANDLED: AtomicU32 = AtomicU32::new(0);
+static HANDLE_TOTAL_US: AtomicU32 = AtomicU32::new(0);
+static HANDLE_MAX_US: AtomicU32 = AtomicU32::new(0);
+static OVER_BUDGET: AtomicU32 = AtomicU32::new(0);
+static CALLS_DEFERRED: AtomicU32 = AtomicU32::new(0);
+
+/// Time taken to handle the messages from the central system since boot
+pub struct ParseStats {
+    pub handled: u32,
+    pub average_us: u32,
+    pub max_us: u32,
+    pub over_budget: u32,
+    pub deferred: u32,
+}
+
+pub fn parse_stats() -> ParseStats {
+    let handled = MESSAGES_HANDLED.load(Ordering::Relaxed);
+    ParseStats {
+        handled,
+        average_us: HANDLE_TOTAL_US.load(Ordering::Relaxed) / handled.max(1),
+        max_us: HANDLE_MAX_US.load(Ordering::Relaxed),
+        over_budget: OVER_BUDGET.load(Ordering::Relaxed),
+        deferred: CALLS_DEFERRED.load(Ordering::Relaxed),
+    }
+}
+
+/// Record the time a message took to handle, including the time it yielded to other tasks
+fn record_handle_time(started: Instant, len: usize) {
+    let elapsed = started.elapsed();
+    let us = u32::try_from(elapsed.as_micros()).unwrap_or(u32::MAX);
+    MESSAGES_HANDLED.fetch_add(1, Ordering::Relaxed);
+    HANDLE_TOTAL_US.fetch_add(us, Ordering::Relaxed);
+    HANDLE_MAX_US.fetch_max(us, Ordering::Relaxed);
+    if elapsed > PARSE_BUDGET {
+        OVER_BUDGET.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "OCPP: Handling a message of {len} bytes took {}ms, over the budget of {}ms",
+            elapsed.as_millis(),
+            PARSE_BUDGET.as_millis()
+        );
+    }
+}
+
+/// Let the other tasks run between the steps of handling a message, a timer always yields
+/// at least once even when it has expired
+async fn yield_now() {
+    Timer::after(Duration::from_ticks(0)).await;
+}
+
+/// Hand a large Call to the deferred call task, false when its queue is full
+fn defer_call(message: &heapless::Vec<u8, 2048>) -> bool {
+    if DEFERRED_CALLS.try_send(message.clone()).is_err() {
+        return false;
+    }
+    CALLS_DEFERRED.fetch_add(1, Ordering::Relaxed);
+    info!("OCPP: Deferred a Call of {} bytes", message.len());
+    true
+}
+
+/// Task to handle the large Calls from the central system, only while no other messages are
+/// waiting so it runs at a lower priority than the response handler
+#[embassy_executor::task]
+pub async fn deferred_call_task(charger: &'static Charger) {
+    info!("TASK: Started OCPP Deferred Call Handler");
+
+    loop {
+        let message = DEFERRED_CALLS.receive().await;
+        while !mqtt::MQTT_RECEIVE_CHANNEL.is_empty() {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        let started = Instant::now();
+        // Parsed by the response handler before the Call was deferred
+        if let Some(Ok(Frame::Call {
+            unique_id,
+            action,
+            payload,
+        })) = from_utf8(&message).ok().map(frame::parse)
+        {
+            handle_call(charger, unique_id, action, payload).await;
+        }
+        record_handle_time(started, message.len());
+    }
+}
+
+/// Task to handle incoming OCPP messages from MQTT
+/// The frame is parsed by the frame module, as the payload differs for different actions its
+/// fields are read with string matching (`json_string_field`) instead of deserializing it
 #[embassy_executor::task]
-pub async fn response_handler_task(charger: &'static Charger) {
+pub async fn response_handler_task(charger: &'static Charger, limits: &'static CurrentLimits) {
     info!("TASK: Started OCPP Response Handler");
 
     loop {
+        watchdog::check_in(Monitored::OcppResponses);
         let message = match embassy_time::with_timeout(
             Duration::from_millis(1000), // 1 second timeout
             mqtt::MQTT_RECEIVE_CHANNEL.receive(),
@@ -339,6 +1030,7 @@ pub async fn response_handler_task(charger: &'static Charger) {
             }
         };
         let mut new_input_event: InputEvent = InputEvent::None;
+        let started = Instant::now();
 
         let message_str = match from_utf8(&message) {
             Ok(s) => s,
@@ -348,94 +1040,277 @@ pub async fn response_handler_task(charger: &'static Charger) {
             }
         };
 
-        if message_str.starts_with('[') && message_str.ends_with(']') {
-            let inner = &message_str[1..message_str.len() - 1]; // Remove brackets
-
-            let parts: heapless::Vec<&str, 3> = inner.splitn(3, ',').collect();
-
-            if parts.len() == 3 {
-                if let Ok(call_result_id) = parts[0].parse::<u8>() {
-                    if call_result_id == 3 {
-                        let message_type = parts[1].trim().trim_matches('"');
-                        let payload = parts[2]; // JSON payload as string
-
-                        match message_type {
-                            "Authorize" => {
-                                info!("OCPP: Received Authorize response");
-
-                                // Extract status from payload
-                                if let Some(status_start) = payload.find("\"status\":\"") {
-                                    let status_pos = status_start + 10; // Skip past "status":"
-                                    if let Some(status_end) = payload[status_pos..].find('"') {
-                                        let status = &payload[status_pos..status_pos + status_end];
-                                        if status == "Accepted" {
-                                            new_input_event = InputEvent::Accepted;
-                                            info!("OCPP: Authorization accepted");
-                                        } else {
-                                            new_input_event = InputEvent::Rejected;
-                                            info!("OCPP: Authorization rejected with status: {status}");
-                                        }
-                                    }
-                                }
-                            }
-                            "StartTransaction" => {
-                                info!("OCPP: Received StartTransaction response");
-
-                                if let Some(tx_start) = payload.find("\"transactionId\":") {
-                                    let tx_pos = tx_start + 16; // Skip past "transactionId":
-                                    if let Some(tx_end) = payload[tx_pos..].find(&[',', '}'][..]) {
-                                        let tx_id_str = &payload[tx_pos..tx_pos + tx_end];
-                                        if let Ok(transaction_id) = tx_id_str.parse::<i32>() {
-                                            match embassy_time::with_timeout(
-                                                Duration::from_millis(500),
-                                                charger.set_transaction_id(transaction_id),
-                                            ).await {
-                                                Ok(_) => info!("OCPP: Successfully set transaction ID to {transaction_id}"),
-                                                Err(_) => warn!("OCPP: Timeout setting transaction ID"),
-                                            }
-                                        }
-                                    }
-                                }
-
-                                if let Some(status_start) = payload.find("\"status\":\"") {
-                                    let status_pos = status_start + 10; // Skip past "status":"
-                                    if let Some(status_end) = payload[status_pos..].find('"') {
-                                        let status = &payload[status_pos..status_pos + status_end];
-                                        if status == "Accepted" {
-                                            info!("OCPP: StartTransaction accepted");
-                                        } else {
-                                            warn!("OCPP: StartTransaction rejected");
-                                        }
-                                    }
-                                }
-                            }
-                            "Heartbeat" => {
-                                info!("OCPP: Received Heartbeat response");
-                            }
-                            "BootNotification" => {
-                                info!("OCPP: Received BootNotification response");
+        match frame::parse(message_str) {
+            Ok(Frame::Call { .. }) if message.len() > DEFER_SIZE && defer_call(&message) => {
+                continue
+            }
+            Ok(Frame::Call {
+                unique_id,
+                action,
+                payload,
+            }) => {
+                yield_now().await;
+                handle_call(charger, unique_id, action, payload).await;
+            }
+            Ok(Frame::CallResult { unique_id, payload }) => {
+                yield_now().await;
+                new_input_event = handle_call_result(charger, limits, unique_id, payload).await;
+            }
+            Ok(Frame::CallError {
+                unique_id,
+                code,
+                description,
+                ..
+            }) => match take_pending(unique_id) {
+                Some(action) => {
+                    warn!("OCPP: Received CallError {code} for {action}: {description}")
+                }
+                None => warn!("OCPP: Received CallError {code}: {description}"),
+            },
+            Err(invalid) => reject(&invalid, message_str),
+        }
+        record_handle_time(started, message.len());
+
+        if new_input_event != InputEvent::None {
+            info!("OCPP: Sending input event to state machine: {new_input_event:?}");
+            match charger::STATE_IN_CHANNEL.try_send(new_input_event) {
+                Ok(_) => info!("OCPP: Successfully sent event to state machine"),
+                Err(_) => warn!("OCPP: Failed to send event to state machine, channel full"),
+            }
+        }
+    }
+}
+
+/// Correct the clock with the currentTime of a Heartbeat or BootNotification response
+fn correct_clock(payload: &str) {
+    let Some(current_time) = json_string_field(payload, "currentTime") else {
+        warn!("OCPP: Response without currentTime");
+        return;
+    };
+    match DateTime::parse_from_rfc3339(current_time)
+        .ok()
+        .and_then(|time| u64::try_from(time.timestamp()).ok())
+    {
+        Some(unix_timestamp) => {
+            let threshold = Config::from_config().ocpp_clock_drift_threshold_secs;
+            ntp::correct_time(unix_timestamp, threshold.into());
+        }
+        None => warn!("OCPP: Invalid currentTime: {current_time}"),
+    }
+}
+
+/// Whether an idTagInfo status withdraws the authorization of the id tag
+fn is_deauthorized(status: &str) -> bool {
+    matches!(status, "Blocked" | "Expired" | "Invalid")
+}
+
+/// Handle a deauthorized id tag of a running session (StopTransactionOnInvalidId), stop the
+/// session or let it continue at the minimum current
+async fn deauthorize(charger: &Charger, limits: &CurrentLimits, status: &str) -> InputEvent {
+    if !charger.get_state().await.is_charging() {
+        return InputEvent::None;
+    }
+    if ocpp_config::boolean(ConfigKey::StopTransactionOnInvalidId) {
+        warn!("OCPP: Id tag {status}, stopping the transaction");
+        charger.set_stop_reason(StopReason::DeAuthorized).await;
+        InputEvent::Deauthorized
+    } else {
+        warn!(
+            "OCPP: Id tag {status}, continuing at {}A",
+            smart_charging::MIN_CURRENT_AMPS
+        );
+        limits
+            .set_limit(LimitSource::Deauthorized, smart_charging::MIN_CURRENT_AMPS)
+            .await;
+        InputEvent::None
+    }
+}
+
+/// Handle a CallResult (`[3,"<Action>",{payload}]`) and return the resulting state machine input
+async fn handle_call_result(
+    charger: &'static Charger,
+    limits: &CurrentLimits,
+    unique_id: &str,
+    payload: &str,
+) -> InputEvent {
+    let mut new_input_event = InputEvent::None;
+
+    // Resolve the action of the Call by its unique id, a central system that echoes the action
+    // instead of the unique id is understood as well
+    let message_type = take_pending(unique_id).unwrap_or(unique_id);
+    connectivity::record_call_result();
+
+    match message_type {
+        "Authorize" => {
+            info!("OCPP: Received Authorize response");
+
+            // Extract status from payload
+            if let Some(status_start) = payload.find("\"status\":\"") {
+                let status_pos = status_start + 10; // Skip past "status":"
+                if let Some(status_end) = payload[status_pos..].find('"') {
+                    let status = &payload[status_pos..status_pos + status_end];
+                    if status == "Accepted" {
+                        new_input_event = InputEvent::Accepted;
+                        info!("OCPP: Authorization accepted");
+                    } else if is_deauthorized(status) && charger.get_state().await.is_charging() {
+                        new_input_event = deauthorize(charger, limits, status).await;
+                    } else {
+                        new_input_event = InputEvent::Rejected;
+                        info!("OCPP: Authorization rejected with status: {status}");
+                    }
+                }
+            }
+        }
+        "StartTransaction" => {
+            info!("OCPP: Received StartTransaction response");
+
+            if let Some(tx_start) = payload.find("\"transactionId\":") {
+                let tx_pos = tx_start + 16; // Skip past "transactionId":
+                if let Some(tx_end) = payload[tx_pos..].find(&[',', '}'][..]) {
+                    let tx_id_str = &payload[tx_pos..tx_pos + tx_end];
+                    if let Ok(transaction_id) = tx_id_str.parse::<i32>() {
+                        // Only the transaction of the running session reaches the charger
+                        match idempotency::confirm(unique_id, transaction_id) {
+                            Confirmation::Adopted => {}
+                            confirmation => {
+                                info!("OCPP: Transaction {transaction_id}: {confirmation:?}");
+                                return new_input_event;
                             }
-                            _ => {
-                                info!("OCPP: Received other response type: {message_type}");
+                        }
+                        match embassy_time::with_timeout(
+                            Duration::from_millis(500),
+                            charger.set_transaction_id(transaction_id),
+                        )
+                        .await
+                        {
+                            Ok(_) => {
+                                info!("OCPP: Successfully set transaction ID to {transaction_id}")
                             }
+                            Err(_) => warn!("OCPP: Timeout setting transaction ID"),
                         }
+                    }
+                }
+            }
+            yield_now().await;
+
+            if let Some(status_start) = payload.find("\"status\":\"") {
+                let status_pos = status_start + 10; // Skip past "status":"
+                if let Some(status_end) = payload[status_pos..].find('"') {
+                    let status = &payload[status_pos..status_pos + status_end];
+                    if status == "Accepted" {
+                        info!("OCPP: StartTransaction accepted");
+                    } else if is_deauthorized(status) {
+                        new_input_event = deauthorize(charger, limits, status).await;
                     } else {
-                        warn!("OCPP: Non-CallResult message are not supported");
+                        warn!("OCPP: StartTransaction rejected");
                     }
                 }
-            } else {
-                warn!("OCPP: Invalid message format: {message_str}");
             }
-        } else {
-            warn!("MQTT: Non-OCPP message: {message_str}");
         }
+        "Heartbeat" => {
+            info!("OCPP: Received Heartbeat response");
+            correct_clock(payload);
+        }
+        "BootNotification" => {
+            info!("OCPP: Received BootNotification response");
+            correct_clock(payload);
+            match json_string_field(payload, "status") {
+                Some("Accepted") => ready::set(Subsystem::Boot),
+                status => warn!("OCPP: BootNotification not accepted: {status:?}"),
+            }
+        }
+        "DataTransfer" => {
+            info!("OCPP: Received DataTransfer response");
+            data_transfer::handle_call_result(payload);
+        }
+        _ => {
+            info!("OCPP: Received other response type: {message_type}");
+        }
+    }
 
-        if new_input_event != InputEvent::None {
-            info!("OCPP: Sending input event to state machine: {new_input_event:?}");
-            match charger::STATE_IN_CHANNEL.try_send(new_input_event) {
-                Ok(_) => info!("OCPP: Successfully sent event to state machine"),
-                Err(_) => warn!("OCPP: Failed to send event to state machine, channel full"),
+    new_input_event
+}
+
+/// Handle a ChangeAvailability Call, Operative clears a fault lockout, a ground fault and a
+/// switch failure
+/// Making the charger Inoperative is not supported
+fn handle_change_availability(payload: &str) -> CallResponse {
+    let status = match json_string_field(payload, "type") {
+        Some("Operative") => {
+            let lockout_cleared = fault::clear_lockout();
+            let ground_fault_cleared = rcd::clear();
+            let switch_failure_cleared = relay::clear_switch_failure();
+            if lockout_cleared || ground_fault_cleared || switch_failure_cleared {
+                let _ = charger::STATE_IN_CHANNEL.try_send(InputEvent::LockoutCleared);
             }
+            "Accepted"
+        }
+        Some("Inoperative") => {
+            warn!("OCPP: Changing availability to Inoperative is not supported");
+            "Rejected"
+        }
+        _ => {
+            return Err((
+                CallErrorCode::PropertyConstraintViolation,
+                "ChangeAvailability requires type Operative or Inoperative",
+            ))
+        }
+    };
+
+    let mut response = heapless::String::new();
+    let _ = write!(response, "{{\"status\":\"{status}\"}}");
+    Ok(response)
+}
+
+/// Report a message that isn't a valid frame, a Call is answered with a FormationViolation
+/// when its unique id could be read
+fn reject(invalid: &Invalid, message: &str) {
+    let reason = invalid.reason;
+    match (invalid.message_type_id, invalid.unique_id) {
+        (Some(frame::CALL), Some(unique_id)) => {
+            warn!("OCPP: Received an invalid Call with id {unique_id}: {reason}");
+            send_call_error(unique_id, CallErrorCode::FormationViolation, reason);
         }
+        (Some(frame::CALL), None) => {
+            warn!("OCPP: Received a Call without a valid unique id, unable to respond: {message}")
+        }
+        _ => warn!("OCPP: Invalid message ({reason}): {message}"),
+    }
+}
+
+/// Handle a Call initiated by the central system (`[2,"<uniqueId>","<Action>",{payload}]`)
+/// Every call is answered, calls we can't handle get a CallError so the central system
+/// is not left waiting for a response that never comes
+async fn handle_call(charger: &'static Charger, unique_id: &str, action: &str, payload: &str) {
+    info!("OCPP: Received {action} Call with id {unique_id}");
+
+    let response = match action {
+        "DataTransfer" => data_transfer::handle_call(payload),
+        "GetDiagnostics" => diagnostics::handle_get_diagnostics(payload, &Config::from_config()),
+        "UpdateFirmware" => ota::handle_update_firmware(payload),
+        "ChangeAvailability" => handle_change_availability(payload),
+        "ReserveNow" => reservation::handle_reserve_now(charger, payload).await,
+        "CancelReservation" => reservation::handle_cancel_reservation(payload),
+        "GetConfiguration" => ocpp_config::handle_get_configuration(payload),
+        "ChangeConfiguration" => ocpp_config::handle_change_configuration(payload),
+        _ if CENTRAL_SYSTEM_ACTIONS.contains(&action) => {
+            warn!("OCPP: {action} is not supported by this charger");
+            Err((
+                CallErrorCode::NotSupported,
+                "Action is recognized but not supported",
+            ))
+        }
+        _ => {
+            warn!("OCPP: Unknown action: {action}");
+            Err((
+                CallErrorCode::NotImplemented,
+                "Action is not known by this charger",
+            ))
+        }
+    };
+
+    match response {
+        Ok(payload) => send_call_result(unique_id, &payload),
+        Err((code, description)) => send_call_error(unique_id, code, description),
     }
 }
@@ -0,0 +1,447 @@
+use core::{cell::RefCell, fmt::Write, str};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{
+    config::Config,
+    info,
+    metering::{self, MeterKind},
+    ocpp::{self, CallErrorCode, CallResponse},
+    profile::AuthSource,
+    storage::{self, Slot},
+    warn,
+};
+
+const KEY_COUNT: usize = 20;
+const MAX_STORED_SIZE: usize = 512;
+
+/// Type of the value of a configuration key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Integer,
+    Boolean,
+    /// Comma separated list, only reported
+    List,
+}
+
+/// Standard OCPP 1.6 configuration keys reported to the central system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigKey {
+    AuthorizeRemoteTxRequests,
+    ClockAlignedDataInterval,
+    ConnectionTimeOut,
+    GetConfigurationMaxKeys,
+    HeartbeatInterval,
+    LocalAuthorizeOffline,
+    LocalPreAuthorize,
+    MeterValuesAlignedData,
+    MeterValuesSampledData,
+    MeterValueSampleInterval,
+    NumberOfConnectors,
+    ResetRetries,
+    StopTransactionOnEVSideDisconnect,
+    StopTransactionOnInvalidId,
+    StopTxnAlignedData,
+    StopTxnSampledData,
+    SupportedFeatureProfiles,
+    TransactionMessageAttempts,
+    TransactionMessageRetryInterval,
+    UnlockConnectorOnEVSideDisconnect,
+}
+
+impl ConfigKey {
+    pub const ALL: [ConfigKey; KEY_COUNT] = [
+        ConfigKey::AuthorizeRemoteTxRequests,
+        ConfigKey::ClockAlignedDataInterval,
+        ConfigKey::ConnectionTimeOut,
+        ConfigKey::GetConfigurationMaxKeys,
+        ConfigKey::HeartbeatInterval,
+        ConfigKey::LocalAuthorizeOffline,
+        ConfigKey::LocalPreAuthorize,
+        ConfigKey::MeterValuesAlignedData,
+        ConfigKey::MeterValuesSampledData,
+        ConfigKey::MeterValueSampleInterval,
+        ConfigKey::NumberOfConnectors,
+        ConfigKey::ResetRetries,
+        ConfigKey::StopTransactionOnEVSideDisconnect,
+        ConfigKey::StopTransactionOnInvalidId,
+        ConfigKey::StopTxnAlignedData,
+        ConfigKey::StopTxnSampledData,
+        ConfigKey::SupportedFeatureProfiles,
+        ConfigKey::TransactionMessageAttempts,
+        ConfigKey::TransactionMessageRetryInterval,
+        ConfigKey::UnlockConnectorOnEVSideDisconnect,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AuthorizeRemoteTxRequests => "AuthorizeRemoteTxRequests",
+            Self::ClockAlignedDataInterval => "ClockAlignedDataInterval",
+            Self::ConnectionTimeOut => "ConnectionTimeOut",
+            Self::GetConfigurationMaxKeys => "GetConfigurationMaxKeys",
+            Self::HeartbeatInterval => "HeartbeatInterval",
+            Self::LocalAuthorizeOffline => "LocalAuthorizeOffline",
+            Self::LocalPreAuthorize => "LocalPreAuthorize",
+            Self::MeterValuesAlignedData => "MeterValuesAlignedData",
+            Self::MeterValuesSampledData => "MeterValuesSampledData",
+            Self::MeterValueSampleInterval => "MeterValueSampleInterval",
+            Self::NumberOfConnectors => "NumberOfConnectors",
+            Self::ResetRetries => "ResetRetries",
+            Self::StopTransactionOnEVSideDisconnect => "StopTransactionOnEVSideDisconnect",
+            Self::StopTransactionOnInvalidId => "StopTransactionOnInvalidId",
+            Self::StopTxnAlignedData => "StopTxnAlignedData",
+            Self::StopTxnSampledData => "StopTxnSampledData",
+            Self::SupportedFeatureProfiles => "SupportedFeatureProfiles",
+            Self::TransactionMessageAttempts => "TransactionMessageAttempts",
+            Self::TransactionMessageRetryInterval => "TransactionMessageRetryInterval",
+            Self::UnlockConnectorOnEVSideDisconnect => "UnlockConnectorOnEVSideDisconnect",
+        }
+    }
+
+    /// Find a key by name, the central system may use any case
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|key| key.as_str().eq_ignore_ascii_case(name))
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Self::AuthorizeRemoteTxRequests
+            | Self::LocalAuthorizeOffline
+            | Self::LocalPreAuthorize
+            | Self::StopTransactionOnEVSideDisconnect
+            | Self::StopTransactionOnInvalidId
+            | Self::UnlockConnectorOnEVSideDisconnect => ValueKind::Boolean,
+            Self::MeterValuesAlignedData
+            | Self::MeterValuesSampledData
+            | Self::StopTxnAlignedData
+            | Self::StopTxnSampledData
+            | Self::SupportedFeatureProfiles => ValueKind::List,
+            _ => ValueKind::Integer,
+        }
+    }
+
+    /// Keys describing fixed behavior of the charger, ChangeConfiguration rejects them
+    pub fn read_only(&self) -> bool {
+        matches!(
+            self,
+            Self::AuthorizeRemoteTxRequests
+                | Self::GetConfigurationMaxKeys
+                | Self::LocalAuthorizeOffline
+                | Self::NumberOfConnectors
+                | Self::StopTransactionOnEVSideDisconnect
+                | Self::UnlockConnectorOnEVSideDisconnect
+        ) || self.kind() == ValueKind::List
+    }
+
+    /// Keys only read at boot, a change is stored and takes effect after a restart
+    pub fn reboot_required(&self) -> bool {
+        matches!(self, Self::LocalPreAuthorize)
+    }
+
+    /// Value before the central system changes it, from the charger configuration
+    fn default_value(&self, config: &Config) -> i32 {
+        let local_list = config
+            .behavior
+            .authorization
+            .contains(&AuthSource::LocalList);
+        match self {
+            // Remote start isn't supported, there are no remote requests to authorize
+            Self::AuthorizeRemoteTxRequests => 0,
+            Self::ConnectionTimeOut => 60,
+            Self::GetConfigurationMaxKeys => KEY_COUNT as i32,
+            Self::HeartbeatInterval => config.ocpp_heartbeat_interval.into(),
+            Self::LocalAuthorizeOffline | Self::LocalPreAuthorize => local_list.into(),
+            Self::NumberOfConnectors => 1,
+            Self::ResetRetries => 1,
+            // Removing the cable always ends the session and releases the lock
+            Self::StopTransactionOnEVSideDisconnect | Self::UnlockConnectorOnEVSideDisconnect => 1,
+            Self::StopTransactionOnInvalidId => config.stop_transaction_on_invalid_id.into(),
+            Self::TransactionMessageAttempts => 3,
+            Self::TransactionMessageRetryInterval => 60,
+            Self::MeterValueSampleInterval if MeterKind::parse(config.meter_type).is_some() => 60,
+            _ => 0,
+        }
+    }
+
+    /// Value of a list key, measurands are only sampled with a meter IC
+    fn list(&self) -> &'static str {
+        match self {
+            Self::SupportedFeatureProfiles => "Core,FirmwareManagement,Reservation",
+            Self::MeterValuesSampledData if metering::present() => {
+                "Energy.Active.Import.Register,Power.Active.Import,Current.Import,Voltage"
+            }
+            _ => "",
+        }
+    }
+}
+
+/// Integer and boolean values of the keys, booleans are stored as 0 or 1
+struct Registry {
+    /// Values in effect
+    active: [i32; KEY_COUNT],
+    /// Values changed by the central system, stored in flash
+    changed: [Option<i32>; KEY_COUNT],
+}
+
+static REGISTRY: Mutex<CriticalSectionRawMutex, RefCell<Registry>> =
+    Mutex::new(RefCell::new(Registry {
+        active: [0; KEY_COUNT],
+        changed: [None; KEY_COUNT],
+    }));
+/// RAM of the registry, counted against the storage budget
+pub const RAM: usize = size_of_val(&REGISTRY);
+
+/// Parse a value sent by the central system, booleans in any case
+fn parse_value(key: ConfigKey, value: &str) -> Option<i32> {
+    let value = value.trim();
+    match key.kind() {
+        ValueKind::Boolean if value.eq_ignore_ascii_case("true") => Some(1),
+        ValueKind::Boolean if value.eq_ignore_ascii_case("false") => Some(0),
+        // Intervals, counts and timeouts can't be negative
+        ValueKind::Integer => value.parse().ok().filter(|value| *value >= 0),
+        _ => None,
+    }
+}
+
+/// Load the defaults from the configuration with the changes stored in flash
+/// Must be called once at boot, after `storage::init` and before the tasks start
+pub fn init(config: &Config) {
+    let mut buffer = [0u8; MAX_STORED_SIZE];
+    let stored = match storage::read(Slot::Configuration, &mut buffer) {
+        Ok(Some(len)) => str::from_utf8(&buffer[..len]).unwrap_or_else(|_| {
+            warn!("OCFG: Stored configuration is invalid");
+            ""
+        }),
+        Ok(None) => "",
+        Err(e) => {
+            warn!("OCFG: Failed to read configuration: {e}");
+            ""
+        }
+    };
+
+    REGISTRY.lock(|registry| {
+        let mut registry = registry.borrow_mut();
+        for key in ConfigKey::ALL {
+            registry.active[key.index()] = key.default_value(config);
+        }
+        // One `key=value` line per changed key
+        for (name, value) in stored.lines().filter_map(|line| line.split_once('=')) {
+            let Some(key) = ConfigKey::from_name(name).filter(|key| !key.read_only()) else {
+                continue;
+            };
+            if let Some(value) = parse_value(key, value) {
+                registry.active[key.index()] = value;
+                registry.changed[key.index()] = Some(value);
+                info!(
+                    "OCFG: {} set to {value} by the central system",
+                    key.as_str()
+                );
+            }
+        }
+    });
+}
+
+/// Value in effect of an integer key
+pub fn integer(key: ConfigKey) -> i32 {
+    REGISTRY.lock(|registry| registry.borrow().active[key.index()])
+}
+
+/// Value in effect of a boolean key
+pub fn boolean(key: ConfigKey) -> bool {
+    integer(key) != 0
+}
+
+/// Value of a key as reported in GetConfiguration
+pub fn value_string(key: ConfigKey) -> heapless::String<64> {
+    let mut value = heapless::String::new();
+    let _ = match key.kind() {
+        ValueKind::Integer => write!(value, "{}", integer(key)),
+        ValueKind::Boolean => write!(value, "{}", boolean(key)),
+        ValueKind::List => write!(value, "{}", key.list()),
+    };
+    value
+}
+
+/// Store the changed values, a key per line
+fn persist(changed: &[Option<i32>; KEY_COUNT]) -> Result<(), &'static str> {
+    let mut stored = heapless::String::<MAX_STORED_SIZE>::new();
+    for key in ConfigKey::ALL {
+        if let Some(value) = changed[key.index()] {
+            writeln!(stored, "{}={value}", key.as_str()).map_err(|_| "Configuration too large")?;
+        }
+    }
+    storage::write(Slot::Configuration, stored.as_bytes())
+}
+
+/// Outcome of a ChangeConfiguration, the status of the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeStatus {
+    Accepted,
+    Rejected,
+    RebootRequired,
+    NotSupported,
+}
+
+impl ChangeStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Accepted => "Accepted",
+            Self::Rejected => "Rejected",
+            Self::RebootRequired => "RebootRequired",
+            Self::NotSupported => "NotSupported",
+        }
+    }
+}
+
+/// Change the value of a key and store it, a key that needs a restart keeps its value in
+/// effect until then
+pub fn change(name: &str, value: &str) -> ChangeStatus {
+    let Some(key) = ConfigKey::from_name(name) else {
+        return ChangeStatus::NotSupported;
+    };
+    if key.read_only() {
+        warn!("OCFG: {} is read-only", key.as_str());
+        return ChangeStatus::Rejected;
+    }
+    let Some(value) = parse_value(key, value) else {
+        warn!("OCFG: Invalid value for {}: {value}", key.as_str());
+        return ChangeStatus::Rejected;
+    };
+
+    let result = REGISTRY.lock(|registry| {
+        let mut registry = registry.borrow_mut();
+        let previous = registry.changed[key.index()];
+        registry.changed[key.index()] = Some(value);
+        let result = persist(&registry.changed);
+        match result {
+            Ok(()) if !key.reboot_required() => registry.active[key.index()] = value,
+            Ok(()) => {}
+            Err(_) => registry.changed[key.index()] = previous,
+        }
+        result
+    });
+
+    match result {
+        Ok(()) if key.reboot_required() => {
+            info!("OCFG: {} changed to {value}, after a restart", key.as_str());
+            ChangeStatus::RebootRequired
+        }
+        Ok(()) => {
+            info!("OCFG: {} changed to {value}", key.as_str());
+            ChangeStatus::Accepted
+        }
+        Err(e) => {
+            warn!("OCFG: Failed to store {}: {e}", key.as_str());
+            ChangeStatus::Rejected
+        }
+    }
+}
+
+/// Append a key with its value to a GetConfiguration response
+fn push_key(
+    payload: &mut heapless::String<{ ocpp::MAX_CALL_PAYLOAD }>,
+    key: ConfigKey,
+) -> Option<()> {
+    write!(
+        payload,
+        "{{\"key\":\"{}\",\"readonly\":{},\"value\":\"{}\"}}",
+        key.as_str(),
+        key.read_only(),
+        value_string(key)
+    )
+    .ok()
+}
+
+/// Handle a GetConfiguration Call, all keys when no keys are requested
+pub fn handle_get_configuration(payload: &str) -> CallResponse {
+    const TOO_LARGE: (CallErrorCode, &str) = (
+        CallErrorCode::InternalError,
+        "GetConfiguration response too large",
+    );
+
+    // The requested keys, e.g. {"key":["HeartbeatInterval","ConnectionTimeOut"]}
+    let requested = payload
+        .find("\"key\"")
+        .and_then(|start| {
+            let list = &payload[start..];
+            let open = list.find('[')?;
+            let close = list[open..].find(']')?;
+            Some(&list[open + 1..open + close])
+        })
+        .map(|list| list.split(',').map(|name| name.trim().trim_matches('"')))
+        .into_iter()
+        .flatten()
+        .filter(|name| !name.is_empty());
+
+    let mut known: heapless::Vec<ConfigKey, KEY_COUNT> = heapless::Vec::new();
+    let mut unknown: heapless::Vec<&str, KEY_COUNT> = heapless::Vec::new();
+    let mut requested_any = false;
+    for name in requested {
+        requested_any = true;
+        let result = match ConfigKey::from_name(name) {
+            Some(key) if known.contains(&key) => Ok(()),
+            Some(key) => known.push(key).map_err(|_| ()),
+            None => unknown.push(name).map_err(|_| ()),
+        };
+        if result.is_err() {
+            return Err((
+                CallErrorCode::OccurrenceConstraintViolation,
+                "More keys requested than GetConfigurationMaxKeys",
+            ));
+        }
+    }
+    if !requested_any {
+        known = heapless::Vec::from_slice(&ConfigKey::ALL).unwrap_or_default();
+    }
+
+    let mut response = heapless::String::new();
+    response
+        .push_str("{\"configurationKey\":[")
+        .map_err(|_| TOO_LARGE)?;
+    for (i, key) in known.iter().enumerate() {
+        if i > 0 {
+            response.push(',').map_err(|_| TOO_LARGE)?;
+        }
+        push_key(&mut response, *key).ok_or(TOO_LARGE)?;
+    }
+    response.push(']').map_err(|_| TOO_LARGE)?;
+    if !unknown.is_empty() {
+        response
+            .push_str(",\"unknownKey\":[")
+            .map_err(|_| TOO_LARGE)?;
+        for (i, name) in unknown.iter().enumerate() {
+            if i > 0 {
+                response.push(',').map_err(|_| TOO_LARGE)?;
+            }
+            response.push('"').map_err(|_| TOO_LARGE)?;
+            ocpp::push_json_escaped(&mut response, name).ok_or(TOO_LARGE)?;
+            response.push('"').map_err(|_| TOO_LARGE)?;
+        }
+        response.push(']').map_err(|_| TOO_LARGE)?;
+    }
+    response.push('}').map_err(|_| TOO_LARGE)?;
+    Ok(response)
+}
+
+/// Handle a ChangeConfiguration Call
+pub fn handle_change_configuration(payload: &str) -> CallResponse {
+    let (Some(key), Some(value)) = (
+        ocpp::json_string_field(payload, "key"),
+        ocpp::json_string_field(payload, "value"),
+    ) else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "ChangeConfiguration requires a key and a value",
+        ));
+    };
+
+    let status = change(key, value);
+    let mut response = heapless::String::new();
+    let _ = write!(response, "{{\"status\":\"{}\"}}", status.as_str());
+    Ok(response)
+}
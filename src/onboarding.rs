@@ -0,0 +1,216 @@
+extern crate alloc;
+use alloc::vec;
+use core::{fmt::Write, str};
+use embassy_time::{Duration, Timer};
+use hmac::{Hmac, Mac};
+use rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1;
+use sha2::Sha256;
+
+use crate::{
+    config::{self, Config, ProvisionedSettings},
+    info, mk_static,
+    network::NetworkStack,
+    ocpp,
+    profile::BehaviorProfile,
+    storage::{self, Slot},
+    utils, version, warn,
+};
+
+/// Well-known topic claim requests are published to
+const CLAIM_TOPIC: &str = "/onboarding/claim";
+const RESPONSE_TIMEOUT_SECS: u64 = 300;
+const RETRY_DELAY_SECS: u64 = 60;
+const MAX_BLOB_SIZE: usize = 512;
+/// RAM of the buffer the provisioning record is read into, counted against the storage budget
+pub const RAM: usize = MAX_BLOB_SIZE;
+
+/// Load the provisioning record stored by a previous claim and install its settings
+/// Must be called once at boot, after `storage::init` and before the configuration is used
+pub fn load_provisioning() -> bool {
+    let buffer = mk_static!([u8; MAX_BLOB_SIZE], [0; MAX_BLOB_SIZE]);
+    let len = match storage::read(Slot::Provisioning, buffer) {
+        Ok(Some(len)) => len,
+        Ok(None) => return false,
+        Err(e) => {
+            warn!("ONBD: Failed to read provisioning record: {e}");
+            return false;
+        }
+    };
+
+    let buffer: &'static [u8] = buffer;
+    match str::from_utf8(&buffer[..len]).ok().and_then(parse_settings) {
+        Some(settings) => {
+            info!(
+                "ONBD: Using provisioned broker {}:{} for site {}",
+                settings.mqtt_broker, settings.mqtt_port, settings.site_id
+            );
+            config::set_provisioned(settings);
+            true
+        }
+        None => {
+            warn!("ONBD: Stored provisioning record is invalid");
+            false
+        }
+    }
+}
+
+/// Read a string value from the provisioning JSON, escaped values are not supported
+fn setting(json: &'static str, key: &str) -> Option<&'static str> {
+    ocpp::json_string_field(json, key).filter(|value| !value.contains('\\'))
+}
+
+/// Read an identifier from the provisioning JSON, an unsafe identifier invalidates the settings
+fn identifier_setting(json: &'static str, key: &str) -> Option<&'static str> {
+    match setting(json, key) {
+        Some(value) if !value.is_empty() => match utils::validate_identifier(value) {
+            Ok(()) => Some(value),
+            Err(e) => {
+                warn!("ONBD: Provisioned {key} {e}");
+                None
+            }
+        },
+        _ => Some(""),
+    }
+}
+
+fn parse_settings(json: &'static str) -> Option<ProvisionedSettings> {
+    let mqtt_broker = setting(json, "broker").filter(|broker| !broker.is_empty())?;
+    Some(ProvisionedSettings {
+        mqtt_broker,
+        mqtt_port: ocpp::json_integer_field(json, "port")
+            .and_then(|port| u16::try_from(port).ok())
+            .unwrap_or(1883),
+        mqtt_client_id: identifier_setting(json, "client_id")?,
+        mqtt_username: setting(json, "username").unwrap_or(""),
+        mqtt_password: setting(json, "password").unwrap_or(""),
+        site_id: identifier_setting(json, "site_id")?,
+        profile: setting(json, "profile").and_then(BehaviorProfile::parse),
+    })
+}
+
+/// Verify a provisioning blob: `<json>.<hex HMAC-SHA256 of the json>`
+/// Returns the json if the signature matches the onboarding key
+fn verify_blob<'a>(blob: &'a str, key: &str) -> Option<&'a str> {
+    let (json, signature) = blob.trim().rsplit_once('.')?;
+    let signature = utils::hex_string_to_bytes::<32>(signature)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).ok()?;
+    mac.update(json.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+    Some(json)
+}
+
+fn claim_request(config: &Config, mac_address: &[u8; 6]) -> heapless::String<256> {
+    let mut request = heapless::String::new();
+    let _ = write!(
+        request,
+        "{{\"serial\":\"{}\",\"mac\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\",\"firmware\":\"{}\",\"model\":\"{}\",\"vendor\":\"{}\"}}",
+        utils::sanitize_identifier::<{ utils::MAX_IDENTIFIER_LEN }>(config.charger_serial),
+        mac_address[0],
+        mac_address[1],
+        mac_address[2],
+        mac_address[3],
+        mac_address[4],
+        mac_address[5],
+        version::firmware_version(),
+        config.charger_model,
+        config.charger_vendor
+    );
+    request
+}
+
+/// Topic the signed configuration for this charger is published on
+fn response_topic(config: &Config) -> heapless::String<64> {
+    let mut topic = heapless::String::new();
+    let _ = write!(
+        topic,
+        "/onboarding/{}",
+        utils::sanitize_identifier::<{ utils::MAX_IDENTIFIER_LEN }>(config.charger_serial)
+    );
+    topic
+}
+
+async fn try_claim(network: &NetworkStack, config: &Config) -> Result<(), &'static str> {
+    let mut rx_buffer = vec![0u8; 1024];
+    let mut tx_buffer = vec![0u8; 1024];
+    let mut write_buffer = vec![0u8; 1024];
+    let mut recv_buffer = vec![0u8; 1024];
+
+    let mut client = network
+        .connect_mqtt_client(
+            config.onboarding_broker,
+            config.onboarding_port,
+            None,
+            NetworkStack::mqtt_config(config.charger_serial, "", ""),
+            &mut rx_buffer,
+            &mut tx_buffer,
+            &mut write_buffer,
+            &mut recv_buffer,
+        )
+        .await
+        .map_err(|_| "Failed to connect to onboarding broker")?;
+
+    let topic = response_topic(config);
+    client
+        .subscribe_to_topic(&topic)
+        .await
+        .map_err(|_| "Failed to subscribe to onboarding topic")?;
+
+    let mac_address = esp_hal::efuse::Efuse::read_base_mac_address();
+    let request = claim_request(config, &mac_address);
+    client
+        .send_message(CLAIM_TOPIC, request.as_bytes(), QoS1, false)
+        .await
+        .map_err(|_| "Failed to publish claim request")?;
+    info!("ONBD: Published claim request, waiting for configuration on {topic}");
+
+    let (_, payload) = embassy_time::with_timeout(
+        Duration::from_secs(RESPONSE_TIMEOUT_SECS),
+        client.receive_message(),
+    )
+    .await
+    .map_err(|_| "No configuration received")?
+    .map_err(|_| "Failed to receive configuration")?;
+
+    let blob = str::from_utf8(payload).map_err(|_| "Configuration is not valid UTF-8")?;
+    let json = verify_blob(blob, config.onboarding_key).ok_or("Invalid configuration signature")?;
+    if json.len() > MAX_BLOB_SIZE {
+        return Err("Configuration too large");
+    }
+
+    // The settings are parsed and installed from the stored record after a restart
+    if !ocpp::json_string_field(json, "broker").is_some_and(|broker| !broker.is_empty()) {
+        return Err("Configuration without broker");
+    }
+
+    storage::write(Slot::Provisioning, json.as_bytes())?;
+    Ok(())
+}
+
+/// Claim the charger for zero-touch onboarding: publish a claim request and wait for a
+/// signed configuration assigning broker credentials and site settings
+/// Retries until the charger is claimed, the configuration is used after a restart
+/// Returns false if onboarding is not possible because no onboarding key is configured
+pub async fn claim(network: &NetworkStack, config: &Config) -> bool {
+    if config.onboarding_key.is_empty() {
+        warn!("ONBD: No onboarding key configured, unable to verify configurations");
+        return false;
+    }
+
+    loop {
+        info!(
+            "ONBD: Claiming charger {} via {}",
+            config.charger_serial, config.onboarding_broker
+        );
+        match try_claim(network, config).await {
+            Ok(()) => {
+                info!("ONBD: Charger claimed successfully");
+                return true;
+            }
+            Err(e) => {
+                warn!("ONBD: Claim failed: {e}, retrying in {RETRY_DELAY_SECS}s");
+                Timer::after(Duration::from_secs(RETRY_DELAY_SECS)).await;
+            }
+        }
+    }
+}
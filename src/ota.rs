@@ -0,0 +1,659 @@
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+use core::{cell::RefCell, fmt::Write};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Timer};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::{
+    ota::{Ota, OtaImageState, Slot},
+    partitions::{
+        self, AppPartitionSubType, DataPartitionSubType, PartitionType, PARTITION_TABLE_MAX_LEN,
+    },
+};
+use esp_storage::FlashStorage;
+use hmac::{Hmac, Mac};
+use ocpp_rs::v16::{
+    call::{Action, FirmwareStatusNotification},
+    enums::FirmwareStatus,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    charger::Charger,
+    delta::{self, Patcher},
+    display::{self, DisplayEvent},
+    http::{self, Scheme, Url},
+    info, maintenance,
+    mqtt::Priority,
+    network::NetworkStack,
+    ntp,
+    ocpp::{self, CallErrorCode, CallResponse},
+    storage, utils, warn,
+};
+
+const SECTOR_SIZE: usize = 4096;
+/// First byte of an ESP application image
+const IMAGE_MAGIC: u8 = 0xE9;
+/// The checksum is published next to the image as `<location>.sha256`
+const CHECKSUM_SUFFIX: &str = ".sha256";
+/// The signature of the checksum is published next to the image as `<location>.sha256.sig`
+const SIGNATURE_SUFFIX: &str = ".sha256.sig";
+/// A patch against the running image is published next to the image as `<location>.patch`
+const PATCH_SUFFIX: &str = ".patch";
+/// Length of the header of an ESP application image, the segments follow it
+const IMAGE_HEADER_LEN: u32 = 24;
+
+/// An UpdateFirmware request waiting to be installed
+pub struct UpdateRequest {
+    location: heapless::String<256>,
+    retrieve_at: u64, // Unix time to start the download, 0 for immediately
+    retries: u8,
+    retry_interval_secs: u16,
+}
+
+static UPDATE_REQUEST_CHANNEL: Channel<CriticalSectionRawMutex, UpdateRequest, 1> = Channel::new();
+
+/// Stage of a firmware update that is in progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateStage {
+    Downloading,
+    Verifying,
+    WaitingForSession,
+    Installing,
+    Restarting,
+}
+
+impl UpdateStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Downloading => "Downloading",
+            Self::Verifying => "Verifying",
+            Self::WaitingForSession => "Waiting for session",
+            Self::Installing => "Installing",
+            Self::Restarting => "Restarting",
+        }
+    }
+}
+
+/// Progress of the firmware update, shown on the display
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateProgress {
+    pub image: heapless::String<32>, // File name of the image being installed
+    pub stage: UpdateStage,
+    pub percent: u8,
+}
+
+static UPDATE_PROGRESS: Mutex<CriticalSectionRawMutex, RefCell<Option<UpdateProgress>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Progress of the firmware update, None when no update is in progress
+pub fn progress() -> Option<UpdateProgress> {
+    UPDATE_PROGRESS.lock(|progress| progress.borrow().clone())
+}
+
+/// Whether a firmware update is in progress, new charging sessions are not started meanwhile
+pub fn is_updating() -> bool {
+    UPDATE_PROGRESS.lock(|progress| progress.borrow().is_some())
+}
+
+fn start_progress(location: &str) {
+    // The last path segment usually carries the version, e.g. `charger-1.2.0.bin`
+    let name = location.rsplit('/').next().unwrap_or(location);
+    let mut image = heapless::String::new();
+    for c in name.chars() {
+        if image.push(c).is_err() {
+            break;
+        }
+    }
+    UPDATE_PROGRESS.lock(|progress| {
+        *progress.borrow_mut() = Some(UpdateProgress {
+            image,
+            stage: UpdateStage::Downloading,
+            percent: 0,
+        })
+    });
+    display::notify(DisplayEvent::UpdateProgress);
+}
+
+fn set_progress(stage: UpdateStage, percent: u8) {
+    UPDATE_PROGRESS.lock(|progress| {
+        if let Some(progress) = progress.borrow_mut().as_mut() {
+            progress.stage = stage;
+            progress.percent = percent;
+        }
+    });
+    display::notify(DisplayEvent::UpdateProgress);
+}
+
+fn end_progress() {
+    UPDATE_PROGRESS.lock(|progress| *progress.borrow_mut() = None);
+    display::notify(DisplayEvent::UpdateProgress);
+}
+
+/// Handle an UpdateFirmware Call, the update itself happens in the firmware update task
+pub fn handle_update_firmware(payload: &str) -> CallResponse {
+    let Some(location) = ocpp::json_string_field(payload, "location") else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "UpdateFirmware requires a location",
+        ));
+    };
+
+    let Some(location) = ocpp::json_unescape::<256>(location) else {
+        return Err((
+            CallErrorCode::PropertyConstraintViolation,
+            "Location too long",
+        ));
+    };
+
+    let Some(retrieve_at) = ocpp::json_string_field(payload, "retrieveDate")
+        .and_then(|date| chrono::DateTime::parse_from_rfc3339(date).ok())
+    else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "UpdateFirmware requires a valid retrieveDate",
+        ));
+    };
+
+    let request = UpdateRequest {
+        location,
+        retrieve_at: u64::try_from(retrieve_at.timestamp()).unwrap_or(0),
+        retries: ocpp::json_integer_field(payload, "retries")
+            .map(|retries| retries.clamp(0, 5) as u8)
+            .unwrap_or(0),
+        retry_interval_secs: ocpp::json_integer_field(payload, "retryInterval")
+            .map(|interval| interval.clamp(0, 3600) as u16)
+            .unwrap_or(30),
+    };
+
+    // UpdateFirmware.conf has no status, a request while an update is pending is dropped
+    match UPDATE_REQUEST_CHANNEL.try_send(request) {
+        Ok(()) => info!("OTA : Firmware update scheduled"),
+        Err(_) => warn!("OTA : Firmware update already in progress, request ignored"),
+    }
+
+    let mut payload = heapless::String::new();
+    let _ = payload.push_str("{}");
+    Ok(payload)
+}
+
+fn firmware_status_notification(status: FirmwareStatus) -> Action {
+    Action::FirmwareStatusNotification(FirmwareStatusNotification { status })
+}
+
+fn send_status(status: FirmwareStatus, description: &str) {
+    ocpp::send_ocpp(description, Priority::Normal, |_| {
+        firmware_status_notification(status)
+    });
+}
+
+/// Run a closure with the otadata partition, which selects the slot the bootloader starts
+fn with_ota<R>(
+    f: impl FnOnce(&mut Ota<'_, FlashStorage>) -> Result<R, &'static str>,
+) -> Result<R, &'static str> {
+    storage::with_flash(|flash| {
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let table = partitions::read_partition_table(flash, &mut buffer)
+            .map_err(|_| "Failed to read partition table")?;
+        let otadata = table
+            .find_partition(PartitionType::Data(DataPartitionSubType::Ota))
+            .map_err(|_| "Failed to read partition table")?
+            .ok_or("No otadata partition")?;
+        let mut otadata = otadata.as_embedded_storage(flash);
+        let mut ota = Ota::new(&mut otadata).map_err(|_| "Invalid otadata partition")?;
+        f(&mut ota)
+    })
+}
+
+/// The slot that is not running, an empty otadata partition boots the first slot
+fn inactive_slot() -> Result<Slot, &'static str> {
+    with_ota(|ota| match ota.current_slot() {
+        Ok(Slot::Slot1) => Ok(Slot::Slot0),
+        Ok(_) => Ok(Slot::Slot1),
+        Err(_) => Err("Failed to read the current slot"),
+    })
+}
+
+/// The slot that is running when the other one is updated
+fn running_slot(update: Slot) -> Slot {
+    match update {
+        Slot::Slot1 => Slot::Slot0,
+        _ => Slot::Slot1,
+    }
+}
+
+/// Offset and size of the app partition for a slot
+fn slot_partition(slot: Slot) -> Result<(u32, u32), &'static str> {
+    let subtype = match slot {
+        Slot::Slot1 => AppPartitionSubType::Ota1,
+        _ => AppPartitionSubType::Ota0,
+    };
+    storage::with_flash(|flash| {
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let table = partitions::read_partition_table(flash, &mut buffer)
+            .map_err(|_| "Failed to read partition table")?;
+        let partition = table
+            .find_partition(PartitionType::App(subtype))
+            .map_err(|_| "Failed to read partition table")?
+            .ok_or("No app partition for the update slot")?;
+        Ok((partition.offset(), partition.len()))
+    })
+}
+
+/// Mark the running image valid once it proved it can reach the backend,
+/// so a bootloader with rollback enabled keeps it
+pub fn mark_valid() {
+    let result = with_ota(|ota| match ota.current_ota_state() {
+        Ok(OtaImageState::New | OtaImageState::PendingVerify) => ota
+            .set_current_ota_state(OtaImageState::Valid)
+            .map(|_| true)
+            .map_err(|_| "Failed to update image state"),
+        _ => Ok(false),
+    });
+
+    match result {
+        Ok(true) => info!("OTA : Running image marked valid"),
+        Ok(false) => {}
+        Err(e) => warn!("OTA : {e}"),
+    }
+}
+
+/// Writes an image to an app partition a sector at a time while computing its checksum
+struct ImageWriter {
+    offset: u32,
+    size: u32,
+    written: u32,
+    sector: Vec<u8>,
+    fill: usize,
+    hasher: Sha256,
+}
+
+impl ImageWriter {
+    fn new(offset: u32, size: u32) -> Self {
+        Self {
+            offset,
+            size,
+            written: 0,
+            sector: vec![0u8; SECTOR_SIZE],
+            fill: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn write(&mut self, mut data: &[u8]) -> Result<(), &'static str> {
+        if self.written == 0 && self.fill == 0 && data.first().is_some_and(|b| *b != IMAGE_MAGIC) {
+            return Err("Not an ESP application image");
+        }
+
+        self.hasher.update(data);
+        while !data.is_empty() {
+            let len = (SECTOR_SIZE - self.fill).min(data.len());
+            self.sector[self.fill..self.fill + len].copy_from_slice(&data[..len]);
+            self.fill += len;
+            data = &data[len..];
+            if self.fill == SECTOR_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        if self.written + self.fill as u32 > self.size {
+            return Err("Image too large for the app partition");
+        }
+        let offset = self.offset + self.written;
+        let sector = &self.sector[..self.fill];
+        storage::with_flash(|flash| {
+            flash
+                .write(offset, sector)
+                .map_err(|_| "Failed to write image to flash")
+        })?;
+        self.written += self.fill as u32;
+        self.fill = 0;
+        Ok(())
+    }
+
+    /// Flush the last sector, returns the image size and its SHA-256
+    fn finish(mut self) -> Result<(u32, [u8; 32]), &'static str> {
+        if self.fill > 0 {
+            self.flush()?;
+        }
+        Ok((self.written, self.hasher.finalize().into()))
+    }
+}
+
+/// Size of the application image at the start of a partition: the header, the segments, the
+/// checksum byte padded to 16 bytes and the SHA-256 when the image has one appended
+fn image_size(offset: u32, partition_size: u32) -> Result<u32, &'static str> {
+    storage::with_flash(|flash| {
+        let mut header = [0u8; IMAGE_HEADER_LEN as usize];
+        flash
+            .read(offset, &mut header)
+            .map_err(|_| "Failed to read the running image")?;
+        if header[0] != IMAGE_MAGIC {
+            return Err("No application image in the running partition");
+        }
+        let mut size = IMAGE_HEADER_LEN;
+        for _ in 0..header[1] {
+            let mut segment = [0u8; 8];
+            flash
+                .read(offset + size, &mut segment)
+                .map_err(|_| "Failed to read the running image")?;
+            let len = u32::from_le_bytes([segment[4], segment[5], segment[6], segment[7]]);
+            size = size
+                .checked_add(8 + len)
+                .filter(|size| *size < partition_size)
+                .ok_or("Invalid running image")?;
+        }
+        size = (size + 16) & !15;
+        if header[23] == 1 {
+            size += 32;
+        }
+        if size > partition_size {
+            return Err("Invalid running image");
+        }
+        Ok(size)
+    })
+}
+
+/// The running image with its SHA-256, a patch is only applied against the image it was made for
+async fn running_image(slot: Slot) -> Result<delta::Base, &'static str> {
+    let (offset, partition_size) = slot_partition(slot)?;
+    let size = image_size(offset, partition_size)?;
+
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; SECTOR_SIZE];
+    let mut hashed = 0;
+    while hashed < size {
+        let len = (size - hashed).min(SECTOR_SIZE as u32) as usize;
+        storage::with_flash(|flash| {
+            flash
+                .read(offset + hashed, &mut chunk[..len])
+                .map_err(|_| "Failed to read the running image")
+        })?;
+        hasher.update(&chunk[..len]);
+        hashed += len as u32;
+        // Hashing takes a while for a full partition, let the other tasks run in between
+        Timer::after(Duration::from_ticks(0)).await;
+    }
+
+    Ok(delta::Base {
+        offset,
+        size,
+        sha256: hasher.finalize().into(),
+    })
+}
+
+/// Download a file next to the image holding a hex encoded digest, as written by `sha256sum`
+async fn download_digest(
+    network: &NetworkStack,
+    location: &str,
+    suffix: &str,
+) -> Result<heapless::Vec<u8, 32>, &'static str> {
+    let mut digest_location = heapless::String::<264>::new();
+    write!(digest_location, "{location}{suffix}").map_err(|_| "Checksum location too long")?;
+    let url = Url::parse(&digest_location)?;
+
+    let mut contents = heapless::Vec::<u8, 128>::new();
+    http::get(network, &url, |chunk, _| {
+        contents
+            .extend_from_slice(chunk)
+            .map_err(|_| "Checksum file too large")
+    })
+    .await?;
+
+    core::str::from_utf8(&contents)
+        .ok()
+        .and_then(|contents| contents.split_whitespace().next())
+        .and_then(utils::hex_string_to_bytes::<32>)
+        .filter(|digest| digest.len() == 32)
+        .ok_or("Invalid checksum file")
+}
+
+/// Download the `<location>.sha256` checksum and verify `<location>.sha256.sig`, the HMAC-SHA256 of
+/// the checksum signed with the OTA key. Images without a valid signature are rejected
+async fn download_checksum(
+    network: &NetworkStack,
+    location: &str,
+) -> Result<heapless::Vec<u8, 32>, &'static str> {
+    let checksum = download_digest(network, location, CHECKSUM_SUFFIX).await?;
+    let signature = download_digest(network, location, SIGNATURE_SUFFIX)
+        .await
+        .map_err(|_| "Image not signed")?;
+
+    let key = network.app_config.ota_key.as_bytes();
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|_| "Invalid OTA key")?;
+    mac.update(&checksum);
+    mac.verify_slice(&signature)
+        .map_err(|_| "Invalid image signature")?;
+    Ok(checksum)
+}
+
+/// Download the image into the partition of a slot and verify its checksum
+async fn download(network: &NetworkStack, location: &str, slot: Slot) -> Result<u32, &'static str> {
+    let url = Url::parse(location)?;
+    if url.scheme != Scheme::Http {
+        return Err("Only HTTP firmware locations are supported");
+    }
+
+    let expected = download_checksum(network, location).await?;
+    let (offset, size) = slot_partition(slot)?;
+    info!("OTA : Writing image to partition at 0x{offset:x} ({size} bytes)");
+
+    let mut writer = ImageWriter::new(offset, size);
+    http::get(network, &url, |chunk, content_length| {
+        writer.write(chunk)?;
+        if let Some(length) = content_length.filter(|length| *length > 0) {
+            let received = writer.written as usize + writer.fill;
+            set_progress(
+                UpdateStage::Downloading,
+                (received * 100 / length).min(100) as u8,
+            );
+        }
+        Ok(())
+    })
+    .await?;
+    set_progress(UpdateStage::Verifying, 100);
+    let (image_size, checksum) = writer.finish()?;
+
+    if checksum[..] != expected[..] {
+        return Err("Image checksum mismatch");
+    }
+    Ok(image_size)
+}
+
+/// Download the patch `<location>.patch` and apply it against the running image into the partition
+/// of a slot, the patched image is verified with the checksum of the full image
+async fn download_delta(
+    network: &NetworkStack,
+    location: &str,
+    slot: Slot,
+) -> Result<u32, &'static str> {
+    let mut patch_location = heapless::String::<264>::new();
+    write!(patch_location, "{location}{PATCH_SUFFIX}").map_err(|_| "Patch location too long")?;
+    let url = Url::parse(&patch_location)?;
+    if url.scheme != Scheme::Http {
+        return Err("Only HTTP firmware locations are supported");
+    }
+
+    let base = running_image(running_slot(slot)).await?;
+    let expected = download_checksum(network, location).await?;
+    let (offset, size) = slot_partition(slot)?;
+    info!(
+        "OTA : Patching the running image ({} bytes) into partition at 0x{offset:x}",
+        base.size
+    );
+
+    let mut writer = ImageWriter::new(offset, size);
+    let mut patcher = Patcher::new(base);
+    let mut received = 0;
+    http::get(network, &url, |chunk, content_length| {
+        patcher.write(chunk, &mut |data| writer.write(data))?;
+        received += chunk.len();
+        if let Some(length) = content_length.filter(|length| *length > 0) {
+            set_progress(
+                UpdateStage::Downloading,
+                (received * 100 / length).min(100) as u8,
+            );
+        }
+        Ok(())
+    })
+    .await?;
+    set_progress(UpdateStage::Verifying, 100);
+    patcher.finish(&mut |data| writer.write(data))?;
+    let (image_size, checksum) = writer.finish()?;
+
+    if checksum[..] != expected[..] {
+        return Err("Patched image checksum mismatch");
+    }
+    Ok(image_size)
+}
+
+/// Task to download and install firmware updates requested with UpdateFirmware
+#[embassy_executor::task]
+pub async fn firmware_update_task(network: &'static NetworkStack, charger: &'static Charger) {
+    info!("TASK: Started Firmware Update Handler");
+
+    loop {
+        let request = UPDATE_REQUEST_CHANNEL.receive().await;
+
+        // Without a synced clock the retrieve date can't be honored, start right away
+        let now = ntp::get_current_unix_time();
+        if now != 0 && request.retrieve_at > now {
+            let delay = request.retrieve_at - now;
+            info!("OTA : Firmware download starts in {delay}s");
+            Timer::after(Duration::from_secs(delay)).await;
+        }
+
+        // Scheduled updates are held back until the maintenance window ends
+        while maintenance::is_active() {
+            info!("OTA : Waiting for the maintenance window to end before updating");
+            Timer::after(Duration::from_secs(60)).await;
+        }
+
+        // Unsigned images are rejected, without a key none can be verified
+        if network.app_config.ota_key.is_empty() {
+            warn!("OTA : No OTA key configured, unable to verify the image");
+            send_status(
+                FirmwareStatus::DownloadFailed,
+                "FirmwareStatusNotification DownloadFailed",
+            );
+            continue;
+        }
+
+        let slot = match inactive_slot() {
+            Ok(slot) => slot,
+            Err(e) => {
+                warn!("OTA : Unable to update: {e}");
+                send_status(
+                    FirmwareStatus::DownloadFailed,
+                    "FirmwareStatusNotification DownloadFailed",
+                );
+                continue;
+            }
+        };
+
+        info!("OTA : Downloading {} to {slot:?}", request.location);
+        start_progress(&request.location);
+        send_status(
+            FirmwareStatus::Downloading,
+            "FirmwareStatusNotification Downloading",
+        );
+
+        // A patch against the running image is tried first, the full image when it fails
+        let patched = network.app_config.ota_delta_enabled
+            && match download_delta(network, &request.location, slot).await {
+                Ok(size) => {
+                    info!("OTA : Patch applied and verified ({size} bytes)");
+                    true
+                }
+                Err(e) => {
+                    info!("OTA : Patch not applied: {e}, downloading the full image");
+                    set_progress(UpdateStage::Downloading, 0);
+                    false
+                }
+            };
+
+        let mut attempt = 0;
+        let downloaded = patched
+            || loop {
+                match download(network, &request.location, slot).await {
+                    Ok(size) => {
+                        info!("OTA : Image downloaded and verified ({size} bytes)");
+                        break true;
+                    }
+                    Err(e) if attempt < request.retries => {
+                        attempt += 1;
+                        warn!(
+                            "OTA : Download failed: {e}, retry {attempt} of {} in {}s",
+                            request.retries, request.retry_interval_secs
+                        );
+                        set_progress(UpdateStage::Downloading, 0);
+                        Timer::after(Duration::from_secs(request.retry_interval_secs.into())).await;
+                    }
+                    Err(e) => {
+                        warn!("OTA : Download failed: {e}");
+                        break false;
+                    }
+                }
+            };
+
+        if !downloaded {
+            end_progress();
+            send_status(
+                FirmwareStatus::DownloadFailed,
+                "FirmwareStatusNotification DownloadFailed",
+            );
+            continue;
+        }
+        send_status(
+            FirmwareStatus::Downloaded,
+            "FirmwareStatusNotification Downloaded",
+        );
+
+        // Don't interrupt a charging session with the restart
+        while charger.get_state().await.is_charging() {
+            info!("OTA : Waiting for the charging session to end before installing");
+            set_progress(UpdateStage::WaitingForSession, 100);
+            Timer::after(Duration::from_secs(30)).await;
+        }
+
+        set_progress(UpdateStage::Installing, 100);
+        send_status(
+            FirmwareStatus::Installing,
+            "FirmwareStatusNotification Installing",
+        );
+        let installed = with_ota(|ota| {
+            ota.set_current_slot(slot)
+                .map_err(|_| "Failed to select the boot slot")?;
+            ota.set_current_ota_state(OtaImageState::New)
+                .map_err(|_| "Failed to update image state")
+        });
+
+        match installed {
+            Ok(()) => {
+                info!("OTA : Boot slot switched to {slot:?}, restarting");
+                set_progress(UpdateStage::Restarting, 100);
+                send_status(
+                    FirmwareStatus::Installed,
+                    "FirmwareStatusNotification Installed",
+                );
+                // Give the MQTT task time to publish the notification
+                Timer::after(Duration::from_secs(3)).await;
+                esp_hal::system::software_reset();
+            }
+            Err(e) => {
+                warn!("OTA : Installation failed: {e}");
+                end_progress();
+                send_status(
+                    FirmwareStatus::InstallationFailed,
+                    "FirmwareStatusNotification InstallationFailed",
+                );
+            }
+        }
+    }
+}
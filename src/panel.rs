@@ -0,0 +1,396 @@
+use core::convert::Infallible;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+use embedded_hal::{delay::DelayNs, digital::OutputPin, i2c::I2c, spi::SpiDevice};
+use ssd1306::{mode::BufferedGraphicsMode, prelude::*, I2CDisplayInterface, Ssd1306};
+
+use crate::display::DisplayPower;
+
+#[cfg(all(feature = "display-sh1106", feature = "display-st7735"))]
+compile_error!("Only one display driver feature can be enabled");
+
+/// Size of the screens, larger panels show them centered
+pub const WIDTH: u32 = 128;
+pub const HEIGHT: u32 = 64;
+
+/// I2C address of the OLED panels
+const OLED_ADDRESS: u8 = 0x3C;
+
+/// A buffered monochrome panel the screens are drawn on, the buffer is shown with `flush`
+pub trait Panel: DrawTarget<Color = BinaryColor> {
+    /// Name of the driver chip, for the log
+    const NAME: &'static str;
+
+    fn clear_buffer(&mut self);
+    fn flush(&mut self) -> Result<(), &'static str>;
+    /// Dim the panel or turn it off, the contents are kept
+    fn set_power(&mut self, power: DisplayPower) -> Result<(), &'static str>;
+}
+
+/// 128x64 SSD1306 OLED on I2C, the panel of the original board
+pub type Ssd1306Panel<I2C> =
+    Ssd1306<I2CInterface<I2C>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>;
+
+/// Initialize an SSD1306 and clear it
+pub fn ssd1306<I2C: I2c>(i2c: I2C) -> Result<Ssd1306Panel<I2C>, &'static str> {
+    let interface = I2CDisplayInterface::new_custom_address(i2c, OLED_ADDRESS);
+    let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+        .into_buffered_graphics_mode();
+    display
+        .init()
+        .map_err(|_| "Failed to initialize display - device responded but init failed")?;
+    Panel::flush(&mut display)?;
+    Ok(display)
+}
+
+impl<I2C: I2c> Panel for Ssd1306Panel<I2C> {
+    const NAME: &'static str = "SSD1306";
+
+    fn clear_buffer(&mut self) {
+        Ssd1306::clear_buffer(self);
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        Ssd1306::flush(self).map_err(|_| "Failed to flush display")
+    }
+
+    fn set_power(&mut self, power: DisplayPower) -> Result<(), &'static str> {
+        let brightness = match power {
+            DisplayPower::Dimmed => Brightness::DIMMEST,
+            DisplayPower::On | DisplayPower::Off => Brightness::NORMAL,
+        };
+        self.set_brightness(brightness)
+            .map_err(|_| "Failed to set display brightness")?;
+        self.set_display_on(power != DisplayPower::Off)
+            .map_err(|_| "Failed to switch display")
+    }
+}
+
+/// Frame buffer of a monochrome screen, a byte holds 8 rows of a column like the OLED pages
+pub struct MonoBuffer {
+    pages: [[u8; WIDTH as usize]; (HEIGHT / 8) as usize],
+}
+
+impl MonoBuffer {
+    pub const fn new() -> Self {
+        Self {
+            pages: [[0; WIDTH as usize]; (HEIGHT / 8) as usize],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages = [[0; WIDTH as usize]; (HEIGHT / 8) as usize];
+    }
+
+    fn is_on(&self, x: usize, y: usize) -> bool {
+        self.pages[y / 8][x] & (1 << (y % 8)) != 0
+    }
+}
+
+impl Default for MonoBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OriginDimensions for MonoBuffer {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+impl DrawTarget for MonoBuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+                continue;
+            };
+            if x >= WIDTH as usize || y >= HEIGHT as usize {
+                continue;
+            }
+            let byte = &mut self.pages[y / 8][x];
+            match color {
+                BinaryColor::On => *byte |= 1 << (y % 8),
+                BinaryColor::Off => *byte &= !(1 << (y % 8)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Draw into the buffer of a panel
+macro_rules! buffered_draw_target {
+    ($panel:ident<$($param:ident: $bound:path),*>) => {
+        impl<$($param: $bound),*> OriginDimensions for $panel<$($param),*> {
+            fn size(&self) -> Size {
+                self.buffer.size()
+            }
+        }
+
+        impl<$($param: $bound),*> DrawTarget for $panel<$($param),*> {
+            type Color = BinaryColor;
+            type Error = Infallible;
+
+            fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+            where
+                I: IntoIterator<Item = Pixel<Self::Color>>,
+            {
+                self.buffer.draw_iter(pixels)
+            }
+        }
+    };
+}
+
+/// SH1106 commands
+const SH1106_DISPLAY_OFF: u8 = 0xAE;
+const SH1106_DISPLAY_ON: u8 = 0xAF;
+const SH1106_CONTRAST: u8 = 0x81;
+const SH1106_PAGE: u8 = 0xB0;
+/// The SH1106 has 132 columns of RAM, a 128 pixel panel starts at the third
+const SH1106_COLUMN_OFFSET: u8 = 2;
+const SH1106_CONTRAST_NORMAL: u8 = 0xCF;
+const SH1106_CONTRAST_DIMMED: u8 = 0x01;
+/// Initialization of the SH1106, it's switched on once the RAM is cleared
+const SH1106_INIT: [u8; 22] = [
+    SH1106_DISPLAY_OFF,
+    // Clock divide ratio
+    0xD5,
+    0x80,
+    // Multiplex ratio, 64 rows
+    0xA8,
+    0x3F,
+    // Display offset
+    0xD3,
+    0x00,
+    // Start line 0
+    0x40,
+    // DC-DC converter on
+    0xAD,
+    0x8B,
+    // Segment remap
+    0xA1,
+    // COM scan direction reversed
+    0xC8,
+    // COM pins
+    0xDA,
+    0x12,
+    SH1106_CONTRAST,
+    SH1106_CONTRAST_NORMAL,
+    // Precharge period
+    0xD9,
+    0x22,
+    // VCOMH level
+    0xDB,
+    0x40,
+    // Show the RAM contents, not inverted
+    0xA4,
+    0xA6,
+];
+
+/// 1.3" 128x64 SH1106 OLED on I2C, pin compatible with the SSD1306 but without its
+/// horizontal addressing, so it's written page by page
+pub struct Sh1106<I2C> {
+    i2c: I2C,
+    buffer: MonoBuffer,
+}
+
+impl<I2C: I2c> Sh1106<I2C> {
+    /// Initialize an SH1106 and clear it
+    pub fn new(i2c: I2C) -> Result<Self, &'static str> {
+        let mut panel = Self {
+            i2c,
+            buffer: MonoBuffer::new(),
+        };
+        panel
+            .commands(&SH1106_INIT)
+            .map_err(|_| "Failed to initialize display - device responded but init failed")?;
+        panel.flush()?;
+        panel
+            .commands(&[SH1106_DISPLAY_ON])
+            .map_err(|_| "Failed to switch display")?;
+        Ok(panel)
+    }
+
+    fn commands(&mut self, commands: &[u8]) -> Result<(), I2C::Error> {
+        for command in commands {
+            self.i2c.write(OLED_ADDRESS, &[0x00, *command])?;
+        }
+        Ok(())
+    }
+}
+
+buffered_draw_target!(Sh1106<I2C: I2c>);
+
+impl<I2C: I2c> Panel for Sh1106<I2C> {
+    const NAME: &'static str = "SH1106";
+
+    fn clear_buffer(&mut self) {
+        self.buffer.clear();
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        let mut data = [0x40; WIDTH as usize + 1];
+        for page in 0..self.buffer.pages.len() {
+            self.commands(&[
+                SH1106_PAGE | page as u8,
+                SH1106_COLUMN_OFFSET & 0x0F,
+                0x10 | (SH1106_COLUMN_OFFSET >> 4),
+            ])
+            .map_err(|_| "Failed to flush display")?;
+            data[1..].copy_from_slice(&self.buffer.pages[page]);
+            self.i2c
+                .write(OLED_ADDRESS, &data)
+                .map_err(|_| "Failed to flush display")?;
+        }
+        Ok(())
+    }
+
+    fn set_power(&mut self, power: DisplayPower) -> Result<(), &'static str> {
+        let contrast = match power {
+            DisplayPower::Dimmed => SH1106_CONTRAST_DIMMED,
+            DisplayPower::On | DisplayPower::Off => SH1106_CONTRAST_NORMAL,
+        };
+        let on = match power {
+            DisplayPower::Off => SH1106_DISPLAY_OFF,
+            DisplayPower::On | DisplayPower::Dimmed => SH1106_DISPLAY_ON,
+        };
+        self.commands(&[SH1106_CONTRAST, contrast, on])
+            .map_err(|_| "Failed to switch display")
+    }
+}
+
+/// ST7735 commands
+const ST7735_SWRESET: u8 = 0x01;
+const ST7735_SLPOUT: u8 = 0x11;
+const ST7735_NORON: u8 = 0x13;
+const ST7735_DISPOFF: u8 = 0x28;
+const ST7735_DISPON: u8 = 0x29;
+const ST7735_CASET: u8 = 0x2A;
+const ST7735_RASET: u8 = 0x2B;
+const ST7735_RAMWR: u8 = 0x2C;
+const ST7735_MADCTL: u8 = 0x36;
+const ST7735_COLMOD: u8 = 0x3A;
+/// Landscape, rows and columns exchanged and the columns mirrored
+const ST7735_MADCTL_LANDSCAPE: u8 = 0x60;
+/// 16 bits per pixel
+const ST7735_COLMOD_RGB565: u8 = 0x05;
+
+/// Size of the 1.8" panel in landscape
+const ST7735_WIDTH: u16 = 160;
+const ST7735_HEIGHT: u16 = 128;
+/// Offset of the screens, centered on the panel
+const ST7735_X: u16 = (ST7735_WIDTH - WIDTH as u16) / 2;
+const ST7735_Y: u16 = (ST7735_HEIGHT - HEIGHT as u16) / 2;
+
+/// RGB565 colors of the pixels that are on and off
+const ST7735_FOREGROUND: u16 = 0xFFFF;
+const ST7735_BACKGROUND: u16 = 0x0000;
+
+/// 1.8" 160x128 ST7735 color TFT on SPI, with a data/command line. The monochrome screens are
+/// shown centered in white on black. The backlight is wired on, so dimming keeps it as is
+pub struct St7735<SPI, DC> {
+    spi: SPI,
+    dc: DC,
+    buffer: MonoBuffer,
+}
+
+impl<SPI: SpiDevice, DC: OutputPin> St7735<SPI, DC> {
+    /// Initialize an ST7735 and clear it
+    pub fn new(spi: SPI, dc: DC, delay: &mut impl DelayNs) -> Result<Self, &'static str> {
+        let mut panel = Self {
+            spi,
+            dc,
+            buffer: MonoBuffer::new(),
+        };
+        let init = "Failed to initialize display - device responded but init failed";
+        panel.command(ST7735_SWRESET, &[]).map_err(|_| init)?;
+        delay.delay_ms(150);
+        panel.command(ST7735_SLPOUT, &[]).map_err(|_| init)?;
+        delay.delay_ms(255);
+        panel
+            .command(ST7735_COLMOD, &[ST7735_COLMOD_RGB565])
+            .map_err(|_| init)?;
+        panel
+            .command(ST7735_MADCTL, &[ST7735_MADCTL_LANDSCAPE])
+            .map_err(|_| init)?;
+        panel.command(ST7735_NORON, &[]).map_err(|_| init)?;
+
+        // Clear the whole panel, the screens only cover the middle
+        panel
+            .window(0, 0, ST7735_WIDTH, ST7735_HEIGHT)
+            .map_err(|_| init)?;
+        let row = [0u8; ST7735_WIDTH as usize * 2];
+        for _ in 0..ST7735_HEIGHT {
+            panel.data(&row).map_err(|_| init)?;
+        }
+        panel.command(ST7735_DISPON, &[]).map_err(|_| init)?;
+        Ok(panel)
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<(), ()> {
+        self.dc.set_low().map_err(|_| ())?;
+        self.spi.write(&[command]).map_err(|_| ())?;
+        if !data.is_empty() {
+            self.data(data)?;
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.dc.set_high().map_err(|_| ())?;
+        self.spi.write(data).map_err(|_| ())
+    }
+
+    /// Start writing the pixels of an area
+    fn window(&mut self, x: u16, y: u16, width: u16, height: u16) -> Result<(), ()> {
+        let [x0h, x0l] = x.to_be_bytes();
+        let [x1h, x1l] = (x + width - 1).to_be_bytes();
+        let [y0h, y0l] = y.to_be_bytes();
+        let [y1h, y1l] = (y + height - 1).to_be_bytes();
+        self.command(ST7735_CASET, &[x0h, x0l, x1h, x1l])?;
+        self.command(ST7735_RASET, &[y0h, y0l, y1h, y1l])?;
+        self.command(ST7735_RAMWR, &[])
+    }
+}
+
+buffered_draw_target!(St7735<SPI: SpiDevice, DC: OutputPin>);
+
+impl<SPI: SpiDevice, DC: OutputPin> Panel for St7735<SPI, DC> {
+    const NAME: &'static str = "ST7735";
+
+    fn clear_buffer(&mut self) {
+        self.buffer.clear();
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        self.window(ST7735_X, ST7735_Y, WIDTH as u16, HEIGHT as u16)
+            .map_err(|_| "Failed to flush display")?;
+        let mut row = [0u8; WIDTH as usize * 2];
+        for y in 0..HEIGHT as usize {
+            for (x, pixel) in row.chunks_exact_mut(2).enumerate() {
+                let color = if self.buffer.is_on(x, y) {
+                    ST7735_FOREGROUND
+                } else {
+                    ST7735_BACKGROUND
+                };
+                pixel.copy_from_slice(&color.to_be_bytes());
+            }
+            self.data(&row).map_err(|_| "Failed to flush display")?;
+        }
+        Ok(())
+    }
+
+    fn set_power(&mut self, power: DisplayPower) -> Result<(), &'static str> {
+        let command = match power {
+            DisplayPower::Off => ST7735_DISPOFF,
+            DisplayPower::On | DisplayPower::Dimmed => ST7735_DISPON,
+        };
+        self.command(command, &[])
+            .map_err(|_| "Failed to switch display")
+    }
+}
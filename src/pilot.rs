@@ -0,0 +1,255 @@
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    analog::adc::AdcPin,
+    ledc::{
+        channel::{self, Channel, ChannelHW, ChannelIFace},
+        timer::{self, TimerIFace},
+        Ledc, LowSpeed,
+    },
+    peripherals::{ADC1, GPIO4, GPIO7},
+    time::Rate,
+};
+
+use crate::{
+    charger::{self, Charger, ChargerState, InputEvent},
+    info, io_state, mk_static,
+    pins::SharedAdc,
+    relay,
+    sequence::{self, Transition},
+    smart_charging::{CurrentLimits, LimitEvent, LIMIT_PUBSUB, MIN_CURRENT_AMPS},
+    warn,
+};
+
+const CHECK_INTERVAL_MS: u64 = 250;
+/// Samples per check, spread over more than one 1kHz pilot period to catch the negative half
+const SAMPLES: usize = 32;
+const SAMPLE_SPACING_US: u64 = 50;
+
+/// Full scale of the ADC at 11dB attenuation
+const ADC_FULL_SCALE_MV: i32 = 3100;
+const ADC_MAX: i32 = 4095;
+/// The front-end maps the ±12V pilot onto the ADC range: 0mV at -12V, full scale at +12V
+const PILOT_RANGE_MV: i32 = 24_000;
+const PILOT_OFFSET_MV: i32 = -12_000;
+
+/// Without the vehicle's diode the negative half stays well above -12V, IEC 61851 allows
+/// -12V ±1V
+pub const DIODE_THRESHOLD_MV: i32 = -11_000;
+
+/// Set while a connected vehicle has no pilot diode, cleared when the cable is removed
+static DIODE_MISSING: AtomicBool = AtomicBool::new(false);
+
+/// Frequency of the pilot PWM (IEC 61851-1)
+const PWM_HZ: u32 = 1000;
+/// Duty of the pilot timer for a steady high level, its resolution is 10 bits
+const PWM_FULL_SCALE: u32 = 1 << 10;
+/// Duty cycle in tenths of a percent that doesn't allow the vehicle to charge, a steady +12V
+pub const STEADY_PERMILLE: u16 = 1000;
+/// Highest current the duty cycle can offer
+const MAX_OFFERED_AMPS: u16 = 80;
+
+/// Duty cycle of the pilot PWM in tenths of a percent, while the PWM output is used
+static DUTY_PERMILLE: AtomicU16 = AtomicU16::new(STEADY_PERMILLE);
+/// Set when this firmware drives the pilot, the diode check then only samples while it oscillates
+static PWM_DRIVEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the diode check failed for the connected vehicle
+pub fn diode_missing() -> bool {
+    DIODE_MISSING.load(Ordering::Relaxed)
+}
+
+/// Clear the diode fault once the vehicle is disconnected
+pub fn clear_diode_fault() {
+    if DIODE_MISSING.swap(false, Ordering::Relaxed) {
+        info!("PILT: Diode fault cleared");
+    }
+}
+
+/// Duty cycle in tenths of a percent that offers a current to the vehicle (IEC 61851-1 table
+/// A.8): amps / 0.6 from 6A to 51A and amps / 2.5 + 64 above. Below 6A the vehicle can't charge,
+/// the minimum current is offered instead
+pub fn duty_permille(amps: u16) -> u16 {
+    let amps = amps.clamp(MIN_CURRENT_AMPS, MAX_OFFERED_AMPS);
+    if amps <= 51 {
+        amps * 50 / 3
+    } else {
+        amps * 4 + 640
+    }
+}
+
+/// Whether the pilot oscillates, the negative half to check the diode on only exists then. A
+/// pilot driven by an external oscillator is assumed to oscillate
+fn oscillating() -> bool {
+    !PWM_DRIVEN.load(Ordering::Relaxed) || DUTY_PERMILLE.load(Ordering::Relaxed) < STEADY_PERMILLE
+}
+
+/// Pilot voltage in millivolts for a raw ADC reading of the front-end
+pub fn pilot_millivolts(raw: u16) -> i32 {
+    let adc_mv = i32::from(raw).min(ADC_MAX) * ADC_FULL_SCALE_MV / ADC_MAX;
+    adc_mv * PILOT_RANGE_MV / ADC_FULL_SCALE_MV + PILOT_OFFSET_MV
+}
+
+/// The diode of a vehicle clips the negative half of the pilot to -12V, a higher negative level
+/// means a cheat device or a wiring fault
+pub fn diode_present(negative_half_mv: i32) -> bool {
+    negative_half_mv <= DIODE_THRESHOLD_MV
+}
+
+/// Lowest pilot level over a few periods, which is the level of the negative half
+async fn sample_negative_half(
+    adc: SharedAdc,
+    pin: &mut AdcPin<GPIO4<'static>, ADC1<'static>>,
+) -> i32 {
+    let mut lowest = i32::MAX;
+    for _ in 0..SAMPLES {
+        let raw = loop {
+            if let Ok(raw) = adc.borrow_mut().read_oneshot(pin) {
+                break raw;
+            }
+        };
+        lowest = lowest.min(pilot_millivolts(raw));
+        Timer::after(Duration::from_micros(SAMPLE_SPACING_US)).await;
+    }
+    lowest
+}
+
+/// Task to check the pilot diode while a vehicle is connected, a missing diode faults the
+/// charger until the cable is removed
+#[embassy_executor::task]
+pub async fn pilot_diode_task(
+    charger: &'static Charger,
+    adc: SharedAdc,
+    mut pin: AdcPin<GPIO4<'static>, ADC1<'static>>,
+) {
+    info!("TASK: Started Pilot Diode Check");
+
+    // The first check of a connected vehicle is in the sequence log, and a failed one
+    let mut checked = false;
+
+    loop {
+        Timer::after(Duration::from_millis(CHECK_INTERVAL_MS)).await;
+
+        if diode_missing() || !charger.get_state().await.is_vehicle_connected() || !oscillating() {
+            checked = false;
+            continue;
+        }
+
+        let negative_half_mv = sample_negative_half(adc, &mut pin).await;
+        io_state::record_pilot(negative_half_mv);
+        if !checked || !diode_present(negative_half_mv) {
+            checked = true;
+            sequence::record(Transition::Pilot(negative_half_mv));
+        }
+        if !diode_present(negative_half_mv) {
+            warn!("PILT: No diode detected, negative half at {negative_half_mv}mV");
+            DIODE_MISSING.store(true, Ordering::Relaxed);
+            charger::STATE_IN_CHANNEL
+                .send(InputEvent::DiodeMissing)
+                .await;
+        }
+    }
+}
+
+/// The control pilot output, a 1kHz PWM on GPIO7 from the LED PWM controller that drives the
+/// ±12V buffer of the pilot front-end. The duty cycle tells the vehicle the current it may draw
+pub struct PilotPwm {
+    channel: Channel<'static, LowSpeed>,
+}
+
+impl PilotPwm {
+    /// Set up the pilot on GPIO7 at a steady high level, the vehicle can't charge until a
+    /// current is offered
+    pub fn new(ledc: &'static Ledc<'static>, pin: GPIO7<'static>) -> Result<Self, &'static str> {
+        let pwm_timer = mk_static!(
+            timer::Timer<'static, LowSpeed>,
+            ledc.timer::<LowSpeed>(timer::Number::Timer2)
+        );
+        pwm_timer
+            .configure(timer::config::Config {
+                duty: timer::config::Duty::Duty10Bit,
+                clock_source: timer::LSClockSource::APBClk,
+                frequency: Rate::from_hz(PWM_HZ),
+            })
+            .map_err(|_| "Failed to configure the pilot timer")?;
+
+        let mut channel = ledc.channel(channel::Number::Channel1, pin);
+        channel
+            .configure(channel::config::Config {
+                timer: pwm_timer,
+                duty_pct: 100,
+                pin_config: channel::config::PinConfig::PushPull,
+            })
+            .map_err(|_| "Failed to configure the pilot channel")?;
+        PWM_DRIVEN.store(true, Ordering::Relaxed);
+        Ok(Self { channel })
+    }
+
+    fn set_duty(&mut self, permille: u16) {
+        self.channel
+            .set_duty_hw(u32::from(permille) * PWM_FULL_SCALE / u32::from(STEADY_PERMILLE));
+        DUTY_PERMILLE.store(permille, Ordering::Relaxed);
+    }
+}
+
+/// Task to offer the effective current limit on the pilot during a session, at the minimum
+/// current at least. Outside a session, and while charging is suspended for a demand response,
+/// the pilot is steady high so the vehicle doesn't draw current
+#[embassy_executor::task]
+pub async fn pilot_pwm_task(
+    mut pwm: PilotPwm,
+    charger: &'static Charger,
+    limits: &'static CurrentLimits,
+) {
+    info!("TASK: Started Pilot PWM");
+
+    let mut states = charger::STATE_PUBSUB.subscriber().unwrap();
+    let mut limit_events = LIMIT_PUBSUB.subscriber().unwrap();
+    let mut state = charger.get_state().await;
+    let mut duty = STEADY_PERMILLE;
+    pwm.set_duty(duty);
+
+    loop {
+        let offered = (state == ChargerState::Charging && !relay::is_suspended())
+            .then_some(limits.effective_limit().await);
+        let next_duty = offered.map_or(STEADY_PERMILLE, duty_permille);
+        if next_duty != duty {
+            duty = next_duty;
+            pwm.set_duty(duty);
+            match offered {
+                Some(amps) => info!(
+                    "PILT: Offering {}A, duty cycle {}.{}%",
+                    amps.max(MIN_CURRENT_AMPS),
+                    duty / 10,
+                    duty % 10
+                ),
+                None => info!("PILT: Pilot steady, no current offered"),
+            }
+        }
+
+        // Every limit event is a new effective limit, a suspension doesn't change the state and
+        // is picked up within a second
+        match select3(
+            states.next_message(),
+            limit_events.next_message(),
+            Timer::after(Duration::from_secs(1)),
+        )
+        .await
+        {
+            Either3::First(WaitResult::Message((new_state, _))) => state = new_state,
+            Either3::First(WaitResult::Lagged(_)) => state = charger.get_state().await,
+            Either3::Second(WaitResult::Message(LimitEvent::FallbackEngaged(source))) => {
+                warn!(
+                    "PILT: No limit from {}, offering the failsafe current",
+                    source.as_str()
+                );
+            }
+            Either3::Second(WaitResult::Message(LimitEvent::FallbackCleared(source))) => {
+                info!("PILT: {} limit is offered again", source.as_str());
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use core::{cell::RefCell, convert::Infallible};
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    analog::adc::Adc,
+    gpio::{Input, Output},
+    peripherals::ADC1,
+    Blocking,
+};
+
+use embedded_hal::digital::{ErrorType, OutputPin};
+
+use crate::expander::ExpanderIo;
+use crate::warn;
+
+/// Interval an expander input is polled at, the expander's interrupt line isn't wired
+const POLL_INTERVAL_MS: u64 = 20;
+
+/// The GPIO expander on the I2C bus, shared by the pins mapped to it
+pub type SharedExpander = &'static RefCell<dyn ExpanderIo>;
+
+/// ADC1, shared by the pilot check and the NTC, each reads it without awaiting in between
+pub type SharedAdc = &'static RefCell<Adc<'static, ADC1<'static>, Blocking>>;
+
+/// An output that more than one SPI device is built on in turn, the chip select of the card
+/// reader whose driver is built again after the reader was lost
+#[derive(Clone, Copy)]
+pub struct SharedOutput(pub &'static RefCell<Output<'static>>);
+
+impl ErrorType for SharedOutput {
+    type Error = Infallible;
+}
+
+impl OutputPin for SharedOutput {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().set_low();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().set_high();
+        Ok(())
+    }
+}
+
+/// Where a logical pin is wired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinLocation {
+    /// Its GPIO of the C6
+    Gpio,
+    /// A pin of the GPIO expander
+    Expander(u8),
+}
+
+impl PinLocation {
+    /// `gpio` or `expander:<pin>`, empty is the GPIO
+    pub fn parse(value: &str) -> Result<Self, &'static str> {
+        match value {
+            "" | "gpio" => Ok(Self::Gpio),
+            _ => value
+                .strip_prefix("expander:")
+                .and_then(|pin| pin.parse().ok())
+                .map(Self::Expander)
+                .ok_or("Pin must be gpio or expander:<pin>"),
+        }
+    }
+}
+
+/// Resolve the location of a pin, a pin mapped to a missing expander stays on its GPIO
+fn resolve(
+    name: &str,
+    location: &str,
+    expander: Option<SharedExpander>,
+) -> Option<(SharedExpander, u8)> {
+    match (PinLocation::parse(location), expander) {
+        (Ok(PinLocation::Gpio), _) => None,
+        (Ok(PinLocation::Expander(pin)), Some(expander)) => Some((expander, pin)),
+        (Ok(PinLocation::Expander(_)), None) => {
+            warn!("PINS: {name} is mapped to the expander but there is none, using its GPIO");
+            None
+        }
+        (Err(e), _) => {
+            warn!("PINS: {name}: {e}, using its GPIO");
+            None
+        }
+    }
+}
+
+/// A logical output, on a GPIO or an expander pin
+pub enum MappedOutput {
+    Gpio(Output<'static>),
+    Expander(SharedExpander, u8),
+}
+
+impl MappedOutput {
+    /// Map an output by its configured location, it starts low
+    pub fn new(
+        name: &'static str,
+        gpio: Output<'static>,
+        location: &str,
+        expander: Option<SharedExpander>,
+    ) -> Self {
+        let Some((expander, pin)) = resolve(name, location, expander) else {
+            return Self::Gpio(gpio);
+        };
+        if let Err(e) = expander.borrow_mut().configure_output(pin, false) {
+            warn!("PINS: {name} on expander pin {pin}: {e}, using its GPIO");
+            return Self::Gpio(gpio);
+        }
+        Self::Expander(expander, pin)
+    }
+
+    pub fn set_level(&mut self, high: bool) {
+        match self {
+            Self::Gpio(output) => output.set_level(high.into()),
+            // A ground fault trip can interrupt a transfer to the expander, the pin is set the
+            // next time instead of panicking on the borrow
+            Self::Expander(expander, pin) => match expander.try_borrow_mut() {
+                Ok(mut expander) => {
+                    if let Err(e) = expander.set_output(*pin, high) {
+                        warn!("PINS: Failed to set expander pin {pin}: {e}");
+                    }
+                }
+                Err(_) => warn!("PINS: Expander busy, pin {pin} not set"),
+            },
+        }
+    }
+
+    pub fn set_high(&mut self) {
+        self.set_level(true);
+    }
+
+    /// Level the output reads back, an expander that reset since it was set reads its default
+    pub fn read_back(&mut self) -> Result<bool, &'static str> {
+        match self {
+            Self::Gpio(output) => Ok(output.is_set_high()),
+            Self::Expander(expander, pin) => expander.borrow_mut().is_high(*pin),
+        }
+    }
+
+    pub fn set_low(&mut self) {
+        self.set_level(false);
+    }
+}
+
+/// A logical input with a pull-up, on a GPIO or an expander pin
+pub enum MappedInput {
+    Gpio(Input<'static>),
+    Expander(SharedExpander, u8),
+}
+
+impl MappedInput {
+    /// Map an input by its configured location
+    pub fn new(
+        name: &'static str,
+        gpio: Input<'static>,
+        location: &str,
+        expander: Option<SharedExpander>,
+    ) -> Self {
+        let Some((expander, pin)) = resolve(name, location, expander) else {
+            return Self::Gpio(gpio);
+        };
+        if let Err(e) = expander.borrow_mut().configure_input(pin) {
+            warn!("PINS: {name} on expander pin {pin}: {e}, using its GPIO");
+            return Self::Gpio(gpio);
+        }
+        Self::Expander(expander, pin)
+    }
+
+    /// Level of the input, an expander that doesn't respond reads high like an open switch
+    pub fn is_low(&mut self) -> bool {
+        match self {
+            Self::Gpio(input) => input.is_low(),
+            Self::Expander(expander, pin) => match expander.borrow_mut().is_high(*pin) {
+                Ok(high) => !high,
+                Err(e) => {
+                    warn!("PINS: Failed to read expander pin {pin}: {e}");
+                    false
+                }
+            },
+        }
+    }
+
+    /// Wait for the level to change
+    pub async fn wait_for_any_edge(&mut self) {
+        if let Self::Gpio(input) = self {
+            input.wait_for_any_edge().await;
+            return;
+        }
+        let level = self.is_low();
+        while self.is_low() == level {
+            Timer::after(Duration::from_millis(POLL_INTERVAL_MS)).await;
+        }
+    }
+}
@@ -0,0 +1,214 @@
+/// High-level behavior presets, each one sets a group of related settings so an installer
+/// only has to pick where the charger is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BehaviorProfile {
+    /// No profile configured, the behavior from before profiles existed
+    Legacy,
+    Home,
+    Workplace,
+    Public,
+}
+
+impl BehaviorProfile {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "legacy" => Some(Self::Legacy),
+            "home" => Some(Self::Home),
+            "workplace" => Some(Self::Workplace),
+            "public" => Some(Self::Public),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Legacy => "legacy",
+            Self::Home => "home",
+            Self::Workplace => "workplace",
+            Self::Public => "public",
+        }
+    }
+
+    /// The settings this profile stands for
+    pub fn settings(&self) -> BehaviorSettings {
+        match self {
+            // Only the status page and every card checked with the central system, so an
+            // existing install behaves the same until a profile is picked
+            Self::Legacy => BehaviorSettings {
+                free_vend: false,
+                receipts: false,
+                quiet_hours: None,
+                display_pages: DisplayPages::STATUS,
+                authorization: chain(&[AuthSource::CentralSystem]),
+            },
+            // Plug in and charge, nobody to bill and no light at night
+            Self::Home => BehaviorSettings {
+                free_vend: true,
+                receipts: false,
+                quiet_hours: Some((22, 7)),
+                display_pages: DisplayPages::STATUS
+                    .with(DisplayPages::TRANSACTION)
+                    .with(DisplayPages::ERROR),
+                authorization: chain(&[AuthSource::FreeVend]),
+            },
+            // Known badges are accepted locally, others are checked with the central system
+            Self::Workplace => BehaviorSettings {
+                free_vend: false,
+                receipts: true,
+                quiet_hours: None,
+                display_pages: DisplayPages::STATUS
+                    .with(DisplayPages::TRANSACTION)
+                    .with(DisplayPages::ERROR),
+                authorization: chain(&[AuthSource::LocalList, AuthSource::CentralSystem]),
+            },
+            Self::Public => BehaviorSettings {
+                free_vend: false,
+                receipts: true,
+                quiet_hours: None,
+                display_pages: DisplayPages::STATUS
+                    .with(DisplayPages::TRANSACTION)
+                    .with(DisplayPages::ERROR)
+                    .with(DisplayPages::QR_CODE)
+                    .with(DisplayPages::ABOUT),
+                authorization: chain(&[AuthSource::CentralSystem]),
+            },
+        }
+    }
+}
+
+/// Where an id tag is authorized, sources are tried in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthSource {
+    /// Accept every id tag
+    FreeVend,
+    /// Accept the id tags in the configured local list
+    LocalList,
+    /// Accept the session of a guest code that was just entered
+    GuestCode,
+    /// Send an Authorize request to the central system
+    CentralSystem,
+}
+
+impl AuthSource {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "free_vend" => Some(Self::FreeVend),
+            "local" => Some(Self::LocalList),
+            "guest" => Some(Self::GuestCode),
+            "central" => Some(Self::CentralSystem),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::FreeVend => "free_vend",
+            Self::LocalList => "local",
+            Self::GuestCode => "guest",
+            Self::CentralSystem => "central",
+        }
+    }
+}
+
+pub type AuthorizationChain = heapless::Vec<AuthSource, 4>;
+
+fn chain(sources: &[AuthSource]) -> AuthorizationChain {
+    heapless::Vec::from_slice(sources).unwrap_or_default()
+}
+
+/// Parse a comma separated authorization chain, e.g. `local,central`
+pub fn parse_authorization_chain(value: &str) -> Option<AuthorizationChain> {
+    let mut chain = AuthorizationChain::new();
+    for source in value.split(',') {
+        let source = AuthSource::parse(source)?;
+        if !chain.contains(&source) {
+            chain.push(source).ok()?;
+        }
+    }
+    (!chain.is_empty()).then_some(chain)
+}
+
+/// Display pages shown in rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayPages(u8);
+
+impl DisplayPages {
+    pub const STATUS: Self = Self(1 << 0);
+    pub const ABOUT: Self = Self(1 << 1);
+    pub const SESSIONS: Self = Self(1 << 2);
+    pub const NETWORK: Self = Self(1 << 3);
+    /// Only shown while charging
+    pub const TRANSACTION: Self = Self(1 << 4);
+    /// Only shown while faulted or locked out
+    pub const ERROR: Self = Self(1 << 5);
+    /// Only shown while available, to start a session from a phone
+    pub const QR_CODE: Self = Self(1 << 6);
+    /// Every page, in the order they are shown
+    pub const ALL: [Self; 7] = [
+        Self::STATUS,
+        Self::TRANSACTION,
+        Self::ERROR,
+        Self::NETWORK,
+        Self::SESSIONS,
+        Self::QR_CODE,
+        Self::ABOUT,
+    ];
+
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn contains(&self, page: Self) -> bool {
+        self.0 & page.0 == page.0
+    }
+
+    /// Parse a comma separated list of pages, e.g. `status,about`
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut pages = Self(0);
+        for page in value.split(',') {
+            pages = pages.with(match page.trim() {
+                "status" => Self::STATUS,
+                "about" => Self::ABOUT,
+                "sessions" => Self::SESSIONS,
+                "network" => Self::NETWORK,
+                "transaction" => Self::TRANSACTION,
+                "error" => Self::ERROR,
+                "qr_code" => Self::QR_CODE,
+                _ => return None,
+            });
+        }
+        (pages.0 != 0).then_some(pages)
+    }
+
+    /// The enabled pages in the order they are shown
+    pub fn enabled(&self) -> impl Iterator<Item = Self> + '_ {
+        Self::ALL.into_iter().filter(|page| self.contains(*page))
+    }
+}
+
+/// Parse quiet hours as `<start>-<end>` in local hours, e.g. `22-7`
+pub fn parse_quiet_hours(value: &str) -> Option<(u8, u8)> {
+    let (start, end) = value.split_once('-')?;
+    let start: u8 = start.trim().parse().ok()?;
+    let end: u8 = end.trim().parse().ok()?;
+    (start < 24 && end < 24 && start != end).then_some((start, end))
+}
+
+/// Whether a local hour falls within quiet hours, which may wrap around midnight
+pub fn in_quiet_hours(quiet_hours: Option<(u8, u8)>, hour: u8) -> bool {
+    match quiet_hours {
+        Some((start, end)) if start < end => hour >= start && hour < end,
+        Some((start, end)) => hour >= start || hour < end,
+        None => false,
+    }
+}
+
+/// The group of settings a behavior profile presets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BehaviorSettings {
+    pub free_vend: bool, // Start charging on cable insert, without a card swipe
+    pub receipts: bool,  // Send a receipt DataTransfer when a transaction stops
+    pub quiet_hours: Option<(u8, u8)>, // Local hours the display, LEDs and buzzer keep quiet
+    pub display_pages: DisplayPages,
+    pub authorization: AuthorizationChain,
+}
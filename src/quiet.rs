@@ -0,0 +1,29 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::{config::Config, ntp, profile};
+
+/// Last time somebody used the charger, a card, the cable or the button
+static LAST_INTERACTION: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Record a user at the charger, the quiet hours are lifted for the configured override time so
+/// the prompts and the display can be seen and heard
+pub fn interaction() {
+    LAST_INTERACTION.lock(|last| last.set(Some(Instant::now())));
+}
+
+/// Whether the quiet hours of the behavior profile are lifted by a recent interaction
+fn overridden(config: &Config) -> bool {
+    let window = Duration::from_secs(u64::from(config.quiet_override_mins) * 60);
+    LAST_INTERACTION.lock(|last| last.get().is_some_and(|at| at.elapsed() < window))
+}
+
+/// Whether it's within the quiet hours of the behavior profile in local time: the display is
+/// dimmed, the LEDs are lowered and the buzzer is silent. Never before the clock is set
+pub fn is_quiet(config: &Config) -> bool {
+    ntp::get_local_hour(&config.time_zone)
+        .is_some_and(|hour| profile::in_quiet_hours(config.behavior.quiet_hours, hour))
+        && !overridden(config)
+}
@@ -0,0 +1,84 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::Input;
+
+use crate::{
+    charger::{self, InputEvent},
+    info, relay,
+    sequence::{self, Transition},
+    warn,
+};
+
+/// Time the test output has to stay released before the RCD counts as reset
+const RELEASE_DEBOUNCE_MS: u64 = 50;
+
+/// Set on a trip, cleared only by the master card or a remote reset
+static LATCHED: AtomicBool = AtomicBool::new(false);
+/// Level of the test output, set while the RCD reports a residual current
+static INPUT_TRIPPED: AtomicBool = AtomicBool::new(false);
+static TRIPS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a ground fault keeps the charger faulted
+pub fn latched() -> bool {
+    LATCHED.load(Ordering::Relaxed)
+}
+
+/// Ground faults since boot
+pub fn trips() -> u32 {
+    TRIPS.load(Ordering::Relaxed)
+}
+
+/// Clear the latched ground fault, refused while the RCD itself hasn't been reset
+/// Returns true if a ground fault was cleared
+pub fn clear() -> bool {
+    if !latched() {
+        return false;
+    }
+    if INPUT_TRIPPED.load(Ordering::Relaxed) {
+        warn!("RCD : Still tripped, the RCD has to be reset first");
+        return false;
+    }
+    LATCHED.store(false, Ordering::Relaxed);
+    relay::release();
+    info!("RCD : Ground fault cleared");
+    true
+}
+
+/// Task to watch the test output of the residual current device, low on a trip. The relay is
+/// opened directly, without waiting for the state machine, and the charger faults with a
+/// GroundFailure until the fault is cleared with the master card or ChangeAvailability Operative.
+/// It runs on an interrupt executor, a task blocking the main executor doesn't delay a trip
+#[embassy_executor::task]
+pub async fn rcd_task(mut input: Input<'static>) {
+    info!("TASK: Started RCD Monitor");
+
+    loop {
+        if !input.is_low() {
+            input.wait_for_low().await;
+            continue;
+        }
+
+        INPUT_TRIPPED.store(true, Ordering::Relaxed);
+        if !LATCHED.swap(true, Ordering::Relaxed) {
+            relay::open_now();
+            sequence::record(Transition::GroundFault(true));
+            TRIPS.fetch_add(1, Ordering::Relaxed);
+            warn!("RCD : Ground fault, relay opened");
+            charger::STATE_IN_CHANNEL
+                .send(InputEvent::GroundFault)
+                .await;
+        }
+
+        // Released once the RCD is reset, a bounce counts as still tripped
+        loop {
+            input.wait_for_high().await;
+            Timer::after(Duration::from_millis(RELEASE_DEBOUNCE_MS)).await;
+            if input.is_high() {
+                break;
+            }
+        }
+        INPUT_TRIPPED.store(false, Ordering::Relaxed);
+        sequence::record(Transition::GroundFault(false));
+        info!("RCD : Reset, the ground fault can be cleared");
+    }
+}
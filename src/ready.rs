@@ -0,0 +1,127 @@
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    waitqueue::MultiWakerRegistration,
+};
+
+use crate::info;
+
+/// Most tasks waiting on one subsystem at a time, more wake all waiters to poll again
+const WAITERS: usize = 8;
+
+/// Subsystems tasks wait for at startup, in the order they normally come up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// An IP address was acquired
+    Network,
+    /// The clock was set, by the RTC, NTP or the central system
+    Time,
+    /// The OCPP connection to the broker is up
+    Mqtt,
+    /// The central system answered the BootNotification
+    Boot,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 4] = [
+        Subsystem::Network,
+        Subsystem::Time,
+        Subsystem::Mqtt,
+        Subsystem::Boot,
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            Self::Network => 0,
+            Self::Time => 1,
+            Self::Mqtt => 2,
+            Self::Boot => 3,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Network => "Network",
+            Self::Time => "Time",
+            Self::Mqtt => "MQTT",
+            Self::Boot => "Boot",
+        }
+    }
+}
+
+/// A barrier that opens once, a subsystem that goes down later is handled by the tasks using it
+pub struct ReadySignal {
+    ready: AtomicBool,
+    wakers: Mutex<CriticalSectionRawMutex, RefCell<MultiWakerRegistration<WAITERS>>>,
+}
+
+impl ReadySignal {
+    pub const fn new() -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            wakers: Mutex::new(RefCell::new(MultiWakerRegistration::new())),
+        }
+    }
+
+    /// Open the barrier and wake the waiting tasks, returns false when it already was open
+    pub fn set(&self) -> bool {
+        if self.ready.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.wakers.lock(|wakers| wakers.borrow_mut().wake());
+        true
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Wait for the barrier to open, returns right away when it is
+    pub async fn wait(&self) {
+        poll_fn(|cx| {
+            self.wakers.lock(|wakers| {
+                if self.is_ready() {
+                    Poll::Ready(())
+                } else {
+                    wakers.borrow_mut().register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl Default for ReadySignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static READY: [ReadySignal; Subsystem::ALL.len()] = [
+    ReadySignal::new(),
+    ReadySignal::new(),
+    ReadySignal::new(),
+    ReadySignal::new(),
+];
+
+/// Mark a subsystem as up, later calls do nothing
+pub fn set(subsystem: Subsystem) {
+    if READY[subsystem.index()].set() {
+        info!("RDY : {} ready", subsystem.as_str());
+    }
+}
+
+pub fn is_ready(subsystem: Subsystem) -> bool {
+    READY[subsystem.index()].is_ready()
+}
+
+/// Wait until a subsystem came up, instead of guessing how long startup takes
+pub async fn wait(subsystem: Subsystem) {
+    READY[subsystem.index()].wait().await
+}
@@ -0,0 +1,451 @@
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    pubsub::WaitResult,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rtc_cntl::SocResetReason;
+
+use crate::{
+    charger::{self, ChargerState, InputEvent, OutputEvent},
+    error, info, io_state, mqtt,
+    pins::{MappedInput, MappedOutput},
+    sequence::{self, Transition},
+    storage::{self, Slot},
+    warn,
+};
+
+/// Time between the warning and energizing the coil, for the display and the log to get out
+/// and a publish in flight to finish
+const PREWARN_MS: u64 = 50;
+/// Time the publishes are held, from the warning until the inrush has passed
+const TX_HOLD_MS: u64 = 300;
+/// Time the supply takes to recover from the inrush, before the relay is verified
+const SETTLE_MS: u64 = 200;
+/// Size of the serialized power record
+const RECORD_SIZE: usize = 9;
+/// Interval the contactor feedback is compared with the relay at
+const FEEDBACK_POLL_MS: u64 = 50;
+/// Time the contactor feedback may differ from the relay, the contactor's travel and bounce
+const FEEDBACK_MISMATCH_MS: u64 = 500;
+
+/// Brownout resets, kept in flash so they're counted across the resets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PowerRecord {
+    brownout_resets: u32,
+    /// Brownout resets while the relay coil was being energized
+    relay_brownouts: u32,
+    /// The coil is being energized, still set at boot when the reset interrupted it
+    armed: bool,
+}
+
+impl PowerRecord {
+    const fn new() -> Self {
+        Self {
+            brownout_resets: 0,
+            relay_brownouts: 0,
+            armed: false,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.brownout_resets.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.relay_brownouts.to_le_bytes());
+        bytes[8] = u8::from(self.armed);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+        Some(Self {
+            brownout_resets: u32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            relay_brownouts: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            armed: bytes[8] == 1,
+        })
+    }
+}
+
+static RECORD: Mutex<CriticalSectionRawMutex, Cell<PowerRecord>> =
+    Mutex::new(Cell::new(PowerRecord::new()));
+static RESET_REASON: Mutex<CriticalSectionRawMutex, RefCell<heapless::String<24>>> =
+    Mutex::new(RefCell::new(heapless::String::new()));
+/// The relay output, shared so a safety input can open it without the state machine
+static RELAY: Mutex<CriticalSectionRawMutex, RefCell<Option<MappedOutput>>> =
+    Mutex::new(RefCell::new(None));
+/// Set while a safety input keeps the relay open, it's not energized until released
+static INHIBITED: AtomicBool = AtomicBool::new(false);
+/// Set while charging is suspended for a demand response, the relay closes again when released
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// The state machine asks for the relay to be on
+static WANTED: AtomicBool = AtomicBool::new(false);
+/// Level the relay was last driven to
+static COMMANDED: AtomicBool = AtomicBool::new(false);
+/// Set when the contactor didn't follow the relay, until cleared
+static SWITCH_FAILED: AtomicBool = AtomicBool::new(false);
+/// Set while the contactor feedback differs from the relay
+static FEEDBACK_MISMATCH: AtomicBool = AtomicBool::new(false);
+static SWITCH_FAILURES: AtomicU32 = AtomicU32::new(0);
+/// Set from the warning until the coil is energized
+static SWITCH_PENDING: AtomicBool = AtomicBool::new(false);
+/// When the relay last changed level
+static LAST_SWITCH: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+static ACTUATIONS: AtomicU32 = AtomicU32::new(0);
+static VERIFY_FAILURES: AtomicU32 = AtomicU32::new(0);
+/// Time the relay task has to open the relay once power is removed
+static OPEN_DEADLINE_MS: AtomicU32 = AtomicU32::new(500);
+/// When the relay has to be open by, set while power is removed and the relay is still on
+static OPEN_DEADLINE: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+/// Wakes the deadline monitor with a new deadline
+static OPEN_EXPECTED: Signal<CriticalSectionRawMutex, Instant> = Signal::new();
+static DEADLINE_MISSES: AtomicU32 = AtomicU32::new(0);
+
+/// Brownout resets and relay actuations, for the diagnostics
+pub struct PowerStats {
+    pub reset_reason: heapless::String<24>,
+    pub brownout_resets: u32,
+    pub relay_brownouts: u32,
+    pub actuations: u32,
+    pub verify_failures: u32,
+    pub switch_failures: u32,
+    pub deadline_misses: u32,
+}
+
+pub fn power_stats() -> PowerStats {
+    let record = RECORD.lock(|record| record.get());
+    PowerStats {
+        reset_reason: RESET_REASON.lock(|reason| reason.borrow().clone()),
+        brownout_resets: record.brownout_resets,
+        relay_brownouts: record.relay_brownouts,
+        actuations: ACTUATIONS.load(Ordering::Relaxed),
+        verify_failures: VERIFY_FAILURES.load(Ordering::Relaxed),
+        switch_failures: SWITCH_FAILURES.load(Ordering::Relaxed),
+        deadline_misses: DEADLINE_MISSES.load(Ordering::Relaxed),
+    }
+}
+
+fn save(record: PowerRecord) {
+    RECORD.lock(|current| current.set(record));
+    if let Err(e) = storage::write(Slot::Power, &record.to_bytes()) {
+        warn!("RLAY: Failed to persist the power record: {e}");
+    }
+}
+
+/// Check the reason of the last reset, a brownout while the relay coil was being energized
+/// points at a supply that can't take its inrush
+pub fn check_reset() {
+    let reason = esp_hal::system::reset_reason();
+    RESET_REASON.lock(|current| {
+        let mut current = current.borrow_mut();
+        current.clear();
+        let _ = match reason {
+            Some(reason) => write!(current, "{reason:?}"),
+            None => write!(current, "Unknown"),
+        };
+    });
+
+    let mut buffer = [0u8; RECORD_SIZE];
+    let mut record = match storage::read(Slot::Power, &mut buffer) {
+        Ok(Some(len)) => PowerRecord::from_bytes(&buffer[..len]).unwrap_or(PowerRecord::new()),
+        Ok(None) => PowerRecord::new(),
+        Err(e) => {
+            warn!("RLAY: Failed to read the power record: {e}");
+            PowerRecord::new()
+        }
+    };
+    RECORD.lock(|current| current.set(record));
+
+    let brownout = reason == Some(SocResetReason::SysBrownOut);
+    if brownout {
+        record.brownout_resets += 1;
+        if record.armed {
+            record.relay_brownouts += 1;
+            warn!(
+                "RLAY: Brownout reset while energizing the relay, {} of {} brownouts, the supply can't take the coil's inrush",
+                record.relay_brownouts, record.brownout_resets
+            );
+        } else {
+            warn!(
+                "RLAY: Brownout reset, {} since the power record was cleared",
+                record.brownout_resets
+            );
+        }
+    }
+    if brownout || record.armed {
+        record.armed = false;
+        save(record);
+    }
+}
+
+/// Whether the relay is kept open by a safety input, a switch failure or a demand response
+fn held_open() -> bool {
+    INHIBITED.load(Ordering::Relaxed)
+        || SWITCH_FAILED.load(Ordering::Relaxed)
+        || SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Drive the relay, it stays open while held open
+fn drive(on: bool) {
+    let on = on && !held_open();
+    if COMMANDED.swap(on, Ordering::Relaxed) != on {
+        LAST_SWITCH.lock(|last| last.set(Some(Instant::now())));
+        sequence::record(Transition::Relay(on));
+    }
+    if !on {
+        OPEN_DEADLINE.lock(|deadline| deadline.set(None));
+    }
+    RELAY.lock(|relay| {
+        if let Some(relay) = relay.borrow_mut().as_mut() {
+            relay.set_level(on);
+        }
+    });
+    io_state::record_relay(on);
+}
+
+fn read_back() -> Result<bool, &'static str> {
+    RELAY.lock(|relay| {
+        relay
+            .borrow_mut()
+            .as_mut()
+            .ok_or("Relay not installed")?
+            .read_back()
+    })
+}
+
+/// Open the relay right away, bypassing the state machine, and keep it open until released
+pub fn open_now() {
+    INHIBITED.store(true, Ordering::Relaxed);
+    drive(false);
+}
+
+/// Allow the relay to be energized again once the safety input is reset
+pub fn release() {
+    INHIBITED.store(false, Ordering::Relaxed);
+}
+
+/// Expect the relay to be opened by the relay task, called by the state machine before it
+/// publishes a state that removes power. The deadline monitor cuts the relay itself when it's
+/// still on after the deadline
+pub fn expect_open() {
+    if !COMMANDED.load(Ordering::Relaxed) && !SWITCH_PENDING.load(Ordering::Relaxed) {
+        return;
+    }
+    let deadline =
+        Instant::now() + Duration::from_millis(u64::from(OPEN_DEADLINE_MS.load(Ordering::Relaxed)));
+    OPEN_DEADLINE.lock(|current| current.set(Some(deadline)));
+    OPEN_EXPECTED.signal(deadline);
+}
+
+/// Suspend charging for a demand response or resume it, a session that is still charging gets
+/// the relay energized again in stages
+pub async fn set_suspended(suspended: bool) {
+    if SUSPENDED.swap(suspended, Ordering::Relaxed) == suspended {
+        return;
+    }
+    if suspended {
+        info!("RLAY: Suspended, opening the relay");
+        drive(false);
+    } else if WANTED.load(Ordering::Relaxed) {
+        energize().await;
+    }
+}
+
+/// Whether charging is suspended for a demand response
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Time left until the relay has been quiet for the given time, None once it has. A switch that
+/// is about to happen counts as one now
+pub fn quiet_remaining(quiet: Duration) -> Option<Duration> {
+    if SWITCH_PENDING.load(Ordering::Relaxed) {
+        return Some(Duration::from_millis(PREWARN_MS) + quiet);
+    }
+    let elapsed = LAST_SWITCH.lock(|last| last.get())?.elapsed();
+    (elapsed < quiet).then(|| quiet - elapsed)
+}
+
+/// Whether the contactor didn't follow the relay, charging is blocked until cleared
+pub fn switch_failed() -> bool {
+    SWITCH_FAILED.load(Ordering::Relaxed)
+}
+
+/// Clear a switch failure, refused while the contactor still doesn't follow the relay
+/// Returns true if a switch failure was cleared
+pub fn clear_switch_failure() -> bool {
+    if !switch_failed() {
+        return false;
+    }
+    if FEEDBACK_MISMATCH.load(Ordering::Relaxed) {
+        warn!("RLAY: Contactor still doesn't follow the relay, not clearing the switch failure");
+        return false;
+    }
+    SWITCH_FAILED.store(false, Ordering::Relaxed);
+    info!("RLAY: Switch failure cleared");
+    true
+}
+
+/// Energize the relay in stages: warn, hold the WiFi publishes, energize, let the supply settle
+/// and verify the output. A reset in between is recognized at boot by the armed power record
+async fn energize() {
+    if held_open() {
+        warn!("RLAY: Relay held open by a safety input, a switch failure or a demand response, not energizing");
+        return;
+    }
+    ACTUATIONS.fetch_add(1, Ordering::Relaxed);
+    info!("RLAY: Energizing the relay");
+
+    save(PowerRecord {
+        armed: true,
+        ..RECORD.lock(|record| record.get())
+    });
+    SWITCH_PENDING.store(true, Ordering::Relaxed);
+    mqtt::hold_tx(Duration::from_millis(TX_HOLD_MS));
+    Timer::after(Duration::from_millis(PREWARN_MS)).await;
+
+    drive(true);
+    SWITCH_PENDING.store(false, Ordering::Relaxed);
+    Timer::after(Duration::from_millis(SETTLE_MS)).await;
+
+    match read_back() {
+        // Opened by a safety input while settling
+        _ if held_open() => {}
+        Ok(true) => info!("RLAY: Relay on"),
+        Ok(false) => {
+            VERIFY_FAILURES.fetch_add(1, Ordering::Relaxed);
+            warn!("RLAY: Relay reads back off after energizing, setting it again");
+            drive(true);
+        }
+        Err(e) => {
+            VERIFY_FAILURES.fetch_add(1, Ordering::Relaxed);
+            warn!("RLAY: Failed to verify the relay: {e}");
+        }
+    }
+
+    save(PowerRecord {
+        armed: false,
+        ..RECORD.lock(|record| record.get())
+    });
+}
+
+/// Task to control the charger relay based on the charging state
+#[embassy_executor::task]
+pub async fn relay_task(relay: MappedOutput) {
+    info!("TASK: Started Charger relay control");
+
+    let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
+
+    RELAY.lock(|current| *current.borrow_mut() = Some(relay));
+    drive(false);
+    info!("RLAY: Initial state set to low (off)");
+
+    loop {
+        // Wait for state changes via PubSub
+        if let WaitResult::Message((current_state, output_events)) = subscriber.next_message().await
+        {
+            match current_state {
+                ChargerState::Charging if output_events.contains(&OutputEvent::ApplyPower) => {
+                    WANTED.store(true, Ordering::Relaxed);
+                    energize().await;
+                }
+                _ => {
+                    info!("RLAY: Setting relay low (off)");
+                    WANTED.store(false, Ordering::Relaxed);
+                    drive(false);
+                }
+            }
+        }
+    }
+}
+
+/// Task to compare the auxiliary contact of the contactor, closed to ground while the contactor
+/// is, with the relay. A contactor that stays closed with the relay off is welded, one that stays
+/// open with the relay on failed to close. Either opens the relay and faults the charger with a
+/// PowerSwitchFailure until it's cleared with the master card or ChangeAvailability Operative
+#[embassy_executor::task]
+pub async fn relay_feedback_task(mut feedback: MappedInput) {
+    info!("TASK: Started Contactor Feedback Monitor");
+
+    let mut mismatch_since: Option<Instant> = None;
+    let mut was_closed = feedback.is_low();
+
+    loop {
+        Timer::after(Duration::from_millis(FEEDBACK_POLL_MS)).await;
+
+        let closed = feedback.is_low();
+        if closed != was_closed {
+            was_closed = closed;
+            sequence::record(Transition::Contactor(closed));
+        }
+        let commanded = COMMANDED.load(Ordering::Relaxed);
+        if closed == commanded {
+            mismatch_since = None;
+            FEEDBACK_MISMATCH.store(false, Ordering::Relaxed);
+            continue;
+        }
+        let since = *mismatch_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < Duration::from_millis(FEEDBACK_MISMATCH_MS) {
+            continue;
+        }
+        FEEDBACK_MISMATCH.store(true, Ordering::Relaxed);
+
+        if SWITCH_FAILED.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        drive(false);
+        SWITCH_FAILURES.fetch_add(1, Ordering::Relaxed);
+        if closed {
+            warn!("RLAY: Contactor closed with the relay off, it may be welded");
+        } else {
+            warn!("RLAY: Contactor open with the relay on, it failed to close");
+        }
+        charger::STATE_IN_CHANNEL
+            .send(InputEvent::PowerSwitchFailure)
+            .await;
+    }
+}
+
+/// Task to watch the deadline of the relay opening after power is removed. A relay task that
+/// didn't get to it in time, e.g. stuck energizing or behind on its subscription, would leave
+/// the power on: the relay is cut here without the state machine and the charger is faulted with
+/// a PowerSwitchFailure, latched until it's cleared like one of the contactor
+#[embassy_executor::task]
+pub async fn relay_deadline_task(deadline_ms: u16) {
+    info!("TASK: Started Relay Deadline Monitor, {deadline_ms}ms to open");
+    OPEN_DEADLINE_MS.store(u32::from(deadline_ms), Ordering::Relaxed);
+
+    loop {
+        let deadline = OPEN_EXPECTED.wait().await;
+        Timer::at(deadline).await;
+
+        // Opened in time, or armed again with a later deadline that is signaled
+        let missed = OPEN_DEADLINE.lock(|current| {
+            current
+                .get()
+                .is_some_and(|deadline| deadline <= Instant::now())
+        });
+        if !missed {
+            continue;
+        }
+
+        SWITCH_FAILED.store(true, Ordering::Relaxed);
+        drive(false);
+        DEADLINE_MISSES.fetch_add(1, Ordering::Relaxed);
+        error!("RLAY: Relay still on {deadline_ms}ms after power was removed, cut it");
+        if charger::STATE_IN_CHANNEL
+            .try_send(InputEvent::PowerSwitchFailure)
+            .is_err()
+        {
+            warn!("RLAY: State machine input full, the switch failure is latched without it");
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use core::{cell::RefCell, fmt::Write};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    charger::{self, Charger, ChargerState, InputEvent},
+    info, ntp,
+    ocpp::{self, CallErrorCode, CallResponse},
+    storage::{self, Slot},
+    warn,
+};
+
+const EXPIRY_CHECK_SECS: u64 = 10;
+
+/// A connector reservation for an id tag until the expiry date
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    pub reservation_id: i32,
+    pub id_tag: heapless::String<20>,
+    pub expiry: u32, // Unix time
+}
+
+impl Reservation {
+    /// Serialized as reservation id and expiry (little endian) followed by the id tag
+    fn to_bytes(&self) -> heapless::Vec<u8, 28> {
+        let mut bytes = heapless::Vec::new();
+        let _ = bytes.extend_from_slice(&self.reservation_id.to_le_bytes());
+        let _ = bytes.extend_from_slice(&self.expiry.to_le_bytes());
+        let _ = bytes.extend_from_slice(self.id_tag.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let mut id_tag = heapless::String::new();
+        id_tag
+            .push_str(core::str::from_utf8(&bytes[8..]).ok()?)
+            .ok()?;
+        Some(Self {
+            reservation_id: i32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            expiry: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            id_tag,
+        })
+    }
+
+    fn is_expired(&self) -> bool {
+        // Without a synced clock the reservation is kept until the time is known
+        let now = ntp::get_current_unix_time();
+        now != 0 && now >= u64::from(self.expiry)
+    }
+}
+
+static RESERVATION: Mutex<CriticalSectionRawMutex, RefCell<Option<Reservation>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Store the reservation in RAM and flash, so it survives a restart
+fn set(reservation: Option<Reservation>) {
+    let result = match &reservation {
+        Some(reservation) => storage::write(Slot::Reservation, &reservation.to_bytes()),
+        None => storage::erase(Slot::Reservation),
+    };
+    if let Err(e) = result {
+        warn!("RSRV: Failed to persist reservation: {e}");
+    }
+    RESERVATION.lock(|current| *current.borrow_mut() = reservation);
+}
+
+/// Restore the reservation stored before a restart, returns true if there is one
+pub fn load() -> bool {
+    let mut buffer = [0u8; 28];
+    let reservation = match storage::read(Slot::Reservation, &mut buffer) {
+        Ok(Some(len)) => Reservation::from_bytes(&buffer[..len]),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("RSRV: Failed to read reservation: {e}");
+            None
+        }
+    };
+
+    match reservation {
+        Some(reservation) => {
+            info!(
+                "RSRV: Restored reservation {} for {}",
+                reservation.reservation_id, reservation.id_tag
+            );
+            RESERVATION.lock(|current| *current.borrow_mut() = Some(reservation));
+            true
+        }
+        None => false,
+    }
+}
+
+/// The active reservation, if any
+pub fn active() -> Option<Reservation> {
+    RESERVATION.lock(|current| current.borrow().clone())
+}
+
+/// Whether an id tag may charge, a reserved connector only accepts the reserved id tag
+pub fn accepts(id_tag: &str) -> bool {
+    active().is_none_or(|reservation| reservation.id_tag.eq_ignore_ascii_case(id_tag))
+}
+
+/// End the reservation when its id tag starts a transaction, returns the reservation id
+pub fn consume(id_tag: &str) -> Option<i32> {
+    let reservation = active().filter(|r| r.id_tag.eq_ignore_ascii_case(id_tag))?;
+    info!(
+        "RSRV: Reservation {} used by {id_tag}",
+        reservation.reservation_id
+    );
+    set(None);
+    Some(reservation.reservation_id)
+}
+
+fn status_response(status: &str) -> CallResponse {
+    let mut response = heapless::String::new();
+    let _ = write!(response, "{{\"status\":\"{status}\"}}");
+    Ok(response)
+}
+
+/// Handle a ReserveNow Call
+pub async fn handle_reserve_now(charger: &Charger, payload: &str) -> CallResponse {
+    let (Some(reservation_id), Some(id_tag), Some(expiry_date)) = (
+        ocpp::json_integer_field(payload, "reservationId"),
+        ocpp::json_string_field(payload, "idTag"),
+        ocpp::json_string_field(payload, "expiryDate"),
+    ) else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "ReserveNow requires a reservationId, idTag and expiryDate",
+        ));
+    };
+
+    let Some(connector_id) = ocpp::json_integer_field(payload, "connectorId") else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "ReserveNow requires a connectorId",
+        ));
+    };
+
+    let (Ok(reservation_id), Some(id_tag), Some(expiry)) = (
+        i32::try_from(reservation_id),
+        ocpp::json_unescape::<20>(id_tag),
+        chrono::DateTime::parse_from_rfc3339(expiry_date)
+            .ok()
+            .and_then(|expiry| u32::try_from(expiry.timestamp()).ok()),
+    ) else {
+        return Err((
+            CallErrorCode::PropertyConstraintViolation,
+            "Invalid reservationId, idTag or expiryDate",
+        ));
+    };
+
+    // This charger has a single connector, 0 reserves the charger as a whole
+    if connector_id > 1 {
+        return status_response("Rejected");
+    }
+
+    let reservation = Reservation {
+        reservation_id,
+        id_tag,
+        expiry,
+    };
+    if reservation.is_expired() {
+        warn!("RSRV: Reservation {reservation_id} already expired");
+        return status_response("Rejected");
+    }
+
+    // A reservation with the same id replaces the existing one
+    let replaces = active().is_some_and(|r| r.reservation_id == reservation_id);
+    let status = match charger.get_state().await {
+        ChargerState::Faulted => "Faulted",
+        ChargerState::Unavailable | ChargerState::Off => "Unavailable",
+        ChargerState::Reserved if replaces => "Accepted",
+        ChargerState::Available => "Accepted",
+        _ => "Occupied",
+    };
+
+    if status == "Accepted" {
+        info!(
+            "RSRV: Reserved for {} until {expiry_date}",
+            reservation.id_tag
+        );
+        set(Some(reservation));
+        if !replaces {
+            let _ = charger::STATE_IN_CHANNEL.try_send(InputEvent::Reserve);
+        }
+    } else {
+        info!("RSRV: Reservation {reservation_id} not accepted: {status}");
+    }
+    status_response(status)
+}
+
+/// Handle a CancelReservation Call
+pub fn handle_cancel_reservation(payload: &str) -> CallResponse {
+    let Some(reservation_id) = ocpp::json_integer_field(payload, "reservationId") else {
+        return Err((
+            CallErrorCode::OccurrenceConstraintViolation,
+            "CancelReservation requires a reservationId",
+        ));
+    };
+
+    if active().is_some_and(|r| i64::from(r.reservation_id) == reservation_id) {
+        info!("RSRV: Reservation {reservation_id} cancelled");
+        set(None);
+        let _ = charger::STATE_IN_CHANNEL.try_send(InputEvent::ReservationEnded);
+        status_response("Accepted")
+    } else {
+        warn!("RSRV: No reservation {reservation_id} to cancel");
+        status_response("Rejected")
+    }
+}
+
+/// Task to end reservations when they expire
+#[embassy_executor::task]
+pub async fn reservation_expiry_task() {
+    info!("TASK: Started Reservation Expiry Handler");
+
+    loop {
+        Timer::after(Duration::from_secs(EXPIRY_CHECK_SECS)).await;
+
+        if let Some(reservation) = active().filter(Reservation::is_expired) {
+            info!("RSRV: Reservation {} expired", reservation.reservation_id);
+            set(None);
+            charger::STATE_IN_CHANNEL
+                .send(InputEvent::ReservationEnded)
+                .await;
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::spi::SpiDevice;
+use embedded_hal_bus::spi::RefCellDevice as SpiRefCellDevice;
+use esp_hal::{delay::Delay, spi::master::Spi, Blocking};
+use mfrc522::{comm::blocking::spi::SpiInterface, AtqA, Initialized, Mfrc522, RxGain};
+
+use crate::{
+    charger::{self, Charger, InputEvent},
+    info, io_state,
+    pins::SharedOutput,
+    relay, utils, warn,
+};
+
+static POLLS: AtomicU32 = AtomicU32::new(0);
+static DEFERRED: AtomicU32 = AtomicU32::new(0);
+static DETECTIONS: AtomicU32 = AtomicU32::new(0);
+static READS: AtomicU32 = AtomicU32::new(0);
+static MISREADS: AtomicU32 = AtomicU32::new(0);
+static LOSSES: AtomicU32 = AtomicU32::new(0);
+/// Set while the configured reader doesn't answer, a charger without a reader never sets it
+static LOST: AtomicBool = AtomicBool::new(false);
+
+/// Signaled when the reader is lost or answers again, the status notification reports it
+pub static READER_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Set while the simulated reader is in use, only then the swipe command is accepted
+static SIMULATED: AtomicBool = AtomicBool::new(false);
+/// The card of a swipe command, until the simulated reader picks it up
+static SIMULATED_CARD: Mutex<CriticalSectionRawMutex, RefCell<Option<Uid>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Version register of the MFRC522, 0x91 or 0x92 for a genuine chip, 0x88 or 0x12 for a clone
+const MFRC522_VERSION_REG: u8 = 0x37;
+/// Command register of the MFRC522
+const MFRC522_COMMAND_REG: u8 = 0x01;
+/// PowerDown bit of the command register, with the Idle command
+const MFRC522_POWER_DOWN: u8 = 0x10;
+
+/// UID of a card, 4, 7 or 10 bytes
+pub type Uid = heapless::Vec<u8, 10>;
+
+/// Card readers there is a driver for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReaderKind {
+    /// NXP MFRC522, on the SPI bus shared with the display
+    Mfrc522,
+    /// No hardware, cards are presented with the swipe command
+    Simulated,
+}
+
+impl ReaderKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "mfrc522" => Some(Self::Mfrc522),
+            "simulated" => Some(Self::Simulated),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mfrc522 => "MFRC522",
+            Self::Simulated => "simulated",
+        }
+    }
+}
+
+/// A card reader, for the swipe task to poll without knowing the chip or the bus
+pub trait CardReader {
+    fn kind(&self) -> ReaderKind;
+    /// Bring the reader up, also after it was lost or powered down
+    fn init(&mut self) -> Result<(), &'static str>;
+    /// Whether a card answers in the field
+    fn poll(&mut self) -> Result<bool, &'static str>;
+    /// UID of the card that answered the last poll
+    fn read_uid(&mut self) -> Result<Uid, &'static str>;
+    /// Whether the reader still answers on its bus, cheap enough to call every few seconds
+    fn probe(&mut self) -> bool;
+    /// Put the reader in its low power mode until the next init
+    fn power_down(&mut self) -> Result<(), &'static str>;
+}
+
+type ReaderSpi = SpiRefCellDevice<'static, Spi<'static, Blocking>, SharedOutput, Delay>;
+
+/// MFRC522 on the shared SPI bus. The driver is built again on every init, so a reader that was
+/// unplugged and plugged back in gets its registers set up again
+pub struct Mfrc522Reader {
+    bus: &'static RefCell<Spi<'static, Blocking>>,
+    cs: SharedOutput,
+    gain_db: u16,
+    driver: Option<Mfrc522<SpiInterface<ReaderSpi>, Initialized>>,
+    atqa: Option<AtqA>,
+}
+
+impl Mfrc522Reader {
+    pub fn new(
+        bus: &'static RefCell<Spi<'static, Blocking>>,
+        cs: SharedOutput,
+        gain_db: u16,
+    ) -> Self {
+        Self {
+            bus,
+            cs,
+            gain_db,
+            driver: None,
+            atqa: None,
+        }
+    }
+
+    fn device(&self) -> Result<ReaderSpi, &'static str> {
+        SpiRefCellDevice::new(self.bus, self.cs, Delay::new())
+            .map_err(|_| "Failed to set up the MFRC522 chip select")
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8, &'static str> {
+        let mut buffer = [0x80 | (register << 1), 0];
+        self.device()?
+            .transfer_in_place(&mut buffer)
+            .map_err(|_| "MFRC522 SPI error")?;
+        Ok(buffer[1])
+    }
+
+    fn write_register(&self, register: u8, value: u8) -> Result<(), &'static str> {
+        self.device()?
+            .write(&[register << 1, value])
+            .map_err(|_| "MFRC522 SPI error")
+    }
+}
+
+impl CardReader for Mfrc522Reader {
+    fn kind(&self) -> ReaderKind {
+        ReaderKind::Mfrc522
+    }
+
+    fn init(&mut self) -> Result<(), &'static str> {
+        self.driver = None;
+        self.atqa = None;
+        // The driver waits for the soft reset without a timeout, which never ends without a chip
+        if !self.probe() {
+            return Err("MFRC522 doesn't answer");
+        }
+        let mut driver = Mfrc522::new(SpiInterface::new(self.device()?))
+            .init()
+            .map_err(|_| "Failed to initialize the MFRC522")?;
+        if let Err(e) = driver.set_antenna_gain(rx_gain(self.gain_db)) {
+            warn!("RFID: Failed to set the receiver gain: {e:?}");
+        }
+        self.driver = Some(driver);
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<bool, &'static str> {
+        let driver = self.driver.as_mut().ok_or("MFRC522 isn't initialized")?;
+        // No answer is the same as no card, a lost reader shows in the probe
+        self.atqa = driver.reqa().ok();
+        Ok(self.atqa.is_some())
+    }
+
+    fn read_uid(&mut self) -> Result<Uid, &'static str> {
+        let driver = self.driver.as_mut().ok_or("MFRC522 isn't initialized")?;
+        let atqa = self.atqa.take().ok_or("No card answered")?;
+        let uid = driver
+            .select(&atqa)
+            .map_err(|_| "Failed to select the card")?;
+        Uid::from_slice(uid.as_bytes()).map_err(|_| "UID is too long")
+    }
+
+    fn probe(&mut self) -> bool {
+        // A missing chip reads as all zeroes or all ones, depending on the bus
+        match self.read_register(MFRC522_VERSION_REG) {
+            Ok(version) => version != 0x00 && version != 0xFF,
+            Err(_) => false,
+        }
+    }
+
+    fn power_down(&mut self) -> Result<(), &'static str> {
+        self.driver = None;
+        self.atqa = None;
+        self.write_register(MFRC522_COMMAND_REG, MFRC522_POWER_DOWN)
+    }
+}
+
+/// A reader without hardware, to run the authorization flow on a bench or in a demo
+pub struct SimulatedReader {
+    card: Option<Uid>,
+}
+
+impl SimulatedReader {
+    pub fn new() -> Self {
+        SIMULATED.store(true, Ordering::Relaxed);
+        Self { card: None }
+    }
+}
+
+impl Default for SimulatedReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CardReader for SimulatedReader {
+    fn kind(&self) -> ReaderKind {
+        ReaderKind::Simulated
+    }
+
+    fn init(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<bool, &'static str> {
+        self.card = SIMULATED_CARD.lock(|card| card.borrow_mut().take());
+        Ok(self.card.is_some())
+    }
+
+    fn read_uid(&mut self) -> Result<Uid, &'static str> {
+        self.card.take().ok_or("No card answered")
+    }
+
+    fn probe(&mut self) -> bool {
+        true
+    }
+
+    fn power_down(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// Present a card to the simulated reader, by its UID in hex
+pub fn simulate_swipe(uid: &str) -> Result<(), &'static str> {
+    if !SIMULATED.load(Ordering::Relaxed) {
+        return Err("The card reader isn't simulated");
+    }
+    let uid = utils::hex_string_to_bytes::<10>(uid)
+        .filter(|uid| !uid.is_empty())
+        .ok_or("UID must be up to 10 bytes in hex")?;
+    SIMULATED_CARD.lock(|card| card.replace(Some(uid)));
+    Ok(())
+}
+
+/// Whether the configured card reader stopped answering, swipes aren't seen until it's back
+pub fn reader_lost() -> bool {
+    LOST.load(Ordering::Relaxed)
+}
+
+fn set_lost(lost: bool) {
+    if LOST.swap(lost, Ordering::Relaxed) != lost {
+        READER_CHANGED.signal(());
+    }
+    io_state::record_rfid(!lost, false);
+}
+
+/// Polls of the reader and their outcome since boot, to verify the reads in the field
+pub struct ReadQuality {
+    pub polls: u32,
+    /// Polls held back until the relay was quiet
+    pub deferred: u32,
+    /// Polls a card answered
+    pub detections: u32,
+    /// Detected cards of which the UID was read
+    pub reads: u32,
+    /// Detected cards of which the UID couldn't be read, a disturbed field shows here first
+    pub misreads: u32,
+    /// Whether the reader doesn't answer at the moment
+    pub lost: bool,
+    /// Times the reader stopped answering since boot, a loose connector shows here
+    pub losses: u32,
+}
+
+impl ReadQuality {
+    /// Detected cards that were read, None before a card was detected
+    pub fn percent(&self) -> Option<u32> {
+        (self.detections > 0).then(|| self.reads * 100 / self.detections)
+    }
+}
+
+pub fn read_quality() -> ReadQuality {
+    ReadQuality {
+        polls: POLLS.load(Ordering::Relaxed),
+        deferred: DEFERRED.load(Ordering::Relaxed),
+        detections: DETECTIONS.load(Ordering::Relaxed),
+        reads: READS.load(Ordering::Relaxed),
+        misreads: MISREADS.load(Ordering::Relaxed),
+        lost: LOST.load(Ordering::Relaxed),
+        losses: LOSSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Receiver gain of the reader for a gain in dB, the nearest step below it
+pub fn rx_gain(db: u16) -> RxGain {
+    match db {
+        0..=22 => RxGain::DB18,
+        23..=32 => RxGain::DB23,
+        33..=37 => RxGain::DB33,
+        38..=42 => RxGain::DB38,
+        43..=47 => RxGain::DB43,
+        _ => RxGain::DB48,
+    }
+}
+
+/// Wait for a read window, until the relay hasn't switched for the quiet time. The contactor's
+/// coil and arc disturb the reader's field while it switches
+pub async fn wait_read_window(quiet: Duration) {
+    let mut deferred = false;
+    while let Some(remaining) = relay::quiet_remaining(quiet) {
+        deferred = true;
+        Timer::after(remaining).await;
+    }
+    if deferred {
+        DEFERRED.fetch_add(1, Ordering::Relaxed);
+    }
+    POLLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a card answering a poll
+pub fn record_detection() {
+    DETECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record whether the UID of a detected card was read
+pub fn record_read(read: bool) {
+    if read {
+        READS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        MISREADS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Task to poll the card reader for swipes. The reader is probed every probe interval, a lost
+/// reader is reported and brought up again once it answers
+#[embassy_executor::task]
+pub async fn card_swipe_task(
+    reader: &'static mut dyn CardReader,
+    charger: &'static Charger,
+    quiet: Duration,
+    probe_interval: Duration,
+) {
+    info!(
+        "TASK: Started Card Swipe Detector ({})",
+        reader.kind().as_str()
+    );
+
+    if let Err(e) = reader.init() {
+        warn!("RFID: {e}, waiting for the card reader");
+        let _ = reader.power_down();
+        set_lost(true);
+    } else {
+        set_lost(false);
+    }
+    let mut probed_at = Instant::now();
+
+    loop {
+        if reader_lost() {
+            Timer::after(probe_interval).await;
+            if !reader.probe() {
+                continue;
+            }
+            match reader.init() {
+                Ok(()) => {
+                    info!("RFID: Card reader {} is back", reader.kind().as_str());
+                    set_lost(false);
+                    probed_at = Instant::now();
+                }
+                Err(e) => {
+                    warn!("RFID: {e}");
+                    let _ = reader.power_down();
+                }
+            }
+            continue;
+        }
+
+        if probed_at.elapsed() >= probe_interval {
+            probed_at = Instant::now();
+            if !reader.probe() {
+                warn!("RFID: Card reader {} lost", reader.kind().as_str());
+                LOSSES.fetch_add(1, Ordering::Relaxed);
+                let _ = reader.power_down();
+                set_lost(true);
+                continue;
+            }
+        }
+
+        wait_read_window(quiet).await;
+        let detected = reader.poll().unwrap_or(false);
+        io_state::record_rfid(true, detected);
+        if detected {
+            info!("RFID: Card swipe detected");
+            record_detection();
+            Timer::after(Duration::from_millis(50)).await;
+            wait_read_window(quiet).await;
+            let uid = reader.read_uid();
+            record_read(uid.is_ok());
+            match uid {
+                Ok(uid) => {
+                    let hex = utils::bytes_to_hex_string::<24>(&uid);
+                    info!("RFID: UID {hex}");
+
+                    let event = charger.card_swiped(&hex).await;
+                    if event != InputEvent::None {
+                        charger::STATE_IN_CHANNEL.send(event).await;
+                    }
+                    Timer::after(Duration::from_millis(500)).await;
+                }
+                Err(e) => warn!("RFID: Failed to read the card: {e}"),
+            }
+        }
+
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
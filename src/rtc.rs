@@ -0,0 +1,173 @@
+use chrono::{Datelike, NaiveDate, Timelike};
+
+use crate::{info, warn};
+
+const DS3231_ADDR: u8 = 0x68;
+const PCF8563_ADDR: u8 = 0x51;
+
+/// DS3231 status register, the oscillator stop flag is set when the time was lost
+const DS3231_STATUS: u8 = 0x0F;
+const DS3231_OSF: u8 = 0x80;
+/// PCF8563 seconds register, the voltage low flag is set when the time was lost
+const PCF8563_SECONDS: u8 = 0x02;
+const PCF8563_VL: u8 = 0x80;
+
+/// Supported RTC chips
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    Ds3231,
+    Pcf8563,
+}
+
+impl Chip {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ds3231 => "DS3231",
+            Self::Pcf8563 => "PCF8563",
+        }
+    }
+
+    fn address(&self) -> u8 {
+        match self {
+            Self::Ds3231 => DS3231_ADDR,
+            Self::Pcf8563 => PCF8563_ADDR,
+        }
+    }
+
+    /// First of the seconds, minutes, hours, day, weekday, month and year registers, the
+    /// chips only differ in the order of the day and the weekday
+    fn time_register(&self) -> u8 {
+        match self {
+            Self::Ds3231 => 0x00,
+            Self::Pcf8563 => PCF8563_SECONDS,
+        }
+    }
+}
+
+/// Battery backed real time clock on the I2C bus of the display, keeps the time while the
+/// charger is off so timestamps are valid before WiFi comes up
+pub struct Rtc<I2C> {
+    i2c: I2C,
+    chip: Chip,
+}
+
+impl<I2C> Rtc<I2C>
+where
+    I2C: embedded_hal::i2c::I2c,
+{
+    /// Look for a DS3231 or PCF8563 on the bus
+    pub fn detect(mut i2c: I2C) -> Result<Self, &'static str> {
+        for chip in [Chip::Ds3231, Chip::Pcf8563] {
+            let mut register = [0u8; 1];
+            if i2c
+                .write_read(chip.address(), &[chip.time_register()], &mut register)
+                .is_ok()
+            {
+                info!(
+                    "RTC : Found a {} at 0x{:02X}",
+                    chip.as_str(),
+                    chip.address()
+                );
+                return Ok(Self { i2c, chip });
+            }
+        }
+        Err("No RTC found")
+    }
+
+    /// Read the time as a Unix timestamp, fails when the chip lost the time
+    pub fn read(&mut self) -> Result<u64, &'static str> {
+        let mut regs = [0u8; 7];
+        self.i2c
+            .write_read(self.chip.address(), &[self.chip.time_register()], &mut regs)
+            .map_err(|_| "Failed to read the RTC")?;
+
+        let (day, month) = match self.chip {
+            Chip::Ds3231 => {
+                let mut status = [0u8; 1];
+                self.i2c
+                    .write_read(DS3231_ADDR, &[DS3231_STATUS], &mut status)
+                    .map_err(|_| "Failed to read the RTC status")?;
+                if status[0] & DS3231_OSF != 0 {
+                    return Err("RTC oscillator stopped, the time was lost");
+                }
+                (regs[4], regs[5])
+            }
+            Chip::Pcf8563 => {
+                if regs[0] & PCF8563_VL != 0 {
+                    return Err("RTC voltage low, the time was lost");
+                }
+                (regs[3], regs[5])
+            }
+        };
+
+        NaiveDate::from_ymd_opt(
+            2000 + from_bcd(regs[6]) as i32,
+            from_bcd(month & 0x1F),
+            from_bcd(day & 0x3F),
+        )
+        .and_then(|date| {
+            date.and_hms_opt(
+                from_bcd(regs[2] & 0x3F),
+                from_bcd(regs[1] & 0x7F),
+                from_bcd(regs[0] & 0x7F),
+            )
+        })
+        .and_then(|time| u64::try_from(time.and_utc().timestamp()).ok())
+        .ok_or("Invalid RTC time")
+    }
+
+    /// Set the time from a Unix timestamp, in 24 hour mode
+    pub fn write(&mut self, unix_timestamp: u64) -> Result<(), &'static str> {
+        let time = i64::try_from(unix_timestamp)
+            .ok()
+            .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+            .ok_or("Invalid time")?
+            .naive_utc();
+        let weekday = time.weekday().num_days_from_sunday() as u8;
+        let day = to_bcd(time.day());
+        let (fourth, fifth) = match self.chip {
+            Chip::Ds3231 => (weekday + 1, day),
+            Chip::Pcf8563 => (day, weekday),
+        };
+        let data = [
+            self.chip.time_register(),
+            to_bcd(time.second()), // Clears the PCF8563 voltage low flag
+            to_bcd(time.minute()),
+            to_bcd(time.hour()),
+            fourth,
+            fifth,
+            to_bcd(time.month()),
+            to_bcd(time.year().rem_euclid(100) as u32),
+        ];
+        self.i2c
+            .write(self.chip.address(), &data)
+            .map_err(|_| "Failed to write the RTC")?;
+
+        if self.chip == Chip::Ds3231 {
+            let mut status = [0u8; 1];
+            self.i2c
+                .write_read(DS3231_ADDR, &[DS3231_STATUS], &mut status)
+                .map_err(|_| "Failed to read the RTC status")?;
+            self.i2c
+                .write(DS3231_ADDR, &[DS3231_STATUS, status[0] & !DS3231_OSF])
+                .map_err(|_| "Failed to clear the RTC oscillator stop flag")?;
+        }
+        Ok(())
+    }
+
+    /// Write the time back after an NTP sync, logs instead of failing
+    pub fn store_time(&mut self, unix_timestamp: u64) {
+        match self.write(unix_timestamp) {
+            Ok(()) => info!("RTC : Stored the NTP time in the {}", self.chip.as_str()),
+            Err(e) => warn!("RTC : {e}"),
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u32 {
+    u32::from(value >> 4) * 10 + u32::from(value & 0x0F)
+}
+
+fn to_bcd(value: u32) -> u8 {
+    ((value / 10) << 4 | value % 10) as u8
+}
@@ -0,0 +1,197 @@
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    analog::adc::AdcPin,
+    peripherals::{ADC1, GPIO6},
+    tsens::TemperatureSensor,
+};
+
+use crate::{
+    charger::{self, InputEvent},
+    config::Config,
+    info, io_state,
+    pins::SharedAdc,
+    smart_charging::{CurrentLimits, LimitSource, MIN_CURRENT_AMPS},
+    warn,
+};
+
+const CHECK_INTERVAL_SECS: u64 = 2;
+/// Degrees the temperature has to drop below a threshold before the derating or shutdown is lifted
+const HYSTERESIS_C: f32 = 5.0;
+/// Full scale of the ADC at 11dB attenuation
+const ADC_FULL_SCALE_MV: i32 = 3100;
+const ADC_MAX: i32 = 4095;
+/// The NTC divider is fed from the 3V3 rail
+const SUPPLY_MV: i32 = 3300;
+const KELVIN: f32 = 273.15;
+/// Sentinel for a sensor without a reading
+const NO_READING: i32 = i32::MIN;
+
+/// Tenths of a degree, NO_READING without a sensor
+static CHIP_DECI: AtomicI32 = AtomicI32::new(NO_READING);
+static NTC_DECI: AtomicI32 = AtomicI32::new(NO_READING);
+/// Set above the critical temperature, until it dropped below it by the hysteresis
+static OVERHEATED: AtomicBool = AtomicBool::new(false);
+
+fn reading(deci: &AtomicI32) -> Option<f32> {
+    let deci = deci.load(Ordering::Relaxed);
+    (deci != NO_READING).then(|| deci as f32 / 10.0)
+}
+
+/// Chip temperature in °C
+pub fn chip_temperature() -> Option<f32> {
+    reading(&CHIP_DECI)
+}
+
+/// Temperature of the external NTC in °C
+pub fn ntc_temperature() -> Option<f32> {
+    reading(&NTC_DECI)
+}
+
+/// Whether charging is stopped for the temperature
+pub fn overheated() -> bool {
+    OVERHEATED.load(Ordering::Relaxed)
+}
+
+/// Natural logarithm, from the exponent and the atanh series of the mantissa
+fn ln(x: f32) -> f32 {
+    let bits = x.to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127;
+    let mantissa = f32::from_bits((bits & 0x007f_ffff) | 0x3f80_0000);
+    let z = (mantissa - 1.0) / (mantissa + 1.0);
+    let z2 = z * z;
+    let series = 2.0 * z * (1.0 + z2 * (1.0 / 3.0 + z2 * (1.0 / 5.0 + z2 / 7.0)));
+    exponent as f32 * core::f32::consts::LN_2 + series
+}
+
+/// Thresholds of the thermal protection and the NTC divider, an NTC to ground under a series
+/// resistor to 3V3
+#[derive(Debug, Clone, Copy)]
+pub struct Thermal {
+    /// Above this the current is derated, down to the minimum at the critical temperature
+    pub warn_c: u16,
+    /// Above this charging stops
+    pub critical_c: u16,
+    pub max_current: u16,
+    pub ntc_beta: u16,
+    /// Resistance of the NTC at 25°C
+    pub ntc_ohms: u16,
+    pub series_ohms: u16,
+}
+
+impl Thermal {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            warn_c: config.temperature_warn_c,
+            critical_c: config
+                .temperature_critical_c
+                .max(config.temperature_warn_c.saturating_add(1)),
+            max_current: config.max_current_amps,
+            ntc_beta: config.ntc_beta.max(1),
+            ntc_ohms: config.ntc_ohms.max(1),
+            series_ohms: config.ntc_series_ohms,
+        }
+    }
+
+    /// Temperature of the NTC for a raw ADC reading, None for an open or shorted NTC
+    fn ntc_celsius(&self, raw: u16) -> Option<f32> {
+        let mv = i32::from(raw).min(ADC_MAX) * ADC_FULL_SCALE_MV / ADC_MAX;
+        if mv <= 0 || mv >= SUPPLY_MV {
+            return None;
+        }
+        let ohms = f32::from(self.series_ohms) * mv as f32 / (SUPPLY_MV - mv) as f32;
+        let inverse_kelvin =
+            1.0 / (25.0 + KELVIN) + ln(ohms / f32::from(self.ntc_ohms)) / f32::from(self.ntc_beta);
+        Some(1.0 / inverse_kelvin - KELVIN)
+    }
+
+    /// Current for a temperature above the warn threshold, from the maximum at the warn
+    /// temperature to the minimum at the critical temperature
+    fn derated_amps(&self, celsius: f32) -> u16 {
+        let span = f32::from(self.critical_c - self.warn_c);
+        let fraction = ((celsius - f32::from(self.warn_c)) / span).clamp(0.0, 1.0);
+        let range = f32::from(self.max_current.saturating_sub(MIN_CURRENT_AMPS));
+        self.max_current - (range * fraction) as u16
+    }
+}
+
+/// Task to read the chip temperature sensor and the external NTC. Above the warn temperature
+/// the advertised current is derated, above the critical temperature charging stops with a
+/// HighTemperature fault until the hottest sensor cooled down
+#[embassy_executor::task]
+pub async fn sensors_task(
+    limits: &'static CurrentLimits,
+    chip_sensor: Option<TemperatureSensor<'static>>,
+    mut ntc: Option<(SharedAdc, AdcPin<GPIO6<'static>, ADC1<'static>>)>,
+    thermal: Thermal,
+) {
+    info!(
+        "TASK: Started Temperature Monitor, derating above {}°C, stopping above {}°C",
+        thermal.warn_c, thermal.critical_c
+    );
+
+    let mut derated: Option<u16> = None;
+
+    loop {
+        if let Some(sensor) = &chip_sensor {
+            let celsius = sensor.get_temperature().to_celsius();
+            io_state::record_temperature(celsius);
+            CHIP_DECI.store((celsius * 10.0) as i32, Ordering::Relaxed);
+        }
+        if let Some((adc, pin)) = &mut ntc {
+            let raw = adc.borrow_mut().read_oneshot(pin).ok();
+            match raw.map(|raw| thermal.ntc_celsius(raw)) {
+                Some(Some(celsius)) => NTC_DECI.store((celsius * 10.0) as i32, Ordering::Relaxed),
+                Some(None) => {
+                    if NTC_DECI.swap(NO_READING, Ordering::Relaxed) != NO_READING {
+                        warn!("TEMP: NTC reads open or shorted");
+                    }
+                }
+                // The conversion is still running, the next check reads it
+                None => {}
+            }
+        }
+
+        let hottest = match (chip_temperature(), ntc_temperature()) {
+            (Some(chip), Some(ntc)) => Some(chip.max(ntc)),
+            (chip, ntc) => chip.or(ntc),
+        };
+        if let Some(celsius) = hottest {
+            let warn_c = f32::from(thermal.warn_c);
+            let critical_c = f32::from(thermal.critical_c);
+
+            let amps = if celsius >= warn_c {
+                Some(thermal.derated_amps(celsius))
+            } else if derated.is_some() && celsius >= warn_c - HYSTERESIS_C {
+                derated
+            } else {
+                None
+            };
+            if amps != derated {
+                match amps {
+                    Some(amps) => {
+                        warn!("TEMP: {celsius:.1}°C, derating to {amps}A");
+                        limits.set_limit(LimitSource::Thermal, amps).await;
+                    }
+                    None => {
+                        info!("TEMP: {celsius:.1}°C, derating lifted");
+                        limits.clear_limit(LimitSource::Thermal).await;
+                    }
+                }
+                derated = amps;
+            }
+
+            if !overheated() && celsius >= critical_c {
+                warn!("TEMP: {celsius:.1}°C, stopping charging");
+                OVERHEATED.store(true, Ordering::Relaxed);
+                charger::STATE_IN_CHANNEL.send(InputEvent::Overheated).await;
+            } else if overheated() && celsius < critical_c - HYSTERESIS_C {
+                info!("TEMP: {celsius:.1}°C, cooled down");
+                OVERHEATED.store(false, Ordering::Relaxed);
+                charger::STATE_IN_CHANNEL.send(InputEvent::Cooled).await;
+            }
+        }
+
+        Timer::after(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+    }
+}
@@ -0,0 +1,106 @@
+use core::{cell::RefCell, fmt::Write};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+use crate::fault::Fault;
+
+/// Transitions kept, enough for the sessions below with a few retries each
+const EVENTS: usize = 96;
+/// Sessions whose transitions are reported, a session starts when a cable is connected
+pub const SESSIONS: u16 = 3;
+
+/// A transition of a signal on the power path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// The cable switch, true when a cable is connected
+    Cable(bool),
+    /// The cable lock output, true when driven locked
+    Lock(bool),
+    /// The relay output, true when driven on
+    Relay(bool),
+    /// The auxiliary contact of the contactor, true when closed
+    Contactor(bool),
+    /// The residual current device, true when tripped
+    GroundFault(bool),
+    /// The negative half of the pilot at the diode check, in millivolts
+    Pilot(i32),
+    /// A fault was raised
+    Fault(Fault),
+}
+
+impl Transition {
+    fn write(&self, out: &mut impl Write) -> core::fmt::Result {
+        let (signal, on, (on_label, off_label)) = match self {
+            Self::Cable(on) => ("cable", *on, ("connected", "disconnected")),
+            Self::Lock(on) => ("lock", *on, ("locked", "unlocked")),
+            Self::Relay(on) => ("relay", *on, ("on", "off")),
+            Self::Contactor(on) => ("contactor", *on, ("closed", "open")),
+            Self::GroundFault(on) => ("rcd", *on, ("tripped", "reset")),
+            Self::Pilot(mv) => return write!(out, "pilot {mv}mV"),
+            Self::Fault(fault) => return write!(out, "fault {}", fault.as_str()),
+        };
+        write!(out, "{signal} {}", if on { on_label } else { off_label })
+    }
+}
+
+/// A transition with the uptime it happened at, in milliseconds
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    uptime_ms: u64,
+    session: u16,
+    transition: Transition,
+}
+
+struct SequenceLog {
+    events: heapless::Deque<Event, EVENTS>,
+    /// Number of the current session, counted since boot
+    session: u16,
+}
+
+static LOG: Mutex<CriticalSectionRawMutex, RefCell<SequenceLog>> =
+    Mutex::new(RefCell::new(SequenceLog {
+        events: heapless::Deque::new(),
+        session: 0,
+    }));
+
+/// Record a transition of the power path, called by the drivers at the moment they switch. A
+/// connected cable starts the next session
+pub fn record(transition: Transition) {
+    let uptime_ms = Instant::now().as_millis();
+    LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        if transition == Transition::Cable(true) {
+            log.session = log.session.wrapping_add(1);
+        }
+        let event = Event {
+            uptime_ms,
+            session: log.session,
+            transition,
+        };
+        if log.events.is_full() {
+            log.events.pop_front();
+        }
+        let _ = log.events.push_back(event);
+    });
+}
+
+/// Write the transitions of the last sessions for the diagnostics report, one per line with the
+/// uptime in milliseconds, e.g. `12 5210.114 relay on`. Session 0 is before the first cable
+pub fn write_report(out: &mut impl Write) -> core::fmt::Result {
+    LOG.lock(|log| {
+        let log = log.borrow();
+        let first = log.session.saturating_sub(SESSIONS - 1);
+        for event in log.events.iter().filter(|event| event.session >= first) {
+            write!(
+                out,
+                "{} {}.{:03} ",
+                event.session,
+                event.uptime_ms / 1000,
+                event.uptime_ms % 1000
+            )?;
+            event.transition.write(out)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,185 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::{cell::Cell, fmt::Write};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{
+    display::{self, DisplayEvent},
+    info, memory, ntp,
+    storage::{self, Slot},
+    warn,
+};
+
+/// Size of a serialized session, all sessions share one storage slot
+const ENTRY_SIZE: usize = 48;
+/// Most sessions kept, the oldest is dropped when a new one is recorded
+pub const MAX_SESSIONS: usize = storage::MAX_RECORD_SIZE / ENTRY_SIZE;
+
+/// A finished charging session, kept in flash for billing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionRecord {
+    pub transaction_id: i32,
+    pub id_tag: heapless::String<20>,
+    pub started: u32, // Unix time, 0 when the clock wasn't synced
+    pub stopped: u32, // Unix time, 0 when the clock wasn't synced
+    pub duration_secs: u32,
+    pub meter_start: u32, // Wh
+    pub meter_stop: u32,  // Wh
+}
+
+impl SessionRecord {
+    pub fn energy_wh(&self) -> u32 {
+        self.meter_stop.saturating_sub(self.meter_start)
+    }
+
+    /// Serialized as the integers (little endian) followed by the id tag length and id tag
+    fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&self.transaction_id.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.started.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.stopped.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.duration_secs.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.meter_start.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.meter_stop.to_le_bytes());
+        let id_tag = self.id_tag.as_bytes();
+        bytes[24] = id_tag.len() as u8;
+        bytes[25..25 + id_tag.len()].copy_from_slice(id_tag);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let integer = |at: usize| bytes[at..at + 4].try_into().ok();
+        let len = usize::from(*bytes.get(24)?);
+        let mut id_tag = heapless::String::new();
+        id_tag
+            .push_str(core::str::from_utf8(bytes.get(25..25 + len)?).ok()?)
+            .ok()?;
+        Some(Self {
+            transaction_id: i32::from_le_bytes(integer(0)?),
+            started: u32::from_le_bytes(integer(4)?),
+            stopped: u32::from_le_bytes(integer(8)?),
+            duration_secs: u32::from_le_bytes(integer(12)?),
+            meter_start: u32::from_le_bytes(integer(16)?),
+            meter_stop: u32::from_le_bytes(integer(20)?),
+            id_tag,
+        })
+    }
+
+    /// The session as a CSV row, the id tag is quoted as it comes from the central system
+    pub fn csv_row(&self) -> heapless::String<160> {
+        let mut row = heapless::String::new();
+        let _ = write!(row, "{},\"", self.transaction_id);
+        for c in self.id_tag.chars() {
+            if c == '"' {
+                let _ = row.push('"');
+            }
+            let _ = row.push(c);
+        }
+        let _ = write!(
+            row,
+            "\",{},{},{},{},{},{}\r\n",
+            time_field(self.started),
+            time_field(self.stopped),
+            self.duration_secs,
+            self.meter_start,
+            self.meter_stop,
+            self.energy_wh()
+        );
+        row
+    }
+}
+
+pub const CSV_HEADER: &str =
+    "transaction_id,id_tag,started,stopped,duration_s,meter_start_wh,meter_stop_wh,energy_wh\r\n";
+
+fn time_field(timestamp: u32) -> heapless::String<32> {
+    if timestamp == 0 {
+        heapless::String::new()
+    } else {
+        ntp::format_iso8601(timestamp.into())
+    }
+}
+
+/// Unix time of a session as kept in flash, unsigned 32 bits last until 2106, 0 after that
+pub fn session_time(unix_timestamp: u64) -> u32 {
+    u32::try_from(unix_timestamp).unwrap_or(0)
+}
+
+/// Totals of the recorded sessions, for the sessions display page
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    pub count: usize,
+    pub energy_wh: u64,
+    pub last: Option<(u32, u32)>, // Duration in seconds and energy in Wh of the last session
+}
+
+/// Totals of the recorded sessions, None until read from flash or after a session was recorded
+static STATS: Mutex<CriticalSectionRawMutex, Cell<Option<SessionStats>>> =
+    Mutex::new(Cell::new(None));
+
+/// Totals of the recorded sessions, read from flash again only after a session was recorded
+pub fn stats() -> SessionStats {
+    if let Some(stats) = STATS.lock(|stats| stats.get()) {
+        return stats;
+    }
+    let stats = match load() {
+        Ok(sessions) => SessionStats {
+            count: sessions.len(),
+            energy_wh: sessions.iter().map(|s| u64::from(s.energy_wh())).sum(),
+            last: sessions.last().map(|s| (s.duration_secs, s.energy_wh())),
+        },
+        Err(e) => {
+            warn!("SESS: Failed to read session history: {e}");
+            SessionStats::default()
+        }
+    };
+    STATS.lock(|cached| cached.set(Some(stats)));
+    stats
+}
+
+/// The recorded sessions, oldest first
+pub fn load() -> Result<Vec<SessionRecord>, &'static str> {
+    let mut buffer = memory::try_alloc("SESS", storage::MAX_RECORD_SIZE)?;
+    let len = storage::read(Slot::Sessions, &mut buffer)?.unwrap_or(0);
+    Ok(buffer[..len]
+        .chunks_exact(ENTRY_SIZE)
+        .filter_map(SessionRecord::from_bytes)
+        .collect())
+}
+
+/// Append a finished session to the history in flash
+pub fn record(session: &SessionRecord) {
+    let mut buffer = match memory::try_alloc("SESS", storage::MAX_RECORD_SIZE) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            warn!("SESS: Session not recorded: {e}");
+            return;
+        }
+    };
+    let mut len = match storage::read(Slot::Sessions, &mut buffer) {
+        Ok(len) => len.unwrap_or(0),
+        Err(e) => {
+            warn!("SESS: Failed to read session history, starting over: {e}");
+            0
+        }
+    };
+    len -= len % ENTRY_SIZE;
+
+    if len + ENTRY_SIZE > MAX_SESSIONS * ENTRY_SIZE {
+        buffer.copy_within(ENTRY_SIZE..len, 0);
+        len -= ENTRY_SIZE;
+    }
+    buffer[len..len + ENTRY_SIZE].copy_from_slice(&session.to_bytes());
+    len += ENTRY_SIZE;
+
+    STATS.lock(|stats| stats.set(None));
+    display::notify(DisplayEvent::SessionRecorded);
+    match storage::write(Slot::Sessions, &buffer[..len]) {
+        Ok(()) => info!(
+            "SESS: Recorded session of transaction {} ({} sessions)",
+            session.transaction_id,
+            len / ENTRY_SIZE
+        ),
+        Err(e) => warn!("SESS: Failed to record session: {e}"),
+    }
+}
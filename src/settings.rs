@@ -0,0 +1,464 @@
+use core::{fmt::Write, str};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    charger::Charger,
+    config::{self, Config, LocalSettings},
+    data_transfer, info, mk_static, ocpp,
+    ready::{self, Subsystem},
+    storage::{self, Slot},
+    utils, warn,
+};
+
+const MAX_SETTINGS_SIZE: usize = 1280;
+/// RAM of the buffer the settings are read into, counted against the storage budget
+pub const RAM: usize = MAX_SETTINGS_SIZE;
+/// Longest value of a single setting
+pub const MAX_VALUE_LEN: usize = 64;
+/// Seconds a remote change has to reach the broker before it's rolled back, by default
+pub const TRIAL_SECS: u32 = 300;
+/// Restarts a remote change gets to reach the broker, against a reset loop outlasting the timeout
+const TRIAL_BOOTS: u8 = 3;
+
+/// Settings configured on site, e.g. over BLE, waiting to be stored
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingSettings {
+    pub wifi_ssid: heapless::String<MAX_VALUE_LEN>,
+    pub wifi_password: heapless::String<MAX_VALUE_LEN>,
+    pub mqtt_broker: heapless::String<MAX_VALUE_LEN>,
+    pub charger_serial: heapless::String<MAX_VALUE_LEN>,
+    /// SSID of the network the charger last connected to, not set over BLE
+    pub last_network: heapless::String<MAX_VALUE_LEN>,
+    /// Network parameters of the last connection, not set over BLE
+    pub cached: [heapless::String<MAX_VALUE_LEN>; Cached::ALL.len()],
+    /// Settings before a remote change on trial, restored when it doesn't reach the broker
+    pub rollback_ssid: heapless::String<MAX_VALUE_LEN>,
+    pub rollback_password: heapless::String<MAX_VALUE_LEN>,
+    pub rollback_broker: heapless::String<MAX_VALUE_LEN>,
+    /// Restarts and seconds left of a remote change on trial, `<boots>:<secs>`, empty without one
+    pub trial: heapless::String<MAX_VALUE_LEN>,
+    /// Why the last remote change was rolled back, until it's reported
+    pub rolled_back: heapless::String<MAX_VALUE_LEN>,
+}
+
+/// A remote change of the connection settings, None keeps a setting
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RemoteChange<'a> {
+    pub wifi_ssid: Option<&'a str>,
+    pub wifi_password: Option<&'a str>,
+    pub mqtt_broker: Option<&'a str>,
+}
+
+/// A remote change on trial, committed once the broker is reached with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Trial {
+    boots_left: u8,
+    secs: u32,
+}
+
+impl Trial {
+    fn parse(value: &str) -> Option<Self> {
+        let (boots_left, secs) = value.split_once(':')?;
+        Some(Self {
+            boots_left: boots_left.parse().ok()?,
+            secs: secs.parse().ok()?,
+        })
+    }
+
+    fn to_value(self) -> heapless::String<MAX_VALUE_LEN> {
+        let mut value = heapless::String::new();
+        let _ = write!(value, "{}:{}", self.boots_left, self.secs);
+        value
+    }
+}
+
+/// Network parameters cached from the last connection, tried first after a restart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cached {
+    /// BSSID and channel of the access point of the last network
+    AccessPoint,
+    /// Address of the MQTT broker
+    Broker,
+    /// Address of the NTP server
+    NtpServer,
+}
+
+impl Cached {
+    pub const ALL: [Cached; 3] = [Cached::AccessPoint, Cached::Broker, Cached::NtpServer];
+
+    fn key(&self) -> &'static str {
+        match self {
+            Self::AccessPoint => "ap",
+            Self::Broker => "broker_ip",
+            Self::NtpServer => "ntp_ip",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AccessPoint => "access point",
+            Self::Broker => "broker address",
+            Self::NtpServer => "NTP server address",
+        }
+    }
+}
+
+/// Check a value before it's stored, quotes and backslashes can't be stored
+pub fn validate_value(value: &[u8]) -> Result<&str, &'static str> {
+    let value = str::from_utf8(value).map_err(|_| "is not valid UTF-8")?;
+    if value.len() > MAX_VALUE_LEN {
+        return Err("is too long");
+    }
+    if value.contains(['"', '\\']) || value.chars().any(char::is_control) {
+        return Err("contains quotes, backslashes or control characters");
+    }
+    Ok(value)
+}
+
+impl PendingSettings {
+    /// The settings with their keys in the stored record
+    fn fields(&self) -> impl Iterator<Item = (&'static str, &heapless::String<MAX_VALUE_LEN>)> {
+        [
+            ("ssid", &self.wifi_ssid),
+            ("password", &self.wifi_password),
+            ("broker", &self.mqtt_broker),
+            ("serial", &self.charger_serial),
+            ("network", &self.last_network),
+            ("rollback_ssid", &self.rollback_ssid),
+            ("rollback_password", &self.rollback_password),
+            ("rollback_broker", &self.rollback_broker),
+            ("trial", &self.trial),
+            ("rolled_back", &self.rolled_back),
+        ]
+        .into_iter()
+        .chain(Cached::ALL.iter().map(Cached::key).zip(&self.cached))
+    }
+
+    fn fields_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&'static str, &mut heapless::String<MAX_VALUE_LEN>)> {
+        let Self {
+            wifi_ssid,
+            wifi_password,
+            mqtt_broker,
+            charger_serial,
+            last_network,
+            cached,
+            rollback_ssid,
+            rollback_password,
+            rollback_broker,
+            trial,
+            rolled_back,
+        } = self;
+        [
+            ("ssid", wifi_ssid),
+            ("password", wifi_password),
+            ("broker", mqtt_broker),
+            ("serial", charger_serial),
+            ("network", last_network),
+            ("rollback_ssid", rollback_ssid),
+            ("rollback_password", rollback_password),
+            ("rollback_broker", rollback_broker),
+            ("trial", trial),
+            ("rolled_back", rolled_back),
+        ]
+        .into_iter()
+        .chain(Cached::ALL.iter().map(Cached::key).zip(cached.iter_mut()))
+    }
+
+    /// The settings as they are stored, to change one and keep the others
+    fn stored() -> Result<Self, &'static str> {
+        let mut settings = Self::default();
+        let mut buffer = [0u8; MAX_SETTINGS_SIZE];
+        let Some(json) = read_stored(&mut buffer) else {
+            return Ok(settings);
+        };
+        for (key, value) in settings.fields_mut() {
+            let stored = ocpp::json_string_field(json, key).unwrap_or_default();
+            *value = heapless::String::try_from(stored).map_err(|_| "Stored setting too long")?;
+        }
+        Ok(settings)
+    }
+
+    /// Store the settings that were set, they are used after a restart
+    pub fn save(&self) -> Result<(), &'static str> {
+        if !self.charger_serial.is_empty() {
+            utils::validate_identifier(&self.charger_serial)?;
+        }
+        let mut json = heapless::String::<MAX_SETTINGS_SIZE>::new();
+        let mut separator = "";
+        json.push('{').map_err(|_| "Settings too large")?;
+        for (key, value) in self.fields() {
+            if !value.is_empty() {
+                write!(json, "{separator}\"{key}\":\"{value}\"")
+                    .map_err(|_| "Settings too large")?;
+                separator = ",";
+            }
+        }
+        json.push('}').map_err(|_| "Settings too large")?;
+        storage::write(Slot::Settings, json.as_bytes())
+    }
+
+    /// Restore the settings from before a remote change on trial
+    fn roll_back(&mut self, reason: &str) {
+        self.wifi_ssid = core::mem::take(&mut self.rollback_ssid);
+        self.wifi_password = core::mem::take(&mut self.rollback_password);
+        self.mqtt_broker = core::mem::take(&mut self.rollback_broker);
+        self.trial.clear();
+        self.rolled_back = heapless::String::try_from(reason).unwrap_or_default();
+    }
+}
+
+/// Read a value from the settings JSON, an empty value means not set
+fn setting(json: &'static str, key: &str) -> Option<&'static str> {
+    ocpp::json_string_field(json, key).filter(|value| !value.is_empty() && !value.contains('\\'))
+}
+
+/// Read the stored settings record into the buffer
+fn read_stored(buffer: &mut [u8; MAX_SETTINGS_SIZE]) -> Option<&str> {
+    match storage::read(Slot::Settings, buffer) {
+        Ok(Some(len)) => str::from_utf8(&buffer[..len]).ok(),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("SETT: Failed to read settings: {e}");
+            None
+        }
+    }
+}
+
+/// SSID of the network the charger last connected to
+pub fn last_network() -> Option<heapless::String<MAX_VALUE_LEN>> {
+    let mut buffer = [0u8; MAX_SETTINGS_SIZE];
+    let json = read_stored(&mut buffer)?;
+    ocpp::json_string_field(json, "network")
+        .filter(|ssid| !ssid.is_empty())
+        .and_then(|ssid| heapless::String::try_from(ssid).ok())
+}
+
+/// Change the stored settings keeping the others, nothing is written when they don't change
+fn update(
+    change: impl FnOnce(&mut PendingSettings) -> Result<(), &'static str>,
+) -> Result<(), &'static str> {
+    let stored = PendingSettings::stored()?;
+    let mut settings = stored.clone();
+    change(&mut settings)?;
+    if settings == stored {
+        return Ok(());
+    }
+    settings.save()
+}
+
+/// Remember the network the charger connected to, the access point of another network is
+/// forgotten
+pub fn remember_network(ssid: &str) -> Result<(), &'static str> {
+    let ssid = heapless::String::try_from(validate_value(ssid.as_bytes())?)
+        .map_err(|_| "SSID too long")?;
+    update(|settings| {
+        if settings.last_network != ssid {
+            settings.last_network = ssid;
+            settings.cached[Cached::AccessPoint as usize].clear();
+        }
+        Ok(())
+    })
+}
+
+/// A network parameter cached from the last connection
+pub fn cached(cached: Cached) -> Option<heapless::String<MAX_VALUE_LEN>> {
+    let mut buffer = [0u8; MAX_SETTINGS_SIZE];
+    let json = read_stored(&mut buffer)?;
+    ocpp::json_string_field(json, cached.key())
+        .filter(|value| !value.is_empty())
+        .and_then(|value| heapless::String::try_from(value).ok())
+}
+
+/// Cache a network parameter, an empty value forgets it
+pub fn cache(cached: Cached, value: &str) -> Result<(), &'static str> {
+    let value = heapless::String::try_from(validate_value(value.as_bytes())?)
+        .map_err(|_| "Value too long")?;
+    update(|settings| {
+        settings.cached[cached as usize] = value;
+        Ok(())
+    })
+}
+
+/// Store a remote change of the connection settings on trial, it's used after a restart and
+/// rolled back when the charger doesn't reach the broker within `secs`
+pub fn start_trial(change: &RemoteChange, secs: u32) -> Result<(), &'static str> {
+    let value = |value: Option<&str>| -> Result<_, &'static str> {
+        value
+            .map(|value| {
+                heapless::String::try_from(validate_value(value.as_bytes())?)
+                    .map_err(|_| "is too long")
+            })
+            .transpose()
+    };
+    let wifi_ssid = value(change.wifi_ssid)?;
+    let wifi_password = value(change.wifi_password)?;
+    let mqtt_broker = value(change.mqtt_broker)?;
+    update(|settings| {
+        if !settings.trial.is_empty() {
+            return Err("Another settings change is on trial");
+        }
+        settings.rollback_ssid = settings.wifi_ssid.clone();
+        settings.rollback_password = settings.wifi_password.clone();
+        settings.rollback_broker = settings.mqtt_broker.clone();
+        if let Some(ssid) = wifi_ssid {
+            settings.wifi_ssid = ssid;
+        }
+        if let Some(password) = wifi_password {
+            settings.wifi_password = password;
+        }
+        if let Some(broker) = mqtt_broker {
+            settings.mqtt_broker = broker;
+        }
+        settings.trial = Trial {
+            boots_left: TRIAL_BOOTS,
+            secs,
+        }
+        .to_value();
+        settings.rolled_back.clear();
+        Ok(())
+    })
+}
+
+/// Count a restart of a remote change on trial, it's rolled back when it ran out of restarts
+fn count_trial_boot() {
+    let result = update(|settings| {
+        let Some(trial) = Trial::parse(&settings.trial) else {
+            return Ok(());
+        };
+        if trial.boots_left == 0 {
+            warn!("SETT: Settings on trial didn't reach the broker in {TRIAL_BOOTS} restarts, rolling back");
+            settings.roll_back("restarts");
+        } else {
+            settings.trial = Trial {
+                boots_left: trial.boots_left - 1,
+                ..trial
+            }
+            .to_value();
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("SETT: Failed to update the settings on trial: {e}");
+    }
+}
+
+/// Load the settings stored on site and install them, returns true if there are any
+/// Must be called once at boot, after `storage::init` and before the configuration is used
+pub fn load() -> bool {
+    count_trial_boot();
+
+    let buffer = mk_static!([u8; MAX_SETTINGS_SIZE], [0; MAX_SETTINGS_SIZE]);
+    let len = match storage::read(Slot::Settings, buffer) {
+        Ok(Some(len)) => len,
+        Ok(None) => return false,
+        Err(e) => {
+            warn!("SETT: Failed to read settings: {e}");
+            return false;
+        }
+    };
+
+    let buffer: &'static [u8] = buffer;
+    let Ok(json) = str::from_utf8(&buffer[..len]) else {
+        warn!("SETT: Stored settings are invalid");
+        return false;
+    };
+    let settings = LocalSettings {
+        wifi_ssid: setting(json, "ssid"),
+        wifi_password: setting(json, "password"),
+        mqtt_broker: setting(json, "broker"),
+        charger_serial: setting(json, "serial")
+            .filter(|serial| utils::validate_identifier(serial).is_ok()),
+    };
+    // Only the parameters of the last connection may be stored
+    if settings.wifi_ssid.is_none()
+        && settings.wifi_password.is_none()
+        && settings.mqtt_broker.is_none()
+        && settings.charger_serial.is_none()
+    {
+        return false;
+    }
+    info!(
+        "SETT: Using settings stored on site (WiFi {})",
+        settings.wifi_ssid.unwrap_or("unchanged")
+    );
+    config::set_local_settings(settings);
+    true
+}
+
+/// Task to commit a remote change on trial once the broker is reached with it, or roll it back
+/// and restart when it isn't in time. A rollback is reported once the broker is reached again
+#[embassy_executor::task]
+pub async fn settings_trial_task(charger: &'static Charger) {
+    info!("TASK: Started Settings Trial");
+
+    let stored = match PendingSettings::stored() {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("SETT: Failed to read the settings: {e}");
+            return;
+        }
+    };
+
+    if let Some(trial) = Trial::parse(&stored.trial) {
+        info!(
+            "SETT: Settings on trial, rolled back unless the broker is reached in {}s",
+            trial.secs
+        );
+        let timeout = Timer::after(Duration::from_secs(trial.secs.into()));
+        match select(ready::wait(Subsystem::Mqtt), timeout).await {
+            Either::First(()) => {
+                let result = update(|settings| {
+                    settings.rollback_ssid.clear();
+                    settings.rollback_password.clear();
+                    settings.rollback_broker.clear();
+                    settings.trial.clear();
+                    Ok(())
+                });
+                match result {
+                    Ok(()) => info!("SETT: Settings on trial reached the broker, committed"),
+                    Err(e) => warn!("SETT: Failed to commit the settings on trial: {e}"),
+                }
+            }
+            Either::Second(()) => {
+                warn!("SETT: Settings on trial didn't reach the broker in time, rolling back");
+                if let Err(e) = update(|settings| {
+                    settings.roll_back("timeout");
+                    Ok(())
+                }) {
+                    warn!("SETT: Failed to roll back the settings: {e}");
+                    return;
+                }
+                // Don't interrupt a charging session with the restart
+                while charger.get_state().await.is_charging() {
+                    Timer::after(Duration::from_secs(30)).await;
+                }
+                esp_hal::system::software_reset();
+            }
+        }
+        return;
+    }
+
+    if !stored.rolled_back.is_empty() {
+        ready::wait(Subsystem::Mqtt).await;
+        let config = Config::from_config();
+        if data_transfer::send_data_transfer(
+            config.charger_vendor,
+            Some("SettingsRolledBack"),
+            Some(&stored.rolled_back),
+        ) {
+            info!(
+                "SETT: Reported the rollback of the settings ({})",
+                stored.rolled_back
+            );
+            if let Err(e) = update(|settings| {
+                settings.rolled_back.clear();
+                Ok(())
+            }) {
+                warn!("SETT: Failed to clear the rollback: {e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use core::cell::RefCell;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, pubsub::PubSubChannel,
+};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{charger::Charger, config::Config, info, meter, warn};
+
+/// External sources that can impose a current limit on the charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitSource {
+    LoadBalancing,
+    Solar,
+    /// The id tag of the session was deauthorized, the session continues at the minimum current
+    Deauthorized,
+    /// A session steps up from the minimum current while the supply voltage holds
+    SoftStart,
+    /// The current is derated while the charger runs hot
+    Thermal,
+    /// The utility asks to shed the load on the demand response contact
+    DemandResponse,
+}
+
+impl LimitSource {
+    pub const ALL: [LimitSource; 6] = [
+        LimitSource::LoadBalancing,
+        LimitSource::Solar,
+        LimitSource::Deauthorized,
+        LimitSource::SoftStart,
+        LimitSource::Thermal,
+        LimitSource::DemandResponse,
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            Self::LoadBalancing => 0,
+            Self::Solar => 1,
+            Self::Deauthorized => 2,
+            Self::SoftStart => 3,
+            Self::Thermal => 4,
+            Self::DemandResponse => 5,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::LoadBalancing => "LoadBalancing",
+            Self::Solar => "Solar",
+            Self::Deauthorized => "Deauthorized",
+            Self::SoftStart => "SoftStart",
+            Self::Thermal => "Thermal",
+            Self::DemandResponse => "DemandResponse",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitEvent {
+    /// The effective current limit changed, in amps
+    LimitChanged(u16),
+    /// A source stopped reporting, the failsafe current is used in its place
+    FallbackEngaged(LimitSource),
+    /// A stale source started reporting again
+    FallbackCleared(LimitSource),
+}
+
+/// Lowest current an EV can charge with (IEC 61851)
+pub const MIN_CURRENT_AMPS: u16 = 6;
+
+/// Subscriber slots of LIMIT_PUBSUB, checked against the task registry at build time. The pilot
+/// PWM task is the only subscriber, it offers every change of the limit to the vehicle
+pub const LIMIT_SUBSCRIBERS: usize = 1;
+
+/// PubSub channel for current limit changes and failsafe events
+pub static LIMIT_PUBSUB: PubSubChannel<
+    CriticalSectionRawMutex,
+    LimitEvent,
+    4,
+    LIMIT_SUBSCRIBERS,
+    2,
+> = PubSubChannel::new();
+
+#[derive(Debug, Clone, Copy)]
+struct SourceLimit {
+    amps: Option<u16>,
+    updated: Instant,
+    stale: bool,
+}
+
+impl SourceLimit {
+    const fn new() -> Self {
+        Self {
+            amps: None,
+            updated: Instant::from_ticks(0),
+            stale: false,
+        }
+    }
+}
+
+/// Tracks the current limits reported by external sources and falls back to a
+/// failsafe current when a source stops reporting
+pub struct CurrentLimits {
+    sources: Mutex<CriticalSectionRawMutex, RefCell<[SourceLimit; LimitSource::ALL.len()]>>,
+    max_current: u16,
+    failsafe_current: u16,
+    timeouts: [Duration; LimitSource::ALL.len()],
+}
+
+impl CurrentLimits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            sources: Mutex::new(RefCell::new([SourceLimit::new(); LimitSource::ALL.len()])),
+            max_current: config.max_current_amps,
+            failsafe_current: config.failsafe_current_amps.min(config.max_current_amps),
+            timeouts: [
+                Duration::from_secs(config.load_balancing_timeout_secs.into()),
+                Duration::from_secs(config.solar_timeout_secs.into()),
+                // Set by the charger itself, they never go stale
+                Duration::MAX,
+                Duration::MAX,
+                Duration::MAX,
+                Duration::MAX,
+            ],
+        }
+    }
+
+    /// Record a fresh limit from a source, clears the fallback if the source was stale
+    pub async fn set_limit(&self, source: LimitSource, amps: u16) {
+        let was_stale = {
+            let sources_guard = self.sources.lock().await;
+            let mut sources = sources_guard.borrow_mut();
+            let entry = &mut sources[source.index()];
+            let was_stale = entry.stale;
+            *entry = SourceLimit {
+                amps: Some(amps.min(self.max_current)),
+                updated: Instant::now(),
+                stale: false,
+            };
+            was_stale
+        };
+
+        info!("LIMT: {} limit set to {amps}A", source.as_str());
+        if was_stale {
+            info!(
+                "LIMT: {} is reporting again, fallback cleared",
+                source.as_str()
+            );
+            LIMIT_PUBSUB
+                .immediate_publisher()
+                .publish_immediate(LimitEvent::FallbackCleared(source));
+        }
+    }
+
+    /// Stop using a source, e.g. when the feature providing it is disabled
+    pub async fn clear_limit(&self, source: LimitSource) {
+        let sources_guard = self.sources.lock().await;
+        sources_guard.borrow_mut()[source.index()] = SourceLimit::new();
+        info!("LIMT: {} limit cleared", source.as_str());
+    }
+
+    /// The lowest limit of all sources, stale sources count as the failsafe current
+    pub async fn effective_limit(&self) -> u16 {
+        let sources_guard = self.sources.lock().await;
+        let sources = sources_guard.borrow();
+        sources
+            .iter()
+            .filter_map(|entry| {
+                if entry.stale {
+                    Some(self.failsafe_current)
+                } else {
+                    entry.amps
+                }
+            })
+            .fold(self.max_current, u16::min)
+    }
+
+    /// Whether a source is currently replaced by the failsafe current
+    pub async fn is_fallback_active(&self, source: LimitSource) -> bool {
+        let sources_guard = self.sources.lock().await;
+        let stale = sources_guard.borrow()[source.index()].stale;
+        stale
+    }
+
+    /// Mark sources that haven't reported within their timeout as stale
+    /// Returns the sources that became stale during this check
+    async fn check_staleness(&self) -> heapless::Vec<LimitSource, { LimitSource::ALL.len() }> {
+        let mut newly_stale = heapless::Vec::new();
+        let sources_guard = self.sources.lock().await;
+        let mut sources = sources_guard.borrow_mut();
+
+        for source in LimitSource::ALL {
+            let entry = &mut sources[source.index()];
+            if entry.amps.is_some()
+                && !entry.stale
+                && entry.updated.elapsed() > self.timeouts[source.index()]
+            {
+                entry.stale = true;
+                let _ = newly_stale.push(source);
+            }
+        }
+        newly_stale
+    }
+}
+
+/// Task to detect stale limit sources and publish changes of the effective limit
+#[embassy_executor::task]
+pub async fn limit_watchdog_task(limits: &'static CurrentLimits) {
+    info!("TASK: Started Current Limit Watchdog");
+
+    let publisher = LIMIT_PUBSUB.publisher().unwrap();
+    let mut last_limit = limits.effective_limit().await;
+
+    loop {
+        for source in limits.check_staleness().await {
+            warn!(
+                "LIMT: No update from {} within timeout, falling back to {}A",
+                source.as_str(),
+                limits.failsafe_current
+            );
+            publisher.publish_immediate(LimitEvent::FallbackEngaged(source));
+        }
+
+        let limit = limits.effective_limit().await;
+        if limit != last_limit {
+            info!("LIMT: Effective current limit changed from {last_limit}A to {limit}A");
+            publisher.publish_immediate(LimitEvent::LimitChanged(limit));
+            last_limit = limit;
+        }
+
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+/// Settings of the soft start, for installations on long or undersized feeder cables
+#[derive(Debug, Clone, Copy)]
+pub struct SoftStart {
+    pub step_amps: u16,
+    pub step_secs: u16,
+    /// Supply voltage below which the current is stepped back down
+    pub min_voltage: u16,
+    pub max_current: u16,
+}
+
+impl SoftStart {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            step_amps: config.soft_start_step_amps.max(1),
+            step_secs: config.soft_start_step_secs.max(1),
+            min_voltage: config.soft_start_min_voltage,
+            max_current: config.max_current_amps,
+        }
+    }
+}
+
+/// Task to start every session at the minimum current and step up to the maximum while the
+/// supply voltage holds. When it sags below the threshold the current is stepped back down and
+/// the session stays at or below that current. Without voltage readings it steps up unchecked
+#[embassy_executor::task]
+pub async fn soft_start_task(
+    charger: &'static Charger,
+    limits: &'static CurrentLimits,
+    soft_start: SoftStart,
+) {
+    info!(
+        "TASK: Started Soft Start, {}A every {}s down below {}V",
+        soft_start.step_amps, soft_start.step_secs, soft_start.min_voltage
+    );
+
+    loop {
+        while !charger.get_state().await.is_charging() {
+            Timer::after(Duration::from_secs(1)).await;
+        }
+
+        let mut amps = MIN_CURRENT_AMPS;
+        let mut last_step = Instant::now();
+        let mut ceiling_found = false;
+        limits.set_limit(LimitSource::SoftStart, amps).await;
+        if meter::supply_voltage().is_none() {
+            warn!("LIMT: No supply voltage readings, soft start can't detect voltage sag");
+        }
+
+        while charger.get_state().await.is_charging() {
+            Timer::after(Duration::from_secs(1)).await;
+
+            match meter::supply_voltage() {
+                Some(volts) if volts < soft_start.min_voltage => {
+                    if amps > MIN_CURRENT_AMPS {
+                        amps = amps
+                            .saturating_sub(soft_start.step_amps)
+                            .max(MIN_CURRENT_AMPS);
+                        warn!("LIMT: Supply voltage sagged to {volts}V, backing off to {amps}A");
+                        limits.set_limit(LimitSource::SoftStart, amps).await;
+                    }
+                    ceiling_found = true;
+                    last_step = Instant::now();
+                }
+                _ if !ceiling_found
+                    && amps < soft_start.max_current
+                    && last_step.elapsed() >= Duration::from_secs(soft_start.step_secs.into()) =>
+                {
+                    amps = (amps + soft_start.step_amps).min(soft_start.max_current);
+                    limits.set_limit(LimitSource::SoftStart, amps).await;
+                    last_step = Instant::now();
+                }
+                _ => {}
+            }
+        }
+
+        limits.clear_limit(LimitSource::SoftStart).await;
+    }
+}
@@ -0,0 +1,171 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_time::{with_timeout, Duration};
+
+use crate::{
+    charger::Charger,
+    config::Config,
+    data_transfer::{self, DataTransferResponse},
+    display::{self, DisplayEvent},
+    info, meter, mqtt, ocpp,
+    smart_charging::{CurrentLimits, LimitSource, MIN_CURRENT_AMPS},
+    warn,
+};
+
+/// Solar charging is configured, the grid topic is subscribed
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+/// Charge on the surplus only, off charges at the full current
+static ECO: AtomicBool = AtomicBool::new(false);
+
+/// Settings of the surplus charging
+#[derive(Debug, Clone, Copy)]
+pub struct Solar {
+    /// Lowest current offered in eco mode, the grid makes up what the surplus doesn't cover
+    pub min_current: u16,
+    pub max_current: u16,
+    /// Export or import tolerated before the current is changed
+    pub hysteresis_watts: u16,
+    pub supply_voltage: u16,
+    pub supply_phases: u8,
+}
+
+impl Solar {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            min_current: config
+                .solar_min_current_amps
+                .clamp(MIN_CURRENT_AMPS, config.max_current_amps),
+            max_current: config.max_current_amps,
+            hysteresis_watts: config.solar_hysteresis_watts,
+            supply_voltage: config.supply_voltage,
+            supply_phases: config.supply_phases,
+        }
+    }
+
+    /// Power of one amp on all phases
+    fn watts_per_amp(&self) -> i32 {
+        i32::from(self.supply_voltage) * i32::from(self.supply_phases)
+    }
+
+    /// Current to offer from the current drawn now and the power at the grid connection, positive
+    /// while importing. The current steps down on an import beyond the hysteresis and steps up
+    /// once the export covers another amp beyond it
+    fn next_current(&self, amps: u16, grid_watts: i32) -> u16 {
+        let hysteresis = i32::from(self.hysteresis_watts);
+        let per_amp = self.watts_per_amp().max(1);
+        let amps = i32::from(amps);
+        let next = if grid_watts > hysteresis {
+            // Rounded up, so the import is gone after the step
+            amps - (grid_watts + per_amp - 1) / per_amp
+        } else if -grid_watts >= hysteresis + per_amp {
+            amps + (-grid_watts - hysteresis) / per_amp
+        } else {
+            amps
+        };
+        next.clamp(i32::from(self.min_current), i32::from(self.max_current)) as u16
+    }
+}
+
+/// Enable solar charging with eco mode on, before the tasks are spawned
+pub fn init(config: &Config) {
+    if !config.solar_enabled {
+        return;
+    }
+    AVAILABLE.store(true, Ordering::Relaxed);
+    ECO.store(true, Ordering::Relaxed);
+    // {"enabled":true} switches eco mode, without data it's reported
+    if let Err(e) =
+        data_transfer::register_handler(config.charger_vendor, Some("EcoMode"), |_, data| {
+            if let Some(enabled) = data.and_then(|data| ocpp::json_bool_field(data, "enabled")) {
+                set_eco(enabled);
+            }
+            let mut state = heapless::String::new();
+            let _ = write!(state, "{{\"enabled\":{}}}", is_eco());
+            DataTransferResponse::accepted(Some(state))
+        })
+    {
+        warn!("SOLR: Failed to register the eco mode handler: {e}");
+    }
+}
+
+/// Whether the charger charges on the solar surplus only
+pub fn is_eco() -> bool {
+    ECO.load(Ordering::Relaxed)
+}
+
+/// Switch eco mode, ignored without solar charging
+pub fn set_eco(enabled: bool) {
+    if !AVAILABLE.load(Ordering::Relaxed) {
+        info!("SOLR: Solar charging isn't configured, eco mode stays off");
+        return;
+    }
+    if ECO.swap(enabled, Ordering::Relaxed) != enabled {
+        info!("SOLR: Eco mode {}", if enabled { "on" } else { "off" });
+        display::notify(DisplayEvent::Input);
+    }
+}
+
+/// Switch eco mode on or off, returns whether it's on now
+pub fn toggle_eco() -> bool {
+    set_eco(!is_eco());
+    is_eco()
+}
+
+/// Power at the grid connection in a message of the grid topic, positive while importing. Either
+/// a bare number or a JSON object with a `watts` field, e.g. `{"watts":-1200}`
+fn grid_watts(payload: &[u8]) -> Option<i32> {
+    let payload = core::str::from_utf8(payload).ok()?.trim();
+    if let Ok(watts) = payload.parse::<f32>() {
+        return Some(watts as i32);
+    }
+    ocpp::json_integer_field(payload, "watts").and_then(|watts| i32::try_from(watts).ok())
+}
+
+/// Task to charge on the solar surplus in eco mode. Every reading of the grid power moves the
+/// solar limit towards no import and no export. When the readings stop the limit goes stale and
+/// the failsafe current is used, out of eco mode the limit is cleared
+#[embassy_executor::task]
+pub async fn solar_task(charger: &'static Charger, limits: &'static CurrentLimits, solar: Solar) {
+    info!(
+        "TASK: Started Solar Charging, eco mode from {}A with {}W hysteresis",
+        solar.min_current, solar.hysteresis_watts
+    );
+
+    let mut offered: Option<u16> = None;
+
+    loop {
+        // Out of eco mode the charger offers what the other sources allow
+        if !is_eco() && offered.take().is_some() {
+            limits.clear_limit(LimitSource::Solar).await;
+        }
+
+        let Ok(payload) =
+            with_timeout(Duration::from_secs(1), mqtt::MQTT_GRID_CHANNEL.receive()).await
+        else {
+            continue;
+        };
+        let Some(grid_watts) = grid_watts(&payload) else {
+            warn!("SOLR: Grid power reading not understood");
+            continue;
+        };
+        if !is_eco() {
+            continue;
+        }
+
+        // A session starts at the minimum, the surplus is only known once it draws
+        let amps = if charger.get_state().await.is_charging() {
+            let drawn = meter::measurement().map(|measurement| (measurement.amps + 0.5) as u16);
+            let amps = drawn.or(offered).unwrap_or(solar.min_current);
+            solar.next_current(amps, grid_watts)
+        } else {
+            solar.min_current
+        };
+        if offered != Some(amps) {
+            info!("SOLR: Grid at {grid_watts}W, offering {amps}A");
+        }
+        offered = Some(amps);
+        limits.set_limit(LimitSource::Solar, amps).await;
+    }
+}
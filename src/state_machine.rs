@@ -0,0 +1,418 @@
+//! Transition table of the charger state machine. Pure: no statics, no async and no hardware, the
+//! charger module samples the conditions it looks at and applies the effects it asks for. It only
+//! depends on heapless and the log macros, so the tests in `host/` build it for the host
+
+use crate::{info, warn};
+
+/// Output events published with a state change, with room for the vendor events of a fork
+pub type OutputEvents = heapless::Vec<OutputEvent, 4>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    InsertCable,
+    RemoveCable,
+    SwipeDetected,
+    Accepted,
+    Rejected,
+    LockoutCleared,
+    Reserve,
+    ReservationEnded,
+    MaintenanceStarted,
+    MaintenanceEnded,
+    DiodeMissing,
+    Deauthorized,
+    NetworkLost,
+    NetworkRestored,
+    /// A safety invariant was violated, see the invariant module
+    InvariantViolated,
+    /// The driver confirmed a stop on the front panel button
+    StopRequested,
+    /// The residual current device tripped, the relay was already opened by the rcd module
+    GroundFault,
+    /// The contactor didn't follow the relay, see the relay module
+    PowerSwitchFailure,
+    /// A temperature went above the critical temperature, see the sensors module
+    Overheated,
+    /// The temperature dropped back after Overheated
+    Cooled,
+    /// Another card was swiped during a session and joined the waiting list
+    Queued,
+    None,
+}
+
+impl InputEvent {
+    pub const ALL: [InputEvent; 22] = [
+        InputEvent::InsertCable,
+        InputEvent::RemoveCable,
+        InputEvent::SwipeDetected,
+        InputEvent::Accepted,
+        InputEvent::Rejected,
+        InputEvent::LockoutCleared,
+        InputEvent::Reserve,
+        InputEvent::ReservationEnded,
+        InputEvent::MaintenanceStarted,
+        InputEvent::MaintenanceEnded,
+        InputEvent::DiodeMissing,
+        InputEvent::Deauthorized,
+        InputEvent::NetworkLost,
+        InputEvent::NetworkRestored,
+        InputEvent::InvariantViolated,
+        InputEvent::StopRequested,
+        InputEvent::GroundFault,
+        InputEvent::PowerSwitchFailure,
+        InputEvent::Overheated,
+        InputEvent::Cooled,
+        InputEvent::Queued,
+        InputEvent::None,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEvent {
+    Lock,
+    Unlock,
+    ApplyPower,
+    RemovePower,
+    ShowRejected,
+    /// An event of a fork, added by a hook registered with the vendor module
+    Vendor(u8),
+}
+
+/// Why a transaction was stopped, when it's not a stop on request of the driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The central system reported the id tag as blocked, expired or invalid
+    DeAuthorized,
+    /// The charger went offline with the stop policy
+    Offline,
+    /// The central system created a second transaction for the same session
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChargerState {
+    #[default]
+    Off,
+    Faulted,
+    Available,
+    Preparing,
+    Charging,
+    Authorizing,
+    Unavailable,
+    Reserved,
+}
+
+impl ChargerState {
+    pub const ALL: [ChargerState; 8] = [
+        ChargerState::Off,
+        ChargerState::Faulted,
+        ChargerState::Available,
+        ChargerState::Preparing,
+        ChargerState::Charging,
+        ChargerState::Authorizing,
+        ChargerState::Unavailable,
+        ChargerState::Reserved,
+    ];
+
+    pub fn is_operational(&self) -> bool {
+        matches!(self, Self::Available | Self::Preparing | Self::Charging)
+    }
+
+    pub fn is_charging(&self) -> bool {
+        matches!(self, Self::Charging)
+    }
+
+    pub fn is_prepared(&self) -> bool {
+        matches!(self, Self::Preparing | Self::Charging)
+    }
+
+    /// A vehicle is plugged in
+    pub fn is_vehicle_connected(&self) -> bool {
+        matches!(self, Self::Preparing | Self::Authorizing | Self::Charging)
+    }
+
+    pub fn is_available(&self) -> bool {
+        matches!(self, Self::Available)
+    }
+
+    pub fn has_error(&self) -> bool {
+        matches!(self, Self::Faulted | Self::Unavailable)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::Faulted => "Faulted",
+            Self::Available => "Available",
+            Self::Preparing => "Preparing",
+            Self::Charging => "Charging",
+            Self::Authorizing => "Authorizing",
+            Self::Unavailable => "Unavailable",
+            Self::Reserved => "Reserved",
+        }
+    }
+}
+
+/// What the guards of the table look at, sampled by the charger before a transition
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Conditions {
+    pub master_card: bool, // The swiped card is the master card, only sampled for a swipe
+    pub safety_latched: bool, // A ground fault or a switch failure keeps the charger faulted
+    pub safety_cleared: bool, // The master card cleared the latched safety fault, see the charger
+    pub overheated: bool,  // A temperature is above the critical temperature
+    pub diode_missing: bool, // The vehicle has no pilot diode
+    pub lockout: bool,     // A recurring fault locked the charger out
+    pub maintenance: bool, // A maintenance window is active
+    pub reserved: bool,    // A reservation holds the connector
+    pub waiting_turn: bool, // It's the turn of the next driver on the waiting list
+    pub offline_stops_sessions: bool, // The offline policy stops a session when the network is lost
+}
+
+/// A side effect of a transition, applied by the charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// Record the fault of the input, counted for the lockout
+    RecordFault,
+    /// Keep the offline stop as the reason of the stopped transaction
+    StopOffline,
+    /// The vehicle without the diode is gone
+    ClearDiodeFault,
+    /// The master card lifts the lockout
+    ClearLockout,
+    /// Drop the inputs queued while faulted
+    ClearInputs,
+    /// Wait `SETTLE_SECS` and drop the inputs meanwhile, the state is then decided again with
+    /// `recovered` from fresh conditions
+    Settle,
+}
+
+/// Time a charger with a passing fault stays faulted before it recovers
+pub const SETTLE_SECS: u64 = 5;
+
+/// Result of a transition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub state: ChargerState,
+    pub events: OutputEvents,
+    pub effect: Option<Effect>,
+}
+
+impl Step {
+    fn to(state: ChargerState) -> Self {
+        Self {
+            state,
+            events: heapless::Vec::new(),
+            effect: None,
+        }
+    }
+
+    fn with(mut self, events: &[OutputEvent]) -> Self {
+        self.events = heapless::Vec::from_slice(events).unwrap_or_default();
+        self
+    }
+
+    fn effect(mut self, effect: Effect) -> Self {
+        self.effect = Some(effect);
+        self
+    }
+}
+
+/// Events that end a session, the relay opens before the cable unlocks
+const STOP: [OutputEvent; 2] = [OutputEvent::RemovePower, OutputEvent::Unlock];
+
+/// The state a faulted charger recovers to, still faulted while a safety condition holds and
+/// unavailable while locked out or in maintenance. The inputs dropped while it settled aren't
+/// needed, the conditions are sampled again
+pub fn recovered(conditions: &Conditions) -> ChargerState {
+    if conditions.safety_latched || conditions.overheated || conditions.diode_missing {
+        ChargerState::Faulted
+    } else if conditions.lockout || conditions.maintenance {
+        ChargerState::Unavailable
+    } else {
+        ChargerState::Available
+    }
+}
+
+/// What the transaction handler does with a published transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionAction {
+    /// Open a session and send its StartTransaction
+    Start,
+    /// A session is open already, its StartTransaction isn't sent again
+    AlreadyStarted,
+    /// Stop the open session and record it
+    Stop,
+}
+
+/// The session action of a transition. The power is removed at the end of every session,
+/// whichever state it ends in, a session that faults is stopped like one that ends normally
+pub fn session_action(
+    state: ChargerState,
+    events: &[OutputEvent],
+    session_open: bool,
+) -> Option<SessionAction> {
+    if state == ChargerState::Charging && events.contains(&OutputEvent::ApplyPower) {
+        Some(if session_open {
+            SessionAction::AlreadyStarted
+        } else {
+            SessionAction::Start
+        })
+    } else if session_open && events.contains(&OutputEvent::RemovePower) {
+        Some(SessionAction::Stop)
+    } else {
+        None
+    }
+}
+
+/// The transition of a state on an input, the first arm that matches wins
+pub fn next(state: ChargerState, input: InputEvent, conditions: &Conditions) -> Step {
+    let c = conditions;
+    match (state, input) {
+        (ChargerState::Available, InputEvent::InsertCable) => Step::to(ChargerState::Preparing),
+        (ChargerState::Preparing, InputEvent::SwipeDetected) => Step::to(ChargerState::Authorizing),
+        (ChargerState::Authorizing, InputEvent::Accepted) => {
+            Step::to(ChargerState::Charging).with(&[OutputEvent::ApplyPower, OutputEvent::Lock])
+        }
+        (ChargerState::Authorizing, InputEvent::Rejected) => {
+            Step::to(ChargerState::Preparing).with(&[OutputEvent::ShowRejected])
+        }
+        (ChargerState::Charging, InputEvent::SwipeDetected) => {
+            Step::to(ChargerState::Preparing).with(&STOP)
+        }
+        (ChargerState::Charging, InputEvent::StopRequested) => {
+            info!("CHGR: Stop requested on the button, stopping the session");
+            Step::to(ChargerState::Preparing).with(&STOP)
+        }
+        (ChargerState::Charging, InputEvent::Deauthorized) => {
+            info!("CHGR: Id tag deauthorized by the central system, stopping the session");
+            Step::to(ChargerState::Preparing).with(&STOP)
+        }
+        (ChargerState::Charging, InputEvent::NetworkLost) if c.offline_stops_sessions => {
+            warn!("CHGR: Offline, stopping the session");
+            Step::to(ChargerState::Preparing)
+                .with(&STOP)
+                .effect(Effect::StopOffline)
+        }
+        (ChargerState::Preparing, InputEvent::RemoveCable) if c.maintenance => {
+            Step::to(ChargerState::Unavailable)
+        }
+        (ChargerState::Preparing, InputEvent::RemoveCable) if c.reserved || c.waiting_turn => {
+            Step::to(ChargerState::Reserved)
+        }
+        (ChargerState::Preparing, InputEvent::RemoveCable) => Step::to(ChargerState::Available),
+        (ChargerState::Available, InputEvent::Reserve) => Step::to(ChargerState::Reserved),
+        (ChargerState::Reserved, InputEvent::InsertCable) => Step::to(ChargerState::Preparing),
+        (ChargerState::Reserved, InputEvent::ReservationEnded) => Step::to(ChargerState::Available),
+        (ChargerState::Charging, InputEvent::RemoveCable) => Step::to(ChargerState::Faulted)
+            .with(&STOP)
+            .effect(Effect::RecordFault),
+        // A vehicle without the pilot diode may be a cheat device, never charge it
+        (
+            ChargerState::Preparing | ChargerState::Authorizing | ChargerState::Charging,
+            InputEvent::DiodeMissing,
+        ) => Step::to(ChargerState::Faulted)
+            .with(&STOP)
+            .effect(Effect::RecordFault),
+        // Power is removed whatever the state, the outputs can't be trusted. A ground fault and
+        // a switch failure are latched until cleared with the master card or remotely
+        (
+            _,
+            InputEvent::InvariantViolated
+            | InputEvent::GroundFault
+            | InputEvent::PowerSwitchFailure
+            | InputEvent::Overheated,
+        ) => Step::to(ChargerState::Faulted)
+            .with(&STOP)
+            .effect(Effect::RecordFault),
+        (ChargerState::Faulted, InputEvent::SwipeDetected) if c.master_card && c.safety_latched => {
+            if !c.safety_cleared {
+                Step::to(ChargerState::Faulted)
+            } else if c.lockout || c.maintenance {
+                info!("CHGR: Safety fault cleared with the master card, staying unavailable");
+                Step::to(ChargerState::Unavailable)
+            } else {
+                info!("CHGR: Safety fault cleared with the master card");
+                Step::to(ChargerState::Available)
+            }
+        }
+        (ChargerState::Faulted, _) if c.safety_latched => {
+            warn!("CHGR: Safety fault, faulted until cleared with the master card or remotely");
+            Step::to(ChargerState::Faulted)
+        }
+        (ChargerState::Faulted, _) if c.overheated => {
+            warn!("CHGR: Too hot, faulted until cooled down");
+            Step::to(ChargerState::Faulted)
+        }
+        (ChargerState::Faulted, InputEvent::RemoveCable) if c.diode_missing => {
+            let cleared = Conditions {
+                diode_missing: false,
+                ..*c
+            };
+            Step::to(recovered(&cleared)).effect(Effect::ClearDiodeFault)
+        }
+        (ChargerState::Faulted, _) if c.diode_missing => {
+            warn!("CHGR: Pilot diode missing, faulted until the cable is removed");
+            Step::to(ChargerState::Faulted)
+        }
+        (ChargerState::Faulted, _) if c.lockout => {
+            warn!("CHGR: Fault keeps recurring, latching unavailable until cleared");
+            Step::to(ChargerState::Unavailable).effect(Effect::ClearInputs)
+        }
+        (ChargerState::Faulted, _) => {
+            warn!(
+                "CHGR: Charger is in faulted state, resetting to available after {SETTLE_SECS} seconds"
+            );
+            Step::to(recovered(c)).effect(Effect::Settle)
+        }
+        (ChargerState::Unavailable, InputEvent::LockoutCleared) if c.maintenance => {
+            info!("CHGR: Lockout cleared remotely, staying unavailable for maintenance");
+            Step::to(ChargerState::Unavailable)
+        }
+        (ChargerState::Unavailable, InputEvent::LockoutCleared) => {
+            info!("CHGR: Lockout cleared remotely");
+            Step::to(ChargerState::Available)
+        }
+        (ChargerState::Unavailable, InputEvent::SwipeDetected) if c.master_card => {
+            info!("CHGR: Lockout cleared with the master card");
+            let state = if c.maintenance {
+                ChargerState::Unavailable
+            } else {
+                ChargerState::Available
+            };
+            Step::to(state).effect(Effect::ClearLockout)
+        }
+        // New sessions are refused during maintenance, a cable still starts a test
+        // session that only the master card can authorize
+        (ChargerState::Available | ChargerState::Reserved, InputEvent::MaintenanceStarted) => {
+            Step::to(ChargerState::Unavailable)
+        }
+        (ChargerState::Unavailable, InputEvent::InsertCable) if c.maintenance && !c.lockout => {
+            Step::to(ChargerState::Preparing)
+        }
+        (ChargerState::Unavailable, InputEvent::MaintenanceEnded) if c.lockout => {
+            Step::to(ChargerState::Unavailable)
+        }
+        (ChargerState::Unavailable, InputEvent::MaintenanceEnded) if c.reserved => {
+            Step::to(ChargerState::Reserved)
+        }
+        (ChargerState::Unavailable, InputEvent::MaintenanceEnded) => {
+            Step::to(ChargerState::Available)
+        }
+        // A session in progress continues, the charger becomes unavailable once it's done
+        (_, InputEvent::MaintenanceStarted | InputEvent::MaintenanceEnded) => Step::to(state),
+        // A reservation that ends while the connector is in use changes nothing
+        (_, InputEvent::ReservationEnded) => Step::to(state),
+        // The session already ended
+        (_, InputEvent::Deauthorized) => Step::to(state),
+        // Offline the charger keeps going, transaction messages are queued until it's back
+        (_, InputEvent::NetworkLost | InputEvent::NetworkRestored) => Step::to(state),
+        // Only a charger faulted for the temperature recovers
+        (_, InputEvent::Cooled) => Step::to(state),
+        // The session goes on, the waiting list holds the connector once it's done
+        (_, InputEvent::Queued) => Step::to(state),
+        _ => {
+            warn!("CHGR: Invalid or unknown transition from {state:?} with input {input:?}");
+            Step::to(state)
+        }
+    }
+}
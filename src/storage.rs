@@ -0,0 +1,425 @@
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_storage::FlashStorage;
+
+use crate::{
+    charger::{self, Charger, ChargerState},
+    info, utils, warn,
+};
+
+/// Flash region used for persistent records, the nvs partition in partitions.csv
+const REGION_OFFSET: u32 = 0x9000;
+const SECTOR_SIZE: u32 = 4096;
+const SLOT_COUNT: u32 = 12;
+/// Slots of the partition table before the guest codes, OTA updates don't change the table
+const LEGACY_SLOT_COUNT: u32 = 6;
+
+const RECORD_MAGIC: u32 = 0x4348_5247; // "CHRG"
+const HEADER_SIZE: usize = 12;
+
+/// Largest record that fits a slot
+pub const MAX_RECORD_SIZE: usize = SECTOR_SIZE as usize - HEADER_SIZE;
+
+/// Time between maintenance passes
+const MAINTENANCE_INTERVAL_SECS: u64 = 6 * 3600;
+/// Time the charger must have been Available before a pass starts
+const MAINTENANCE_IDLE_SECS: u64 = 300;
+/// Pause between the slots of a pass, so other tasks get the flash and the CPU
+const MAINTENANCE_STEP_MS: u64 = 200;
+
+/// Contents of an erased sector, written to reclaim one as FlashStorage erases it for the write
+static BLANK_SECTOR: [u8; SECTOR_SIZE as usize] = [0xFF; SECTOR_SIZE as usize];
+
+/// Persistent records, each stored in its own flash sector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Provisioning,
+    Reservation,
+    Sessions,
+    Settings,
+    Configuration,
+    Logo,
+    GuestCodes,
+    Energy,
+    Power,
+    Endpoints,
+    Watchdog,
+    Crash,
+}
+
+impl Slot {
+    pub const ALL: [Slot; SLOT_COUNT as usize] = [
+        Slot::Provisioning,
+        Slot::Reservation,
+        Slot::Sessions,
+        Slot::Settings,
+        Slot::Configuration,
+        Slot::Logo,
+        Slot::GuestCodes,
+        Slot::Energy,
+        Slot::Power,
+        Slot::Endpoints,
+        Slot::Watchdog,
+        Slot::Crash,
+    ];
+
+    fn index(&self) -> u32 {
+        match self {
+            Self::Provisioning => 0,
+            Self::Reservation => 1,
+            Self::Sessions => 2,
+            Self::Settings => 3,
+            Self::Configuration => 4,
+            Self::Logo => 5,
+            Self::GuestCodes => 6,
+            Self::Energy => 7,
+            Self::Power => 8,
+            Self::Endpoints => 9,
+            Self::Watchdog => 10,
+            Self::Crash => 11,
+        }
+    }
+
+    /// Offset of the slot, fails for a slot beyond the end of the nvs partition
+    fn offset(&self) -> Result<u32, &'static str> {
+        let index = self.index();
+        debug_assert!(index < SLOT_COUNT);
+        if index >= SLOTS_AVAILABLE.load(Ordering::Relaxed) {
+            return Err("Slot beyond the nvs partition");
+        }
+        Ok(REGION_OFFSET + index * SECTOR_SIZE)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Provisioning => "Provisioning",
+            Self::Reservation => "Reservation",
+            Self::Sessions => "Sessions",
+            Self::Settings => "Settings",
+            Self::Configuration => "Configuration",
+            Self::Logo => "Logo",
+            Self::GuestCodes => "GuestCodes",
+            Self::Energy => "Energy",
+            Self::Power => "Power",
+            Self::Endpoints => "Endpoints",
+            Self::Watchdog => "Watchdog",
+            Self::Crash => "Crash",
+        }
+    }
+}
+
+static FLASH: Mutex<CriticalSectionRawMutex, RefCell<Option<FlashStorage>>> =
+    Mutex::new(RefCell::new(None));
+/// Slots that fit the nvs partition, a board flashed with an older partition table has fewer
+static SLOTS_AVAILABLE: AtomicU32 = AtomicU32::new(0);
+/// Sector erases per slot since boot, every write of a record erases its sector
+static ERASE_CYCLES: [AtomicU32; SLOT_COUNT as usize] =
+    [const { AtomicU32::new(0) }; SLOT_COUNT as usize];
+static MAINTENANCE_STATS: Mutex<CriticalSectionRawMutex, Cell<MaintenanceStats>> =
+    Mutex::new(Cell::new(MaintenanceStats {
+        passes: 0,
+        preempted: 0,
+        reclaimed: 0,
+        last_pass_secs: None,
+    }));
+
+/// Counters of the background flash maintenance since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceStats {
+    /// Passes that checked every slot
+    pub passes: u32,
+    /// Passes cut short by a state change
+    pub preempted: u32,
+    /// Sectors blanked of a removed or corrupt record
+    pub reclaimed: u32,
+    /// Uptime of the last completed pass
+    pub last_pass_secs: Option<u64>,
+}
+
+pub fn maintenance_stats() -> MaintenanceStats {
+    MAINTENANCE_STATS.lock(|stats| stats.get())
+}
+
+fn update_maintenance_stats(f: impl FnOnce(&mut MaintenanceStats)) {
+    MAINTENANCE_STATS.lock(|stats| {
+        let mut updated = stats.get();
+        f(&mut updated);
+        stats.set(updated);
+    });
+}
+
+/// Sector erases of a slot since boot
+pub fn erase_cycles(slot: Slot) -> u32 {
+    ERASE_CYCLES[slot.index() as usize].load(Ordering::Relaxed)
+}
+
+fn count_erase(slot: Slot) {
+    ERASE_CYCLES[slot.index() as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Initialize access to the flash, must be called before reading or writing records
+pub fn init() {
+    FLASH.lock(|flash| {
+        flash.borrow_mut().replace(FlashStorage::new());
+    });
+
+    let slots = with_flash(|flash| {
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let table = partitions::read_partition_table(flash, &mut buffer)
+            .map_err(|_| "Failed to read partition table")?;
+        let nvs = table
+            .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+            .map_err(|_| "Failed to read partition table")?
+            .ok_or("No nvs partition")?;
+        if nvs.offset() != REGION_OFFSET {
+            return Err("The nvs partition moved");
+        }
+        Ok(nvs.len() / SECTOR_SIZE)
+    })
+    .unwrap_or_else(|e| {
+        warn!("STOR: {e}, assuming the original partition table");
+        LEGACY_SLOT_COUNT
+    });
+    if slots < SLOT_COUNT {
+        warn!(
+            "STOR: The nvs partition has room for {slots} of {SLOT_COUNT} slots, flash the \
+             partition table over serial"
+        );
+    }
+    SLOTS_AVAILABLE.store(slots.min(SLOT_COUNT), Ordering::Relaxed);
+    info!("STOR: Flash storage initialized");
+}
+
+/// Run a closure with exclusive access to the flash
+pub(crate) fn with_flash<R>(
+    f: impl FnOnce(&mut FlashStorage) -> Result<R, &'static str>,
+) -> Result<R, &'static str> {
+    FLASH.lock(|flash| match flash.borrow_mut().as_mut() {
+        Some(flash) => f(flash),
+        None => Err("Flash storage not initialized"),
+    })
+}
+
+/// Read a record into the buffer, returns the record length or None if the slot is empty
+pub fn read(slot: Slot, buffer: &mut [u8]) -> Result<Option<usize>, &'static str> {
+    let offset = slot.offset()?;
+    with_flash(|flash| {
+        let mut header = [0u8; HEADER_SIZE];
+        flash
+            .read(offset, &mut header)
+            .map_err(|_| "Failed to read record header")?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic != RECORD_MAGIC {
+            return Ok(None);
+        }
+
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if len > MAX_RECORD_SIZE || len > buffer.len() {
+            warn!("STOR: {} record too large ({len} bytes)", slot.as_str());
+            return Err("Record too large for buffer");
+        }
+
+        flash
+            .read(offset + HEADER_SIZE as u32, &mut buffer[..len])
+            .map_err(|_| "Failed to read record")?;
+
+        if utils::crc32(&buffer[..len]) != crc {
+            warn!("STOR: {} record is corrupt", slot.as_str());
+            return Ok(None);
+        }
+        Ok(Some(len))
+    })
+}
+
+/// Replace a record, the write is verified by reading it back
+pub fn write(slot: Slot, data: &[u8]) -> Result<(), &'static str> {
+    if data.len() > MAX_RECORD_SIZE {
+        return Err("Record too large");
+    }
+
+    let offset = slot.offset()?;
+    with_flash(|flash| {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&utils::crc32(data).to_le_bytes());
+
+        // FlashStorage erases the sector as needed, the data is written before the header
+        // so a power loss halfway fails the crc check instead of leaving a mixed record
+        flash
+            .write(offset + HEADER_SIZE as u32, data)
+            .map_err(|_| "Failed to write record")?;
+        flash
+            .write(offset, &header)
+            .map_err(|_| "Failed to write record header")?;
+
+        count_erase(slot);
+
+        // Verify the write by reading it back in chunks
+        let mut verify = [0u8; 64];
+        for (i, chunk) in data.chunks(verify.len()).enumerate() {
+            let chunk_offset = offset + (HEADER_SIZE + i * verify.len()) as u32;
+            flash
+                .read(chunk_offset, &mut verify[..chunk.len()])
+                .map_err(|_| "Failed to verify record")?;
+            if &verify[..chunk.len()] != chunk {
+                warn!("STOR: Verification of {} record failed", slot.as_str());
+                return Err("Record verification failed");
+            }
+        }
+        Ok(())
+    })?;
+
+    info!(
+        "STOR: Stored {} record ({} bytes)",
+        slot.as_str(),
+        data.len()
+    );
+    Ok(())
+}
+
+/// Remove a record, only its header is cleared and the data is left for maintenance to reclaim
+pub fn erase(slot: Slot) -> Result<(), &'static str> {
+    let offset = slot.offset()?;
+    with_flash(|flash| {
+        flash
+            .write(offset, &[0u8; HEADER_SIZE])
+            .map_err(|_| "Failed to erase record")
+    })?;
+    count_erase(slot);
+    info!("STOR: Erased {} record", slot.as_str());
+    Ok(())
+}
+
+/// Whether the sector at an offset holds a record that passes the crc check, read in chunks as
+/// records can be as large as the sector
+fn has_valid_record(flash: &mut FlashStorage, offset: u32) -> Result<bool, &'static str> {
+    let mut header = [0u8; HEADER_SIZE];
+    flash
+        .read(offset, &mut header)
+        .map_err(|_| "Failed to read record header")?;
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if magic != RECORD_MAGIC || len > MAX_RECORD_SIZE {
+        return Ok(false);
+    }
+
+    let mut buffer = [0u8; 64];
+    let chunk_len = buffer.len();
+    let mut computed = 0xFFFF_FFFF;
+    for start in (0..len).step_by(chunk_len) {
+        let chunk = &mut buffer[..(len - start).min(chunk_len)];
+        flash
+            .read(offset + (HEADER_SIZE + start) as u32, chunk)
+            .map_err(|_| "Failed to read record")?;
+        computed = utils::crc32_update(computed, chunk);
+    }
+    Ok(!computed == crc)
+}
+
+/// Blank the sector of a slot without a valid record, returns true when it held leftover data
+fn reclaim(slot: Slot) -> Result<bool, &'static str> {
+    // A slot beyond the nvs partition has no sector to reclaim
+    let Ok(offset) = slot.offset() else {
+        return Ok(false);
+    };
+    with_flash(|flash| {
+        if has_valid_record(flash, offset)? {
+            return Ok(false);
+        }
+        let mut buffer = [0u8; 64];
+        let mut blank = true;
+        for chunk in (0..SECTOR_SIZE).step_by(buffer.len()) {
+            flash
+                .read(offset + chunk, &mut buffer)
+                .map_err(|_| "Failed to read sector")?;
+            if buffer.iter().any(|byte| *byte != 0xFF) {
+                blank = false;
+                break;
+            }
+        }
+        if blank {
+            return Ok(false);
+        }
+        flash
+            .write(offset, &BLANK_SECTOR)
+            .map_err(|_| "Failed to blank sector")?;
+        Ok(true)
+    })
+    .inspect(|reclaimed| {
+        if *reclaimed {
+            count_erase(slot);
+            info!("STOR: Reclaimed the {} sector", slot.as_str());
+        }
+    })
+}
+
+/// Task to reclaim the sectors of removed or corrupt records while the charger is idle, a
+/// pass stops at the first state change so it never delays a session
+#[embassy_executor::task]
+pub async fn storage_maintenance_task(charger: &'static Charger) {
+    info!("TASK: Started Flash Maintenance");
+
+    let mut subscriber = charger::STATE_PUBSUB.subscriber().unwrap();
+    loop {
+        Timer::after(Duration::from_secs(MAINTENANCE_INTERVAL_SECS)).await;
+
+        // Wait until the charger has been Available for a while, changes while waiting are
+        // seen through the current state
+        loop {
+            while subscriber.try_next_message_pure().is_some() {}
+            if charger.get_state().await != ChargerState::Available {
+                subscriber.next_message_pure().await;
+                continue;
+            }
+            let idle = Timer::after(Duration::from_secs(MAINTENANCE_IDLE_SECS));
+            if let Either::Second(()) = select(subscriber.next_message_pure(), idle).await {
+                break;
+            }
+        }
+
+        info!("STOR: Starting flash maintenance");
+        let mut reclaimed = 0;
+        let mut preempted = false;
+        for slot in Slot::ALL {
+            if subscriber.try_next_message_pure().is_some() {
+                preempted = true;
+                break;
+            }
+            match reclaim(slot) {
+                Ok(true) => reclaimed += 1,
+                Ok(false) => {}
+                // The next pass tries again
+                Err(e) => warn!("STOR: Maintenance of {} skipped: {e}", slot.as_str()),
+            }
+            Timer::after(Duration::from_millis(MAINTENANCE_STEP_MS)).await;
+        }
+
+        update_maintenance_stats(|stats| {
+            stats.reclaimed += reclaimed;
+            if preempted {
+                stats.preempted += 1;
+            } else {
+                stats.passes += 1;
+                stats.last_pass_secs = Some(Instant::now().as_secs());
+            }
+        });
+        if preempted {
+            info!("STOR: Flash maintenance preempted by a state change, {reclaimed} reclaimed");
+        } else {
+            info!("STOR: Flash maintenance done, {reclaimed} sectors reclaimed");
+        }
+    }
+}
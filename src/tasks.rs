@@ -0,0 +1,282 @@
+use crate::{charger, info, smart_charging, warn};
+
+/// Size of the arena holding the state of all tasks, the `task-arena-size` feature of
+/// embassy-executor in Cargo.toml. Tasks have no stack of their own
+pub const TASK_ARENA_SIZE: usize = 32768;
+
+/// Channels, queues and signals shared between tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    StatePubSub,
+    StateIn,
+    LimitPubSub,
+    MqttSend,
+    MqttReceive,
+    MqttTelemetry,
+    MqttCmd,
+    MqttOta,
+    MqttLoad,
+    MqttAnnounce,
+    MqttGrid,
+    MqttLog,
+    ConnectionSignal,
+    DisplayEvents,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 14] = [
+        Resource::StatePubSub,
+        Resource::StateIn,
+        Resource::LimitPubSub,
+        Resource::MqttSend,
+        Resource::MqttReceive,
+        Resource::MqttTelemetry,
+        Resource::MqttCmd,
+        Resource::MqttOta,
+        Resource::MqttLoad,
+        Resource::MqttAnnounce,
+        Resource::MqttGrid,
+        Resource::MqttLog,
+        Resource::ConnectionSignal,
+        Resource::DisplayEvents,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::StatePubSub => "STATE_PUBSUB",
+            Self::StateIn => "STATE_IN_CHANNEL",
+            Self::LimitPubSub => "LIMIT_PUBSUB",
+            Self::MqttSend => "MQTT_SEND_QUEUE",
+            Self::MqttReceive => "MQTT_RECEIVE_CHANNEL",
+            Self::MqttTelemetry => "MQTT_TELEMETRY_CHANNEL",
+            Self::MqttCmd => "MQTT_CMD_CHANNEL",
+            Self::MqttOta => "MQTT_OTA_CHANNEL",
+            Self::MqttLoad => "MQTT_LOAD_CHANNEL",
+            Self::MqttAnnounce => "MQTT_ANNOUNCE_CHANNEL",
+            Self::MqttGrid => "MQTT_GRID_CHANNEL",
+            Self::MqttLog => "MQTT_LOG_CHANNEL",
+            Self::ConnectionSignal => "CONNECTION_SIGNAL",
+            Self::DisplayEvents => "DISPLAY_CHANNEL",
+        }
+    }
+}
+
+/// How a task uses a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Sends to a channel or queue, or signals
+    Send,
+    /// Receives from a channel or queue, or waits for a signal
+    Receive,
+    /// Holds a publisher of a pubsub channel
+    Publish,
+    /// Holds a subscriber of a pubsub channel
+    Subscribe,
+}
+
+impl Access {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Send => "send",
+            Self::Receive => "receive",
+            Self::Publish => "publish",
+            Self::Subscribe => "subscribe",
+        }
+    }
+}
+
+/// A task of the firmware and the shared resources it uses
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub module: &'static str,
+    pub name: &'static str,
+    pub uses: &'static [(Resource, Access)],
+}
+
+/// Declare the tasks of the firmware, each with the resources it uses
+macro_rules! task_registry {
+    ($($module:ident::$name:ident { $($resource:ident: $access:ident),* $(,)? })*) => {
+        /// Every task the firmware can spawn, tasks that depend on the configuration included
+        pub const TASKS: &[TaskInfo] = &[$(TaskInfo {
+            module: stringify!($module),
+            name: stringify!($name),
+            uses: &[$((Resource::$resource, Access::$access)),*],
+        }),*];
+    };
+}
+
+// Keep in sync with the spawns in main.rs and network.rs, a task that subscribes to
+// STATE_PUBSUB must be listed here so the subscriber slots are checked at build time
+task_registry! {
+    main::main { StatePubSub: Publish, ConnectionSignal: Receive }
+    leds::led_task { StatePubSub: Subscribe }
+    main::display_task { StatePubSub: Subscribe, DisplayEvents: Receive }
+    buzzer::buzzer_task { StatePubSub: Subscribe }
+    main::charger_cable_task { StateIn: Send }
+    button::button_task { StateIn: Send, DisplayEvents: Send }
+    relay::relay_task { StatePubSub: Subscribe }
+    relay::relay_feedback_task { StateIn: Send }
+    relay::relay_deadline_task { StateIn: Send }
+    demand_response::demand_response_task { MqttSend: Send }
+    rcd::rcd_task { StateIn: Send }
+    main::cable_lock_task { StatePubSub: Subscribe }
+    rfid::card_swipe_task { StateIn: Send, MqttSend: Send, DisplayEvents: Send }
+    charger::statemachine_handler_task {
+        StateIn: Receive,
+        StatePubSub: Publish,
+        DisplayEvents: Send,
+    }
+    ocpp::authorize_task { StatePubSub: Subscribe, StateIn: Send, MqttSend: Send }
+    ocpp::status_notification_task { StatePubSub: Subscribe, MqttSend: Send }
+    ocpp::transaction_handler_task { StatePubSub: Subscribe, MqttSend: Send, DisplayEvents: Send }
+    ocpp::response_handler_task { MqttReceive: Receive, StateIn: Send, MqttSend: Send }
+    ocpp::deferred_call_task { MqttSend: Send }
+    ocpp::heartbeat_task { MqttSend: Send }
+    ocpp::meter_values_task { MqttSend: Send }
+    ocpp::boot_notification_task { MqttSend: Send }
+    idempotency::start_retry_task { MqttSend: Send }
+    demo::demo_backend_task { MqttReceive: Send }
+    network::connection_task {}
+    network::net_task {}
+    mqtt::mqtt_client_task {
+        MqttSend: Receive,
+        MqttReceive: Send,
+        MqttCmd: Send,
+        MqttOta: Send,
+        MqttLoad: Send,
+        MqttGrid: Send,
+        MqttTelemetry: Receive,
+        MqttAnnounce: Receive,
+        MqttLog: Receive,
+        ConnectionSignal: Send,
+    }
+    mqtt::analytics_client_task { MqttTelemetry: Receive }
+    telemetry::telemetry_task { MqttTelemetry: Send }
+    command::command_handler_task { MqttCmd: Receive, StateIn: Send, MqttSend: Send }
+    usb_api::usb_api_task { StateIn: Send, MqttSend: Send }
+    maintenance::maintenance_expiry_task { StateIn: Send }
+    connectivity::connectivity_watcher_task { StateIn: Send, DisplayEvents: Send }
+    reservation::reservation_expiry_task { StateIn: Send }
+    pilot::pilot_diode_task { StateIn: Send }
+    pilot::pilot_pwm_task { StatePubSub: Subscribe, LimitPubSub: Subscribe }
+    sensors::sensors_task { StateIn: Send }
+    invariant::invariant_task { StateIn: Send }
+    smart_charging::limit_watchdog_task { LimitPubSub: Publish }
+    meter::meter_task {}
+    metering::metering_task {}
+    energy::energy_task { MqttSend: Send }
+    smart_charging::soft_start_task {}
+    load_management::load_management_task { MqttLoad: Receive, MqttAnnounce: Send }
+    solar::solar_task { MqttGrid: Receive }
+    waiting_list::waiting_list_task { StateIn: Send, MqttSend: Send }
+    watchdog::watchdog_task {}
+    ntp::ntp_sync_task {}
+    settings::settings_trial_task { MqttSend: Send }
+    storage::storage_maintenance_task { StatePubSub: Subscribe }
+    ota::firmware_update_task { MqttSend: Send, DisplayEvents: Send }
+    diagnostics::diagnostics_upload_task { MqttSend: Send }
+    http_server::http_server_task {}
+    mdns::mdns_task {}
+    webhook::webhook_task {}
+    log_forward::log_forward_task { MqttLog: Send }
+    ble_provisioning::ble_provisioning_task {}
+    factory_test::run { StatePubSub: Subscribe, StateIn: Send }
+}
+
+/// Number of tasks using a resource in a way, for the checks at build time
+const fn count(resource: Resource, access: Access) -> usize {
+    let mut count = 0;
+    let mut i = 0;
+    while i < TASKS.len() {
+        let mut j = 0;
+        while j < TASKS[i].uses.len() {
+            let (used, how) = TASKS[i].uses[j];
+            if used as u8 == resource as u8 && how as u8 == access as u8 {
+                count += 1;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    count
+}
+
+/// Whether a task lists the same use twice, e.g. a second subscriber it doesn't need
+const fn has_duplicate_use() -> bool {
+    let mut i = 0;
+    while i < TASKS.len() {
+        let uses = TASKS[i].uses;
+        let mut j = 0;
+        while j < uses.len() {
+            let mut k = j + 1;
+            while k < uses.len() {
+                if uses[j].0 as u8 == uses[k].0 as u8 && uses[j].1 as u8 == uses[k].1 as u8 {
+                    return true;
+                }
+                k += 1;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    false
+}
+
+const _: () = assert!(
+    count(Resource::StatePubSub, Access::Subscribe) <= charger::STATE_SUBSCRIBERS,
+    "More tasks subscribe to STATE_PUBSUB than it has subscriber slots"
+);
+const _: () = assert!(
+    count(Resource::StatePubSub, Access::Publish) <= charger::STATE_PUBLISHERS,
+    "More tasks publish to STATE_PUBSUB than it has publisher slots"
+);
+const _: () = assert!(
+    count(Resource::LimitPubSub, Access::Subscribe) <= smart_charging::LIMIT_SUBSCRIBERS,
+    "More tasks subscribe to LIMIT_PUBSUB than it has subscriber slots"
+);
+const _: () = assert!(
+    !has_duplicate_use(),
+    "A task lists the same resource use twice"
+);
+
+/// Log the task topology, for the `tasks` command
+pub fn log_tasks() {
+    info!(
+        "TASK: {} tasks share a {TASK_ARENA_SIZE} byte arena on the thread-mode executor",
+        TASKS.len()
+    );
+    for task in TASKS {
+        let mut uses = heapless::String::<192>::new();
+        for (i, (resource, access)) in task.uses.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            let _ = core::fmt::Write::write_fmt(
+                &mut uses,
+                format_args!("{separator}{} {}", resource.as_str(), access.as_str()),
+            );
+        }
+        info!("TASK: {}::{} [{uses}]", task.module, task.name);
+    }
+
+    info!(
+        "TASK: STATE_PUBSUB {}/{} subscribers, {}/{} publishers",
+        count(Resource::StatePubSub, Access::Subscribe),
+        charger::STATE_SUBSCRIBERS,
+        count(Resource::StatePubSub, Access::Publish),
+        charger::STATE_PUBLISHERS
+    );
+    for resource in Resource::ALL {
+        let (sent, received) = match resource {
+            Resource::StatePubSub | Resource::LimitPubSub => (
+                count(resource, Access::Publish),
+                count(resource, Access::Subscribe),
+            ),
+            _ => (
+                count(resource, Access::Send),
+                count(resource, Access::Receive),
+            ),
+        };
+        if sent > 0 && received == 0 {
+            warn!("TASK: {} has no task receiving from it", resource.as_str());
+        }
+    }
+}
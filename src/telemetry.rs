@@ -0,0 +1,135 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    charger::{Charger, ChargerState},
+    connectivity, demand_response, info, mqtt, sensors, warn, wifi_monitor, wire,
+};
+
+/// Most heap in use at any sample since boot
+static HEAP_HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+
+/// Sample the heap usage, returns the bytes in use now
+pub fn record_heap_usage() -> usize {
+    let used = esp_alloc::HEAP.used();
+    HEAP_HIGH_WATER.fetch_max(used, Ordering::Relaxed);
+    used
+}
+
+/// Most heap in use since boot, in bytes
+pub fn heap_high_water() -> usize {
+    HEAP_HIGH_WATER.load(Ordering::Relaxed)
+}
+
+/// Device metrics published on the telemetry topic
+#[derive(Debug, Clone, Copy)]
+pub struct Metrics {
+    pub heap_free: usize,
+    pub heap_high_water: usize,
+    pub rssi: Option<i32>, // dBm, None while not associated
+    pub uptime_secs: u64,
+    pub temperature: Option<f32>,     // Chip temperature in °C
+    pub ntc_temperature: Option<f32>, // External NTC in °C, None without one
+    pub state: ChargerState,
+    pub backend_degraded: bool, // The central system stopped answering
+    pub demand_response: bool,  // The utility asks to shed the load
+    pub mqtt_stalls: u32,       // MQTT sessions torn down for a send queue that stayed full
+}
+
+impl Metrics {
+    /// Coarse metrics for anonymized telemetry: heap in whole KB, RSSI in steps of 10 dB,
+    /// uptime in whole hours and temperature in whole degrees
+    pub fn coarse(self) -> Self {
+        let whole = |celsius: f32| {
+            let half = if celsius < 0.0 { -0.5 } else { 0.5 };
+            (celsius + half) as i32 as f32
+        };
+        Self {
+            heap_free: self.heap_free / 1024 * 1024,
+            heap_high_water: self.heap_high_water / 1024 * 1024,
+            rssi: self.rssi.map(|rssi| rssi / 10 * 10),
+            uptime_secs: self.uptime_secs / 3600 * 3600,
+            temperature: self.temperature.map(whole),
+            ntc_temperature: self.ntc_temperature.map(whole),
+            state: self.state,
+            backend_degraded: self.backend_degraded,
+            demand_response: self.demand_response,
+            mqtt_stalls: self.mqtt_stalls,
+        }
+    }
+
+    pub fn to_json(&self) -> heapless::String<256> {
+        let mut json = heapless::String::new();
+        let _ = write!(
+            json,
+            "{{\"heap_free\":{},\"heap_high_water\":{},\"rssi\":",
+            self.heap_free, self.heap_high_water
+        );
+        let _ = match self.rssi {
+            Some(rssi) => write!(json, "{rssi}"),
+            None => write!(json, "null"),
+        };
+        let _ = write!(json, ",\"uptime\":{}", self.uptime_secs);
+        for (key, celsius) in [
+            ("temperature", self.temperature),
+            ("ntc_temperature", self.ntc_temperature),
+        ] {
+            let _ = match celsius {
+                Some(celsius) => write!(json, ",\"{key}\":{celsius:.1}"),
+                None => write!(json, ",\"{key}\":null"),
+            };
+        }
+        let _ = json.push(',');
+        let _ = wire::write_json(&mut json, "state", self.state);
+        let _ = write!(json, ",\"backend_degraded\":{}", self.backend_degraded);
+        let _ = write!(json, ",\"demand_response\":{}", self.demand_response);
+        let _ = write!(json, ",\"mqtt_stalls\":{}", self.mqtt_stalls);
+        let _ = json.push('}');
+        json
+    }
+}
+
+/// Task to publish the device metrics periodically, through its own channel so telemetry
+/// never takes a slot of the OCPP messages
+#[embassy_executor::task]
+pub async fn telemetry_task(charger: &'static Charger, interval_secs: u16, anonymized: bool) {
+    info!(
+        "TASK: Started Telemetry Publisher every {interval_secs}s{}",
+        if anonymized { ", anonymized" } else { "" }
+    );
+
+    loop {
+        Timer::after(Duration::from_secs(interval_secs.max(1).into())).await;
+
+        record_heap_usage();
+        let metrics = Metrics {
+            heap_free: esp_alloc::HEAP.free(),
+            heap_high_water: heap_high_water(),
+            rssi: wifi_monitor::rssi(),
+            uptime_secs: Instant::now().as_secs(),
+            temperature: sensors::chip_temperature(),
+            ntc_temperature: sensors::ntc_temperature(),
+            state: charger.get_state().await,
+            backend_degraded: connectivity::is_backend_degraded(),
+            demand_response: demand_response::is_active(),
+            mqtt_stalls: mqtt::stats().send_stalls,
+        };
+        let metrics = if anonymized {
+            metrics.coarse()
+        } else {
+            metrics
+        };
+
+        // A pending sample that wasn't published yet is stale, replace it
+        mqtt::MQTT_TELEMETRY_CHANNEL.clear();
+        if mqtt::MQTT_TELEMETRY_CHANNEL
+            .try_send(heapless::Vec::from_slice(metrics.to_json().as_bytes()).unwrap())
+            .is_err()
+        {
+            warn!("TELE: Failed to queue telemetry");
+        }
+    }
+}
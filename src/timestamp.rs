@@ -0,0 +1,147 @@
+//! Conversions between Unix times, NTP timestamps and ISO8601 strings. Pure like the state
+//! machine, the tests in `host/` build it for the host and walk the era and year boundaries
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const NTP_EPOCH_OFFSET: u32 = 2_208_988_800;
+
+/// Unix time in milliseconds of an NTP timestamp, None for a timestamp of 0 (not set). The
+/// seconds wrap in 2036, a timestamp with the top bit clear is in the next era (RFC 4330)
+pub fn ntp_to_unix_ms(timestamp: u64) -> Option<u64> {
+    if timestamp == 0 {
+        return None;
+    }
+    // Upper 32 bits are seconds, lower 32 bits are fractional seconds
+    let ntp_seconds = timestamp >> 32;
+    let ntp_seconds = if ntp_seconds & 0x8000_0000 == 0 {
+        ntp_seconds + (1 << 32)
+    } else {
+        ntp_seconds
+    };
+    let millis = ((timestamp & 0xFFFF_FFFF) * 1000) >> 32;
+    Some((ntp_seconds - u64::from(NTP_EPOCH_OFFSET)) * 1000 + millis)
+}
+
+/// NTP timestamp of a Unix time in milliseconds, the seconds wrap at the end of the era. The
+/// fraction is rounded up, so converting it back gives the same millisecond
+pub fn unix_ms_to_ntp(unix_ms: u64) -> u64 {
+    let seconds = ((unix_ms / 1000) as u32).wrapping_add(NTP_EPOCH_OFFSET);
+    let fraction = ((unix_ms % 1000) << 32).div_ceil(1000);
+    u64::from(seconds) << 32 | fraction
+}
+
+/// Format a Unix time as ISO8601, e.g. `2024-01-01T12:00:00Z`
+pub fn format_iso8601(timestamp: u64) -> heapless::String<32> {
+    if timestamp == 0 {
+        let mut result = heapless::String::new();
+        result.push_str("1970-01-01T00:00:00Z").unwrap();
+        return result;
+    }
+
+    // Convert Unix timestamp to date and time components
+    let mut result = heapless::String::new();
+
+    // Calculate days since Unix epoch
+    let days_since_epoch = (timestamp / 86400) as u32; // 86400 seconds in a day
+    let seconds_in_day = (timestamp % 86400) as u32;
+
+    // Calculate hours, minutes, seconds
+    let hours = seconds_in_day / 3600;
+    let minutes = (seconds_in_day % 3600) / 60;
+    let seconds = seconds_in_day % 60;
+
+    // Calculate year, month, day from days since epoch
+    let (year, month, day) = days_to_date(days_since_epoch);
+
+    // Format as ISO8601: YYYY-MM-DDTHH:MM:SSZ
+    write_u32_padded(&mut result, year, 4);
+    result.push('-').unwrap();
+    write_u32_padded(&mut result, month, 2);
+    result.push('-').unwrap();
+    write_u32_padded(&mut result, day, 2);
+    result.push('T').unwrap();
+    write_u32_padded(&mut result, hours, 2);
+    result.push(':').unwrap();
+    write_u32_padded(&mut result, minutes, 2);
+    result.push(':').unwrap();
+    write_u32_padded(&mut result, seconds, 2);
+    result.push('Z').unwrap();
+
+    result
+}
+
+fn write_u32_padded(s: &mut heapless::String<32>, num: u32, width: usize) {
+    let mut temp = heapless::String::<12>::new();
+    write_u32_to_temp(&mut temp, num);
+
+    // Add leading zeros if needed
+    for _ in temp.len()..width {
+        s.push('0').unwrap();
+    }
+
+    s.push_str(&temp).unwrap();
+}
+
+fn write_u32_to_temp(s: &mut heapless::String<12>, mut num: u32) {
+    if num == 0 {
+        s.push('0').unwrap();
+        return;
+    }
+
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+
+    while num > 0 && count < 10 {
+        digits[count] = (num % 10) as u8 + b'0';
+        num /= 10;
+        count += 1;
+    }
+
+    for i in (0..count).rev() {
+        if s.push(digits[i] as char).is_err() {
+            break;
+        }
+    }
+}
+
+fn days_to_date(mut days: u32) -> (u32, u32, u32) {
+    // Start from 1970
+    let mut year = 1970;
+
+    // Handle full years
+    loop {
+        let days_in_year = if is_leap_year(year) { 366 } else { 365 };
+        if days >= days_in_year {
+            days -= days_in_year;
+            year += 1;
+        } else {
+            break;
+        }
+    }
+
+    // Days in each month (non-leap year)
+    const DAYS_IN_MONTH: [u32; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+    let mut month = 1;
+    for &days_in_month in &DAYS_IN_MONTH {
+        let actual_days = if month == 2 && is_leap_year(year) {
+            29 // February in leap year
+        } else {
+            days_in_month
+        };
+
+        if days >= actual_days {
+            days -= actual_days;
+            month += 1;
+        } else {
+            break;
+        }
+    }
+
+    let day = days + 1; // Day is 1-indexed
+
+    (year, month, day)
+}
+
+fn is_leap_year(year: u32) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
@@ -0,0 +1,275 @@
+use chrono::{Datelike, NaiveDate};
+
+/// Named timezones with their POSIX TZ rules, a name not in the table can be configured as a
+/// POSIX TZ string directly
+const NAMED_ZONES: [(&str, &str); 18] = [
+    ("UTC", "UTC0"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Dublin", "IST-1GMT0,M10.5.0,M3.5.0/1"),
+    ("Europe/Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Brussels", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Oslo", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Athens", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Phoenix", "MST7"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+];
+
+/// Day a daylight saving change happens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Day {
+    /// `Mm.w.d`: weekday `d` (0 is Sunday) of week `w` of month `m`, week 5 is the last
+    MonthWeekDay { month: u32, week: u32, weekday: u32 },
+    /// `Jn`: day 1 to 365, February 29 is never counted
+    Julian(u32),
+    /// `n`: day 0 to 365, counting February 29
+    ZeroBased(u32),
+}
+
+/// A daylight saving change, at a local time in seconds after midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Change {
+    day: Day,
+    time_secs: i32,
+}
+
+impl Change {
+    /// Local time of the change in a year, as seconds since the Unix epoch
+    fn local_secs(&self, year: i32) -> Option<i64> {
+        let date = match self.day {
+            Day::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let first_weekday = first.weekday().num_days_from_sunday();
+                let mut day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+                // Week 5 is the last occurrence, which may be the fourth
+                while NaiveDate::from_ymd_opt(year, month, day).is_none() {
+                    day -= 7;
+                }
+                NaiveDate::from_ymd_opt(year, month, day)?
+            }
+            Day::Julian(day) => {
+                let leap_day = NaiveDate::from_ymd_opt(year, 2, 29).is_some() && day >= 60;
+                NaiveDate::from_yo_opt(year, day + u32::from(leap_day))?
+            }
+            Day::ZeroBased(day) => NaiveDate::from_yo_opt(year, day + 1)?,
+        };
+        let midnight = date.and_hms_opt(0, 0, 0)?.and_utc().timestamp();
+        Some(midnight + i64::from(self.time_secs))
+    }
+}
+
+/// Daylight saving time of a timezone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Dst {
+    offset_secs: i32,
+    start: Change,
+    end: Change,
+}
+
+/// Timezone of the local time, a fixed offset from UTC or one with daylight saving time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeZone {
+    /// Standard offset, east of UTC
+    offset_secs: i32,
+    dst: Option<Dst>,
+}
+
+impl TimeZone {
+    /// A fixed offset from UTC in hours, e.g. +1 for CET
+    pub fn fixed(hours: i8) -> Self {
+        Self {
+            offset_secs: i32::from(hours) * 3600,
+            dst: None,
+        }
+    }
+
+    /// A timezone from the table, e.g. `Europe/Amsterdam`, or a POSIX TZ string, e.g.
+    /// `CET-1CEST,M3.5.0,M10.5.0/3`
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let rules = NAMED_ZONES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(value))
+            .map_or(value, |(_, rules)| *rules);
+        Self::parse_posix(rules)
+    }
+
+    fn parse_posix(value: &str) -> Option<Self> {
+        let mut parser = Parser(value.as_bytes());
+        parser.name()?;
+        // POSIX offsets are west of UTC
+        let offset_secs = -parser.time()?;
+        if parser.0.is_empty() {
+            return Some(Self {
+                offset_secs,
+                dst: None,
+            });
+        }
+
+        parser.name()?;
+        let dst_offset_secs = match parser.0.first() {
+            Some(b',') => offset_secs + 3600,
+            _ => -parser.time()?,
+        };
+        parser.expect(b',')?;
+        let start = parser.change()?;
+        parser.expect(b',')?;
+        let end = parser.change()?;
+        if !parser.0.is_empty() {
+            return None;
+        }
+        Some(Self {
+            offset_secs,
+            dst: Some(Dst {
+                offset_secs: dst_offset_secs,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Offset from UTC in seconds at a Unix time, with daylight saving time when in effect
+    pub fn offset_at(&self, unix_timestamp: i64) -> i32 {
+        let Some(dst) = self.dst else {
+            return self.offset_secs;
+        };
+        let Some(year) =
+            chrono::DateTime::from_timestamp(unix_timestamp + i64::from(self.offset_secs), 0)
+                .map(|local| local.year())
+        else {
+            return self.offset_secs;
+        };
+        // The start is in standard time and the end in daylight saving time
+        let (Some(start), Some(end)) = (dst.start.local_secs(year), dst.end.local_secs(year))
+        else {
+            return self.offset_secs;
+        };
+        let start = start - i64::from(self.offset_secs);
+        let end = end - i64::from(dst.offset_secs);
+        let in_dst = if start < end {
+            (start..end).contains(&unix_timestamp)
+        } else {
+            // Southern hemisphere, daylight saving time spans the new year
+            unix_timestamp < end || unix_timestamp >= start
+        };
+        if in_dst {
+            dst.offset_secs
+        } else {
+            self.offset_secs
+        }
+    }
+}
+
+/// Parser of the parts of a POSIX TZ string
+struct Parser<'a>(&'a [u8]);
+
+impl Parser<'_> {
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        let (&first, rest) = self.0.split_first()?;
+        if first != byte {
+            return None;
+        }
+        self.0 = rest;
+        Some(())
+    }
+
+    /// A zone abbreviation, letters or anything quoted in `<>`, e.g. `CET` or `<+03>`
+    fn name(&mut self) -> Option<()> {
+        let len = if self.0.first() == Some(&b'<') {
+            self.0.iter().position(|&b| b == b'>')? + 1
+        } else {
+            self.0
+                .iter()
+                .take_while(|b| b.is_ascii_alphabetic())
+                .count()
+        };
+        if len < 3 {
+            return None;
+        }
+        self.0 = &self.0[len..];
+        Some(())
+    }
+
+    fn number(&mut self) -> Option<i32> {
+        let len = self.0.iter().take_while(|b| b.is_ascii_digit()).count();
+        let digits = core::str::from_utf8(&self.0[..len]).ok()?;
+        self.0 = &self.0[len..];
+        digits.parse().ok()
+    }
+
+    /// A time as `[+-]hh[:mm[:ss]]` in seconds
+    fn time(&mut self) -> Option<i32> {
+        let sign = match self.0.first() {
+            Some(b'-') => -1,
+            Some(b'+') => 1,
+            _ => 0,
+        };
+        if sign != 0 {
+            self.0 = &self.0[1..];
+        }
+        let mut secs = self.number()? * 3600;
+        for scale in [60, 1] {
+            if self.expect(b':').is_none() {
+                break;
+            }
+            secs += self.number()? * scale;
+        }
+        Some(if sign < 0 { -secs } else { secs })
+    }
+
+    /// A daylight saving change, `Mm.w.d`, `Jn` or `n` with an optional `/time`, by default
+    /// at 02:00
+    fn change(&mut self) -> Option<Change> {
+        let day = match self.0.first()? {
+            b'M' => {
+                self.0 = &self.0[1..];
+                let month = self.number()?;
+                self.expect(b'.')?;
+                let week = self.number()?;
+                self.expect(b'.')?;
+                let weekday = self.number()?;
+                if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                    return None;
+                }
+                Day::MonthWeekDay {
+                    month: month as u32,
+                    week: week as u32,
+                    weekday: weekday as u32,
+                }
+            }
+            b'J' => {
+                self.0 = &self.0[1..];
+                let day = self.number()?;
+                if !(1..=365).contains(&day) {
+                    return None;
+                }
+                Day::Julian(day as u32)
+            }
+            _ => {
+                let day = self.number()?;
+                if !(0..=365).contains(&day) {
+                    return None;
+                }
+                Day::ZeroBased(day as u32)
+            }
+        };
+        let time_secs = if self.expect(b'/').is_some() {
+            self.time()?
+        } else {
+            2 * 3600
+        };
+        Some(Change { day, time_secs })
+    }
+}
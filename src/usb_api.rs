@@ -0,0 +1,306 @@
+use embassy_time::Instant;
+use embedded_io_async::Read;
+use esp_hal::{usb_serial_jtag::UsbSerialJtagRx, Async};
+use log::Level;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    charger::Charger, command, info, logging, network::NetworkStack, ocpp, ocpp_config, sessions,
+    telemetry, version, warn, wifi_monitor, wire::WireFormat,
+};
+
+/// Version of the message schema, raised when a message changes. Variants are only appended, a
+/// request of another version is answered with `Response::Unsupported`
+pub const SCHEMA_VERSION: u16 = 1;
+/// Largest frame in either direction, COBS encoded with its delimiter
+const MAX_FRAME: usize = 512;
+
+/// A frame on the port, postcard encoded and COBS framed with a 0x00 delimiter. The body is the
+/// postcard encoded `Request` or `Response` of the schema version, the id of a request is
+/// repeated in each of its responses
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame<'a> {
+    version: u16,
+    id: u16,
+    body: &'a [u8],
+}
+
+/// Requests of the desktop tool
+#[derive(Debug, Deserialize)]
+enum Request<'a> {
+    /// Answered with `Hello`, to find the schema version of the firmware
+    Hello,
+    /// Answered with `Status`
+    Status,
+    /// Answered with `Config`, or `Error` for an unknown key
+    ConfigGet { key: &'a str },
+    /// Answered with `ConfigChanged`
+    ConfigSet { key: &'a str, value: &'a str },
+    /// The buffered log lines of the level and above, 1 (error) to 5 (trace), answered with a
+    /// `Log` per line and `Done`
+    Logs { level: u8 },
+    /// The session history, answered with a `Session` per session and `Done`
+    Sessions,
+    /// A command as on the cmd topic, e.g. `{"command":"maintenance","hours":4}`, answered
+    /// with `Done` or `Error`
+    Command { json: &'a str },
+}
+
+/// Responses of the firmware
+#[derive(Debug, Serialize)]
+enum Response<'a> {
+    Hello {
+        schema: u16,
+        firmware: &'a str,
+        serial: &'a str,
+    },
+    Status {
+        /// Wire id of the charger state
+        state: u8,
+        /// 0 without a transaction
+        transaction_id: i32,
+        uptime_secs: u64,
+        ip: Option<[u8; 4]>,
+        rssi: Option<i32>,
+        heap_free: u32,
+    },
+    Config {
+        key: &'a str,
+        value: &'a str,
+        read_only: bool,
+    },
+    ConfigChanged {
+        /// Accepted, Rejected, RebootRequired or NotSupported as in ChangeConfiguration
+        status: &'a str,
+    },
+    Log {
+        uptime_ms: u64,
+        level: u8,
+        text: &'a str,
+    },
+    Session {
+        transaction_id: i32,
+        id_tag: &'a str,
+        /// Unix time, 0 when the clock wasn't synced
+        started: u32,
+        stopped: u32,
+        duration_secs: u32,
+        meter_start_wh: u32,
+        meter_stop_wh: u32,
+    },
+    /// End of a request, with the number of responses before it
+    Done {
+        count: u16,
+    },
+    Error {
+        message: &'a str,
+    },
+    /// The request is of another schema version, with the version of the firmware
+    Unsupported {
+        schema: u16,
+    },
+}
+
+/// Send a response to a request, a 0x00 goes first so a log line printed before it on the same
+/// port ends up in a frame of its own that the desktop tool discards
+fn send(id: u16, response: &Response) {
+    let mut body = [0u8; MAX_FRAME];
+    let Ok(body) = postcard::to_slice(response, &mut body) else {
+        warn!("USB : Response to request {id} too large");
+        return;
+    };
+    let frame = Frame {
+        version: SCHEMA_VERSION,
+        id,
+        body,
+    };
+    let mut buffer = [0u8; MAX_FRAME + 16];
+    let Ok(encoded) = postcard::to_slice_cobs(&frame, &mut buffer[1..]) else {
+        warn!("USB : Response to request {id} too large");
+        return;
+    };
+    let len = encoded.len() + 1;
+    // Written in one go, the log lines are printed on the port in between frames
+    esp_println::Printer::write_bytes(&buffer[..len]);
+}
+
+/// Answer a request, the responses are sent as they're ready
+async fn handle(network: &NetworkStack, charger: &Charger, id: u16, request: Request<'_>) {
+    match request {
+        Request::Hello => send(
+            id,
+            &Response::Hello {
+                schema: SCHEMA_VERSION,
+                firmware: &version::firmware_version(),
+                serial: network.app_config.charger_serial,
+            },
+        ),
+        Request::Status => {
+            telemetry::record_heap_usage();
+            send(
+                id,
+                &Response::Status {
+                    state: charger.get_state().await.wire_id(),
+                    transaction_id: charger.get_transaction_id().await,
+                    uptime_secs: Instant::now().as_secs(),
+                    ip: network.get_ip_address().map(|ip| ip.octets()),
+                    rssi: wifi_monitor::rssi(),
+                    heap_free: esp_alloc::HEAP.free() as u32,
+                },
+            );
+        }
+        Request::ConfigGet { key } => match ocpp_config::ConfigKey::from_name(key) {
+            Some(key) => send(
+                id,
+                &Response::Config {
+                    key: key.as_str(),
+                    value: &ocpp_config::value_string(key),
+                    read_only: key.read_only(),
+                },
+            ),
+            None => send(
+                id,
+                &Response::Error {
+                    message: "Unknown configuration key",
+                },
+            ),
+        },
+        Request::ConfigSet { key, value } => {
+            let status = ocpp_config::change(key, value);
+            send(
+                id,
+                &Response::ConfigChanged {
+                    status: status.as_str(),
+                },
+            );
+        }
+        Request::Logs { level } => {
+            let level = match level {
+                1 => Level::Error,
+                2 => Level::Warn,
+                3 => Level::Info,
+                4 => Level::Debug,
+                _ => Level::Trace,
+            };
+            match logging::entries(level) {
+                Ok(entries) => {
+                    for entry in &entries {
+                        send(
+                            id,
+                            &Response::Log {
+                                uptime_ms: entry.uptime_ms,
+                                level: entry.level as u8,
+                                text: &entry.text,
+                            },
+                        );
+                    }
+                    send(
+                        id,
+                        &Response::Done {
+                            count: entries.len() as u16,
+                        },
+                    );
+                }
+                Err(message) => send(id, &Response::Error { message }),
+            }
+        }
+        Request::Sessions => match sessions::load() {
+            Ok(sessions) => {
+                for session in &sessions {
+                    send(
+                        id,
+                        &Response::Session {
+                            transaction_id: session.transaction_id,
+                            id_tag: &session.id_tag,
+                            started: session.started,
+                            stopped: session.stopped,
+                            duration_secs: session.duration_secs,
+                            meter_start_wh: session.meter_start,
+                            meter_stop_wh: session.meter_stop,
+                        },
+                    );
+                }
+                send(
+                    id,
+                    &Response::Done {
+                        count: sessions.len() as u16,
+                    },
+                );
+            }
+            Err(message) => send(id, &Response::Error { message }),
+        },
+        Request::Command { json } => {
+            // Logged without the payload, it may hold a guest code or a password
+            info!(
+                "USB : Received command {}",
+                ocpp::json_string_field(json, "command").unwrap_or("without a name")
+            );
+            match command::handle_command(charger, json).await {
+                Ok(()) => send(id, &Response::Done { count: 0 }),
+                Err(message) => send(id, &Response::Error { message }),
+            }
+        }
+    }
+}
+
+/// Decode a frame and answer it, frames that don't decode are dropped
+async fn receive(network: &NetworkStack, charger: &Charger, frame: &mut [u8]) {
+    let Ok(frame) = postcard::from_bytes_cobs::<Frame>(frame) else {
+        return;
+    };
+    if frame.version != SCHEMA_VERSION {
+        send(
+            frame.id,
+            &Response::Unsupported {
+                schema: SCHEMA_VERSION,
+            },
+        );
+        return;
+    }
+    match postcard::from_bytes::<Request>(frame.body) {
+        Ok(request) => handle(network, charger, frame.id, request).await,
+        Err(_) => send(
+            frame.id,
+            &Response::Error {
+                message: "Invalid request",
+            },
+        ),
+    }
+}
+
+/// Task to serve the charger API on the USB-Serial-JTAG port for a desktop commissioning tool,
+/// the same port the log is printed on. It doesn't need the network, so a charger can be set up
+/// before it's online
+#[embassy_executor::task]
+pub async fn usb_api_task(
+    network: &'static NetworkStack,
+    charger: &'static Charger,
+    mut rx: UsbSerialJtagRx<'static, Async>,
+) {
+    info!("TASK: Started USB API, schema version {SCHEMA_VERSION}");
+
+    let mut frame = heapless::Vec::<u8, MAX_FRAME>::new();
+    // Set when a frame didn't fit, the rest of it is skipped up to the next delimiter
+    let mut overflow = false;
+    let mut buffer = [0u8; 64];
+    loop {
+        let Ok(len) = rx.read(&mut buffer).await else {
+            continue;
+        };
+        for &byte in &buffer[..len] {
+            if byte != 0 {
+                if frame.push(byte).is_err() {
+                    overflow = true;
+                }
+                continue;
+            }
+            if overflow {
+                warn!("USB : Frame larger than {MAX_FRAME} bytes dropped");
+            } else if !frame.is_empty() {
+                receive(network, charger, &mut frame).await;
+            }
+            frame.clear();
+            overflow = false;
+        }
+    }
+}
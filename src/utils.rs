@@ -22,3 +22,133 @@ pub fn bytes_to_hex_string<const N: usize>(data: &[u8]) -> heapless::String<N> {
     }
     hex_buf
 }
+
+// Computes the CRC-32 (IEEE) checksum of a byte slice
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xFFFF_FFFF, data)
+}
+
+// Continues a CRC-32 over the next bytes, for data read in chunks. Start with 0xFFFF_FFFF and
+// invert the result
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+// Converts a hex string to bytes, returns None for invalid hex or if the output doesn't fit
+pub fn hex_string_to_bytes<const N: usize>(hex: &str) -> Option<heapless::Vec<u8, N>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut bytes = heapless::Vec::new();
+    for pair in hex.as_bytes().chunks(2) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        bytes.push(u8::from_str_radix(pair, 16).ok()?).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Longest identifier that still fits in a topic together with its prefix
+pub const MAX_IDENTIFIER_LEN: usize = 48;
+
+// Whether a character is safe in MQTT topics, client ids and JSON strings without escaping
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')
+}
+
+// Checks an identifier such as a serial or client id before it's used in topics and JSON
+pub fn validate_identifier(value: &str) -> Result<(), &'static str> {
+    if value.is_empty() {
+        return Err("is empty");
+    }
+    if value.len() > MAX_IDENTIFIER_LEN {
+        return Err("is longer than 48 characters");
+    }
+    for c in value.chars() {
+        match c {
+            c if is_identifier_char(c) => {}
+            '+' | '#' => return Err("contains an MQTT wildcard (+ or #)"),
+            '/' => return Err("contains a topic separator (/)"),
+            '"' | '\\' => return Err("contains a quote or backslash"),
+            c if c.is_whitespace() => return Err("contains whitespace"),
+            _ => return Err("contains a character other than letters, digits, - _ . :"),
+        }
+    }
+    Ok(())
+}
+
+// Replaces every unsafe character of an identifier with an underscore, so it can't corrupt a topic
+// The output is truncated to the capacity N
+pub fn sanitize_identifier<const N: usize>(value: &str) -> heapless::String<N> {
+    let mut sanitized = heapless::String::new();
+    for c in value.chars() {
+        let c = if is_identifier_char(c) { c } else { '_' };
+        if sanitized.push(c).is_err() {
+            break;
+        }
+    }
+    sanitized
+}
+
+// Encodes bytes as standard base64 with padding
+// The output is truncated to the capacity N
+pub fn base64_encode<const N: usize>(data: &[u8]) -> heapless::String<N> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = heapless::String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let triple = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            let c = if i <= chunk.len() {
+                ALPHABET[(triple >> (18 - 6 * i) & 0x3F) as usize] as char
+            } else {
+                '='
+            };
+            if encoded.push(c).is_err() {
+                return encoded;
+            }
+        }
+    }
+    encoded
+}
+
+// Decodes standard base64, padding is optional
+// Returns None for invalid input or if the output doesn't fit the capacity N
+pub fn base64_decode<const N: usize>(encoded: &str) -> Option<heapless::Vec<u8, N>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = heapless::Vec::new();
+    for chunk in encoded.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut quad = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            quad |= value(*c)? << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            decoded.push((quad >> (16 - 8 * i)) as u8).ok()?;
+        }
+    }
+    Some(decoded)
+}
@@ -0,0 +1,57 @@
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::charger::{ChargerState, InputEvent, OutputEvent, OutputEvents};
+use crate::{info, warn};
+
+/// Most hooks a fork can register
+const MAX_HOOKS: usize = 4;
+/// Highest code of a vendor event, its wire id is the code from VENDOR_WIRE_BASE on
+pub const MAX_CODE: u8 = 127;
+/// Wire id of vendor event 0
+pub const VENDOR_WIRE_BASE: u8 = 128;
+
+/// Hook of a fork, called with the old state, the input and the new state of every state change.
+/// The code it returns is published as `OutputEvent::Vendor` with the state change
+pub type OutputHook = fn(ChargerState, InputEvent, ChargerState) -> Option<u8>;
+
+static HOOKS: Mutex<CriticalSectionRawMutex, RefCell<heapless::Vec<OutputHook, MAX_HOOKS>>> =
+    Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// Register a hook that adds a vendor event to the state changes, before the tasks are spawned.
+/// The fork handles its events in its own task, subscribed to STATE_PUBSUB with the vendor slot
+pub fn register(hook: OutputHook) -> Result<(), &'static str> {
+    HOOKS
+        .lock(|hooks| hooks.borrow_mut().push(hook))
+        .map_err(|_| "No room for another vendor hook")?;
+    info!("VNDR: Registered a vendor output hook");
+    Ok(())
+}
+
+/// Add the events of the registered hooks to the events of a state change
+pub fn apply(
+    old_state: ChargerState,
+    input: InputEvent,
+    new_state: ChargerState,
+    events: &mut OutputEvents,
+) {
+    let hooks = HOOKS.lock(|hooks| hooks.borrow().clone());
+    for hook in hooks {
+        let Some(code) = hook(old_state, input, new_state) else {
+            continue;
+        };
+        if code > MAX_CODE {
+            warn!("VNDR: Vendor event {code} is out of range, dropped");
+        } else if events.push(OutputEvent::Vendor(code)).is_err() {
+            warn!("VNDR: No room for vendor event {code}, dropped");
+        }
+    }
+}
+
+/// Codes of the vendor events published with a state change, for the task of a fork
+pub fn codes(events: &[OutputEvent]) -> impl Iterator<Item = u8> + '_ {
+    events.iter().filter_map(|event| match event {
+        OutputEvent::Vendor(code) => Some(*code),
+        _ => None,
+    })
+}
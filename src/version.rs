@@ -0,0 +1,75 @@
+use core::fmt::Write;
+
+use crate::config::Config;
+use crate::info;
+
+/// Firmware version from Cargo.toml
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short git hash of the build, embedded by build.rs ("unknown" when built outside a git checkout)
+pub const GIT_HASH: &str = env!("CHARGER_GIT_HASH");
+
+/// Comma separated list of enabled cargo features, embedded by build.rs
+pub const BUILD_FEATURES: &str = env!("CHARGER_BUILD_FEATURES");
+
+/// OCPP 1.6 feature profiles implemented by this firmware
+pub const OCPP_PROFILES: &[&str] = &["Core", "FirmwareManagement", "Reservation"];
+
+/// Version string as reported to the central system, e.g. `0.1.0+1a2b3c4`
+/// OCPP limits the BootNotification firmwareVersion to 50 characters
+pub fn firmware_version() -> heapless::String<50> {
+    let mut version = heapless::String::new();
+    if write!(version, "{FIRMWARE_VERSION}+{GIT_HASH}").is_err() {
+        version.clear();
+        let _ = version.push_str(FIRMWARE_VERSION);
+    }
+    version
+}
+
+/// Build features for display/logging, "none" when no features are enabled
+pub fn build_features() -> &'static str {
+    if BUILD_FEATURES.is_empty() {
+        "none"
+    } else {
+        BUILD_FEATURES
+    }
+}
+
+/// Comma separated list of the supported OCPP feature profiles
+pub fn ocpp_profiles() -> heapless::String<128> {
+    let mut profiles = heapless::String::new();
+    for (i, profile) in OCPP_PROFILES.iter().enumerate() {
+        if i > 0 && profiles.push(',').is_err() {
+            break;
+        }
+        if profiles.push_str(profile).is_err() {
+            break;
+        }
+    }
+    profiles
+}
+
+/// Inventory of the firmware build as JSON, reported through DataTransfer
+pub fn inventory() -> heapless::String<256> {
+    let mut inventory = heapless::String::new();
+    let _ = write!(
+        inventory,
+        "{{\"firmware\":\"{FIRMWARE_VERSION}\",\"git\":\"{GIT_HASH}\",\"features\":\"{}\",\"profiles\":\"{}\"}}",
+        build_features(),
+        ocpp_profiles()
+    );
+    inventory
+}
+
+/// Log the startup banner with charger identity and build information
+pub fn log_banner(config: &Config) {
+    info!("MAIN: ========================================");
+    info!(
+        "MAIN: {} {} ({})",
+        config.charger_vendor, config.charger_model, config.charger_serial
+    );
+    info!("MAIN: Firmware: {}", firmware_version());
+    info!("MAIN: Features: {}", build_features());
+    info!("MAIN: OCPP 1.6 profiles: {}", ocpp_profiles());
+    info!("MAIN: ========================================");
+}
@@ -0,0 +1,199 @@
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    charger::{self, Charger, ChargerState, InputEvent},
+    config::Config,
+    data_transfer,
+    display::{self, DisplayEvent},
+    info, ocpp, reservation, warn,
+};
+
+/// Drivers that can wait for the charger, a card swiped when it's full is turned away
+const MAX_WAITING: usize = 3;
+const CHECK_INTERVAL_SECS: u64 = 1;
+
+/// Cards swiped while somebody else is charging join the waiting list
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Time the first driver on the list has to plug in and start once the connector is free
+static PRIORITY_SECS: Mutex<CriticalSectionRawMutex, Cell<u16>> = Mutex::new(Cell::new(0));
+/// vendorId of the DataTransfer notifications
+static VENDOR: Mutex<CriticalSectionRawMutex, Cell<&'static str>> = Mutex::new(Cell::new(""));
+
+/// The id tags waiting in the order they were swiped, and when the turn of the first one started
+struct WaitingList {
+    id_tags: heapless::Vec<heapless::String<20>, MAX_WAITING>,
+    turn_started: Option<Instant>,
+}
+
+static WAITING: Mutex<CriticalSectionRawMutex, RefCell<WaitingList>> =
+    Mutex::new(RefCell::new(WaitingList {
+        id_tags: heapless::Vec::new(),
+        turn_started: None,
+    }));
+
+/// Enable the waiting list, before the card swipes are read
+pub fn init(config: &Config) {
+    ENABLED.store(config.waiting_list_enabled, Ordering::Relaxed);
+    PRIORITY_SECS.lock(|secs| secs.set(config.waiting_priority_secs));
+    VENDOR.lock(|vendor| vendor.set(config.charger_vendor));
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Tell the central system about a change of the waiting list, as a DataTransfer with messageId
+/// `WaitingList`, e.g. `{"event":"joined","idTag":"04A1B2C3","position":1}`
+fn notify(event: &str, id_tag: &str, position: Option<usize>) {
+    let mut json = heapless::String::<96>::new();
+    let _ = write!(json, "{{\"event\":\"{event}\",\"idTag\":\"");
+    let _ = ocpp::push_json_escaped(&mut json, id_tag);
+    let _ = json.push('"');
+    if let Some(position) = position {
+        let _ = write!(json, ",\"position\":{position}");
+    }
+    let _ = json.push('}');
+    let vendor = VENDOR.lock(|vendor| vendor.get());
+    data_transfer::send_data_transfer(vendor, Some("WaitingList"), Some(&json));
+}
+
+/// Add an id tag swiped while another session is charging, returns its position from 1. A tag
+/// that is already waiting keeps its place
+pub fn join(id_tag: &str) -> Result<usize, &'static str> {
+    let id_tag = heapless::String::<20>::try_from(id_tag)
+        .map_err(|_| "Id tag too long for the waiting list")?;
+    let joined = WAITING.lock(|waiting| {
+        let mut waiting = waiting.borrow_mut();
+        if let Some(index) = waiting
+            .id_tags
+            .iter()
+            .position(|waiting| waiting.eq_ignore_ascii_case(&id_tag))
+        {
+            return Ok((index + 1, false));
+        }
+        waiting
+            .id_tags
+            .push(id_tag.clone())
+            .map_err(|_| "The waiting list is full")?;
+        Ok((waiting.id_tags.len(), true))
+    });
+    let (position, added) = joined?;
+    if added {
+        info!("WAIT: {id_tag} joined the waiting list at position {position}");
+        notify("joined", &id_tag, Some(position));
+    }
+    display::notify(DisplayEvent::Queued(position as u8));
+    Ok(position)
+}
+
+/// The id tag whose turn it is, while the connector is held for it
+pub fn turn() -> Option<heapless::String<20>> {
+    WAITING.lock(|waiting| {
+        let waiting = waiting.borrow();
+        waiting.turn_started?;
+        waiting.id_tags.first().cloned()
+    })
+}
+
+/// Whether an id tag may start a session, while it's somebody's turn only theirs
+pub fn accepts(id_tag: &str) -> bool {
+    turn().is_none_or(|turn| turn.eq_ignore_ascii_case(id_tag))
+}
+
+/// Take an id tag off the list when it starts a session, its turn ends
+pub fn consume(id_tag: &str) {
+    let removed = WAITING.lock(|waiting| {
+        let mut waiting = waiting.borrow_mut();
+        let index = waiting
+            .id_tags
+            .iter()
+            .position(|waiting| waiting.eq_ignore_ascii_case(id_tag))?;
+        waiting.id_tags.remove(index);
+        if index == 0 {
+            waiting.turn_started = None;
+        }
+        Some(())
+    });
+    if removed.is_some() {
+        info!("WAIT: {id_tag} started charging, off the waiting list");
+        notify("started", id_tag, None);
+    }
+}
+
+/// End the turn of the first driver when the priority window passed, they lose their place
+fn expire_turn() {
+    let window = Duration::from_secs(PRIORITY_SECS.lock(|secs| secs.get()).into());
+    let expired = WAITING.lock(|waiting| {
+        let mut waiting = waiting.borrow_mut();
+        if waiting
+            .turn_started
+            .is_none_or(|started| started.elapsed() < window)
+        {
+            return None;
+        }
+        waiting.turn_started = None;
+        (!waiting.id_tags.is_empty()).then(|| waiting.id_tags.remove(0))
+    });
+    if let Some(id_tag) = expired {
+        warn!("WAIT: {id_tag} didn't start within the priority window, off the waiting list");
+        notify("expired", &id_tag, None);
+    }
+}
+
+/// Give the first driver on the list their turn
+fn start_turn() {
+    let started = WAITING.lock(|waiting| {
+        let mut waiting = waiting.borrow_mut();
+        let first = waiting.id_tags.first().cloned()?;
+        waiting.turn_started = Some(Instant::now());
+        Some(first)
+    });
+    if let Some(id_tag) = started {
+        info!("WAIT: Connector free, holding it for {id_tag}");
+        notify("turn", &id_tag, Some(1));
+    }
+}
+
+/// Task to hold the free connector for the first driver on the waiting list for the priority
+/// window, the charger shows Reserved meanwhile. A reservation from the central system goes first
+#[embassy_executor::task]
+pub async fn waiting_list_task(charger: &'static Charger) {
+    info!(
+        "TASK: Started Waiting List, {}s priority window",
+        PRIORITY_SECS.lock(|secs| secs.get())
+    );
+
+    // The charger was put in Reserved for a turn, not for a reservation
+    let mut holding = false;
+
+    loop {
+        Timer::after(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+
+        expire_turn();
+        let state = charger.get_state().await;
+        let reserved = reservation::active().is_some();
+
+        let free = matches!(state, ChargerState::Available | ChargerState::Reserved);
+        if free && !reserved && turn().is_none() {
+            start_turn();
+        }
+
+        if turn().is_some() && state == ChargerState::Available && !reserved {
+            holding = true;
+            charger::STATE_IN_CHANNEL.send(InputEvent::Reserve).await;
+        } else if holding && turn().is_none() && !reserved {
+            holding = false;
+            if state == ChargerState::Reserved {
+                charger::STATE_IN_CHANNEL
+                    .send(InputEvent::ReservationEnded)
+                    .await;
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    peripherals::TIMG1,
+    timer::timg::{MwdtStage, Wdt},
+};
+
+use crate::storage::{self, Slot};
+use crate::{error, info, mqtt, warn};
+
+/// Time the hardware watchdog waits for a feed before it resets the chip, it catches a stalled
+/// executor where the health checks can't run
+const HARDWARE_TIMEOUT_SECS: u64 = 10;
+/// Interval of the health checks, the hardware watchdog is fed with them
+const CHECK_INTERVAL_SECS: u64 = 1;
+/// Size of the serialized watchdog record
+const RECORD_SIZE: usize = 10;
+/// Culprit of a record without a pending reset
+const NO_CULPRIT: u8 = 0xFF;
+
+/// Critical tasks that check in with the watchdog
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Monitored {
+    StateMachine,
+    MqttClient,
+    Network,
+    OcppResponses,
+    OcppTransactions,
+}
+
+impl Monitored {
+    pub const ALL: [Monitored; 5] = [
+        Monitored::StateMachine,
+        Monitored::MqttClient,
+        Monitored::Network,
+        Monitored::OcppResponses,
+        Monitored::OcppTransactions,
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            Self::StateMachine => 0,
+            Self::MqttClient => 1,
+            Self::Network => 2,
+            Self::OcppResponses => 3,
+            Self::OcppTransactions => 4,
+        }
+    }
+
+    /// Time the task may go without checking in while it's busy. Covers the slowest step it
+    /// takes, e.g. a broker connect with its DNS, TCP and MQTT timeouts
+    fn deadline(&self) -> Duration {
+        match self {
+            Self::StateMachine | Self::OcppResponses | Self::OcppTransactions => {
+                Duration::from_secs(30)
+            }
+            Self::MqttClient | Self::Network => Duration::from_secs(120),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::StateMachine => "State machine",
+            Self::MqttClient => "MQTT client",
+            Self::Network => "Network",
+            Self::OcppResponses => "OCPP responses",
+            Self::OcppTransactions => "OCPP transactions",
+        }
+    }
+}
+
+/// Last check in of each task, None while it waits for input and isn't monitored
+static CHECK_INS: Mutex<CriticalSectionRawMutex, Cell<[Option<Instant>; Monitored::ALL.len()]>> =
+    Mutex::new(Cell::new([None; Monitored::ALL.len()]));
+
+/// Mark a task alive, it's monitored until it checks in again or goes idle
+pub fn check_in(task: Monitored) {
+    CHECK_INS.lock(|check_ins| {
+        let mut current = check_ins.get();
+        current[task.index()] = Some(Instant::now());
+        check_ins.set(current);
+    });
+}
+
+/// Mark a task waiting for input, e.g. a channel, it isn't monitored until it checks in
+pub fn idle(task: Monitored) {
+    CHECK_INS.lock(|check_ins| {
+        let mut current = check_ins.get();
+        current[task.index()] = None;
+        check_ins.set(current);
+    });
+}
+
+/// The first task that is busy beyond its deadline, with the time since it checked in
+fn stalled() -> Option<(Monitored, Duration)> {
+    let check_ins = CHECK_INS.lock(|check_ins| check_ins.get());
+    Monitored::ALL.into_iter().find_map(|task| {
+        let silent = check_ins[task.index()]?.elapsed();
+        (silent > task.deadline()).then_some((task, silent))
+    })
+}
+
+/// Watches the outbound MQTT queue. A queue that stays full while the client is connected and no
+/// message goes out means the client is stuck though its socket looks alive, e.g. a broker that
+/// stopped acknowledging. The session is torn down, after repeated teardowns the charger resets
+struct SendQueueCheck {
+    stall_after: Duration,
+    restarts: u8,
+    full_since: Option<Instant>,
+    sent: u32,
+    /// Teardowns since the last message went out
+    teardowns: u8,
+}
+
+impl SendQueueCheck {
+    fn new(stall_secs: u16, restarts: u8) -> Self {
+        Self {
+            stall_after: Duration::from_secs(stall_secs.into()),
+            restarts,
+            full_since: None,
+            sent: mqtt::stats().sent,
+            teardowns: 0,
+        }
+    }
+
+    /// Tear the session down when the queue starved, returns the time it was full when the
+    /// teardowns didn't help and the charger has to reset
+    fn check(&mut self) -> Option<Duration> {
+        let sent = mqtt::stats().sent;
+        if sent != self.sent {
+            self.sent = sent;
+            self.full_since = None;
+            self.teardowns = 0;
+        }
+        // A broker that is down fills the queue as well, that's for the reconnects to handle
+        if !mqtt::MQTT_SEND_QUEUE.is_full() || !mqtt::is_session_active() {
+            self.full_since = None;
+            return None;
+        }
+
+        let starved = self.full_since.get_or_insert_with(Instant::now).elapsed();
+        if starved < self.stall_after {
+            return None;
+        }
+        if self.teardowns >= self.restarts {
+            return Some(starved);
+        }
+        self.teardowns += 1;
+        self.full_since = None;
+        warn!(
+            "WDOG: MQTT send queue full for {}s without a message going out, reconnecting ({} of {})",
+            starved.as_secs(),
+            self.teardowns,
+            self.restarts
+        );
+        mqtt::reset_client();
+        None
+    }
+}
+
+/// Resets by the watchdog, kept in flash so the culprit is reported after the reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WatchdogRecord {
+    resets: u32,
+    /// Index of the task that stalled, NO_CULPRIT once it was reported
+    culprit: u8,
+    /// Time the task was silent, in seconds
+    silent_secs: u32,
+    /// Index of the task of the last reset by the watchdog, kept after it was reported
+    last_culprit: u8,
+}
+
+impl WatchdogRecord {
+    const fn new() -> Self {
+        Self {
+            resets: 0,
+            culprit: NO_CULPRIT,
+            silent_secs: 0,
+            last_culprit: NO_CULPRIT,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.resets.to_le_bytes());
+        bytes[4] = self.culprit;
+        bytes[5..9].copy_from_slice(&self.silent_secs.to_le_bytes());
+        bytes[9] = self.last_culprit;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+        Some(Self {
+            resets: u32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            culprit: bytes[4],
+            silent_secs: u32::from_le_bytes(bytes[5..9].try_into().ok()?),
+            last_culprit: bytes[9],
+        })
+    }
+}
+
+static RECORD: Mutex<CriticalSectionRawMutex, Cell<WatchdogRecord>> =
+    Mutex::new(Cell::new(WatchdogRecord::new()));
+
+/// Resets by the watchdog since the record was created, for the diagnostics
+pub struct WatchdogStats {
+    pub resets: u32,
+    pub last_culprit: Option<&'static str>,
+}
+
+pub fn stats() -> WatchdogStats {
+    let record = RECORD.lock(|record| record.get());
+    WatchdogStats {
+        resets: record.resets,
+        last_culprit: Monitored::ALL
+            .get(usize::from(record.last_culprit))
+            .map(Monitored::as_str),
+    }
+}
+
+/// Read the watchdog record at boot and report a reset by the watchdog
+pub fn load() {
+    let mut buffer = [0u8; RECORD_SIZE];
+    let mut record = match storage::read(Slot::Watchdog, &mut buffer) {
+        Ok(Some(len)) => {
+            WatchdogRecord::from_bytes(&buffer[..len]).unwrap_or(WatchdogRecord::new())
+        }
+        Ok(None) => WatchdogRecord::new(),
+        Err(e) => {
+            warn!("WDOG: Failed to read the watchdog record: {e}");
+            WatchdogRecord::new()
+        }
+    };
+
+    if let Some(task) = Monitored::ALL.get(usize::from(record.culprit)) {
+        error!(
+            "WDOG: Reset by the watchdog, {} was stuck for {}s, {} watchdog resets so far",
+            task.as_str(),
+            record.silent_secs,
+            record.resets
+        );
+        record.culprit = NO_CULPRIT;
+        if let Err(e) = storage::write(Slot::Watchdog, &record.to_bytes()) {
+            warn!("WDOG: Failed to persist the watchdog record: {e}");
+        }
+    }
+    RECORD.lock(|current| current.set(record));
+}
+
+/// Persist the stalled task and reset the chip
+fn reset(task: Monitored, silent: Duration) -> ! {
+    let mut record = RECORD.lock(|record| record.get());
+    record.resets += 1;
+    record.culprit = task.index() as u8;
+    record.last_culprit = record.culprit;
+    record.silent_secs = silent.as_secs() as u32;
+    if let Err(e) = storage::write(Slot::Watchdog, &record.to_bytes()) {
+        warn!("WDOG: Failed to persist the watchdog record: {e}");
+    }
+    esp_hal::system::software_reset();
+}
+
+/// Task to feed the hardware watchdog while the critical tasks are healthy. A task that is busy
+/// beyond its deadline is written to flash and the chip is reset, so e.g. a hung MQTT socket
+/// can't keep a session from being reported. When the executor itself stalls the hardware
+/// watchdog resets the chip. An MQTT send queue that stays full gets the client reconnected
+/// first, the MQTT client is the culprit when that doesn't help
+#[embassy_executor::task]
+pub async fn watchdog_task(
+    mut wdt: Wdt<TIMG1<'static>>,
+    mqtt_stall_secs: u16,
+    mqtt_stall_restarts: u8,
+) {
+    info!(
+        "TASK: Started Watchdog, {}s hardware timeout",
+        HARDWARE_TIMEOUT_SECS
+    );
+
+    wdt.set_timeout(
+        MwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(HARDWARE_TIMEOUT_SECS),
+    );
+    wdt.enable();
+
+    let mut send_queue = SendQueueCheck::new(mqtt_stall_secs, mqtt_stall_restarts);
+    loop {
+        if let Some(starved) = send_queue.check() {
+            error!(
+                "WDOG: MQTT send queue still full after {} reconnects, resetting",
+                mqtt_stall_restarts
+            );
+            reset(Monitored::MqttClient, starved);
+        }
+        if let Some((task, silent)) = stalled() {
+            error!(
+                "WDOG: {} didn't check in for {}s, resetting",
+                task.as_str(),
+                silent.as_secs()
+            );
+            reset(task, silent);
+        }
+        wdt.feed();
+        Timer::after(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+    }
+}
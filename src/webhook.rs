@@ -0,0 +1,165 @@
+use core::{
+    cell::Cell,
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    fault::Fault,
+    http::{self, Scheme, Url},
+    info,
+    network::NetworkStack,
+    ntp,
+    ocpp::push_json_escaped,
+    sessions::SessionRecord,
+    warn, wire,
+};
+
+/// Delay before the first retry, doubled for every next one
+const BACKOFF_SECS: u64 = 5;
+const MAX_BACKOFF_SECS: u64 = 300;
+
+/// An event as the JSON body of the POST
+type Event = heapless::String<256>;
+
+/// Events waiting to be posted, events are dropped while it's full
+static WEBHOOK_CHANNEL: Channel<CriticalSectionRawMutex, Event, 4> = Channel::new();
+/// Set when the webhook task runs, so no events are queued without a URL
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SERIAL: Mutex<CriticalSectionRawMutex, Cell<&'static str>> = Mutex::new(Cell::new(""));
+
+/// Start of an event, `{"event":"<name>","serial":"<serial>","time":"<iso8601>"`
+fn event(name: &str) -> Option<Event> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut json = Event::new();
+    write!(json, "{{\"event\":\"{name}\",\"serial\":\"").ok()?;
+    push_json_escaped(&mut json, SERIAL.lock(|serial| serial.get()))?;
+    write!(json, "\",\"time\":\"{}\"", ntp::get_iso8601_time()).ok()?;
+    Some(json)
+}
+
+fn queue(mut json: Event) {
+    if json.push('}').is_err() {
+        warn!("HOOK: Event too large, dropped");
+        return;
+    }
+    if WEBHOOK_CHANNEL.try_send(json).is_err() {
+        warn!("HOOK: Queue full, event dropped");
+    }
+}
+
+/// A charging session started for an id tag
+pub fn session_started(id_tag: &str) {
+    let Some(mut json) = event("session_started") else {
+        return;
+    };
+    if json.push_str(",\"id_tag\":\"").is_err() || push_json_escaped(&mut json, id_tag).is_none() {
+        return;
+    }
+    if json.push('"').is_ok() {
+        queue(json);
+    }
+}
+
+/// A charging session ended
+pub fn session_ended(session: &SessionRecord) {
+    let Some(mut json) = event("session_ended") else {
+        return;
+    };
+    if write!(
+        json,
+        ",\"transaction_id\":{},\"id_tag\":\"",
+        session.transaction_id
+    )
+    .is_err()
+        || push_json_escaped(&mut json, &session.id_tag).is_none()
+    {
+        return;
+    }
+    if write!(
+        json,
+        "\",\"duration_secs\":{},\"energy_wh\":{}",
+        session.duration_secs,
+        session.energy_wh()
+    )
+    .is_ok()
+    {
+        queue(json);
+    }
+}
+
+/// A fault was raised, `locked_out` when it recurred often enough to latch the lockout
+pub fn fault_raised(fault: Fault, locked_out: bool) {
+    let Some(mut json) = event("fault_raised") else {
+        return;
+    };
+    if json.push(',').is_ok()
+        && wire::write_json(&mut json, "fault", fault).is_ok()
+        && write!(json, ",\"locked_out\":{locked_out}").is_ok()
+    {
+        queue(json);
+    }
+}
+
+/// Post an event, a response other than 2xx is an error
+async fn post(network: &NetworkStack, url: &Url<'_>, json: &str) -> Result<(), &'static str> {
+    match http::post(network, url, "application/json", json.as_bytes()).await? {
+        200..=299 => Ok(()),
+        400..=499 => Err("Event rejected by server"),
+        _ => Err("Server error"),
+    }
+}
+
+#[embassy_executor::task]
+pub async fn webhook_task(network: &'static NetworkStack) {
+    info!("TASK: Started Webhook Handler");
+
+    let config = &network.app_config;
+    let url = match Url::parse(config.webhook_url) {
+        Ok(url) if url.scheme == Scheme::Http => url,
+        Ok(_) => {
+            warn!("HOOK: Only plain HTTP webhook URLs are supported");
+            return;
+        }
+        Err(e) => {
+            warn!("HOOK: Invalid webhook URL: {e}");
+            return;
+        }
+    };
+    SERIAL.lock(|serial| serial.set(config.charger_serial));
+    ENABLED.store(true, Ordering::Relaxed);
+
+    loop {
+        let json = WEBHOOK_CHANNEL.receive().await;
+        let mut attempt = 0;
+        let mut backoff_secs = BACKOFF_SECS;
+        loop {
+            match post(network, &url, &json).await {
+                Ok(()) => {
+                    info!("HOOK: Posted {json}");
+                    break;
+                }
+                Err(e) if attempt < config.webhook_retries => {
+                    attempt += 1;
+                    warn!(
+                        "HOOK: Post failed: {e}, retry {attempt} of {} in {backoff_secs}s",
+                        config.webhook_retries
+                    );
+                    Timer::after(Duration::from_secs(backoff_secs)).await;
+                    backoff_secs = (backoff_secs * 2).min(MAX_BACKOFF_SECS);
+                }
+                Err(e) => {
+                    warn!("HOOK: Post failed: {e}, event dropped");
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicI32, Ordering},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use esp_wifi::wifi::{ScanConfig, WifiController, WifiEvent};
+
+use crate::{
+    config::Config,
+    info, warn,
+    watchdog::{self, Monitored},
+};
+
+/// Interval of the RSSI samples while connected
+const RSSI_INTERVAL_SECS: u64 = 10;
+/// A candidate must be this much stronger than the current access point, so the charger doesn't
+/// hop between two access points of about the same strength
+const ROAM_MARGIN_DB: i32 = 8;
+
+/// Signal strength of the access point in dBm, 0 while not connected
+static RSSI: AtomicI32 = AtomicI32::new(0);
+/// SSID of the network, empty while not connected
+static SSID: Mutex<CriticalSectionRawMutex, Cell<&'static str>> = Mutex::new(Cell::new(""));
+
+/// Signal strength of the access point in dBm, None while not connected
+pub fn rssi() -> Option<i32> {
+    Some(RSSI.load(Ordering::Relaxed)).filter(|rssi| *rssi != 0)
+}
+
+/// SSID of the network the charger is connected to, None while not connected
+pub fn ssid() -> Option<&'static str> {
+    Some(SSID.lock(|ssid| ssid.get())).filter(|ssid| !ssid.is_empty())
+}
+
+/// Parse a BSSID written as `aa:bb:cc:dd:ee:ff`
+pub fn parse_bssid(value: &str) -> Option<[u8; 6]> {
+    let mut bssid = [0u8; 6];
+    let mut octets = value.trim().split(':');
+    for octet in bssid.iter_mut() {
+        *octet = u8::from_str_radix(octets.next()?, 16).ok()?;
+    }
+    octets.next().is_none().then_some(bssid)
+}
+
+/// Whether the charger may roam to an access point, any access point of the SSID when no
+/// BSSIDs are configured
+fn is_allowed(config: &Config, bssid: &[u8; 6]) -> bool {
+    config.wifi_bssids.is_empty()
+        || config
+            .wifi_bssids
+            .split(',')
+            .filter_map(parse_bssid)
+            .any(|allowed| allowed == *bssid)
+}
+
+/// Access point to roam to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoamTarget {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i32,
+}
+
+/// Why monitoring the connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorOutcome {
+    /// The access point dropped the connection
+    Disconnected,
+    /// The signal stayed weak and a stronger access point was found
+    Roam(RoamTarget),
+}
+
+/// Scan for the strongest allowed access point of the SSID that beats the current signal
+async fn find_stronger(
+    controller: &mut WifiController<'static>,
+    config: &Config,
+    ssid: &str,
+    current_rssi: i32,
+) -> Option<RoamTarget> {
+    let scan = ScanConfig {
+        ssid: Some(ssid),
+        ..Default::default()
+    };
+    let access_points = match controller.scan_with_config_async(scan).await {
+        Ok(access_points) => access_points,
+        Err(e) => {
+            warn!("WIFI: Scan failed: {e:?}");
+            return None;
+        }
+    };
+    access_points
+        .iter()
+        .filter(|ap| ap.ssid.as_str() == ssid && is_allowed(config, &ap.bssid))
+        .map(|ap| RoamTarget {
+            bssid: ap.bssid,
+            channel: ap.channel,
+            rssi: ap.signal_strength.into(),
+        })
+        .filter(|target| target.rssi >= current_rssi + ROAM_MARGIN_DB)
+        .max_by_key(|target| target.rssi)
+}
+
+/// Sample the signal strength while connected, returns when the connection drops or, with
+/// roaming enabled, when the signal stayed below the threshold and a stronger access point of
+/// the SSID of the network was found. Runs in the connection task, which owns the WiFi controller
+pub async fn monitor(
+    controller: &mut WifiController<'static>,
+    config: &Config,
+    ssid: &'static str,
+) -> MonitorOutcome {
+    SSID.lock(|current| current.set(ssid));
+    let roam_after = Duration::from_secs(config.wifi_roam_after_secs.into());
+    let threshold = i32::from(config.wifi_roam_threshold_dbm);
+    let mut weak_since: Option<Instant> = None;
+
+    loop {
+        watchdog::check_in(Monitored::Network);
+        if embassy_time::with_timeout(
+            Duration::from_secs(RSSI_INTERVAL_SECS),
+            controller.wait_for_event(WifiEvent::StaDisconnected),
+        )
+        .await
+        .is_ok()
+        {
+            RSSI.store(0, Ordering::Relaxed);
+            SSID.lock(|current| current.set(""));
+            return MonitorOutcome::Disconnected;
+        }
+
+        let Ok(rssi) = controller.rssi() else {
+            continue;
+        };
+        RSSI.store(rssi, Ordering::Relaxed);
+
+        if !config.wifi_roaming || rssi >= threshold {
+            weak_since = None;
+            continue;
+        }
+        let since = *weak_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < roam_after {
+            continue;
+        }
+
+        info!(
+            "WIFI: Signal at {rssi}dBm for {}s, scanning for a stronger access point",
+            since.elapsed().as_secs()
+        );
+        // Wait another period before scanning again
+        weak_since = None;
+        match find_stronger(controller, config, ssid, rssi).await {
+            Some(target) => {
+                info!(
+                    "WIFI: Roaming to {:02x?} on channel {} at {}dBm",
+                    target.bssid, target.channel, target.rssi
+                );
+                return MonitorOutcome::Roam(target);
+            }
+            None => info!("WIFI: No stronger access point found"),
+        }
+    }
+}
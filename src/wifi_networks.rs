@@ -0,0 +1,178 @@
+use esp_wifi::wifi::{AccessPointInfo, ScanConfig, WifiController};
+
+use crate::{
+    config::{Config, WifiNetwork},
+    info, network_cache,
+    settings::{self, Cached},
+    warn,
+    wifi_monitor::RoamTarget,
+};
+
+/// The primary network and its fallbacks
+const MAX_NETWORKS: usize = 3;
+/// Failed connects to a network before falling through to the next one
+const MAX_FAILURES: u8 = 3;
+
+/// The configured networks and the one the charger connects to
+pub struct WifiNetworks {
+    networks: heapless::Vec<WifiNetwork, MAX_NETWORKS>,
+    current: usize,
+    failures: u8,
+    /// A network was picked from a scan since the last connection
+    scanned: bool,
+    /// The network to prefer when it's available, stored in flash
+    remembered: Option<heapless::String<{ settings::MAX_VALUE_LEN }>>,
+    /// Access point of the current network, from a scan, a roam or the cache
+    access_point: Option<RoamTarget>,
+    /// The cached access point is tried first after a restart, without a scan
+    cached: Option<RoamTarget>,
+    /// Connecting to the cached access point
+    pinned: bool,
+}
+
+impl WifiNetworks {
+    pub fn new(config: &Config) -> Self {
+        let networks: heapless::Vec<WifiNetwork, MAX_NETWORKS> =
+            config.wifi_networks().take(MAX_NETWORKS).collect();
+        let remembered = settings::last_network();
+        let current = remembered
+            .as_deref()
+            .and_then(|ssid| networks.iter().position(|network| network.ssid == ssid))
+            .unwrap_or(0);
+        if networks.len() > 1 {
+            info!(
+                "WIFI: {} networks configured, starting with {}",
+                networks.len(),
+                networks[current].ssid
+            );
+        }
+        let cached = network_cache::access_point(networks[current].ssid);
+        Self {
+            networks,
+            current,
+            failures: 0,
+            scanned: false,
+            remembered,
+            access_point: cached,
+            cached,
+            pinned: false,
+        }
+    }
+
+    pub fn current(&self) -> WifiNetwork {
+        self.networks[self.current]
+    }
+
+    /// The cached access point of the current network, once after a restart. The charger
+    /// connects to it without scanning
+    pub fn take_cached(&mut self) -> Option<RoamTarget> {
+        let target = self.cached.take()?;
+        info!(
+            "WIFI: Connecting to the cached access point {:02x?} on channel {}",
+            target.bssid, target.channel
+        );
+        self.pinned = true;
+        Some(target)
+    }
+
+    /// A scan is needed to pick a network, once per connection when there are fallbacks, or to
+    /// learn the access point so it can be cached
+    pub fn needs_scan(&self) -> bool {
+        !self.scanned && (self.networks.len() > 1 || self.access_point.is_none())
+    }
+
+    /// Pick the network to connect to from the visible access points: the last network the
+    /// charger connected to, otherwise the first visible one in priority order. The current
+    /// network is kept when none is visible, it may be hidden
+    pub fn select(&mut self, access_points: &[AccessPointInfo]) {
+        let visible = |network: &WifiNetwork| {
+            access_points
+                .iter()
+                .any(|ap| ap.ssid.as_str() == network.ssid)
+        };
+        let remembered = self.remembered.as_deref().and_then(|ssid| {
+            self.networks
+                .iter()
+                .position(|network| network.ssid == ssid && visible(network))
+        });
+        if let Some(index) = remembered.or_else(|| self.networks.iter().position(visible)) {
+            if index != self.current {
+                info!("WIFI: Selected {}", self.networks[index].ssid);
+            }
+            self.current = index;
+        }
+        // The driver connects to the strongest access point of the network
+        let ssid = self.current().ssid;
+        self.access_point = access_points
+            .iter()
+            .filter(|ap| ap.ssid.as_str() == ssid)
+            .max_by_key(|ap| ap.signal_strength)
+            .map(|ap| RoamTarget {
+                bssid: ap.bssid,
+                channel: ap.channel,
+                rssi: ap.signal_strength.into(),
+            });
+    }
+
+    /// The charger roams to another access point of the current network
+    pub fn roaming(&mut self, target: RoamTarget) {
+        self.access_point = Some(target);
+    }
+
+    /// Scan for the configured networks and pick one
+    pub async fn scan(&mut self, controller: &mut WifiController<'static>) {
+        self.scanned = true;
+        match controller
+            .scan_with_config_async(ScanConfig::default())
+            .await
+        {
+            Ok(access_points) => self.select(&access_points),
+            Err(e) => warn!("WIFI: Scan failed: {e:?}"),
+        }
+    }
+
+    /// The charger connected to the current network, it's remembered for the next boot together
+    /// with its access point
+    pub fn connected(&mut self) {
+        network_cache::log_milestone("WiFi connected", self.pinned);
+        self.failures = 0;
+        self.scanned = false;
+        self.pinned = false;
+        let ssid = self.current().ssid;
+        if self.remembered.as_deref() != Some(ssid) {
+            match settings::remember_network(ssid) {
+                Ok(()) => self.remembered = heapless::String::try_from(ssid).ok(),
+                Err(e) => {
+                    warn!("WIFI: Failed to remember {ssid}: {e}");
+                    return;
+                }
+            }
+        }
+        if let Some(target) = &self.access_point {
+            network_cache::remember_access_point(target);
+        }
+    }
+
+    /// Connecting to the current network failed, falls through to the next network after
+    /// repeated failures
+    pub fn failed(&mut self) {
+        if self.pinned {
+            // The access point went away or moved to another channel
+            self.pinned = false;
+            network_cache::forget(Cached::AccessPoint);
+        }
+        // The driver picks the access point of the next attempt
+        self.access_point = None;
+        self.failures = self.failures.saturating_add(1);
+        if self.failures < MAX_FAILURES || self.networks.len() < 2 {
+            return;
+        }
+        let failed = self.current().ssid;
+        self.current = (self.current + 1) % self.networks.len();
+        self.failures = 0;
+        info!(
+            "WIFI: {MAX_FAILURES} failed attempts on {failed}, trying {}",
+            self.current().ssid
+        );
+    }
+}
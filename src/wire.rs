@@ -0,0 +1,160 @@
+use core::fmt::Write;
+
+use crate::{
+    charger::{ChargerState, InputEvent, OutputEvent, StopReason},
+    fault::Fault,
+    vendor,
+};
+
+/// Stable name and id of a value for external tools, unlike `as_str()` and `Debug` these never
+/// change once released. New variants get a new id, retired ids are not reused
+pub trait WireFormat: Sized + Copy {
+    fn wire_name(&self) -> &'static str;
+    fn wire_id(&self) -> u8;
+    fn from_wire_name(name: &str) -> Option<Self>;
+    fn from_wire_id(id: u8) -> Option<Self>;
+}
+
+/// Implement WireFormat from a table of variants with their id and name
+macro_rules! wire_format {
+    ($type:ident { $($variant:ident = $id:literal $name:literal),* $(,)? }) => {
+        impl WireFormat for $type {
+            fn wire_name(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $name),*
+                }
+            }
+
+            fn wire_id(&self) -> u8 {
+                match self {
+                    $(Self::$variant => $id),*
+                }
+            }
+
+            fn from_wire_name(name: &str) -> Option<Self> {
+                match name {
+                    $($name => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+
+            fn from_wire_id(id: u8) -> Option<Self> {
+                match id {
+                    $($id => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+wire_format!(ChargerState {
+    Off = 0 "Off",
+    Faulted = 1 "Faulted",
+    Available = 2 "Available",
+    Preparing = 3 "Preparing",
+    Charging = 4 "Charging",
+    Authorizing = 5 "Authorizing",
+    Unavailable = 6 "Unavailable",
+    Reserved = 7 "Reserved",
+});
+
+wire_format!(InputEvent {
+    None = 0 "None",
+    InsertCable = 1 "InsertCable",
+    RemoveCable = 2 "RemoveCable",
+    SwipeDetected = 3 "SwipeDetected",
+    Accepted = 4 "Accepted",
+    Rejected = 5 "Rejected",
+    LockoutCleared = 6 "LockoutCleared",
+    Reserve = 7 "Reserve",
+    ReservationEnded = 8 "ReservationEnded",
+    MaintenanceStarted = 9 "MaintenanceStarted",
+    MaintenanceEnded = 10 "MaintenanceEnded",
+    DiodeMissing = 11 "DiodeMissing",
+    Deauthorized = 12 "Deauthorized",
+    NetworkLost = 13 "NetworkLost",
+    NetworkRestored = 14 "NetworkRestored",
+    InvariantViolated = 15 "InvariantViolated",
+    StopRequested = 16 "StopRequested",
+    GroundFault = 17 "GroundFault",
+    PowerSwitchFailure = 18 "PowerSwitchFailure",
+    Overheated = 19 "Overheated",
+    Cooled = 20 "Cooled",
+    Queued = 21 "Queued",
+});
+
+/// Vendor events share a name, their id is the code from the vendor range on
+impl WireFormat for OutputEvent {
+    fn wire_name(&self) -> &'static str {
+        match self {
+            Self::Lock => "Lock",
+            Self::Unlock => "Unlock",
+            Self::ApplyPower => "ApplyPower",
+            Self::RemovePower => "RemovePower",
+            Self::ShowRejected => "ShowRejected",
+            Self::Vendor(_) => "Vendor",
+        }
+    }
+
+    fn wire_id(&self) -> u8 {
+        match self {
+            Self::Lock => 0,
+            Self::Unlock => 1,
+            Self::ApplyPower => 2,
+            Self::RemovePower => 3,
+            Self::ShowRejected => 4,
+            Self::Vendor(code) => vendor::VENDOR_WIRE_BASE.saturating_add(*code),
+        }
+    }
+
+    fn from_wire_name(name: &str) -> Option<Self> {
+        match name {
+            "Lock" => Some(Self::Lock),
+            "Unlock" => Some(Self::Unlock),
+            "ApplyPower" => Some(Self::ApplyPower),
+            "RemovePower" => Some(Self::RemovePower),
+            "ShowRejected" => Some(Self::ShowRejected),
+            _ => None,
+        }
+    }
+
+    fn from_wire_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Lock),
+            1 => Some(Self::Unlock),
+            2 => Some(Self::ApplyPower),
+            3 => Some(Self::RemovePower),
+            4 => Some(Self::ShowRejected),
+            id if id >= vendor::VENDOR_WIRE_BASE => {
+                Some(Self::Vendor(id - vendor::VENDOR_WIRE_BASE))
+            }
+            _ => None,
+        }
+    }
+}
+
+wire_format!(Fault {
+    EvDisconnected = 0 "EvDisconnected",
+    PilotDiodeMissing = 1 "PilotDiodeMissing",
+    InvariantViolated = 2 "InvariantViolated",
+    GroundFault = 3 "GroundFault",
+    PowerSwitchFailure = 4 "PowerSwitchFailure",
+    HighTemperature = 5 "HighTemperature",
+});
+
+wire_format!(StopReason {
+    DeAuthorized = 0 "DeAuthorized",
+    Offline = 1 "Offline",
+    Duplicate = 2 "Duplicate",
+});
+
+/// Write a value as JSON members, `"<key>":"<name>","<key>_id":<id>`
+pub fn write_json<T: WireFormat>(out: &mut impl Write, key: &str, value: T) -> core::fmt::Result {
+    write!(
+        out,
+        "\"{key}\":\"{}\",\"{key}_id\":{}",
+        value.wire_name(),
+        value.wire_id()
+    )
+}